@@ -0,0 +1,67 @@
+//! Draws the same patch of each `noise_type` to a PNG, to compare them before picking one for a world.
+//! The patch is what the built-in generator samples for its heightmap, around the origin,
+//! with the rest of the noise settings at their defaults.
+//!
+//!     cargo run --example noise_gallery -- <output directory> [seed]
+use enum_iterator::IntoEnumIterator;
+use quanta::config::{GameConfig, NoiseType};
+use quanta::noise_gen::noise;
+use std::path::PathBuf;
+
+const SIZE: u32 = 256;
+/// How far apart samples are, in noise space. The generator's heightmap is at 0.0004 a voxel, so this is 16 voxels a pixel.
+const SCALE: f64 = 0.0064;
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let dir = match args.next() {
+        Some(dir) => PathBuf::from(dir),
+        None => {
+            eprintln!("usage: noise_gallery <output directory> [seed]");
+            std::process::exit(1);
+        }
+    };
+    let seed = args.next().map_or(0, |s| {
+        s.parse().unwrap_or_else(|_| {
+            eprintln!("the seed has to be a number, not {}", s);
+            std::process::exit(1)
+        })
+    });
+    std::fs::create_dir_all(&dir).expect("couldn't make the output directory");
+
+    for noise_type in NoiseType::into_enum_iter() {
+        let config = GameConfig {
+            noise_type,
+            ..GameConfig::default()
+        };
+        let n = noise(&config, seed);
+        let values: Vec<f64> = (0..SIZE * SIZE)
+            .map(|i| {
+                let (x, y) = ((i % SIZE) as f64, (i / SIZE) as f64);
+                let half = SIZE as f64 * 0.5;
+                n.get2([(x - half) * SCALE, (y - half) * SCALE])
+            })
+            .collect();
+        // Each one has its own range, so they're stretched to fill black to white
+        let min = values.iter().cloned().fold(f64::INFINITY, f64::min);
+        let max = values.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+        let range = (max - min).max(1e-9);
+        let rgba: Vec<u8> = values
+            .iter()
+            .flat_map(|v| {
+                let g = ((v - min) / range * 255.0).round() as u8;
+                vec![g, g, g, 255]
+            })
+            .collect();
+
+        let path = dir.join(format!("{:?}.png", noise_type).to_lowercase());
+        quanta::png::save(&path, SIZE, SIZE, &rgba).expect("couldn't write the image");
+        println!(
+            "{:?}: {:.3} to {:.3}, in {}",
+            noise_type,
+            min,
+            max,
+            path.display()
+        );
+    }
+}
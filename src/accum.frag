@@ -0,0 +1,22 @@
+#version 450
+
+layout(location=0) in vec2 frag_coord_ndc;
+layout(location=0) out vec4 frag_color;
+
+layout(push_constant) uniform PushConstants {
+  // How much the new frame counts for in the average, which is 1 / how many frames are in it now
+  float weight;
+};
+
+// The frame main.frag just drew, and the average of the ones before it. They're the same size as what we draw to.
+layout(set=0, binding=0) uniform sampler2D frame;
+layout(set=0, binding=1) uniform sampler2D average;
+
+void main() {
+  ivec2 p = ivec2(gl_FragCoord.xy);
+  vec4 new = texelFetch(frame, p, 0);
+  vec4 avg = texelFetch(average, p, 0);
+  // The alpha channel is the distance to the hit, which stays the first frame's,
+  // since the average of two distances across an edge is somewhere there isn't anything
+  frag_color = weight >= 1.0 ? new : vec4(mix(avg.rgb, new.rgb, weight), avg.a);
+}
@@ -0,0 +1,539 @@
+//! A reproducible benchmark: renders the default generated world headless, flying the camera along a committed path.
+//! `calibrate()` runs it with different settings to find ones that are fast enough on this machine.
+use crate::camera::Camera;
+use crate::camera_path::{CameraPath, Keyframe};
+use crate::client::Client;
+use crate::client_world::WorldSource;
+use crate::common::*;
+use crate::config::*;
+use crate::event::{Event, FrameNum, Time};
+use crate::profile::Profiler;
+use crate::window::Window;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The longest we'll wait for the world to finish loading before starting anyway
+const LOAD_TIMEOUT: Duration = Duration::from_secs(60);
+/// The world counts as loaded once the number of chunks has stayed the same for this many frames
+const LOAD_STABLE_FRAMES: usize = 60;
+
+/// The seed the world was generated with before seeds were configurable, which `bench/path.ron` was made for
+const BENCH_SEED: u64 = 1;
+
+/// The contents of a benchmark file, like `bench/path.ron`
+#[derive(Deserialize)]
+struct BenchPath {
+    resolution: (u32, u32),
+    /// The number of frames to render. The path is spread evenly over them, so the frames rendered don't depend on speed.
+    frames: usize,
+    keyframes: Vec<Keyframe>,
+}
+
+/// How `run()` writes its results
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BenchFormat {
+    /// A few lines for people to read
+    Pretty,
+    /// One object with every number, the settings and where they came from
+    Json,
+    /// A header line and one row, for appending to a spreadsheet of runs
+    Csv,
+}
+
+impl std::str::FromStr for BenchFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "pretty" => Ok(BenchFormat::Pretty),
+            "json" => Ok(BenchFormat::Json),
+            "csv" => Ok(BenchFormat::Csv),
+            _ => Err(format!(
+                "{} isn't a benchmark output format; try json, csv or pretty",
+                s
+            )),
+        }
+    }
+}
+
+/// What we found, in milliseconds
+#[derive(Serialize)]
+struct BenchReport {
+    frames: usize,
+    resolution: (u32, u32),
+    mean_ms: f64,
+    p50_ms: f64,
+    p95_ms: f64,
+    p99_ms: f64,
+    max_ms: f64,
+    mpixels_per_sec: f64,
+}
+
+impl BenchReport {
+    fn new(mut times: Vec<f64>, resolution: (u32, u32)) -> Self {
+        times.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let n = times.len();
+        let percentile = |p: f64| times[((n - 1) as f64 * p).round() as usize];
+        let mean_ms = times.iter().sum::<f64>() / n as f64;
+        BenchReport {
+            frames: n,
+            resolution,
+            mean_ms,
+            p50_ms: percentile(0.5),
+            p95_ms: percentile(0.95),
+            p99_ms: percentile(0.99),
+            max_ms: times[n - 1],
+            mpixels_per_sec: (resolution.0 * resolution.1) as f64 / (mean_ms * 1000.0),
+        }
+    }
+}
+
+/// A benchmark's report, with what it ran and what it ran on, so runs can be compared later
+#[derive(Serialize)]
+struct BenchResults<'a> {
+    /// The benchmark file's name without `.ron`, like `path`
+    scene: String,
+    /// The git commit checked out where the benchmark ran, if it's a repository
+    commit: Option<String>,
+    version: &'static str,
+    #[serde(flatten)]
+    report: BenchReport,
+    config: &'a ClientConfig,
+}
+
+/// The commit checked out in the current directory, if there is one and git is installed
+fn git_commit() -> Option<String> {
+    let out = std::process::Command::new("git")
+        .args(&["rev-parse", "HEAD"])
+        .output()
+        .ok()?;
+    if !out.status.success() {
+        return None;
+    }
+    Some(String::from_utf8(out.stdout).ok()?.trim().to_string())
+}
+
+/// Quotes a CSV field if it needs it
+fn csv_field(s: &str) -> String {
+    if s.contains(|c: char| c == ',' || c == '"' || c == '\n') {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+impl BenchResults<'_> {
+    fn format(&self, format: BenchFormat) -> String {
+        let r = &self.report;
+        match format {
+            BenchFormat::Pretty => format!(
+                "{} frames of {} at {}x{}\n\
+                 mean {:.2}ms, p50 {:.2}ms, p95 {:.2}ms, p99 {:.2}ms, max {:.2}ms\n\
+                 {:.1} Mpixels/s\n",
+                r.frames,
+                self.scene,
+                r.resolution.0,
+                r.resolution.1,
+                r.mean_ms,
+                r.p50_ms,
+                r.p95_ms,
+                r.p99_ms,
+                r.max_ms,
+                r.mpixels_per_sec
+            ),
+            BenchFormat::Json => serde_json::to_string_pretty(self).unwrap() + "\n",
+            BenchFormat::Csv => {
+                let c = self.config;
+                let row = [
+                    csv_field(&self.scene),
+                    csv_field(self.commit.as_deref().unwrap_or("")),
+                    self.version.to_string(),
+                    r.frames.to_string(),
+                    r.resolution.0.to_string(),
+                    r.resolution.1.to_string(),
+                    r.mean_ms.to_string(),
+                    r.p50_ms.to_string(),
+                    r.p95_ms.to_string(),
+                    r.p99_ms.to_string(),
+                    r.max_ms.to_string(),
+                    r.mpixels_per_sec.to_string(),
+                    c.render_scale.to_string(),
+                    format!("{:?}", c.aa_mode),
+                    c.max_ray_steps.to_string(),
+                    c.view_distance.to_string(),
+                ];
+                format!("{}\n{}\n", CSV_HEADER, row.join(","))
+            }
+        }
+    }
+}
+
+/// The columns of `BenchFormat::Csv`. It only has the settings that matter most for speed, unlike the JSON.
+const CSV_HEADER: &str = "scene,commit,version,frames,width,height,mean_ms,p50_ms,p95_ms,p99_ms,max_ms,mpixels_per_sec,render_scale,aa_mode,max_ray_steps,view_distance";
+
+/// The config the benchmark runs with, so numbers don't depend on anyone's config file.
+/// Chunks aren't saved or loaded and the seed is fixed, so the world is always the same and freshly generated.
+pub fn bench_config(log_level: String) -> ClientConfig {
+    ClientConfig {
+        log_level,
+        game_config: Arc::new(GameConfig {
+            save_chunks: false,
+            seed: Some(BENCH_SEED),
+            ..GameConfig::default()
+        }),
+        ..ClientConfig::default()
+    }
+}
+
+/// The config `calibrate()` runs with: the player's own, since the rest of their settings matter too,
+/// but in the same world as the benchmark
+pub fn calibrate_config(config: ClientConfig) -> ClientConfig {
+    ClientConfig {
+        game_config: bench_config(String::new()).game_config,
+        ..config
+    }
+}
+
+/// Reads a benchmark file, like `bench/path.ron`
+fn load_path(path_file: &Path) -> BenchPath {
+    let path: BenchPath = ron::de::from_reader(
+        std::fs::File::open(path_file)
+            .unwrap_or_else(|e| panic!("Couldn't open {}: {}", path_file.display(), e)),
+    )
+    .unwrap_or_else(|e| panic!("Couldn't parse {}: {}", path_file.display(), e));
+    assert!(
+        path.keyframes.len() >= 2 && path.frames >= 2,
+        "The camera path needs at least two keyframes and two frames"
+    );
+    path
+}
+
+/// A headless client, flying the camera along a benchmark path
+struct Harness {
+    w: World,
+    d: Dispatcher<'static, 'static>,
+    path: CameraPath,
+    frame_num: usize,
+}
+
+impl Harness {
+    fn new(
+        conn: Connection,
+        config: Arc<ClientConfig>,
+        resolution: (u32, u32),
+        path: CameraPath,
+    ) -> Self {
+        let window = Window::headless([resolution.0, resolution.1], &config).unwrap_or_else(|e| {
+            error!("{}", e);
+            std::process::exit(1)
+        });
+
+        let mut w = World::new();
+        let mut e: EventChannel<Event> = EventChannel::new();
+
+        let mut cam = Camera::new(window.size());
+        cam.set_path_view(path.sample(0.0).unwrap());
+        let source = WorldSource::Server(conn);
+        let (client, client_world) = match Client::new(&window, &cam, source, config, &mut e) {
+            Ok(x) => x,
+            Err(err) => {
+                error!("{}", err);
+                std::process::exit(1)
+            }
+        };
+
+        w.insert(e);
+        w.insert(cam);
+        w.insert(window);
+        w.insert(crate::world::World::with_edit_tracking());
+        w.insert(Profiler::default());
+
+        let d = DispatcherBuilder::new()
+            .with(client, "", &[])
+            .with(client_world, "", &[])
+            .build();
+        Harness {
+            w,
+            d,
+            path,
+            frame_num: 0,
+        }
+    }
+
+    /// Renders the frame `time` seconds along the path, `delta` after the last one, and returns how long it took in milliseconds.
+    /// Headless clients wait for the GPU every frame, so that includes all of the rendering.
+    fn frame(&mut self, time: f64, delta: f64) -> f64 {
+        self.frame_num += 1;
+        self.w.insert(Time {
+            total: Duration::from_secs_f64(time),
+            delta: Duration::from_secs_f64(delta),
+        });
+        self.w.insert(FrameNum(self.frame_num));
+        self.w
+            .fetch_mut::<Camera>()
+            .set_path_view(self.path.sample(time).unwrap());
+
+        let start = Instant::now();
+        self.d.dispatch_par(&self.w);
+        self.w.maintain();
+        self.w.fetch_mut::<Profiler>().frame();
+        start.elapsed().as_secs_f64() * 1000.0
+    }
+
+    /// Waits for the world around the start of the path to load
+    fn load(&mut self) {
+        info!("Loading benchmark world");
+        let load_start = Instant::now();
+        let mut last_count = 0;
+        let mut stable = 0;
+        while stable < LOAD_STABLE_FRAMES && load_start.elapsed() < LOAD_TIMEOUT {
+            self.frame(0.0, 0.0);
+            let count = self.w.fetch::<crate::world::World>().locs().count();
+            if count == last_count && count > 0 {
+                stable += 1;
+            } else {
+                stable = 0;
+            }
+            last_count = count;
+        }
+        if stable < LOAD_STABLE_FRAMES {
+            warn!("The world didn't finish loading, benchmarking anyway");
+        }
+    }
+
+    /// Renders `frames` frames at a fixed time step from one end of the path to the other, so everything but the frame times
+    /// is deterministic, and returns how long each one took
+    fn fly(&mut self, frames: usize) -> Vec<f64> {
+        let step = self.path.duration() / (frames - 1) as f64;
+        (0..frames)
+            .map(|f| self.frame(f as f64 * step, step))
+            .collect()
+    }
+
+    /// Switches to `config`, like the config file changing, and renders a frame with it so the change is done before we time anything
+    fn set_config(&mut self, config: Arc<ClientConfig>) {
+        self.w
+            .fetch_mut::<EventChannel<Event>>()
+            .single_write(Event::ConfigChanged(config));
+        self.frame(0.0, 0.0);
+    }
+}
+
+/// Runs the benchmark along the camera path in `path_file`, and prints the results in `format`,
+/// or writes them to `out_file` if there is one.
+pub fn run(
+    conn: Connection,
+    config: Arc<ClientConfig>,
+    path_file: &Path,
+    format: BenchFormat,
+    out_file: Option<&Path>,
+) {
+    let path = load_path(path_file);
+    let mut h = Harness::new(
+        conn,
+        Arc::clone(&config),
+        path.resolution,
+        CameraPath::new(path.keyframes),
+    );
+    h.load();
+
+    info!("Running benchmark");
+    let times = h.fly(path.frames);
+
+    let results = BenchResults {
+        scene: path_file
+            .file_stem()
+            .map_or_else(String::new, |s| s.to_string_lossy().into_owned()),
+        commit: git_commit(),
+        version: env!("CARGO_PKG_VERSION"),
+        report: BenchReport::new(times, path.resolution),
+        config: &config,
+    };
+    let s = results.format(format);
+    match out_file {
+        Some(file) => match std::fs::write(file, s) {
+            Ok(()) => info!("Wrote the results to {}", file.display()),
+            Err(e) => error!("Couldn't write {}: {}", file.display(), e),
+        },
+        None => print!("{}", s),
+    }
+}
+
+/// What `calibrate()` aims for if it isn't given a frame rate
+pub const DEFAULT_TARGET_FPS: f64 = 60.0;
+/// The render scales `calibrate()` tries, from fastest to best-looking
+const CALIBRATE_SCALES: [f32; 4] = [0.5, 0.67, 0.85, 1.0];
+/// How many frames `calibrate()` renders along the path for each combination of settings.
+/// Fewer than the benchmark, since there are a lot of combinations, but enough to see the whole path.
+const CALIBRATE_FRAMES: usize = 150;
+
+/// The settings `calibrate()` picked, in the same format as the config file
+#[derive(Serialize)]
+struct Calibration {
+    render_scale: f32,
+    max_ray_steps: u32,
+}
+
+/// The `max_ray_steps` values `calibrate()` tries with a root of `root_size`, from fastest to farthest-reaching.
+/// Fewer than `min_ray_steps()` cuts off distant terrain, so that's where they start.
+fn calibrate_steps(root_size: f32) -> Vec<u32> {
+    let min = min_ray_steps(root_size);
+    let mut steps: Vec<u32> = [min, 256, 384, 512]
+        .iter()
+        .copied()
+        .filter(|&x| x >= min)
+        .collect();
+    steps.dedup();
+    steps
+}
+
+/// The best-looking settings out of `results`, which are (render scale, ray steps, 95th percentile frame time),
+/// that are still under `target_ms`. Resolution matters more than view distance, so that's picked first.
+/// Returns `None` if none of them are fast enough.
+fn recommend(results: &[(f32, u32, f64)], target_ms: f64) -> Option<(f32, u32)> {
+    results
+        .iter()
+        .filter(|r| r.2 <= target_ms)
+        .max_by(|a, b| a.0.partial_cmp(&b.0).unwrap().then(a.1.cmp(&b.1)))
+        .map(|r| (r.0, r.1))
+}
+
+/// Runs the benchmark path with every combination of a few render scales and ray step counts,
+/// and recommends the best-looking one that still runs at `target_fps`.
+/// Everything else comes from `config`, so the recommendation fits the rest of the player's settings.
+/// It's printed, and written to `out_file` in the config file's format so it can be copied in.
+pub fn calibrate(
+    conn: Connection,
+    config: ClientConfig,
+    path_file: &Path,
+    target_fps: f64,
+    out_file: &Path,
+) {
+    let path = load_path(path_file);
+    let target_ms = 1000.0 / target_fps;
+    let steps = calibrate_steps(config.max_root_size);
+    let config = ClientConfig {
+        // We're choosing the resolution ourselves
+        dynamic_resolution: false,
+        ..config
+    };
+    let mut h = Harness::new(
+        conn,
+        Arc::new(config.clone()),
+        path.resolution,
+        CameraPath::new(path.keyframes),
+    );
+    h.load();
+
+    info!(
+        "Calibrating for {} FPS ({:.1}ms a frame) at {}x{}",
+        target_fps, target_ms, path.resolution.0, path.resolution.1
+    );
+    let mut results = Vec::new();
+    for &render_scale in &CALIBRATE_SCALES {
+        for &max_ray_steps in &steps {
+            h.set_config(Arc::new(ClientConfig {
+                render_scale,
+                max_ray_steps,
+                ..config.clone()
+            }));
+            let report = BenchReport::new(h.fly(CALIBRATE_FRAMES), path.resolution);
+            println!(
+                "render_scale {:.2}, max_ray_steps {}: mean {:.2}ms, p95 {:.2}ms",
+                render_scale, max_ray_steps, report.mean_ms, report.p95_ms
+            );
+            results.push((render_scale, max_ray_steps, report.p95_ms));
+        }
+    }
+
+    // Going by the 95th percentile, so it's the target most of the time and not just on average
+    let (render_scale, max_ray_steps) = match recommend(&results, target_ms) {
+        Some(x) => x,
+        None => {
+            println!(
+                "Nothing tried was fast enough for {} FPS; these are the fastest settings, but try turning down view_distance, bloom or motion blur too",
+                target_fps
+            );
+            (CALIBRATE_SCALES[0], steps[0])
+        }
+    };
+    println!(
+        "Recommended: render_scale: {}, max_ray_steps: {}",
+        render_scale, max_ray_steps
+    );
+
+    let rec = Calibration {
+        render_scale,
+        max_ray_steps,
+    };
+    let s = format!(
+        "// From `quanta --calibrate` for {} FPS at {}x{}. Copy these into config.ron to use them.\n{}\n",
+        target_fps,
+        path.resolution.0,
+        path.resolution.1,
+        ron::ser::to_string_pretty(&rec, Default::default()).unwrap()
+    );
+    match std::fs::write(out_file, s) {
+        Ok(()) => println!("Wrote it to {}", out_file.display()),
+        Err(e) => error!("Couldn't write {}: {}", out_file.display(), e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_results() {
+        let config = ClientConfig::default();
+        let results = BenchResults {
+            scene: "a, \"b\"".to_string(),
+            commit: None,
+            version: "0.1.0",
+            report: BenchReport::new(vec![4.0, 2.0, 3.0, 1.0], (100, 50)),
+            config: &config,
+        };
+        assert_eq!(results.report.p50_ms, 3.0);
+        assert_eq!(results.report.max_ms, 4.0);
+
+        let csv = results.format(BenchFormat::Csv);
+        let lines: Vec<_> = csv.lines().collect();
+        assert_eq!(lines[0], CSV_HEADER);
+        assert!(lines[1].starts_with("\"a, \"\"b\"\"\",,0.1.0,4,100,50,2.5,3,"));
+        // The quoted comma doesn't count
+        assert_eq!(
+            lines[1].matches(',').count() - 1,
+            CSV_HEADER.matches(',').count()
+        );
+
+        let json: serde_json::Value =
+            serde_json::from_str(&results.format(BenchFormat::Json)).unwrap();
+        assert_eq!(json["p95_ms"], 4.0);
+        assert_eq!(json["resolution"][1], 50);
+        assert_eq!(json["config"]["render_scale"], 1.0);
+
+        assert_eq!("csv".parse(), Ok(BenchFormat::Csv));
+        assert!("xml".parse::<BenchFormat>().is_err());
+    }
+
+    #[test]
+    fn recommends_best_that_hits_target() {
+        let results = [
+            (0.5, 160, 5.0),
+            (0.5, 512, 8.0),
+            (1.0, 160, 15.0),
+            (1.0, 512, 30.0),
+        ];
+        // Resolution first, then steps
+        assert_eq!(recommend(&results, 16.7), Some((1.0, 160)));
+        assert_eq!(recommend(&results, 10.0), Some((0.5, 512)));
+        assert_eq!(recommend(&results, 100.0), Some((1.0, 512)));
+        assert_eq!(recommend(&results, 1.0), None);
+
+        assert_eq!(calibrate_steps(1024.0), [160, 256, 384, 512]);
+        // A huge root needs more than most of them
+        assert_eq!(calibrate_steps(65536.0), [256, 384, 512]);
+    }
+}
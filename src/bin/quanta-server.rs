@@ -0,0 +1,67 @@
+//! A dedicated server, which players join with `quanta --connect <address>`.
+//! It takes the path to its config file as an argument, or uses `server.ron` in the config directory.
+//! Type `stop` to save the world and shut down.
+#[macro_use]
+extern crate log;
+
+use quanta::common::*;
+use quanta::config::ServerConfig;
+use quanta::server::Server;
+use std::io::{BufRead, Write};
+use std::sync::Arc;
+
+fn main() {
+    let config_file = std::env::args()
+        .nth(1)
+        .map(std::path::PathBuf::from)
+        .unwrap_or_else(|| {
+            let mut path =
+                app_dirs2::app_root(app_dirs2::AppDataType::UserConfig, &quanta::APP_INFO).unwrap();
+            path.push("server.ron");
+            path
+        });
+    let mut config = if config_file.exists() {
+        ServerConfig::load(&config_file).expect("bad server config file")
+    } else {
+        let c = ServerConfig::default();
+        let s = ron::ser::to_string(&c).unwrap();
+        let mut f = std::fs::File::create(&config_file).unwrap();
+        writeln!(f, "{}", s).unwrap();
+        c
+    };
+
+    env_logger::Builder::from_env(
+        env_logger::Env::default().default_filter_or(config.log_level.as_str()),
+    )
+    .init();
+    config.game_config.validate();
+
+    let address = config.address.clone();
+    let mut server = Server::new(Arc::new(config.game_config));
+    server
+        .listen(address.as_str())
+        .unwrap_or_else(|e| panic!("Couldn't listen on {}: {}", address, e));
+
+    // The console is a local player that can't see anything, and it stops the server by leaving
+    let (console, conn) = Connection::local();
+    server.join(conn, Vector3::zeros(), 0);
+    std::thread::spawn(move || {
+        let stdin = std::io::stdin();
+        for line in stdin.lock().lines() {
+            match line.as_ref().map(|s| s.trim()) {
+                Ok("stop") => {
+                    info!("Stopping");
+                    console.send(Message::Leave);
+                    return;
+                }
+                Ok("") => (),
+                Ok(cmd) => warn!("Unknown command {:?}, the only one is 'stop'", cmd),
+                Err(_) => break,
+            }
+        }
+        // If there's no stdin, like when running as a service, we just keep going
+    });
+
+    server.run();
+    info!("Saved the world");
+}
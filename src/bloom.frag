@@ -0,0 +1,37 @@
+#version 450
+
+layout(location=0) in vec2 frag_coord_ndc;
+layout(location=0) out vec4 frag_color;
+
+layout(push_constant) uniform PushConstants {
+  // The size of a texel in the input image, in uv coordinates
+  vec2 texel;
+  // The direction to blur in, or zero to downsample instead
+  vec2 dir;
+  // When downsampling, everything dimmer than this is thrown out
+  float threshold;
+};
+
+layout(set=0, binding=0) uniform sampler2D input_image;
+
+// 9-tap gaussian, using linear filtering to get it in five samples
+const float offsets[3] = float[](0.0, 1.3846153846, 3.2307692308);
+const float weights[3] = float[](0.2270270270, 0.3162162162, 0.0702702703);
+
+void main() {
+  vec2 uv = frag_coord_ndc * 0.5 + 0.5;
+
+  if (dir == vec2(0.0)) {
+    // Downsample with a 2x2 box filter, which linear filtering gives us in one sample
+    vec3 col = texture(input_image, uv).rgb;
+    frag_color = vec4(max(col - threshold, vec3(0.0)), 1.0);
+    return;
+  }
+
+  vec3 col = texture(input_image, uv).rgb * weights[0];
+  for (int i = 1; i < 3; i++) {
+    col += texture(input_image, uv + dir * texel * offsets[i]).rgb * weights[i];
+    col += texture(input_image, uv - dir * texel * offsets[i]).rgb * weights[i];
+  }
+  frag_color = vec4(col, 1.0);
+}
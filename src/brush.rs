@@ -0,0 +1,150 @@
+//! Brushes, for editing more than one voxel with a click. The brush is centered on the voxel the player's looking at,
+//! and everything it covers is edited in the same frame, so the chunks it touches are each uploaded once per click.
+//! The `brush_shape` key switches shapes, and scrolling changes the radius.
+use crate::common::*;
+use serde::{Deserialize, Serialize};
+
+/// The biggest radius a brush can have. A sphere this big is about 2000 voxels, each of which is a message to the server.
+pub const MAX_BRUSH_RADIUS: u32 = 8;
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub enum BrushShape {
+    /// Every voxel within about `radius` of the center, so it's round even when it's small
+    Sphere,
+    /// A cube `2 * radius + 1` voxels wide
+    Cube,
+    /// A line `2 * radius + 1` voxels long, along whichever axis the camera is looking along the most
+    Line,
+}
+
+impl BrushShape {
+    /// The one the `brush_shape` key switches to from this one
+    pub fn next(self) -> Self {
+        match self {
+            BrushShape::Sphere => BrushShape::Cube,
+            BrushShape::Cube => BrushShape::Line,
+            BrushShape::Line => BrushShape::Sphere,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Brush {
+    pub shape: BrushShape,
+    /// In voxels, not counting the center; 0 is just the voxel that was clicked on, whatever the shape
+    pub radius: u32,
+}
+
+/// The axis `dir` points along the most
+fn main_axis(dir: Vector3<f32>) -> usize {
+    dir.iamax()
+}
+
+impl Brush {
+    /// Adds `steps` to the radius, keeping it between 0 and `MAX_BRUSH_RADIUS`
+    pub fn resize(&mut self, steps: i32) {
+        self.radius = (self.radius as i32 + steps)
+            .max(0)
+            .min(MAX_BRUSH_RADIUS as i32) as u32;
+    }
+
+    /// The voxels the brush covers around the voxel at `center`, as their centers, like `World::set_block()` takes.
+    /// `dir` is where the camera is looking.
+    pub fn voxels(&self, center: Vector3<f32>, dir: Vector3<f32>) -> Vec<Vector3<f32>> {
+        let r = self.radius as i32;
+        let center = center.map(|x| x.floor() + 0.5);
+        match self.shape {
+            BrushShape::Line => {
+                let axis = main_axis(dir);
+                (-r..=r)
+                    .map(|i| {
+                        let mut p = center;
+                        p[axis] += i as f32;
+                        p
+                    })
+                    .collect()
+            }
+            BrushShape::Sphere | BrushShape::Cube => {
+                let mut v = Vec::new();
+                for x in -r..=r {
+                    for y in -r..=r {
+                        for z in -r..=r {
+                            // Within `radius + 0.5`, so a radius of 1 isn't just a plus sign
+                            if self.shape == BrushShape::Sphere && x * x + y * y + z * z > r * r + r
+                            {
+                                continue;
+                            }
+                            v.push(center + Vector3::new(x, y, z).map(|x| x as f32));
+                        }
+                    }
+                }
+                v
+            }
+        }
+    }
+
+    /// The corners of the box around everything `voxels()` covers, on the outside faces of the voxels
+    pub fn bounds(&self, center: Vector3<f32>, dir: Vector3<f32>) -> (Vector3<f32>, Vector3<f32>) {
+        let center = center.map(|x| x.floor() + 0.5);
+        let mut extent = Vector3::repeat(self.radius as f32 + 0.5);
+        if self.shape == BrushShape::Line {
+            let axis = main_axis(dir);
+            for i in 0..3 {
+                if i != axis {
+                    extent[i] = 0.5;
+                }
+            }
+        }
+        (center - extent, center + extent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn brush(shape: BrushShape, radius: u32) -> Brush {
+        Brush { shape, radius }
+    }
+
+    #[test]
+    fn shapes() {
+        let center = Vector3::new(10.2, -3.7, 0.5);
+        let dir = Vector3::new(0.1, -0.9, 0.3);
+        let voxel = Vector3::new(10.5, -3.5, 0.5);
+        // A radius of 0 is one voxel, whatever the shape
+        for &shape in &[BrushShape::Sphere, BrushShape::Cube, BrushShape::Line] {
+            assert_eq!(brush(shape, 0).voxels(center, dir), [voxel]);
+            // Everything's inside the bounds
+            let b = brush(shape, 3);
+            let (min, max) = b.bounds(center, dir);
+            for p in b.voxels(center, dir) {
+                assert!((0..3).all(|i| p[i] > min[i] && p[i] < max[i]), "{:?}", p);
+            }
+        }
+
+        assert_eq!(brush(BrushShape::Cube, 2).voxels(center, dir).len(), 125);
+        // A cube, minus the corners
+        assert_eq!(brush(BrushShape::Sphere, 1).voxels(center, dir).len(), 19);
+        let sphere = brush(BrushShape::Sphere, 4).voxels(center, dir);
+        assert!(sphere.contains(&(voxel + Vector3::new(4.0, 0.0, 0.0))));
+        assert!(!sphere.contains(&(voxel + Vector3::new(3.0, 3.0, 2.0))));
+
+        // Along y, since that's mostly where it's looking
+        let line = brush(BrushShape::Line, 2).voxels(center, dir);
+        assert_eq!(line.len(), 5);
+        assert!(line.iter().all(|p| p.x == voxel.x && p.z == voxel.z));
+        let (min, max) = brush(BrushShape::Line, 2).bounds(center, dir);
+        assert_eq!(max - min, Vector3::new(1.0, 5.0, 1.0));
+    }
+
+    #[test]
+    fn resize() {
+        let mut b = brush(BrushShape::Cube, 1);
+        b.resize(-3);
+        assert_eq!(b.radius, 0);
+        b.resize(100);
+        assert_eq!(b.radius, MAX_BRUSH_RADIUS);
+        assert_eq!(BrushShape::Line.next().next().next(), BrushShape::Line);
+    }
+}
@@ -1,33 +1,130 @@
+use crate::camera_path::{CameraPath, Keyframe, View};
 use crate::common::*;
+use crate::config::ClientConfig;
 use crate::event::*;
 use crate::shaders::PushConstants;
 
 /// In m/s
 pub const MOVE_SPEED: f32 = 10.0;
 pub const SENSITIVITY: f64 = 2.0;
+/// With `mouse_smoothing` on, once there's less mouse movement than this left to catch up on, in pixels, it's all applied at once
+const SMOOTHING_EPSILON: f64 = 1e-3;
+
+/// Walking physics runs at this many steps per second, whatever the frame rate, so jumps always go as high
+const PHYSICS_RATE: f64 = 120.0;
+/// The player collides as two spheres of this radius, one at their feet and one at their head
+pub const PLAYER_RADIUS: f32 = 0.3;
+/// How far the camera is above the bottom of the player
+pub const EYE_HEIGHT: f32 = 1.6;
+/// How far below the player's feet counts as standing on something
+const GROUND_DIST: f32 = 0.05;
+/// How fast walking speeds up or slows down to the speed the keys want, per second, on ground with a friction of 1.
+/// This is fast enough to feel instant; it's only noticeable on slippery ground.
+const GRIP_RATE: f32 = 60.0;
+/// What counts as the ground's friction in the air, so there's a little control but jumps keep most of their momentum
+const AIR_GRIP: f32 = 0.2;
+/// The shader is happy to start rays right at the camera, but a projection matrix needs its near plane in front of it
+const MIN_PROJECTION_NEAR: f32 = 0.01;
+/// How quickly the field of view eases to `sprint_fov` and back, in seconds:
+/// each frame it goes `1 - e^(-frame time / SPRINT_FOV_TIME)` of the rest of the way, like `mouse_smoothing`
+const SPRINT_FOV_TIME: f64 = 0.1;
+
+/// The walking and sprinting settings from the config
+#[derive(Clone, Copy, Debug)]
+struct WalkConfig {
+    speed: f32,
+    /// The upwards speed that gets us to `jump_height`
+    jump_speed: f32,
+    gravity: f32,
+    coyote_time: f32,
+    air_jumps: u32,
+    jump_key: u32,
+    walk_key: u32,
+    step_height: f32,
+    sprint_key: u32,
+    sprint_speed: f32,
+    /// In radians, or 0 if it's off
+    sprint_fov: f32,
+}
+
+impl WalkConfig {
+    fn new(config: &ClientConfig) -> Self {
+        WalkConfig {
+            speed: config.walk_speed,
+            jump_speed: (2.0 * config.gravity * config.jump_height).sqrt(),
+            gravity: config.gravity,
+            coyote_time: config.coyote_time,
+            air_jumps: config.air_jumps,
+            jump_key: config.keycodes.up,
+            walk_key: config.keycodes.walk,
+            step_height: config.step_height,
+            sprint_key: config.keycodes.sprint,
+            sprint_speed: config.sprint_speed,
+            sprint_fov: radians(config.sprint_fov),
+        }
+    }
+}
 
 pub struct Camera {
+    /// The field of view without the sprint kick, which is what camera paths set and record
     fov: f32,
+    /// How far the field of view is toward `sprint_fov`, from 0 to 1
+    sprint_kick: f32,
     resolution: (f64, f64),
-    pub pos: Point3<f32>,
+    /// `near` and `far` from the config, for `projection_matrix()`
+    near: f32,
+    far: f32,
+    /// Double precision, so the camera still moves smoothly millions of voxels out. See `RenderOrigin`.
+    pub pos: Point3<f64>,
     pub start: Vector3<i32>,
     pub dir: Vector3<f32>,
     up: Vector3<f32>,
     rx: f64,
     ry: f64,
     moving: Vector3<f32>, // vec3(right, up, forward)
+    /// The sprint key is held down
+    sprint: bool,
+    /// Mouse movement since the last `update()`. It's all applied at once there,
+    /// so looking around goes at the same speed however many mouse events we get per frame.
+    mouse: (f64, f64),
+    /// `mouse_smoothing` from the config, in seconds, or 0 if it's off
+    smoothing: f64,
+    /// With smoothing, mouse movement that hasn't turned the camera yet
+    smoothed: (f64, f64),
+
+    /// Walking instead of flying, so there's gravity and we can't go through things
+    walking: bool,
+    walk: WalkConfig,
+    /// Time from frames that hasn't been simulated yet, in seconds
+    physics_time: f64,
+    vel_y: f32,
+    /// The velocity along the ground, which only gets to what the keys want as fast as the ground's friction allows
+    vel_h: Vector3<f32>,
+    /// Seconds since we were last on the ground, or infinity if we've jumped since
+    since_ground: f32,
+    air_jumps_left: u32,
+    /// Jump was pressed, and the next physics step should try to jump
+    jump: bool,
+    /// The path we're flying along, and how far along it we are, in seconds.
+    /// While this is set, input doesn't move the camera.
+    playback: Option<(CameraPath, f64)>,
 }
 
 impl Camera {
     pub fn new(resolution: (f64, f64)) -> Self {
+        let resolution = (resolution.0.max(1.0), resolution.1.max(1.0));
         let fov = radians(90.0);
-        let pos = Point3::from(na::Vector3::new(1.0, 1.0, 1.0));
+        let pos = Point3::new(1.0, 1.0, 1.0);
         let dir = Vector3::z();
         let up = Vector3::y();
+        let config = ClientConfig::default();
 
         Camera {
             fov,
+            sprint_kick: 0.0,
             resolution,
+            near: config.near,
+            far: config.far,
             pos,
             start: [-8; 3].into(),
             dir,
@@ -35,31 +132,244 @@ impl Camera {
             rx: 0.0,
             ry: 0.0,
             moving: Vector3::zeros(),
+            sprint: false,
+            mouse: (0.0, 0.0),
+            smoothing: 0.0,
+            smoothed: (0.0, 0.0),
+            walking: false,
+            walk: WalkConfig::new(&config),
+            physics_time: 0.0,
+            vel_y: 0.0,
+            vel_h: Vector3::zeros(),
+            since_ground: f32::INFINITY,
+            air_jumps_left: 0,
+            jump: false,
+            playback: None,
+        }
+    }
+
+    /// Picks up the walking settings, keys, mouse smoothing, and near and far planes from `config`. Call this again when the config changes.
+    /// `walk` is only what we start out with, so it isn't changed after the first time.
+    pub fn configure(&mut self, config: &ClientConfig, first: bool) {
+        self.walk = WalkConfig::new(config);
+        self.near = config.near;
+        self.far = config.far;
+        self.smoothing = config.mouse_smoothing as f64;
+        if first {
+            self.walking = config.walk;
         }
     }
 
+    /// The position in single precision, which is fine for everything but rendering
     pub fn pos(&self) -> Vector3<f32> {
-        Vector3::new(self.pos.x, self.pos.y, self.pos.z)
+        self.pos.coords.map(|x| x as f32)
     }
 
-    pub fn update(&mut self, delta: f64) {
+    /// Moves the camera by `delta` seconds. When walking, it collides with `world`.
+    pub fn update(&mut self, delta: f64, world: &crate::world::World) {
+        if let Some((path, time)) = &mut self.playback {
+            *time += delta;
+            let (view, done) = (path.sample(*time), *time >= path.duration());
+            if let Some(view) = view {
+                self.set_path_view(view);
+            }
+            if done {
+                info!("Finished playing the camera path");
+                self.playback = None;
+            }
+            self.mouse = (0.0, 0.0);
+            self.smoothed = (0.0, 0.0);
+            // The path's field of view is what's shown
+            self.sprint_kick = 0.0;
+            return;
+        }
+
+        if self.smoothing > 0.0 {
+            self.smooth_mouse(delta);
+        }
+        if self.mouse != (0.0, 0.0) {
+            self.rx -= SENSITIVITY * self.mouse.0 / self.resolution.0;
+            self.ry += SENSITIVITY * self.mouse.1 / self.resolution.1;
+            self.mouse = (0.0, 0.0);
+            self.look();
+        }
+
+        let kick = if self.sprinting() { 1.0 } else { 0.0 };
+        self.sprint_kick +=
+            (kick - self.sprint_kick) * (1.0 - (-delta / SPRINT_FOV_TIME).exp()) as f32;
+
+        if self.walking {
+            // Don't try to catch up on a long hitch, that would just make the hitch longer
+            self.physics_time = (self.physics_time + delta).min(0.25);
+            while self.physics_time >= 1.0 / PHYSICS_RATE {
+                self.physics_time -= 1.0 / PHYSICS_RATE;
+                self.walk_step((1.0 / PHYSICS_RATE) as f32, world);
+            }
+            return;
+        }
+
         // self.up is the CAMERA up, but jumping moves up in the WORLD
-        let up = Vector3::y();
-        self.pos += self.dir * self.moving.z * delta as f32 * MOVE_SPEED;
-        self.pos += up * self.moving.y * delta as f32 * MOVE_SPEED;
-        self.pos += self.dir.cross(&up).normalize() * self.moving.x * delta as f32 * MOVE_SPEED;
+        let world_up = up();
+        let right = self.dir.cross(&world_up).normalize();
+        let v = self.dir * self.moving.z + world_up * self.moving.y + right * self.moving.x;
+        self.pos += v.map(|x| x as f64) * delta * (MOVE_SPEED * self.speed_factor()) as f64;
+    }
+
+    /// Whether the sprint key is held while moving along the ground, or while flying
+    fn sprinting(&self) -> bool {
+        self.sprint && (self.moving.x != 0.0 || self.moving.z != 0.0)
+    }
+
+    /// How many times as fast the keys move us right now
+    fn speed_factor(&self) -> f32 {
+        if self.sprinting() {
+            self.walk.sprint_speed
+        } else {
+            1.0
+        }
+    }
+
+    /// How far the player can move in direction `dir` before hitting something, up to `max`
+    fn free_distance(&self, world: &crate::world::World, dir: Vector3<f32>, max: f32) -> f32 {
+        let feet = self.pos() - up() * (EYE_HEIGHT - PLAYER_RADIUS);
+        let head = self.pos();
+        [feet, head]
+            .iter()
+            .filter_map(|&p| world.sphere_cast(p, dir, PLAYER_RADIUS, max))
+            .map(|hit| hit.t)
+            .fold(max, f32::min)
+    }
+
+    /// The material the player is standing on, if there's one right under the middle of them
+    fn ground(&self, world: &crate::world::World) -> Option<Material> {
+        world
+            .block(self.pos() - up() * (EYE_HEIGHT + GROUND_DIST * 0.5))
+            .filter(|&m| m != Material::Air)
+    }
+
+    /// The center of the voxel under the player and its material, while they're walking and on the ground.
+    /// It's `None` while flying or in the air.
+    pub fn standing_on(&self, world: &crate::world::World) -> Option<(Vector3<f32>, Material)> {
+        if !self.walking
+            || self.vel_y > 0.0
+            || self.free_distance(world, -up(), GROUND_DIST) >= GROUND_DIST
+        {
+            return None;
+        }
+        let mat = self.ground(world)?;
+        let under = self.pos() - up() * (EYE_HEIGHT + GROUND_DIST * 0.5);
+        Some((under.map(|x| x.floor() + 0.5), mat))
+    }
+
+    /// If moving `dist` along `dir` is blocked by a ledge no higher than `step_height`, climbs onto it and makes the move.
+    /// Returns whether it did.
+    fn step_up(&mut self, world: &crate::world::World, dir: Vector3<f32>, dist: f32) -> bool {
+        let old = self.pos;
+        // A bit over, so we aren't scraping the top of the ledge on the way over it
+        let height = self.walk.step_height + GROUND_DIST;
+        let h = self.free_distance(world, up(), height);
+        self.pos += (up() * h).map(|x| x as f64);
+        if self.free_distance(world, dir, dist) < dist {
+            // It's taller than that, or there isn't room above it
+            self.pos = old;
+            return false;
+        }
+        self.pos += (dir * dist).map(|x| x as f64);
+        let down = self.free_distance(world, -up(), h);
+        self.pos -= (up() * down).map(|x| x as f64);
+        true
+    }
+
+    /// One fixed step of walking physics
+    fn walk_step(&mut self, dt: f32, world: &crate::world::World) {
+        let grounded =
+            self.vel_y <= 0.0 && self.free_distance(world, -up(), GROUND_DIST) < GROUND_DIST;
+        if grounded {
+            self.vel_y = 0.0;
+            self.since_ground = 0.0;
+            self.air_jumps_left = self.walk.air_jumps;
+        } else {
+            self.since_ground += dt;
+        }
+
+        if std::mem::replace(&mut self.jump, false) {
+            // Just after walking off an edge still counts as being on the ground
+            if self.since_ground <= self.walk.coyote_time {
+                self.vel_y = self.walk.jump_speed;
+                self.since_ground = f32::INFINITY;
+            } else if self.air_jumps_left > 0 {
+                self.air_jumps_left -= 1;
+                self.vel_y = self.walk.jump_speed;
+            }
+        }
+        if !grounded || self.vel_y > 0.0 {
+            self.vel_y -= self.walk.gravity * dt;
+        }
+
+        // Walk along the ground in the direction we're looking, whether or not we're looking up or down
+        let forward = (self.dir - up() * self.dir.dot(&up()))
+            .try_normalize(0.0001)
+            .unwrap_or_else(Vector3::z);
+        let right = forward.cross(&up());
+        let target = (forward * self.moving.z + right * self.moving.x)
+            * self.walk.speed
+            * self.speed_factor();
+        let grip = if grounded {
+            self.ground(world).map_or(1.0, Material::friction)
+        } else {
+            AIR_GRIP
+        };
+        self.vel_h += (target - self.vel_h) * (GRIP_RATE * grip * dt).min(1.0);
+        let v = self.vel_h + up() * self.vel_y;
+
+        // One axis at a time, so walking into a wall slides along it instead of stopping
+        for axis in 0..3 {
+            let d = v[axis] * dt;
+            if d == 0.0 {
+                continue;
+            }
+            let mut dir = Vector3::zeros();
+            dir[axis] = d.signum();
+            let t = self.free_distance(world, dir, d.abs());
+            if t < d.abs() && axis != UP_AXIS {
+                if grounded && self.walk.step_height > 0.0 && self.step_up(world, dir, d.abs()) {
+                    continue;
+                }
+                // Stop pushing into the wall, or we'd keep sliding into it on ice
+                self.vel_h[axis] = 0.0;
+            }
+            self.pos[axis] += (dir[axis] * t) as f64;
+            if axis == UP_AXIS && t < d.abs() {
+                // Hit the ground or the ceiling
+                self.vel_y = 0.0;
+            }
+        }
     }
 
-    pub fn push(&self, origin: [f32; 3], root_size: f32, sun_dir: [f32; 3]) -> PushConstants {
+    /// Positions in the push constants are relative to `render_origin`, including `origin`, the octree origin in world space.
+    /// `camera_mat` is the material the camera is inside of, which tints everything if it's transparent
+    pub fn push(
+        &self,
+        render_origin: &RenderOrigin,
+        origin: Vector3<f32>,
+        root_size: f32,
+        sun_dir: [f32; 3],
+        camera_mat: u32,
+    ) -> PushConstants {
         PushConstants {
-            fov: self.fov,
+            fov: self.fov(),
             resolution: [self.resolution.0 as f32, self.resolution.1 as f32],
-            camera_pos: [self.pos.x, self.pos.y, self.pos.z],
+            camera_pos: render_origin.local(self.pos.coords).into(),
             camera_dir: self.dir.into(),
             camera_up: self.up.into(),
-            origin,
+            origin: render_origin.local(origin.map(|x| x as f64)).into(),
             root_size,
             sun_dir,
+            camera_mat,
+            // These come from the config, so the client fills them in
+            traversal: 0,
+            debug_mode: 0,
+            wrap: 0,
             _dummy0: [0; 4],
             _dummy1: [0; 4],
             _dummy2: [0; 4],
@@ -67,8 +377,171 @@ impl Camera {
         }
     }
 
+    /// Goes from positions relative to `render_origin`, like the ones in `push()`, to view space,
+    /// where x is right on the screen, y is down and z is forward, like Vulkan's clip space.
+    /// Use `RenderOrigin(Vector3::zeros())` for world space, if it's close enough to the origin for single precision.
+    pub fn view_matrix(&self, render_origin: &RenderOrigin) -> na::Matrix4<f32> {
+        // `main.frag` calls `cross(camera_up, camera_dir)` right, but it flips the screen coordinates too,
+        // so this is what actually ends up on the right
+        let right = self.dir.cross(&self.up).normalize();
+        let down = -self.up;
+        let rot =
+            na::Matrix3::from_rows(&[right.transpose(), down.transpose(), self.dir.transpose()]);
+        let pos = render_origin.local(self.pos.coords);
+        rot.to_homogeneous().append_translation(&(-rot * pos))
+    }
+
+    /// A Vulkan perspective projection with the shader's field of view and aspect ratio.
+    /// Depth goes from 0 at `near` to 1 at `far`; `near` is at least `MIN_PROJECTION_NEAR`, since it can't be 0 here.
+    pub fn projection_matrix(&self) -> na::Matrix4<f32> {
+        self.projection_with_aspect(self.aspect())
+    }
+
+    /// Width / height of the window
+    pub fn aspect(&self) -> f32 {
+        (self.resolution.0 / self.resolution.1) as f32
+    }
+
+    /// Like `projection_matrix()`, but for an image with a different aspect ratio than the window, like in `Client::render_frame()`
+    pub fn projection_with_aspect(&self, aspect: f32) -> na::Matrix4<f32> {
+        let (near, far) = self.depth_range();
+        // `main.frag` scales the screen by this, which is half the height of the image at a distance of 1
+        let film_height = (self.fov() * 0.5).tan();
+        let mut m = na::Matrix4::zeros();
+        m[(0, 0)] = 1.0 / (film_height * aspect);
+        m[(1, 1)] = 1.0 / film_height;
+        m[(2, 2)] = far / (far - near);
+        m[(2, 3)] = -far * near / (far - near);
+        m[(3, 2)] = 1.0;
+        m
+    }
+
+    /// The near and far planes `projection_matrix()` uses, which `main.frag` needs to write matching depth
+    pub fn depth_range(&self) -> (f32, f32) {
+        let near = self.near.max(MIN_PROJECTION_NEAR);
+        (near, self.far.max(near * 2.0))
+    }
+
+    /// `projection_matrix() * view_matrix(render_origin)`, which takes positions relative to `render_origin` to clip space
+    pub fn view_proj(&self, render_origin: &RenderOrigin) -> na::Matrix4<f32> {
+        self.projection_matrix() * self.view_matrix(render_origin)
+    }
+
+    /// Moves the camera to `pos`, without changing where it's looking. Any falling stops.
+    pub fn teleport(&mut self, pos: Vector3<f32>) {
+        self.pos = Point3::from(pos.map(|x| x as f64));
+        self.vel_y = 0.0;
+        self.vel_h = Vector3::zeros();
+        self.physics_time = 0.0;
+    }
+
+    /// Puts the camera at `pos`, looking in the direction given by `yaw` and `pitch` in radians
+    pub fn set_view(&mut self, pos: Vector3<f32>, yaw: f64, pitch: f64) {
+        self.pos = Point3::from(pos.map(|x| x as f64));
+        self.rx = yaw;
+        self.ry = pitch;
+        self.look();
+    }
+
+    /// The vertical field of view, in radians, with the sprint kick toward `sprint_fov` if it's wider
+    pub fn fov(&self) -> f32 {
+        let sprint = self.walk.sprint_fov;
+        if sprint <= self.fov {
+            return self.fov;
+        }
+        self.fov + (sprint - self.fov) * self.sprint_kick
+    }
+
+    /// Puts the camera where `view` says, with its FOV too
+    pub fn set_path_view(&mut self, view: View) {
+        self.set_view(view.pos.into(), view.yaw, view.pitch);
+        self.fov = view.fov;
+    }
+
+    /// A keyframe of where the camera is now, at `time` seconds along a path
+    pub fn keyframe(&self, time: f64) -> Keyframe {
+        Keyframe {
+            time,
+            pos: self.pos().into(),
+            yaw: self.rx.to_degrees(),
+            pitch: self.ry.to_degrees(),
+            fov: self.fov.to_degrees(),
+        }
+    }
+
+    /// Flies along `path` from the start, ignoring input until it gets to the end
+    pub fn play(&mut self, path: CameraPath) {
+        self.vel_y = 0.0;
+        self.vel_h = Vector3::zeros();
+        self.moving = Vector3::zeros();
+        self.sprint_kick = 0.0;
+        self.playback = Some((path, 0.0));
+    }
+
+    /// Stops playing a path, leaving the camera wherever it got to
+    pub fn stop(&mut self) {
+        self.playback = None;
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playback.is_some()
+    }
+
+    /// Updates `dir` and `up` from `rx` and `ry`
+    /// Replaces `mouse` with how much of the mouse movement so far to turn by in a frame of `delta` seconds,
+    /// and keeps the rest for later frames
+    fn smooth_mouse(&mut self, delta: f64) {
+        let (x, y) = (
+            self.smoothed.0 + self.mouse.0,
+            self.smoothed.1 + self.mouse.1,
+        );
+        let f = 1.0 - (-delta / self.smoothing).exp();
+        self.mouse = (x * f, y * f);
+        self.smoothed = (x - self.mouse.0, y - self.mouse.1);
+        // Otherwise it'd keep turning by ever tinier amounts forever
+        if self.smoothed.0.abs() < SMOOTHING_EPSILON && self.smoothed.1.abs() < SMOOTHING_EPSILON {
+            self.mouse = (x, y);
+            self.smoothed = (0.0, 0.0);
+        }
+    }
+
+    fn look(&mut self) {
+        self.ry = na::clamp(
+            self.ry,
+            0.01 - std::f64::consts::FRAC_PI_2,
+            -0.01 + std::f64::consts::FRAC_PI_2,
+        );
+        // Turning left and right is around the world's up, and then looking up and down is around the camera's right
+        self.dir =
+            na::UnitQuaternion::from_axis_angle(&na::Unit::new_unchecked(up()), self.rx as f32)
+                * na::UnitQuaternion::from_axis_angle(
+                    &na::Unit::new_unchecked(na::Vector3::x()),
+                    self.ry as f32,
+                )
+                * na::Vector3::z();
+        self.up =
+            na::UnitQuaternion::from_axis_angle(&na::Unit::new_unchecked(up()), self.rx as f32)
+                * na::UnitQuaternion::from_axis_angle(
+                    &na::Unit::new_unchecked(na::Vector3::x()),
+                    self.ry as f32,
+                )
+                * na::Vector3::y();
+    }
+
     pub fn process(&mut self, event: &Event) {
         match event {
+            Event::KeyPressed(k) if *k == self.walk.walk_key => {
+                self.walking = !self.walking;
+                self.vel_y = 0.0;
+                self.vel_h = Vector3::zeros();
+                self.physics_time = 0.0;
+                info!("{}", if self.walking { "Walking" } else { "Flying" });
+            }
+            Event::KeyPressed(k) if *k == self.walk.jump_key && self.walking => {
+                self.jump = true;
+            }
+            Event::KeyPressed(k) if *k == self.walk.sprint_key => self.sprint = true,
+            Event::KeyReleased(k) if *k == self.walk.sprint_key => self.sprint = false,
             // /*w*/ my layout
             Event::KeyPressed(/*0x11*/ 52) => {
                 self.moving.z = 1.0;
@@ -92,32 +565,502 @@ impl Camera {
                 self.moving.x = 0.0;
             }
             Event::Mouse(x, y) => {
-                self.rx -= SENSITIVITY * x / self.resolution.0;
-                self.ry += SENSITIVITY * y / self.resolution.1;
-                self.ry = na::clamp(
-                    self.ry,
-                    0.01 - std::f64::consts::FRAC_PI_2,
-                    -0.01 + std::f64::consts::FRAC_PI_2,
-                );
-                self.dir = na::UnitQuaternion::from_axis_angle(
-                    &na::Unit::new_unchecked(na::Vector3::y()),
-                    self.rx as f32,
-                ) * na::UnitQuaternion::from_axis_angle(
-                    &na::Unit::new_unchecked(na::Vector3::x()),
-                    self.ry as f32,
-                ) * na::Vector3::z();
-                self.up = na::UnitQuaternion::from_axis_angle(
-                    &na::Unit::new_unchecked(na::Vector3::y()),
-                    self.rx as f32,
-                ) * na::UnitQuaternion::from_axis_angle(
-                    &na::Unit::new_unchecked(na::Vector3::x()),
-                    self.ry as f32,
-                ) * na::Vector3::y();
+                self.mouse.0 += x;
+                self.mouse.1 += y;
             }
-            Event::Resize(x, y) => {
+            // Minimized windows are 0 by 0, which has no aspect ratio, so we keep the last real size until it's back
+            Event::Resize(x, y) if *x >= 1.0 && *y >= 1.0 => {
                 self.resolution = (*x, *y);
             }
             _ => {}
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Moves the mouse by `total` over one second at `fps`, with `per_frame` events each frame
+    fn look_around(fps: usize, per_frame: usize, total: (f64, f64)) -> Camera {
+        smoothed_look_around(0.0, fps, per_frame, total)
+    }
+
+    /// Like `look_around()`, with `mouse_smoothing` set to `smoothing`
+    fn smoothed_look_around(
+        smoothing: f32,
+        fps: usize,
+        per_frame: usize,
+        total: (f64, f64),
+    ) -> Camera {
+        let mut cam = Camera::new((1920.0, 1080.0));
+        cam.configure(
+            &ClientConfig {
+                mouse_smoothing: smoothing,
+                ..ClientConfig::default()
+            },
+            true,
+        );
+        let events = (fps * per_frame) as f64;
+        for _ in 0..fps {
+            for _ in 0..per_frame {
+                cam.process(&Event::Mouse(total.0 / events, total.1 / events));
+            }
+            cam.update(1.0 / fps as f64, &crate::world::World::new());
+        }
+        cam
+    }
+
+    #[test]
+    fn look_speed_ignores_frame_rate() {
+        let total = (1500.0, -400.0);
+        let slow = look_around(30, 1, total);
+        let fast = look_around(300, 1, total);
+        // Lots of small events in each frame, like a high polling rate mouse
+        let many = look_around(30, 40, total);
+        for cam in &[&fast, &many] {
+            assert!((cam.dir - slow.dir).norm() < 1e-4);
+            assert!((cam.up - slow.up).norm() < 1e-4);
+        }
+        assert!((slow.dir - Vector3::z()).norm() > 0.1);
+    }
+
+    #[test]
+    fn survives_degenerate_resizes() {
+        let mut cam = Camera::new((0.0, 0.0));
+        cam.configure(&ClientConfig::default(), true);
+        let sizes = [
+            (800.0, 600.0),
+            (0.0, 0.0),
+            (1.0, 0.0),
+            (0.0, 1.0),
+            (1.0, 1.0),
+            (100_000.0, 1.0),
+            (0.0, 0.0),
+            (1280.0, 720.0),
+            (0.0, 0.0),
+        ];
+        for &(x, y) in &sizes {
+            cam.process(&Event::Resize(x, y));
+            let aspect = cam.aspect();
+            assert!(aspect.is_finite() && aspect > 0.0, "{:?}", (x, y));
+            assert!(cam.projection_matrix().iter().all(|x| x.is_finite()));
+        }
+        // Back to the last real size
+        assert!((cam.aspect() - 16.0 / 9.0).abs() < 1e-6);
+
+        // Looking around while it's minimized doesn't break anything either
+        cam.process(&Event::Mouse(10.0, -5.0));
+        cam.update(1.0 / 60.0, &crate::world::World::new());
+        assert!(cam.dir.iter().all(|x| x.is_finite()));
+        assert!((cam.dir - Vector3::z()).norm() > 0.0);
+    }
+
+    #[test]
+    fn smoothing_off_is_unchanged() {
+        let mut cam = Camera::new((1920.0, 1080.0));
+        cam.configure(&ClientConfig::default(), true);
+        cam.process(&Event::Mouse(123.0, -45.0));
+        cam.update(1.0 / 60.0, &crate::world::World::new());
+        // Exactly what it turned by before there was smoothing, all in the first frame
+        assert_eq!(cam.rx, -SENSITIVITY * 123.0 / 1920.0);
+        assert_eq!(cam.ry, SENSITIVITY * -45.0 / 1080.0);
+        assert_eq!(cam.smoothed, (0.0, 0.0));
+        let rx = cam.rx;
+        cam.update(1.0 / 60.0, &crate::world::World::new());
+        assert_eq!(cam.rx, rx);
+    }
+
+    #[test]
+    fn smoothing_glides_to_the_same_place() {
+        let total = (1500.0, -400.0);
+        let sharp = look_around(60, 1, total);
+        let mut cam = Camera::new((1920.0, 1080.0));
+        cam.configure(
+            &ClientConfig {
+                mouse_smoothing: 0.05,
+                ..ClientConfig::default()
+            },
+            true,
+        );
+        cam.process(&Event::Mouse(100.0, 0.0));
+        cam.update(1.0 / 60.0, &crate::world::World::new());
+        // Only part of the way there after the first frame
+        assert!(cam.rx < 0.0 && cam.rx > -SENSITIVITY * 100.0 / 1920.0);
+
+        // Smoothed movement ends up where it would have without it, at any frame rate, once it's caught up
+        for &fps in &[30, 144] {
+            let mut cam = smoothed_look_around(0.02, fps, 1, total);
+            for _ in 0..fps {
+                cam.update(1.0 / fps as f64, &crate::world::World::new());
+            }
+            assert!((cam.dir - sharp.dir).norm() < 1e-4);
+            assert_eq!(cam.smoothed, (0.0, 0.0));
+        }
+    }
+
+    #[test]
+    fn look_waits_for_update() {
+        let mut cam = Camera::new((1920.0, 1080.0));
+        cam.process(&Event::Mouse(100.0, 0.0));
+        assert_eq!(cam.dir, Vector3::z());
+        cam.update(0.0, &crate::world::World::new());
+        assert!(cam.dir != Vector3::z());
+    }
+
+    /// Where `view_proj()` puts `p`, in normalized device coordinates
+    fn project(cam: &Camera, origin: &RenderOrigin, p: Vector3<f32>) -> Vector3<f32> {
+        let clip = cam.view_proj(origin) * na::Vector4::new(p.x, p.y, p.z, 1.0);
+        clip.xyz() / clip.w
+    }
+
+    #[test]
+    fn projects_points() {
+        let mut cam = Camera::new((1920.0, 1080.0));
+        cam.configure(
+            &ClientConfig {
+                near: 1.0,
+                far: 101.0,
+                ..ClientConfig::default()
+            },
+            true,
+        );
+        // At (1, 1, 1) looking down +z with +y up, so +x is on the left; the fov is 90 degrees vertically
+        let p = project(
+            &cam,
+            &RenderOrigin(Vector3::zeros()),
+            Vector3::new(3.0, 2.0, 5.0),
+        );
+        // x: -2 / (4 * tan(45) * 16/9), y: -1 / (4 * tan(45)), depth: 101/100 * (1 - 1/4)
+        let expected = Vector3::new(-0.28125, -0.25, 0.7575);
+        assert!((p - expected).norm() < 1e-5, "{}", p);
+    }
+
+    /// The direction `main.frag` sends the ray for this pixel
+    fn shader_ray(cam: &Camera, ndc: [f32; 2]) -> Vector3<f32> {
+        let aspect = cam.aspect();
+        let uv = -Vector3::new(ndc[0] * aspect, ndc[1], 0.0);
+        let right = cam.up.cross(&cam.dir).normalize();
+        let film_width = (cam.fov * 0.5).tan();
+        (cam.dir + film_width * cam.up * uv.y + film_width * right * uv.x).normalize()
+    }
+
+    #[test]
+    fn matrices_match_shader() {
+        let mut cam = Camera::new((1280.0, 720.0));
+        cam.set_view(Vector3::new(1e6, 40.0, -3e5), 2.1, -0.4);
+        let origin = RenderOrigin::new(cam.pos.coords);
+        let eye = origin.local(cam.pos.coords);
+        for &ndc in &[[0.0, 0.0], [0.5, -0.25], [-0.9, 0.8], [1.0, 1.0]] {
+            let p = eye + shader_ray(&cam, ndc) * 20.0;
+            let q = project(&cam, &origin, p);
+            assert!(
+                (q.x - ndc[0]).abs() < 1e-3 && (q.y - ndc[1]).abs() < 1e-3,
+                "{:?} went to {}",
+                ndc,
+                q
+            );
+        }
+    }
+
+    /// Where the camera ends up after holding `key` for a second
+    fn moved_by(key: u32, walking: bool, yaw: f64) -> Vector3<f32> {
+        let (mut cam, world) = on_floor(&ClientConfig::default());
+        cam.walking = walking;
+        // Standing on the floor, so walking doesn't fall
+        let start = Vector3::new(8.0, 4.0 + EYE_HEIGHT + 0.01, 8.0);
+        cam.set_view(start, yaw, 0.0);
+        cam.process(&Event::KeyPressed(key));
+        for _ in 0..60 {
+            cam.update(1.0 / 60.0, &world);
+        }
+        cam.pos() - start
+    }
+
+    #[test]
+    fn movement_keys() {
+        // With y up and looking down +z, right is -x
+        let dirs = [
+            (52, Vector3::z()),
+            (18, -Vector3::z()),
+            (24, Vector3::x()),
+            (22, -Vector3::x()),
+        ];
+        for &walking in &[false, true] {
+            let speed = if walking {
+                ClientConfig::default().walk_speed
+            } else {
+                MOVE_SPEED
+            };
+            for &(key, dir) in &dirs {
+                let moved = moved_by(key, walking, 0.0);
+                assert!(
+                    (moved.normalize() - dir).norm() < 0.01
+                        && (moved.norm() - speed).abs() < speed * 0.05,
+                    "Key {} moved {} while {}",
+                    key,
+                    moved,
+                    if walking { "walking" } else { "flying" }
+                );
+            }
+        }
+        // Turning left a quarter turn, forward is +x
+        let moved = moved_by(52, false, std::f64::consts::FRAC_PI_2);
+        assert!(
+            (moved.normalize() - Vector3::x()).norm() < 0.01,
+            "{}",
+            moved
+        );
+    }
+
+    /// A camera walking above one chunk of `ground`
+    fn on_ground(
+        config: &ClientConfig,
+        ground: impl Fn(Vector3<usize>) -> Material,
+    ) -> (Camera, crate::world::World) {
+        let mut world = crate::world::World::new();
+        world.add_chunk(Vector3::zeros(), Chunk::from_voxels(ground));
+        let mut cam = Camera::new((1920.0, 1080.0));
+        cam.configure(config, true);
+        cam.walking = true;
+        cam.pos = Point3::new(8.0, 10.0, 8.0);
+        (cam, world)
+    }
+
+    /// A camera walking on a floor whose top is at y = 4
+    fn on_floor(config: &ClientConfig) -> (Camera, crate::world::World) {
+        on_ground(config, |p| {
+            if p.y < 4 {
+                Material::Stone
+            } else {
+                Material::Air
+            }
+        })
+    }
+
+    /// Lands, and then walks towards +x for `secs` seconds
+    fn walk_x(cam: &mut Camera, world: &crate::world::World, secs: f64) {
+        for _ in 0..120 {
+            cam.update(1.0 / 60.0, world);
+        }
+        cam.rx = std::f64::consts::FRAC_PI_2;
+        cam.look();
+        cam.process(&Event::KeyPressed(52));
+        for _ in 0..(secs * 60.0) as usize {
+            cam.update(1.0 / 60.0, world);
+        }
+    }
+
+    #[test]
+    fn knows_what_its_standing_on() {
+        let (mut cam, world) = on_floor(&ClientConfig::default());
+        // Still falling
+        assert_eq!(cam.standing_on(&world), None);
+        for _ in 0..120 {
+            cam.update(1.0 / 60.0, &world);
+        }
+        let (pos, mat) = cam.standing_on(&world).unwrap();
+        assert_eq!(mat, Material::Stone);
+        assert!((cam.pos().y - EYE_HEIGHT - pos.y - 0.5).abs() < GROUND_DIST);
+        cam.walking = false;
+        assert_eq!(cam.standing_on(&world), None);
+    }
+
+    #[test]
+    fn steps_up_ledges() {
+        // The floor, with a ledge from x = 10 on that's `height` voxels high
+        let ledge = |height: usize| {
+            move |p: Vector3<usize>| {
+                if p.y < 4 || (p.y < 4 + height && p.x >= 10) {
+                    Material::Stone
+                } else {
+                    Material::Air
+                }
+            }
+        };
+        let config = ClientConfig::default();
+        let (mut cam, world) = on_ground(&config, ledge(1));
+        walk_x(&mut cam, &world, 1.0);
+        assert!(cam.pos.x > 12.0, "Stopped at {}", cam.pos.x);
+        assert!((eye_height(&cam) - 1.0).abs() < GROUND_DIST);
+
+        // Too high
+        let (mut cam, world) = on_ground(&config, ledge(2));
+        walk_x(&mut cam, &world, 1.0);
+        assert!(cam.pos.x < 10.0);
+        assert!(eye_height(&cam).abs() < GROUND_DIST);
+
+        // Turned off
+        let config = ClientConfig {
+            step_height: 0.0,
+            ..ClientConfig::default()
+        };
+        let (mut cam, world) = on_ground(&config, ledge(1));
+        walk_x(&mut cam, &world, 1.0);
+        assert!(cam.pos.x < 10.0);
+    }
+
+    #[test]
+    fn ice_is_slippery() {
+        // How far we slide after letting go of the key
+        let slide = |mat: Material| {
+            let (mut cam, world) = on_ground(&ClientConfig::default(), move |p| {
+                if p.y < 4 {
+                    mat
+                } else {
+                    Material::Air
+                }
+            });
+            walk_x(&mut cam, &world, 1.0);
+            cam.process(&Event::KeyReleased(52));
+            let stop = cam.pos.x;
+            for _ in 0..60 {
+                cam.update(1.0 / 60.0, &world);
+            }
+            (cam.pos.x - stop) as f32
+        };
+        let stone = slide(Material::Stone);
+        let ice = slide(Material::Ice);
+        assert!(stone < 0.2, "Slid {} on stone", stone);
+        assert!(ice > 1.0, "Slid {} on ice", ice);
+    }
+
+    fn eye_height(cam: &Camera) -> f32 {
+        cam.pos.y as f32 - 4.0 - EYE_HEIGHT
+    }
+
+    #[test]
+    fn falls_and_lands() {
+        let (mut cam, world) = on_floor(&ClientConfig::default());
+        for _ in 0..120 {
+            cam.update(1.0 / 60.0, &world);
+        }
+        assert!(eye_height(&cam).abs() < GROUND_DIST);
+        assert_eq!(cam.vel_y, 0.0);
+    }
+
+    #[test]
+    fn jump_height() {
+        let config = ClientConfig::default();
+        let (mut cam, world) = on_floor(&config);
+        for _ in 0..120 {
+            cam.update(1.0 / 60.0, &world);
+        }
+        cam.process(&Event::KeyPressed(config.keycodes.up));
+        let mut peak: f32 = 0.0;
+        for i in 0..120 {
+            if i == 20 {
+                // Trying again in the air does nothing without air jumps
+                cam.process(&Event::KeyPressed(config.keycodes.up));
+            }
+            cam.update(1.0 / 60.0, &world);
+            peak = peak.max(eye_height(&cam));
+        }
+        assert!((peak - config.jump_height).abs() < 0.1, "peak = {}", peak);
+        assert!(eye_height(&cam).abs() < GROUND_DIST);
+    }
+
+    #[test]
+    fn air_jumps() {
+        let config = ClientConfig {
+            air_jumps: 1,
+            ..ClientConfig::default()
+        };
+        let (mut cam, world) = on_floor(&config);
+        for _ in 0..120 {
+            cam.update(1.0 / 60.0, &world);
+        }
+        cam.process(&Event::KeyPressed(config.keycodes.up));
+        let mut peak: f32 = 0.0;
+        for i in 0..120 {
+            if i == 20 {
+                cam.process(&Event::KeyPressed(config.keycodes.up));
+            }
+            cam.update(1.0 / 60.0, &world);
+            peak = peak.max(eye_height(&cam));
+        }
+        assert!(peak > config.jump_height * 1.5, "peak = {}", peak);
+    }
+
+    #[test]
+    fn plays_paths() {
+        let world = crate::world::World::new();
+        let mut cam = Camera::new((1920.0, 1080.0));
+        cam.set_view(Vector3::new(5.0, 6.0, 7.0), 1.0, 0.5);
+        let start = cam.keyframe(0.0);
+        cam.set_view(Vector3::new(-5.0, 6.0, 7.0), -1.0, 0.0);
+        let mut path = CameraPath::default();
+        path.push(start);
+        path.push(cam.keyframe(1.0));
+
+        cam.play(path);
+        cam.update(0.0, &world);
+        assert!((cam.pos() - Vector3::new(5.0, 6.0, 7.0)).norm() < 1e-4);
+        assert!((cam.rx - 1.0).abs() < 1e-6);
+
+        // Input doesn't do anything while it's playing
+        cam.process(&Event::KeyPressed(52));
+        cam.process(&Event::Mouse(500.0, 0.0));
+        cam.update(0.5, &world);
+        assert!(cam.is_playing());
+        assert!((cam.pos() - Vector3::new(0.0, 6.0, 7.0)).norm() < 1e-4);
+        cam.process(&Event::KeyReleased(52));
+
+        cam.update(0.6, &world);
+        assert!(!cam.is_playing());
+        assert!((cam.pos() - Vector3::new(-5.0, 6.0, 7.0)).norm() < 1e-4);
+        assert!((cam.rx + 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn sprint_widens_the_view() {
+        let config = ClientConfig {
+            sprint_fov: 110.0,
+            ..ClientConfig::default()
+        };
+        let world = crate::world::World::new();
+        // Sprinting for a quarter of a second, at two frame rates
+        let sprint = |fps: usize| {
+            let mut cam = Camera::new((1920.0, 1080.0));
+            cam.configure(&config, true);
+            cam.process(&Event::KeyPressed(config.keycodes.sprint));
+            cam.process(&Event::KeyPressed(52));
+            for _ in 0..fps / 4 {
+                cam.update(1.0 / fps as f64, &world);
+            }
+            cam
+        };
+        let (mut slow, fast) = (sprint(40), sprint(400));
+        assert!((slow.fov() - fast.fov()).abs() < 1e-3);
+        assert!(slow.fov() > radians(105.0) && slow.fov() < radians(110.0));
+        // Faster, too
+        let moved = slow.pos().z - 1.0;
+        assert!((moved - MOVE_SPEED * config.sprint_speed * 0.25).abs() < 0.1);
+
+        // Letting go of the key eases it back
+        slow.process(&Event::KeyReleased(config.keycodes.sprint));
+        slow.update(1.0 / 40.0, &world);
+        assert!(slow.fov() < fast.fov());
+        for _ in 0..30 {
+            slow.update(1.0 / 40.0, &world);
+        }
+        assert!((slow.fov() - radians(90.0)).abs() < 1e-3);
+
+        // A wider view already doesn't get narrower, and a path's field of view wins while it's playing
+        let mut cam = sprint(40);
+        cam.fov = radians(120.0);
+        assert_eq!(cam.fov(), radians(120.0));
+        cam.fov = radians(90.0);
+        let mut path = CameraPath::default();
+        path.push(cam.keyframe(0.0));
+        path.push(cam.keyframe(1.0));
+        cam.play(path);
+        cam.update(0.1, &world);
+        assert!((cam.fov() - radians(90.0)).abs() < 1e-4);
+        // And the keyframes don't record the kick
+        let mut cam = sprint(40);
+        assert!((cam.keyframe(0.0).fov - 90.0).abs() < 1e-3);
+        cam.configure(&ClientConfig::default(), false);
+        assert_eq!(cam.fov(), radians(90.0));
+    }
+}
@@ -0,0 +1,252 @@
+//! Camera paths for flythroughs: keyframes of where the camera is and where it's looking, with a smooth curve through them.
+//! The client records keyframes where the camera is when the `keyframe` key is pressed, and `play_path` flies along them.
+//! Where the camera is only depends on the time along the path, so playing it back is deterministic, which `--bench` relies on.
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// How many numbers each keyframe is interpolated as: position, yaw, pitch and FOV
+const CHANNELS: usize = 6;
+
+fn default_fov() -> f32 {
+    90.0
+}
+
+/// One point on a camera path. Angles are in degrees, so path files are easier to write by hand.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Keyframe {
+    /// In seconds from the start of the path
+    pub time: f64,
+    pub pos: [f32; 3],
+    pub yaw: f64,
+    pub pitch: f64,
+    #[serde(default = "default_fov")]
+    pub fov: f32,
+}
+
+impl Keyframe {
+    fn channels(&self) -> [f64; CHANNELS] {
+        [
+            self.pos[0] as f64,
+            self.pos[1] as f64,
+            self.pos[2] as f64,
+            self.yaw,
+            self.pitch,
+            self.fov as f64,
+        ]
+    }
+}
+
+/// Where the camera is at some point along a path, with angles in radians like `Camera` uses
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct View {
+    pub pos: [f32; 3],
+    pub yaw: f64,
+    pub pitch: f64,
+    pub fov: f32,
+}
+
+impl View {
+    fn from_channels(c: [f64; CHANNELS]) -> Self {
+        View {
+            pos: [c[0] as f32, c[1] as f32, c[2] as f32],
+            yaw: c[3].to_radians(),
+            pitch: c[4].to_radians(),
+            fov: (c[5] as f32).to_radians(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct CameraPath {
+    /// Sorted by time
+    keyframes: Vec<Keyframe>,
+}
+
+impl CameraPath {
+    /// A path through `keyframes`, in order of time
+    pub fn new(mut keyframes: Vec<Keyframe>) -> Self {
+        keyframes.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap());
+        CameraPath { keyframes }
+    }
+
+    /// Where the path for the save in `dir` goes
+    pub fn path(dir: &Path) -> PathBuf {
+        dir.join("camera_path.ron")
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let f = std::fs::File::open(path).map_err(|e| e.to_string())?;
+        let keyframes: Vec<Keyframe> = ron::de::from_reader(f).map_err(|e| e.to_string())?;
+        if keyframes.iter().any(|k| !k.time.is_finite()) {
+            return Err("a keyframe's time isn't a number".to_string());
+        }
+        Ok(CameraPath::new(keyframes))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let s = ron::ser::to_string_pretty(&self.keyframes, Default::default())
+            .map_err(|e| e.to_string())?;
+        std::fs::write(path, s).map_err(|e| e.to_string())
+    }
+
+    pub fn keyframes(&self) -> &[Keyframe] {
+        &self.keyframes
+    }
+
+    /// How long it takes to play, in seconds
+    pub fn duration(&self) -> f64 {
+        self.keyframes.last().map_or(0.0, |k| k.time)
+    }
+
+    /// Adds a keyframe at the end, which should be at least as late as the last one.
+    /// The camera's yaw can be anywhere, so it's moved by whole turns to be as close as possible to the last keyframe's,
+    /// or the path would spin all the way around to get there.
+    pub fn push(&mut self, mut key: Keyframe) {
+        if let Some(last) = self.keyframes.last() {
+            key.time = key.time.max(last.time);
+            key.yaw -= 360.0 * ((key.yaw - last.yaw) / 360.0).round();
+        }
+        self.keyframes.push(key);
+    }
+
+    /// Where the camera is `time` seconds along the path, or `None` if there aren't any keyframes.
+    /// The curve goes through every keyframe, and before the first or after the last it stays there.
+    ///
+    /// This is a Catmull-Rom spline, but with the tangents in units per second,
+    /// so keyframes don't have to be evenly spaced and the camera doesn't suddenly speed up or slow down at them.
+    pub fn sample(&self, time: f64) -> Option<View> {
+        let k = &self.keyframes;
+        let (first, last) = (k.first()?, k.last()?);
+        if k.len() == 1 || time <= first.time {
+            return Some(View::from_channels(first.channels()));
+        }
+        if time >= last.time {
+            return Some(View::from_channels(last.channels()));
+        }
+
+        // The segment `time` is in, from `k[i]` to `k[i + 1]`
+        let i = k
+            .iter()
+            .rposition(|x| x.time <= time)
+            .unwrap()
+            .min(k.len() - 2);
+        let h = k[i + 1].time - k[i].time;
+        let s = (time - k[i].time) / h;
+        let tangent = |j: usize| {
+            let (a, b) = (&k[j.saturating_sub(1)], &k[(j + 1).min(k.len() - 1)]);
+            let (ca, cb) = (a.channels(), b.channels());
+            let dt = b.time - a.time;
+            let mut m = [0.0; CHANNELS];
+            if dt > 0.0 {
+                for c in 0..CHANNELS {
+                    m[c] = (cb[c] - ca[c]) / dt;
+                }
+            }
+            m
+        };
+        let (p0, p1) = (k[i].channels(), k[i + 1].channels());
+        let (m0, m1) = (tangent(i), tangent(i + 1));
+
+        // Cubic Hermite basis
+        let s2 = s * s;
+        let s3 = s2 * s;
+        let h00 = 2.0 * s3 - 3.0 * s2 + 1.0;
+        let h10 = s3 - 2.0 * s2 + s;
+        let h01 = -2.0 * s3 + 3.0 * s2;
+        let h11 = s3 - s2;
+        let mut c = [0.0; CHANNELS];
+        for j in 0..CHANNELS {
+            c[j] = h00 * p0[j] + h10 * h * m0[j] + h01 * p1[j] + h11 * h * m1[j];
+        }
+        Some(View::from_channels(c))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(time: f64, pos: [f32; 3], yaw: f64) -> Keyframe {
+        Keyframe {
+            time,
+            pos,
+            yaw,
+            pitch: 0.0,
+            fov: 90.0,
+        }
+    }
+
+    fn path() -> CameraPath {
+        CameraPath::new(vec![
+            key(0.0, [0.0, 0.0, 0.0], 0.0),
+            key(2.0, [10.0, 0.0, 0.0], 90.0),
+            key(3.0, [10.0, 5.0, 10.0], 180.0),
+            key(6.0, [0.0, 0.0, 20.0], 270.0),
+        ])
+    }
+
+    fn close(a: [f32; 3], b: [f32; 3]) -> bool {
+        a.iter().zip(&b).all(|(a, b)| (a - b).abs() < 1e-3)
+    }
+
+    #[test]
+    fn goes_through_keyframes() {
+        let p = path();
+        for k in p.keyframes() {
+            let v = p.sample(k.time).unwrap();
+            assert!(close(v.pos, k.pos), "{:?} at {}", v.pos, k.time);
+            assert!((v.yaw - k.yaw.to_radians()).abs() < 1e-6);
+        }
+        // It stays at the ends
+        assert_eq!(p.sample(-1.0), p.sample(0.0));
+        assert_eq!(p.sample(100.0), p.sample(6.0));
+        assert_eq!(p.duration(), 6.0);
+        assert_eq!(CameraPath::default().sample(1.0), None);
+    }
+
+    #[test]
+    fn smooth_at_keyframes() {
+        let p = path();
+        let e = 1e-4;
+        for t in &[2.0, 3.0] {
+            let before = p.sample(t - e).unwrap().pos;
+            let at = p.sample(*t).unwrap().pos;
+            let after = p.sample(t + e).unwrap().pos;
+            for i in 0..3 {
+                // The same velocity going in and coming out
+                let v0 = (at[i] - before[i]) as f64 / e;
+                let v1 = (after[i] - at[i]) as f64 / e;
+                assert!((v0 - v1).abs() < 0.05, "{} vs {} at {}", v0, v1, t);
+            }
+        }
+    }
+
+    #[test]
+    fn yaw_takes_the_short_way() {
+        let mut p = CameraPath::default();
+        p.push(key(0.0, [0.0; 3], 350.0));
+        p.push(key(1.0, [0.0; 3], 10.0 + 720.0));
+        // Earlier than the last one, so it's moved to be at the same time
+        p.push(key(0.5, [0.0; 3], 10.0));
+        let yaws: Vec<f64> = p.keyframes().iter().map(|k| k.yaw).collect();
+        assert_eq!(yaws, [350.0, 370.0, 370.0]);
+        assert_eq!(p.keyframes()[2].time, 1.0);
+    }
+
+    #[test]
+    fn save_and_load() {
+        let dir = crate::common::test_dir("camera-path");
+        let file = CameraPath::path(&dir);
+        path().save(&file).unwrap();
+        assert_eq!(CameraPath::load(&file).unwrap(), path());
+
+        // FOV is optional, like in `bench/path.ron`
+        std::fs::write(
+            &file,
+            "[(time: 1.0, pos: (0.0, 1.0, 2.0), yaw: 0.0, pitch: 10.0)]",
+        )
+        .unwrap();
+        assert_eq!(CameraPath::load(&file).unwrap().keyframes()[0].fov, 90.0);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,17 +1,88 @@
 use crate::common::*;
 use crate::config::GameConfig;
+use crate::gen_pool::{pin_current_thread, GenPool};
+use crate::region::Region;
 use crate::terrain::*;
 use crate::world::*;
+use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
 use std::collections::VecDeque;
+use std::hash::{BuildHasher, Hasher};
 use std::sync::mpsc::*;
 use std::sync::Arc;
 
 const CACHE_SIZE: usize = 16;
 
+/// Saved next to the regions, so a save records how it was generated
+#[derive(Serialize, Deserialize)]
+struct WorldInfo {
+    seed: u64,
+    /// Worlds from before the chunk size was configurable are all the default size
+    #[serde(default = "default_chunk_size")]
+    chunk_size: u32,
+}
+
+fn default_chunk_size() -> u32 {
+    DEFAULT_CHUNK_SIZE
+}
+
+/// Picks the seed to generate with: the one in the config, or the saved world's, or a random one.
+/// If we're saving chunks, it's written to `world.ron` so the world keeps using it.
+/// Also returns whether we can load and save chunks, since we can't if the save has a different chunk size.
+fn world_seed(config: &GameConfig) -> (u64, bool) {
+    let mut path = app_dirs2::app_root(app_dirs2::AppDataType::UserData, &crate::APP_INFO).unwrap();
+    path.push("world.ron");
+
+    let saved = if config.save_chunks {
+        std::fs::File::open(&path)
+            .ok()
+            .and_then(|f| ron::de::from_reader::<_, WorldInfo>(f).ok())
+    } else {
+        None
+    };
+    let chunk_size = chunk_size() as u32;
+    if let Some(saved) = &saved {
+        if saved.chunk_size != chunk_size {
+            error!(
+                "The saved world has chunks {} voxels wide, but we're using {}. Its chunks won't be loaded or saved; set chunk_size to {} to play it.",
+                saved.chunk_size, chunk_size, saved.chunk_size
+            );
+            return (config.seed.unwrap_or(saved.seed), false);
+        }
+    }
+    let saved = saved.map(|info| info.seed);
+
+    let seed = match (config.seed, saved) {
+        (Some(seed), Some(saved)) if seed != saved => {
+            warn!(
+                "The saved world was generated with seed {}, but the config says {}. Chunks that were already saved won't change.",
+                saved, seed
+            );
+            seed
+        }
+        (Some(seed), _) => seed,
+        (None, Some(saved)) => saved,
+        // `RandomState` is seeded randomly, so hashing nothing with it gives us a random number
+        (None, None) => std::collections::hash_map::RandomState::new()
+            .build_hasher()
+            .finish(),
+    };
+    info!("World seed is {}", seed);
+
+    if config.save_chunks && saved != Some(seed) {
+        let info = ron::ser::to_string(&WorldInfo { seed, chunk_size }).unwrap();
+        if let Err(e) = std::fs::write(&path, info) {
+            warn!("Couldn't save the world seed to {}: {}", path.display(), e);
+        }
+    }
+    (seed, config.save_chunks)
+}
+
+/// The most recently used regions, so streaming chunks in and out doesn't hit the disk every time.
+/// Regions are written back when they fall out of the cache, and on `flush()`.
 struct RegionCache {
-    indices: VecDeque<(Vector3<i32>, usize)>,
-    regions: Vec<Vec<Option<Vec<u8>>>>,
+    /// Most recently used first
+    regions: VecDeque<(Vector3<i32>, Region)>,
     path: std::path::PathBuf,
 }
 
@@ -25,114 +96,126 @@ impl RegionCache {
         }
 
         RegionCache {
-            indices: VecDeque::new(),
-            regions: Vec::new(),
+            regions: VecDeque::new(),
             path: chunks_path,
         }
     }
 
-    fn _store(&mut self, v: Vector3<i32>, mut region: Vec<Option<Vec<u8>>>) -> usize {
-        if self.indices.len() < CACHE_SIZE {
-            assert_eq!(self.regions.len(), self.indices.len());
-            self.regions.push(region);
-            let i = self.regions.len() - 1;
-            self.indices.push_front((v, i));
-            i
-        } else {
-            let (nv, i) = self.indices.pop_back().unwrap();
-            self.indices.push_front((v, i));
-
-            std::mem::swap(&mut region, &mut self.regions[i]);
-
-            use std::fs::File;
-            use std::io::Write;
-
-            let mut path = self.path.clone();
-            path.push(format!("{},{},{}.region.zst", nv.x, nv.y, nv.z));
-            let f = File::create(path).unwrap();
-            let mut f = zstd::stream::write::Encoder::new(f, 3).unwrap();
-
-            f.write_all(&bincode::serialize(&region).unwrap()).unwrap();
-
-            f.finish().unwrap();
-
-            i
+    fn save(&self, v: Vector3<i32>, region: &Region) {
+        let path = Region::path(&self.path, v);
+        if let Err(e) = region.save(&path) {
+            error!("Failed to save region {}: {}", path.display(), e);
         }
     }
 
-    fn _load(&mut self, v: Vector3<i32>) -> usize {
-        for i in 0..self.indices.len() {
-            if self.indices[i].0 == v {
-                let t = self.indices[i];
-                self.indices.remove(i);
-                self.indices.push_front(t);
-                return t.1;
+    /// Gets a region from the cache, or loads it from disk
+    fn region(&mut self, v: Vector3<i32>) -> &mut Region {
+        if let Some(i) = self.regions.iter().position(|(x, _)| *x == v) {
+            let r = self.regions.remove(i).unwrap();
+            self.regions.push_front(r);
+        } else {
+            let path = Region::path(&self.path, v);
+            let region = Region::load(&path).unwrap_or_else(|e| {
+                // Move it out of the way instead of overwriting it, in case someone wants to recover it
+                let corrupt = path.with_extension("region.corrupt");
+                error!(
+                    "Region {} is unreadable, so its chunks will be regenerated. Moved it to {}. {}",
+                    path.display(),
+                    corrupt.display(),
+                    e
+                );
+                let _ = std::fs::rename(&path, corrupt);
+                Region::empty()
+            });
+
+            if self.regions.len() >= CACHE_SIZE {
+                let (old, region) = self.regions.pop_back().unwrap();
+                if region.dirty {
+                    self.save(old, &region);
+                }
             }
+            self.regions.push_front((v, region));
         }
-
-        // It's not in the cache, so load it from disk
-        let mut path = self.path.clone();
-        path.push(format!("{},{},{}.region.zstd", v.x, v.y, v.z));
-
-        let region: Vec<Option<Vec<u8>>> = if path.exists() {
-            use std::fs::File;
-            use std::io::Read;
-
-            let f = File::open(path).unwrap();
-            let mut f = zstd::stream::read::Decoder::new(f).unwrap();
-
-            let mut buf = Vec::new();
-            f.read_to_end(&mut buf).unwrap();
-
-            bincode::deserialize(&buf).unwrap()
-        } else {
-            (0..REGION_SIZE * REGION_SIZE * REGION_SIZE)
-                .map(|_| None)
-                .collect()
-        };
-
-        self._store(v, region)
+        &mut self.regions[0].1
     }
 
     fn load(&mut self, chunk: Vector3<i32>) -> Option<Chunk> {
         let v = chunk_to_region(chunk);
         let idx = in_region(chunk);
 
-        let ri = self._load(v);
-        if let Some(x) = &self.regions[ri][idx] {
-            bincode::deserialize(x).ok()
-        } else {
+        self.region(v).get(idx).unwrap_or_else(|e| {
+            warn!("Saved chunk {} is corrupt, regenerating it: {}", chunk, e);
             None
-        }
+        })
     }
 
     fn store(&mut self, pos: Vector3<i32>, chunk: Chunk) {
-        let ser = bincode::serialize(&chunk).unwrap();
-
         let v = chunk_to_region(pos);
         let idx = in_region(pos);
 
-        let ri = self._load(v);
-        self.regions[ri][idx] = Some(ser);
+        self.region(v).set(idx, &chunk);
+    }
+
+    /// Saves every region that changed
+    fn flush(&mut self) {
+        for (v, region) in &self.regions {
+            if region.dirty {
+                self.save(*v, region);
+            }
+        }
+        for (_, region) in &mut self.regions {
+            region.dirty = false;
+        }
     }
 }
 
 pub struct ChunkThread {
-    pub gen: Gen,
+    /// Shared with the generation threads
+    pub gen: Arc<Gen>,
+    seed: u64,
+    /// Whether to load and save chunks. This is `save_chunks` from the config, unless the save can't be used.
+    save: bool,
     ch: (Sender<ChunkMessage>, Receiver<ChunkMessage>),
     config: Arc<GameConfig>,
     world: ArcWorld,
 }
 
 impl ChunkThread {
+    /// Saves a copy of every chunk in the world, and returns how many there were.
+    /// The copy is taken all at once, and the server can keep editing the world while we write it out.
+    /// Chunks that haven't been decorated yet are left out, since they'd never get decorated once they're saved.
+    fn save_all(&self, cache: &mut RegionCache, to_decorate: &HashSet<Vector3<i32>>) -> usize {
+        if !self.save {
+            warn!("Not saving, since saving chunks is turned off");
+            return 0;
+        }
+        let snapshot: Vec<(Vector3<i32>, Chunk)> = {
+            let world = self.world.read().unwrap();
+            world
+                .locs()
+                .filter(|p| !to_decorate.contains(p))
+                .filter_map(|&p| Some((p, world.chunk(p)?.clone())))
+                .collect()
+        };
+        let n = snapshot.len();
+        for (p, chunk) in snapshot {
+            cache.store(p, chunk);
+        }
+        cache.flush();
+        n
+    }
+
     pub fn new(
         config: Arc<GameConfig>,
         world: ArcWorld,
         to: Sender<ChunkMessage>,
         from: Receiver<ChunkMessage>,
     ) -> Self {
+        let (seed, save) = world_seed(&config);
         ChunkThread {
-            gen: Gen::new(),
+            gen: Arc::new(Gen::new(&config, seed)),
+            seed,
+            save,
             ch: (to, from),
             config,
             world,
@@ -140,7 +223,13 @@ impl ChunkThread {
     }
 
     pub fn run(self) {
-        let save = self.config.save_chunks;
+        if let Some(core) = self.config.world_thread_core {
+            info!("Pinning the world thread to core {}", core);
+            pin_current_thread(core);
+        }
+        let save = self.save;
+        // The server doesn't pick the seed, so it has to hear it from us
+        self.ch.0.send(ChunkMessage::Seed(self.seed)).unwrap();
 
         let mut cache = RegionCache::new();
 
@@ -154,29 +243,47 @@ impl ChunkThread {
         }
 
         let mut to_load = Vec::new();
+        // Whether the server asked us to stop, and wants to know when we have
+        let mut done = false;
+
+        let gen = Arc::clone(&self.gen);
+        let mut pool = GenPool::new(
+            move |p| gen.gen(p),
+            self.config.gen_thread_count(),
+            &self.config.gen_thread_cores,
+        );
 
         loop {
-            if !to_load.is_empty() {
+            // Hand out chunks to generate nearest first, but only as many as the pool has room for.
+            // Saved chunks don't need generating, so they're ready right away.
+            let mut ready = Vec::new();
+            while pool.has_room() && ready.len() < self.config.batch_size && !to_load.is_empty() {
+                let p = to_load.remove(0);
+                match if save { cache.load(p) } else { None } {
+                    Some(chunk) => ready.push((p, chunk)),
+                    None => {
+                        to_decorate.insert(p);
+                        pool.submit(p);
+                    }
+                }
+            }
+            // Then take up to a batch of finished ones
+            let wait = ready.is_empty();
+            let max = self.config.batch_size - ready.len();
+            // If it's not waiting to be decorated anymore, it went out of range while it was generating
+            ready.extend(pool.finished(max, wait, |p| to_decorate.contains(&p)));
+
+            let busy = !to_load.is_empty() || !pool.is_idle();
+            if !ready.is_empty() {
                 // let timer = Stopwatch::start_new();
                 let (decorate, mut ret): (Vec<_>, _) = {
                     let mut world = self.world.write().unwrap();
-                    to_load
-                        .drain(0..self.config.batch_size.min(to_load.len()))
-                        .map(|p: Vector3<i32>| {
-                            let chunk = if save {
-                                cache.load(p).unwrap_or_else(|| {
-                                    to_decorate.insert(p);
-                                    self.gen.gen(p)
-                                })
-                            } else {
-                                to_decorate.insert(p);
-                                self.gen.gen(p)
-                            };
-
+                    ready
+                        .into_iter()
+                        .map(|(p, chunk)| {
                             world.add_chunk(p, chunk);
                             p
                         })
-                        // So it's not lazy and can borrow to_decorate
                         .collect::<Vec<_>>()
                         .into_iter()
                         .partition(|x| to_decorate.contains(x))
@@ -209,7 +316,9 @@ impl ChunkThread {
                 }
 
                 // println!("Loading took {} ms/chunk, {} ms total", timer.elapsed_ms() as f64 / l as f64, timer.elapsed_ms());
+            }
 
+            if busy {
                 let mut connected = true;
                 let mut sort = Vec::new();
                 loop {
@@ -217,14 +326,20 @@ impl ChunkThread {
                         Ok(ChunkMessage::LoadChunks(mut chunks)) => {
                             to_load.append(&mut chunks);
                         }
-                        Ok(ChunkMessage::UnloadChunk(_, _)) => {
-                            // TODO save chunk
+                        Ok(ChunkMessage::UnloadChunk(p, chunk)) => {
+                            if save {
+                                cache.store(p, chunk);
+                            }
                         }
                         Ok(ChunkMessage::Players(players)) => {
                             sort = players;
                         }
+                        Ok(ChunkMessage::Save) => {
+                            let n = self.save_all(&mut cache, &to_decorate);
+                            self.ch.0.send(ChunkMessage::Saved(n)).unwrap();
+                        }
                         Ok(ChunkMessage::Done) => {
-                            self.ch.0.send(ChunkMessage::Done).unwrap();
+                            done = true;
                             connected = false;
                             break;
                         }
@@ -240,9 +355,8 @@ impl ChunkThread {
                 }
                 if !sort.is_empty() {
                     for chunk in to_decorate.iter().cloned().collect::<Vec<_>>() {
-                        let in_range = sort.iter().any(|y| {
-                            (world_to_chunk(*y) - chunk).map(|x| x as f32).norm()
-                                <= self.config.draw_chunks as f32
+                        let in_range = sort.iter().any(|(y, r)| {
+                            (world_to_chunk(*y) - chunk).map(|x| x as f32).norm() <= *r as f32
                         });
                         if !in_range {
                             self.world.write().unwrap().remove_chunk(chunk);
@@ -251,14 +365,15 @@ impl ChunkThread {
                     }
                     // let timer = Stopwatch::start_new();
                     to_load.retain(|x| {
-                        sort.iter().any(|y| {
-                            (world_to_chunk(*y) - x).map(|x| x as f32).norm()
-                                <= self.config.draw_chunks as f32
+                        sort.iter().any(|(y, r)| {
+                            (world_to_chunk(*y) - x).map(|x| x as f32).norm() <= *r as f32
                         })
                     });
                     to_load.sort_by_cached_key(|x| {
                         let x = chunk_to_world(*x);
-                        sort.iter().map(|y| ((x - y).norm() * 100.0) as usize).min()
+                        sort.iter()
+                            .map(|(y, _)| ((x - y).norm() * 100.0) as usize)
+                            .min()
                     });
                     // println!("Sorting took {} ms for to_load len {}", timer.elapsed().as_micros() as f64 / 1000.0, to_load.len());
                 }
@@ -274,9 +389,25 @@ impl ChunkThread {
                         }
                     }
                     Ok(ChunkMessage::Players(_)) => {}
+                    Ok(ChunkMessage::Save) => {
+                        let n = self.save_all(&mut cache, &to_decorate);
+                        self.ch.0.send(ChunkMessage::Saved(n)).unwrap();
+                    }
+                    Ok(ChunkMessage::Done) => {
+                        done = true;
+                        break;
+                    }
                     _ => break,
                 }
             }
         }
+
+        // Save everything before saying we're done, so the server doesn't exit partway through
+        if save {
+            cache.flush();
+        }
+        if done {
+            let _ = self.ch.0.send(ChunkMessage::Done);
+        }
     }
 }
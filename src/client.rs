@@ -1,18 +1,37 @@
+use crate::brush::Brush;
 use crate::camera::*;
+use crate::camera_path::CameraPath;
 use crate::client_world::*;
 use crate::common::*;
 use crate::config::*;
+use crate::console::{Action, Console};
+use crate::day_clock::DayClock;
 use crate::event::*;
+use crate::lod_bias::LodBias;
+use crate::markers::{MarkerPass, Markers};
+use crate::overlay::{overlay_scale, Overlay, OverlayInfo, OverlayPass};
+use crate::plugin::{call_plugins, compute_plugins, render_plugins, Plugin, WorldBuffers};
+use crate::post::{ease_focus, render_target, Post, RenderTarget, Targets};
+use crate::profile::{mark, Profiler};
+use crate::remote::RemotePlayers;
+use crate::render_scale::DynamicScale;
+use crate::shaders::{self, BeamShader, RaymarchShader, ShaderError};
+use crate::shaders::{FrameData, PushConstants, Settings};
+use crate::thumbnail::{Thumbnail, THUMBNAIL_SIZE};
 use crate::window::*;
+use enum_iterator::IntoEnumIterator;
 use vulkano::command_buffer::DynamicState;
 
 use std::sync::Arc;
-use vulkano::buffer::{BufferUsage, CpuBufferPool, ImmutableBuffer};
+use std::time::{Duration, Instant};
+use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer, CpuBufferPool, ImmutableBuffer};
 use vulkano::command_buffer::{AutoCommandBufferBuilder, CommandBuffer};
 use vulkano::descriptor::descriptor_set::{DescriptorSet, PersistentDescriptorSet};
 use vulkano::descriptor::PipelineLayoutAbstract;
+use vulkano::device::DeviceOwned;
 use vulkano::framebuffer::Subpass;
 use vulkano::image::{AttachmentImage, ImageUsage};
+use vulkano::pipeline::depth_stencil::{Compare, DepthStencil};
 use vulkano::pipeline::{
     vertex::BufferlessDefinition, vertex::BufferlessVertices, GraphicsPipeline,
 };
@@ -22,6 +41,28 @@ use vulkano::sync::GpuFuture;
 use specs::World;
 
 const BEAM_RES_FAC: u32 = 8;
+/// How far past a boundary the camera has to go before it counts as inside the new material, so it doesn't flicker
+const MEDIUM_HYSTERESIS: f32 = 0.05;
+/// The least time between two keyframes on the camera path, in seconds, so pressing the key twice doesn't make the camera jump
+const MIN_KEYFRAME_GAP: f64 = 0.5;
+/// Other players are drawn as a box outline of this color
+const REMOTE_PLAYER_COLOR: [f32; 3] = [1.0, 0.5, 0.1];
+/// Other players' names are drawn this color, this far above their box
+const NAME_COLOR: [f32; 3] = [1.0, 1.0, 1.0];
+const NAME_GAP: f32 = 0.25;
+/// The outline around what the brush covers
+const BRUSH_COLOR: [f32; 3] = [1.0, 1.0, 1.0];
+/// How far outside the voxels the brush outline is, so it doesn't fight with their faces over depth
+const BRUSH_OUTLINE_GAP: f32 = 0.01;
+/// How far the brightness keys move the slider each time
+const BRIGHTNESS_STEP: f32 = 0.1;
+/// How far away the player can edit, in voxels
+const REACH: f32 = 12.0;
+/// The most clicks waiting for their chunks at once, so holding the button at somewhere that isn't loaded doesn't pile them up
+const MAX_PENDING_EDITS: usize = 16;
+/// With `ShadowMode::Baked`, how far the sun moves before the shadows are baked again, in radians.
+/// That's every 4 seconds with 24 minute days.
+const SHADOW_BAKE_ANGLE: f32 = 0.0175;
 
 type BufferlessPipeline = GraphicsPipeline<
     BufferlessDefinition,
@@ -29,32 +70,556 @@ type BufferlessPipeline = GraphicsPipeline<
     Arc<dyn vulkano::framebuffer::RenderPassAbstract + Send + Sync>,
 >;
 
-pub struct Client {
-    tree_buffer: Arc<vulkano::buffer::DeviceLocalBuffer<[u32]>>,
+type TreeBuffer = Arc<vulkano::buffer::DeviceLocalBuffer<[u32]>>;
+type BeamImage = Arc<AttachmentImage<vulkano::format::R16Sfloat>>;
+type MatBuffer = Arc<ImmutableBuffer<[crate::shaders::MatData]>>;
+
+/// The brush `config` starts with
+fn brush(config: &ClientConfig) -> Brush {
+    Brush {
+        shape: config.brush_shape,
+        radius: config.brush_radius,
+    }
+}
+
+/// The shader settings that come from the config, which go in a uniform buffer
+fn shader_settings(config: &ClientConfig) -> Settings {
+    Settings {
+        sky_zenith: config.sky_zenith,
+        sun_size: radians(config.sun_size),
+        sky_horizon: config.sky_horizon,
+        sun_intensity: config.sun_intensity,
+        outline_color: config.outline_color,
+        outline_width: config.outline_width,
+        sun_angular_size: radians(config.sun_angular_size),
+        max_ray_steps: config.max_ray_steps,
+        draw_sky: config.sky as u32,
+        background: [
+            config.clear_color[0],
+            config.clear_color[1],
+            config.clear_color[2],
+        ],
+        _dummy0: [0; 4],
+        near: config.near,
+        far: config.far,
+        chunk_size: chunk_size(),
+        step_falloff_start: config.step_falloff_start,
+        min_step_fraction: config.min_step_fraction,
+        edge_aa: if config.edge_aa {
+            config.edge_aa_strength
+        } else {
+            0.0
+        },
+        shadow_mode: config.shadow_mode as u32,
+        lod_fade: config.lod_fade,
+    }
+}
+
+/// Where the camera path is saved, next to the world
+fn camera_path_file() -> Option<std::path::PathBuf> {
+    match app_dirs2::app_root(app_dirs2::AppDataType::UserData, &crate::APP_INFO) {
+        Ok(dir) => Some(CameraPath::path(&dir)),
+        Err(e) => {
+            warn!("Couldn't find where to keep the camera path: {}", e);
+            None
+        }
+    }
+}
+
+/// The camera path from last time, or an empty one if there isn't one
+fn load_camera_path() -> CameraPath {
+    let file = match camera_path_file() {
+        Some(file) if file.exists() => file,
+        _ => return CameraPath::default(),
+    };
+    match CameraPath::load(&file) {
+        Ok(path) => {
+            info!(
+                "Loaded a camera path with {} keyframes from {}",
+                path.keyframes().len(),
+                file.display()
+            );
+            path
+        }
+        Err(e) => {
+            warn!(
+                "Couldn't load the camera path from {}: {}",
+                file.display(),
+                e
+            );
+            CameraPath::default()
+        }
+    }
+}
+
+fn save_camera_path(path: &CameraPath) {
+    if let Some(file) = camera_path_file() {
+        if let Err(e) = path.save(&file) {
+            warn!("Couldn't save the camera path to {}: {}", file.display(), e);
+        }
+    }
+}
+
+/// Which way the sun is `time` seconds in
+fn sun_dir(time: f64) -> Vector3<f32> {
+    // days / second
+    let sun_speed = 1.0 / (24.0 * 60.0); // a day is 24 minutes
+    Vector3::new(
+        (time * sun_speed * std::f64::consts::PI * 2.0).sin() as f32,
+        (time * sun_speed * std::f64::consts::PI * 2.0).cos() as f32,
+        0.1,
+    )
+    .normalize()
+}
+
+/// How far away whatever's under the crosshair is, for depth of field, or the edge of the view distance if that's sky
+fn focus_distance(config: &ClientConfig, world: &crate::world::World, cam: &Camera) -> f32 {
+    let max_t = (config.view_distance as f32 + 0.5) * chunk_size();
+    let ray_dir = cam.dir.map(|x| if x.abs() < 0.0001 { 0.0001 } else { x });
+    world
+        .raycast(cam.pos(), ray_dir, max_t)
+        .map_or(max_t, |RayCast { t, .. }| t[0])
+}
+
+/// The dynamic resolution controller, if it's turned on
+fn dynamic_scale(config: &ClientConfig) -> Option<DynamicScale> {
+    if config.dynamic_resolution {
+        Some(DynamicScale::new(config))
+    } else {
+        None
+    }
+}
+
+/// The adaptive LOD controller, if it's turned on
+fn lod_bias(config: &ClientConfig) -> Option<LodBias> {
+    if config.adaptive_lod {
+        Some(LodBias::new(config))
+    } else {
+        None
+    }
+}
+
+/// The material whose color is closest to an sRGB color from a .vox palette
+fn nearest_material(color: [u8; 4]) -> Material {
+    let linear = Vector3::new(color[0], color[1], color[2]).map(|x| (x as f32 / 255.0).powf(2.2));
+    Material::into_enum_iter()
+        .filter(|m| !matches!(m, Material::Air | Material::Water | Material::Wrong))
+        .min_by(|a, b| {
+            let dist = |m: &Material| (Vector3::from(m.mat_data().color) - linear).norm_squared();
+            dist(a).partial_cmp(&dist(b)).unwrap()
+        })
+        .unwrap()
+}
+
+/// The voxels of the first model in a .vox file, turned so `up_axis` is up, with the size of the box they're in
+fn vox_voxels(vox: &crate::vox::Vox, up_axis: UpAxis) -> (Vec<([u32; 3], Material)>, [u32; 3]) {
+    let model = &vox.models[0];
+    if vox.models.len() > 1 {
+        warn!(
+            "There are {} models in the file, only importing the first",
+            vox.models.len()
+        );
+    }
+    // Without a palette it'd be MagicaVoxel's default one, which we don't have a copy of
+    let materials: Vec<Material> = match &vox.palette {
+        Some(p) => p.iter().map(|&c| nearest_material(c)).collect(),
+        None => vec![Material::Stone; 256],
+    };
+
+    let voxels = model
+        .voxels
+        .iter()
+        .map(|&(p, i)| {
+            let p = up_axis.grid_pos([p[0] as u32, p[1] as u32, p[2] as u32], model.size);
+            (p, materials[i as usize - 1])
+        })
+        .collect();
+    (voxels, up_axis.grid_size(model.size))
+}
+
+/// The voxels of a scene put together from several .vox models, like `vox_voxels()`
+fn scene_voxels(scene: &crate::vox_scene::AssembledScene) -> (Vec<([u32; 3], Material)>, [u32; 3]) {
+    let materials: Vec<Material> = scene
+        .palette
+        .iter()
+        .map(|c| c.map_or(Material::Stone, nearest_material))
+        .collect();
+    let voxels = scene
+        .voxels
+        .iter()
+        .map(|&(p, i)| (p, materials[i]))
+        .collect();
+    (voxels, scene.size)
+}
+
+/// Places the first model in a .vox file sitting on top of `at`, centered on it, turned so `up_axis` is up.
+/// Voxels outside the loaded chunks are left out. Returns how many voxels were placed.
+fn place_vox(
+    vox: &crate::vox::Vox,
+    up_axis: UpAxis,
+    at: Vector3<f32>,
+    world: &mut crate::world::World,
+    bake_ao: bool,
+    edits: &mut Vec<(Vector3<f32>, Material)>,
+    reupload: &mut Vec<Vector3<i32>>,
+) -> usize {
+    let (voxels, size) = vox_voxels(vox, up_axis);
+    place_voxels(voxels, size, at, world, bake_ao, edits, reupload)
+}
+
+/// Places a scene put together from several .vox models like `place_vox()` places one
+fn place_scene(
+    scene: &crate::vox_scene::AssembledScene,
+    at: Vector3<f32>,
+    world: &mut crate::world::World,
+    bake_ao: bool,
+    edits: &mut Vec<(Vector3<f32>, Material)>,
+    reupload: &mut Vec<Vector3<i32>>,
+) -> usize {
+    let (voxels, size) = scene_voxels(scene);
+    place_voxels(voxels, size, at, world, bake_ao, edits, reupload)
+}
+
+/// Loads the .vox model or scene manifest at `path` for `static_scene` in the config, as the chunks it fills.
+/// It sits on y = 0, centered on the origin, turned so `up_axis` is up if it's a model.
+pub fn load_static_scene(
+    path: &std::path::Path,
+    up_axis: UpAxis,
+) -> Result<Vec<(Vector3<i32>, Chunk)>, String> {
+    let (voxels, size) = if path.extension().map_or(false, |x| x == "ron") {
+        let scene = crate::vox_scene::load_scene(path, up_axis).map_err(|e| e.to_string())?;
+        scene_voxels(&scene)
+    } else {
+        let vox = crate::vox::load_vox(path).map_err(|e| e.to_string())?;
+        vox_voxels(&vox, up_axis)
+    };
+    let min = Vector3::new(-((size[0] / 2) as f32), 0.0, -((size[2] / 2) as f32));
+    let voxels = voxels.into_iter().map(|(p, mat)| {
+        let p = Vector3::new(p[0] as f32, p[1] as f32, p[2] as f32);
+        (min + p + Vector3::repeat(0.5), mat)
+    });
+    Ok(crate::world::chunks_from_voxels(voxels))
+}
+
+/// Puts `voxels`, at positions in a box `size` big, into the world sitting on top of `at`, centered on it.
+/// If octrees don't go down to single voxels, they're downsampled to fit, see `downsample_voxels()`.
+/// Voxels outside the loaded chunks are left out. Returns how many voxels, or downsampled cells, were placed.
+fn place_voxels(
+    voxels: Vec<([u32; 3], Material)>,
+    size: [u32; 3],
+    at: Vector3<f32>,
+    world: &mut crate::world::World,
+    bake_ao: bool,
+    edits: &mut Vec<(Vector3<f32>, Material)>,
+    reupload: &mut Vec<Vector3<i32>>,
+) -> usize {
+    let min = at + Vector3::new(-((size[0] / 2) as f32), 1.0, -((size[2] / 2) as f32));
+    let voxels: Vec<_> = voxels
+        .into_iter()
+        .map(|(p, mat)| {
+            (
+                min + Vector3::new(p[0] as f32, p[1] as f32, p[2] as f32),
+                mat,
+            )
+        })
+        .collect();
+    let voxels = if leaf_size() > 1.0 {
+        downsample_voxels(voxels, leaf_size())
+    } else {
+        voxels
+    };
+    let mut placed = 0;
+    for (pos, mat) in voxels {
+        if world.block(pos).is_none() {
+            continue;
+        }
+        world.set_block(pos, mat);
+        edits.push((pos, mat));
+        let chunk = world_to_chunk(pos);
+        if !reupload.contains(&chunk) {
+            reupload.push(chunk);
+        }
+        placed += 1;
+    }
+    if bake_ao && placed > 0 {
+        let max = min + Vector3::new(size[0] as f32, size[1] as f32, size[2] as f32);
+        for loc in world.bake_ao(min - Vector3::repeat(1.5), max + Vector3::repeat(1.5)) {
+            if !reupload.contains(&loc) {
+                reupload.push(loc);
+            }
+        }
+    }
+    placed
+}
+
+/// Whether right-click can place a block where there's `m`, so a big brush doesn't swallow what's already there
+fn placeable(m: Material) -> bool {
+    m == Material::Air || m == Material::Water
+}
+
+/// Sets each voxel `brush` covers around `center` that `replace` says yes to, going by what's there now, to `mat`.
+/// `dir` is where the camera is looking. Returns the chunks that changed.
+#[allow(clippy::too_many_arguments)]
+fn brush_edit(
+    brush: Brush,
+    bake_ao: bool,
+    world: &mut crate::world::World,
+    center: Vector3<f32>,
+    dir: Vector3<f32>,
+    mat: Material,
+    replace: impl Fn(Material) -> bool,
+    edits: &mut Vec<(Vector3<f32>, Material)>,
+) -> Vec<Vector3<i32>> {
+    let mut modified = Vec::new();
+    for p in brush.voxels(center, dir) {
+        // There's no point sending the server edits that don't change anything
+        if world.block(p).map_or(true, |m| m == mat || !replace(m)) {
+            continue;
+        }
+        world.set_block(p, mat);
+        edits.push((p, mat));
+        let loc = world_to_chunk(p);
+        if !modified.contains(&loc) {
+            modified.push(loc);
+        }
+    }
+    if bake_ao && !modified.is_empty() {
+        // Changing blocks changes the occlusion of everything around them
+        let (min, max) = brush.bounds(center, dir);
+        let r = Vector3::repeat(1.0);
+        for loc in world.bake_ao(min - r, max + r) {
+            if !modified.contains(&loc) {
+                modified.push(loc);
+            }
+        }
+    }
+    modified
+}
+
+/// The voxel a click edits around when the ray from `ro` along `rd` hits `hit`: the one it hit to break blocks,
+/// or for placing them the empty one in front of the face it hit
+fn click_target(ro: Vector3<f32>, rd: Vector3<f32>, hit: &RayCast, breaks: bool) -> Vector3<f32> {
+    let t = if breaks {
+        hit.t[0] + 0.05
+    } else {
+        hit.t[0] - 0.05
+    };
+    (ro + rd * t).map(|x| x.floor() + 0.5)
+}
+
+/// `world_wrap` as the bits of `wrap` in `main.frag`'s push constants
+fn wrap_bits(wrap: [bool; 3]) -> u32 {
+    (0..3).filter(|&i| wrap[i]).map(|i| 1 << i).sum()
+}
+
+/// Creates the descriptor set for the beam shader
+fn beam_desc(
+    pipeline: &Arc<BufferlessPipeline>,
+    tree_buffer: &TreeBuffer,
+) -> Arc<dyn DescriptorSet + Send + Sync> {
+    Arc::new(
+        PersistentDescriptorSet::start(pipeline.layout().descriptor_set_layout(0).unwrap().clone())
+            .add_buffer(tree_buffer.clone())
+            .unwrap()
+            .build()
+            .unwrap(),
+    )
+}
+
+/// Creates the descriptor set for the main shader
+fn main_desc(
+    pipeline: &Arc<BufferlessPipeline>,
+    tree_buffer: &TreeBuffer,
+    beam_image: &BeamImage,
+    sampler: &Arc<Sampler>,
+    mat_buf: &MatBuffer,
+    settings: impl vulkano::buffer::BufferAccess + Send + Sync + 'static,
+    lod_buffer: &TreeBuffer,
+) -> Arc<dyn DescriptorSet + Send + Sync> {
+    Arc::new(
+        PersistentDescriptorSet::start(pipeline.layout().descriptor_set_layout(0).unwrap().clone())
+            .add_buffer(tree_buffer.clone())
+            .unwrap()
+            .add_sampled_image(beam_image.clone(), sampler.clone())
+            .unwrap()
+            .add_buffer(mat_buf.clone())
+            .unwrap()
+            .add_buffer(settings)
+            .unwrap()
+            .add_buffer(lod_buffer.clone())
+            .unwrap()
+            .build()
+            .unwrap(),
+    )
+}
+
+/// Everything the client made with the device, which has to be made again if it's lost
+struct Gpu {
+    tree_buffer: TreeBuffer,
+    lod_buffer: TreeBuffer,
     pipeline: Arc<BufferlessPipeline>,
     desc: Arc<dyn DescriptorSet + Send + Sync>,
+    beam_image: BeamImage,
+    sampler: Arc<Sampler>,
+    mat_buf: MatBuffer,
+    settings_pool: CpuBufferPool<Settings>,
+    frame_pool: CpuBufferPool<FrameData>,
     beam_pipeline: Arc<BufferlessPipeline>,
     beam_framebuffer: Arc<dyn vulkano::framebuffer::FramebufferAbstract + Send + Sync>,
     beam_state: DynamicState,
     beam_desc: Arc<dyn DescriptorSet + Send + Sync>,
+    post: Post,
+    marker_pass: MarkerPass,
+    overlay_pass: OverlayPass,
+}
+
+/// How a frame went, for the callback set with `Client::on_frame()`
+#[derive(Clone, Debug, PartialEq)]
+pub struct FrameStats {
+    /// How long the client's update took on the CPU, in milliseconds, including building and submitting the frame
+    pub cpu_ms: f64,
+    /// How long we waited for the GPU to finish the frame, in milliseconds.
+    /// Only headless clients wait for each frame, so with a window it's `None`.
+    pub gpu_ms: Option<f64>,
+    /// Going by the time since the last frame
+    pub fps: f64,
+    /// How many million pixels a second that is at the window's size, like `debug_info` logs
+    pub mpixels_per_s: f64,
+    pub camera_pos: Vector3<f32>,
+    /// Whether anything was drawn. It isn't while the swapchain is being recreated, or while the GPU is starting again.
+    pub rendered: bool,
+}
+
+/// A voxel the player is looking at or standing on, for things built on top of the client like footstep sounds
+#[derive(Clone, Debug, PartialEq)]
+pub struct MaterialHit {
+    /// The center of the voxel
+    pub pos: Vector3<f32>,
+    pub mat: Material,
+    /// The material's tag from `material_tags` in the config, see `Material::tag()`
+    pub tag: String,
+}
+
+/// A click to edit that pointed at a chunk that wasn't loaded yet, which is made once it is, see `pending_edit_timeout`
+struct PendingEdit {
+    /// Whether it was a left-click, which breaks blocks, instead of a right-click, which places them
+    breaks: bool,
+    /// The ray from the camera when it was clicked
+    ro: Vector3<f32>,
+    rd: Vector3<f32>,
+    /// The chunk it's waiting for
+    chunk: Vector3<i32>,
+    until: Instant,
+}
+
+pub struct Client {
+    gpu: Gpu,
+    /// Lines and triangles to draw over the world next frame, which are cleared after it
+    markers: Markers,
+    /// The other players on the server, and where to draw them
+    remote: RemotePlayers,
+    /// The images `render_frame()` draws through, kept for the next one if it's the same size
+    offscreen: Option<Targets>,
     future: Box<dyn GpuFuture + Send + Sync>,
-    pool: CpuBufferPool<u32>,
     recreate_swapchain: bool,
+    /// The window's scale factor, which the overlay's text is sized by
+    scale_factor: f32,
+    /// We lost the device and made a new one, and we're waiting for `ClientWorld` to upload the world to it.
+    /// Nothing's drawn until then, since everything in `gpu` is from the old device.
+    device_lost: bool,
+    /// The octree origin, in world space
     origin: Vector3<f32>,
+    /// What positions given to the shaders are relative to
+    render_origin: RenderOrigin,
     root_size: f32,
-    chunk_slots: HashMap<Vector3<i32>, (usize, usize)>,
     reader_id: ReaderId<Event>,
+    config: Arc<ClientConfig>,
+    /// The material the camera is inside of
+    medium: Material,
+    /// Where the sun is this frame
+    sun_dir: Vector3<f32>,
+    /// The time the sun goes by, which is the server's in multiplayer
+    day_clock: DayClock,
+    /// Where the sun was when we last sent `SunMoved`, for `ShadowMode::Baked`
+    baked_sun: Option<Vector3<f32>>,
+    plugins: Vec<Box<dyn Plugin>>,
+    /// Slash commands, with any that plugins added
+    console: Console,
+    /// Whether to log the frame rate and camera position every so often.
+    /// This starts out as `debug_info` from the config, and the `debug_info` key toggles it.
+    debug_info: bool,
+    /// Whether depth of field is on. This starts out as `dof` from the config, and the `dof` key toggles it.
+    dof: bool,
+    /// How far away depth of field is focused, which follows the crosshair. It's `None` while that's off,
+    /// so turning it on focuses straight away instead of easing in from wherever it was last time.
+    focus: Option<f32>,
+    /// Where the brightness slider is. This starts out as `brightness` from the config, and the brightness keys move it.
+    brightness: f32,
+    /// Which debug view is showing. This starts out as `debug_mode` from the config, and the `debug_mode` key cycles it.
+    debug_mode: DebugMode,
+    /// The debug overlay, which the `overlay` key shows and hides
+    overlay: Overlay,
+    /// The time and number of frames since we last logged the frame rate
     tot: f64,
+    tot_frames: usize,
+    /// The `render_scale` the HDR target was last made with
+    render_scale: f32,
+    /// Adjusts that based on frame times, if `dynamic_resolution` is on
+    dynamic_scale: Option<DynamicScale>,
+    /// How coarse distant detail is, if `adaptive_lod` is on
+    lod_bias: Option<LodBias>,
+    /// What left-click edits. This starts out as `brush_shape` and `brush_radius` from the config,
+    /// and the `brush_shape` key and scrolling change it.
+    brush: Brush,
+    /// What right-click places. This starts out as `place_material` from the config, and middle-click picks another.
+    material: Material,
+    /// Lines scrolled that haven't added up to a whole one yet
+    scroll: f64,
+    /// The keyframes recorded so far, which are saved whenever they change
+    camera_path: CameraPath,
+    /// When the last keyframe was added, in seconds since the game started
+    last_keyframe: f64,
+    /// We got `Event::Quit`, so we shouldn't draw any more frames
+    quitting: bool,
+    /// Shrinking and writing the thumbnail happens on this thread, so quitting doesn't wait on it until the end
+    thumbnail_thread: Option<std::thread::JoinHandle<()>>,
+    /// Whether we're looking at a static scene, so there's no server to tell where we are
+    static_scene: bool,
+    /// Called at the end of every frame, if it's set, see `on_frame()`
+    on_frame: Option<Box<dyn FnMut(&FrameStats) + Send>>,
+    /// Called once the world around the player is loaded, see `on_world_ready()`
+    on_world_ready: Option<Box<dyn FnOnce(bool) + Send>>,
+    /// With `wait_for_world`, nothing's drawn until `Event::WorldReady`
+    waiting_for_world: bool,
+    /// What's under the crosshair and under the player's feet as of the last frame
+    looking_at: Option<MaterialHit>,
+    standing_on: Option<MaterialHit>,
+    /// Clicks waiting for their chunks to load, oldest first
+    pending_edits: Vec<PendingEdit>,
+    /// How long `draw()` waited for the GPU this frame, if it did
+    gpu_ms: Option<f64>,
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        // Otherwise quitting could cut off the thumbnail halfway through writing it
+        if let Some(t) = self.thumbnail_thread.take() {
+            info!("Waiting for the thumbnail to be saved");
+            if t.join().is_err() {
+                warn!("The thumbnail thread panicked");
+            }
+        }
+    }
 }
 
 #[derive(SystemData)]
 pub struct ClientData<'a> {
     time: Read<'a, Time>,
-    i: Read<'a, FrameNum>,
     win: WriteExpect<'a, Window>,
     cam: WriteExpect<'a, Camera>,
     world: WriteExpect<'a, crate::world::World>,
     channel: Write<'a, EventChannel<Event>>,
+    prof: Write<'a, Profiler>,
 }
 
 impl<'a> System<'a> for Client {
@@ -63,228 +628,534 @@ impl<'a> System<'a> for Client {
     fn run(&mut self, data: Self::SystemData) {
         let ClientData {
             time,
-            i,
             mut win,
             mut cam,
             mut world,
             mut channel,
+            mut prof,
         } = data;
 
+        if self.quitting {
+            return;
+        }
+        let start = self.on_frame.as_ref().map(|_| Instant::now());
+        self.gpu_ms = None;
         let size = win.size();
 
         let delta = time.delta.as_secs_f64();
-        self.tot += delta;
         let time = time.total.as_secs_f64();
 
-        // Average FPS over last 30 frames
-        if i.0 % 30 == 0 {
-            println!(
-                "Main loop at {:.1} Mpixels/s ({:.1} FPS)",
-                size.0 * size.1 * (30.0 / self.tot) / 1_000_000.0,
-                (30.0 / self.tot)
-            );
-            self.tot = 0.0;
-            println!("Camera at {:?}", cam.pos);
+        // Average FPS over the last `debug_info_frames` frames
+        if self.debug_info {
+            self.tot += delta;
+            self.tot_frames += 1;
+            if self.tot_frames >= self.config.debug_info_frames.max(1) {
+                let fps = self.tot_frames as f64 / self.tot;
+                info!(
+                    "Main loop at {:.1} Mpixels/s ({:.1} FPS)",
+                    size.0 * size.1 * fps / 1_000_000.0,
+                    fps
+                );
+                info!("Camera at {:?}", cam.pos);
+                self.tot = 0.0;
+                self.tot_frames = 0;
+            }
+        }
+
+        let ms = delta * 1000.0;
+        let (lod_ms, scale_ms) = match (&self.lod_bias, &self.dynamic_scale) {
+            (Some(l), Some(d)) => crate::lod_bias::share(
+                ms,
+                self.config.target_frame_ms as f64,
+                l.at_max(),
+                d.at_max(),
+            ),
+            _ => (ms, ms),
+        };
+        if let Some(bias) = self.lod_bias.as_mut().and_then(|l| l.frame(lod_ms)) {
+            debug!("LOD bias is now {:.2}", bias);
+        }
+        if let Some(scale) = self.dynamic_scale.as_mut().and_then(|d| d.frame(scale_ms)) {
+            debug!("Render scale is now {:.2}", scale);
+            self.set_render_scale(&win, scale);
         }
 
         self.future.cleanup_finished();
-        if self.recreate_swapchain {
-            if !win.recreate() {
-                // continue
-                return;
+        self.sun_dir = sun_dir(self.day_clock.time(time));
+
+        // Find the material the camera is in, for underwater tint
+        let medium_at = |p| world.block(p).unwrap_or(Material::Air);
+        let here = medium_at(cam.pos());
+        if here != self.medium {
+            let d = up() * MEDIUM_HYSTERESIS;
+            if medium_at(cam.pos() + d) == here && medium_at(cam.pos() - d) == here {
+                self.medium = here;
             }
-            self.recreate_swapchain = false;
         }
 
-        let frame = match win.frame() {
-            Ok(r) => r,
-            Err(vulkano::swapchain::AcquireError::OutOfDate) => {
-                self.recreate_swapchain = true;
-                // continue
-                return;
-            }
-            Err(err) => panic!("{:?}", err),
-        };
+        if self
+            .render_origin
+            .update(cam.pos.coords, self.config.origin_rebase_distance as f64)
+        {
+            debug!("Moved the render origin to chunk {}", self.render_origin.0);
+        }
 
-        // days / second
-        let sun_speed = 1.0 / (24.0 * 60.0); // a day is 24 minutes
-        let sun_dir = Vector3::new(
-            (time * sun_speed * std::f64::consts::PI * 2.0).sin() as f32,
-            (time * sun_speed * std::f64::consts::PI * 2.0).cos() as f32,
-            0.1,
-        )
-        .normalize();
+        // Find the voxel the player is looking at, for the outline and editing
+        let ray_dir = cam.dir.map(|x| if x.abs() < 0.0001 { 0.0001 } else { x });
+        let hit = world.raycast(cam.pos(), ray_dir, REACH);
+        let target = hit
+            .as_ref()
+            .map(|h| click_target(cam.pos(), ray_dir, h, true));
+        let place_target = hit
+            .as_ref()
+            .map(|h| click_target(cam.pos(), ray_dir, h, false));
+        let tags = &self.config.material_tags;
+        self.looking_at = hit.as_ref().map(|h| MaterialHit {
+            pos: click_target(cam.pos(), ray_dir, h, true),
+            mat: h.mat,
+            tag: h.mat.tag(tags),
+        });
+        self.standing_on = cam.standing_on(&world).map(|(pos, mat)| MaterialHit {
+            pos,
+            mat,
+            tag: mat.tag(tags),
+        });
+        if self.dof {
+            self.focus = Some(ease_focus(
+                self.focus,
+                focus_distance(&self.config, &world, &cam),
+                delta as f32,
+                self.config.dof_focus_time,
+            ));
+        }
 
-        let pc = cam.push(self.origin.into(), self.root_size, sun_dir.into());
-        let pc_beam = crate::shaders::BeamConstants {
-            fov: pc.fov,
-            resolution: [
-                (pc.resolution[0] / BEAM_RES_FAC as f32).floor(),
-                (pc.resolution[1] / BEAM_RES_FAC as f32).floor(),
-            ],
-            camera_pos: pc.camera_pos,
-            camera_dir: pc.camera_dir,
-            camera_up: pc.camera_up,
-            origin: pc.origin,
-            root_size: self.root_size,
-            _dummy0: pc._dummy0,
-            _dummy1: pc._dummy1,
-            _dummy2: pc._dummy2,
+        // What right-click would place, which is only drawn; the world doesn't change until the click
+        let preview: Vec<_> = match place_target.filter(|_| self.config.brush_preview) {
+            Some(pos) => self
+                .brush
+                .voxels(pos, cam.dir)
+                .into_iter()
+                .filter(|&p| world.block(p).map_or(false, placeable))
+                .collect(),
+            None => Vec::new(),
         };
 
-        let command_buffer =
-            AutoCommandBufferBuilder::primary_one_time_submit(win.device(), win.queue.family())
-                .unwrap()
-                .begin_render_pass(self.beam_framebuffer.clone(), false, vec![[0.0].into()])
-                .unwrap()
-                .draw(
-                    self.beam_pipeline.clone(),
-                    &self.beam_state,
-                    BufferlessVertices {
-                        vertices: 4,
-                        instances: 1,
-                    },
-                    self.beam_desc.clone(),
-                    pc_beam,
-                )
-                .unwrap()
-                .end_render_pass()
-                .unwrap()
-                .begin_render_pass(frame.framebuffer, false, vec![[0.0, 0.0, 0.0, 1.0].into()])
-                .unwrap()
-                .draw(
-                    self.pipeline.clone(),
-                    &win.dynamic_state,
-                    BufferlessVertices {
-                        vertices: 4,
-                        instances: 1,
-                    },
-                    self.desc.clone(),
-                    pc,
-                )
-                .unwrap()
-                .end_render_pass()
-                .unwrap()
-                .build()
-                .unwrap();
-
-        let mut f: Box<dyn GpuFuture + Send + Sync> = Box::new(vulkano::sync::now(win.device()));
-        std::mem::swap(&mut f, &mut self.future);
-        let f = f
-            .join(frame.acquire)
-            .then_execute(win.queue.clone(), command_buffer)
-            .unwrap()
-            .then_swapchain_present(win.queue.clone(), win.swapchain.clone(), frame.image_num)
-            .then_signal_fence_and_flush();
+        let (render_scale, debug_mode) = (self.render_scale, self.debug_mode);
+        let lod_bias = self.lod_bias.as_ref().map(LodBias::bias);
+        if self.overlay.frame(time, delta, |fps| OverlayInfo {
+            fov: cam.fov(),
+            pos: cam.pos.coords,
+            fps,
+            render_scale,
+            lod_bias,
+            debug_mode,
+        }) {
+            // It'll be here in time for the next update
+            channel.single_write(Event::QueryStats);
+        }
 
-        match f {
-            Ok(f) => {
-                self.future = Box::new(f) as Box<_>;
-            }
-            Err(vulkano::sync::FlushError::OutOfDate) => {
-                self.recreate_swapchain = true;
-                self.future = Box::new(vulkano::sync::now(win.device())) as Box<_>;
-            }
-            Err(err) => {
-                // We'll keep going, it's probably not a big deal
-                println!("{:?}", err);
-                self.future = Box::new(vulkano::sync::now(win.device())) as Box<_>;
+        // While the device is lost there's nothing to draw with, but we still need to see the world coming back,
+        // and the same when we're waiting for the world to load
+        let skip = self.device_lost || self.waiting_for_world;
+        let rendered = !skip && self.draw(&mut win, &cam, time, target, &preview, &mut prof);
+        if !skip && !rendered {
+            if self.device_lost {
+                self.reset_device(&mut win, &mut channel);
             }
+            self.report_frame(start, delta, size, &cam, false);
+            return;
         }
 
-        channel.single_write(Event::PlayerMove(cam.pos()));
-
-        cam.update(delta);
+        // There's no server to load chunks around a static scene's player
+        if !self.static_scene {
+            channel.single_write(Event::PlayerMove(cam.pos()));
+        }
+        if self.config.shadow_mode == ShadowMode::Baked
+            && self
+                .baked_sun
+                .map_or(true, |s| s.angle(&self.sun_dir) > SHADOW_BAKE_ANGLE)
+        {
+            self.baked_sun = Some(self.sun_dir);
+            channel.single_write(Event::SunMoved(self.sun_dir));
+        }
 
+        let span = mark();
+        let mut edits = Vec::new();
+        let mut reupload = Vec::new();
+        // Messages from console commands, for `ClientWorld` to send
+        let mut sent = Vec::new();
         for ev in channel.read(&mut self.reader_id) {
             cam.process(&ev);
+            call_plugins(&mut self.plugins, |p| p.on_event(ev));
 
             match ev {
                 Event::Submit(once) => {
-                    let (cmd, origin, root_size, chunk_slots) =
+                    let upload_span = mark();
+                    let (cmd, origin, root_size, tree_buffer) =
                         once.get().expect("Somebody took the stuff out of Submit!");
+                    self.origin = origin;
+                    self.root_size = root_size;
+                    // If it was made for a device we've since lost, `ClientWorld` is uploading everything again anyway
+                    if !Arc::ptr_eq(cmd.device(), &win.device()) {
+                        continue;
+                    }
+                    // The world changed, so the average of still frames is out of date
+                    self.gpu.post.restart_accumulation();
+
+                    if let Some((tree_buffer, lod_buffer)) = tree_buffer {
+                        if self.device_lost {
+                            // It's the whole world on the new device, so we can draw again
+                            self.rebuild(&win, tree_buffer, lod_buffer);
+                        } else {
+                            // It grew, so the descriptor sets need to point at the new one
+                            self.gpu.tree_buffer = tree_buffer;
+                            self.gpu.lod_buffer = lod_buffer;
+                            self.gpu.beam_desc =
+                                beam_desc(&self.gpu.beam_pipeline, &self.gpu.tree_buffer);
+                            self.update_desc();
+                        }
+                    }
 
                     // This shouldn't be necessary
                     let mut f: Box<dyn GpuFuture + Send + Sync> =
                         Box::new(vulkano::sync::now(win.device()));
                     std::mem::swap(&mut f, &mut self.future);
-                    f.then_signal_fence_and_flush().unwrap().wait(None).unwrap();
-                    self.future = Box::new(cmd.execute(win.queue.clone()).unwrap());
+                    if let Err(e) = f.then_signal_fence_and_flush().and_then(|f| f.wait(None)) {
+                        warn!("Couldn't wait for the GPU: {:?}", e);
+                    }
+                    // It was built for `transfer_queue`, and if that's a different queue,
+                    // the next frame needs a semaphore to wait for it on the graphics queue
+                    let separate = !Arc::ptr_eq(&win.transfer_queue, &win.queue);
+                    let f = cmd
+                        .execute(win.transfer_queue.clone())
+                        .map_err(|e| format!("{:?}", e))
+                        .and_then(|f| {
+                            if separate {
+                                f.then_signal_semaphore_and_flush()
+                                    .map(|f| Box::new(f) as Box<dyn GpuFuture + Send + Sync>)
+                                    .map_err(|e| format!("{:?}", e))
+                            } else {
+                                Ok(Box::new(f) as Box<_>)
+                            }
+                        });
+                    self.future = match f {
+                        Ok(f) => f,
+                        Err(e) => {
+                            warn!("Couldn't upload chunks: {}", e);
+                            Box::new(vulkano::sync::now(win.device()))
+                        }
+                    };
                     // self.future = Box::new(f.then_execute(win.queue.clone(), cmd).unwrap());
-
-                    self.origin = origin;
-                    self.root_size = root_size;
-                    self.chunk_slots = chunk_slots;
+                    prof.record("world-upload", upload_span);
                 }
                 Event::Resize(_, _) => self.recreate_swapchain = true,
-                Event::Quit => (),
+                // This is only a flag, so with the `Resize` that usually comes too, it's still made again once
+                Event::ScaleFactorChanged(scale_factor) => {
+                    debug!("The window's scale factor is {} now", scale_factor);
+                    self.scale_factor = *scale_factor;
+                    self.recreate_swapchain = true;
+                }
+                Event::KeyPressed(k) if *k == self.config.keycodes.debug_info => {
+                    self.debug_info = !self.debug_info;
+                    self.tot = 0.0;
+                    self.tot_frames = 0;
+                    info!(
+                        "Turned debug info {}",
+                        if self.debug_info { "on" } else { "off" }
+                    );
+                }
+                Event::KeyPressed(k) if *k == self.config.keycodes.dof => {
+                    self.set_dof(!self.dof);
+                    info!(
+                        "Turned depth of field {}",
+                        if self.dof { "on" } else { "off" }
+                    );
+                }
+                Event::KeyPressed(k) if *k == self.config.keycodes.keyframe => {
+                    let t = self.camera_path.keyframes().last().map_or(0.0, |last| {
+                        last.time + (time - self.last_keyframe).max(MIN_KEYFRAME_GAP)
+                    });
+                    self.last_keyframe = time;
+                    self.camera_path.push(cam.keyframe(t));
+                    info!(
+                        "Added keyframe {} to the camera path, at {:.1}s",
+                        self.camera_path.keyframes().len(),
+                        t
+                    );
+                    save_camera_path(&self.camera_path);
+                }
+                Event::KeyPressed(k) if *k == self.config.keycodes.play_path => {
+                    if cam.is_playing() {
+                        cam.stop();
+                        info!("Stopped playing the camera path");
+                    } else if self.camera_path.keyframes().len() < 2 {
+                        warn!("The camera path needs at least two keyframes to play");
+                    } else {
+                        info!(
+                            "Playing the camera path, {:.1}s long",
+                            self.camera_path.duration()
+                        );
+                        cam.play(self.camera_path.clone());
+                    }
+                }
+                Event::KeyPressed(k) if *k == self.config.keycodes.clear_path => {
+                    self.camera_path = CameraPath::default();
+                    info!("Cleared the camera path");
+                    save_camera_path(&self.camera_path);
+                }
+                // It resizes the window, which recreates the swapchain
+                Event::KeyPressed(k) if *k == self.config.keycodes.fullscreen => {
+                    win.toggle_fullscreen()
+                }
+                Event::KeyPressed(k) if *k == self.config.keycodes.debug_mode => {
+                    self.set_debug_mode(&win, self.debug_mode.next());
+                }
+                Event::KeyPressed(k) if *k == self.config.keycodes.brightness_up => {
+                    self.set_brightness(self.brightness + BRIGHTNESS_STEP);
+                }
+                Event::KeyPressed(k) if *k == self.config.keycodes.brightness_down => {
+                    self.set_brightness(self.brightness - BRIGHTNESS_STEP);
+                }
+                Event::KeyPressed(k) if *k == self.config.keycodes.overlay => {
+                    self.overlay.toggle();
+                }
+                Event::OtherPlayer(id, pos) => self.remote.update(*id, time, *pos),
+                Event::PlayerLeft(id) => self.remote.remove(*id),
+                Event::PlayerName(id, name) => self.remote.set_name(*id, name.clone()),
+                Event::TimeOfDay(t) => self.day_clock.update(time, *t),
+                Event::WorldStats(stats) => self.overlay.set_stats(stats.clone()),
+                Event::ConfigChanged(config) => {
+                    cam.configure(config, false);
+                    self.remote.configure(config);
+                    if config.debug_info != self.config.debug_info {
+                        self.debug_info = config.debug_info;
+                    }
+                    if config.dof != self.config.dof {
+                        self.set_dof(config.dof);
+                    }
+                    if config.debug_mode != self.config.debug_mode {
+                        self.set_debug_mode(&win, config.debug_mode);
+                    }
+                    if config.brightness != self.config.brightness {
+                        self.set_brightness(config.brightness);
+                    }
+                    if config.brush_shape != self.config.brush_shape
+                        || config.brush_radius != self.config.brush_radius
+                    {
+                        self.brush = brush(config);
+                    }
+                    if config.place_material != self.config.place_material {
+                        self.material = config.place_material;
+                    }
+                    if config.shadow_mode != self.config.shadow_mode {
+                        // So it's sent again, for `ClientWorld` to bake everything
+                        self.baked_sun = None;
+                    }
+                    let rescale = config.render_scale != self.config.render_scale
+                        || config.dynamic_resolution != self.config.dynamic_resolution
+                        || config.min_render_scale != self.config.min_render_scale
+                        || config.max_render_scale != self.config.max_render_scale
+                        || config.target_frame_ms != self.config.target_frame_ms;
+                    let relod = config.adaptive_lod != self.config.adaptive_lod
+                        || config.min_lod_bias != self.config.min_lod_bias
+                        || config.max_lod_bias != self.config.max_lod_bias
+                        || config.target_frame_ms != self.config.target_frame_ms;
+                    let aa_changed = config.aa_mode != self.config.aa_mode;
+                    // Turning the sky off leaves pixels for the clear color, so it has to go back to clearing
+                    win.set_clear(config.clear_window());
+                    self.config = Arc::clone(config);
+                    self.update_desc();
+                    self.gpu.post.restart_accumulation();
+                    if rescale {
+                        self.dynamic_scale = dynamic_scale(&self.config);
+                        let scale = self
+                            .dynamic_scale
+                            .as_ref()
+                            .map_or(self.config.render_scale, DynamicScale::scale);
+                        self.set_render_scale(&win, scale);
+                    }
+                    if relod {
+                        self.lod_bias = lod_bias(&self.config);
+                    }
+                    if aa_changed {
+                        info!("Anti-aliasing is now {:?}", self.config.aa_mode);
+                        self.resize_targets(&win);
+                    }
+                }
+                Event::ImportVox(path) => match crate::vox::load_vox(path) {
+                    Ok(vox) => {
+                        // In front of the player if they're not looking at anything
+                        let at = target.unwrap_or_else(|| {
+                            (cam.pos() + cam.dir * 8.0).map(|x| x.floor() + 0.5)
+                        });
+                        let placed = place_vox(
+                            &vox,
+                            self.config.vox_up_axis,
+                            at,
+                            &mut world,
+                            self.config.bake_ao,
+                            &mut edits,
+                            &mut reupload,
+                        );
+                        info!("Imported {}, {} voxels", path.display(), placed);
+                    }
+                    Err(e) => warn!("Couldn't import {}: {}", path.display(), e),
+                },
+                Event::ImportScene(path) => {
+                    match crate::vox_scene::load_scene(path, self.config.vox_up_axis) {
+                        Ok(scene) => {
+                            let at = target.unwrap_or_else(|| {
+                                (cam.pos() + cam.dir * 8.0).map(|x| x.floor() + 0.5)
+                            });
+                            let placed = place_scene(
+                                &scene,
+                                at,
+                                &mut world,
+                                self.config.bake_ao,
+                                &mut edits,
+                                &mut reupload,
+                            );
+                            info!("Imported scene {}, {} voxels", path.display(), placed);
+                        }
+                        Err(e) => warn!("Couldn't import {}: {}", path.display(), e),
+                    }
+                }
+                Event::Command(line) => {
+                    info!("{}", line);
+                    for action in self.console.run(line) {
+                        match action {
+                            Action::Send(m) => sent.push(m),
+                            Action::Teleport(pos) => cam.teleport(pos),
+                            Action::Print(s) => info!("{}", s),
+                            Action::Screenshot(width, height) => {
+                                match self.capture_at(&win, &world, width, height, &cam) {
+                                    Ok(path) => info!("Saved a screenshot to {}", path.display()),
+                                    Err(e) => warn!("Couldn't take a screenshot: {}", e),
+                                }
+                            }
+                        }
+                    }
+                }
+                Event::WorldLoading(done, total) if self.waiting_for_world => {
+                    win.set_status(Some(&format!(
+                        "loading the world, {} of {} chunks",
+                        done, total
+                    )));
+                }
+                Event::WorldReady(ready) => {
+                    if self.waiting_for_world {
+                        self.waiting_for_world = false;
+                        win.set_status(None);
+                    }
+                    if let Some(f) = self.on_world_ready.take() {
+                        f(*ready);
+                    }
+                }
+                Event::Saving(saving) => {
+                    win.set_status(if *saving { Some("saving...") } else { None });
+                    if *saving {
+                        self.save_thumbnail(&win);
+                    }
+                }
+                Event::Quit => {
+                    // A static scene isn't the world that's saved, so it shouldn't be its thumbnail
+                    if self.config.game_config.save_chunks && !self.static_scene {
+                        self.save_thumbnail(&win);
+                    }
+                    self.quitting = true;
+                    info!("Waiting for the GPU to finish");
+                    let mut f: Box<dyn GpuFuture + Send + Sync> =
+                        Box::new(vulkano::sync::now(win.device()));
+                    std::mem::swap(&mut f, &mut self.future);
+                    if let Err(e) = f.then_signal_fence_and_flush().and_then(|f| f.wait(None)) {
+                        warn!("Couldn't wait for the GPU: {:?}", e);
+                    }
+                }
                 // Left-click
                 Event::Button(1) => {
-                    println!("You clicked!");
-                    let cast = world.raycast(
-                        cam.pos(),
-                        cam.dir.map(|x| if x.abs() < 0.0001 { 0.0001 } else { x }),
-                        12.0,
-                    );
-                    println!("Found {:?}", cast);
-                    if let Some(RayCast { t, .. }) = cast {
-                        let pos = cam.pos() + cam.dir * (t[0] + 0.05);
-                        world.set_block(pos, Material::Air);
-                        let loc = world_to_chunk(pos);
-                        let chunk = self
-                            .pool
-                            .chunk(world.chunk(loc).unwrap().0.clone())
-                            .unwrap();
-
-                        let slot = self.chunk_slots.get(&loc).unwrap();
-                        let view = vulkano::buffer::BufferSlice::from_typed_buffer_access(
-                            self.tree_buffer.clone(),
-                        )
-                        .slice(slot.0..slot.1)
-                        .unwrap();
-                        let cmd =
-                            AutoCommandBufferBuilder::primary(win.device(), win.queue.family())
-                                .unwrap()
-                                .copy_buffer(chunk, view)
-                                .unwrap()
-                                .build()
-                                .unwrap();
-
-                        // This shouldn't be necessary
-                        let mut f: Box<dyn GpuFuture + Send + Sync> =
-                            Box::new(vulkano::sync::now(win.device()));
-                        std::mem::swap(&mut f, &mut self.future);
-                        f.then_signal_fence_and_flush().unwrap().wait(None).unwrap();
-
-                        self.future = Box::new(cmd.execute(win.queue.clone()).unwrap());
+                    debug!("You clicked on {:?}", target);
+                    if let Some(pos) = target {
+                        reupload
+                            .extend(self.click_edit(true, &mut world, pos, cam.dir, &mut edits));
+                    } else {
+                        self.wait_to_edit(true, &world, &win, cam.pos(), ray_dir);
+                    }
+                }
+                // Middle-click picks the material to place, like an eyedropper
+                Event::Button(2) => {
+                    if let Some(h) = &hit {
+                        self.material = h.mat;
+                        info!("Placing {:?} now", self.material);
+                        win.set_status(Some(&format!("placing {:?}", self.material)));
+                    }
+                }
+                // Right-click
+                Event::Button(3) => {
+                    if let Some(pos) = place_target {
+                        reupload
+                            .extend(self.click_edit(false, &mut world, pos, cam.dir, &mut edits));
+                    } else {
+                        self.wait_to_edit(false, &world, &win, cam.pos(), ray_dir);
+                    }
+                }
+                Event::KeyPressed(k) if *k == self.config.keycodes.brush_shape => {
+                    self.brush.shape = self.brush.shape.next();
+                    info!("Brush is now a {:?}", self.brush.shape);
+                }
+                Event::Scroll(lines) => {
+                    // Whole lines only, so touchpads don't change it on every little movement
+                    self.scroll += lines;
+                    let steps = self.scroll.trunc();
+                    if steps != 0.0 {
+                        self.scroll -= steps;
+                        self.brush.resize(steps as i32);
+                        info!("Brush radius is now {}", self.brush.radius);
                     }
                 }
                 _ => {}
             }
         }
+        // After the events, so chunks that came this frame count
+        if !self.pending_edits.is_empty() {
+            reupload.extend(self.make_pending_edits(&mut world, &win, &mut edits));
+        }
+        for (pos, mat) in edits {
+            channel.single_write(Event::SetBlock(pos, mat));
+        }
+        for m in sent {
+            channel.single_write(Event::Send(Once::new(m)));
+        }
+        if !reupload.is_empty() {
+            // `ClientWorld` knows where the chunks go in the tree buffer, so it does the uploading
+            channel.single_write(Event::Reupload(reupload));
+        }
+        prof.record("events", span);
+
+        // After the events, so the camera has all of this frame's input
+        cam.update(delta, &world);
+        call_plugins(&mut self.plugins, |p| p.on_update(delta));
+        self.report_frame(start, delta, size, &cam, rendered);
     }
 }
 
-impl Client {
-    pub fn new(
+impl Gpu {
+    /// Makes everything for drawing the world in `tree_buffer`, with the LOD colors in `lod_buffer`, with `window`'s device.
+    /// The HDR target is `render_scale` times the window size, before supersampling.
+    /// The future is for uploading the materials, which has to finish before the first frame.
+    /// It fails if one of the built-in shaders can't be loaded; replacements from `shader_dir` fall back to those.
+    fn new(
         window: &Window,
-        cam: &Camera,
-        conn: Connection,
-        config: Arc<ClientConfig>,
-        events: &mut EventChannel<Event>,
-    ) -> (Self, ClientWorld) {
-        let c = ClientWorld::new(
-            window.device(),
-            window.queue.clone(),
-            conn,
-            Vector3::zeros(),
-            config,
-            events.register_reader(),
-        );
-        let tree_buffer = c.tree_buffer.clone();
+        config: &ClientConfig,
+        render_scale: f32,
+        tree_buffer: TreeBuffer,
+        lod_buffer: TreeBuffer,
+    ) -> Result<(Self, Box<dyn GpuFuture + Send + Sync>), ShaderError> {
+        let device = window.device();
+        let shader_dir = config.shader_dir.as_deref();
+        let vs = shaders::load("blank.vert", || shaders::Vertex::load(device.clone()))?;
+        let fs = RaymarchShader::load(&device, shader_dir)?;
 
-        let vs = crate::shaders::Vertex::load(window.device()).unwrap();
-        let fs = crate::shaders::Fragment::load(window.device()).unwrap();
+        let post = Post::new(window, render_scale * config.aa_mode.factor() as f32)?;
 
         let pipeline = Arc::new(
             GraphicsPipeline::start()
@@ -292,14 +1163,21 @@ impl Client {
                 .fragment_shader(fs.main_entry_point(), ())
                 .triangle_strip()
                 .viewports_dynamic_scissors_irrelevant(1)
-                .render_pass(Subpass::from(window.rpass.clone(), 0).unwrap())
+                // It covers the whole screen and writes depth for every pixel, for the markers drawn after it
+                .depth_stencil(DepthStencil {
+                    depth_compare: Compare::Always,
+                    depth_write: true,
+                    ..DepthStencil::disabled()
+                })
+                .render_pass(Subpass::from(post.hdr_rpass(), 0).unwrap())
                 .build(window.device())
                 .unwrap(),
         );
 
+        // Tiny windows still need a beam image
         let size = [
-            window.size().0 as u32 / BEAM_RES_FAC,
-            window.size().1 as u32 / BEAM_RES_FAC,
+            (window.size().0 as u32 / BEAM_RES_FAC).max(1),
+            (window.size().1 as u32 / BEAM_RES_FAC).max(1),
         ];
 
         let viewport = vulkano::pipeline::viewport::Viewport {
@@ -343,7 +1221,7 @@ impl Client {
             .unwrap(),
         ) as Arc<dyn vulkano::framebuffer::RenderPassAbstract + Send + Sync>;
 
-        let fs_beam = crate::shaders::Beam::load(window.device()).unwrap();
+        let fs_beam = BeamShader::load(&device, shader_dir)?;
 
         let beam_pipeline = Arc::new(
             GraphicsPipeline::start()
@@ -363,19 +1241,7 @@ impl Client {
                 .unwrap(),
         );
 
-        let beam_desc = Arc::new(
-            PersistentDescriptorSet::start(
-                beam_pipeline
-                    .layout()
-                    .descriptor_set_layout(0)
-                    .unwrap()
-                    .clone(),
-            )
-            .add_buffer(tree_buffer.clone())
-            .unwrap()
-            .build()
-            .unwrap(),
-        );
+        let beam_desc = beam_desc(&beam_pipeline, &tree_buffer);
 
         let (mat_buf, future) = ImmutableBuffer::from_iter(
             crate::material::Material::all().into_iter(),
@@ -387,7 +1253,12 @@ impl Client {
         )
         .unwrap();
 
-        let mut future: Box<dyn GpuFuture + Send + Sync> = Box::new(future);
+        let (marker_pass, font_future) =
+            MarkerPass::new(window.device(), window.queue.clone(), post.hdr_rpass())?;
+        let (overlay_pass, overlay_future) =
+            OverlayPass::new(window.device(), window.queue.clone(), window.rpass.clone())?;
+        let future: Box<dyn GpuFuture + Send + Sync> =
+            Box::new(future.join(font_future).join(overlay_future));
 
         // This shouldn't be necessary
         // future
@@ -397,57 +1268,961 @@ impl Client {
         //     .unwrap();
         // future = Box::new(vulkano::sync::now(window.device()));
 
-        let desc = Arc::new(
+        let sampler = Sampler::new(
+            window.device(),
+            Filter::Nearest,
+            Filter::Nearest,
+            MipmapMode::Nearest,
+            SamplerAddressMode::ClampToEdge,
+            SamplerAddressMode::ClampToEdge,
+            SamplerAddressMode::ClampToEdge,
+            0.0,
+            1.0,
+            0.0,
+            0.0,
+        )
+        .unwrap();
+
+        let settings_pool = CpuBufferPool::uniform_buffer(window.device());
+        let desc = main_desc(
+            &pipeline,
+            &tree_buffer,
+            &beam_image,
+            &sampler,
+            &mat_buf,
+            settings_pool.next(shader_settings(config)).unwrap(),
+            &lod_buffer,
+        );
+
+        Ok((
+            Gpu {
+                tree_buffer,
+                lod_buffer,
+                pipeline,
+                desc,
+                beam_image,
+                sampler,
+                mat_buf,
+                settings_pool,
+                frame_pool: CpuBufferPool::uniform_buffer(window.device()),
+                beam_pipeline,
+                beam_framebuffer,
+                beam_state,
+                beam_desc,
+                marker_pass,
+                overlay_pass,
+                post,
+            },
+            future,
+        ))
+    }
+}
+
+impl Client {
+    /// Draws a frame and submits it, returning whether to go on with the rest of the frame.
+    /// If it returns false, the frame was skipped; if the device was lost, `device_lost` is set too.
+    /// `preview` is the voxels right-click would place, which are drawn as ghosts of the material.
+    fn draw(
+        &mut self,
+        win: &mut Window,
+        cam: &Camera,
+        time: f64,
+        target: Option<Vector3<f32>>,
+        preview: &[Vector3<f32>],
+        prof: &mut Profiler,
+    ) -> bool {
+        if self.recreate_swapchain {
+            if !win.recreate() {
+                return false;
+            }
+            self.recreate_swapchain = false;
+            self.resize_targets(win);
+        }
+
+        let span = mark();
+        let frame = match win.frame() {
+            Ok(r) => r,
+            Err(vulkano::swapchain::AcquireError::OutOfDate) => {
+                self.recreate_swapchain = true;
+                return false;
+            }
+            Err(vulkano::swapchain::AcquireError::DeviceLost) => {
+                self.device_lost = true;
+                self.forget_future(win);
+                return false;
+            }
+            Err(err) => panic!("{:?}", err),
+        };
+        prof.record("acquire", span);
+
+        let pc = self.push_constants(cam, self.medium);
+        let beam_resolution = [
+            (pc.resolution[0] / BEAM_RES_FAC as f32).floor().max(1.0),
+            (pc.resolution[1] / BEAM_RES_FAC as f32).floor().max(1.0),
+        ];
+
+        let jitter = self
+            .gpu
+            .post
+            .begin_frame(&pc, self.render_origin, &self.config);
+        let frame_desc = self.frame_desc(cam, target, self.lod_scale(&pc), jitter);
+        for (id, pos) in self.remote.positions(time) {
+            // `pos` is where their camera is, so the box goes down to their feet
+            let r = Vector3::repeat(PLAYER_RADIUS);
+            let min = pos - r - up() * (EYE_HEIGHT - PLAYER_RADIUS);
+            self.markers.box_outline(min, pos + r, REMOTE_PLAYER_COLOR);
+            if let Some(name) = self.remote.name(id) {
+                let above = pos + up() * (PLAYER_RADIUS + NAME_GAP);
+                self.markers.label(above, name, NAME_COLOR);
+            }
+        }
+        if let Some(pos) = target.filter(|_| self.brush.radius > 0) {
+            // The main shader outlines the voxel itself, but bigger brushes get an outline around everything they cover
+            let (min, max) = self.brush.bounds(pos, cam.dir);
+            let e = Vector3::repeat(BRUSH_OUTLINE_GAP);
+            self.markers.box_outline(min - e, max + e, BRUSH_COLOR);
+        }
+        self.markers
+            .ghost_voxels(preview, self.material.mat_data().color);
+        let markers = &mut self.markers;
+        call_plugins(&mut self.plugins, |p| p.on_markers(markers));
+
+        let span = mark();
+        // A plugin that panics takes the command buffer with it, and it's been removed by the time we find out,
+        // so the frame is recorded again without it, and still presented
+        let command_buffer = loop {
+            let builder =
+                AutoCommandBufferBuilder::primary_one_time_submit(win.device(), win.queue.family())
+                    .unwrap();
+            let buffers = self.world_buffers();
+            let builder = match compute_plugins(&mut self.plugins, builder, &buffers) {
+                Some(builder) => builder,
+                None => continue,
+            };
+            let builder = self.draw_scene(
+                builder,
+                pc,
+                beam_resolution,
+                frame_desc.clone(),
+                cam.view_proj(&self.render_origin),
+                self.gpu.post.targets(),
+            );
+            let builder = self.gpu.post.draw(
+                builder,
+                frame.framebuffer.clone(),
+                win,
+                &self.config,
+                &pc,
+                self.render_origin,
+                self.focus,
+                self.brightness,
+            );
+            let builder = match render_plugins(&mut self.plugins, builder, win) {
+                Some(builder) => builder,
+                None => continue,
+            };
+            // Last, so it's over anything plugins drew
+            let (w, h) = win.size();
+            let builder = self.gpu.overlay_pass.draw(
+                builder,
+                self.overlay.lines(),
+                &win.dynamic_state,
+                [w as f32, h as f32],
+                overlay_scale(self.scale_factor),
+            );
+            break builder.end_render_pass().unwrap().build().unwrap();
+        };
+        self.markers.clear();
+        prof.record("command-build", span);
+
+        let span = mark();
+        let mut f: Box<dyn GpuFuture + Send + Sync> = Box::new(vulkano::sync::now(win.device()));
+        std::mem::swap(&mut f, &mut self.future);
+        if let Some(acquire) = frame.acquire {
+            f = Box::new(f.join(acquire));
+        }
+        let mut f: Box<dyn GpuFuture + Send + Sync> =
+            Box::new(f.then_execute(win.queue.clone(), command_buffer).unwrap());
+        if let Some(swapchain) = &win.swapchain {
+            f = Box::new(f.then_swapchain_present(
+                win.queue.clone(),
+                swapchain.clone(),
+                frame.image_num,
+            ));
+        }
+        let f = f.then_signal_fence_and_flush();
+
+        // Nothing else limits how far ahead of the GPU we get when headless, so wait for each frame
+        let f = match f {
+            Ok(f) if win.is_headless() => {
+                let wait = Instant::now();
+                let f = f.wait(None).map(|()| f);
+                self.gpu_ms = Some(wait.elapsed().as_secs_f64() * 1000.0);
+                f
+            }
+            f => f,
+        };
+        match f {
+            Ok(f) => {
+                self.future = Box::new(f) as Box<_>;
+            }
+            Err(vulkano::sync::FlushError::OutOfDate) => {
+                self.recreate_swapchain = true;
+                self.future = Box::new(vulkano::sync::now(win.device())) as Box<_>;
+            }
+            Err(vulkano::sync::FlushError::DeviceLost) => {
+                self.device_lost = true;
+                return false;
+            }
+            Err(err) => {
+                // We'll keep going, it's probably not a big deal
+                warn!("Failed to flush frame: {:?}", err);
+                self.future = Box::new(vulkano::sync::now(win.device())) as Box<_>;
+            }
+        }
+        prof.record("submit", span);
+        true
+    }
+
+    /// Makes a new device after the old one was lost, and asks `ClientWorld` to upload the world to it.
+    /// Everything else is made again when that comes back in `Submit`, in `rebuild()`.
+    fn reset_device(&mut self, win: &mut Window, channel: &mut EventChannel<Event>) {
+        error!("Lost the GPU, probably because the driver reset; starting it again");
+        self.offscreen = None;
+        // Before the device goes, so nothing waits on it
+        self.forget_future(win);
+        if let Err(e) = win.recreate_device(&self.config) {
+            error!("Couldn't start the GPU again: {}", e);
+            // The world can still be saved, but we won't be drawing it
+            self.quitting = true;
+            channel.single_write(Event::Quit);
+            return;
+        }
+        self.future = Box::new(vulkano::sync::now(win.device()));
+        channel.single_write(Event::DeviceReset(win.device(), win.transfer_queue.clone()));
+    }
+
+    /// Gets rid of the last frame's future without waiting for it, for when the device is lost.
+    /// Dropping a flushed `FenceSignalFuture` waits on its fence, which fails on a lost device and panics,
+    /// so it's leaked instead, along with what it's holding onto from the old device.
+    fn forget_future(&mut self, win: &Window) {
+        let old = std::mem::replace(&mut self.future, Box::new(vulkano::sync::now(win.device())));
+        std::mem::forget(old);
+    }
+
+    /// Makes everything for drawing again with the new device, now that `tree_buffer` and `lod_buffer` have the world in them
+    fn rebuild(&mut self, win: &Window, tree_buffer: TreeBuffer, lod_buffer: TreeBuffer) {
+        let (gpu, future) = match Gpu::new(
+            win,
+            &self.config,
+            self.render_scale,
+            tree_buffer,
+            lod_buffer,
+        ) {
+            Ok(x) => x,
+            Err(e) => {
+                // Nothing's drawn, but the world can still be saved when the window's closed
+                error!("Couldn't start drawing again: {}", e);
+                return;
+            }
+        };
+        self.gpu = gpu;
+        let mut f: Box<dyn GpuFuture + Send + Sync> = Box::new(vulkano::sync::now(win.device()));
+        std::mem::swap(&mut f, &mut self.future);
+        self.future = Box::new(f.join(future));
+        self.recreate_swapchain = false;
+        self.device_lost = false;
+        info!("The GPU is back");
+    }
+
+    /// Sets a callback to call at the end of every frame with how it went, including frames where nothing was drawn.
+    /// Nothing's measured until there is one.
+    pub fn on_frame(&mut self, f: impl FnMut(&FrameStats) + Send + 'static) {
+        self.on_frame = Some(Box::new(f));
+    }
+
+    /// Calls the `on_frame()` callback, if there is one. `start` is when the frame started, which is only measured with one.
+    fn report_frame(
+        &mut self,
+        start: Option<Instant>,
+        delta: f64,
+        size: (f64, f64),
+        cam: &Camera,
+        rendered: bool,
+    ) {
+        if let (Some(f), Some(start)) = (&mut self.on_frame, start) {
+            let fps = if delta > 0.0 { 1.0 / delta } else { 0.0 };
+            f(&FrameStats {
+                cpu_ms: start.elapsed().as_secs_f64() * 1000.0,
+                gpu_ms: self.gpu_ms,
+                fps,
+                mpixels_per_s: size.0 * size.1 * fps / 1_000_000.0,
+                camera_pos: cam.pos(),
+                rendered,
+            });
+        }
+    }
+
+    /// Sets a callback to call once the chunks within `view_distance` of where the player started are loaded,
+    /// with `false` if `world_load_timeout` ran out before they all were. It's only ever called once,
+    /// and not if that's already happened, so set it before the first frame.
+    pub fn on_world_ready(&mut self, f: impl FnOnce(bool) + Send + 'static) {
+        self.on_world_ready = Some(Box::new(f));
+    }
+
+    /// The voxel under the crosshair, within reach for editing, as of the last frame
+    pub fn looking_at(&self) -> Option<&MaterialHit> {
+        self.looking_at.as_ref()
+    }
+
+    /// The voxel the player is standing on as of the last frame, if they're walking and on the ground
+    pub fn standing_on(&self) -> Option<&MaterialHit> {
+        self.standing_on.as_ref()
+    }
+
+    /// The buffers the world is drawn from right now, for running shaders over it outside of a plugin.
+    /// To use them in a command buffer of your own, submit it after the frame's with the client's queue,
+    /// since nothing else waits for it; `Plugin::on_compute()` doesn't have to worry about that.
+    pub fn world_buffers(&self) -> WorldBuffers {
+        WorldBuffers {
+            tree: self.gpu.tree_buffer.clone(),
+            lod_colors: self.gpu.lod_buffer.clone(),
+            materials: self.gpu.mat_buf.clone(),
+            origin: self.render_origin.local(self.origin.map(|x| x as f64)),
+            root_size: self.root_size,
+        }
+    }
+
+    /// Adds a plugin, whose hooks get called from now on
+    pub fn add_plugin(&mut self, plugin: Box<dyn Plugin>) {
+        info!("Loaded plugin {}", plugin.name());
+        let console = &mut self.console;
+        let mut plugins = vec![plugin];
+        call_plugins(&mut plugins, |p| p.register_commands(console));
+        self.plugins.extend(plugins);
+    }
+
+    /// Recreates the main descriptor set with the current config's settings
+    fn update_desc(&mut self) {
+        let settings = self
+            .gpu
+            .settings_pool
+            .next(shader_settings(&self.config))
+            .unwrap();
+        self.gpu.desc = main_desc(
+            &self.gpu.pipeline,
+            &self.gpu.tree_buffer,
+            &self.gpu.beam_image,
+            &self.gpu.sampler,
+            &self.gpu.mat_buf,
+            settings,
+            &self.gpu.lod_buffer,
+        );
+    }
+
+    /// What the shaders need to draw what `cam` sees, from inside `medium`
+    fn push_constants(&self, cam: &Camera, medium: Material) -> PushConstants {
+        let mut pc = cam.push(
+            &self.render_origin,
+            self.origin,
+            self.root_size,
+            self.sun_dir.into(),
+            medium as u32,
+        );
+        pc.traversal = self.config.traversal as u32;
+        pc.debug_mode = self.debug_mode as u32;
+        pc.wrap = wrap_bits(self.config.world_wrap);
+        pc
+    }
+
+    /// The per-frame descriptor set for `cam`, with an outline around the voxel at `target` if there is one.
+    /// `lod_scale` is for `main.frag`, from `lod_scale()`, or 0 for all the detail, and `jitter` is from `Post::begin_frame()`.
+    fn frame_desc(
+        &self,
+        cam: &Camera,
+        target: Option<Vector3<f32>>,
+        lod_scale: f32,
+        jitter: [f32; 2],
+    ) -> Arc<dyn DescriptorSet + Send + Sync> {
+        let (proj_near, proj_far) = cam.depth_range();
+        let frame_data = self
+            .gpu
+            .frame_pool
+            .next(FrameData {
+                target_pos: self
+                    .render_origin
+                    .local(target.unwrap_or_else(Vector3::zeros).map(|x| x as f64))
+                    .into(),
+                target_size: if target.is_some() { 1.0 } else { 0.0 },
+                proj_near,
+                proj_far,
+                jitter,
+                lod_scale,
+            })
+            .unwrap();
+        Arc::new(
             PersistentDescriptorSet::start(
-                pipeline.layout().descriptor_set_layout(0).unwrap().clone(),
+                self.gpu
+                    .pipeline
+                    .layout()
+                    .descriptor_set_layout(1)
+                    .unwrap()
+                    .clone(),
             )
-            .add_buffer(tree_buffer.clone())
+            .add_buffer(frame_data)
             .unwrap()
-            .add_sampled_image(
-                beam_image,
-                Sampler::new(
-                    window.device(),
-                    Filter::Nearest,
-                    Filter::Nearest,
-                    MipmapMode::Nearest,
-                    SamplerAddressMode::ClampToEdge,
-                    SamplerAddressMode::ClampToEdge,
-                    SamplerAddressMode::ClampToEdge,
-                    0.0,
-                    1.0,
-                    0.0,
-                    0.0,
-                )
-                .unwrap(),
+            .build()
+            .unwrap(),
+        )
+    }
+
+    /// Records the beam pass and then the main pass, which draws what `pc` sees into `targets`, with the markers on top.
+    /// The beam image is always the same size, so `beam_resolution` is what `pc.resolution` works out to at that size.
+    /// `view_proj` is the camera's, relative to the render origin, for the markers.
+    fn draw_scene(
+        &self,
+        builder: AutoCommandBufferBuilder,
+        pc: PushConstants,
+        beam_resolution: [f32; 2],
+        frame_desc: Arc<dyn DescriptorSet + Send + Sync>,
+        view_proj: na::Matrix4<f32>,
+        targets: &Targets,
+    ) -> AutoCommandBufferBuilder {
+        let pc_beam = crate::shaders::BeamConstants {
+            fov: pc.fov,
+            resolution: beam_resolution,
+            camera_pos: pc.camera_pos,
+            camera_dir: pc.camera_dir,
+            camera_up: pc.camera_up,
+            origin: pc.origin,
+            root_size: self.root_size,
+            _dummy0: pc._dummy0,
+            _dummy1: pc._dummy1,
+            _dummy2: pc._dummy2,
+        };
+        let builder = builder
+            .begin_render_pass(self.gpu.beam_framebuffer.clone(), false, vec![[0.0].into()])
+            .unwrap()
+            .draw(
+                self.gpu.beam_pipeline.clone(),
+                &self.gpu.beam_state,
+                BufferlessVertices {
+                    vertices: 4,
+                    instances: 1,
+                },
+                self.gpu.beam_desc.clone(),
+                pc_beam,
+            )
+            .unwrap()
+            .end_render_pass()
+            .unwrap()
+            .begin_render_pass(
+                targets.hdr_framebuffer(),
+                false,
+                vec![
+                    vulkano::format::ClearValue::None,
+                    vulkano::format::ClearValue::None,
+                ],
             )
             .unwrap()
-            .add_buffer(mat_buf)
+            .draw(
+                self.gpu.pipeline.clone(),
+                targets.hdr_state(),
+                BufferlessVertices {
+                    vertices: 4,
+                    instances: 1,
+                },
+                (self.gpu.desc.clone(), frame_desc),
+                pc,
+            )
+            .unwrap();
+        self.gpu
+            .marker_pass
+            .draw(
+                builder,
+                &self.markers,
+                targets.hdr_state(),
+                view_proj,
+                &pc,
+                self.render_origin,
+            )
+            .end_render_pass()
             .unwrap()
+    }
+
+    /// What to draw over the world next frame. It's cleared after each frame, so add anything that should stay every frame.
+    pub fn markers(&mut self) -> &mut Markers {
+        &mut self.markers
+    }
+
+    /// Draws what `cam` sees into `target`, which can be any size, without touching the window or anything about the game's frames.
+    /// This is for tools and tests that want pictures of the world; see `render_rgba()` to get the pixels back.
+    /// It's submitted after everything the game has already submitted, and the future is for when it's done.
+    pub fn render_frame(
+        &mut self,
+        win: &Window,
+        cam: &Camera,
+        world: &crate::world::World,
+        target: RenderTarget,
+    ) -> Box<dyn GpuFuture + Send + Sync> {
+        let [width, height] = vulkano::image::ImageAccess::dimensions(&*target).width_height();
+        let factor = self.config.aa_mode.factor();
+        let size = [width * factor, height * factor];
+        if self.offscreen.as_ref().map_or(true, |t| t.size() != size) {
+            self.offscreen = Some(self.gpu.post.new_targets(win, size));
+        }
+        let targets = self.offscreen.as_ref().unwrap();
+
+        let medium = world.block(cam.pos()).unwrap_or(Material::Air);
+        let mut pc = self.push_constants(cam, medium);
+        pc.resolution = [width as f32, height as f32];
+        // There's no last frame to ease from, so it's focused right on the middle of the picture
+        let focus = if self.dof {
+            Some(focus_distance(&self.config, world, cam))
+        } else {
+            None
+        };
+        let beam_height = self.gpu.beam_image.dimensions()[1] as f32;
+        let beam_resolution = [beam_height * width as f32 / height as f32, beam_height];
+
+        let builder =
+            AutoCommandBufferBuilder::primary_one_time_submit(win.device(), win.queue.family())
+                .unwrap();
+        let view_proj = cam.projection_with_aspect(width as f32 / height as f32)
+            * cam.view_matrix(&self.render_origin);
+        let builder = self.draw_scene(
+            builder,
+            pc,
+            beam_resolution,
+            // Pictures are for looking at closely, so they get all the detail whatever the frame rate is
+            self.frame_desc(cam, None, 0.0, [0.0; 2]),
+            view_proj,
+            targets,
+        );
+        let command_buffer = self
+            .gpu
+            .post
+            .draw_to_image(
+                builder,
+                targets,
+                target,
+                &self.config,
+                &pc,
+                self.render_origin,
+                focus,
+                self.brightness,
+            )
             .build()
-            .unwrap(),
+            .unwrap();
+
+        let mut f: Box<dyn GpuFuture + Send + Sync> = Box::new(vulkano::sync::now(win.device()));
+        std::mem::swap(&mut f, &mut self.future);
+        let f = Arc::new(
+            f.then_execute(win.queue.clone(), command_buffer)
+                .unwrap()
+                .then_signal_fence_and_flush()
+                .unwrap(),
         );
+        // The game's next frame and chunk uploads wait on this too, since they use the same buffers
+        self.future = Box::new(f.clone());
+        Box::new(f)
+    }
+
+    /// Renders like `render_frame()` into a new image of `size`, and waits to read it back.
+    /// The pixels are RGBA in sRGB, in rows from the top.
+    pub fn render_rgba(
+        &mut self,
+        win: &Window,
+        cam: &Camera,
+        world: &crate::world::World,
+        size: [u32; 2],
+    ) -> Vec<u8> {
+        let target = render_target(win, size);
+        let buf = CpuAccessibleBuffer::from_iter(
+            win.device(),
+            BufferUsage::transfer_destination(),
+            false,
+            (0..size[0] * size[1]).map(|_| [0u8; 4]),
+        )
+        .unwrap();
+        let f = self.render_frame(win, cam, world, target.clone());
+        let cmd =
+            AutoCommandBufferBuilder::primary_one_time_submit(win.device(), win.queue.family())
+                .unwrap()
+                .copy_image_to_buffer(target, buf.clone())
+                .unwrap()
+                .build()
+                .unwrap();
+        f.then_execute(win.queue.clone(), cmd)
+            .unwrap()
+            .then_signal_fence_and_flush()
+            .unwrap()
+            .wait(None)
+            .unwrap();
+        let mut pixels = Vec::with_capacity(size[0] as usize * size[1] as usize * 4);
+        pixels.extend(buf.read().unwrap().iter().flatten());
+        pixels
+    }
+
+    /// Renders what `cam` sees at `width` by `height` pixels, whatever size the window is, and saves it as a PNG
+    /// in the `screenshots` folder next to the world, returning where it went. This is what `/screenshot` does.
+    /// It's drawn off-screen like `render_frame()`, so it has the same vertical field of view as the window,
+    /// and a wider or narrower picture shows more or less to the sides.
+    pub fn capture_at(
+        &mut self,
+        win: &Window,
+        world: &crate::world::World,
+        width: u32,
+        height: u32,
+        cam: &Camera,
+    ) -> Result<std::path::PathBuf, String> {
+        if self.device_lost {
+            return Err("the GPU is starting again".to_string());
+        }
+        // Supersampling draws it bigger first, and that has to fit too
+        let max = win
+            .device()
+            .physical_device()
+            .limits()
+            .max_image_dimension_2d()
+            / self.config.aa_mode.factor();
+        if width == 0 || height == 0 || width > max || height > max {
+            return Err(format!(
+                "{}x{} is too big for this GPU with {:?}, which can do up to {}x{}",
+                width, height, self.config.aa_mode, max, max
+            ));
+        }
+        let dir = app_dirs2::app_root(app_dirs2::AppDataType::UserData, &crate::APP_INFO)
+            .map_err(|e| e.to_string())?
+            .join("screenshots");
+        std::fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        let path = dir.join(format!("screenshot-{}.png", now.as_millis()));
+
+        let pixels = self.render_rgba(win, cam, world, [width, height]);
+        crate::png::save(&path, width, height, &pixels).map_err(|e| e.to_string())?;
+        Ok(path)
+    }
+
+    fn set_dof(&mut self, dof: bool) {
+        self.dof = dof;
+        self.focus = None;
+    }
+
+    /// Moves the brightness slider, keeping it in range
+    fn set_brightness(&mut self, brightness: f32) {
+        let brightness = clamp_brightness(brightness);
+        if brightness != self.brightness {
+            self.brightness = brightness;
+            info!("Brightness is now {:.1}", brightness);
+        }
+    }
+
+    /// Switches debug views, and shows which one it is in the title bar
+    fn set_debug_mode(&mut self, win: &Window, mode: DebugMode) {
+        self.debug_mode = mode;
+        info!("Debug view is now {:?}", mode);
+        match mode {
+            DebugMode::Off => win.set_status(None),
+            _ => win.set_status(Some(&format!("debug view: {:?}", mode))),
+        }
+    }
+
+    /// The edit a left-click, if `breaks`, or a right-click makes with the brush around `pos`. Returns the chunks that changed.
+    fn click_edit(
+        &self,
+        breaks: bool,
+        world: &mut crate::world::World,
+        pos: Vector3<f32>,
+        dir: Vector3<f32>,
+        edits: &mut Vec<(Vector3<f32>, Material)>,
+    ) -> Vec<Vector3<i32>> {
+        let bake_ao = self.config.bake_ao;
+        if breaks {
+            brush_edit(
+                self.brush,
+                bake_ao,
+                world,
+                pos,
+                dir,
+                Material::Air,
+                |m| m != Material::Air,
+                edits,
+            )
+        } else {
+            brush_edit(
+                self.brush,
+                bake_ao,
+                world,
+                pos,
+                dir,
+                self.material,
+                placeable,
+                edits,
+            )
+        }
+    }
 
-        let pool = vulkano::buffer::CpuBufferPool::upload(window.device());
+    /// A click along `rd` from `ro` didn't hit anything, so if that's because it got to a chunk that isn't loaded yet,
+    /// keep it until the chunk is, for `pending_edit_timeout` seconds
+    fn wait_to_edit(
+        &mut self,
+        breaks: bool,
+        world: &crate::world::World,
+        win: &Window,
+        ro: Vector3<f32>,
+        rd: Vector3<f32>,
+    ) {
+        let timeout = self.config.pending_edit_timeout;
+        if timeout <= 0.0 || self.pending_edits.len() >= MAX_PENDING_EDITS {
+            return;
+        }
+        if let Some(chunk) = world.unloaded_on_ray(ro, rd, REACH) {
+            debug!("Waiting for chunk {} to load to edit it", chunk);
+            self.pending_edits.push(PendingEdit {
+                breaks,
+                ro,
+                rd,
+                chunk,
+                until: Instant::now() + Duration::from_secs_f32(timeout),
+            });
+            win.set_status(Some("edit waiting for its chunk to load"));
+        }
+    }
 
-        (
+    /// Makes the edits that were waiting for chunks that have loaded now, with the brush and material there are now,
+    /// and drops the ones that ran out of time. Returns the chunks that changed.
+    fn make_pending_edits(
+        &mut self,
+        world: &mut crate::world::World,
+        win: &Window,
+        edits: &mut Vec<(Vector3<f32>, Material)>,
+    ) -> Vec<Vector3<i32>> {
+        let now = Instant::now();
+        let mut changed = Vec::new();
+        for mut p in std::mem::take(&mut self.pending_edits) {
+            if world.chunk(p.chunk).is_some() {
+                if let Some(hit) = world.raycast(p.ro, p.rd, REACH) {
+                    let pos = click_target(p.ro, p.rd, &hit, p.breaks);
+                    changed.extend(self.click_edit(p.breaks, world, pos, p.rd, edits));
+                    continue;
+                }
+                // It goes on into another chunk that isn't here yet, or there's nothing there after all
+                match world.unloaded_on_ray(p.ro, p.rd, REACH) {
+                    Some(chunk) => p.chunk = chunk,
+                    None => continue,
+                }
+            }
+            if now < p.until {
+                self.pending_edits.push(p);
+            } else {
+                info!(
+                    "Chunk {} didn't load in time, so the edit waiting for it was dropped",
+                    p.chunk
+                );
+            }
+        }
+        if self.pending_edits.is_empty() {
+            win.set_status(None);
+        }
+        changed
+    }
+
+    /// What `main.frag` needs for the adaptive LOD bias at the resolution in `pc`, or 0 if it's off
+    fn lod_scale(&self, pc: &PushConstants) -> f32 {
+        self.lod_bias.as_ref().map_or(0.0, |l| {
+            crate::lod_bias::lod_scale(l.bias(), pc.fov, pc.resolution[1])
+        })
+    }
+
+    /// Remakes the HDR target at `scale` times the window size, if it isn't already
+    fn set_render_scale(&mut self, win: &Window, scale: f32) {
+        if scale != self.render_scale {
+            self.render_scale = scale;
+            self.resize_targets(win);
+        }
+    }
+
+    /// Remakes the HDR target to match the window, `render_scale` and the supersampling factor
+    fn resize_targets(&mut self, win: &Window) {
+        // They're made again with everything else once the device is back
+        if self.device_lost {
+            return;
+        }
+        let factor = self.config.aa_mode.factor() as f32;
+        self.gpu.post.resize(win, self.render_scale * factor);
+    }
+
+    /// Reads the last frame back from the GPU, and saves a thumbnail of it next to the save.
+    /// Waiting for the copy and shrinking it happen on another thread, which `drop()` waits for.
+    fn save_thumbnail(&mut self, win: &Window) {
+        if self.device_lost {
+            warn!("Not saving a thumbnail, since the GPU is starting again");
+            return;
+        }
+        // The last one is almost certainly done by now, and we only keep track of one at a time
+        if let Some(t) = self.thumbnail_thread.take() {
+            let _ = t.join();
+        }
+        let hdr = self.gpu.post.hdr_image();
+        let [width, height] = hdr.dimensions();
+        // Blitting converts from half floats, which we'd otherwise have to decode ourselves
+        let image = AttachmentImage::with_usage(
+            win.device(),
+            [width, height],
+            vulkano::format::R32G32B32A32Sfloat,
+            ImageUsage {
+                transfer_source: true,
+                transfer_destination: true,
+                ..ImageUsage::none()
+            },
+        )
+        .unwrap();
+        let buf = CpuAccessibleBuffer::from_iter(
+            win.device(),
+            BufferUsage::transfer_destination(),
+            false,
+            (0..width * height).map(|_| [0.0f32; 4]),
+        )
+        .unwrap();
+        let corner = [width as i32, height as i32, 1];
+        let cmd =
+            AutoCommandBufferBuilder::primary_one_time_submit(win.device(), win.queue.family())
+                .unwrap()
+                .blit_image(
+                    hdr,
+                    [0; 3],
+                    corner,
+                    0,
+                    0,
+                    image.clone(),
+                    [0; 3],
+                    corner,
+                    0,
+                    0,
+                    1,
+                    Filter::Nearest,
+                )
+                .unwrap()
+                .copy_image_to_buffer(image, buf.clone())
+                .unwrap()
+                .build()
+                .unwrap();
+
+        let mut f: Box<dyn GpuFuture + Send + Sync> = Box::new(vulkano::sync::now(win.device()));
+        std::mem::swap(&mut f, &mut self.future);
+        let f = match f
+            .then_execute(win.queue.clone(), cmd)
+            .map_err(|e| e.to_string())
+            .and_then(|f| f.then_signal_fence_and_flush().map_err(|e| e.to_string()))
+        {
+            Ok(f) => f,
+            Err(e) => {
+                warn!("Couldn't read back the frame for the thumbnail: {}", e);
+                return;
+            }
+        };
+
+        self.thumbnail_thread = Some(std::thread::spawn(move || {
+            if let Err(e) = f.wait(None) {
+                warn!("Couldn't read back the frame for the thumbnail: {:?}", e);
+                return;
+            }
+            let frame: Vec<f32> = buf.read().unwrap().iter().flatten().copied().collect();
+            let thumbnail = Thumbnail::from_frame(width, height, &frame, THUMBNAIL_SIZE);
+            let dir =
+                app_dirs2::app_root(app_dirs2::AppDataType::UserData, &crate::APP_INFO).unwrap();
+            let path = Thumbnail::path(&dir);
+            match thumbnail.save(&path) {
+                Ok(()) => info!("Saved thumbnail to {}", path.display()),
+                Err(e) => warn!("Couldn't save thumbnail to {}: {}", path.display(), e),
+            }
+        }));
+    }
+
+    pub fn new(
+        window: &Window,
+        cam: &Camera,
+        source: WorldSource,
+        config: Arc<ClientConfig>,
+        events: &mut EventChannel<Event>,
+    ) -> Result<(Self, ClientWorld), ShaderError> {
+        let static_scene = matches!(source, WorldSource::Static(_));
+        let debug_info = config.debug_info;
+        let dof = config.dof;
+        let debug_mode = config.debug_mode;
+        let c = ClientWorld::new(
+            window.device(),
+            window.transfer_queue.clone(),
+            source,
+            Vector3::zeros(),
+            Arc::clone(&config),
+            events.register_reader(),
+        );
+        let dynamic_scale = dynamic_scale(&config);
+        let render_scale = dynamic_scale
+            .as_ref()
+            .map_or(config.render_scale, DynamicScale::scale);
+        let (gpu, future) = Gpu::new(
+            window,
+            &config,
+            render_scale,
+            c.tree_buffer.clone(),
+            c.lod_buffer.clone(),
+        )?;
+
+        Ok((
             Client {
-                tree_buffer,
-                pipeline,
-                desc,
-                beam_pipeline,
-                beam_framebuffer,
-                beam_state,
-                beam_desc,
+                gpu,
+                markers: Markers::default(),
+                remote: RemotePlayers::new(&config),
+                offscreen: None,
                 future,
-                pool,
-                chunk_slots: HashMap::new(),
                 reader_id: events.register_reader(),
-                origin: cam.pos().map(|x| x % CHUNK_SIZE),
+                medium: Material::Air,
+                sun_dir: sun_dir(0.0),
+                day_clock: DayClock::default(),
+                baked_sun: None,
+                plugins: Vec::new(),
+                console: Console::default(),
+                origin: cam.pos().map(|x| x % chunk_size()),
+                render_origin: RenderOrigin::new(cam.pos.coords),
                 root_size: 0.0,
                 recreate_swapchain: false,
+                scale_factor: window.scale_factor(),
+                device_lost: false,
+                debug_info,
+                dof,
+                focus: None,
+                debug_mode,
+                overlay: Overlay::default(),
+                brightness: config.brightness,
                 tot: 0.0,
+                tot_frames: 0,
+                render_scale,
+                dynamic_scale,
+                lod_bias: lod_bias(&config),
+                brush: brush(&config),
+                material: config.place_material,
+                scroll: 0.0,
+                camera_path: load_camera_path(),
+                last_keyframe: 0.0,
+                quitting: false,
+                thumbnail_thread: None,
+                static_scene,
+                on_frame: None,
+                on_world_ready: None,
+                waiting_for_world: config.wait_for_world,
+                looking_at: None,
+                standing_on: None,
+                pending_edits: Vec::new(),
+                gpu_ms: None,
+                // Last, since the fields before it borrow it
+                config,
             },
             c,
-        )
+        ))
     }
 }
@@ -1,55 +1,428 @@
 use crate::common::*;
 use crate::config::*;
 use crate::event::*;
+use crate::node_alloc::{AllocStats, NodeAlloc};
+use crate::offline::{Backoff, EditQueue};
+use crate::profile::{mark, Profiler};
+use crate::world::shadow_area;
 use std::collections::HashMap;
+use std::sync::mpsc::{Receiver, TryRecvError};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use vulkano::command_buffer::{AutoCommandBuffer, AutoCommandBufferBuilder};
 
+type TreeBuffer = Arc<vulkano::buffer::DeviceLocalBuffer<[u32]>>;
+
+/// Extra room after each chunk, in `u32`s, so it can grow a bit from edits without moving
+const CHUNK_SLACK: usize = 64 * 8;
+/// The least time between compacting the tree buffer. Past that, it grows instead, which doesn't re-upload everything.
+const COMPACT_COOLDOWN: Duration = Duration::from_secs(10);
+/// The longest we'll wait for our own server to save and stop after we leave
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+/// If the server hasn't answered a ping in this long, send another one, in case it was lost somehow
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+/// With `ShadowMode::Baked`, how many chunks' shadows to bake again each frame after the sun moves
+const SHADOW_CHUNKS_PER_FRAME: usize = 4;
+
+fn tree_buffer(device: &Arc<vulkano::device::Device>, len: usize) -> TreeBuffer {
+    vulkano::buffer::DeviceLocalBuffer::array(
+        device.clone(),
+        len,
+        vulkano::buffer::BufferUsage {
+            storage_buffer: true,
+            transfer_destination: true,
+            // So it can be copied into a bigger one
+            transfer_source: true,
+            ..vulkano::buffer::BufferUsage::none()
+        },
+        device.active_queue_families(),
+    )
+    .unwrap()
+}
+
+/// A chunk the client has loaded, as answered to `Event::QueryChunks`.
+/// It's just enough for a map; `Event::QueryHeightmap` has the rest.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChunkInfo {
+    pub pos: Vector3<i32>,
+    /// Whether it's all air, so there's no point asking for its heightmap
+    pub empty: bool,
+}
+
+/// How much of the world the client has, and how fast the server answers, as answered to `Event::QueryStats`
+#[derive(Clone, Debug, PartialEq)]
+pub struct WorldStats {
+    /// How many chunks are loaded
+    pub chunks: usize,
+    /// How many nodes of the tree buffer they take up
+    pub nodes: usize,
+    /// How long the last ping took to get to the server and back, in milliseconds.
+    /// `None` before the first one's answered, and for a static scene, which doesn't have a server.
+    pub ping_ms: Option<f64>,
+}
+
+/// How loading the chunks around where the player started is going, from `ReadyWatch::update()`
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Progress {
+    /// How many of them are loaded, and how many there are
+    Loading(usize, usize),
+    Ready,
+    /// They weren't all loaded in time, with how many were, and how many there are
+    TimedOut(usize, usize),
+}
+
+/// Waits for the chunks around where the player started to be loaded, for `Event::WorldReady`
+struct ReadyWatch {
+    /// The ones that weren't loaded last time we checked
+    needed: Vec<Vector3<i32>>,
+    total: usize,
+    /// When we give up on them
+    deadline: Instant,
+}
+
+impl ReadyWatch {
+    /// Waits for every chunk closer than `radius` chunks to the one `pos` is in, which is what the server sends first.
+    /// Ones exactly `radius` away don't count, since the server only sends some of those.
+    fn new(pos: Vector3<f32>, radius: usize, timeout: Duration, now: Instant) -> Self {
+        let center = world_to_chunk(pos);
+        let r = radius as i32;
+        let mut needed = Vec::new();
+        for x in -r..=r {
+            for y in -r..=r {
+                for z in -r..=r {
+                    let p = Vector3::new(x, y, z);
+                    if p.map(|x| x as f32).norm() < radius as f32 {
+                        needed.push(center + p);
+                    }
+                }
+            }
+        }
+        ReadyWatch {
+            total: needed.len(),
+            needed,
+            deadline: now + timeout,
+        }
+    }
+
+    /// Crosses off the chunks `loaded` says are loaded now
+    fn update(&mut self, now: Instant, loaded: impl Fn(&Vector3<i32>) -> bool) -> Progress {
+        self.needed.retain(|c| !loaded(c));
+        let done = self.total - self.needed.len();
+        if self.needed.is_empty() {
+            Progress::Ready
+        } else if now >= self.deadline {
+            Progress::TimedOut(done, self.total)
+        } else {
+            Progress::Loading(done, self.total)
+        }
+    }
+}
+
+/// Where `ClientWorld` gets its chunks
+pub enum WorldSource {
+    /// A server, ours or someone else's, which sends chunks as the player moves around
+    Server(Connection),
+    /// A server we connected to at this address, so we can connect to it again if the connection drops, see `reconnect`
+    Remote(Connection, String),
+    /// A static scene, like from `static_scene` in the config, which is all loaded on the first frame.
+    /// There's no server, so nothing's sent anywhere, edits stay on the client, and nothing's unloaded.
+    Static(Vec<(Vector3<i32>, Chunk)>),
+}
+
+impl WorldSource {
+    /// Where a camera looking along +z can see all of a static scene from, in front of its -z side.
+    /// A server puts the player where it likes, so that's `None`, and so is an empty scene.
+    pub fn start(&self) -> Option<Vector3<f32>> {
+        let chunks = match self {
+            WorldSource::Server(_) | WorldSource::Remote(..) => return None,
+            WorldSource::Static(chunks) => chunks,
+        };
+        let first = chunks.first()?.0;
+        let (l, h) = chunks.iter().fold((first, first), |(l, h), (i, _)| {
+            (l.zip_map(i, i32::min), h.zip_map(i, i32::max))
+        });
+        let min = l.map(|x| x as f32 * chunk_size());
+        let max = h.map(|x| (x + 1) as f32 * chunk_size());
+        let center = (min + max) * 0.5;
+        // With the default 90 degree FOV, it's as wide as it is far away
+        let size = (max - min).x.max((max - min).y);
+        Some(Vector3::new(center.x, center.y, min.z - size))
+    }
+}
+
+/// How getting back to the server is going, while the connection is down
+struct Offline {
+    since: Instant,
+    backoff: Backoff,
+    /// The try that's going, which happens on another thread, since it can take seconds
+    attempt: Option<Receiver<Result<(Connection, u32), JoinError>>>,
+}
+
 pub struct ClientWorld {
-    conn: Connection,
+    /// `None` for a static scene, which doesn't have a server to talk to, or while we're offline
+    conn: Option<Connection>,
+    /// Where the server is, if we connected to it over the network and can connect again
+    address: Option<String>,
+    /// Set while the connection's down and we're trying to get it back
+    offline: Option<Offline>,
+    /// The edits made meanwhile, to send once the server's sent their chunks again
+    offline_edits: EditQueue,
+    /// A static scene's chunks, until they're loaded
+    pending: Vec<(Vector3<i32>, Chunk)>,
     device: Arc<vulkano::device::Device>,
     queue: Arc<vulkano::device::Queue>,
     origin: Vector3<f32>,
     player: Vector3<f32>,
     pub root_size: f32,
+    /// From the config at startup, since that's what we reserved room in `tree_buffer` for
+    max_root_size: f32,
     pub root: Vec<u32>, // The root structure. Points to chunks, gets buffer in the map
     pub map: HashMap<Vector3<i32>, (usize, usize)>, // (start, end)
-    spaces: Vec<(usize, usize)>, // (start, end)
-    pub tree_buffer: Arc<vulkano::buffer::DeviceLocalBuffer<[u32]>>,
+    /// Which nodes in `tree_buffer` are in use. The root structure is reserved at the start.
+    alloc: NodeAlloc,
+    pub tree_buffer: TreeBuffer,
+    /// The color of each non-leaf node for drawing it as one voxel, from `lod_colors()`, one for each node of `tree_buffer`.
+    /// The root structure's are 0, so those are drawn with whatever's first inside them.
+    pub lod_buffer: TreeBuffer,
+    /// Whether `tree_buffer` was replaced with a bigger one since we last told the client
+    grown: bool,
+    /// When we last compacted the tree buffer, for `COMPACT_COOLDOWN`
+    last_compact: Option<Instant>,
+    /// How many nodes `reupload()` has copied since it was last logged, and how many it would have without tracking edits
+    reupload_stats: (usize, usize),
     upload: vulkano::buffer::CpuBufferPool<u32>,
     config: Arc<ClientConfig>,
     reader_id: ReaderId<Event>,
+    /// Whether we're waiting for the server to finish saving
+    saving: bool,
+    /// We got `Event::Quit`, so we leave the server next frame, after sending anything else that came out of this one
+    quitting: bool,
+    /// We sent `Leave`, so there's nothing more to tell the server
+    left: bool,
+    /// The number of the last `Ping` we sent and when, if the server hasn't answered it yet
+    ping: Option<(u32, Instant)>,
+    /// The number to send with the next one
+    next_ping: u32,
+    /// How long the last answered one took, in milliseconds
+    ping_ms: Option<f64>,
+    /// Whether we've sent `Event::WorldReady` yet
+    ready: bool,
+    /// What we're waiting for to send it, once we know where the player started
+    ready_watch: Option<ReadyWatch>,
+    /// How many chunks we last said were loaded with `Event::WorldLoading`
+    ready_progress: usize,
+    /// Where the sun was in the last `Event::SunMoved`, which the shadows are baked for. `None` unless `shadow_mode` is `Baked`.
+    sun: Option<Vector3<f32>>,
+    /// Chunks whose shadows still need baking again for `sun`, nearest first
+    shadow_queue: Vec<Vector3<i32>>,
 }
 
 impl<'a> System<'a> for ClientWorld {
     type SystemData = (
         WriteExpect<'a, crate::world::World>,
         Write<'a, EventChannel<Event>>,
+        Write<'a, Profiler>,
     );
 
-    fn run(&mut self, (mut world, mut events): Self::SystemData) {
+    fn run(&mut self, (mut world, mut events, mut prof): Self::SystemData) {
         let mut new_pos = None;
+        let mut new_config = None;
+        let mut edited = Vec::new();
+        let mut compact = false;
+        let mut save = false;
+        let mut new_device = None;
+        let mut queries = Vec::new();
+        let mut stats = false;
+        // The box around this frame's edits, for baking shadows
+        let mut edit_box: Option<(Vector3<f32>, Vector3<f32>)> = None;
+        let leave = self.quitting && !self.left;
         for event in events.read(&mut self.reader_id) {
+            if let Event::SetBlock(pos, _) = event {
+                let voxel = pos.map(f32::floor);
+                let (min, max) = edit_box.unwrap_or((voxel, voxel));
+                edit_box = Some((min.inf(&voxel), max.sup(&voxel)));
+            }
             match event {
                 Event::PlayerMove(x) => {
                     new_pos = Some(*x);
+                    if !self.ready && self.ready_watch.is_none() {
+                        let radius = self
+                            .config
+                            .view_distance
+                            .min(self.config.game_config.draw_chunks);
+                        let timeout = Duration::from_secs_f32(self.config.world_load_timeout);
+                        self.ready_watch =
+                            Some(ReadyWatch::new(*x, radius, timeout, Instant::now()));
+                    }
+                }
+                Event::ConfigChanged(config) => {
+                    new_config = Some(Arc::clone(config));
+                }
+                Event::SunMoved(sun) if self.config.shadow_mode == ShadowMode::Baked => {
+                    self.sun = Some(*sun);
+                    // Everything's baked again, nearest first, so that's where it catches up first
+                    let player = world_to_chunk(self.player);
+                    self.shadow_queue = self.map.keys().copied().collect();
+                    self.shadow_queue
+                        .sort_by_key(|c| (c - player).map(i32::abs).sum());
+                }
+                Event::SetBlock(pos, mat) if self.offline.is_some() => {
+                    if !self
+                        .offline_edits
+                        .push(*pos, *mat, self.config.offline_edits)
+                    {
+                        debug!("Too many edits while offline, dropped one");
+                    }
+                }
+                Event::SetBlock(pos, mat) => {
+                    self.send(Message::SetBlock(*pos, *mat));
+                }
+                Event::Send(m) => match m.get() {
+                    // The same as the save key, so the title shows it
+                    Some(Message::Save) => save = true,
+                    Some(m) if self.offline.is_some() => {
+                        warn!("Not connected to the server, so {:?} wasn't sent", m)
+                    }
+                    Some(m) => {
+                        self.send(m);
+                    }
+                    None => (),
+                },
+                Event::Reupload(chunks) => {
+                    edited.extend(chunks.iter().cloned());
+                }
+                Event::CompactTree => {
+                    compact = true;
+                }
+                Event::DeviceReset(device, queue) => {
+                    new_device = Some((Arc::clone(device), Arc::clone(queue)));
+                }
+                Event::QueryChunks => queries.push(None),
+                Event::QueryStats => stats = true,
+                Event::QueryHeightmap(chunk) => queries.push(Some(*chunk)),
+                Event::KeyPressed(k) if *k == self.config.keycodes.save => {
+                    save = true;
                 }
                 Event::Quit => {
-                    self.conn
-                        .send(Message::Leave)
-                        .expect("Disconnected from server");
+                    self.quitting = true;
                 }
                 _ => (),
             }
         }
+        // Answered before anything's loaded this frame, but they don't wait long
+        for query in queries {
+            events.single_write(match query {
+                None => Event::LoadedChunks(self.loaded_chunks(&world)),
+                Some(chunk) => Event::Heightmap(chunk, self.heightmap(&world, chunk)),
+            });
+        }
+        if stats {
+            events.single_write(Event::WorldStats(WorldStats {
+                chunks: self.map.len(),
+                nodes: self.alloc_stats().used,
+                ping_ms: self.ping_ms,
+            }));
+            // Only while someone's asking, so there's nothing extra going to the server the rest of the time
+            let waiting = self.ping.map_or(false, |(_, t)| t.elapsed() < PING_TIMEOUT);
+            if !waiting && self.conn.is_some() {
+                self.send(Message::Ping(self.next_ping));
+                self.ping = Some((self.next_ping, Instant::now()));
+                self.next_ping = self.next_ping.wrapping_add(1);
+            }
+        }
+        // A static scene isn't saved anywhere
+        if save && !self.saving && self.conn.is_some() {
+            self.send(Message::Save);
+            self.saving = true;
+            events.single_write(Event::Saving(true));
+        }
         if let Some(x) = new_pos {
             self.player = x;
-            self.conn.send(Message::PlayerMove(x));
+            self.send(Message::PlayerMove(x));
+        }
+        if leave {
+            // Everything from the last frame has been sent, so there's nothing left to upload
+            self.leave();
+            self.left = true;
+            return;
+        }
+        if let Some((device, queue)) = new_device {
+            // First, so anything else this frame goes to the new device too
+            let cmd = self.reset_device(device, queue, &world);
+            self.submit(cmd, &mut events);
+        }
+        if let Some(config) = new_config {
+            let old = self.config.view_distance;
+            let old_lod_color = self.config.lod_color;
+            self.config = config;
+            if self.config.lod_color != old_lod_color {
+                let cmd = self.upload_lods(&world);
+                self.submit(cmd, &mut events);
+            }
+            if self.config.shadow_mode != ShadowMode::Baked {
+                // The bits stay in the voxels, but nothing reads them
+                self.sun = None;
+                self.shadow_queue.clear();
+            }
+            if self.config.view_distance != old {
+                self.send(Message::ViewDistance(self.config.view_distance));
+                if self.config.view_distance < old {
+                    // The server won't send us anything new, so prune the far chunks now
+                    let cmd = self.load_chunks(Vec::new(), &mut world);
+                    self.submit(cmd, &mut events);
+                }
+            }
         }
-        if let Some(m) = self.conn.recv() {
-            // Only load chunks once per frame
+        if let Some(sun) = self.sun {
+            if let Some((min, max)) = edit_box {
+                let (min, max) = shadow_area(min, max + Vector3::repeat(1.0), sun);
+                edited.extend(world.bake_shadows(min, max, sun));
+            }
+            let n = self.shadow_queue.len().min(SHADOW_CHUNKS_PER_FRAME);
+            for chunk in self.shadow_queue.drain(..n) {
+                let center = chunk_to_world(chunk);
+                let r = Vector3::repeat(chunk_size() * 0.5);
+                edited.extend(world.bake_shadows(center - r, center + r, sun));
+            }
+        }
+        if !edited.is_empty() || compact {
+            // Uploading a chunk takes its edits, so a second time would copy all of it
+            edited.sort_by_key(|x| (x.x, x.y, x.z));
+            edited.dedup();
+            let mut cmd = AutoCommandBufferBuilder::primary_one_time_submit(
+                self.device.clone(),
+                self.queue.family(),
+            )
+            .unwrap();
+            for i in edited {
+                cmd = self.reupload(i, cmd, &mut world);
+            }
+            self.log_reuploads();
+            if compact {
+                cmd = self.compact(cmd, &mut world);
+            }
+            // Chunks that grew might have moved
+            self.create_root(&mut world);
+            let cmd = self.upload_root(cmd).build().unwrap();
+            self.submit(cmd, &mut events);
+        }
+        if !self.pending.is_empty() {
+            let chunks = std::mem::take(&mut self.pending);
+            info!("Loading the static scene, {} chunks", chunks.len());
+            let cmd = self.load_chunks(chunks, &mut world);
+            self.submit(cmd, &mut events);
+            // That's all of it, so it's ready as soon as it's uploaded
+            if !self.ready {
+                self.ready = true;
+                events.single_write(Event::WorldReady(true));
+            }
+        }
+        let span = mark();
+        // Only load chunks once per frame, but everything else is cheap, so take all of that
+        while let Some(m) = self.conn.as_ref().and_then(Connection::recv) {
             match m {
-                Message::Chunks(chunks) => {
+                Message::Chunks(mut chunks) => {
+                    self.replay_edits(&mut chunks);
                     // println!(
                     //     "Requested load of {} chunks: \n{:?}",
                     //     chunks.len(),
@@ -57,61 +430,439 @@ impl<'a> System<'a> for ClientWorld {
                     // );
 
                     let cmd = self.load_chunks(chunks, &mut world);
-                    events.single_write(Event::Submit(Once::new((
-                        cmd,
-                        self.origin,
-                        self.root_size,
-                        self.map.clone(),
-                    ))));
+                    self.submit(cmd, &mut events);
+                    break;
+                }
+                Message::OtherPlayer(id, pos) => events.single_write(Event::OtherPlayer(id, pos)),
+                Message::PlayerLeft(id) => events.single_write(Event::PlayerLeft(id)),
+                Message::PlayerName(id, name) => events.single_write(Event::PlayerName(id, name)),
+                Message::TimeOfDay(t) => events.single_write(Event::TimeOfDay(t)),
+                Message::Saved(n) => {
+                    info!("Saved the world, {} chunks", n);
+                    self.saving = false;
+                    events.single_write(Event::Saving(false));
                 }
+                Message::SaveRefused => {
+                    warn!("The server doesn't let anyone but the host save the world");
+                    self.saving = false;
+                    events.single_write(Event::Saving(false));
+                }
+                Message::Seed(seed) => info!("The world seed is {}", seed),
+                Message::Pong(n) => {
+                    // An answer to one we gave up on doesn't count
+                    if let Some((_, t)) = self.ping.filter(|&(sent, _)| sent == n) {
+                        self.ping_ms = Some(t.elapsed().as_secs_f64() * 1000.0);
+                        self.ping = None;
+                    }
+                }
+                Message::EditRejected(reason) => warn!("The server dropped an edit: {}", reason),
+                Message::Leave => self.disconnected(),
                 _ => (),
             }
         }
+        self.reconnect();
+        prof.record("world-recv", span);
+
+        if let Some(watch) = &mut self.ready_watch {
+            let map = &self.map;
+            match watch.update(Instant::now(), |c| map.contains_key(c)) {
+                Progress::Loading(done, total) => {
+                    if done != self.ready_progress {
+                        self.ready_progress = done;
+                        events.single_write(Event::WorldLoading(done, total));
+                    }
+                }
+                Progress::Ready => {
+                    info!("The world around the player is loaded");
+                    events.single_write(Event::WorldReady(true));
+                    self.ready = true;
+                    self.ready_watch = None;
+                }
+                Progress::TimedOut(done, total) => {
+                    warn!(
+                        "Only {} of the {} chunks around the player loaded within {} seconds, going ahead without the rest",
+                        done, total, self.config.world_load_timeout
+                    );
+                    events.single_write(Event::WorldReady(false));
+                    self.ready = true;
+                    self.ready_watch = None;
+                }
+            }
+        }
     }
 }
 
 impl ClientWorld {
+    /// The server hung up. If we can connect to it again, we keep playing on what's loaded until we're back,
+    /// and keep the edits made meanwhile.
+    fn disconnected(&mut self) {
+        if self.address.is_none() || !self.config.reconnect || self.quitting {
+            warn!("The server disconnected");
+            return;
+        }
+        warn!(
+            "Lost the connection to the server, trying to connect again; edits until then are sent once it's back"
+        );
+        self.conn = None;
+        self.ping = None;
+        let now = Instant::now();
+        self.offline = Some(Offline {
+            since: now,
+            backoff: Backoff::new(now),
+            attempt: None,
+        });
+    }
+
+    /// While we're offline, starts the next try at connecting again when it's time, and checks on the one that's going
+    fn reconnect(&mut self) {
+        let (offline, address) = match (&mut self.offline, &self.address) {
+            (Some(offline), Some(address)) => (offline, address),
+            _ => return,
+        };
+        let now = Instant::now();
+        let result = match &offline.attempt {
+            Some(attempt) => match attempt.try_recv() {
+                Ok(result) => result,
+                Err(TryRecvError::Empty) => return,
+                Err(TryRecvError::Disconnected) => Err(JoinError::Timeout),
+            },
+            None => {
+                if offline.backoff.ready(now) {
+                    let (to, from) = std::sync::mpsc::channel();
+                    let (address, config) = (address.clone(), Arc::clone(&self.config));
+                    std::thread::spawn(move || {
+                        let _ = to.send(Connection::join(&address, &config, JOIN_TIMEOUT));
+                    });
+                    offline.attempt = Some(from);
+                }
+                return;
+            }
+        };
+        offline.attempt = None;
+        match result {
+            Ok((conn, size)) if size as f32 == chunk_size() => {
+                info!(
+                    "Connected to the server again after {:.0} seconds",
+                    offline.since.elapsed().as_secs_f32()
+                );
+                self.offline = None;
+                self.conn = Some(conn);
+                // It doesn't know where we are, and it sends every chunk around there again
+                self.send(Message::PlayerMove(self.player));
+                let dropped = self.offline_edits.take_dropped();
+                if dropped > 0 {
+                    warn!(
+                        "{} edits didn't fit in offline_edits while we were offline, so they're lost",
+                        dropped
+                    );
+                }
+                if !self.offline_edits.is_empty() {
+                    info!(
+                        "Sending {} edits from while we were offline as their chunks come in",
+                        self.offline_edits.len()
+                    );
+                }
+            }
+            Ok((conn, size)) => {
+                error!(
+                    "The server's chunks are {} voxels now, instead of {}, so we can't play on it anymore",
+                    size,
+                    chunk_size()
+                );
+                conn.close();
+                self.offline = None;
+            }
+            Err(e) => {
+                debug!("Couldn't connect to the server again: {}", e);
+                offline.backoff.failed(now);
+            }
+        }
+    }
+
+    /// Puts edits from while we were offline back into chunks the server just sent, so they don't disappear, and sends them on.
+    /// The server can still reject them, and then they're only gone once the chunk is sent again.
+    fn replay_edits(&mut self, chunks: &mut [(Vector3<i32>, Chunk)]) {
+        if self.offline_edits.is_empty() {
+            return;
+        }
+        for (loc, chunk) in chunks.iter_mut() {
+            for (pos, mat) in self.offline_edits.take_in(*loc) {
+                chunk.set_block(pos - chunk_to_world(*loc), chunk_depth(), mat);
+                self.send(Message::SetBlock(pos, mat));
+            }
+        }
+    }
+
+    /// Sends `m` to the server, if there is one
+    fn send(&self, m: Message) {
+        if let Some(conn) = &self.conn {
+            conn.send(m);
+        }
+    }
+
+    /// Tells the server we're leaving, and if it's ours, waits for it to save everything and stop
+    fn leave(&mut self) {
+        let conn = match &self.conn {
+            Some(conn) => conn,
+            None => return,
+        };
+        info!("Leaving the server");
+        if conn.send(Message::Leave).is_none() {
+            warn!("The server already disconnected");
+            return;
+        }
+        if let Connection::Local(..) = conn {
+            info!("Waiting for the server to save and stop");
+            let start = Instant::now();
+            loop {
+                match conn.recv() {
+                    Some(Message::Leave) => {
+                        info!("The server stopped");
+                        break;
+                    }
+                    Some(_) => (),
+                    None if start.elapsed() > SHUTDOWN_TIMEOUT => {
+                        warn!(
+                            "The server didn't stop within {:?}, quitting anyway; recent edits might not be saved",
+                            SHUTDOWN_TIMEOUT
+                        );
+                        break;
+                    }
+                    None => std::thread::sleep(Duration::from_millis(10)),
+                }
+            }
+        }
+        conn.close();
+    }
+
+    /// Sends the client a command buffer to run, along with everything it needs to draw the new state of the world
+    fn submit(&mut self, cmd: AutoCommandBuffer, events: &mut EventChannel<Event>) {
+        let tree_buffer = if self.grown {
+            self.grown = false;
+            Some((self.tree_buffer.clone(), self.lod_buffer.clone()))
+        } else {
+            None
+        };
+        events.single_write(Event::Submit(Once::new((
+            cmd,
+            self.origin,
+            self.root_size,
+            tree_buffer,
+        ))));
+    }
+
+    /// Switches to a new device after the old one was lost, and uploads every chunk and the root to a new tree buffer on it.
+    /// The chunks stay where they were, so only the buffer changes.
+    fn reset_device(
+        &mut self,
+        device: Arc<vulkano::device::Device>,
+        queue: Arc<vulkano::device::Queue>,
+        world: &crate::world::World,
+    ) -> AutoCommandBuffer {
+        info!("Uploading the world to the new device");
+        self.tree_buffer = tree_buffer(&device, self.tree_buffer.len());
+        self.lod_buffer = tree_buffer(&device, self.lod_buffer.len());
+        self.upload = vulkano::buffer::CpuBufferPool::upload(device.clone());
+        self.device = device;
+        self.queue = queue;
+        self.grown = true;
+
+        let mut cmd = AutoCommandBufferBuilder::primary_one_time_submit(
+            self.device.clone(),
+            self.queue.family(),
+        )
+        .unwrap();
+        let slots: Vec<_> = self.map.iter().map(|(&i, &slot)| (i, slot)).collect();
+        for (i, (start, _)) in slots {
+            if let Some(chunk) = world.chunk(i) {
+                let chunk = chunk.clone();
+                cmd = self.upload_lod(start, &chunk, cmd);
+                cmd = self.upload_chunk(start..start + chunk.len(), chunk, cmd);
+            }
+        }
+        self.upload_root(cmd).build().unwrap()
+    }
+
+    /// Works out the LOD colors of every chunk again, after `lod_color` changed
+    fn upload_lods(&mut self, world: &crate::world::World) -> AutoCommandBuffer {
+        let mut cmd = AutoCommandBufferBuilder::primary_one_time_submit(
+            self.device.clone(),
+            self.queue.family(),
+        )
+        .unwrap();
+        let slots: Vec<_> = self.map.iter().map(|(&i, &slot)| (i, slot)).collect();
+        for (i, (start, _)) in slots {
+            if let Some(chunk) = world.chunk(i) {
+                let chunk = chunk.clone();
+                cmd = self.upload_lod(start, &chunk, cmd);
+            }
+        }
+        cmd.build().unwrap()
+    }
+
+    /// Every chunk that's loaded, sorted by position
+    pub fn loaded_chunks(&self, world: &crate::world::World) -> Vec<ChunkInfo> {
+        let mut v: Vec<_> = self
+            .map
+            .keys()
+            .filter_map(|&pos| {
+                let chunk = world.chunk(pos)?;
+                Some(ChunkInfo {
+                    pos,
+                    empty: chunk.iter().all(|&x| x == 0),
+                })
+            })
+            .collect();
+        v.sort_by_key(|c| (c.pos.x, c.pos.y, c.pos.z));
+        v
+    }
+
+    /// The top of each column of the chunk at `chunk`, or `None` if it isn't loaded
+    pub fn heightmap(
+        &self,
+        world: &crate::world::World,
+        chunk: Vector3<i32>,
+    ) -> Option<crate::world::Heightmap> {
+        if !self.map.contains_key(&chunk) {
+            return None;
+        }
+        world.heightmap(chunk)
+    }
+
+    /// Sweeps a sphere through the loaded chunks, like `World::sphere_cast()`, which the camera uses directly since it only has the `World`.
+    /// Anything that isn't loaded is empty, so a cast can go into chunks that haven't been generated yet.
+    pub fn sphere_cast(
+        &self,
+        world: &crate::world::World,
+        ro: Vector3<f32>,
+        rd: Vector3<f32>,
+        radius: f32,
+        max_t: f32,
+    ) -> Option<crate::world::Hit> {
+        world.sphere_cast_in(ro, rd, radius, max_t, |c| self.map.contains_key(&c))
+    }
+
+    /// The voxel on the other side of `face` of the one containing `pos`, like `World::neighbor()`.
+    /// Both voxels have to be in loaded chunks, or it's `None`.
+    pub fn neighbor(
+        &self,
+        world: &crate::world::World,
+        pos: Vector3<f32>,
+        face: Face,
+    ) -> Option<crate::world::VoxelRef> {
+        world.neighbor_in(pos, face, |c| self.map.contains_key(&c))
+    }
+
+    /// Calls `f` for every nonempty leaf in a loaded chunk that overlaps the box from `min` to `max`, like `World::visit_box()`.
+    /// Doesn't allocate, so prefer this over `voxels_in_box()` for big boxes.
+    pub fn visit_box(
+        &self,
+        world: &crate::world::World,
+        min: Vector3<f32>,
+        max: Vector3<f32>,
+        mut f: impl FnMut(crate::world::VoxelRef),
+    ) {
+        world.visit_box(min, max, |v| {
+            if self.map.contains_key(&world_to_chunk(v.pos)) {
+                f(v)
+            }
+        });
+    }
+
+    /// Collects every nonempty leaf in a loaded chunk that overlaps the box from `min` to `max`
+    pub fn voxels_in_box(
+        &self,
+        world: &crate::world::World,
+        min: Vector3<f32>,
+        max: Vector3<f32>,
+    ) -> Vec<crate::world::VoxelRef> {
+        let mut ret = Vec::new();
+        self.visit_box(world, min, max, |v| ret.push(v));
+        ret
+    }
+
+    /// Casts a ray through the tree buffer the way the shader does, with `raymarch()`, for testing traversal without a GPU.
+    /// `ro` is in world space, and so is the `pos` it hits. Unlike `World::raycast()`, this goes through the root structure too,
+    /// so it only sees what's in this frame's tree.
+    pub fn cpu_raymarch(
+        &self,
+        world: &crate::world::World,
+        ro: Vector3<f32>,
+        rd: Vector3<f32>,
+        max_steps: usize,
+    ) -> Option<RayCast> {
+        let chunks = self
+            .map
+            .iter()
+            .filter_map(|(&i, &(start, _))| Some((start, world.chunk(i)?)))
+            .collect();
+        raymarch_tree(
+            &self.root,
+            chunks,
+            self.origin,
+            self.root_size,
+            ro,
+            rd,
+            max_steps,
+        )
+    }
+
+    /// How much of the tree buffer the chunks take up, and how fragmented it is, in nodes
+    pub fn alloc_stats(&self) -> AllocStats {
+        self.alloc.stats()
+    }
+
     pub fn new(
         device: Arc<vulkano::device::Device>,
         queue: Arc<vulkano::device::Queue>,
-        conn: Connection,
+        source: WorldSource,
         player: Vector3<f32>,
         config: Arc<ClientConfig>,
         reader_id: ReaderId<Event>,
     ) -> Self {
-        let start_len = 3_200_000; // = 12 MB
-        let mut max_root_size = config.game_config.draw_chunks.pow(3);
-        let mut last = max_root_size * 8;
-        while last > 0 {
-            last /= 8;
-            max_root_size += last;
-        }
-        println!("Max root size = {}", max_root_size);
+        let root_len = root_len(&config);
+        info!("Max root size = {}", root_len);
+        let len = initial_tree_len(&config);
+        let (conn, address, pending) = match source {
+            WorldSource::Server(conn) => (Some(conn), None, Vec::new()),
+            WorldSource::Remote(conn, address) => (Some(conn), Some(address), Vec::new()),
+            WorldSource::Static(chunks) => (None, None, chunks),
+        };
 
         ClientWorld {
             conn,
+            address,
+            offline: None,
+            offline_edits: EditQueue::default(),
+            pending,
             device: device.clone(),
             queue,
-            origin: player.map(|x| x % CHUNK_SIZE),
+            origin: player.map(|x| x % chunk_size()),
             player,
-            root_size: 8.0, //CHUNK_NUM.max() as f32 * CHUNK_SIZE,
+            root_size: 8.0, //CHUNK_NUM.max() as f32 * chunk_size(),
             root: vec![0; 8],
             map: HashMap::new(),
-            spaces: vec![(max_root_size as usize * 8, start_len)],
-            tree_buffer: vulkano::buffer::DeviceLocalBuffer::array(
-                device.clone(),
-                start_len,
-                vulkano::buffer::BufferUsage {
-                    storage_buffer: true,
-                    transfer_destination: true,
-                    ..vulkano::buffer::BufferUsage::none()
-                },
-                device.active_queue_families(),
-            )
-            .unwrap(),
+            max_root_size: config.max_root_size,
+            alloc: NodeAlloc::new(root_len / 8, len / 8),
+            tree_buffer: tree_buffer(&device, len),
+            lod_buffer: tree_buffer(&device, len / 8),
+            grown: false,
+            last_compact: None,
+            reupload_stats: (0, 0),
             upload: vulkano::buffer::CpuBufferPool::upload(device.clone()),
             config,
             reader_id,
+            saving: false,
+            quitting: false,
+            left: false,
+            ping: None,
+            next_ping: 0,
+            ping_ms: None,
+            ready: false,
+            ready_watch: None,
+            ready_progress: 0,
+            sun: None,
+            shadow_queue: Vec::new(),
         }
     }
 
@@ -128,12 +879,57 @@ impl ClientWorld {
         )
         .unwrap();
 
+        // Filling in the holes re-uploads every chunk, so only do it if these wouldn't fit without growing the buffer
+        let needed = chunks
+            .iter()
+            .map(|(_, c)| (c.len() + CHUNK_SLACK) / 8)
+            .sum();
+        let since = self.last_compact.map(|t| t.elapsed());
+        if should_compact(self.alloc.stats(), needed, since) {
+            cmd = self.compact(cmd, world);
+        }
+
+        let mut baked = Vec::new();
         for (i, c) in chunks {
             cmd = self.load(i, c, cmd, world);
+            if self.config.bake_ao {
+                // Bake one voxel into the neighbors too, since their border voxels can see into this chunk
+                let center = chunk_to_world(i);
+                let r = Vector3::repeat(chunk_size() * 0.5 + 1.0);
+                baked.extend(world.bake_ao(center - r, center + r));
+            }
+            if let Some(sun) = self.sun {
+                let center = chunk_to_world(i);
+                let r = Vector3::repeat(chunk_size() * 0.5);
+                baked.extend(world.bake_shadows(center - r, center + r, sun));
+                // It can shadow the chunks that have it between them and the sun, too, but those can wait
+                let (min, max) = shadow_area(center - r, center + r, sun);
+                let (min, max) = (world_to_chunk(min), world_to_chunk(max));
+                for &c in self.map.keys() {
+                    let inside = (0..3).all(|a| c[a] >= min[a] && c[a] <= max[a]);
+                    if inside && c != i && !self.shadow_queue.contains(&c) {
+                        self.shadow_queue.push(c);
+                    }
+                }
+            }
+        }
+        baked.sort_by_key(|x| (x.x, x.y, x.z));
+        baked.dedup();
+        for i in baked {
+            cmd = self.reupload(i, cmd, world);
         }
+        self.log_reuploads();
 
         self.prune_chunks(world);
         self.create_root(world);
+        let s = self.alloc.stats();
+        debug!(
+            "Tree buffer: {} nodes used, {} free in {} pieces ({:.0}% fragmented)",
+            s.used,
+            s.free,
+            s.free_ranges,
+            s.fragmentation * 100.0
+        );
         self.upload_root(cmd).build().unwrap()
     }
 
@@ -142,7 +938,167 @@ impl ClientWorld {
         let view = vulkano::buffer::BufferSlice::from_typed_buffer_access(self.tree_buffer.clone())
             .slice(0..self.root.len())
             .unwrap();
-        builder.copy_buffer(chunk, view).unwrap()
+        let builder = builder.copy_buffer(chunk, view).unwrap();
+        // The root structure doesn't have colors, see `lod_buffer`
+        let n = self.root.len() / 8;
+        let zeros = self.upload.chunk(vec![0; n]).unwrap();
+        let view = vulkano::buffer::BufferSlice::from_typed_buffer_access(self.lod_buffer.clone())
+            .slice(0..n)
+            .unwrap();
+        builder.copy_buffer(zeros, view).unwrap()
+    }
+
+    /// Works out the LOD colors of the chunk at `start` in the tree buffer, with `lod_color` from the config, and uploads them
+    fn upload_lod(
+        &mut self,
+        start: usize,
+        chunk: &Chunk,
+        builder: AutoCommandBufferBuilder,
+    ) -> AutoCommandBufferBuilder {
+        let colors = crate::octree::lod_colors(chunk, self.config.lod_color, &lod_palette());
+        if colors.is_empty() {
+            return builder;
+        }
+        let r = start / 8..start / 8 + colors.len();
+        let data = self.upload.chunk(colors).unwrap();
+        let view = vulkano::buffer::BufferSlice::from_typed_buffer_access(self.lod_buffer.clone())
+            .slice(r)
+            .unwrap();
+        builder.copy_buffer(data, view).unwrap()
+    }
+
+    /// Uploads the current version of an already-loaded chunk into its slot, if it's loaded.
+    /// If the world knows which nodes were edited since it was last uploaded, only those are copied.
+    /// If it's outgrown its slot it gets moved to a new one, so call `create_root()` after.
+    pub fn reupload<'a>(
+        &mut self,
+        idx: Vector3<i32>,
+        mut builder: AutoCommandBufferBuilder,
+        world: &mut WriteExpect<'a, crate::world::World>,
+    ) -> AutoCommandBufferBuilder {
+        let (mut start, mut end) = match self.map.get(&idx) {
+            Some(&slot) => slot,
+            None => return builder,
+        };
+        let len = match world.chunk(idx) {
+            Some(chunk) => chunk.len(),
+            None => return builder,
+        };
+        let edits = world.take_edits(idx);
+        self.reupload_stats.1 += len;
+        if len > end - start {
+            self.alloc.free(start / 8..end / 8);
+            let (r, b) = self.alloc_chunk(len + CHUNK_SLACK, builder);
+            builder = b;
+            start = r.start;
+            end = r.end;
+            self.map.insert(idx, (start, end));
+        } else if let Some(edits) = edits {
+            // It's still where it was, so everything else in the slot is already right.
+            // The LOD colors above the edits can all change, though, so those are done again for all of it.
+            let chunk = world.chunk(idx).unwrap().clone();
+            for r in edits {
+                let data = Chunk(chunk[r.clone()].to_vec());
+                self.reupload_stats.0 += r.len();
+                builder = self.upload_chunk(start + r.start..start + r.end, data, builder);
+            }
+            return self.upload_lod(start, &chunk, builder);
+        }
+        self.reupload_stats.0 += len;
+        let chunk = world.chunk(idx).unwrap().clone();
+        builder = self.upload_lod(start, &chunk, builder);
+        self.upload_chunk(start..start + len, chunk, builder)
+    }
+
+    /// Logs how much `reupload()` copied since last time, compared to uploading the whole chunks
+    fn log_reuploads(&mut self) {
+        let (copied, whole) = std::mem::take(&mut self.reupload_stats);
+        if whole > 0 {
+            debug!(
+                "Uploaded {} bytes of edited chunks, instead of all {} bytes of them",
+                copied * 4,
+                whole * 4
+            );
+        }
+    }
+
+    /// Moves every chunk up against the one before it, so all the free space in the tree buffer is in one piece at the end.
+    /// Pointers inside chunks are relative, so the chunks don't change, they just get uploaded again; call `create_root()` after.
+    pub fn compact<'a>(
+        &mut self,
+        mut builder: AutoCommandBufferBuilder,
+        world: &mut WriteExpect<'a, crate::world::World>,
+    ) -> AutoCommandBufferBuilder {
+        let before = self.alloc.stats();
+        self.last_compact = Some(Instant::now());
+        let sizes = self
+            .map
+            .keys()
+            .filter_map(|&i| Some((i, world.chunk(i)?.len())))
+            .collect();
+        self.map = layout_chunks(&mut self.alloc, sizes);
+        // Chunks can come out bigger than before if they grew into their slack, since they get new slack now
+        self.grow_buffer();
+
+        let slots: Vec<_> = self.map.iter().map(|(&i, &slot)| (i, slot)).collect();
+        for (i, (start, _)) in slots {
+            if let Some(chunk) = world.chunk(i) {
+                let chunk = chunk.clone();
+                builder = self.upload_lod(start, &chunk, builder);
+                builder = self.upload_chunk(start..start + chunk.len(), chunk, builder);
+            }
+        }
+        debug!(
+            "Compacted the tree buffer from {} free ranges to {}",
+            before.free_ranges,
+            self.alloc.stats().free_ranges
+        );
+        builder
+    }
+
+    /// Replaces the tree buffer and the LOD buffer with bigger ones if the allocator has grown past the end of them.
+    /// Returns the old buffers, so anything in them can be copied over.
+    fn grow_buffer(&mut self) -> Option<(TreeBuffer, TreeBuffer)> {
+        let needed = self.alloc.len() * 8;
+        let old_len = self.tree_buffer.len();
+        if needed <= old_len {
+            return None;
+        }
+        // Double it, so we don't have to do this often
+        let new_len = needed.max(old_len * 2);
+        info!(
+            "Growing the tree buffer to {} MB",
+            new_len * 4 / (1024 * 1024)
+        );
+        self.alloc.grow(new_len / 8);
+        self.grown = true;
+        Some((
+            std::mem::replace(&mut self.tree_buffer, tree_buffer(&self.device, new_len)),
+            std::mem::replace(&mut self.lod_buffer, tree_buffer(&self.device, new_len / 8)),
+        ))
+    }
+
+    /// Finds room for `size` `u32`s in the tree buffer, growing it if there isn't any.
+    /// Returns the range it found, also in `u32`s.
+    fn alloc_chunk(
+        &mut self,
+        size: usize,
+        mut builder: AutoCommandBufferBuilder,
+    ) -> (std::ops::Range<usize>, AutoCommandBufferBuilder) {
+        let r = self.alloc.alloc(size / 8);
+        if let Some((old, old_lod)) = self.grow_buffer() {
+            let view =
+                vulkano::buffer::BufferSlice::from_typed_buffer_access(self.tree_buffer.clone())
+                    .slice(0..old.len())
+                    .unwrap();
+            builder = builder.copy_buffer(old, view).unwrap();
+            let view =
+                vulkano::buffer::BufferSlice::from_typed_buffer_access(self.lod_buffer.clone())
+                    .slice(0..old_lod.len())
+                    .unwrap();
+            builder = builder.copy_buffer(old_lod, view).unwrap();
+        }
+        (r.start * 8..r.end * 8, builder)
     }
 
     fn upload_chunk(
@@ -158,7 +1114,7 @@ impl ClientWorld {
         builder.copy_buffer(chunk, view).unwrap()
     }
 
-    /// Loads a chunk in at position `idx` in world-space (divided by CHUNK_SIZE)
+    /// Loads a chunk in at position `idx` in world-space (divided by chunk_size())
     /// Will automatically unload the chunk that was previously there.
     /// Uploads this chunk to GPU memory, and returns a command buffer to copy it to the right location.
     pub fn load<'a>(
@@ -172,37 +1128,14 @@ impl ClientWorld {
         self.unload(idx, world);
 
         // We need this much space
-        // We add space for 64 nodes to allow for the chunk to grow without moving. We'll move it if it goes past 32 - TODO
-        let size = chunk.len() + 64 * 8;
-
-        // Find a space
-        let mut i = 0;
-        let (start, end) = loop {
-            let (space_start, space_end) = self.spaces[i];
-            let space_size = space_end - space_start;
-            if space_size == size {
-                // Our chunk fits EXACTLY, so just remove this space
-                self.spaces.remove(i);
-                break (space_start, space_end);
-            }
-            if space_size > size {
-                // Our chunk fits, so we can shrink this space
-                // We'll put our new chunk at the start
-                self.spaces[i] = (space_start + size, space_end);
-                break (space_start, space_start + size);
-            }
-
-            // This one doesn't fit, so move on to the next space
-            i += 1;
-            if i >= self.spaces.len() {
-                // We're to the end of `spaces`, so this chunk can't fit anywhere
-                panic!("Could not find space for chunk {:?}, size {}!", idx, size);
-            }
-        };
+        let size = chunk.len() + CHUNK_SLACK;
+        let (r, builder) = self.alloc_chunk(size, builder);
+        let (start, end) = (r.start, r.end);
 
-        // Add the 64 empty nodes here
+        // Add the empty nodes here
         let mut chunk_gpu = chunk.clone();
-        chunk_gpu.append(&mut vec![0; 64 * 8]);
+        chunk_gpu.append(&mut vec![0; CHUNK_SLACK]);
+        let builder = self.upload_lod(start, &chunk, builder);
 
         // Add to map & chunks
         world.add_chunk(idx, chunk);
@@ -221,30 +1154,7 @@ impl ClientWorld {
     ) {
         if let Some((start, end)) = self.map.remove(&idx) {
             world.remove_chunk(idx);
-
-            // Add a space
-            for i in 0..self.spaces.len() {
-                let (space_start, space_end) = self.spaces[i];
-
-                if space_start == end {
-                    // This space was at the end of our chunk, so we can just extend it backwards to fill the space
-                    self.spaces[i] = (start, space_end);
-                    break;
-                }
-                if space_end == start {
-                    // Our chunk was just after this space, so we can extend the space forwards
-                    self.spaces[i] = (space_start, end);
-                    break;
-                }
-
-                if space_start > end {
-                    // This space is after our chunk, so we'll put our new space here. It's like insertion sort
-                    self.spaces.insert(i, (start, end));
-                    break;
-                }
-
-                // This space is before our chunk, so we'll keep going until we find the right position
-            }
+            self.alloc.free(start / 8..end / 8);
 
             // We don't have to touch GPU memory, because we aren't necessarily replacing this chunk with anything
         }
@@ -252,9 +1162,13 @@ impl ClientWorld {
 
     /// Unloads chunks that are too far away
     fn prune_chunks<'a>(&mut self, world: &mut WriteExpect<'a, crate::world::World>) {
+        // A static scene is all there is, however far away it goes, and while we're offline we can't get chunks back
+        if self.conn.is_none() {
+            return;
+        }
         let c = world_to_chunk(self.player);
         for i in self.map.clone().keys() {
-            if (c - i).map(|x| x as f32).norm() > self.config.game_config.draw_chunks as f32 {
+            if (c - i).map(|x| x as f32).norm() > self.config.view_distance as f32 {
                 self.unload(*i, world);
             }
         }
@@ -278,44 +1192,414 @@ impl ClientWorld {
         let h = chunk_to_world(h);
         let l = chunk_to_world(l);
 
-        self.origin = chunk_to_world(world_to_chunk((h + l) * 0.5)); // + Vector3::repeat(CHUNK_SIZE * 0.5);
-        self.root_size = (h - l).abs().max() + CHUNK_SIZE; // Add two halves of a chunk
+        self.origin = chunk_to_world(world_to_chunk((h + l) * 0.5)); // + Vector3::repeat(chunk_size() * 0.5);
+        self.root_size = (h - l).abs().max() + chunk_size(); // Add two halves of a chunk
         self.root_size = self.root_size.log2().ceil().exp2(); // Round up to a power of 2
+        if self.root_size > self.max_root_size {
+            // Everything doesn't fit, so keep what's around the player and leave out the rest
+            self.root_size = self.max_root_size;
+            self.origin = chunk_to_world(world_to_chunk(self.player));
+        }
+
+        let chunks: Vec<_> = self.map.keys().cloned().collect();
+        self.root = create_node(&self.map, self.origin, self.root_size, 0, &chunks);
+    }
+}
+
+/// Whether to compact the tree buffer before allocating `needed` more nodes, `since` the last time we did.
+/// That's only worth it if they don't fit in the biggest free range, so the buffer would have to grow,
+/// but do fit in all the free space put together, and not more than once every `COMPACT_COOLDOWN`.
+fn should_compact(stats: AllocStats, needed: usize, since: Option<Duration>) -> bool {
+    needed > stats.largest_free
+        && needed <= stats.free
+        && since.map_or(true, |t| t >= COMPACT_COOLDOWN)
+}
+
+/// Lays out chunks one after another from the start of `alloc`, with fresh slack after each.
+/// `chunks` has the size of each chunk in `u32`s, and they're sorted by position first so neighbors end up close together.
+/// Returns the new `(start, end)` of each chunk, like `ClientWorld.map`.
+fn layout_chunks(
+    alloc: &mut NodeAlloc,
+    mut chunks: Vec<(Vector3<i32>, usize)>,
+) -> HashMap<Vector3<i32>, (usize, usize)> {
+    alloc.clear();
+    chunks.sort_by_key(|(i, _)| (i.x, i.y, i.z));
+    chunks
+        .into_iter()
+        .map(|(i, len)| {
+            let r = alloc.alloc((len + CHUNK_SLACK) / 8);
+            (i, (r.start * 8, r.end * 8))
+        })
+        .collect()
+}
 
-        self.root = self.create_node(self.origin, self.root_size, 0);
-    }
-
-    /// Create a node in the root structure, returning that node and all children
-    fn create_node(&self, pos: Vector3<f32>, size: f32, pointer: usize) -> Vec<u32> {
-        let size = size * 0.5; // Child size
-        let mut ret = vec![0; 8]; // ret[0..8] is the node we're actually working on
-        for uidx in 0..8 {
-            let idx = idx_to_pos(uidx);
-            let pos = pos + idx * size * 0.5;
-            if size > CHUNK_SIZE {
-                // Descend
-                let ptr = ret.len(); // Relative pointer to the first of the new nodes
-                ret.append(&mut self.create_node(pos, size, pointer + ptr));
-                let ptr = (ptr << 1) | 1;
-                ret[uidx] = ptr as u32;
+/// Create a node in the root structure, returning that node and all children.
+/// `chunks` are the loaded chunks that might be inside this node; children without any are left empty.
+/// `map` is where each chunk is in the tree buffer.
+fn create_node(
+    map: &HashMap<Vector3<i32>, (usize, usize)>,
+    pos: Vector3<f32>,
+    size: f32,
+    pointer: usize,
+    chunks: &[Vector3<i32>],
+) -> Vec<u32> {
+    let size = size * 0.5; // Child size
+    let mut ret = vec![0; 8]; // ret[0..8] is the node we're actually working on
+    for uidx in 0..8 {
+        let idx = idx_to_pos(uidx);
+        let pos = pos + idx * size * 0.5;
+        if size > chunk_size() {
+            // The chunks this child covers are the ones its corner chunks are in, and everything between
+            let r = Vector3::repeat((size - chunk_size()) * 0.5);
+            let (lo, hi) = (world_to_chunk(pos - r), world_to_chunk(pos + r));
+            let inside: Vec<_> = chunks
+                .iter()
+                .filter(|c| (0..3).all(|i| c[i] >= lo[i] && c[i] <= hi[i]))
+                .cloned()
+                .collect();
+            if inside.is_empty() {
+                // Nothing's loaded in this child, so don't bother making nodes for it
+                continue;
+            }
+
+            // Descend
+            let ptr = ret.len(); // Relative pointer to the first of the new nodes
+            ret.append(&mut create_node(map, pos, size, pointer + ptr, &inside));
+            let ptr = (ptr << 1) | 1;
+            ret[uidx] = ptr as u32;
+        } else {
+            // This is a chunk, so figure out which one
+            let chunk_loc = world_to_chunk(pos);
+            let ptr = if let Some((chunk_ptr, _)) = map.get(&chunk_loc) {
+                if pointer >= *chunk_ptr {
+                    panic!(
+                        "pointer={}, chunk_ptr={}, chunk {}",
+                        pointer, chunk_ptr, chunk_loc
+                    );
+                }
+                ((chunk_ptr - pointer) << 1) | 1
             } else {
-                // This is a chunk, so figure out which one
-                let chunk_loc = world_to_chunk(pos);
-                let ptr = if let Some((chunk_ptr, _)) = self.map.get(&chunk_loc) {
-                    if pointer >= *chunk_ptr {
-                        panic!(
-                            "pointer={}, chunk_ptr={}, chunk {}",
-                            pointer, chunk_ptr, chunk_loc
-                        );
-                    }
-                    ((chunk_ptr - pointer) << 1) | 1
-                } else {
-                    // There's no chunk here, it's empty
-                    0
-                };
-                ret[uidx] = ptr as u32;
+                // There's no chunk here, it's empty
+                0
+            };
+            ret[uidx] = ptr as u32;
+        }
+    }
+    ret
+}
+
+/// `raymarch()` through a tree buffer with `root` at the start and each of `chunks` at the index it's paired with,
+/// without copying them all into one
+fn raymarch_tree(
+    root: &[u32],
+    mut chunks: Vec<(usize, &Chunk)>,
+    origin: Vector3<f32>,
+    root_size: f32,
+    ro: Vector3<f32>,
+    rd: Vector3<f32>,
+    max_steps: usize,
+) -> Option<RayCast> {
+    chunks.sort_by_key(|&(start, _)| start);
+    let node = |i: usize| {
+        if i < root.len() {
+            return root[i];
+        }
+        let j = match chunks.binary_search_by_key(&i, |&(start, _)| start) {
+            Ok(j) => j,
+            Err(0) => return 0,
+            Err(j) => j - 1,
+        };
+        let (start, chunk) = chunks[j];
+        chunk.get(i - start).copied().unwrap_or(0)
+    };
+    crate::octree::raymarch(node, origin, root_size, ro, rd, max_steps)
+}
+
+/// The color and opacity of each material, by its number, for `lod_colors()`
+fn lod_palette() -> Vec<[f32; 4]> {
+    crate::material::Material::all()
+        .iter()
+        .map(|m| [m.color[0], m.color[1], m.color[2], 1.0 - m.trans])
+        .collect()
+}
+
+/// How much room the tree buffer starts out with for chunks, in `u32`s
+const START_LEN: usize = 3_200_000; // = 12 MB
+
+/// The space reserved for the root structure at the start of the tree buffer, in `u32`s
+fn root_len(config: &ClientConfig) -> usize {
+    let max_chunks = (config.game_config.draw_chunks.min(MAX_VIEW_DISTANCE) * 2 + 1).pow(3);
+    root_capacity(config.max_root_size, max_chunks)
+}
+
+/// How big the tree buffer is to start with, in `u32`s. It can grow past this if the chunks don't fit.
+pub fn initial_tree_len(config: &ClientConfig) -> usize {
+    root_len(config) + START_LEN
+}
+
+/// The most space the root structure can take up in the tree buffer, in `u32`s.
+/// Each level of nodes can have at most one node per chunk, and at most 8 times as many as the level above.
+fn root_capacity(max_root_size: f32, max_chunks: usize) -> usize {
+    let levels = (max_root_size / chunk_size()).log2().round() as u32;
+    let nodes: usize = (0..levels)
+        .map(|i| 8usize.saturating_pow(i).min(max_chunks))
+        .sum();
+    nodes * 8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Finds the leaf at `target` in a tree buffer, the same way `get_voxel()` in `octree.glsl` does
+    fn voxel(tree: &[u32], origin: Vector3<f32>, root_size: f32, target: Vector3<f32>) -> u32 {
+        let mut size = root_size;
+        let mut pos = origin;
+        let mut parent = 0;
+        loop {
+            size *= 0.5;
+            let idx = (target - pos).map(f32::signum);
+            pos += idx * size * 0.5;
+            let node = tree[parent + pos_to_idx(idx)];
+            if node & 1 > 0 {
+                parent += (node >> 1) as usize;
+            } else {
+                break node;
             }
         }
-        ret
+    }
+
+    /// What the GPU would have in the tree buffer, and every voxel it would see in a root of `root_size` at `origin`
+    fn render(
+        map: &HashMap<Vector3<i32>, (usize, usize)>,
+        chunks: &HashMap<Vector3<i32>, Chunk>,
+        origin: Vector3<f32>,
+        root_size: f32,
+    ) -> Vec<u32> {
+        let keys: Vec<_> = map.keys().cloned().collect();
+        let mut tree = create_node(map, origin, root_size, 0, &keys);
+        let len = map.values().map(|x| x.1).max().unwrap();
+        tree.resize(len, 0);
+        for (i, &(start, _)) in map {
+            tree[start..start + chunks[i].len()].copy_from_slice(&chunks[i]);
+        }
+
+        let n = root_size as i32;
+        let min = origin - Vector3::repeat(root_size * 0.5);
+        let mut voxels = Vec::new();
+        for x in 0..n {
+            for y in 0..n {
+                for z in 0..n {
+                    let p = min + Vector3::new(x, y, z).map(|x| x as f32 + 0.5);
+                    voxels.push(voxel(&tree, origin, root_size, p));
+                }
+            }
+        }
+        voxels
+    }
+
+    #[test]
+    fn raymarch_goes_between_chunks() {
+        let air = Chunk::from_voxels(|_| Material::Air);
+        let ground = Chunk::from_voxels(|p| {
+            if p.y < 4 {
+                Material::Stone
+            } else {
+                Material::Air
+            }
+        });
+        let (a, b) = (Vector3::new(0, 0, 0), Vector3::new(1, 0, 0));
+        let origin = Vector3::repeat(chunk_size());
+        let root_size = chunk_size() * 2.0;
+        let start = root_capacity(root_size, 2);
+        let map: HashMap<_, _> = vec![
+            (a, (start, start + air.len())),
+            (b, (start + 800, start + 800 + ground.len())),
+        ]
+        .into_iter()
+        .collect();
+        let keys: Vec<_> = map.keys().cloned().collect();
+        let root = create_node(&map, origin, root_size, 0, &keys);
+        let chunks = vec![(map[&b].0, &ground), (map[&a].0, &air)];
+
+        // Along x through the empty chunk, into the ground in the next one
+        let (ro, rd) = (Vector3::new(1.0, 2.5, 8.3), Vector3::x());
+        let hit = raymarch_tree(&root, chunks.clone(), origin, root_size, ro, rd, 256).unwrap();
+        assert_eq!(hit.mat, Material::Stone);
+        assert!((hit.t[0] - 15.0).abs() < 1e-4, "{:?}", hit);
+        // The same block the chunk finds on its own
+        let center = chunk_to_world(b);
+        let own = ground.raycast(ro - center, rd, 256).unwrap();
+        assert_eq!(hit.pos, own.pos + center);
+        assert!((hit.t[0] - own.t[0]).abs() < 1e-4);
+
+        // Over the ground, it leaves the root without hitting anything
+        let ro = Vector3::new(1.0, 6.5, 8.3);
+        assert!(raymarch_tree(&root, chunks, origin, root_size, ro, rd, 256).is_none());
+    }
+
+    #[test]
+    fn root_fits_its_capacity() {
+        // A root 8 chunks across, full of chunks, with more around it than fit, like when the root's at `max_root_size`
+        let root_size = chunk_size() * 8.0;
+        let origin = Vector3::zeros();
+        let all: Vec<_> = (-6..6)
+            .flat_map(|x| (-6..6).flat_map(move |y| (-6..6).map(move |z| Vector3::new(x, y, z))))
+            .collect();
+        // Far enough along that the root never gets to them
+        let slots = |chunks: &[Vector3<i32>]| -> HashMap<_, _> {
+            chunks
+                .iter()
+                .enumerate()
+                .map(|(n, &c)| (c, (1_000_000 + n * 1000, 1_000_000 + n * 1000 + 8)))
+                .collect()
+        };
+        // The pointers in `root` that go to a chunk rather than another node of the root
+        let chunk_pointers = |root: &[u32]| {
+            root.iter()
+                .enumerate()
+                .filter(|&(i, &x)| x & 1 > 0 && i + (x >> 1) as usize >= 1_000_000)
+                .count()
+        };
+
+        let map = slots(&all);
+        let root = create_node(&map, origin, root_size, 0, &all);
+        // Every level's full, so it takes up all the room there is, and has all 512 chunks inside it
+        assert_eq!(root.len(), root_capacity(root_size, all.len()));
+        assert_eq!(chunk_pointers(&root), 512);
+
+        // With just a few chunks, those limit how much room it needs
+        let few = [
+            Vector3::new(0, 0, 0),
+            Vector3::new(3, 3, 3),
+            Vector3::new(-1, -1, -1),
+            Vector3::new(5, 0, 0),
+        ];
+        let map = slots(&few);
+        let root = create_node(&map, origin, root_size, 0, &few);
+        assert!(root.len() <= root_capacity(root_size, few.len()));
+        assert_eq!(chunk_pointers(&root), 3);
+        assert_eq!(root_capacity(root_size, 3), (1 + 3 + 3) * 8);
+    }
+
+    #[test]
+    fn compact_looks_the_same() {
+        let chunks: HashMap<_, _> = [
+            Vector3::new(0, 0, 0),
+            Vector3::new(1, 0, 0),
+            Vector3::new(0, 1, 1),
+            Vector3::new(1, 1, 1),
+        ]
+        .iter()
+        .enumerate()
+        .map(|(n, &i)| {
+            let chunk = Chunk::from_voxels(|p| {
+                if p.y + p.x % (n + 2) < 4 + n * 2 {
+                    Material::Stone
+                } else {
+                    Material::Air
+                }
+            });
+            (i, chunk)
+        })
+        .collect();
+        let origin = Vector3::repeat(chunk_size());
+        let root_size = chunk_size() * 2.0;
+
+        // Load the chunks with other allocations between them, then free those to leave holes
+        let start = root_capacity(root_size, chunks.len()) / 8;
+        let mut alloc = NodeAlloc::new(start, start);
+        let mut map = HashMap::new();
+        let mut keys: Vec<_> = chunks.keys().cloned().collect();
+        keys.sort_by_key(|i| (i.x, i.y, i.z));
+        let mut holes = Vec::new();
+        for i in keys {
+            holes.push(alloc.alloc(100));
+            let r = alloc.alloc((chunks[&i].len() + CHUNK_SLACK) / 8);
+            map.insert(i, (r.start * 8, r.end * 8));
+        }
+        for r in holes {
+            alloc.free(r);
+        }
+        assert!(alloc.stats().fragmentation > 0.5);
+        let before = render(&map, &chunks, origin, root_size);
+
+        let sizes = chunks.iter().map(|(&i, c)| (i, c.len())).collect();
+        let compacted = layout_chunks(&mut alloc, sizes);
+        let s = alloc.stats();
+        assert_eq!(s.free_ranges, 1);
+        assert_eq!(s.fragmentation, 0.0);
+        // Nothing moved past where it was before
+        assert!(compacted.values().map(|x| x.1).max() < map.values().map(|x| x.1).max());
+
+        let after = render(&compacted, &chunks, origin, root_size);
+        assert!(before.iter().any(|&x| leaf_material(x) == Material::Stone));
+        assert_eq!(before, after);
+    }
+
+    #[test]
+    fn compacts_only_to_avoid_growing() {
+        let mut alloc = NodeAlloc::new(0, 0);
+        let (a, b, c) = (alloc.alloc(10), alloc.alloc(10), alloc.alloc(10));
+        alloc.free(a);
+        alloc.free(c);
+        // Two holes of 10: anything up to 10 fits in one of them, and anything past 20 has to grow anyway
+        let s = alloc.stats();
+        assert!(!should_compact(s, 10, None));
+        assert!(should_compact(s, 11, None));
+        assert!(should_compact(s, 20, None));
+        assert!(!should_compact(s, 21, None));
+
+        // Not again until the cooldown's over, even if it would help
+        let soon = COMPACT_COOLDOWN / 2;
+        assert!(!should_compact(s, 15, Some(soon)));
+        assert!(should_compact(s, 15, Some(COMPACT_COOLDOWN)));
+
+        // Once it's all in one piece, there's nothing to gain
+        alloc.free(b);
+        assert!(!should_compact(alloc.stats(), 15, None));
+        assert!(!should_compact(alloc.stats(), 31, None));
+    }
+
+    #[test]
+    fn ready_when_the_start_is_loaded() {
+        let now = Instant::now();
+        let timeout = Duration::from_secs(10);
+        let pos = Vector3::new(0.5, 0.5, chunk_size() * 3.5);
+        let mut w = ReadyWatch::new(pos, 2, timeout, now);
+        // Closer than 2 chunks is the 3x3x3 cube around it, since its corners are only about 1.7 away
+        assert_eq!(w.total, 27);
+        assert!(w.needed.contains(&Vector3::new(0, 0, 4)));
+        assert!(!w.needed.contains(&Vector3::new(2, 0, 3)));
+
+        let mut loaded: Vec<Vector3<i32>> = Vec::new();
+        assert_eq!(
+            w.update(now, |c| loaded.contains(c)),
+            Progress::Loading(0, 27)
+        );
+        loaded.push(Vector3::new(0, 0, 3));
+        loaded.push(Vector3::new(10, 0, 0));
+        assert_eq!(
+            w.update(now, |c| loaded.contains(c)),
+            Progress::Loading(1, 27)
+        );
+        // It only has to be loaded once, in case the player moves on and it's unloaded again
+        loaded.clear();
+        let all = w.needed.clone();
+        assert_eq!(
+            w.update(now, |c| loaded.contains(c)),
+            Progress::Loading(1, 27)
+        );
+        assert_eq!(w.update(now, |c| all.contains(c)), Progress::Ready);
+
+        // Waiting forever for a server that never sends anything isn't ready, but it's done
+        let mut w = ReadyWatch::new(pos, 2, timeout, now);
+        assert_eq!(
+            w.update(now + timeout, |_| false),
+            Progress::TimedOut(0, 27)
+        );
+        // With nothing to wait for, it's ready straight away
+        let mut w = ReadyWatch::new(pos, 0, timeout, now);
+        assert_eq!(w.update(now, |_| false), Progress::Ready);
     }
 }
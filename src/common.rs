@@ -1,19 +1,204 @@
+use crate::config::{ClientConfig, GameConfig};
 pub use crate::material::Material;
 pub use crate::octree::*;
 pub use na::{Point3, Vector3};
 pub use nalgebra as na;
 pub use num_traits::Zero;
+use serde::{Deserialize, Serialize};
 pub use specs::prelude::*;
 pub use specs::shrev::{EventChannel, ReaderId};
 pub use std::collections::HashMap;
+use std::fmt;
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::mpsc::*;
 use std::sync::RwLock;
+#[cfg(feature = "client")]
 pub use vulkano::half::prelude::*;
 
-pub const CHUNK_SIZE: f32 = 16.0;
+/// The port servers listen on, and clients connect to, if the address doesn't say
+pub const DEFAULT_PORT: u16 = 7700;
+
+/// The longest a player's name can be, in characters; the server cuts longer ones short
+pub const MAX_NAME_LEN: usize = 32;
+
+/// How many voxels wide chunks are by default
+pub const DEFAULT_CHUNK_SIZE: u32 = 16;
+/// Tiny chunks mean lots of per-chunk overhead and a deep tree above them,
+/// and huge ones take long enough to generate and upload that streaming stutters, so we stick to this range.
+pub const MIN_CHUNK_SIZE: u32 = 4;
+pub const MAX_CHUNK_SIZE: u32 = 256;
+
+static CHUNK_SIZE: AtomicU32 = AtomicU32::new(DEFAULT_CHUNK_SIZE);
+
+/// Whether chunks can be `size` voxels wide: it has to be a power of two, between `MIN_CHUNK_SIZE` and `MAX_CHUNK_SIZE`
+pub fn valid_chunk_size(size: u32) -> bool {
+    size.is_power_of_two() && (MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&size)
+}
+
+/// How many voxels wide chunks are, as set by `set_chunk_size()`
+pub fn chunk_size() -> f32 {
+    CHUNK_SIZE.load(Ordering::Relaxed) as f32
+}
+
+/// Sets the width of chunks for the whole process. Everything that handles chunks has to agree on it,
+/// so this should be called at startup, before any chunks are generated or loaded.
+pub fn set_chunk_size(size: u32) {
+    assert!(valid_chunk_size(size), "Invalid chunk size {}", size);
+    let old = CHUNK_SIZE.swap(size, Ordering::Relaxed);
+    if old != size {
+        info!("Chunks are {} voxels wide", size);
+    }
+}
+
+/// How deep chunk octrees can go by default, which is deep enough for single voxels in the biggest chunks
+pub const MAX_OCTREE_DEPTH: u32 = 8;
+
+static MAX_DEPTH: AtomicU32 = AtomicU32::new(MAX_OCTREE_DEPTH);
+
+/// How many levels each chunk's octree goes down below its root: enough for single voxels,
+/// unless `set_max_depth()` limited it to fewer
+pub fn chunk_depth() -> u32 {
+    MAX_DEPTH
+        .load(Ordering::Relaxed)
+        .min(chunk_size().log2() as u32)
+}
+
+/// How wide the smallest leaves in a chunk are, in voxels, at `chunk_depth()`
+pub fn leaf_size() -> f32 {
+    chunk_size() / (1 << chunk_depth()) as f32
+}
+
+/// Limits how deep chunk octrees go for the whole process, like `set_chunk_size()`, so chunks are built and edited
+/// with leaves no smaller than `chunk_size() / 2^depth`. It has to be at least 1.
+pub fn set_max_depth(depth: u32) {
+    assert!(depth >= 1, "Octrees have to be at least one level deep");
+    let old = MAX_DEPTH.swap(depth, Ordering::Relaxed);
+    if old != depth && depth < chunk_size().log2() as u32 {
+        info!(
+            "Octrees are at most {} levels deep, so the smallest voxels are {} wide",
+            depth,
+            leaf_size()
+        );
+    }
+}
 
 pub const REGION_SIZE: i32 = 4;
 
+// Every node in the octree is a `u32`. If bit 0 is set it has children, and the other 31 bits are how far after its parent
+// they start. Otherwise it's a leaf, laid out like this:
+//
+//   bit 0       0, since it's a leaf
+//   bits 1-15   the material, as a `Material`
+//   bits 16-27  baked ambient occlusion, two bits per face in `Face::all()` order
+//   bit 28      set if the AO has been baked; otherwise the shader computes it itself
+//   bits 29-31  for `ShadowMode::Baked`, set if the face on the sun's side of x, y and z is in shadow
+//
+// So an empty leaf is 0. The shaders unpack this too, with the `#define`s at the top of `octree.glsl`,
+// so change those along with these; a test checks that they agree.
+pub const MAT_SHIFT: u32 = 1;
+pub const MAT_MASK: u32 = 0x7fff;
+pub const AO_SHIFT: u32 = 16;
+pub const AO_MASK: u32 = 0xfff << AO_SHIFT;
+pub const AO_BAKED: u32 = 1 << 28;
+pub const SHADOW_SHIFT: u32 = 29;
+pub const SHADOW_MASK: u32 = 0b111 << SHADOW_SHIFT;
+
+/// The fields of a leaf node, unpacked
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Leaf {
+    /// A `Material` as a number, up to `MAT_MASK`. Numbers that aren't a material come out of `leaf_material()` as `Material::Wrong`.
+    pub mat: u32,
+    /// The baked AO, up to `AO_MASK >> AO_SHIFT`, or `None` if it hasn't been baked
+    pub ao: Option<u32>,
+    /// Which of the faces toward the sun are in shadow, a bit for each axis, see `World::bake_shadows()`. 0 if it hasn't been baked.
+    pub shadow: u32,
+}
+
+impl Leaf {
+    /// A leaf of `mat` without any baked AO or shadows, which is what building and editing chunks makes
+    pub fn new(mat: Material) -> Self {
+        Leaf {
+            mat: mat as u32,
+            ao: None,
+            shadow: 0,
+        }
+    }
+}
+
+/// Packs `leaf` into a node. Fields too big for their bits are cut off, so they can't spill into the others.
+pub fn pack_leaf(leaf: Leaf) -> u32 {
+    debug_assert!(
+        leaf.mat <= MAT_MASK,
+        "Material {} doesn't fit in a leaf",
+        leaf.mat
+    );
+    let mut node = (leaf.mat & MAT_MASK) << MAT_SHIFT;
+    if let Some(ao) = leaf.ao {
+        debug_assert!(
+            ao <= AO_MASK >> AO_SHIFT,
+            "AO {:#x} doesn't fit in a leaf",
+            ao
+        );
+        node |= ((ao << AO_SHIFT) & AO_MASK) | AO_BAKED;
+    }
+    debug_assert!(
+        leaf.shadow <= SHADOW_MASK >> SHADOW_SHIFT,
+        "Shadow bits {:#b} don't fit in a leaf",
+        leaf.shadow
+    );
+    node | ((leaf.shadow << SHADOW_SHIFT) & SHADOW_MASK)
+}
+
+/// The fields of a leaf node. Nodes with children don't have any, so this doesn't mean anything for them.
+pub fn unpack_leaf(node: u32) -> Leaf {
+    Leaf {
+        mat: (node >> MAT_SHIFT) & MAT_MASK,
+        ao: if node & AO_BAKED != 0 {
+            Some((node & AO_MASK) >> AO_SHIFT)
+        } else {
+            None
+        },
+        shadow: (node & SHADOW_MASK) >> SHADOW_SHIFT,
+    }
+}
+
+/// The world is right-handed with y up. The octree, terrain, shaders and physics all assume that,
+/// so it isn't configurable; files with another convention are converted when they're imported, with `UpAxis`.
+/// Use these instead of `Vector3::y()` and `1` when you mean up in the world.
+pub const UP_AXIS: usize = 1;
+pub fn up() -> Vector3<f32> {
+    Vector3::y()
+}
+
+/// Which axis is up in a file we're importing, in a right-handed coordinate system
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum UpAxis {
+    Y,
+    Z,
+}
+
+impl UpAxis {
+    /// The size of a voxel model after it's turned to have y up
+    pub fn grid_size(self, size: [u32; 3]) -> [u32; 3] {
+        match self {
+            UpAxis::Y => size,
+            UpAxis::Z => [size[0], size[2], size[1]],
+        }
+    }
+
+    /// Where voxel `p` of a model `size` voxels big goes once it's turned to have y up.
+    /// This rotates it instead of swapping axes, which would mirror the model.
+    pub fn grid_pos(self, p: [u32; 3], size: [u32; 3]) -> [u32; 3] {
+        match self {
+            UpAxis::Y => p,
+            // Turn forwards around x, so +z goes to +y and +y goes to -z
+            UpAxis::Z => [p[0], p[2], size[1] - 1 - p[1]],
+        }
+    }
+}
+
 pub fn radians(degrees: f32) -> f32 {
     std::f32::consts::PI / 180.0 * degrees
 }
@@ -38,26 +223,213 @@ impl<T> Once<T> {
 
 /// Returns the center of a chunk
 pub fn chunk_to_world(chunk: Vector3<i32>) -> Vector3<f32> {
-    chunk.map(|x| (x as f32 + 0.5) * CHUNK_SIZE)
+    chunk.map(|x| (x as f32 + 0.5) * chunk_size())
 }
 pub fn world_to_chunk(world: Vector3<f32>) -> Vector3<i32> {
-    world.map(|x| (x / CHUNK_SIZE).floor() as i32)
+    world.map(|x| (x / chunk_size()).floor() as i32)
+}
+
+/// The chunk that positions sent to the GPU are measured from, so the numbers the shaders work with stay small.
+/// Floats lose precision the bigger they get, which far from the world origin shows up as jittery, blocky shading.
+/// This follows the camera a chunk at a time, so everything the shaders see is within a few chunks of zero.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RenderOrigin(pub Vector3<i32>);
+
+impl RenderOrigin {
+    pub fn new(pos: Vector3<f64>) -> Self {
+        RenderOrigin(pos.map(|x| (x / chunk_size() as f64).floor() as i32))
+    }
+
+    /// Moves to the chunk `pos` is in once it's `threshold` voxels from the middle of the one we're at on any axis,
+    /// or out of that chunk if `threshold` is less than half a chunk. Returns whether it moved.
+    /// Moving less often means less rebasing, and positions are still precise as long as `threshold` isn't huge.
+    pub fn update(&mut self, pos: Vector3<f64>, threshold: f64) -> bool {
+        let half = chunk_size() as f64 * 0.5;
+        let center = self.world().add_scalar(half);
+        if (pos - center).iter().all(|d| d.abs() < threshold.max(half)) {
+            return false;
+        }
+        let new = RenderOrigin::new(pos);
+        let moved = new != *self;
+        *self = new;
+        moved
+    }
+
+    /// The minimum corner of the origin chunk, in world space
+    pub fn world(&self) -> Vector3<f64> {
+        self.0.map(|x| x as f64 * chunk_size() as f64)
+    }
+
+    /// Converts a world-space position to one relative to the origin.
+    /// We subtract in double precision, so the result is as precise as `f32` gets near zero.
+    pub fn local(&self, pos: Vector3<f64>) -> Vector3<f32> {
+        (pos - self.world()).map(|x| x as f32)
+    }
+
+    /// Converts a position relative to `from` into one relative to this origin
+    pub fn rebase(&self, from: RenderOrigin, local: Vector3<f32>) -> Vector3<f32> {
+        local + (from.0 - self.0).map(|x| x as f32 * chunk_size())
+    }
 }
 
 pub fn region_to_chunk(chunk: Vector3<i32>) -> Vector3<i32> {
     chunk.map(|x| x * REGION_SIZE)
 }
+/// Rounds down, so negative chunks still agree with `in_region()`
 pub fn chunk_to_region(world: Vector3<i32>) -> Vector3<i32> {
-    world.map(|x| (x + REGION_SIZE / 2) / REGION_SIZE)
+    world.map(|x| x.div_euclid(REGION_SIZE))
 }
+/// The index of a chunk within its region
 pub fn in_region(chunk: Vector3<i32>) -> usize {
-    let v = chunk.map(|x| ((x % REGION_SIZE) + REGION_SIZE) as usize % REGION_SIZE as usize);
+    let v = chunk.map(|x| x.rem_euclid(REGION_SIZE) as usize);
     v.x + v.y * REGION_SIZE as usize + v.z * REGION_SIZE as usize * REGION_SIZE as usize
 }
 
+/// The biggest message we'll accept over the network, so a bad length can't make us allocate everything
+const MAX_MESSAGE_SIZE: usize = 256 * 1024 * 1024;
+
+/// Why `Connection::connect()` couldn't connect to a server
+#[derive(Debug)]
+pub enum ConnectError {
+    /// The host name couldn't be looked up
+    Resolve(String, std::io::Error),
+    /// It was looked up, but it doesn't have any addresses
+    NoAddresses(String),
+    /// None of its addresses could be reached, with why for each one
+    Connect(Vec<(SocketAddr, std::io::Error)>),
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConnectError::Resolve(host, e) => write!(f, "couldn't look up {}: {}", host, e),
+            ConnectError::NoAddresses(host) => write!(f, "{} doesn't have any addresses", host),
+            ConnectError::Connect(errors) => {
+                write!(f, "couldn't reach it")?;
+                for (i, (addr, e)) in errors.iter().enumerate() {
+                    write!(f, "{} {}: {}", if i == 0 { ":" } else { ";" }, addr, e)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConnectError {}
+
+/// How long to wait for a server to start talking after connecting, in `Connection::join()`
+pub const JOIN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Why `Connection::join()` couldn't start playing on a server
+#[derive(Debug)]
+pub enum JoinError {
+    Connect(ConnectError),
+    /// It sent this before its chunk size, which should be the first thing, or it said a size we can't use
+    Unexpected(String),
+    /// It didn't say what size its chunks are in time
+    Timeout,
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JoinError::Connect(e) => write!(f, "{}", e),
+            JoinError::Unexpected(m) => write!(f, "it sent {} instead of its chunk size", m),
+            JoinError::Timeout => write!(f, "it didn't say what size its chunks are"),
+        }
+    }
+}
+
+impl std::error::Error for JoinError {}
+
+/// The host and port in `address`, with `DEFAULT_PORT` if it doesn't have one.
+/// IPv6 literals can have brackets, like `[::1]:7700`, and need them to have a port.
+fn split_address(address: &str) -> (&str, u16) {
+    let address = address.trim();
+    if let Some(rest) = address.strip_prefix('[') {
+        if let Some(end) = rest.find(']') {
+            let port = rest[end + 1..]
+                .strip_prefix(':')
+                .and_then(|p| p.parse().ok());
+            return (&rest[..end], port.unwrap_or(DEFAULT_PORT));
+        }
+    }
+    // More than one colon without brackets is an IPv6 address on its own
+    if address.matches(':').count() == 1 {
+        let (host, port) = address.split_at(address.find(':').unwrap());
+        if let Ok(port) = port[1..].parse() {
+            return (host, port);
+        }
+    }
+    (address, DEFAULT_PORT)
+}
+
+/// The addresses `address` could mean, like `example.com:7700`, `192.168.0.2`, `::1` or `[::1]:7700`, looking it up if it's a host name.
+/// Hosts with both kinds of address have the IPv6 ones first if `prefer_ipv6` is set, and the IPv4 ones first if not,
+/// but the others are still there to try.
+pub fn resolve(address: &str, prefer_ipv6: bool) -> Result<Vec<SocketAddr>, ConnectError> {
+    let (host, port) = split_address(address);
+    let mut addrs: Vec<SocketAddr> = match host.parse::<IpAddr>() {
+        Ok(ip) => vec![SocketAddr::new(ip, port)],
+        Err(_) => (host, port)
+            .to_socket_addrs()
+            .map_err(|e| ConnectError::Resolve(host.to_string(), e))?
+            .collect(),
+    };
+    if addrs.is_empty() {
+        return Err(ConnectError::NoAddresses(host.to_string()));
+    }
+    prefer(&mut addrs, prefer_ipv6);
+    Ok(addrs)
+}
+
+/// Puts the IPv6 addresses first if `prefer_ipv6` is set, and the IPv4 ones first if not.
+/// Each family stays in the order the resolver gave it.
+fn prefer(addrs: &mut [SocketAddr], prefer_ipv6: bool) {
+    addrs.sort_by_key(|a| a.is_ipv6() != prefer_ipv6);
+}
+
 pub enum Connection {
     Local(Sender<Message>, Receiver<Message>),
-    // TODO some sort of buffered TCP stream inplementation of Connection
+    /// Each message is sent as its length, as a little-endian u32, and then the message in bincode.
+    /// A thread reads incoming messages into the receiver, and sends a `Leave` when the other side hangs up.
+    Tcp(TcpStream, Receiver<Message>),
+    /// Messages in bincode, passed over channels in the same process, for testing the protocol without sockets; see `memory()`.
+    /// Unlike `Local`, the server treats it like any other network player, and everything's serialized like it is over TCP.
+    Memory(Sender<Vec<u8>>, Receiver<Vec<u8>>),
+}
+
+/// Reads messages from `stream` into `to` until the connection closes
+fn read_messages(mut stream: TcpStream, to: Sender<Message>) {
+    loop {
+        let mut len = [0; 4];
+        if stream.read_exact(&mut len).is_err() {
+            break;
+        }
+        let len = u32::from_le_bytes(len) as usize;
+        if len > MAX_MESSAGE_SIZE {
+            warn!("Got a {} byte message, disconnecting", len);
+            break;
+        }
+        let mut buf = vec![0; len];
+        if stream.read_exact(&mut buf).is_err() {
+            break;
+        }
+        match bincode::deserialize(&buf) {
+            Ok(m) => {
+                if to.send(m).is_err() {
+                    // Nobody's listening anymore
+                    return;
+                }
+            }
+            Err(e) => {
+                warn!("Got a bad message, disconnecting: {}", e);
+                break;
+            }
+        }
+    }
+    let _ = stream.shutdown(std::net::Shutdown::Both);
+    let _ = to.send(Message::Leave);
 }
 
 impl Connection {
@@ -70,26 +442,160 @@ impl Connection {
         (client, server)
     }
 
+    /// Create two ends of a `Memory` connection - (client, server)
+    pub fn memory() -> (Connection, Connection) {
+        let (cto, sfrom) = channel();
+        let (sto, cfrom) = channel();
+        let client = Connection::Memory(cto, cfrom);
+        let server = Connection::Memory(sto, sfrom);
+        (client, server)
+    }
+
+    /// Wraps a TCP stream that's already connected, starting a thread to read messages from it.
+    /// The socket options in `config` are set on it first.
+    pub fn tcp(stream: TcpStream, config: &GameConfig) -> std::io::Result<Connection> {
+        stream.set_nodelay(config.tcp_nodelay)?;
+        let socket = socket2::SockRef::from(&stream);
+        if let Some(size) = config.tcp_send_buffer {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = config.tcp_recv_buffer {
+            socket.set_recv_buffer_size(size)?;
+        }
+        let reader = stream.try_clone()?;
+        let (to, from) = channel();
+        std::thread::spawn(move || read_messages(reader, to));
+        Ok(Connection::Tcp(stream, from))
+    }
+
+    /// Connects to a server over TCP at `address`, like `example.com:7700`, `192.168.0.2` or `[::1]:7700`; see `resolve()`.
+    /// Each address it resolves to is tried in turn, in the order `prefer_ipv6` says, giving up on each after `connect_timeout`.
+    pub fn connect(address: &str, config: &ClientConfig) -> Result<Connection, ConnectError> {
+        let addrs = resolve(address, config.prefer_ipv6)?;
+        let timeout = std::time::Duration::from_secs_f32(config.connect_timeout);
+        let mut errors = Vec::new();
+        for addr in addrs {
+            match TcpStream::connect_timeout(&addr, timeout)
+                .and_then(|stream| Connection::tcp(stream, &config.game_config))
+            {
+                Ok(conn) => return Ok(conn),
+                Err(e) => {
+                    debug!("Couldn't connect to {}: {}", addr, e);
+                    errors.push((addr, e));
+                }
+            }
+        }
+        Err(ConnectError::Connect(errors))
+    }
+
+    /// Connects to the server at `address` like `connect()`, and waits up to `timeout` for it to say its chunk size,
+    /// which we have to use too. Then it tells the server how far we can see and what we're called, so it starts sending chunks.
+    /// Returns the connection and the chunk size.
+    pub fn join(
+        address: &str,
+        config: &ClientConfig,
+        timeout: std::time::Duration,
+    ) -> Result<(Connection, u32), JoinError> {
+        let conn = Connection::connect(address, config).map_err(JoinError::Connect)?;
+        let start = std::time::Instant::now();
+        let size = loop {
+            match conn.recv() {
+                Some(Message::ChunkSize(size)) if valid_chunk_size(size) => break size,
+                Some(m) => return Err(JoinError::Unexpected(format!("{:?}", m))),
+                None if start.elapsed() > timeout => return Err(JoinError::Timeout),
+                None => std::thread::sleep(std::time::Duration::from_millis(10)),
+            }
+        };
+        // The server doesn't load anything for us until it knows how far we can see
+        conn.send(Message::ViewDistance(config.view_distance));
+        conn.send(Message::SetName(config.player_name.clone()));
+        Ok((conn, size))
+    }
+
     /// Equivalent to Sender::send() but as an option
     pub fn send(&self, m: Message) -> Option<()> {
         match self {
             Connection::Local(to, _from) => to.send(m).ok(),
+            Connection::Tcp(stream, _from) => {
+                // `Write` is implemented for `&TcpStream`, so we don't need a lock
+                let mut stream: &TcpStream = stream;
+                let data = bincode::serialize(&m).ok()?;
+                let mut buf = Vec::with_capacity(data.len() + 4);
+                buf.extend_from_slice(&(data.len() as u32).to_le_bytes());
+                buf.extend_from_slice(&data);
+                stream.write_all(&buf).ok()
+            }
+            Connection::Memory(to, _from) => to.send(bincode::serialize(&m).ok()?).ok(),
+        }
+    }
+
+    /// Tells the other side we're done. Over TCP that shuts down our half of the stream,
+    /// so the server sees us leave right away instead of waiting for a timeout.
+    pub fn close(&self) {
+        if let Connection::Tcp(stream, _) = self {
+            let _ = stream.shutdown(std::net::Shutdown::Write);
         }
     }
 
     /// Equivalent to Receiver::try_recv() but as an option - doesn't block
     pub fn recv(&self) -> Option<Message> {
         match self {
-            Connection::Local(_to, from) => from.try_recv().ok(),
+            Connection::Local(_, from) | Connection::Tcp(_, from) => from.try_recv().ok(),
+            Connection::Memory(_, from) => match from.try_recv() {
+                Ok(bytes) => match bincode::deserialize(&bytes) {
+                    Ok(m) => Some(m),
+                    // Garbage means the other side is broken, which over TCP would hang up
+                    Err(e) => {
+                        warn!("Got a bad message, disconnecting: {}", e);
+                        Some(Message::Leave)
+                    }
+                },
+                Err(TryRecvError::Empty) => None,
+                // The other end was dropped, which is hanging up like TCP does
+                Err(TryRecvError::Disconnected) => Some(Message::Leave),
+            },
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub enum Message {
     PlayerMove(Vector3<f32>),
+    /// The client wants to see this many chunks in every direction
+    ViewDistance(usize),
     Chunks(Vec<(Vector3<i32>, Chunk)>),
-    //SetBlock(Vector3<i32>, Material),
+    /// The client changed the block at this position. The server applies it and sends the chunk to everyone who can see it.
+    SetBlock(Vector3<f32>, Material),
+    /// The client wants every block in the box between these two corners, inclusive, set to this material, like with `/fill`.
+    /// The server limits how big it can be with `max_fill_volume`.
+    FillRegion(Vector3<i32>, Vector3<i32>, Material),
+    /// The server dropped one of the client's edits, for this reason, like being too far away or too many at once.
+    /// It sends these if `warn_rejected_edits` is on, at most once a second.
+    EditRejected(String),
+    /// The client wants the world saved now. The server replies with `Saved` when it's written.
+    Save,
+    /// The world was saved, with this many chunks in it
+    Saved(usize),
+    /// The server won't save the world for this client, since it isn't the host and `remote_save` is off
+    SaveRefused,
+    /// The client wants to know the world seed. The server replies with `Seed`.
+    GetSeed,
+    Seed(u64),
+    /// The client wants to know how long a message takes to get to the server and back. The server replies with `Pong` and the same number.
+    Ping(u32),
+    Pong(u32),
+    /// The server sends this to network players when they connect, since chunks have to be the same size on both sides
+    ChunkSize(u32),
+    /// Another player, with this id, is here now. The server sends these a few times a second, for players that moved.
+    OtherPlayer(u32, Vector3<f32>),
+    /// The player with this id left
+    PlayerLeft(u32),
+    /// The client's name, which the server tells the other players with `PlayerName`
+    SetName(String),
+    /// The player with this id is called this. The server sends it when they set their name, and to new players for everyone already here.
+    PlayerName(u32, String),
+    /// The server's clock, in seconds, which the sun follows. It sends this to network players when they connect and every so often after.
+    TimeOfDay(f64),
     Leave,
 }
 
@@ -100,13 +606,106 @@ pub enum ChunkMessage {
     LoadChunks(Vec<Vector3<i32>>),
     // Chunks(Vec<(Vector3<i32>, Chunk)>),
     UnloadChunk(Vector3<i32>, Chunk),
-    Players(Vec<Vector3<f32>>),
+    /// Each player's position and view distance
+    Players(Vec<(Vector3<f32>, usize)>),
+    /// Save every chunk in the world now, and reply with `Saved`
+    Save,
+    /// The number of chunks that were saved
+    Saved(usize),
+    /// The seed the world is generated with, which the chunk thread sends once when it starts
+    Seed(u64),
+}
+
+/// A fresh, empty directory in the system's temporary directory, for tests that write files, here and in `tests/`.
+/// It's named after `name` and this process, so tests running at the same time don't step on each other.
+pub fn test_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("quanta-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn z_up_turns_without_mirroring() {
+        let size = [2, 3, 4];
+        assert_eq!(UpAxis::Z.grid_size(size), [2, 4, 3]);
+        let turn = |p| {
+            let q = UpAxis::Z.grid_pos(p, size);
+            Vector3::new(q[0] as f32, q[1] as f32, q[2] as f32)
+        };
+        let o = turn([0, 0, 0]);
+        let (x, y, z) = (
+            turn([1, 0, 0]) - o,
+            turn([0, 1, 0]) - o,
+            turn([0, 0, 1]) - o,
+        );
+        assert_eq!(z, up());
+        // Still right-handed
+        assert_eq!(x.cross(&y), z);
+        // And it fits in the new size
+        assert_eq!(turn([1, 0, 3]), Vector3::new(1.0, 3.0, 2.0));
+        assert_eq!(UpAxis::Y.grid_pos([1, 2, 3], size), [1, 2, 3]);
+    }
+
+    #[test]
+    fn leaves_round_trip() {
+        for mat in 0..=MAT_MASK {
+            for &ao in &[
+                None,
+                Some(0),
+                Some(1),
+                Some(0b10_01_11_00_01_10),
+                Some(AO_MASK >> AO_SHIFT),
+            ] {
+                let leaf = Leaf { mat, ao, shadow: 0 };
+                let node = pack_leaf(leaf);
+                assert_eq!(node & 1, 0, "{:?} looks like it has children", leaf);
+                assert_eq!(node & SHADOW_MASK, 0, "{:?} has shadows", leaf);
+                assert_eq!(unpack_leaf(node), leaf);
+            }
+        }
+        for ao in 0..=AO_MASK >> AO_SHIFT {
+            for &mat in &[0, 1, MAT_MASK] {
+                for shadow in 0..=SHADOW_MASK >> SHADOW_SHIFT {
+                    let leaf = Leaf {
+                        mat,
+                        ao: Some(ao),
+                        shadow,
+                    };
+                    assert_eq!(unpack_leaf(pack_leaf(leaf)), leaf);
+                }
+            }
+        }
+        // Air without AO is an empty leaf
+        assert_eq!(pack_leaf(Leaf::new(Material::Air)), 0);
+        assert_eq!(
+            leaf_material(pack_leaf(Leaf::new(Material::Stone))),
+            Material::Stone
+        );
+    }
+
+    #[test]
+    fn shader_leaf_layout_matches() {
+        let glsl = include_str!("octree.glsl");
+        for define in &[
+            format!("#define MAT_SHIFT {}u", MAT_SHIFT),
+            format!("#define MAT_MASK {:#X}u", MAT_MASK),
+            format!("#define AO_SHIFT {}u", AO_SHIFT),
+            format!("#define AO_BAKED (1u << {})", AO_BAKED.trailing_zeros()),
+            format!("#define SHADOW_SHIFT {}u", SHADOW_SHIFT),
+        ] {
+            assert!(
+                glsl.contains(define.as_str()),
+                "octree.glsl doesn't have {}",
+                define
+            );
+        }
+    }
+
     #[test]
     fn conversion_recip() {
         let v = Vector3::new(-23.0, 3.0, -5.0);
@@ -118,4 +717,266 @@ mod tests {
             (v - chunk_to_world(world_to_chunk(v))).norm()
         );
     }
+
+    #[test]
+    fn region_unique() {
+        // Every chunk near the origin, including negative ones, should have its own spot in a region
+        let mut seen = std::collections::HashSet::new();
+        for x in -9..9 {
+            for y in -9..9 {
+                for z in -9..9 {
+                    let c = Vector3::new(x, y, z);
+                    let r = chunk_to_region(c);
+                    assert!(seen.insert((r, in_region(c))), "{} collides", c);
+                    assert!((c - region_to_chunk(r))
+                        .iter()
+                        .all(|&x| x >= 0 && x < REGION_SIZE));
+                }
+            }
+        }
+    }
+
+    /// Where a ray from `cam` hits the plane `x = wall`, relative to the render origin, like the shader sees it
+    fn hit(cam: Vector3<f64>, wall: f64) -> Vector3<f32> {
+        hit_from(RenderOrigin::new(cam), cam, wall)
+    }
+
+    /// The same, relative to `origin` instead of the chunk `cam` is in
+    fn hit_from(origin: RenderOrigin, cam: Vector3<f64>, wall: f64) -> Vector3<f32> {
+        let ro = origin.local(cam);
+        let rd = Vector3::new(1.0, -0.3, 0.2).normalize();
+        let wall = origin.local(Vector3::new(wall, 0.0, 0.0)).x;
+        ro + rd * ((wall - ro.x) / rd.x)
+    }
+
+    #[test]
+    fn render_origin_far_away() {
+        // The same view near the world origin and millions of voxels out should hit the same spot
+        let offset = Vector3::new(3.3, 5.7, 2.1);
+        for &far in &[0.0, 1_000_000.0, 3_000_000.0, -5_000_000.0] {
+            let base = Vector3::repeat(far);
+            let near = hit(offset, 12.0);
+            let there = hit(base + offset, far + 12.0);
+            assert!(
+                (near - there).norm() < 1e-5,
+                "Hit {} at {}, but {} near the origin",
+                there,
+                far,
+                near
+            );
+        }
+    }
+
+    #[test]
+    fn render_origin_no_jitter() {
+        // Moving the camera a millimeter at a time far out should move the hit smoothly, across chunk boundaries too
+        let start = Vector3::new(2_000_000.0 + 14.0, 40.0, -3_000_000.0);
+        let mut last = None;
+        for i in 0..4000 {
+            let cam = start + Vector3::x() * (i as f64 * 0.001);
+            let origin = RenderOrigin::new(cam);
+            let p = hit(cam, start.x + 20.0);
+            // Compare in world space, since the origin moves partway through
+            let p = p.map(|x| x as f64) + origin.world();
+            if let Some(last) = last {
+                let d: Vector3<f64> = p - last;
+                assert!(
+                    d.y.abs() < 1e-3 && d.z.abs() < 1e-3,
+                    "Hit jumped by {} at step {}",
+                    d,
+                    i
+                );
+            }
+            last = Some(p);
+        }
+    }
+
+    #[test]
+    fn render_origin_threshold() {
+        // Far enough out that f32 can't tell voxels apart, flying a centimeter at a time with a lazy origin
+        let start = Vector3::new(40_000_000.0 + 3.0, 40.0, -50_000_000.0);
+        let threshold = 256.0;
+        let mut origin = RenderOrigin::new(start);
+        let mut moves = 0;
+        let mut last = None;
+        for i in 0..100_000 {
+            let cam = start + Vector3::x() * (i as f64 * 0.01);
+            if origin.update(cam, threshold) {
+                moves += 1;
+            }
+            // It's never far enough from the camera to lose precision
+            let local = origin.local(cam);
+            assert!(local.abs().max() <= threshold as f32 + chunk_size());
+            let p = hit_from(origin, cam, cam.x + 20.0).map(|x| x as f64) + origin.world();
+            if let Some(last) = last {
+                let d: Vector3<f64> = p - last;
+                assert!(
+                    (d.x - 0.01).abs() < 1e-3 && d.y.abs() < 1e-3 && d.z.abs() < 1e-3,
+                    "Hit jumped by {} at step {}",
+                    d,
+                    i
+                );
+            }
+            last = Some(p);
+        }
+        // A kilometer is 62 chunks, but only a few times the threshold
+        assert!((2..=4).contains(&moves), "{} moves", moves);
+    }
+
+    #[test]
+    fn render_origin_rebase() {
+        let a = RenderOrigin(Vector3::new(100_000, 0, -3));
+        let mut b = a;
+        assert!(b.update(Vector3::new(1_600_020.0, 1.0, 1.0), 0.0));
+        assert!(!b.update(Vector3::new(1_600_030.0, 15.0, 15.0), 0.0));
+        let p = Vector3::new(1_600_005.5, 3.25, -40.0);
+        assert_eq!(b.rebase(a, a.local(p)), b.local(p));
+    }
+
+    #[test]
+    fn render_origin_raymarch() {
+        // Ground four voxels deep in a chunk 1.6 million voxels out, marched the way the shader does, relative to the render origin
+        let chunk = Chunk::from_voxels(|p| {
+            if p.y < 4 {
+                Material::Stone
+            } else {
+                Material::Air
+            }
+        });
+        let node = |i: usize| chunk.get(i).copied().unwrap_or(0);
+        let offset = Vector3::new(-5.3, 6.7, 2.2);
+        let rd = Vector3::new(0.6, -0.7, 0.2).normalize();
+        let near =
+            crate::octree::raymarch(node, Vector3::zeros(), chunk_size(), offset, rd, 256).unwrap();
+
+        let center = chunk_to_world(Vector3::new(100_000, 0, -3)).map(|x| x as f64);
+        assert_eq!(center.x, 1_600_008.0);
+        let cam = center + offset.map(|x| x as f64);
+        let origin = RenderOrigin::new(cam);
+        let far = crate::octree::raymarch(
+            node,
+            origin.local(center),
+            chunk_size(),
+            origin.local(cam),
+            rd,
+            256,
+        )
+        .unwrap();
+        assert_eq!(far.mat, Material::Stone);
+        assert!((far.t[0] - near.t[0]).abs() < 1e-4, "{:?} {:?}", far, near);
+        // The same voxel, in world space
+        let pos = far.pos.map(|x| x as f64) + origin.world() - center;
+        assert!(
+            (pos - near.pos.map(|x| x as f64)).norm() < 1e-4,
+            "{} {}",
+            pos,
+            near.pos
+        );
+        // And it's the top of the ground
+        let top = 4.0 - chunk_size() * 0.5;
+        assert!(
+            (offset.y + rd.y * near.t[0] - top).abs() < 1e-4,
+            "{:?}",
+            near
+        );
+    }
+
+    #[test]
+    fn parses_addresses() {
+        let v4 = |a: &str| resolve(a, false).unwrap();
+        assert_eq!(
+            v4("192.168.0.2:7701"),
+            ["192.168.0.2:7701".parse().unwrap()]
+        );
+        assert_eq!(v4(" 10.0.0.1 "), ["10.0.0.1:7700".parse().unwrap()]);
+        assert_eq!(v4("[::1]:7701"), ["[::1]:7701".parse().unwrap()]);
+        // Without brackets the colons are all part of the address
+        assert_eq!(v4("::1"), ["[::1]:7700".parse().unwrap()]);
+        assert_eq!(v4("[fe80::1]"), ["[fe80::1]:7700".parse().unwrap()]);
+    }
+
+    #[test]
+    fn prefers_a_family() {
+        let ips = |prefer_ipv6| {
+            let mut v: Vec<SocketAddr> = ["[::1]:1", "127.0.0.1:1", "[::2]:1", "127.0.0.2:1"]
+                .iter()
+                .map(|a| a.parse().unwrap())
+                .collect();
+            prefer(&mut v, prefer_ipv6);
+            v.iter().map(|a| a.ip().to_string()).collect::<Vec<_>>()
+        };
+        // Each family stays in the order it was in
+        assert_eq!(ips(false), ["127.0.0.1", "127.0.0.2", "::1", "::2"]);
+        assert_eq!(ips(true), ["::1", "::2", "127.0.0.1", "127.0.0.2"]);
+    }
+
+    #[test]
+    fn connects_or_says_why_not() {
+        let config = ClientConfig {
+            connect_timeout: 1.0,
+            ..ClientConfig::default()
+        };
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        assert!(Connection::connect(&address, &config).is_ok());
+
+        // Nothing's listening there anymore
+        drop(listener);
+        match Connection::connect(&address, &config) {
+            Err(e @ ConnectError::Connect(_)) => assert!(e.to_string().contains(&address)),
+            Err(e) => panic!("{}", e),
+            Ok(_) => panic!("connected to {} after it closed", address),
+        }
+    }
+
+    #[test]
+    fn splits_host_names() {
+        assert_eq!(split_address("example.com:7701"), ("example.com", 7701));
+        assert_eq!(
+            split_address(" example.com "),
+            ("example.com", DEFAULT_PORT)
+        );
+        assert_eq!(split_address("[::1]"), ("::1", DEFAULT_PORT));
+        // It says which host it couldn't look up
+        let e = ConnectError::Resolve(
+            "nowhere.invalid".to_string(),
+            std::io::Error::new(std::io::ErrorKind::NotFound, "no such host"),
+        );
+        assert_eq!(
+            e.to_string(),
+            "couldn't look up nowhere.invalid: no such host"
+        );
+    }
+
+    /// Needs a resolver, even though `.invalid` never resolves
+    #[test]
+    #[ignore]
+    fn says_why_it_cant_look_up() {
+        assert!(matches!(
+            resolve("nowhere.invalid:7701", false),
+            Err(ConnectError::Resolve(host, _)) if host == "nowhere.invalid"
+        ));
+    }
+
+    #[test]
+    fn memory_connections_serialize() {
+        let (client, server) = Connection::memory();
+        assert!(server.recv().is_none());
+        client.send(Message::SetName("Alex".to_string()));
+        client.send(Message::Ping(3));
+        assert!(matches!(server.recv(), Some(Message::SetName(n)) if n == "Alex"));
+        assert!(matches!(server.recv(), Some(Message::Ping(3))));
+        server.send(Message::Pong(3));
+        assert!(matches!(client.recv(), Some(Message::Pong(3))));
+
+        // It's bytes in between, so garbage gets there too, and hangs up like it would over TCP
+        let (to, from) = channel();
+        let broken = Connection::Memory(to.clone(), from);
+        to.send(vec![0xff; 3]).unwrap();
+        assert!(matches!(broken.recv(), Some(Message::Leave)));
+
+        // And so does dropping the other end
+        drop(client);
+        assert!(matches!(server.recv(), Some(Message::Leave)));
+    }
 }
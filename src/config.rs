@@ -1,18 +1,1078 @@
+use crate::brush::{BrushShape, MAX_BRUSH_RADIUS};
+use crate::common::{
+    valid_chunk_size, UpAxis, DEFAULT_CHUNK_SIZE, MAX_CHUNK_SIZE, MAX_OCTREE_DEPTH, MIN_CHUNK_SIZE,
+};
+use crate::material::Material;
+use enum_iterator::IntoEnumIterator;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::SystemTime;
+
+/// The most chunks a client can ask to see in every direction, so nobody runs out of memory by accident
+pub const MAX_VIEW_DISTANCE: usize = 32;
+/// The biggest `max_root_size` we allow, since the shader's traversal stack is 16 levels deep
+pub const MAX_ROOT_SIZE: f32 = 65536.0;
+/// The range `render_scale` and its dynamic bounds have to be in. Above 1 is supersampling, which gets expensive fast.
+pub const MIN_RENDER_SCALE: f32 = 0.1;
+pub const MAX_RENDER_SCALE: f32 = 2.0;
+/// How far `brightness` goes each way. Past these, dark scenes wash out or everything goes black.
+pub const MIN_BRIGHTNESS: f32 = 0.5;
+pub const MAX_BRIGHTNESS: f32 = 2.0;
+/// Keeps the brightness slider in range. NaN goes to 1, no change.
+pub fn clamp_brightness(x: f32) -> f32 {
+    if x.is_nan() {
+        1.0
+    } else {
+        x.max(MIN_BRIGHTNESS).min(MAX_BRIGHTNESS)
+    }
+}
+
+/// The farthest `origin_rebase_distance`, where single precision is still about a hundredth of a voxel
+pub const MAX_REBASE_DISTANCE: f32 = 65536.0;
+/// The coarsest `max_lod_bias`, where nodes 256 pixels across are one voxel
+pub const MAX_LOD_BIAS: f32 = 8.0;
+/// The longest `connect_timeout`, in seconds
+pub const MAX_CONNECT_TIMEOUT: f32 = 60.0;
+/// The most `noise_octaves`, which is also as many as `noise`'s own fractals go up to
+pub const MAX_NOISE_OCTAVES: usize = 32;
+/// The longest `world_load_timeout`, in seconds
+pub const MAX_WORLD_LOAD_TIMEOUT: f32 = 600.0;
+/// The longest `pending_edit_timeout`, in seconds
+pub const MAX_PENDING_EDIT_TIMEOUT: f32 = 60.0;
+/// The widest `sprint_fov`, in degrees
+pub const MAX_SPRINT_FOV: f32 = 150.0;
+/// The biggest supersampling factor, since the cost goes up with its square
+pub const MAX_SSAA: u32 = 4;
+/// The most frames `still_accumulation` averages. Past this, half-float precision makes more of them pointless.
+pub const MAX_STILL_SAMPLES: u32 = 256;
 
 /// Config for both the client and server
-#[derive(Deserialize, Serialize)]
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
 pub struct GameConfig {
-    pub draw_chunks: usize, // The number of chunks to draw in every direction
+    pub draw_chunks: usize, // The most chunks the server will send a client in every direction
     pub batch_size: usize,  // The number of chunks to load per batch
+    /// The number of threads to generate chunks on. 0 means half the cores, leaving the rest for the world thread,
+    /// the server and the client, or anything else running on the same machine.
+    pub gen_threads: usize,
+    /// A core to pin the world thread to, which loads, saves and decorates chunks. By default it isn't pinned.
+    pub world_thread_core: Option<usize>,
+    /// Cores to pin the generation threads to, going around the list if there are more threads than cores.
+    /// By default they aren't pinned.
+    pub gen_thread_cores: Vec<usize>,
     pub save_chunks: bool,
+    /// The world generation seed. If it's not set, a saved world keeps the seed it was made with,
+    /// and a new one gets a random seed, which is logged so it can be reused.
+    pub seed: Option<u64>,
+    /// A rhai script to generate terrain with instead of the built-in generator. See `script.rs` for what it needs to define.
+    /// This only works if the game was built with the `scripting` feature.
+    pub gen_script: Option<PathBuf>,
+    /// Which noise the built-in generator shapes its terrain with. The same seed gives the same terrain with each one,
+    /// but changing it on a saved world means new chunks won't line up with the ones that were already generated.
+    /// Scripts get it too, through `noise2` and `noise3`.
+    pub noise_type: NoiseType,
+    /// How stretched out the noise is: the frequency of its first octave, so smaller values make bigger features
+    pub noise_frequency: f64,
+    /// How many layers of finer and finer noise are added together, from 1 to `MAX_NOISE_OCTAVES`.
+    /// More gives rougher detail, and costs more for each point. `Worley` only ever uses one.
+    pub noise_octaves: usize,
+    /// How much higher each octave's frequency is than the last one's
+    pub noise_lacunarity: f64,
+    /// How much of the last octave's strength each one has, so lower values make smoother terrain
+    pub noise_persistence: f64,
+    /// How many voxels wide chunks are: a power of two from 4 to 256. Bigger chunks mean less overhead per chunk,
+    /// but each one takes longer to generate and send, so streaming is coarser.
+    /// This is only read at startup, and a saved world keeps the chunk size it was made with.
+    /// When playing on a server, the server's chunk size is used instead.
+    pub chunk_size: u32,
+    /// How many levels the octree in each chunk can go down. Each level halves the size of the smallest voxels,
+    /// so `log2(chunk_size)` levels, or more, is single voxels, and fewer makes the world blockier
+    /// in exchange for fewer nodes to store and fewer steps for rays to take through them.
+    /// Terrain is generated, and imported models are placed, with anything smaller downsampled to whichever material covers most of it.
+    /// Like `chunk_size`, this is only read at startup, and when playing on a server the server's is what builds the world.
+    pub max_depth: u32,
+    /// Sends each message right away instead of holding small ones back to share a packet with the next (Nagle's algorithm).
+    /// Our messages are already batched where it matters, so this mostly helps small ones that need to arrive soon,
+    /// like `PlayerMove`. Turning it off saves a little bandwidth on headers, at the cost of up to a round trip of latency.
+    pub tcp_nodelay: bool,
+    /// How big the operating system's send buffer is for each connection, in bytes, or the system default if it's not set.
+    /// A bigger one lets a burst of chunks go out without waiting, but messages sent after it wait behind all of it.
+    pub tcp_send_buffer: Option<usize>,
+    /// The same for the receive buffer
+    pub tcp_recv_buffer: Option<usize>,
+    /// The most edits each network player can make a second, counting a `/fill` as one, after a burst of up to a second's worth.
+    /// Edits past that are dropped. 0 turns the limit off. The local player, who's hosting, isn't limited.
+    pub max_edits_per_second: u32,
+    /// The most blocks a network player can fill at once
+    pub max_fill_volume: usize,
+    /// Tell network players when their edits are dropped, for being too fast, too big, or outside the chunks they can see
+    pub warn_rejected_edits: bool,
+    /// Let network players save the world. Saving writes out every chunk, so by default only the local player,
+    /// who's hosting, can, and anyone else who asks gets `SaveRefused`.
+    pub remote_save: bool,
 }
 
-/// Config for just the client
+impl Default for GameConfig {
+    fn default() -> Self {
+        GameConfig {
+            draw_chunks: 16,
+            batch_size: 64,
+            gen_threads: 0,
+            world_thread_core: None,
+            gen_thread_cores: Vec::new(),
+            save_chunks: true,
+            seed: None,
+            gen_script: None,
+            noise_type: NoiseType::Perlin,
+            // These are what the terrain was always generated with, so worlds from before there was a choice still line up
+            noise_frequency: 2.0,
+            noise_octaves: 8,
+            noise_lacunarity: std::f64::consts::PI * 2.0 / 3.0,
+            noise_persistence: 0.5,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            max_depth: MAX_OCTREE_DEPTH,
+            tcp_nodelay: true,
+            tcp_send_buffer: None,
+            tcp_recv_buffer: None,
+            // A brush at `MAX_BRUSH_RADIUS` is 17^3 edits, and this lets a few of those through each second
+            max_edits_per_second: 20_000,
+            max_fill_volume: 32 * 32 * 32,
+            warn_rejected_edits: true,
+            remote_save: false,
+        }
+    }
+}
+
+impl GameConfig {
+    /// Replaces anything that's invalid, with a warning
+    pub fn validate(&mut self) {
+        if !valid_chunk_size(self.chunk_size) {
+            warn!(
+                "chunk_size {} isn't a power of two between {} and {}, using {}",
+                self.chunk_size, MIN_CHUNK_SIZE, MAX_CHUNK_SIZE, DEFAULT_CHUNK_SIZE
+            );
+            self.chunk_size = DEFAULT_CHUNK_SIZE;
+        }
+        if self.max_depth == 0 {
+            warn!("max_depth can't be 0, using 1");
+            self.max_depth = 1;
+        }
+        if self.noise_octaves == 0 || self.noise_octaves > MAX_NOISE_OCTAVES {
+            let octaves = self.noise_octaves.max(1).min(MAX_NOISE_OCTAVES);
+            warn!(
+                "noise_octaves {} isn't between 1 and {}, using {}",
+                self.noise_octaves, MAX_NOISE_OCTAVES, octaves
+            );
+            self.noise_octaves = octaves;
+        }
+        let defaults = GameConfig::default();
+        for (name, x, default) in [
+            (
+                "noise_frequency",
+                &mut self.noise_frequency,
+                defaults.noise_frequency,
+            ),
+            (
+                "noise_lacunarity",
+                &mut self.noise_lacunarity,
+                defaults.noise_lacunarity,
+            ),
+            (
+                "noise_persistence",
+                &mut self.noise_persistence,
+                defaults.noise_persistence,
+            ),
+        ] {
+            if !(x.is_finite() && *x > 0.0) {
+                warn!("{} {} has to be above 0, using {}", name, x, default);
+                *x = default;
+            }
+        }
+        let cores = available_cores();
+        if self.gen_threads > cores {
+            warn!(
+                "gen_threads {} is more than the {} cores available, using {}",
+                self.gen_threads, cores, cores
+            );
+            self.gen_threads = cores;
+        }
+        if let Some(core) = self.world_thread_core.filter(|&c| c >= cores) {
+            warn!(
+                "world_thread_core {} doesn't exist, there are only {} cores; not pinning it",
+                core, cores
+            );
+            self.world_thread_core = None;
+        }
+        if self.gen_thread_cores.iter().any(|&c| c >= cores) {
+            warn!(
+                "gen_thread_cores has cores that don't exist, there are only {}; leaving them out",
+                cores
+            );
+            self.gen_thread_cores.retain(|&c| c < cores);
+        }
+        for (name, size) in [
+            ("tcp_send_buffer", &mut self.tcp_send_buffer),
+            ("tcp_recv_buffer", &mut self.tcp_recv_buffer),
+        ] {
+            if *size == Some(0) {
+                warn!("{} can't be 0, using the system default", name);
+                *size = None;
+            }
+        }
+    }
+
+    /// How many generation threads to start, with 0 in `gen_threads` worked out
+    pub fn gen_thread_count(&self) -> usize {
+        if self.gen_threads == 0 {
+            (available_cores() / 2).max(1)
+        } else {
+            self.gen_threads
+        }
+    }
+}
+
+/// How many threads can run at once on this machine
+pub fn available_cores() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+/// Config for the dedicated server
 #[derive(Deserialize, Serialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// The address to listen on, like "0.0.0.0:7700", or "[::]:7700" for IPv6 as well as IPv4 on most systems
+    pub address: String,
+    /// A filter in `env_logger` syntax, like "info" or "quanta=debug". `RUST_LOG` overrides it.
+    pub log_level: String,
+    pub game_config: GameConfig,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            address: "0.0.0.0:7700".to_string(),
+            log_level: "info".to_string(),
+            game_config: GameConfig::default(),
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let f = std::fs::File::open(path).map_err(|e| e.to_string())?;
+        ron::de::from_reader(f).map_err(|e| e.to_string())
+    }
+}
+
+/// The noise functions the built-in terrain generator can use; see `noise_gen.rs`
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, IntoEnumIterator)]
+pub enum NoiseType {
+    /// Rolling hills and mountains, with flatter valleys between them. This is what worlds used before there was a choice.
+    Perlin,
+    /// Like `Perlin`, but smoother, without its tendency to line up with the axes
+    Simplex,
+    /// Blockier, with softer hills, since it's just blending between random values on a grid
+    Value,
+    /// Sharp ridges, like mountain ranges
+    Ridged,
+    /// Cells, with ridges along the edges between them. Only `noise_frequency` affects it.
+    Worley,
+}
+
+/// How camera rays find their way through the octree. Both find exactly the same voxels, they just take different amounts of work.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub enum Traversal {
+    /// Remember the nodes we came through on a stack, and go back up to them when we leave a child
+    Stack,
+    /// Keep no stack, and go back down from the root each time we leave a child instead
+    Restart,
+}
+
+/// How the color of a node that's drawn as one voxel is picked, for `lod_color`; see `lod_colors()` in `octree.rs`
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub enum LodColor {
+    /// The material of the first thing inside it. It's the cheapest, but it can be anything that's in there.
+    First,
+    /// The average color of everything inside it, by how much of it each one takes up. It can look muddy.
+    Mean,
+    /// The color of the material that takes up the most of it, so it's always one of the real colors
+    Dominant,
+    /// Like `Mean`, but see-through materials like water count for less, so they don't wash out what's under them
+    AlphaWeighted,
+}
+
+/// Where shadows from the sun come from, for `shadow_mode`
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub enum ShadowMode {
+    /// Trace a ray toward the sun from every pixel, every frame. They're exact, and soft with `sun_angular_size`.
+    Rays,
+    /// Bake whether each face toward the sun is in shadow into its voxel, see `World::bake_shadows()`, and only read that when drawing.
+    /// It's much cheaper per frame in big scenes, but it costs time on the CPU when chunks load, when blocks change, and as the sun moves,
+    /// since everything is baked again every few degrees. It's an approximation:
+    /// - Shadows are hard, and a whole face is either in shadow or not, so they're blocky, even more so on leaves bigger than one voxel
+    /// - Only things within `SHADOW_REACH` voxels cast them, and chunks that aren't loaded don't
+    /// - They lag behind the sun a little, and for a while after it moves while the chunks are baked again
+    Baked,
+}
+
+/// How the HDR image is fit into what the screen can show, after bloom, for `tonemap`.
+/// `post.frag` gets it as a number, so keep them in this order.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub enum Tonemap {
+    /// Anything brighter than white just clips
+    None,
+    /// `x / (1 + x)` on each channel: nothing clips, but everything comes out darker and flatter
+    Reinhard,
+    /// A fit of the ACES filmic curve, with more contrast than `Reinhard`, where bright colors roll off to white
+    Aces,
+}
+
+/// How to smooth out jagged edges
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub enum AaMode {
+    None,
+    /// Draw everything at this many times the resolution on each axis, and average it back down.
+    /// This is on top of `render_scale`, and costs about the square of it.
+    Ssaa(u32),
+    /// Temporal anti-aliasing. This needs a history buffer, which we don't have yet, so picking it is an error.
+    Taa,
+}
+
+impl AaMode {
+    /// How many times bigger than the window the HDR image is on each axis, before `render_scale`
+    pub fn factor(self) -> u32 {
+        match self {
+            AaMode::Ssaa(n) => n.max(1),
+            AaMode::None | AaMode::Taa => 1,
+        }
+    }
+}
+
+/// Debug views that replace or draw over the normal image
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub enum DebugMode {
+    Off,
+    /// Color each pixel by how many steps its ray took, from blue for none to red for `max_ray_steps`
+    Steps,
+    /// Tint each chunk a different color, with lines along the borders between them,
+    /// to see which chunks are loaded and that they line up
+    Chunks,
+}
+
+impl DebugMode {
+    /// The one the `debug_mode` key switches to from this one
+    pub fn next(self) -> DebugMode {
+        match self {
+            DebugMode::Off => DebugMode::Steps,
+            DebugMode::Steps => DebugMode::Chunks,
+            DebugMode::Chunks => DebugMode::Off,
+        }
+    }
+}
+
+/// Config for just the client
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
 pub struct ClientConfig {
     pub keycodes: crate::input::KeyCodes,
+    /// A filter in `env_logger` syntax, like "info" or "quanta=debug". `RUST_LOG` overrides it.
+    pub log_level: String,
+    /// Bake ambient occlusion into the octree on the CPU, instead of computing it in the shader every frame
+    pub bake_ao: bool,
+    /// The number of chunks to load in every direction. The server might limit this further.
+    pub view_distance: usize,
+    /// The biggest the octree root can get, in voxels. This has to be a power of two, at least two chunks across.
+    /// Chunks that don't fit in the root around the player aren't drawn, so it should be at least
+    /// `(view_distance * 2 + 1) * 16` rounded up to a power of two. Changing it takes a restart.
+    ///
+    /// Each doubling adds a level to the octree, so rays need more steps to get anywhere:
+    /// a small root with a short view distance is fast, but a big one needs `max_ray_steps` raised to match,
+    /// or distant terrain gets cut off and shows up as sky.
+    pub max_root_size: f32,
+    /// The most octree nodes a ray from the camera can visit. See `max_root_size` for how to choose it.
+    pub max_ray_steps: u32,
+    /// How close to the camera things start being drawn, in voxels
+    pub near: f32,
+    /// How far away things stop being drawn, in voxels, whatever `max_ray_steps` is.
+    /// They fade into the sky on the way there, so there isn't a hard edge.
+    pub far: f32,
+    /// Rays that get farther than this, in voxels, get fewer steps the farther they go,
+    /// down to `min_step_fraction` of `max_ray_steps` at `far`. Those are mostly fog, so this saves time without changing much.
+    /// 0 gives every ray all of them.
+    pub step_falloff_start: f32,
+    pub min_step_fraction: f32,
+    /// The color of the sky straight up, in the middle of the day
+    pub sky_zenith: [f32; 3],
+    /// The color of the sky at the horizon, which is also what fog fades into
+    pub sky_horizon: [f32; 3],
+    /// Draw the sky. With it off, `clear_color` shows through instead, and fog fades into that.
+    pub sky: bool,
+    /// What the screen is cleared to before anything's drawn, as linear RGBA.
+    /// This is only visible where nothing else is drawn, so usually only with `sky` off.
+    pub clear_color: [f32; 4],
+    /// Don't clear the window before each frame while `sky` is on, since the sky covers everything the world doesn't.
+    /// It's a little less memory bandwidth. With `sky` off it's always cleared, so `clear_color` shows through.
+    pub skip_clear: bool,
+    /// The angular radius of the sun disk, in degrees
+    pub sun_size: f32,
+    /// How bright the sun disk is compared to the sky
+    pub sun_intensity: f32,
+    /// How big the sun is for shadows, in degrees. Bigger means softer shadows, and 0 means hard ones.
+    pub sun_angular_size: f32,
+    /// Whether shadows are traced for every pixel or baked into the voxels, see `ShadowMode`
+    pub shadow_mode: ShadowMode,
+    /// The color of the outline around the voxel you're looking at
+    pub outline_color: [f32; 3],
+    /// The thickness of that outline, in voxels
+    pub outline_width: f32,
+    /// Make bright things glow
+    pub bloom: bool,
+    /// How bright something has to be before it glows
+    pub bloom_threshold: f32,
+    /// How strong that glow is
+    pub bloom_intensity: f32,
+    /// How colors too bright for the screen are brought back into range, which keeps bloom from washing out to white
+    pub tonemap: Tonemap,
+    /// The brightness slider, for dark scenes. It's a gamma curve on the final image, so above 1 brightens shadows
+    /// more than highlights and nothing that was visible clips to white. It's between 0.5 and 2, and this is what it starts as;
+    /// the `brightness_up` and `brightness_down` keys move it.
+    pub brightness: f32,
+    /// Blur the image along the direction things are moving on screen
+    pub motion_blur: bool,
+    /// How much of the movement in one frame to blur over
+    pub motion_blur_strength: f32,
+    /// The number of samples along that movement. More is smoother but slower, and it's capped at 32.
+    pub motion_blur_samples: u32,
+    /// Blur things nearer or farther than whatever's under the crosshair, like a camera focused on it.
+    /// The `dof` key toggles this while playing, to turn it on for screenshots and off for building.
+    /// While the camera's moving with `motion_blur` on, the motion blur replaces it.
+    pub dof: bool,
+    /// How blurry things out of focus get: the radius of the blur for something infinitely far behind the focus,
+    /// as a fraction of the screen height. It's capped at 0.05.
+    pub dof_aperture: f32,
+    /// How long focusing takes, in seconds: each frame the focus moves `1 - e^(-frame time / dof_focus_time)`
+    /// of the way to whatever's under the crosshair. 0 focuses instantly.
+    pub dof_focus_time: f32,
+    /// Which way rays go through the octree, for comparing how fast they are
+    pub traversal: Traversal,
+    /// Which axes, x, y and z, the world wraps around on when it's drawn, for looking at tiling scenes: past each side of the root,
+    /// the power-of-two box around what's loaded, is the other side again. It's only drawing; moving and editing don't wrap.
+    /// Rays that go around keep going until they run out of steps or reach `far`, so this can be slow.
+    pub world_wrap: [bool; 3],
+    /// The resolution the world is drawn at, compared to the window. Below 1 is faster but blurrier.
+    pub render_scale: f32,
+    /// The camera's position is kept in double precision, and the shaders get positions relative to a render origin near it.
+    /// The origin moves to the camera's chunk once the camera is this many voxels from it, so it doesn't move every chunk.
+    /// Anything less than half a chunk moves it every chunk, and it can be up to 65536.
+    pub origin_rebase_distance: f32,
+    /// Adjust `render_scale` while playing, keeping it between `min_render_scale` and `max_render_scale`,
+    /// so frames take about `target_frame_ms`. It only changes after a while of frames being too slow or fast.
+    pub dynamic_resolution: bool,
+    /// The frame time to aim for with `dynamic_resolution` and `adaptive_lod`, in milliseconds. 16.7 is 60 FPS.
+    pub target_frame_ms: f32,
+    pub min_render_scale: f32,
+    pub max_render_scale: f32,
+    /// Draw distant parts of the world with less detail while playing when frames take longer than `target_frame_ms`,
+    /// and bring it back when there's time to spare. With `dynamic_resolution` too, detail goes before resolution does.
+    pub adaptive_lod: bool,
+    /// How coarse `adaptive_lod` goes, in powers of two: at 0 things smaller than a pixel are merged, at 2 ones up to four pixels.
+    /// It starts at `min_lod_bias`.
+    pub min_lod_bias: f32,
+    pub max_lod_bias: f32,
+    /// How gradually detail comes and goes with `adaptive_lod`, so it eases in instead of popping as things get closer or the bias changes.
+    /// It's how much of the way from one level of detail to the next a node's color fades between them over, from 0 to 1; 0 switches all at once.
+    /// The next level's color is just the child the ray goes into, so it doesn't take any more steps, which the step count `debug_mode` shows.
+    pub lod_fade: f32,
+    /// What color a node is when the level of detail draws it as one voxel, which distant terrain is mostly made of
+    pub lod_color: LodColor,
+    /// Anti-aliasing, which can be changed while playing
+    pub aa_mode: AaMode,
+    /// Soften the silhouettes of voxels, by blending pixels on an edge with what's behind it by how much of the pixel hangs over.
+    /// It only costs another ray for those pixels, and it works with any `aa_mode`. Faces and corners inside a surface stay sharp.
+    pub edge_aa: bool,
+    /// How much of what's behind an edge `edge_aa` blends in, from 0 to 1
+    pub edge_aa_strength: f32,
+    /// Once the camera has been still for `still_frames` frames, average frames drawn a fraction of a pixel apart into a sharp image,
+    /// and keep showing it once there are `still_samples` of them, until the camera moves or the world changes.
+    /// It's on top of `aa_mode`, and it takes two more images the size of the HDR target while it's going.
+    pub still_accumulation: bool,
+    pub still_frames: u32,
+    /// Up to `MAX_STILL_SAMPLES`
+    pub still_samples: u32,
+    /// Draw a debug view instead of the normal image, or on top of it. This is what it starts as; the `debug_mode` key cycles through them.
+    pub debug_mode: DebugMode,
+    /// Which GPU to use, by part of its name, like "nvidia" - case doesn't matter. The ones available are logged at startup.
+    /// If this isn't set or nothing matches, we pick the only one there is, or ask if there's more than one.
+    /// Changing it takes a restart.
+    pub device_name: Option<String>,
+    /// Like `device_name`, but by its number in the list at startup. This wins if both are set.
+    pub device_index: Option<usize>,
+    /// Upload chunks on a separate transfer queue, if the GPU has one, so they don't wait behind drawing.
+    /// Turning it off puts everything on the one queue, to compare with `--bench`. Changing it takes a restart.
+    pub transfer_queue: bool,
+    /// Log the frame rate and camera position every `debug_info_frames` frames.
+    /// The `debug_info` key toggles this while playing.
+    pub debug_info: bool,
+    pub debug_info_frames: usize,
+    /// Smooths out mouse movement for noisy mice, in seconds: each frame turns the camera `1 - e^(-frame time / mouse_smoothing)`
+    /// of the way to where the mouse has moved it, so it glides there instead of jittering, and ends up in the same place.
+    /// Up to about 0.02 doesn't feel laggy. 0 turns it off.
+    pub mouse_smoothing: f32,
+    /// Start out walking instead of flying. The `walk` key switches between them.
+    pub walk: bool,
+    /// How fast you walk, in voxels per second
+    pub walk_speed: f32,
+    /// How high you can jump, in voxels
+    pub jump_height: f32,
+    /// How fast you speed up falling, in voxels per second squared
+    pub gravity: f32,
+    /// How long after walking off an edge you can still jump, in seconds
+    pub coyote_time: f32,
+    /// How many more times you can jump in the air before you land. 0 means no double jumps.
+    pub air_jumps: u32,
+    /// The highest ledge you walk up onto without jumping, in voxels. 0 turns it off.
+    pub step_height: f32,
+    /// How many times as fast you move while holding the `sprint` key, walking or flying
+    pub sprint_speed: f32,
+    /// The vertical field of view to widen to while sprinting, in degrees, for a sense of speed.
+    /// It eases there and back over about a tenth of a second. It never narrows a view that's already wider,
+    /// and a camera path's own field of view takes over while one's playing. 0 turns it off.
+    pub sprint_fov: f32,
+    /// Which axis is up in .vox files dropped on the window. MagicaVoxel uses `Z`, but some tools write them with `Y` up.
+    /// They're turned to fit the world, which always has y up.
+    pub vox_up_axis: UpAxis,
+    /// How far in the past other players are drawn, in seconds, so there are positions on both sides to move them smoothly between.
+    /// It should be a bit longer than the time between updates from the server, which is about a twentieth of a second.
+    /// It's an `f64` like the arrival times it's subtracted from, so it doesn't pick up rounding from an `f32`.
+    pub remote_player_delay: f64,
+    /// How long to keep moving other players the way they were going when an update is late, in seconds, before they stop to wait for it
+    pub remote_player_extrapolation: f64,
+    /// What other players see over our head when we play on a server. It's sent when we connect,
+    /// and the server cuts it to `MAX_NAME_LEN` characters.
+    pub player_name: String,
+    /// The server to play on instead of starting one, like "example.com", "192.168.0.2:7700" or "[::1]:7700".
+    /// The port is 7700 if it doesn't say, and `--connect <address>` overrides this.
+    pub server_address: Option<String>,
+    /// When the server's host name has both IPv4 and IPv6 addresses, try the IPv6 ones first.
+    /// Either way, if the first kind can't be reached the other is tried.
+    pub prefer_ipv6: bool,
+    /// How long to wait for each of the server's addresses to answer, in seconds, before trying the next one or giving up
+    pub connect_timeout: f32,
+    /// When the connection to a server we connected to drops, keep playing on the chunks we have and try to connect again,
+    /// waiting longer after each try that fails, up to half a minute. A server started by the game doesn't go away, so this doesn't apply.
+    pub reconnect: bool,
+    /// The most voxels edited while the connection is down to keep, which are sent once we're back and the server has sent their chunks.
+    /// The server can still reject them. Edits past this are lost, so a long outage doesn't pile up without limit.
+    pub offline_edits: usize,
+    /// Don't show anything until the chunks within `view_distance` of where we start are loaded, so the world doesn't
+    /// pop in around us. The title bar says how far along it is meanwhile.
+    pub wait_for_world: bool,
+    /// How long to wait for those chunks, in seconds, before going ahead without the rest, in case the server never sends them.
+    /// `Event::WorldReady` says whether they all came.
+    pub world_load_timeout: f32,
+    /// The brush left-click and right-click edit with. These are what it starts as; the `brush_shape` key and scrolling change them.
+    pub brush_shape: BrushShape,
+    /// In voxels around the one clicked on, up to `MAX_BRUSH_RADIUS`. 0 edits just that one.
+    pub brush_radius: u32,
+    /// Show where right-click would place blocks, as translucent voxels of the material, before clicking
+    pub brush_preview: bool,
+    /// When a click to edit points at a chunk that hasn't loaded yet, wait this many seconds for it to load and then make the edit.
+    /// The title bar says when an edit's waiting. 0 drops those clicks, and it's at most `MAX_PENDING_EDIT_TIMEOUT`.
+    pub pending_edit_timeout: f32,
+    /// What right-click places to start with. Middle-clicking a block picks its material instead.
+    pub place_material: Material,
+    /// Tags for materials, for things like footstep sounds that go by what the player is standing on or looking at, like `{Leaf: "grass"}`.
+    /// Materials that aren't here are tagged with their name in lowercase. See `Client::looking_at()` and `Client::standing_on()`.
+    pub material_tags: HashMap<Material, String>,
+    /// A .vox model or scene manifest to look at on its own, instead of playing in a world.
+    /// There's no server, terrain generation or networking, just the model, sitting on y = 0 around the origin, and the camera.
+    pub static_scene: Option<PathBuf>,
+    /// A folder of compiled SPIR-V shaders to draw with instead of the built-in ones, for modding.
+    /// `main.frag.spv` replaces the raymarching shader and `beam.frag.spv` the beam pass; any that aren't there are built in.
+    /// They need the same inputs, outputs, descriptors and push constants as the ones in `src`, and any that don't fit are skipped with a warning.
+    pub shader_dir: Option<PathBuf>,
 
     pub game_config: Arc<GameConfig>,
 }
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            keycodes: crate::input::DEFAULT_KEY_CODES,
+            log_level: "info".to_string(),
+            bake_ao: false,
+            view_distance: 16,
+            max_root_size: 1024.0,
+            max_ray_steps: 256,
+            near: 0.0,
+            far: 4096.0,
+            step_falloff_start: 512.0,
+            min_step_fraction: 0.25,
+            sky_zenith: [0.25, 0.4, 0.75],
+            sky_horizon: [0.55, 0.65, 0.8],
+            sky: true,
+            clear_color: [0.0, 0.0, 0.0, 1.0],
+            skip_clear: true,
+            sun_size: 1.5,
+            sun_intensity: 4.0,
+            sun_angular_size: 3.0,
+            shadow_mode: ShadowMode::Rays,
+            outline_color: [0.1, 0.1, 0.1],
+            outline_width: 0.04,
+            bloom: false,
+            bloom_threshold: 1.0,
+            bloom_intensity: 0.3,
+            tonemap: Tonemap::Aces,
+            brightness: 1.0,
+            motion_blur: false,
+            motion_blur_strength: 0.5,
+            motion_blur_samples: 8,
+            dof: false,
+            dof_aperture: 0.01,
+            dof_focus_time: 0.2,
+            traversal: Traversal::Stack,
+            world_wrap: [false; 3],
+            render_scale: 1.0,
+            origin_rebase_distance: 1024.0,
+            dynamic_resolution: false,
+            target_frame_ms: 16.7,
+            min_render_scale: 0.5,
+            max_render_scale: 1.0,
+            adaptive_lod: false,
+            min_lod_bias: 0.0,
+            max_lod_bias: 3.0,
+            lod_fade: 0.5,
+            lod_color: LodColor::First,
+            aa_mode: AaMode::None,
+            edge_aa: false,
+            edge_aa_strength: 1.0,
+            still_accumulation: false,
+            still_frames: 30,
+            still_samples: 64,
+            debug_mode: DebugMode::Off,
+            device_name: None,
+            device_index: None,
+            transfer_queue: true,
+            debug_info: false,
+            debug_info_frames: 30,
+            mouse_smoothing: 0.0,
+            walk: false,
+            walk_speed: 5.0,
+            jump_height: 1.25,
+            gravity: 30.0,
+            coyote_time: 0.1,
+            air_jumps: 0,
+            step_height: 1.0,
+            sprint_speed: 1.6,
+            sprint_fov: 0.0,
+            vox_up_axis: UpAxis::Z,
+            remote_player_delay: 0.1,
+            remote_player_extrapolation: 0.25,
+            player_name: "Player".to_string(),
+            server_address: None,
+            prefer_ipv6: false,
+            connect_timeout: 5.0,
+            reconnect: true,
+            offline_edits: 4096,
+            wait_for_world: false,
+            world_load_timeout: 30.0,
+            brush_shape: BrushShape::Cube,
+            brush_radius: 0,
+            brush_preview: true,
+            pending_edit_timeout: 3.0,
+            place_material: Material::Stone,
+            material_tags: HashMap::new(),
+            static_scene: None,
+            shader_dir: None,
+            game_config: Arc::new(GameConfig::default()),
+        }
+    }
+}
+
+impl ClientConfig {
+    /// Reads a config file. Call `validate()` on the result before using it.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let f = std::fs::File::open(path).map_err(|e| e.to_string())?;
+        ron::de::from_reader(f).map_err(|e| e.to_string())
+    }
+
+    /// Whether the window has to be cleared before each frame, or every pixel gets drawn over anyway
+    pub fn clear_window(&self) -> bool {
+        !(self.skip_clear && self.sky)
+    }
+
+    /// Clamps anything that's out of range, with a warning
+    pub fn validate(&mut self) {
+        Arc::make_mut(&mut self.game_config).validate();
+        let chunk_size = self.game_config.chunk_size as f32;
+
+        if self.view_distance > MAX_VIEW_DISTANCE {
+            warn!(
+                "view_distance {} is too big, using {}",
+                self.view_distance, MAX_VIEW_DISTANCE
+            );
+            self.view_distance = MAX_VIEW_DISTANCE;
+        }
+
+        if self.near < 0.0 {
+            warn!("near {} is negative, using 0", self.near);
+            self.near = 0.0;
+        }
+        if self.far <= self.near {
+            let far = self.near + chunk_size;
+            warn!(
+                "far {} isn't past near {}, using {}",
+                self.far, self.near, far
+            );
+            self.far = far;
+        }
+        if self.step_falloff_start.is_nan() || self.step_falloff_start < 0.0 {
+            warn!(
+                "step_falloff_start {} is negative, turning it off",
+                self.step_falloff_start
+            );
+            self.step_falloff_start = 0.0;
+        }
+        if self.min_step_fraction.is_nan() || !(0.0..=1.0).contains(&self.min_step_fraction) {
+            let x = if self.min_step_fraction > 1.0 {
+                1.0
+            } else {
+                0.0
+            };
+            warn!(
+                "min_step_fraction {} isn't between 0 and 1, using {}",
+                self.min_step_fraction, x
+            );
+            self.min_step_fraction = x;
+        }
+
+        let clamp_scale = |name: &str, x: &mut f32| {
+            let clamped = x.max(MIN_RENDER_SCALE).min(MAX_RENDER_SCALE);
+            // This catches NaN too
+            if clamped != *x {
+                warn!(
+                    "{} {} isn't between {} and {}, using {}",
+                    name, x, MIN_RENDER_SCALE, MAX_RENDER_SCALE, clamped
+                );
+                *x = clamped;
+            }
+        };
+        clamp_scale("render_scale", &mut self.render_scale);
+        clamp_scale("min_render_scale", &mut self.min_render_scale);
+        clamp_scale("max_render_scale", &mut self.max_render_scale);
+        if self.min_render_scale > self.max_render_scale {
+            warn!(
+                "min_render_scale {} is more than max_render_scale {}, swapping them",
+                self.min_render_scale, self.max_render_scale
+            );
+            std::mem::swap(&mut self.min_render_scale, &mut self.max_render_scale);
+        }
+        if self.target_frame_ms.is_nan() || self.target_frame_ms <= 0.0 {
+            warn!(
+                "target_frame_ms {} isn't positive, using 16.7",
+                self.target_frame_ms
+            );
+            self.target_frame_ms = 16.7;
+        }
+        let brightness = clamp_brightness(self.brightness);
+        if brightness != self.brightness {
+            warn!(
+                "brightness {} isn't between {} and {}, using {}",
+                self.brightness, MIN_BRIGHTNESS, MAX_BRIGHTNESS, brightness
+            );
+            self.brightness = brightness;
+        }
+        if self.origin_rebase_distance.is_nan() || self.origin_rebase_distance < 0.0 {
+            warn!(
+                "origin_rebase_distance {} is negative, moving the origin every chunk",
+                self.origin_rebase_distance
+            );
+            self.origin_rebase_distance = 0.0;
+        } else if self.origin_rebase_distance > MAX_REBASE_DISTANCE {
+            warn!(
+                "origin_rebase_distance {} is more than the most, {}, using that",
+                self.origin_rebase_distance, MAX_REBASE_DISTANCE
+            );
+            self.origin_rebase_distance = MAX_REBASE_DISTANCE;
+        }
+        let clamp_bias = |name: &str, x: &mut f32| {
+            let clamped = x.max(0.0).min(MAX_LOD_BIAS);
+            if clamped != *x {
+                warn!(
+                    "{} {} isn't between 0 and {}, using {}",
+                    name, x, MAX_LOD_BIAS, clamped
+                );
+                *x = clamped;
+            }
+        };
+        clamp_bias("min_lod_bias", &mut self.min_lod_bias);
+        clamp_bias("max_lod_bias", &mut self.max_lod_bias);
+        if self.min_lod_bias > self.max_lod_bias {
+            warn!(
+                "min_lod_bias {} is more than max_lod_bias {}, swapping them",
+                self.min_lod_bias, self.max_lod_bias
+            );
+            std::mem::swap(&mut self.min_lod_bias, &mut self.max_lod_bias);
+        }
+        if self.lod_fade.is_nan() || !(0.0..=1.0).contains(&self.lod_fade) {
+            let x = if self.lod_fade > 1.0 { 1.0 } else { 0.0 };
+            warn!(
+                "lod_fade {} isn't between 0 and 1, using {}",
+                self.lod_fade, x
+            );
+            self.lod_fade = x;
+        }
+        if self.mouse_smoothing.is_nan() || self.mouse_smoothing < 0.0 {
+            warn!(
+                "mouse_smoothing {} is negative, turning it off",
+                self.mouse_smoothing
+            );
+            self.mouse_smoothing = 0.0;
+        }
+        if self.sprint_speed.is_nan() || self.sprint_speed <= 0.0 {
+            warn!("sprint_speed {} isn't positive, using 1", self.sprint_speed);
+            self.sprint_speed = 1.0;
+        }
+        if self.sprint_fov.is_nan() || self.sprint_fov < 0.0 || self.sprint_fov > MAX_SPRINT_FOV {
+            let fov = if self.sprint_fov > MAX_SPRINT_FOV {
+                MAX_SPRINT_FOV
+            } else {
+                0.0
+            };
+            warn!(
+                "sprint_fov {} isn't between 0 and {}, using {}",
+                self.sprint_fov, MAX_SPRINT_FOV, fov
+            );
+            self.sprint_fov = fov;
+        }
+        if self.dof_aperture.is_nan() || self.dof_aperture < 0.0 {
+            warn!("dof_aperture {} is negative, using 0", self.dof_aperture);
+            self.dof_aperture = 0.0;
+        }
+        if self.dof_focus_time.is_nan() || self.dof_focus_time < 0.0 {
+            warn!(
+                "dof_focus_time {} is negative, focusing instantly",
+                self.dof_focus_time
+            );
+            self.dof_focus_time = 0.0;
+        }
+        if self.remote_player_delay.is_nan() || self.remote_player_delay < 0.0 {
+            warn!(
+                "remote_player_delay {} is negative, using 0",
+                self.remote_player_delay
+            );
+            self.remote_player_delay = 0.0;
+        }
+        if self.remote_player_extrapolation.is_nan() || self.remote_player_extrapolation < 0.0 {
+            warn!(
+                "remote_player_extrapolation {} is negative, using 0",
+                self.remote_player_extrapolation
+            );
+            self.remote_player_extrapolation = 0.0;
+        }
+        if !(self.connect_timeout > 0.0 && self.connect_timeout <= MAX_CONNECT_TIMEOUT) {
+            let timeout = if self.connect_timeout > MAX_CONNECT_TIMEOUT {
+                MAX_CONNECT_TIMEOUT
+            } else {
+                5.0
+            };
+            warn!(
+                "connect_timeout {} isn't between 0 and {} seconds, using {}",
+                self.connect_timeout, MAX_CONNECT_TIMEOUT, timeout
+            );
+            self.connect_timeout = timeout;
+        }
+        if !(self.world_load_timeout > 0.0 && self.world_load_timeout <= MAX_WORLD_LOAD_TIMEOUT) {
+            let timeout = if self.world_load_timeout > MAX_WORLD_LOAD_TIMEOUT {
+                MAX_WORLD_LOAD_TIMEOUT
+            } else {
+                30.0
+            };
+            warn!(
+                "world_load_timeout {} isn't between 0 and {} seconds, using {}",
+                self.world_load_timeout, MAX_WORLD_LOAD_TIMEOUT, timeout
+            );
+            self.world_load_timeout = timeout;
+        }
+        if !(self.pending_edit_timeout >= 0.0
+            && self.pending_edit_timeout <= MAX_PENDING_EDIT_TIMEOUT)
+        {
+            let timeout = if self.pending_edit_timeout > MAX_PENDING_EDIT_TIMEOUT {
+                MAX_PENDING_EDIT_TIMEOUT
+            } else {
+                0.0
+            };
+            warn!(
+                "pending_edit_timeout {} isn't between 0 and {} seconds, using {}",
+                self.pending_edit_timeout, MAX_PENDING_EDIT_TIMEOUT, timeout
+            );
+            self.pending_edit_timeout = timeout;
+        }
+        if self.brush_radius > MAX_BRUSH_RADIUS {
+            warn!(
+                "brush_radius {} is bigger than the most, {}, using that",
+                self.brush_radius, MAX_BRUSH_RADIUS
+            );
+            self.brush_radius = MAX_BRUSH_RADIUS;
+        }
+        if self.edge_aa_strength.is_nan() || !(0.0..=1.0).contains(&self.edge_aa_strength) {
+            let x = if self.edge_aa_strength > 1.0 {
+                1.0
+            } else {
+                0.0
+            };
+            warn!(
+                "edge_aa_strength {} isn't between 0 and 1, using {}",
+                self.edge_aa_strength, x
+            );
+            self.edge_aa_strength = x;
+        }
+        if self.still_samples == 0 || self.still_samples > MAX_STILL_SAMPLES {
+            let x = self.still_samples.max(1).min(MAX_STILL_SAMPLES);
+            warn!(
+                "still_samples {} isn't between 1 and {}, using {}",
+                self.still_samples, MAX_STILL_SAMPLES, x
+            );
+            self.still_samples = x;
+        }
+        match self.aa_mode {
+            AaMode::Taa => {
+                error!("aa_mode Taa isn't implemented yet, since there's no history buffer for it; turning anti-aliasing off");
+                self.aa_mode = AaMode::None;
+            }
+            AaMode::Ssaa(0) => {
+                warn!("aa_mode Ssaa(0) doesn't mean anything, turning anti-aliasing off");
+                self.aa_mode = AaMode::None;
+            }
+            AaMode::Ssaa(n) if n > MAX_SSAA => {
+                warn!(
+                    "aa_mode Ssaa({}) is more than the most, {}, using that",
+                    n, MAX_SSAA
+                );
+                self.aa_mode = AaMode::Ssaa(MAX_SSAA);
+            }
+            _ => (),
+        }
+
+        let min_root = chunk_size * 2.0;
+        let root = self.max_root_size.max(min_root).min(MAX_ROOT_SIZE);
+        let root = root.log2().ceil().exp2();
+        if root != self.max_root_size {
+            warn!(
+                "max_root_size {} isn't a power of two between {} and {}, using {}",
+                self.max_root_size, min_root, MAX_ROOT_SIZE, root
+            );
+            self.max_root_size = root;
+        }
+        let needed = ((self.view_distance * 2 + 1) as f32 * chunk_size)
+            .log2()
+            .ceil()
+            .exp2();
+        if needed > self.max_root_size {
+            warn!(
+                "max_root_size {} is too small for view_distance {}, so far chunks won't be drawn; it should be {}",
+                self.max_root_size, self.view_distance, needed
+            );
+        }
+        let steps = min_ray_steps(self.max_root_size);
+        if self.max_ray_steps < steps {
+            warn!(
+                "max_ray_steps {} is probably too few for max_root_size {}, distant terrain might be cut off; try {}",
+                self.max_ray_steps, self.max_root_size, steps
+            );
+        }
+    }
+}
+
+/// A rough lower bound on the ray steps needed for a root this big: each step goes down a level or across a node,
+/// and a ray has to go down about as many levels as the octree has before it can cross much.
+pub fn min_ray_steps(root_size: f32) -> u32 {
+    16 * root_size.log2().ceil() as u32
+}
+
+/// Watches the config file, so changes can be applied without restarting
+pub struct ConfigWatcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+}
+
+impl ConfigWatcher {
+    pub fn new(path: PathBuf) -> Self {
+        let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+        ConfigWatcher { path, modified }
+    }
+
+    /// Returns the new config if the file changed since the last call.
+    /// If it doesn't parse, we keep the old one.
+    pub fn poll(&mut self) -> Option<ClientConfig> {
+        let modified = std::fs::metadata(&self.path)
+            .and_then(|m| m.modified())
+            .ok();
+        if modified == self.modified {
+            return None;
+        }
+        self.modified = modified;
+
+        match ClientConfig::load(&self.path) {
+            Ok(mut c) => {
+                info!("Reloaded config");
+                c.validate();
+                Some(c)
+            }
+            Err(e) => {
+                warn!("Not reloading config, it has an error: {}", e);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixes_max_root_size() {
+        let fixed = |size: f32| {
+            let mut config = ClientConfig {
+                max_root_size: size,
+                ..ClientConfig::default()
+            };
+            config.validate();
+            config.max_root_size
+        };
+        let chunk_size = GameConfig::default().chunk_size as f32;
+        assert_eq!(fixed(1024.0), 1024.0);
+        // Anything that isn't a power of two rounds up to one
+        assert_eq!(fixed(1000.0), 1024.0);
+        assert_eq!(fixed(chunk_size * 3.0), chunk_size * 4.0);
+        // Smaller than two chunks, or bigger than the shader's stack can go, and it's clamped
+        for &size in &[chunk_size, 0.0, -64.0, f32::NAN] {
+            assert_eq!(fixed(size), chunk_size * 2.0, "{}", size);
+        }
+        assert_eq!(fixed(MAX_ROOT_SIZE * 2.0), MAX_ROOT_SIZE);
+        assert_eq!(fixed(f32::INFINITY), MAX_ROOT_SIZE);
+    }
+
+    #[test]
+    fn rejects_bad_files() {
+        let dir = crate::common::test_dir("config");
+        let load = |name: &str, text: &str| {
+            let path = dir.join(name);
+            std::fs::write(&path, text).unwrap();
+            ClientConfig::load(&path)
+        };
+        // Anything left out is the default
+        let config = load("good.ron", "(max_root_size: 512.0, max_ray_steps: 300)").unwrap();
+        assert_eq!(config.max_root_size, 512.0);
+        assert_eq!(config.max_ray_steps, 300);
+        assert_eq!(config.view_distance, ClientConfig::default().view_distance);
+
+        assert!(load("string.ron", "(max_root_size: \"big\")").is_err());
+        assert!(load("negative.ron", "(max_ray_steps: -1)").is_err());
+        assert!(load("truncated.ron", "(max_root_size: 512.0,").is_err());
+        assert!(ClientConfig::load(&dir.join("missing.ron")).is_err());
+    }
+}
@@ -0,0 +1,384 @@
+//! Slash commands, like `/tp 0 64 0`. Typing `/` in the window opens the console, and enter sends the line
+//! as `Event::Command`; the client runs it here and carries out the actions it returns.
+//! Plugins can add their own commands in `Plugin::register_commands()`.
+use crate::common::*;
+use std::collections::BTreeMap;
+
+/// The most voxels `/fill` will set at once, which is what servers allow by default with `max_fill_volume`
+pub const MAX_FILL: usize = 32 * 32 * 32;
+/// The longest line you can type into the console
+pub const MAX_LINE: usize = 256;
+/// The biggest `/screenshot` can be in either direction, which is as big as images get on most GPUs
+pub const MAX_SCREENSHOT_SIZE: u32 = 16384;
+
+/// What a command wants done. Commands don't touch the client themselves, so they can't leave it in a bad state.
+#[derive(Debug)]
+pub enum Action {
+    /// Send this to the server
+    Send(Message),
+    /// Move the camera here
+    Teleport(Vector3<f32>),
+    /// Show the player a line of output
+    Print(String),
+    /// Save a screenshot this many pixels wide and tall, whatever size the window is
+    Screenshot(u32, u32),
+}
+
+/// Runs a command with the words after its name, or returns what was wrong with them
+pub type CommandFn = Box<dyn Fn(&[&str]) -> Result<Vec<Action>, String> + Send>;
+
+struct Command {
+    usage: String,
+    help: String,
+    run: CommandFn,
+}
+
+pub struct Console {
+    commands: BTreeMap<String, Command>,
+}
+
+impl Default for Console {
+    /// A console with the built-in commands
+    fn default() -> Self {
+        let mut console = Console {
+            commands: BTreeMap::new(),
+        };
+        console.register("tp", "<x> <y> <z>", "Moves you to a position", |args| {
+            Ok(vec![Action::Teleport(parse_pos(args)?)])
+        });
+        console.register(
+            "fill",
+            "<x1> <y1> <z1> <x2> <y2> <z2> <material>",
+            "Sets every block in a box, including both corners",
+            fill,
+        );
+        console.register("save", "", "Saves the world now", |args| {
+            no_args(args)?;
+            Ok(vec![Action::Send(Message::Save)])
+        });
+        console.register(
+            "screenshot",
+            "<width> <height>",
+            "Saves a picture of what you see at any resolution, like 3840 2160",
+            screenshot,
+        );
+        console.register(
+            "seed",
+            "",
+            "Shows the seed the world was generated with",
+            |args| {
+                no_args(args)?;
+                Ok(vec![Action::Send(Message::GetSeed)])
+            },
+        );
+        console
+    }
+}
+
+impl Console {
+    /// Adds a command, replacing any other with the same name.
+    /// `usage` describes its arguments, like `"<x> <y> <z>"`, and `help` is a line about what it does, both for `/help`.
+    pub fn register(
+        &mut self,
+        name: &str,
+        usage: &str,
+        help: &str,
+        run: impl Fn(&[&str]) -> Result<Vec<Action>, String> + Send + 'static,
+    ) {
+        self.commands.insert(
+            name.to_string(),
+            Command {
+                usage: usage.to_string(),
+                help: help.to_string(),
+                run: Box::new(run),
+            },
+        );
+    }
+
+    /// Runs a line like `/tp 0 64 0`. Mistakes, including unknown commands, come back as a `Print`.
+    pub fn run(&self, line: &str) -> Vec<Action> {
+        let mut words = line.trim().trim_start_matches('/').split_whitespace();
+        let name = match words.next() {
+            Some(name) => name,
+            None => return Vec::new(),
+        };
+        let args: Vec<&str> = words.collect();
+        if name == "help" {
+            return self.help();
+        }
+        match self.commands.get(name) {
+            Some(command) => (command.run)(&args).unwrap_or_else(|e| {
+                vec![Action::Print(format!(
+                    "{}. Usage: {}",
+                    e,
+                    usage(name, &command.usage)
+                ))]
+            }),
+            None => vec![Action::Print(format!(
+                "Unknown command /{}, try /help",
+                name
+            ))],
+        }
+    }
+
+    fn help(&self) -> Vec<Action> {
+        std::iter::once(Action::Print("/help: Lists the commands".to_string()))
+            .chain(
+                self.commands.iter().map(|(name, c)| {
+                    Action::Print(format!("{}: {}", usage(name, &c.usage), c.help))
+                }),
+            )
+            .collect()
+    }
+}
+
+/// The line being typed into the console, if it's open
+#[derive(Default)]
+pub struct Prompt {
+    line: Option<String>,
+}
+
+impl Prompt {
+    /// While it's open, keys are for typing, so they shouldn't move the player
+    pub fn is_open(&self) -> bool {
+        self.line.is_some()
+    }
+
+    /// What's been typed so far, for showing the player
+    pub fn line(&self) -> Option<&str> {
+        self.line.as_deref()
+    }
+
+    /// Handles a typed character: `/` opens the console, escape closes it, and enter returns the line and closes it
+    pub fn type_char(&mut self, c: char) -> Option<String> {
+        let line = match &mut self.line {
+            Some(line) => line,
+            None => {
+                if c == '/' {
+                    self.line = Some("/".to_string());
+                }
+                return None;
+            }
+        };
+        match c {
+            '\r' | '\n' => return self.line.take(),
+            '\u{1b}' => self.line = None,
+            '\u{8}' => {
+                line.pop();
+                // Deleting the `/` closes it
+                if line.is_empty() {
+                    self.line = None;
+                }
+            }
+            c if !c.is_control() && line.len() + c.len_utf8() <= MAX_LINE => line.push(c),
+            _ => (),
+        }
+        None
+    }
+}
+
+/// How to write a command, like `/tp <x> <y> <z>`
+fn usage(name: &str, usage: &str) -> String {
+    if usage.is_empty() {
+        format!("/{}", name)
+    } else {
+        format!("/{} {}", name, usage)
+    }
+}
+
+fn no_args(args: &[&str]) -> Result<(), String> {
+    if args.is_empty() {
+        Ok(())
+    } else {
+        Err("It doesn't take any arguments".to_string())
+    }
+}
+
+/// Three numbers as a position
+pub fn parse_pos(args: &[&str]) -> Result<Vector3<f32>, String> {
+    if args.len() != 3 {
+        return Err(format!("Expected 3 numbers, got {}", args.len()));
+    }
+    let mut pos = Vector3::zeros();
+    for (i, a) in args.iter().enumerate() {
+        pos[i] = a
+            .parse::<f32>()
+            .ok()
+            .filter(|x| x.is_finite())
+            .ok_or_else(|| format!("{} isn't a number", a))?;
+    }
+    Ok(pos)
+}
+
+/// `/screenshot`, with a size in pixels
+fn screenshot(args: &[&str]) -> Result<Vec<Action>, String> {
+    if args.len() != 2 {
+        return Err(format!(
+            "Expected a width and a height, got {} numbers",
+            args.len()
+        ));
+    }
+    let mut size = [0; 2];
+    for (x, a) in size.iter_mut().zip(args) {
+        *x = a
+            .parse::<u32>()
+            .ok()
+            .filter(|x| (1..=MAX_SCREENSHOT_SIZE).contains(x))
+            .ok_or_else(|| {
+                format!(
+                    "{} isn't a number of pixels from 1 to {}",
+                    a, MAX_SCREENSHOT_SIZE
+                )
+            })?;
+    }
+    Ok(vec![Action::Screenshot(size[0], size[1])])
+}
+
+/// `/fill`, which sends the box to the server as a `FillRegion`
+fn fill(args: &[&str]) -> Result<Vec<Action>, String> {
+    if args.len() != 7 {
+        return Err(format!("Expected 7 arguments, got {}", args.len()));
+    }
+    let a = parse_pos(&args[0..3])?.map(|x| x.floor() as i32);
+    let b = parse_pos(&args[3..6])?.map(|x| x.floor() as i32);
+    let mat = Material::from_name(args[6]).ok_or_else(|| {
+        format!(
+            "There's no material {}, try one of {}",
+            args[6],
+            Material::names().join(", ")
+        )
+    })?;
+    let min = a.zip_map(&b, i32::min);
+    let max = a.zip_map(&b, i32::max);
+    // In i64, since huge coordinates are saturated to the ends of i32, and the distance between those doesn't fit
+    let count = min
+        .iter()
+        .zip(max.iter())
+        .map(|(&lo, &hi)| (hi as i64 - lo as i64 + 1) as u64)
+        .fold(1u64, |acc, x| acc.saturating_mul(x));
+    if count > MAX_FILL as u64 {
+        return Err(format!(
+            "That's {} blocks, but the most you can fill at once is {}",
+            count, MAX_FILL
+        ));
+    }
+
+    Ok(vec![
+        Action::Send(Message::FillRegion(min, max, mat)),
+        Action::Print(format!("Filled {} blocks with {:?}", count, mat)),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prints(actions: &[Action]) -> Vec<&str> {
+        actions
+            .iter()
+            .filter_map(|a| match a {
+                Action::Print(s) => Some(s.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn runs_builtins() {
+        let console = Console::default();
+        match &console.run("/tp 1 -2.5 3")[..] {
+            [Action::Teleport(p)] => assert_eq!(*p, Vector3::new(1.0, -2.5, 3.0)),
+            x => panic!("{:?}", x),
+        }
+        assert!(matches!(
+            &console.run("  /save ")[..],
+            [Action::Send(Message::Save)]
+        ));
+        assert!(matches!(
+            &console.run("/seed")[..],
+            [Action::Send(Message::GetSeed)]
+        ));
+        assert!(matches!(
+            &console.run("/screenshot 3840 2160")[..],
+            [Action::Screenshot(3840, 2160)]
+        ));
+
+        let actions = console.run("/fill 0 0 0 1 2 -1 stone");
+        // The corners come out in order, whichever way around they were typed
+        match &actions[0] {
+            Action::Send(Message::FillRegion(min, max, Material::Stone)) => {
+                assert_eq!(*min, Vector3::new(0, 0, -1));
+                assert_eq!(*max, Vector3::new(1, 2, 0));
+            }
+            x => panic!("{:?}", x),
+        }
+        assert_eq!(prints(&actions), ["Filled 12 blocks with Stone"]);
+    }
+
+    #[test]
+    fn reports_mistakes() {
+        let console = Console::default();
+        for line in &[
+            "/nope",
+            "/tp 1 2",
+            "/tp 1 2 x",
+            "/tp 1 2 NaN",
+            "/save now",
+            "/screenshot 1920",
+            "/screenshot 0 1080",
+            "/screenshot 100000 1080",
+            "/screenshot -5 10",
+            "/fill 0 0 0 1 1 1 cheese",
+            "/fill 0 0 0 1000 1000 1000 stone",
+            "/fill -1e30 0 0 1e30 0 0 stone",
+        ] {
+            let actions = console.run(line);
+            assert_eq!(prints(&actions).len(), 1, "{}", line);
+            assert_eq!(actions.len(), 1, "{} did something", line);
+        }
+        assert!(prints(&console.run("/nope"))[0].contains("Unknown command /nope"));
+        assert!(console.run("/").is_empty());
+    }
+
+    fn type_str(prompt: &mut Prompt, s: &str) -> Option<String> {
+        s.chars().filter_map(|c| prompt.type_char(c)).last()
+    }
+
+    #[test]
+    fn typing() {
+        let mut prompt = Prompt::default();
+        // Nothing happens until it's opened
+        assert_eq!(type_str(&mut prompt, "wasd\r"), None);
+        assert!(!prompt.is_open());
+
+        assert_eq!(type_str(&mut prompt, "/tp 1 2 33"), None);
+        assert_eq!(prompt.line(), Some("/tp 1 2 33"));
+        assert_eq!(
+            type_str(&mut prompt, "\u{8}\r"),
+            Some("/tp 1 2 3".to_string())
+        );
+        assert!(!prompt.is_open());
+
+        type_str(&mut prompt, "/seed\u{1b}");
+        assert!(!prompt.is_open());
+        type_str(&mut prompt, "/\u{8}");
+        assert!(!prompt.is_open());
+
+        type_str(&mut prompt, "/");
+        type_str(&mut prompt, &"x".repeat(MAX_LINE * 2));
+        assert_eq!(prompt.line().unwrap().len(), MAX_LINE);
+    }
+
+    #[test]
+    fn register_commands() {
+        let mut console = Console::default();
+        console.register("echo", "<words>", "Says it back", |args| {
+            Ok(vec![Action::Print(args.join(" "))])
+        });
+        assert_eq!(prints(&console.run("/echo hi  there")), ["hi there"]);
+        let help = console.run("/help");
+        assert!(prints(&help).contains(&"/echo <words>: Says it back"));
+        assert!(prints(&help).contains(&"/tp <x> <y> <z>: Moves you to a position"));
+        assert!(prints(&help).contains(&"/save: Saves the world now"));
+    }
+}
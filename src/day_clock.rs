@@ -0,0 +1,82 @@
+//! The clock the sun follows. In multiplayer the server owns it and sends `Message::TimeOfDay` every so often,
+//! so everyone sees the same sky. Between updates we keep counting on our own clock from the last one,
+//! and when an update says we've drifted, we speed up or slow down a little until we're back in step,
+//! so the sun doesn't jump. Without a server clock, like in single-player, it's just the local one.
+
+/// If we're this many seconds off from the server, we jump straight there instead of catching up,
+/// like when we first join and our clock has nothing to do with theirs
+pub const SNAP_ERROR: f64 = 5.0;
+/// How much faster or slower than real time our clock can run while it catches up with the server's, as a fraction of real time
+pub const MAX_CORRECTION: f64 = 0.1;
+
+#[derive(Default)]
+pub struct DayClock {
+    /// What we add to the local clock to get the server's, as far as we've caught up
+    offset: f64,
+    /// What the last update says `offset` should be, or `None` if there hasn't been one
+    target: Option<f64>,
+    /// The local time the last `time()` was for
+    last: f64,
+}
+
+impl DayClock {
+    /// The server says it's `server` on its clock when it's `local` on ours, both in seconds
+    pub fn update(&mut self, local: f64, server: f64) {
+        let target = server - local;
+        if self.target.is_none() || (target - self.offset).abs() > SNAP_ERROR {
+            self.offset = target;
+        }
+        self.target = Some(target);
+    }
+
+    /// What the time is on the server's clock when it's `local` on ours. Call this once a frame,
+    /// since it's also what moves us toward the last update.
+    pub fn time(&mut self, local: f64) -> f64 {
+        let dt = (local - self.last).max(0.0);
+        self.last = local;
+        if let Some(target) = self.target {
+            let step = MAX_CORRECTION * dt;
+            self.offset += (target - self.offset).max(-step).min(step);
+        }
+        local + self.offset
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn local_without_a_server() {
+        let mut c = DayClock::default();
+        assert_eq!(c.time(0.0), 0.0);
+        assert_eq!(c.time(12.5), 12.5);
+    }
+
+    #[test]
+    fn follows_the_server() {
+        let mut c = DayClock::default();
+        c.time(1.0);
+        // The first update jumps straight there, and then it keeps going from it
+        c.update(1.0, 301.0);
+        assert_eq!(c.time(1.0), 301.0);
+        assert_eq!(c.time(3.0), 303.0);
+
+        // We're a second fast, so it slows down instead of jumping back
+        c.update(4.0, 303.0);
+        let t = c.time(5.0);
+        assert!(t < 305.0 && t > 304.0, "{}", t);
+        // And never runs backwards while it catches up
+        let mut last = t;
+        for i in 1..200 {
+            let t = c.time(5.0 + i as f64 * 0.1);
+            assert!(t > last);
+            last = t;
+        }
+        assert!((c.time(30.0) - 329.0).abs() < 1e-9);
+
+        // Way off, so it jumps
+        c.update(30.0, 1000.0);
+        assert_eq!(c.time(30.0), 1000.0);
+    }
+}
@@ -1,9 +1,15 @@
 use crate::camera::Camera;
 use crate::client::Client;
+use crate::client_world::{ChunkInfo, WorldSource, WorldStats};
 use crate::common::*;
 /// The event system for both client and server
 use crate::config::*;
+use crate::console::Prompt;
+use crate::plugin::Plugin;
+use crate::profile::Profiler;
 use crate::window::Window;
+use crate::window_geometry::WindowGeometry;
+use crate::world::Heightmap;
 use std::sync::Arc;
 use std::time::Duration;
 use winit::event as we;
@@ -19,29 +25,77 @@ pub struct Time {
 #[derive(Default)]
 pub struct FrameNum(pub usize);
 
-pub fn run_client_loop(conn: Connection, config: Arc<ClientConfig>) -> ! {
-    let (window, evloop) = Window::new("Quanta");
+/// How often to check the config file for changes
+const CONFIG_POLL_FRAMES: usize = 60;
+/// Touchpads scroll in pixels instead of lines, so this many of them count as one line
+const SCROLL_PIXELS_PER_LINE: f64 = 20.0;
+
+/// Runs everything after the window closes: sends `Quit`, and runs two last frames.
+/// The first is where systems see `Quit`, and the second sends anything they did because of it, like the last edits.
+/// `Client` waits for the GPU then, and `ClientWorld` leaves the server and waits for it to save.
+fn shutdown(w: &mut World, d: &mut specs::Dispatcher) {
+    info!("Shutting down");
+    w.fetch_mut::<EventChannel<Event>>()
+        .single_write(Event::Quit);
+    for _ in 0..2 {
+        d.dispatch_par(w);
+        w.maintain();
+    }
+    info!("Finished shutting down");
+}
+
+/// Opens the window and runs the game in it until it's closed. `source` is where the world comes from:
+/// a connection to a server, or a static scene with no server at all.
+pub fn run_client_loop(
+    source: WorldSource,
+    config: Arc<ClientConfig>,
+    config_path: std::path::PathBuf,
+    plugins: Vec<Box<dyn Plugin>>,
+) -> ! {
+    let geometry_path = WindowGeometry::path(&config_path);
+    let geometry = WindowGeometry::load(&geometry_path).ok();
+    let (window, evloop) = Window::new("Quanta", &config, geometry).unwrap_or_else(|e| {
+        error!("{}", e);
+        std::process::exit(1)
+    });
 
     let mut w = World::new();
 
     let mut e: EventChannel<Event> = EventChannel::new();
 
-    let cam = Camera::new(window.size());
-    let (client, client_world) = Client::new(&window, &cam, conn, config, &mut e);
+    let mut cam = Camera::new(window.size());
+    cam.configure(&config, true);
+    if let Some(pos) = source.start() {
+        cam.set_view(pos, 0.0, 0.0);
+    }
+    let (mut client, client_world) = match Client::new(&window, &cam, source, config, &mut e) {
+        Ok(x) => x,
+        Err(err) => {
+            error!("{}", err);
+            std::process::exit(1)
+        }
+    };
+    for plugin in plugins {
+        client.add_plugin(plugin);
+    }
 
     w.insert(e);
     w.insert(cam);
     w.insert(window);
-    w.insert(crate::world::World::new());
+    w.insert(crate::world::World::with_edit_tracking());
+    w.insert(Profiler::default());
 
     let mut d = DispatcherBuilder::new()
         .with(client, "", &[])
         .with(client_world, "", &[])
         .build();
 
+    let mut watcher = ConfigWatcher::new(config_path);
+
     let timer = stopwatch::Stopwatch::start_new();
     let mut i = 0;
     let mut time = Duration::from_secs(0);
+    let mut prompt = Prompt::default();
 
     evloop.run(move |event, _target, _flow| {
         let mut e: specs::shred::FetchMut<EventChannel<Event>> = w.fetch_mut();
@@ -53,11 +107,53 @@ pub fn run_client_loop(conn: Connection, config: Arc<ClientConfig>) -> ! {
             } => {
                 e.single_write(Event::Resize(size.width.into(), size.height.into()));
             }
+            // Moving to a monitor with a different scale factor. The window's resized to keep its size on the new one,
+            // and that comes as a `Resized` after this, on platforms where it changes the size at all.
+            we::Event::WindowEvent {
+                event: WindowEvent::ScaleFactorChanged { scale_factor, .. },
+                ..
+            } => {
+                e.single_write(Event::ScaleFactorChanged(scale_factor as f32));
+            }
+            we::Event::WindowEvent {
+                event: WindowEvent::DroppedFile(path),
+                ..
+            } => {
+                // A scene manifest, or a model
+                if path.extension().map_or(false, |x| x == "ron") {
+                    e.single_write(Event::ImportScene(path));
+                } else {
+                    e.single_write(Event::ImportVox(path));
+                }
+            }
+            we::Event::WindowEvent {
+                event: WindowEvent::ReceivedCharacter(c),
+                ..
+            } => {
+                let was_open = prompt.is_open();
+                if let Some(line) = prompt.type_char(c) {
+                    e.single_write(Event::Command(line));
+                }
+                // The line goes in the title bar while it's being typed
+                if prompt.is_open() || was_open {
+                    w.fetch::<Window>().set_status(prompt.line());
+                }
+            }
             we::Event::WindowEvent {
                 event: WindowEvent::CloseRequested,
                 ..
             } => {
-                e.single_write(Event::Quit);
+                drop(e);
+                if let Some(g) = w.fetch::<Window>().geometry() {
+                    if let Err(e) = g.save(&geometry_path) {
+                        warn!(
+                            "Couldn't save the window position to {}: {}",
+                            geometry_path.display(),
+                            e
+                        );
+                    }
+                }
+                shutdown(&mut w, &mut d);
                 *_flow = ControlFlow::Exit;
             }
             we::Event::DeviceEvent { event, .. } => {
@@ -71,7 +167,11 @@ pub fn run_client_loop(conn: Connection, config: Arc<ClientConfig>) -> ! {
                         state: we::ElementState::Pressed,
                         ..
                     }) => {
-                        e.single_write(Event::KeyPressed(scancode));
+                        // Typing in the console shouldn't also walk around.
+                        // Releases still go through, so keys that were held when it opened don't get stuck.
+                        if !prompt.is_open() {
+                            e.single_write(Event::KeyPressed(scancode));
+                        }
                     }
                     DeviceEvent::Key(we::KeyboardInput {
                         scancode,
@@ -86,10 +186,22 @@ pub fn run_client_loop(conn: Connection, config: Arc<ClientConfig>) -> ! {
                     } => {
                         e.single_write(Event::Button(button));
                     }
+                    DeviceEvent::MouseWheel { delta } => {
+                        let lines = match delta {
+                            we::MouseScrollDelta::LineDelta(_, y) => y as f64,
+                            we::MouseScrollDelta::PixelDelta(p) => p.y / SCROLL_PIXELS_PER_LINE,
+                        };
+                        e.single_write(Event::Scroll(lines));
+                    }
                     _ => {}
                 }
             }
             we::Event::RedrawEventsCleared => {
+                if i % CONFIG_POLL_FRAMES == 0 {
+                    if let Some(c) = watcher.poll() {
+                        e.single_write(Event::ConfigChanged(Arc::new(c)));
+                    }
+                }
                 drop(e);
 
                 let cur = timer.elapsed();
@@ -101,6 +213,7 @@ pub fn run_client_loop(conn: Connection, config: Arc<ClientConfig>) -> ! {
 
                 d.dispatch_par(&w);
                 w.maintain();
+                w.fetch_mut::<Profiler>().frame();
 
                 // Keep the cursor in the window
                 // window.surface
@@ -124,14 +237,23 @@ pub fn run_client_loop(conn: Connection, config: Arc<ClientConfig>) -> ! {
 pub enum Event {
     /// The player moved
     PlayerMove(Vector3<f32>),
+    /// A command buffer from `ClientWorld` to run, with the new octree origin and root size,
+    /// and the new tree and LOD buffers if they had to be replaced with bigger ones
     Submit(
         Once<(
             vulkano::command_buffer::AutoCommandBuffer,
             Vector3<f32>,
             f32,
-            HashMap<Vector3<i32>, (usize, usize)>,
+            Option<(
+                Arc<vulkano::buffer::DeviceLocalBuffer<[u32]>>,
+                Arc<vulkano::buffer::DeviceLocalBuffer<[u32]>>,
+            )>,
         )>,
     ),
+    /// These chunks were edited on the client, so their copies on the GPU need updating
+    Reupload(Vec<Vector3<i32>>),
+    /// Move all the chunks in the tree buffer next to each other, to get rid of the holes between them
+    CompactTree,
     /// A press of a mouse button with this id
     Button(u32),
     /// A key press with this scan code
@@ -139,8 +261,58 @@ pub enum Event {
     KeyReleased(u32),
     /// A change in mouse position
     Mouse(f64, f64),
+    /// The mouse wheel turned this many lines, positive going up
+    Scroll(f64),
     /// A window resize, with new width and height
     Resize(f64, f64),
-    /// The application needs to close, so do any destruction necessary
+    /// The window's on a monitor with a different scale factor now, which is how many pixels each of the OS's logical pixels is.
+    /// A `Resize` usually comes with it, and both only need the swapchain made again once.
+    ScaleFactorChanged(f32),
+    /// The player changed a block, which should be sent to the server
+    SetBlock(Vector3<f32>, Material),
+    /// A message for `ClientWorld` to send to the server
+    Send(Once<Message>),
+    /// The player typed this line into the console, like `/tp 0 64 0`
+    Command(String),
+    /// A file was dropped on the window, which should be a MagicaVoxel model to place where the player's looking
+    ImportVox(std::path::PathBuf),
+    /// A scene manifest was dropped on the window, to put together from its models and place the same way, see `vox_scene`
+    ImportScene(std::path::PathBuf),
+    /// We asked the server to save the world (`true`), or it finished saving (`false`)
+    Saving(bool),
+    /// The server says another player, with this id, is here
+    OtherPlayer(u32, Vector3<f32>),
+    /// The player with this id left the server
+    PlayerLeft(u32),
+    /// The server says the player with this id is called this
+    PlayerName(u32, String),
+    /// The server's clock, in seconds, for the sun to follow
+    TimeOfDay(f64),
+    /// The sun's direction moved far enough since the last one that shadows baked for `ShadowMode::Baked` need baking again
+    SunMoved(Vector3<f32>),
+    /// Asks `ClientWorld` which chunks are loaded, for minimaps and the like. It answers with `LoadedChunks`.
+    QueryChunks,
+    /// Every chunk `ClientWorld` has loaded, sorted by position
+    LoadedChunks(Vec<ChunkInfo>),
+    /// Asks `ClientWorld` for the heightmap of the chunk at this position. It answers with `Heightmap`.
+    QueryHeightmap(Vector3<i32>),
+    /// The heightmap of the chunk at this position, or `None` if it isn't loaded
+    Heightmap(Vector3<i32>, Option<Heightmap>),
+    /// Asks `ClientWorld` how many chunks and nodes it has, and pings the server. It answers with `WorldStats`.
+    QueryStats,
+    /// How much of the world is loaded, and the last ping, which is from before this query's unless it was very fast
+    WorldStats(WorldStats),
+    /// How many of the chunks around where the player started `ClientWorld` has loaded, and how many there are,
+    /// which it sends whenever that goes up until they're all there
+    WorldLoading(usize, usize),
+    /// The chunks around where the player started are loaded, so there's something to show.
+    /// It's sent once, with `false` if `world_load_timeout` ran out first.
+    WorldReady(bool),
+    /// The device was lost and this is the new one, with its queue. Everything made with the old one has to be made again,
+    /// including plugins' pipelines. `ClientWorld` uploads the world again, and `Client` draws again once that's done.
+    DeviceReset(Arc<vulkano::device::Device>, Arc<vulkano::device::Queue>),
+    /// The config file changed, so pick up any new settings
+    ConfigChanged(Arc<ClientConfig>),
+    /// The window was closed, so finish up. There are two more frames after this one, see `shutdown()`.
     Quit,
 }
@@ -0,0 +1,435 @@
+//! A tiny bitmap font for text drawn in the world, like the names over other players.
+//! It's printable ASCII plus an ellipsis, each glyph 5 by 7 texels, packed into one small atlas texture.
+//! Everything else is drawn as a question mark.
+
+/// How big each glyph is, in texels
+pub const GLYPH_SIZE: [u32; 2] = [5, 7];
+/// How far apart glyphs are along a line, in texels, so there's a gap of one between them
+pub const ADVANCE: u32 = GLYPH_SIZE[0] + 1;
+/// Every glyph has this many empty texels around it in the atlas, so there's room to outline it
+pub const PADDING: u32 = 1;
+/// The size of each glyph's cell in the atlas, with the padding
+pub const CELL_SIZE: [u32; 2] = [GLYPH_SIZE[0] + 2 * PADDING, GLYPH_SIZE[1] + 2 * PADDING];
+/// How many cells there are in each row of the atlas
+const ATLAS_COLUMNS: u32 = 16;
+/// The size of the atlas texture, in texels
+pub const ATLAS_SIZE: [u32; 2] = [
+    ATLAS_COLUMNS * CELL_SIZE[0],
+    (GLYPHS.len() as u32 + ATLAS_COLUMNS - 1) / ATLAS_COLUMNS * CELL_SIZE[1],
+];
+/// The glyph for a character we don't have one for
+const UNKNOWN: char = '?';
+/// Goes at the end of text that was cut short
+pub const ELLIPSIS: char = '\u{2026}';
+
+/// Glyphs for ' ' to '~', and then the ellipsis, with a row for each line of texels from the top, and the high bit on the left
+const GLYPHS: [[u8; 7]; 96] = [
+    [
+        0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b00000,
+    ], // space
+    [
+        0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b00000, 0b00100,
+    ], // !
+    [
+        0b01010, 0b01010, 0b00000, 0b00000, 0b00000, 0b00000, 0b00000,
+    ], // "
+    [
+        0b01010, 0b01010, 0b11111, 0b01010, 0b11111, 0b01010, 0b01010,
+    ], // #
+    [
+        0b00100, 0b01111, 0b10100, 0b01110, 0b00101, 0b11110, 0b00100,
+    ], // $
+    [
+        0b11000, 0b11001, 0b00010, 0b00100, 0b01000, 0b10011, 0b00011,
+    ], // %
+    [
+        0b01100, 0b10010, 0b10100, 0b01000, 0b10101, 0b10010, 0b01101,
+    ], // &
+    [
+        0b00100, 0b00100, 0b00000, 0b00000, 0b00000, 0b00000, 0b00000,
+    ], // '
+    [
+        0b00010, 0b00100, 0b01000, 0b01000, 0b01000, 0b00100, 0b00010,
+    ], // (
+    [
+        0b01000, 0b00100, 0b00010, 0b00010, 0b00010, 0b00100, 0b01000,
+    ], // )
+    [
+        0b00000, 0b00100, 0b10101, 0b01110, 0b10101, 0b00100, 0b00000,
+    ], // *
+    [
+        0b00000, 0b00100, 0b00100, 0b11111, 0b00100, 0b00100, 0b00000,
+    ], // +
+    [
+        0b00000, 0b00000, 0b00000, 0b00000, 0b01100, 0b00100, 0b01000,
+    ], // ,
+    [
+        0b00000, 0b00000, 0b00000, 0b11111, 0b00000, 0b00000, 0b00000,
+    ], // -
+    [
+        0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b01100, 0b01100,
+    ], // .
+    [
+        0b00000, 0b00001, 0b00010, 0b00100, 0b01000, 0b10000, 0b00000,
+    ], // /
+    [
+        0b01110, 0b10001, 0b10011, 0b10101, 0b11001, 0b10001, 0b01110,
+    ], // 0
+    [
+        0b00100, 0b01100, 0b00100, 0b00100, 0b00100, 0b00100, 0b01110,
+    ], // 1
+    [
+        0b01110, 0b10001, 0b00001, 0b00010, 0b00100, 0b01000, 0b11111,
+    ], // 2
+    [
+        0b11111, 0b00010, 0b00100, 0b00010, 0b00001, 0b10001, 0b01110,
+    ], // 3
+    [
+        0b00010, 0b00110, 0b01010, 0b10010, 0b11111, 0b00010, 0b00010,
+    ], // 4
+    [
+        0b11111, 0b10000, 0b11110, 0b00001, 0b00001, 0b10001, 0b01110,
+    ], // 5
+    [
+        0b00110, 0b01000, 0b10000, 0b11110, 0b10001, 0b10001, 0b01110,
+    ], // 6
+    [
+        0b11111, 0b00001, 0b00010, 0b00100, 0b01000, 0b01000, 0b01000,
+    ], // 7
+    [
+        0b01110, 0b10001, 0b10001, 0b01110, 0b10001, 0b10001, 0b01110,
+    ], // 8
+    [
+        0b01110, 0b10001, 0b10001, 0b01111, 0b00001, 0b00010, 0b01100,
+    ], // 9
+    [
+        0b00000, 0b01100, 0b01100, 0b00000, 0b01100, 0b01100, 0b00000,
+    ], // :
+    [
+        0b00000, 0b01100, 0b01100, 0b00000, 0b01100, 0b00100, 0b01000,
+    ], // ;
+    [
+        0b00010, 0b00100, 0b01000, 0b10000, 0b01000, 0b00100, 0b00010,
+    ], // <
+    [
+        0b00000, 0b00000, 0b11111, 0b00000, 0b11111, 0b00000, 0b00000,
+    ], // =
+    [
+        0b01000, 0b00100, 0b00010, 0b00001, 0b00010, 0b00100, 0b01000,
+    ], // >
+    [
+        0b01110, 0b10001, 0b00001, 0b00010, 0b00100, 0b00000, 0b00100,
+    ], // ?
+    [
+        0b01110, 0b10001, 0b00001, 0b01101, 0b10101, 0b10101, 0b01110,
+    ], // @
+    [
+        0b01110, 0b10001, 0b10001, 0b11111, 0b10001, 0b10001, 0b10001,
+    ], // A
+    [
+        0b11110, 0b10001, 0b10001, 0b11110, 0b10001, 0b10001, 0b11110,
+    ], // B
+    [
+        0b01110, 0b10001, 0b10000, 0b10000, 0b10000, 0b10001, 0b01110,
+    ], // C
+    [
+        0b11100, 0b10010, 0b10001, 0b10001, 0b10001, 0b10010, 0b11100,
+    ], // D
+    [
+        0b11111, 0b10000, 0b10000, 0b11110, 0b10000, 0b10000, 0b11111,
+    ], // E
+    [
+        0b11111, 0b10000, 0b10000, 0b11110, 0b10000, 0b10000, 0b10000,
+    ], // F
+    [
+        0b01110, 0b10001, 0b10000, 0b10111, 0b10001, 0b10001, 0b01111,
+    ], // G
+    [
+        0b10001, 0b10001, 0b10001, 0b11111, 0b10001, 0b10001, 0b10001,
+    ], // H
+    [
+        0b01110, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b01110,
+    ], // I
+    [
+        0b00111, 0b00010, 0b00010, 0b00010, 0b00010, 0b10010, 0b01100,
+    ], // J
+    [
+        0b10001, 0b10010, 0b10100, 0b11000, 0b10100, 0b10010, 0b10001,
+    ], // K
+    [
+        0b10000, 0b10000, 0b10000, 0b10000, 0b10000, 0b10000, 0b11111,
+    ], // L
+    [
+        0b10001, 0b11011, 0b10101, 0b10101, 0b10001, 0b10001, 0b10001,
+    ], // M
+    [
+        0b10001, 0b10001, 0b11001, 0b10101, 0b10011, 0b10001, 0b10001,
+    ], // N
+    [
+        0b01110, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01110,
+    ], // O
+    [
+        0b11110, 0b10001, 0b10001, 0b11110, 0b10000, 0b10000, 0b10000,
+    ], // P
+    [
+        0b01110, 0b10001, 0b10001, 0b10001, 0b10101, 0b10010, 0b01101,
+    ], // Q
+    [
+        0b11110, 0b10001, 0b10001, 0b11110, 0b10100, 0b10010, 0b10001,
+    ], // R
+    [
+        0b01111, 0b10000, 0b10000, 0b01110, 0b00001, 0b00001, 0b11110,
+    ], // S
+    [
+        0b11111, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100,
+    ], // T
+    [
+        0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01110,
+    ], // U
+    [
+        0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01010, 0b00100,
+    ], // V
+    [
+        0b10001, 0b10001, 0b10001, 0b10101, 0b10101, 0b10101, 0b01010,
+    ], // W
+    [
+        0b10001, 0b10001, 0b01010, 0b00100, 0b01010, 0b10001, 0b10001,
+    ], // X
+    [
+        0b10001, 0b10001, 0b10001, 0b01010, 0b00100, 0b00100, 0b00100,
+    ], // Y
+    [
+        0b11111, 0b00001, 0b00010, 0b00100, 0b01000, 0b10000, 0b11111,
+    ], // Z
+    [
+        0b01110, 0b01000, 0b01000, 0b01000, 0b01000, 0b01000, 0b01110,
+    ], // [
+    [
+        0b00000, 0b10000, 0b01000, 0b00100, 0b00010, 0b00001, 0b00000,
+    ], // backslash
+    [
+        0b01110, 0b00010, 0b00010, 0b00010, 0b00010, 0b00010, 0b01110,
+    ], // ]
+    [
+        0b00100, 0b01010, 0b10001, 0b00000, 0b00000, 0b00000, 0b00000,
+    ], // ^
+    [
+        0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b11111,
+    ], // _
+    [
+        0b01000, 0b00100, 0b00000, 0b00000, 0b00000, 0b00000, 0b00000,
+    ], // `
+    [
+        0b00000, 0b00000, 0b01110, 0b00001, 0b01111, 0b10001, 0b01111,
+    ], // a
+    [
+        0b10000, 0b10000, 0b10110, 0b11001, 0b10001, 0b10001, 0b11110,
+    ], // b
+    [
+        0b00000, 0b00000, 0b01110, 0b10000, 0b10000, 0b10001, 0b01110,
+    ], // c
+    [
+        0b00001, 0b00001, 0b01101, 0b10011, 0b10001, 0b10001, 0b01111,
+    ], // d
+    [
+        0b00000, 0b00000, 0b01110, 0b10001, 0b11111, 0b10000, 0b01110,
+    ], // e
+    [
+        0b00110, 0b01001, 0b01000, 0b11100, 0b01000, 0b01000, 0b01000,
+    ], // f
+    [
+        0b00000, 0b01111, 0b10001, 0b10001, 0b01111, 0b00001, 0b01110,
+    ], // g
+    [
+        0b10000, 0b10000, 0b10110, 0b11001, 0b10001, 0b10001, 0b10001,
+    ], // h
+    [
+        0b00100, 0b00000, 0b01100, 0b00100, 0b00100, 0b00100, 0b01110,
+    ], // i
+    [
+        0b00010, 0b00000, 0b00110, 0b00010, 0b00010, 0b10010, 0b01100,
+    ], // j
+    [
+        0b10000, 0b10000, 0b10010, 0b10100, 0b11000, 0b10100, 0b10010,
+    ], // k
+    [
+        0b01100, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b01110,
+    ], // l
+    [
+        0b00000, 0b00000, 0b11010, 0b10101, 0b10101, 0b10001, 0b10001,
+    ], // m
+    [
+        0b00000, 0b00000, 0b10110, 0b11001, 0b10001, 0b10001, 0b10001,
+    ], // n
+    [
+        0b00000, 0b00000, 0b01110, 0b10001, 0b10001, 0b10001, 0b01110,
+    ], // o
+    [
+        0b00000, 0b00000, 0b11110, 0b10001, 0b11110, 0b10000, 0b10000,
+    ], // p
+    [
+        0b00000, 0b00000, 0b01101, 0b10011, 0b01111, 0b00001, 0b00001,
+    ], // q
+    [
+        0b00000, 0b00000, 0b10110, 0b11001, 0b10000, 0b10000, 0b10000,
+    ], // r
+    [
+        0b00000, 0b00000, 0b01110, 0b10000, 0b01110, 0b00001, 0b11110,
+    ], // s
+    [
+        0b01000, 0b01000, 0b11100, 0b01000, 0b01000, 0b01001, 0b00110,
+    ], // t
+    [
+        0b00000, 0b00000, 0b10001, 0b10001, 0b10001, 0b10011, 0b01101,
+    ], // u
+    [
+        0b00000, 0b00000, 0b10001, 0b10001, 0b10001, 0b01010, 0b00100,
+    ], // v
+    [
+        0b00000, 0b00000, 0b10001, 0b10001, 0b10101, 0b10101, 0b01010,
+    ], // w
+    [
+        0b00000, 0b00000, 0b10001, 0b01010, 0b00100, 0b01010, 0b10001,
+    ], // x
+    [
+        0b00000, 0b00000, 0b10001, 0b10001, 0b01111, 0b00001, 0b01110,
+    ], // y
+    [
+        0b00000, 0b00000, 0b11111, 0b00010, 0b00100, 0b01000, 0b11111,
+    ], // z
+    [
+        0b00010, 0b00100, 0b00100, 0b01000, 0b00100, 0b00100, 0b00010,
+    ], // {
+    [
+        0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100,
+    ], // |
+    [
+        0b01000, 0b00100, 0b00100, 0b00010, 0b00100, 0b00100, 0b01000,
+    ], // }
+    [
+        0b00000, 0b00000, 0b01000, 0b10101, 0b00010, 0b00000, 0b00000,
+    ], // ~
+    [
+        0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b10101,
+    ], // …
+];
+
+/// Which glyph `c` is drawn with
+pub fn glyph(c: char) -> usize {
+    match c {
+        ' '..='~' => c as usize - ' ' as usize,
+        ELLIPSIS => GLYPHS.len() - 1,
+        _ => glyph(UNKNOWN),
+    }
+}
+
+/// The top-left corner of glyph `i`'s cell in the atlas, in texels, counting the padding
+pub fn cell(i: usize) -> [u32; 2] {
+    let i = i as u32;
+    [
+        i % ATLAS_COLUMNS * CELL_SIZE[0],
+        i / ATLAS_COLUMNS * CELL_SIZE[1],
+    ]
+}
+
+/// The atlas as one byte per texel, row by row from the top: 255 inside glyphs and 0 everywhere else
+pub fn atlas() -> Vec<u8> {
+    let mut texels = vec![0; (ATLAS_SIZE[0] * ATLAS_SIZE[1]) as usize];
+    for (i, rows) in GLYPHS.iter().enumerate() {
+        let [cx, cy] = cell(i);
+        for (y, row) in rows.iter().enumerate() {
+            for x in 0..GLYPH_SIZE[0] {
+                if row & (1 << (GLYPH_SIZE[0] - 1 - x)) != 0 {
+                    let tx = cx + PADDING + x;
+                    let ty = cy + PADDING + y as u32;
+                    texels[(ty * ATLAS_SIZE[0] + tx) as usize] = 255;
+                }
+            }
+        }
+    }
+    texels
+}
+
+/// `text` if it's at most `max` characters long, or else as much of it as fits with an ellipsis after
+pub fn truncate(text: &str, max: usize) -> String {
+    if text.chars().count() <= max {
+        text.to_string()
+    } else {
+        let mut s: String = text.chars().take(max.saturating_sub(1)).collect();
+        s.push(ELLIPSIS);
+        s
+    }
+}
+
+/// How wide a line of text is, in texels, from the left of its first glyph to the right of its last
+pub fn width(text: &str) -> u32 {
+    let n = text.chars().count() as u32;
+    // The last glyph doesn't need the gap after it
+    (n * ADVANCE).saturating_sub(ADVANCE - GLYPH_SIZE[0])
+}
+
+/// Where each character of a line of text goes, as the bottom-left corner of its cell in texels,
+/// with the line centered on x = 0 and the bottom of the glyphs at y = 0, and the top-left of the cell in the atlas.
+/// Spaces are left out, since there's nothing to draw.
+pub fn layout(text: &str) -> Vec<([f32; 2], [u32; 2])> {
+    let width = width(text);
+    text.chars()
+        .enumerate()
+        .filter(|&(_, c)| c != ' ')
+        .map(|(i, c)| {
+            let x = (i as u32 * ADVANCE) as f32 - width as f32 * 0.5 - PADDING as f32;
+            ([x, -(PADDING as f32)], cell(glyph(c)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn atlas() {
+        let atlas = super::atlas();
+        assert_eq!(atlas.len(), (ATLAS_SIZE[0] * ATLAS_SIZE[1]) as usize);
+        let texel = |x: u32, y: u32| atlas[(y * ATLAS_SIZE[0] + x) as usize] != 0;
+        // The top of 'T' is a solid line, and its cell is padded
+        let [cx, cy] = cell(glyph('T'));
+        assert!((0..GLYPH_SIZE[0]).all(|x| texel(cx + PADDING + x, cy + PADDING)));
+        assert!((0..CELL_SIZE[0]).all(|x| !texel(cx + x, cy)));
+        assert!((0..CELL_SIZE[1]).all(|y| !texel(cx, cy + y)));
+        // Spaces are empty, and everything else has something in it
+        let [cx, cy] = cell(glyph(' '));
+        assert!((0..CELL_SIZE[0]).all(|x| (0..CELL_SIZE[1]).all(|y| !texel(cx + x, cy + y))));
+        assert!(GLYPHS[1..].iter().all(|g| g.iter().any(|&r| r != 0)));
+        assert!(GLYPHS.iter().flatten().all(|&r| r < 1 << GLYPH_SIZE[0]));
+    }
+
+    #[test]
+    fn glyphs() {
+        assert_eq!(glyph(' '), 0);
+        assert_eq!(glyph('~'), 94);
+        assert_eq!(glyph(ELLIPSIS), 95);
+        assert_eq!(glyph('\u{e9}'), glyph('?'));
+        assert_eq!(glyph('\n'), glyph('?'));
+    }
+
+    #[test]
+    fn truncates() {
+        assert_eq!(truncate("Steve", 8), "Steve");
+        assert_eq!(truncate("Alexandria", 8), "Alexand\u{2026}");
+        assert_eq!(truncate("\u{e9}\u{e9}\u{e9}", 2), "\u{e9}\u{2026}");
+    }
+
+    #[test]
+    fn layout() {
+        assert!(super::layout("").is_empty());
+        let l = super::layout("a b");
+        assert_eq!(l.len(), 2);
+        // Three glyphs and two gaps is 17 texels, so it starts 8.5 left of the middle, minus the padding
+        assert_eq!(l[0], ([-9.5, -1.0], cell(glyph('a'))));
+        assert_eq!(l[1].0, [-9.5 + 2.0 * ADVANCE as f32, -1.0]);
+        // And ends as far to the right
+        let right = l[1].0[0] + (CELL_SIZE[0] - PADDING) as f32;
+        assert_eq!(right, 8.5);
+    }
+}
@@ -0,0 +1,227 @@
+//! Generates chunks on several threads at once, so a burst of new chunks doesn't hold up everything else
+use crate::common::*;
+use std::sync::mpsc::*;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// How long `finished()` waits for a chunk when asked to
+const WAIT: Duration = Duration::from_millis(1);
+/// How many chunks each thread can have queued or generating at once.
+/// This is kept small, so when the player turns around the chunks in front of them don't wait behind old ones.
+const JOBS_PER_THREAD: usize = 2;
+
+/// Keeps the current thread on one core, so it doesn't compete with whatever else is running on the others.
+/// Not every platform supports this, and where it doesn't, this does nothing.
+pub fn pin_current_thread(core: usize) {
+    core_affinity::set_for_current(core_affinity::CoreId { id: core });
+}
+
+pub struct GenPool {
+    jobs: Sender<Vector3<i32>>,
+    results: Receiver<(Vector3<i32>, Chunk)>,
+    /// Chunks we've submitted that haven't come back yet
+    in_flight: usize,
+    capacity: usize,
+}
+
+impl GenPool {
+    /// Starts `threads` threads generating chunks with `gen`, usually `Gen::gen()`.
+    /// If `cores` isn't empty, each one is pinned to the next core in it.
+    pub fn new(
+        gen: impl Fn(Vector3<i32>) -> Chunk + Send + Sync + 'static,
+        threads: usize,
+        cores: &[usize],
+    ) -> Self {
+        let gen = Arc::new(gen);
+        let threads = threads.max(1);
+        if cores.is_empty() {
+            info!("Generating chunks on {} threads", threads);
+        } else {
+            info!(
+                "Generating chunks on {} threads, pinned to cores {:?}",
+                threads, cores
+            );
+        }
+        let capacity = threads * JOBS_PER_THREAD;
+
+        let (jobs, job_recv) = channel::<Vector3<i32>>();
+        let job_recv = Arc::new(Mutex::new(job_recv));
+        // We never have more than `capacity` chunks in flight, so this never actually blocks the workers
+        let (result_send, results) = sync_channel(capacity);
+        for i in 0..threads {
+            let gen = Arc::clone(&gen);
+            let job_recv = Arc::clone(&job_recv);
+            let result_send = result_send.clone();
+            let core = if cores.is_empty() {
+                None
+            } else {
+                Some(cores[i % cores.len()])
+            };
+            thread::spawn(move || {
+                if let Some(core) = core {
+                    pin_current_thread(core);
+                }
+                loop {
+                    // Stop once the pool is dropped
+                    let p = match job_recv.lock().unwrap().recv() {
+                        Ok(p) => p,
+                        Err(_) => break,
+                    };
+                    if result_send.send((p, gen(p))).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+
+        GenPool {
+            jobs,
+            results,
+            in_flight: 0,
+            capacity,
+        }
+    }
+
+    /// Whether we can submit another chunk. If not, wait for some to finish first.
+    pub fn has_room(&self) -> bool {
+        self.in_flight < self.capacity
+    }
+
+    /// Whether there's nothing generating right now
+    pub fn is_idle(&self) -> bool {
+        self.in_flight == 0
+    }
+
+    /// Starts generating the chunk at `p`. Call `has_room()` first.
+    pub fn submit(&mut self, p: Vector3<i32>) {
+        self.in_flight += 1;
+        self.jobs.send(p).unwrap();
+    }
+
+    /// Returns up to `max` finished chunks. If `wait` is set, and there's one generating, waits a little for the first one.
+    /// Chunks that `keep()` is false for are dropped, for ones that went out of range while they were generating;
+    /// they don't count towards `max`, but they do make room.
+    pub fn finished(
+        &mut self,
+        max: usize,
+        wait: bool,
+        keep: impl Fn(Vector3<i32>) -> bool,
+    ) -> Vec<(Vector3<i32>, Chunk)> {
+        let mut ret = Vec::new();
+        if max == 0 || self.is_idle() {
+            return ret;
+        }
+        let mut next = if wait {
+            self.results.recv_timeout(WAIT).ok()
+        } else {
+            None
+        };
+        while ret.len() < max {
+            let (p, chunk) = match next.take().or_else(|| self.results.try_recv().ok()) {
+                Some(x) => x,
+                None => break,
+            };
+            self.in_flight -= 1;
+            if keep(p) {
+                ret.push((p, chunk));
+            }
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    /// A pool that makes empty chunks, but only once `go` has been sent something for each one
+    fn gated(threads: usize) -> (GenPool, Sender<()>) {
+        let (go, gate) = channel();
+        let gate = Mutex::new(gate);
+        let pool = GenPool::new(
+            move |_| {
+                gate.lock().unwrap().recv().unwrap();
+                Chunk::empty()
+            },
+            threads,
+            &[],
+        );
+        (pool, go)
+    }
+
+    /// Calls `finished()` until `n` chunks have come back, `max` at a time, and returns where they were
+    fn collect(pool: &mut GenPool, n: usize, max: usize) -> Vec<Vector3<i32>> {
+        let start = Instant::now();
+        let mut ret = Vec::new();
+        while ret.len() < n {
+            assert!(start.elapsed() < Duration::from_secs(10), "{:?}", ret);
+            let got = pool.finished(max, true, |_| true);
+            assert!(got.len() <= max);
+            ret.extend(got.into_iter().map(|(p, _)| p));
+        }
+        ret
+    }
+
+    #[test]
+    fn back_pressure() {
+        let (mut pool, go) = gated(2);
+        assert!(pool.is_idle());
+        let mut n = 0;
+        while pool.has_room() {
+            pool.submit(Vector3::new(n, 0, 0));
+            n += 1;
+        }
+        assert_eq!(n as usize, 2 * JOBS_PER_THREAD);
+        assert!(!pool.is_idle());
+
+        // Nothing's done, so it comes back empty either way, and waiting gives up after `WAIT`
+        assert!(pool.finished(n as usize, false, |_| true).is_empty());
+        let start = Instant::now();
+        assert!(pool.finished(n as usize, true, |_| true).is_empty());
+        assert!(start.elapsed() >= WAIT);
+        assert!(!pool.has_room());
+
+        // Once one's done there's room for one more
+        go.send(()).unwrap();
+        let mut all = collect(&mut pool, 1, 1);
+        assert!(pool.has_room());
+        pool.submit(Vector3::new(n, 0, 0));
+        assert!(!pool.has_room());
+
+        // Then everything comes back, a couple at a time
+        for _ in 0..n {
+            go.send(()).unwrap();
+        }
+        all.extend(collect(&mut pool, n as usize, 2));
+        all.sort_by_key(|p| p.x);
+        assert_eq!(
+            all,
+            (0..=n).map(|x| Vector3::new(x, 0, 0)).collect::<Vec<_>>()
+        );
+        assert!(pool.is_idle());
+        assert!(pool.finished(1, true, |_| true).is_empty());
+    }
+
+    #[test]
+    fn drops_unwanted() {
+        let (mut pool, go) = gated(1);
+        let (near, far) = (Vector3::new(0, 0, 0), Vector3::new(9, 0, 0));
+        pool.submit(near);
+        pool.submit(far);
+        go.send(()).unwrap();
+        go.send(()).unwrap();
+
+        // `far` went out of range, so only `near` comes back, but both make room
+        let start = Instant::now();
+        let mut got = Vec::new();
+        while !pool.is_idle() {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            got.extend(pool.finished(2, true, |p| p != far));
+        }
+        assert_eq!(got.len(), 1);
+        assert_eq!(got[0].0, near);
+        assert!(pool.has_room());
+    }
+}
@@ -0,0 +1,16 @@
+#version 450
+
+// Translucent markers, like the preview of what the brush will place, blended over whatever's behind them
+
+layout(location = 0) in vec3 frag_color_in;
+
+// The alpha channel here is how opaque it is. The pipeline keeps the distance `main.frag` put there,
+// so motion blur and depth of field go by what's behind it.
+layout(location = 0) out vec4 frag_color;
+
+// How much of the color behind shows through is 1 minus this
+#define OPACITY 0.35
+
+void main() {
+  frag_color = vec4(frag_color_in, OPACITY);
+}
@@ -1,6 +1,7 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct KeyCodes {
     pub forward: u32,
     pub left: u32,
@@ -9,6 +10,34 @@ pub struct KeyCodes {
 
     pub up: u32,
     pub down: u32,
+
+    /// Switches between walking and flying. In walking mode, `up` jumps.
+    pub walk: u32,
+    /// Held to move `sprint_speed` times as fast, and widen the view to `sprint_fov`
+    pub sprint: u32,
+    /// Turns the periodic frame rate and position logging on and off
+    pub debug_info: u32,
+    /// Switches to the next debug view, and back to the normal image after the last one
+    pub debug_mode: u32,
+    /// Saves the world now, instead of waiting until quitting
+    pub save: u32,
+    /// Adds a keyframe to the camera path where the camera is now, after as long as it's been since the last one
+    pub keyframe: u32,
+    /// Flies the camera along the path, or stops if it's already going
+    pub play_path: u32,
+    /// Deletes every keyframe on the camera path
+    pub clear_path: u32,
+    /// Switches to the next brush shape. Scrolling changes its size.
+    pub brush_shape: u32,
+    /// Switches between fullscreen and a window
+    pub fullscreen: u32,
+    /// Turns depth of field on and off
+    pub dof: u32,
+    /// Move the brightness slider up and down a step, between `MIN_BRIGHTNESS` and `MAX_BRIGHTNESS`
+    pub brightness_up: u32,
+    pub brightness_down: u32,
+    /// Shows and hides the debug overlay, with the frame rate, position, loaded chunks and ping over the corner of the window
+    pub overlay: u32,
 }
 
 pub const DEFAULT_KEY_CODES: KeyCodes = KeyCodes {
@@ -19,4 +48,27 @@ pub const DEFAULT_KEY_CODES: KeyCodes = KeyCodes {
 
     up: 56,   // Space
     down: 42, // LShift
+
+    walk: 33,       // F
+    sprint: 29,     // LCtrl
+    debug_info: 61, // F3
+    debug_mode: 62, // F4
+    save: 63,       // F5
+    keyframe: 64,   // F6
+    play_path: 65,  // F7
+    clear_path: 66, // F8
+
+    brush_shape: 48, // B
+    fullscreen: 87,  // F11
+    dof: 67,         // F9
+
+    brightness_up: 13,   // =
+    brightness_down: 12, // -
+    overlay: 68,         // F10
 };
+
+impl Default for KeyCodes {
+    fn default() -> Self {
+        DEFAULT_KEY_CODES
+    }
+}
@@ -0,0 +1,63 @@
+//! The game as a library, shared by the client (`main.rs`) and the dedicated server (`bin/quanta-server.rs`).
+//! Everything that needs Vulkan or a window is behind the `client` feature, so the server can be built without it.
+#[macro_use]
+extern crate log;
+
+#[cfg(feature = "client")]
+pub mod bench;
+pub mod brush;
+#[cfg(feature = "client")]
+pub mod camera;
+pub mod camera_path;
+pub mod chunk_thread;
+#[cfg(feature = "client")]
+pub mod client;
+#[cfg(feature = "client")]
+pub mod client_world;
+pub mod common;
+pub mod config;
+pub mod console;
+pub mod day_clock;
+#[cfg(feature = "client")]
+pub mod event;
+pub mod font;
+pub mod gen_pool;
+pub mod input;
+pub mod lod_bias;
+#[cfg(feature = "client")]
+pub mod markers;
+pub mod material;
+pub mod node_alloc;
+pub mod noise_gen;
+pub mod octree;
+pub mod offline;
+#[cfg(feature = "client")]
+pub mod overlay;
+#[cfg(feature = "client")]
+pub mod plugin;
+pub mod png;
+#[cfg(feature = "client")]
+pub mod post;
+pub mod profile;
+pub mod region;
+pub mod remote;
+pub mod render_scale;
+#[cfg(feature = "scripting")]
+pub mod script;
+pub mod server;
+#[cfg(feature = "client")]
+pub mod shaders;
+pub mod spirv;
+pub mod terrain;
+pub mod thumbnail;
+pub mod vox;
+pub mod vox_scene;
+#[cfg(feature = "client")]
+pub mod window;
+pub mod window_geometry;
+pub mod world;
+
+pub const APP_INFO: app_dirs2::AppInfo = app_dirs2::AppInfo {
+    name: "quanta",
+    author: "Lorxu",
+};
@@ -0,0 +1,170 @@
+//! Adaptive level of detail: makes distant nodes of the octree draw as single voxels when frames take too long,
+//! and brings the detail back when there's time to spare. The bias is in powers of two of a pixel,
+//! so at 0 only nodes smaller than about a pixel are merged, and at 2 ones up to four pixels across are.
+//! It works alongside dynamic resolution, and `share()` decides which one a frame counts for,
+//! so distant detail goes before resolution does and comes back after it.
+//! Changes only happen after a long run of slow or fast frames, and the bar for fast frames is well under budget,
+//! so the detail doesn't flicker back and forth between two levels.
+use crate::config::ClientConfig;
+
+/// How many frames in a row have to be over budget before we make it coarser
+const SLOW_FRAMES: usize = 45;
+/// How many frames in a row have to be comfortably under budget before we make it finer.
+/// Since every change is visible, this is longer than for dynamic resolution.
+const FAST_FRAMES: usize = 240;
+/// Frames have to take less than this much of the budget to count as fast.
+/// This is lower than dynamic resolution's, since a finer level costs more than a slightly higher resolution.
+const FAST_MARGIN: f64 = 0.7;
+/// How much the bias changes at a time
+const STEP: f32 = 0.25;
+
+pub struct LodBias {
+    bias: f32,
+    min: f32,
+    max: f32,
+    target_ms: f64,
+    /// How many frames in a row were over budget, or under it with room to spare
+    slow: usize,
+    fast: usize,
+}
+
+impl LodBias {
+    /// Starts at `min_lod_bias`, the most detail allowed, and stays between that and `max_lod_bias`
+    pub fn new(config: &ClientConfig) -> Self {
+        LodBias {
+            bias: config.min_lod_bias,
+            min: config.min_lod_bias,
+            max: config.max_lod_bias,
+            target_ms: config.target_frame_ms as f64,
+            slow: 0,
+            fast: 0,
+        }
+    }
+
+    pub fn bias(&self) -> f32 {
+        self.bias
+    }
+
+    /// Whether it's as coarse as it's allowed to go
+    pub fn at_max(&self) -> bool {
+        self.bias >= self.max
+    }
+
+    /// Records how long a frame took, and returns the new bias if it changed
+    pub fn frame(&mut self, ms: f64) -> Option<f32> {
+        if ms > self.target_ms {
+            self.slow += 1;
+            self.fast = 0;
+        } else if ms < self.target_ms * FAST_MARGIN {
+            self.fast += 1;
+            self.slow = 0;
+        } else {
+            self.slow = 0;
+            self.fast = 0;
+        }
+
+        let new = if self.slow >= SLOW_FRAMES {
+            (self.bias + STEP).min(self.max)
+        } else if self.fast >= FAST_FRAMES {
+            (self.bias - STEP).max(self.min)
+        } else {
+            return None;
+        };
+        // Start counting again, with frames at the new level
+        self.slow = 0;
+        self.fast = 0;
+        if (new - self.bias).abs() < f32::EPSILON {
+            return None;
+        }
+        self.bias = new;
+        Some(new)
+    }
+}
+
+/// Which controller a frame that took `ms` counts for, when both LOD and dynamic resolution are on.
+/// Returns the frame time to give the LOD controller and the dynamic resolution one, in that order;
+/// the other one gets `target_ms`, which is on budget so it doesn't move either way.
+/// Slow frames go to the LOD first, until it's at `max_lod_bias`, and fast ones go to the resolution first, until it's at its max.
+pub fn share(ms: f64, target_ms: f64, lod_at_max: bool, scale_at_max: bool) -> (f64, f64) {
+    let lod_first = if ms > target_ms {
+        !lod_at_max
+    } else {
+        scale_at_max
+    };
+    if lod_first {
+        (ms, target_ms)
+    } else {
+        (target_ms, ms)
+    }
+}
+
+/// How big a node can be, compared to how far away it is, and still be drawn as one voxel, for `lod_scale` in `main.frag`.
+/// `fov` is vertical in radians and `height` is in pixels. It's half of a pixel's size at `bias` 0, since the shader compares it to half a node.
+pub fn lod_scale(bias: f32, fov: f32, height: f32) -> f32 {
+    let pixel = 2.0 * (fov * 0.5).tan() / height.max(1.0);
+    0.5 * pixel * bias.exp2()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lod() -> LodBias {
+        LodBias::new(&ClientConfig {
+            min_lod_bias: 0.0,
+            max_lod_bias: 1.0,
+            target_frame_ms: 10.0,
+            ..ClientConfig::default()
+        })
+    }
+
+    #[test]
+    fn coarser_when_slow_and_finer_with_room() {
+        let mut l = lod();
+        for _ in 0..SLOW_FRAMES - 1 {
+            assert_eq!(l.frame(15.0), None);
+        }
+        assert_eq!(l.frame(15.0), Some(0.25));
+        for _ in 0..SLOW_FRAMES * 100 {
+            l.frame(100.0);
+        }
+        assert_eq!(l.bias(), 1.0);
+        assert!(l.at_max());
+
+        // Just under budget doesn't bring detail back, so it doesn't flicker
+        for _ in 0..FAST_FRAMES * 2 {
+            assert_eq!(l.frame(8.0), None);
+        }
+        for _ in 0..FAST_FRAMES - 1 {
+            assert_eq!(l.frame(5.0), None);
+        }
+        assert_eq!(l.frame(5.0), Some(0.75));
+    }
+
+    #[test]
+    fn ignores_spikes() {
+        let mut l = lod();
+        for i in 0..SLOW_FRAMES * 10 {
+            let ms = if i % 10 == 0 { 50.0 } else { 9.0 };
+            assert_eq!(l.frame(ms), None);
+        }
+    }
+
+    #[test]
+    fn detail_goes_before_resolution() {
+        // Slow: the LOD takes it until it's at its max
+        assert_eq!(share(15.0, 10.0, false, true), (15.0, 10.0));
+        assert_eq!(share(15.0, 10.0, true, true), (10.0, 15.0));
+        // Fast: the resolution takes it until it's back up
+        assert_eq!(share(5.0, 10.0, false, false), (10.0, 5.0));
+        assert_eq!(share(5.0, 10.0, true, true), (5.0, 10.0));
+    }
+
+    #[test]
+    fn scale_is_in_pixels() {
+        let fov = std::f32::consts::FRAC_PI_2;
+        // A 90 degree fov is 2 units tall at distance 1, so each of 1000 pixels is 0.002
+        assert!((lod_scale(0.0, fov, 1000.0) - 0.001).abs() < 1e-6);
+        assert!((lod_scale(2.0, fov, 1000.0) - 0.004).abs() < 1e-6);
+    }
+}
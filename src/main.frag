@@ -4,6 +4,7 @@
 #define IPI 3.1415926535
 
 layout(location=0) in vec2 frag_coord_ndc;
+// The alpha channel has the distance to the hit, for motion blur
 layout(location=0) out vec4 frag_color;
 
 layout(push_constant) uniform PushConstants {
@@ -15,6 +16,16 @@ layout(push_constant) uniform PushConstants {
   vec3 camera_dir;
   vec3 camera_up;
   vec3 sun_dir;
+  // The material the camera is inside of, usually air
+  uint camera_mat;
+  // 0 to trace with a stack, 1 to restart from the root instead - see `Traversal` in `config.rs`
+  uint traversal;
+  // See `DebugMode` in `config.rs`: 0 is the normal image, 1 shows how many steps each ray took instead,
+  // and 2 tints each chunk a different color and draws lines along the borders between them
+  uint debug_mode;
+  // Which sides of the root camera rays wrap around at, coming back in the other side: bit 0 for x, 1 for y and 2 for z.
+  // See `world_wrap` in `config.rs`.
+  uint wrap;
 };
 
 // Each node takes up eight consecutive slots in tree[], which correspond to the eight child pointers.
@@ -25,27 +36,206 @@ layout(set=0, binding=0, std430) buffer octree_buffer {
 };
 layout(set=0, binding=1) uniform sampler2D beam_image;
 
-#define MAX_ITER 256
+// Settings that come from the config, and don't change every frame
+layout(set=0, binding=3) uniform Settings {
+  vec3 sky_zenith;
+  float sun_size;
+  vec3 sky_horizon;
+  float sun_intensity;
+  vec3 outline_color;
+  float outline_width;
+  // How soft shadows are, in radians. Zero means hard shadows.
+  float sun_angular_size;
+  // The most nodes a camera ray can visit, which bigger roots need more of
+  uint max_ray_steps;
+  // If this is 0, the sky is left for `post.frag` to replace with the clear color, and fog fades into `background` instead
+  uint draw_sky;
+  // The clear color, without alpha
+  vec3 background;
+  // Nothing closer than `near` or farther than `far` is drawn, and things fade into the sky as they get close to `far`
+  float near;
+  float far;
+  // How many voxels wide chunks are, for the chunk border overlay
+  float chunk_size;
+  // Rays farther than this get fewer steps, down to `min_step_fraction` of `max_ray_steps` at `far`. Zero turns that off.
+  float step_falloff_start;
+  float min_step_fraction;
+  // How much of what's behind a silhouette edge is blended into the pixels along it, from 0 to 1. Zero turns edge antialiasing off.
+  float edge_aa;
+  // See `ShadowMode` in `config.rs`: 0 traces a ray to the sun for each pixel, and 1 uses the shadows baked into the leaves
+  uint shadow_mode;
+  // How much of the way between two levels of detail nodes drawn as one voxel fade from one to the other over, see `lod_fade_color`
+  float lod_fade;
+};
+
+// Things that change every frame, but don't fit in the push constants
+layout(set=1, binding=0) uniform FrameData {
+  // The center of the voxel the player is looking at
+  vec3 target_pos;
+  // The size of that voxel, or 0 if they aren't looking at anything
+  float target_size;
+  // The near and far planes of the camera's projection matrix, so markers drawn with it are hidden behind voxels
+  float proj_near;
+  float proj_far;
+  // How far off the pixel centers to trace, in pixels, for `still_accumulation`
+  vec2 jitter;
+  // For the adaptive LOD bias: nodes smaller than this times their distance are drawn as one voxel. Zero draws everything.
+  float lod_scale;
+};
+
+// What we put in the alpha channel for the sky. It has to fit in a 16-bit float.
+#define SKY_DEPTH 60000.0
+// Things start fading into the sky this fraction of the way to `far`
+#define FAR_FADE 0.8
+// The most times a camera ray can go around with `wrap`, on top of running out of steps or going past `far`
+#define MAX_WRAPS 8
 
 #include "sky.glsl"
 #include "octree.glsl"
 #include "shade.glsl"
 
+// How wide the lines between chunks are, in voxels
+#define CHUNK_LINE_WIDTH 0.08
+
+// For `debug_mode` 2: tints `color` depending on which chunk `pos`, a point on the surface of a voxel, is in,
+// and makes it a line if it's near the border of that chunk.
+// Positions are relative to the render origin, which is on a chunk corner, so this lines up if rebasing is right.
+vec3 chunk_overlay(vec3 color, vec3 pos, vec3 rd) {
+  // Just inside the voxel, so we get its chunk instead of the one in front of it
+  vec3 inside = pos + rd * 0.001;
+  vec3 chunk = floor(inside / chunk_size);
+  vec3 tint = fract(sin(vec3(
+    dot(chunk, vec3(12.9898, 78.233, 37.719)),
+    dot(chunk, vec3(39.346, 11.135, 83.155)),
+    dot(chunk, vec3(73.156, 52.235, 9.151))
+  )) * 43758.5453);
+  color = mix(color, tint, 0.3);
+
+  // The face we hit is on the axis that's closest to a whole number, and that axis can't have a line along it,
+  // or faces right on a chunk border would be all line
+  vec3 face = abs(pos - round(pos));
+  int n = face.x < face.y ? (face.x < face.z ? 0 : 2) : (face.y < face.z ? 1 : 2);
+  vec3 border = abs(inside - chunk_size * round(inside / chunk_size));
+  for (int i = 0; i < 3; i++)
+    if (i != n && border[i] < CHUNK_LINE_WIDTH)
+      return vec3(1.0, 0.2, 0.8);
+  return color;
+}
+
+// The depth `projection_matrix()` in `camera.rs` gives to a point `dist` along `rd`
+float frag_depth(float dist, vec3 rd) {
+  float z = max(dist * dot(rd, camera_dir), proj_near);
+  return saturate(proj_far / (proj_far - proj_near) * (1.0 - proj_near / z));
+}
+
 layout(set=0, binding=2, std430) buffer material_buffer {
   MatData mats[];
 };
 
+// The color of each non-leaf node for when it's drawn as one voxel, for `lod_color` in the config; see `lod_colors()` in `octree.rs`.
+// There's one for each group of eight nodes in tree[], for the node that points to it. Each channel is the square root of the color,
+// in a byte, and the top byte is 0 if there's no color, like for `First`, so whatever's first inside it is used instead.
+layout(set=0, binding=4, std430) readonly buffer lod_buffer {
+  uint lod_colors[];
+};
+
+// For `edge_aa`: if the pixel's footprint, a cone around `rd` that's half a pixel across, hangs over an edge of the voxel it hit,
+// blends in what a ray just past the edge sees, by how much of the footprint is past it.
+// Only silhouettes count: if the surface goes on past the edge, bends back toward us, or turns onto a side of the voxel we can see,
+// there's no discontinuity to soften, so flat and inside surfaces stay sharp.
+// `pos` is the center of the voxel the ray from `ro` hit at `t`, and `start_t` is how far along from the camera `ro` is.
+vec3 edge_blend(vec3 color, vec3 ro, vec3 rd, vec2 t, vec3 pos, float start_t) {
+  float dist = start_t + t.x;
+  vec3 d = ro + rd * t.x - pos;
+  vec3 ad = abs(d);
+  // Bigger than voxels if it's a whole node drawn as one, with the LOD
+  float half_size = max(ad.x, max(ad.y, ad.z));
+  // The face we hit is on the axis we're farthest from the center on, and the nearest edge is on one of the other two
+  int n = ad.x >= ad.y ? (ad.x >= ad.z ? 0 : 2) : (ad.y >= ad.z ? 1 : 2);
+  int a = (n + 1) % 3;
+  int b = (n + 2) % 3;
+  int e = ad[a] > ad[b] ? a : b;
+  float gap = half_size - ad[e];
+  float r = dist * tan(fov * 0.5) / resolution.y;
+  if (gap >= r)
+    return color;
+
+  vec3 normal = vec3(0.0);
+  normal[n] = sign(d[n]);
+  vec3 side = vec3(0.0);
+  side[e] = sign(d[e]);
+  float size = 2.0 * half_size;
+  if (dot(side, rd) < 0.0 || get_voxel(pos + side * size) != 0u || get_voxel(pos + (side + normal) * size) != 0u)
+    return color;
+
+  // Like the ray through the next pixel over, passing the edge just outside it
+  vec3 past = ro + rd * t.x + side * (gap + 0.5 * r);
+  vec3 rd2 = normalize(past - camera_pos);
+  vec3 ro2 = camera_pos + rd2 * start_t;
+  vec2 t2;
+  int i = int(max_ray_steps);
+  vec3 p2;
+  uint result = trace(ro2, rd2, t2, i, p2);
+  vec3 fallback = draw_sky != 0u ? sky(ro2, rd2) : background;
+  vec3 behind = fallback;
+  if (result != 0u && start_t + t2.x < far) {
+    behind = shade(ro2, rd2, t2, p2, mats[leaf_mat(result)], result);
+    behind = mix(behind, fallback, smoothstep(FAR_FADE * far, far, start_t + t2.x));
+  }
+
+  // The footprint's a disc, but a line through it is close enough: half is past the edge when it's right on it
+  float coverage = saturate(0.5 + 0.5 * gap / r);
+  return mix(color, behind, (1.0 - coverage) * edge_aa);
+}
+
+// For `lod_fade`: `coarse` is the color of the node the ray stopped at for `lod_scale`, which fades toward what the next level down
+// would show there as the node gets closer to being big enough to go into. Then colors ease in and out as the level of detail changes.
+// The next level is just the child the ray goes into first, so it doesn't take any more steps.
+// `block` is the group of the node's children, like `lod_block`, `pos` is its center, and the ray from `ro` hit it at `t`.
+vec3 lod_fade_color(vec3 coarse, uint block, vec3 ro, vec3 rd, vec2 t, vec3 pos) {
+  vec3 d = ro + rd * t.x - pos;
+  float half_size = max(abs(d.x), max(abs(d.y), abs(d.z)));
+  // This is 1 when the node is only just small enough to stop at, and 0.5 when its parent is, where the parent's own color takes over
+  float level = half_size / (t.x * lod_scale);
+  float fine_part = smoothstep(1.0 - 0.5 * lod_fade, 1.0, level);
+  if (fine_part <= 0.0)
+    return coarse;
+
+  // A little inside, so it's in the child on the side the ray came in on
+  vec3 inside = d + rd * half_size * 0.01;
+  uint ptr = block * 8u;
+  uint child = tree[ptr + u_idx(inside)];
+  vec3 fine;
+  if ((child & 1u) > 0u) {
+    uint c = lod_colors[(ptr + (child >> 1)) >> 3];
+    if ((c >> 24) == 0u)
+      return coarse;
+    fine = unpackUnorm4x8(c).rgb;
+    fine *= fine;
+  } else if (child != 0u) {
+    fine = mats[leaf_mat(child)].color;
+  } else {
+    // The ray would have gone on past it, to something we don't know the color of
+    return coarse;
+  }
+  return mix(coarse, fine, fine_part);
+}
+
 void main() {
   vec2 uv = frag_coord_ndc;
   vec4 ts = textureGather(beam_image, uv*0.5+0.5);
-  float start_t = max(0.0, min(min(ts.x, ts.y), min(ts.z, ts.w)) - 1.0);
+  float start_t = max(near, min(min(ts.x, ts.y), min(ts.z, ts.w)) - 1.0);
+  uv += jitter * 2.0 / resolution;
   uv.x *= resolution.x / resolution.y;
   // Vulkan has this backwards for us
   uv *= -1;
 
   // Circle in the center of the screen to show where they're pointing
   if (length(uv) < 0.007 && length(uv) > 0.003 && min(abs(uv.x), abs(uv.y)) > 0.002) {
-      frag_color = vec4(1.0);
+      // Zero depth means it doesn't get blurred
+      frag_color = vec4(1.0, 1.0, 1.0, 0.0);
+      // And it's in front of everything
+      gl_FragDepth = 0.0;
       return;
   }
 
@@ -58,18 +248,89 @@ void main() {
   rd += film_width * right * uv.x;
   rd = normalize(rd);
 
+  vec3 tmid, tmax;
+  if (wrap != 0u) {
+    // The beams don't wrap, so they only know about the way to the side of the root
+    vec2 rt = isect(ro, 1.0 / rd, origin, root_size, tmid, tmax);
+    start_t = max(near, min(start_t, rt.y - 0.01));
+  }
   ro += rd * start_t;
 
   vec2 t;
-  int i = 256;
+  int i = int(max_ray_steps);
   vec3 p;
+  stackless = traversal == 1u;
+  // Not with `wrap`, since a ray it cuts short would look like one that left the root, and go around
+  if (step_falloff_start > 0.0 && wrap == 0u) {
+    // `trace` measures from `ro`, which already starts `start_t` along the ray
+    falloff_start = max(step_falloff_start - start_t, 0.001);
+    falloff_end = far - start_t;
+    falloff_min = min_step_fraction;
+  }
+  // `trace` measures from `ro` here too, but a node is only farther from the camera than that, so it's a little more detailed than asked
+  lod_tan = lod_scale;
   uint result = trace(ro, rd, t, i, p);
+  uint lod = lod_block;
+  vec3 wrapped = vec3(notEqual(uvec3(wrap) & uvec3(1u, 2u, 4u), uvec3(0u)));
+  for (int w = 0; w < MAX_WRAPS && result == 0u && wrap != 0u && i > 0; w++) {
+    // Where it left the root, which only wraps if every side it went through there does
+    vec2 rt = isect(ro, 1.0 / rd, origin, root_size, tmid, tmax);
+    vec3 side = vec3(equal(tmax, vec3(rt.y)));
+    if (dot(side, 1.0 - wrapped) > 0.0 || start_t + rt.y > far)
+      break;
+    // Moving the start back by the size of the root puts it in the same place on the other side, at the same distance along the ray,
+    // so everything after this is drawn as though the root were repeated, with fog and depth from how far the ray really went
+    ro -= side * sign(rd) * root_size;
+    result = trace(ro, rd, t, i, p);
+    lod = lod_block;
+  }
+  // Shadow rays get all their steps, and all the detail
+  falloff_start = 0.0;
+  lod_tan = 0.0;
+  if (start_t + t.x > far)
+    result = 0;
+  gl_FragDepth = result != 0 ? frag_depth(start_t + t.x, rd) : 1.0;
+  if (debug_mode == 1u) {
+    // Blue for no steps, through green, to red for all of them
+    float s = 1.0 - float(i) / float(max_ray_steps);
+    frag_color = vec4(saturate(2.0 * s - 1.0), 1.0 - abs(2.0 * s - 1.0), saturate(1.0 - 2.0 * s), 0.0);
+    return;
+  }
   if (result != 0) {
-    MatData mat = mats[result];
+    MatData mat = mats[leaf_mat(result)];
+    if (lod != 0u && (lod_colors[lod] >> 24) != 0u) {
+      vec3 c = unpackUnorm4x8(lod_colors[lod]).rgb;
+      mat.color = c * c;
+    }
+    if (lod != 0u && lod_fade > 0.0)
+      mat.color = lod_fade_color(mat.color, lod, ro, rd, t, p);
     //mat.color = vec3(0.3, 0.6, 0.1);
-    frag_color = vec4(shade(ro, rd, t, p, mat), 1.0);
+    frag_color = vec4(shade(ro, rd, t, p, mat, result), start_t + t.x);
+    // Fade out before `far`, into the same thing that's drawn past it
+    float fade = smoothstep(FAR_FADE * far, far, start_t + t.x);
+    frag_color.rgb = mix(frag_color.rgb, draw_sky != 0u ? sky(ro, rd) : background, fade);
+    // Before the outline, so that stays sharp
+    if (edge_aa > 0.0)
+      frag_color.rgb = edge_blend(frag_color.rgb, ro, rd, t, p, start_t);
+
+    // Outline the edges of the targeted voxel
+    if (target_size > 0.0) {
+      vec3 d = 0.5 * target_size - abs(ro + rd * t.x - target_pos); // Distance to the faces on each axis
+      if (all(greaterThan(d, vec3(-0.01)))) {
+        // We're on one face, so we're near an edge if we're also near a second one
+        float mid = d.x + d.y + d.z - min(d.x, min(d.y, d.z)) - max(d.x, max(d.y, d.z));
+        if (mid < outline_width)
+          frag_color.rgb = outline_color;
+      }
+    }
+
+    if (debug_mode == 2u)
+      frag_color.rgb = chunk_overlay(frag_color.rgb, ro + rd * t.x, rd);
   } else {
-    frag_color = vec4(sky(ro, rd), 1.0);
+    frag_color = vec4(sky(ro, rd), SKY_DEPTH);
   }
-  // frag_color.r = float(i)/256.0;
+
+  // Everything is murky from inside water
+  if (camera_mat != 0u && mats[camera_mat].trans > 0.0)
+    frag_color.rgb = underwater(frag_color.rgb, frag_color.a, mats[camera_mat].color);
 }
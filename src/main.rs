@@ -1,61 +1,163 @@
-use crate::config::*;
+#[macro_use]
+extern crate log;
+
+use quanta::client_world::WorldSource;
+use quanta::common::*;
+use quanta::config::*;
+use quanta::{bench, event, server, APP_INFO};
 use std::fs::File;
 use std::io::Write;
 
 use std::sync::Arc;
 
-mod camera;
-mod chunk_thread;
-mod client;
-mod client_world;
-mod common;
-mod config;
-mod event;
-mod input;
-mod material;
-mod octree;
-mod server;
-mod shaders;
-mod terrain;
-mod window;
-mod world;
-use common::*;
-
-pub const APP_INFO: app_dirs2::AppInfo = app_dirs2::AppInfo {
-    name: "quanta",
-    author: "Lorxu",
-};
-
 fn main() {
     let mut config_file =
         app_dirs2::app_root(app_dirs2::AppDataType::UserConfig, &APP_INFO).unwrap();
     config_file.push("config.ron");
-    let client_config = if config_file.exists() {
-        ron::de::from_reader(File::open(config_file).unwrap()).expect("bad config file")
+    let mut client_config = if config_file.exists() {
+        ClientConfig::load(&config_file).expect("bad config file")
     } else {
-        let c = ClientConfig {
-            keycodes: crate::input::DEFAULT_KEY_CODES,
-            game_config: Arc::new(GameConfig {
-                draw_chunks: 16,
-                batch_size: 64,
-                save_chunks: true,
-            }),
-        };
+        let c = ClientConfig::default();
         let s = ron::ser::to_string(&c).unwrap();
-        let mut f = File::create(config_file).unwrap();
+        let mut f = File::create(&config_file).unwrap();
         writeln!(f, "{}", s).unwrap();
         c
     };
+
+    env_logger::Builder::from_env(
+        env_logger::Env::default().default_filter_or(client_config.log_level.as_str()),
+    )
+    .init();
+    client_config.validate();
+
+    // `quanta --bench [path.ron] [--bench-output json|csv|pretty] [--bench-out <file>]` runs the benchmark instead of the game,
+    // and `quanta --calibrate [path.ron] [--fps <fps>]` runs it with different settings to find ones fast enough
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let bench = args.first().map_or(false, |a| a == "--bench");
+    let calibrate = args.first().map_or(false, |a| a == "--calibrate");
+    if bench {
+        client_config = bench::bench_config(client_config.log_level);
+    } else if calibrate {
+        client_config = bench::calibrate_config(client_config);
+    }
+
     let client_config = Arc::new(client_config);
 
     let config = Arc::clone(&client_config.game_config);
+    let view_distance = client_config.view_distance;
+
+    // `quanta --connect <address>` plays on a dedicated server instead of starting one, like `server_address`
+    let connect = args
+        .iter()
+        .position(|a| a == "--connect")
+        .map(|i| {
+            args.get(i + 1)
+                .expect("--connect needs an address, like localhost:7700 or [::1]:7700")
+                .clone()
+        })
+        .or_else(|| client_config.server_address.clone());
+    // A static scene doesn't need a world, so there's no server to start. `--connect` still plays online.
+    let static_scene = client_config
+        .static_scene
+        .clone()
+        .filter(|_| connect.is_none() && !bench && !calibrate);
+    if let Some(path) = static_scene {
+        set_chunk_size(config.chunk_size);
+        set_max_depth(config.max_depth);
+        let chunks = quanta::client::load_static_scene(&path, client_config.vox_up_axis)
+            .unwrap_or_else(|e| panic!("Couldn't load {}: {}", path.display(), e));
+        info!("Viewing {} on its own", path.display());
+        event::run_client_loop(
+            WorldSource::Static(chunks),
+            client_config,
+            config_file,
+            Vec::new(),
+        );
+    }
+    let conn_client = match &connect {
+        Some(address) => {
+            info!("Connecting to {}", address);
+            let (conn, size) = Connection::join(address, &client_config, JOIN_TIMEOUT)
+                .unwrap_or_else(|e| {
+                    error!("Couldn't join {}: {}", address, e);
+                    std::process::exit(1)
+                });
+            set_chunk_size(size);
+            conn
+        }
+        None => {
+            set_chunk_size(config.chunk_size);
+            let (conn_client, conn_server) = Connection::local();
+            std::thread::spawn(move || {
+                let mut server = server::Server::new(config);
+                server.join(conn_server, Vector3::zeros(), view_distance);
+                server.run();
+            });
+            conn_client
+        }
+    };
 
-    let (conn_client, conn_server) = Connection::local();
-    std::thread::spawn(move || {
-        let mut server = server::Server::new(config);
-        server.join(conn_server, Vector3::zeros());
-        server.run();
-    });
+    if bench || calibrate {
+        // Where the value after each of these options is, so it isn't taken for the path
+        let value = |flag: &str| args.iter().position(|a| a == flag).map(|i| i + 1);
+        let (fps_arg, format_arg, out_arg) = (
+            value("--fps"),
+            value("--bench-output"),
+            value("--bench-out"),
+        );
+        let fps = fps_arg.map(|i| {
+            args.get(i)
+                .and_then(|s| s.parse::<f64>().ok())
+                .filter(|&x| x > 0.0)
+                .expect("--fps needs a frame rate, like 60")
+        });
+        let path = args
+            .iter()
+            .enumerate()
+            .skip(1)
+            .find(|&(i, a)| {
+                !a.starts_with("--") && ![fps_arg, format_arg, out_arg].contains(&Some(i))
+            })
+            .map_or("bench/path.ron", |(_, s)| s.as_str());
+        let path = std::path::Path::new(path);
+        if bench {
+            // `--json` is what `--bench-output json` used to be
+            let format = match format_arg {
+                Some(i) => args
+                    .get(i)
+                    .expect("--bench-output needs a format: json, csv or pretty")
+                    .parse()
+                    .unwrap_or_else(|e| panic!("{}", e)),
+                None if args.iter().any(|a| a == "--json") => bench::BenchFormat::Json,
+                None => bench::BenchFormat::Pretty,
+            };
+            let out_file = out_arg.map(|i| {
+                std::path::PathBuf::from(args.get(i).expect("--bench-out needs a file to write to"))
+            });
+            bench::run(
+                conn_client,
+                client_config,
+                path,
+                format,
+                out_file.as_deref(),
+            );
+        } else {
+            let out_file = config_file.with_file_name("calibration.ron");
+            bench::calibrate(
+                conn_client,
+                (*client_config).clone(),
+                path,
+                fps.unwrap_or(bench::DEFAULT_TARGET_FPS),
+                &out_file,
+            );
+        }
+        return;
+    }
 
-    event::run_client_loop(conn_client, client_config);
+    // Only a server we connected to can be connected to again if the connection drops
+    let source = match connect {
+        Some(address) => WorldSource::Remote(conn_client, address),
+        None => WorldSource::Server(conn_client),
+    };
+    event::run_client_loop(source, client_config, config_file, Vec::new());
 }
@@ -0,0 +1,11 @@
+#version 450
+
+layout(location = 0) in vec3 frag_color_in;
+layout(location = 1) in vec3 from_camera;
+
+// Like `main.frag`, the alpha channel has the distance from the camera, for motion blur
+layout(location = 0) out vec4 frag_color;
+
+void main() {
+  frag_color = vec4(frag_color_in, length(from_camera));
+}
@@ -0,0 +1,21 @@
+#version 450
+
+// Lines and triangles from `markers.rs`, drawn over the world with the camera's matrices
+
+layout(push_constant) uniform PushConstants {
+  // Takes positions relative to the render origin to clip space
+  mat4 view_proj;
+  vec3 camera_pos;
+};
+
+layout(location = 0) in vec3 position;
+layout(location = 1) in vec3 color;
+
+layout(location = 0) out vec3 frag_color_in;
+layout(location = 1) out vec3 from_camera;
+
+void main() {
+  gl_Position = view_proj * vec4(position, 1.0);
+  frag_color_in = color;
+  from_camera = position - camera_pos;
+}
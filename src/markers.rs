@@ -0,0 +1,523 @@
+//! Simple shapes drawn over the world, like selection boxes, other players and debug gizmos, and text labels, like players' names.
+//! Anything can add lines, triangles, translucent voxels and labels to `Markers` during a frame, with `Client::markers()` or `Plugin::on_markers()`.
+//! The client draws them right after the main shader, into the same HDR image and depth buffer,
+//! so voxels in front of them hide them and they get bloom and tone mapping like everything else, and then clears them.
+use crate::common::*;
+use crate::font;
+use crate::shaders::{self, ShaderError};
+use crate::shaders::{GhostFragment, MarkerConstants, MarkerFragment, MarkerVertex, PushConstants};
+use crate::shaders::{TextConstants, TextFragment, TextVertex};
+
+use std::collections::HashSet;
+use std::sync::Arc;
+use vulkano::buffer::{BufferAccess, CpuBufferPool};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, DynamicState};
+use vulkano::descriptor::descriptor_set::{DescriptorSet, PersistentDescriptorSet};
+use vulkano::device::{Device, Queue};
+use vulkano::format::R8Unorm;
+use vulkano::framebuffer::{RenderPassAbstract, Subpass};
+use vulkano::image::{Dimensions, ImmutableImage};
+use vulkano::pipeline::blend::{AttachmentBlend, BlendFactor, BlendOp};
+use vulkano::pipeline::depth_stencil::DepthStencil;
+use vulkano::pipeline::{GraphicsPipeline, GraphicsPipelineAbstract};
+use vulkano::sampler::{Filter, MipmapMode, Sampler, SamplerAddressMode};
+use vulkano::sync::GpuFuture;
+
+/// How big a texel of the font is up close, in voxels, so a line of text is about a third of a voxel tall
+pub const LABEL_TEXEL: f32 = 0.05;
+/// Far away, a line of text stays at least this much of the height of the screen, so it can still be read
+pub const MIN_LABEL_HEIGHT: f32 = 0.02;
+/// Labels longer than this many characters are cut short, with an ellipsis
+pub const MAX_LABEL_CHARS: usize = 20;
+
+/// A vertex as the GPU sees it
+#[derive(Default, Debug, Clone, Copy)]
+struct Vert {
+    /// Relative to the render origin
+    position: [f32; 3],
+    color: [f32; 3],
+}
+vulkano::impl_vertex!(Vert, position, color);
+
+/// A corner of a glyph in a label, as the GPU sees it
+#[derive(Default, Debug, Clone, Copy)]
+struct TextVert {
+    /// The label's position, relative to the render origin
+    position: [f32; 3],
+    /// Where the corner is from `position`, in texels of the font, before `text.vert` faces it toward the camera
+    offset: [f32; 2],
+    /// Where the corner is in the font atlas, in texels
+    uv: [f32; 2],
+    color: [f32; 3],
+}
+vulkano::impl_vertex!(TextVert, position, offset, uv, color);
+
+/// A corner of a marker in world space, and its color.
+/// Colors are linear, and can go above 1 to glow with bloom.
+type Point = (Vector3<f32>, [f32; 3]);
+
+/// The shapes to draw this frame
+#[derive(Default)]
+pub struct Markers {
+    /// Two points for each line
+    lines: Vec<Point>,
+    /// Three points for each triangle
+    triangles: Vec<Point>,
+    /// Three points for each translucent triangle
+    ghosts: Vec<Point>,
+    /// Where each label is, what it says and its color
+    labels: Vec<(Vector3<f32>, String, [f32; 3])>,
+}
+
+impl Markers {
+    pub fn line(&mut self, a: Vector3<f32>, b: Vector3<f32>, color: [f32; 3]) {
+        self.lines.extend_from_slice(&[(a, color), (b, color)]);
+    }
+
+    /// Triangles are drawn from both sides
+    pub fn triangle(&mut self, a: Vector3<f32>, b: Vector3<f32>, c: Vector3<f32>, color: [f32; 3]) {
+        self.triangles
+            .extend_from_slice(&[(a, color), (b, color), (c, color)]);
+    }
+
+    /// The twelve edges of the box from `min` to `max`.
+    /// Lines right on the surface of a voxel fight with it over depth, so make boxes around voxels a little bigger than them.
+    pub fn box_outline(&mut self, min: Vector3<f32>, max: Vector3<f32>, color: [f32; 3]) {
+        for axis in 0..3 {
+            // Four edges go along each axis, one for each combination of the other two axes being at `min` or `max`
+            let (j, k) = ((axis + 1) % 3, (axis + 2) % 3);
+            for corner in 0..4 {
+                let mut a = min;
+                if corner & 1 != 0 {
+                    a[j] = max[j];
+                }
+                if corner & 2 != 0 {
+                    a[k] = max[k];
+                }
+                let mut b = a;
+                b[axis] = max[axis];
+                self.line(a, b, color);
+            }
+        }
+    }
+
+    /// Translucent cubes over each of the voxels at `voxels`, which are their centers, like the brush preview.
+    /// Faces between two of them are left out, so they look like one solid shape instead of a stack of boxes.
+    /// They don't hide anything behind them, even each other, so they're drawn after everything else.
+    pub fn ghost_voxels(&mut self, voxels: &[Vector3<f32>], color: [f32; 3]) {
+        let set: HashSet<[i32; 3]> = voxels.iter().map(|p| voxel_key(*p)).collect();
+        for &p in voxels {
+            let key = voxel_key(p);
+            for axis in 0..3 {
+                for &side in &[-1, 1] {
+                    let mut next = key;
+                    next[axis] += side;
+                    if set.contains(&next) {
+                        continue;
+                    }
+                    // The four corners of the face, going around it
+                    let (j, k) = ((axis + 1) % 3, (axis + 2) % 3);
+                    let corner = |a: f32, b: f32| {
+                        let mut c = p;
+                        c[axis] += 0.5 * side as f32;
+                        c[j] += a;
+                        c[k] += b;
+                        (c, color)
+                    };
+                    let q = [
+                        corner(-0.5, -0.5),
+                        corner(0.5, -0.5),
+                        corner(0.5, 0.5),
+                        corner(-0.5, 0.5),
+                    ];
+                    self.ghosts
+                        .extend_from_slice(&[q[0], q[1], q[2], q[0], q[2], q[3]]);
+                }
+            }
+        }
+    }
+
+    /// A line of text with its bottom middle at `pos`, facing the camera. It gets smaller farther away, but not so small it can't be read.
+    /// It has a black outline, so it shows up in front of anything, but voxels in front of it still hide it.
+    /// Only the first `MAX_LABEL_CHARS` characters are drawn, and characters outside of ASCII are question marks.
+    pub fn label(&mut self, pos: Vector3<f32>, text: &str, color: [f32; 3]) {
+        self.labels
+            .push((pos, font::truncate(text, MAX_LABEL_CHARS), color));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+            && self.triangles.is_empty()
+            && self.ghosts.is_empty()
+            && self.labels.is_empty()
+    }
+
+    /// Removes everything, which the client does after every frame
+    pub fn clear(&mut self) {
+        self.lines.clear();
+        self.triangles.clear();
+        self.ghosts.clear();
+        self.labels.clear();
+    }
+}
+
+/// Which voxel the one centered at `p` is, as a whole number on each axis
+fn voxel_key(p: Vector3<f32>) -> [i32; 3] {
+    let v = p.map(|x| x.floor() as i32);
+    [v.x, v.y, v.z]
+}
+
+/// The points as vertices relative to `origin`, which we subtract in double precision like everything else sent to the GPU
+fn vertices(points: &[Point], origin: RenderOrigin) -> impl ExactSizeIterator<Item = Vert> + '_ {
+    points.iter().map(move |(pos, color)| Vert {
+        position: origin.local(pos.map(|x| x as f64)).into(),
+        color: *color,
+    })
+}
+
+/// Two triangles for each glyph of each label, relative to `origin`
+fn text_vertices(
+    labels: &[(Vector3<f32>, String, [f32; 3])],
+    origin: RenderOrigin,
+) -> Vec<TextVert> {
+    let [w, h] = font::CELL_SIZE;
+    let mut verts = Vec::new();
+    for (pos, text, color) in labels {
+        let position: [f32; 3] = origin.local(pos.map(|x| x as f64)).into();
+        for ([x, y], [u, v]) in font::layout(text) {
+            // The atlas goes down from the top, and offsets go up from the bottom
+            let corner = |cx: u32, cy: u32| TextVert {
+                position,
+                offset: [x + (cx * w) as f32, y + (cy * h) as f32],
+                uv: [(u + cx * w) as f32, (v + (1 - cy) * h) as f32],
+                color: *color,
+            };
+            verts.extend_from_slice(&[
+                corner(0, 0),
+                corner(1, 0),
+                corner(1, 1),
+                corner(0, 0),
+                corner(1, 1),
+                corner(0, 1),
+            ]);
+        }
+    }
+    verts
+}
+
+/// The font atlas in a descriptor set for `pipeline`, which should draw with `text.frag`.
+/// The future is for uploading it, which has to be done before drawing any text with it.
+pub fn font_desc(
+    device: Arc<Device>,
+    queue: Arc<Queue>,
+    pipeline: &Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+) -> (
+    Arc<dyn DescriptorSet + Send + Sync>,
+    impl GpuFuture + Send + Sync,
+) {
+    let (atlas, future) = ImmutableImage::from_iter(
+        font::atlas().into_iter(),
+        Dimensions::Dim2d {
+            width: font::ATLAS_SIZE[0],
+            height: font::ATLAS_SIZE[1],
+        },
+        R8Unorm,
+        queue,
+    )
+    .unwrap();
+    // `text.frag` reads texels directly, so this doesn't filter anything, but it needs a sampler anyway
+    let sampler = Sampler::new(
+        device,
+        Filter::Nearest,
+        Filter::Nearest,
+        MipmapMode::Nearest,
+        SamplerAddressMode::ClampToEdge,
+        SamplerAddressMode::ClampToEdge,
+        SamplerAddressMode::ClampToEdge,
+        0.0,
+        1.0,
+        0.0,
+        0.0,
+    )
+    .unwrap();
+    let desc = Arc::new(
+        PersistentDescriptorSet::start(pipeline.descriptor_set_layout(0).unwrap().clone())
+            .add_sampled_image(atlas, sampler)
+            .unwrap()
+            .build()
+            .unwrap(),
+    );
+    (desc, future)
+}
+
+/// The pipelines for drawing `Markers`
+pub struct MarkerPass {
+    lines: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    triangles: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    /// Blends over what's there, without writing depth
+    ghosts: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    pool: CpuBufferPool<Vert>,
+    text: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    /// The font atlas, for the text pipeline
+    text_desc: Arc<dyn DescriptorSet + Send + Sync>,
+    text_pool: CpuBufferPool<TextVert>,
+}
+
+impl MarkerPass {
+    /// `rpass` should be `Post::hdr_rpass()`, since markers need its depth buffer.
+    /// The future is for uploading the font, which has to be done before drawing any labels.
+    pub fn new(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        rpass: Arc<dyn RenderPassAbstract + Send + Sync>,
+    ) -> Result<(Self, impl GpuFuture + Send + Sync), ShaderError> {
+        let vs = shaders::load("marker.vert", || MarkerVertex::load(device.clone()))?;
+        let fs = shaders::load("marker.frag", || MarkerFragment::load(device.clone()))?;
+        let pipeline = |lines: bool| {
+            let start = GraphicsPipeline::start()
+                .vertex_input_single_buffer::<Vert>()
+                .vertex_shader(vs.main_entry_point(), ());
+            let start = if lines {
+                start.line_list()
+            } else {
+                start.triangle_list()
+            };
+            Arc::new(
+                start
+                    .viewports_dynamic_scissors_irrelevant(1)
+                    .fragment_shader(fs.main_entry_point(), ())
+                    .depth_stencil_simple_depth()
+                    .render_pass(Subpass::from(rpass.clone(), 0).unwrap())
+                    .build(device.clone())
+                    .unwrap(),
+            ) as Arc<dyn GraphicsPipelineAbstract + Send + Sync>
+        };
+
+        let ghost_fs = shaders::load("ghost.frag", || GhostFragment::load(device.clone()))?;
+        let ghosts = Arc::new(
+            GraphicsPipeline::start()
+                .vertex_input_single_buffer::<Vert>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(ghost_fs.main_entry_point(), ())
+                // Hidden behind voxels, but not hiding anything itself
+                .depth_stencil(DepthStencil {
+                    depth_write: false,
+                    ..DepthStencil::simple_depth_test()
+                })
+                // The color is mixed by the alpha `ghost.frag` gives it, and the alpha channel keeps the distance that was there
+                .blend_collective(AttachmentBlend {
+                    alpha_op: BlendOp::Add,
+                    alpha_source: BlendFactor::Zero,
+                    alpha_destination: BlendFactor::One,
+                    ..AttachmentBlend::alpha_blending()
+                })
+                .render_pass(Subpass::from(rpass.clone(), 0).unwrap())
+                .build(device.clone())
+                .unwrap(),
+        ) as Arc<dyn GraphicsPipelineAbstract + Send + Sync>;
+
+        let text_vs = shaders::load("text.vert", || TextVertex::load(device.clone()))?;
+        let text_fs = shaders::load("text.frag", || TextFragment::load(device.clone()))?;
+        let text = Arc::new(
+            GraphicsPipeline::start()
+                .vertex_input_single_buffer::<TextVert>()
+                .vertex_shader(text_vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(text_fs.main_entry_point(), ())
+                .depth_stencil_simple_depth()
+                .render_pass(Subpass::from(rpass.clone(), 0).unwrap())
+                .build(device.clone())
+                .unwrap(),
+        ) as Arc<dyn GraphicsPipelineAbstract + Send + Sync>;
+        let (text_desc, future) = font_desc(device.clone(), queue, &text);
+
+        Ok((
+            MarkerPass {
+                lines: pipeline(true),
+                triangles: pipeline(false),
+                ghosts,
+                pool: CpuBufferPool::vertex_buffer(device.clone()),
+                text,
+                text_desc,
+                text_pool: CpuBufferPool::vertex_buffer(device),
+            },
+            future,
+        ))
+    }
+
+    /// Records drawing `markers` into the render pass the main shader drew into, which should still be open.
+    /// `view_proj` takes positions relative to `origin` to clip space, and `pc` is what the main shader drew with,
+    /// for where the camera is and which way it's facing.
+    pub fn draw(
+        &self,
+        mut builder: AutoCommandBufferBuilder,
+        markers: &Markers,
+        state: &DynamicState,
+        view_proj: na::Matrix4<f32>,
+        pc: &PushConstants,
+        origin: RenderOrigin,
+    ) -> AutoCommandBufferBuilder {
+        let camera_pos = pc.camera_pos;
+        let pc_text = text_constants(view_proj, pc);
+        let pc = MarkerConstants {
+            view_proj: view_proj.into(),
+            camera_pos,
+        };
+        for (points, pipeline) in &[
+            (&markers.lines, &self.lines),
+            (&markers.triangles, &self.triangles),
+            (&markers.ghosts, &self.ghosts),
+        ] {
+            if points.is_empty() {
+                continue;
+            }
+            let buf = Arc::new(self.pool.chunk(vertices(points, origin)).unwrap())
+                as Arc<dyn BufferAccess + Send + Sync>;
+            builder = builder
+                .draw((*pipeline).clone(), state, vec![buf], (), pc)
+                .unwrap();
+        }
+        if !markers.labels.is_empty() {
+            let buf = Arc::new(
+                self.text_pool
+                    .chunk(text_vertices(&markers.labels, origin))
+                    .unwrap(),
+            ) as Arc<dyn BufferAccess + Send + Sync>;
+            builder = builder
+                .draw(
+                    self.text.clone(),
+                    state,
+                    vec![buf],
+                    self.text_desc.clone(),
+                    pc_text,
+                )
+                .unwrap();
+        }
+        builder
+    }
+}
+
+/// What `text.vert` needs to face labels toward the camera described by `pc` and size them
+fn text_constants(view_proj: na::Matrix4<f32>, pc: &PushConstants) -> TextConstants {
+    let dir = Vector3::from(pc.camera_dir);
+    let up = Vector3::from(pc.camera_up);
+    // The same as `Camera::view_matrix()`
+    let right = dir.cross(&up).normalize();
+    // A line of text at a distance of 1 is this much of the screen's height, in voxels, at least
+    let min_height = MIN_LABEL_HEIGHT * 2.0 * (pc.fov * 0.5).tan();
+    let [x, y, z] = pc.camera_pos;
+    TextConstants {
+        view_proj: view_proj.into(),
+        camera_pos: [x, y, z, LABEL_TEXEL],
+        right: [
+            right.x,
+            right.y,
+            right.z,
+            min_height / font::GLYPH_SIZE[1] as f32,
+        ],
+        up: [up.x, up.y, up.z, 0.0],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn box_outline() {
+        let mut m = Markers::default();
+        assert!(m.is_empty());
+        let (min, max) = (Vector3::new(0.0, 1.0, 2.0), Vector3::new(1.0, 3.0, 5.0));
+        m.box_outline(min, max, [1.0; 3]);
+        assert_eq!(m.lines.len(), 24);
+        for line in m.lines.chunks(2) {
+            let (a, b) = (line[0].0, line[1].0);
+            // Each edge goes all the way along one axis, and is on a corner on the others
+            let d = b - a;
+            assert_eq!(
+                d.iter().filter(|&&x| x != 0.0).count(),
+                1,
+                "{:?} {:?}",
+                a,
+                b
+            );
+            assert!(d.iter().zip(&(max - min)).all(|(d, s)| *d == 0.0 || d == s));
+            assert!((0..3).all(|i| a[i] == min[i] || a[i] == max[i]));
+        }
+        // And they're all different
+        let mut edges: Vec<_> = m
+            .lines
+            .chunks(2)
+            .map(|l| format!("{:?}", (l[0].0, l[1].0)))
+            .collect();
+        edges.sort();
+        edges.dedup();
+        assert_eq!(edges.len(), 12);
+
+        m.clear();
+        assert!(m.is_empty());
+    }
+
+    #[test]
+    fn ghost_voxels() {
+        let mut m = Markers::default();
+        let voxels = [Vector3::new(0.5, 0.5, 0.5), Vector3::new(1.5, 0.5, 0.5)];
+        m.ghost_voxels(&voxels, [1.0; 3]);
+        assert!(!m.is_empty());
+        // Two cubes side by side have ten faces on the outside, each two triangles
+        assert_eq!(m.ghosts.len(), 10 * 6);
+        // None of them are on the face between them
+        assert!(m
+            .ghosts
+            .chunks(3)
+            .all(|t| !t.iter().all(|&(p, _)| p.x == 1.0)));
+        // And every corner is on the box around both
+        assert!(m
+            .ghosts
+            .iter()
+            .all(|&(p, _)| (0..3).all(|i| p[i] >= 0.0 && p[i] <= if i == 0 { 2.0 } else { 1.0 })));
+
+        m.clear();
+        assert!(m.is_empty());
+    }
+
+    #[test]
+    fn labels() {
+        let mut m = Markers::default();
+        m.label(Vector3::new(1.0, 2.0, 3.0), "hi there", [1.0; 3]);
+        m.label(Vector3::zeros(), &"x".repeat(100), [1.0; 3]);
+        assert!(!m.is_empty());
+        assert_eq!(m.labels[1].1.chars().count(), MAX_LABEL_CHARS);
+
+        let verts = text_vertices(&m.labels[..1], RenderOrigin(Vector3::zeros()));
+        // The space isn't drawn
+        assert_eq!(verts.len(), 7 * 6);
+        assert!(verts.iter().all(|v| v.position == [1.0, 2.0, 3.0]));
+        // It's centered, and the glyphs sit on its position
+        let min = verts
+            .iter()
+            .map(|v| v.offset[0])
+            .fold(f32::INFINITY, f32::min);
+        let max = verts
+            .iter()
+            .map(|v| v.offset[0])
+            .fold(-f32::INFINITY, f32::max);
+        assert_eq!(min, -max);
+        let bottom = verts
+            .iter()
+            .map(|v| v.offset[1])
+            .fold(f32::INFINITY, f32::min);
+        assert_eq!(bottom, -(font::PADDING as f32));
+        // The atlas is upside down from the offsets, so the glyphs are the right way up
+        for glyph in verts.chunks(6) {
+            for a in glyph {
+                for b in glyph {
+                    assert_eq!(a.offset[1] > b.offset[1], a.uv[1] < b.uv[1]);
+                }
+            }
+        }
+    }
+}
@@ -1,10 +1,14 @@
+#[cfg(feature = "client")]
 use crate::shaders::MatData;
 use enum_iterator::IntoEnumIterator;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 #[derive(
     IntoEnumIterator,
     PartialEq,
+    Eq,
+    Hash,
     Clone,
     Copy,
     Debug,
@@ -22,9 +26,66 @@ pub enum Material {
     Sand,
     Wood,
     Leaf,
+    Ice,
     Wrong,
 }
 
+impl Material {
+    /// The name of every material, in order of the numbers they're stored as.
+    /// Saves record this, so they still load right if materials are added or reordered.
+    pub fn names() -> Vec<String> {
+        Material::into_enum_iter()
+            .map(|x| format!("{:?}", x))
+            .collect()
+    }
+
+    /// How much grip walking on this has, compared to normal ground. Below 1 is slippery.
+    pub fn friction(self) -> f32 {
+        match self {
+            Material::Ice => 0.05,
+            Material::Sand => 0.7,
+            _ => 1.0,
+        }
+    }
+
+    /// What this is called for things built on top of the game, like footstep sounds, which the renderer doesn't care about.
+    /// It's the tag in `tags` if there is one, like `material_tags` in the config, or the material's name in lowercase, like `"stone"`.
+    /// Since it goes by the material, it stays the same through edits, saves and .vox imports.
+    pub fn tag(self, tags: &HashMap<Material, String>) -> String {
+        tags.get(&self)
+            .cloned()
+            .unwrap_or_else(|| format!("{:?}", self).to_lowercase())
+    }
+
+    /// The material with this name, ignoring case, like `"stone"`
+    pub fn from_name(name: &str) -> Option<Material> {
+        Material::into_enum_iter().find(|x| format!("{:?}", x).eq_ignore_ascii_case(name))
+    }
+
+    /// What each material number in a save made with `names` is now, or `None` if nothing changed.
+    /// Materials that don't exist anymore become `Wrong`, so they're easy to spot.
+    pub fn remap(names: &[String]) -> Option<Vec<Material>> {
+        let current = Material::names();
+        if names == current.as_slice() {
+            return None;
+        }
+        let all: Vec<_> = Material::into_enum_iter().collect();
+        Some(
+            names
+                .iter()
+                .map(|name| {
+                    current
+                        .iter()
+                        .position(|x| x == name)
+                        .map_or(Material::Wrong, |i| all[i])
+                })
+                .collect(),
+        )
+    }
+}
+
+/// The GPU side of materials, which the server doesn't need
+#[cfg(feature = "client")]
 impl Material {
     pub fn all() -> Vec<MatData> {
         Material::into_enum_iter().map(|x| x.mat_data()).collect()
@@ -96,6 +157,14 @@ impl Material {
                 ior: 1.45,
                 nothing: 0.0,
             },
+            Material::Ice => MatData {
+                color: [0.7, 0.85, 0.95],
+                roughness: 0.05,
+                trans: 0.0,
+                metal: 0.0,
+                ior: 1.31,
+                nothing: 0.0,
+            },
             Material::Wrong => MatData {
                 color: [1000.0, 0.0, 0.0],
                 roughness: 1.0,
@@ -121,4 +190,33 @@ mod test {
             Some(Material::Dirt)
         );
     }
+
+    #[test]
+    fn tags() {
+        let mut tags = HashMap::new();
+        assert_eq!(Material::Stone.tag(&tags), "stone");
+        tags.insert(Material::Leaf, "grass".to_string());
+        assert_eq!(Material::Leaf.tag(&tags), "grass");
+        assert_eq!(Material::Grass.tag(&tags), "grass");
+    }
+
+    #[test]
+    fn remap_by_name() {
+        assert_eq!(Material::remap(&Material::names()), None);
+
+        // An older version with fewer materials, in a different order
+        let old: Vec<String> = ["Air", "Dirt", "Stone", "Marble"]
+            .iter()
+            .map(|x| x.to_string())
+            .collect();
+        assert_eq!(
+            Material::remap(&old),
+            Some(vec![
+                Material::Air,
+                Material::Dirt,
+                Material::Stone,
+                Material::Wrong
+            ])
+        );
+    }
 }
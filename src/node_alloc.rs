@@ -0,0 +1,275 @@
+//! Keeps track of which parts of the tree buffer are in use, in units of nodes (8 `u32`s each).
+//! This doesn't touch the buffer itself; `ClientWorld` does the uploading, and grows the buffer when `len()` does.
+use std::ops::Range;
+
+/// How full and fragmented the tree buffer is, in nodes
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AllocStats {
+    pub used: usize,
+    pub free: usize,
+    /// The free range the biggest allocation that fits without growing would go in
+    pub largest_free: usize,
+    /// The number of separate free ranges
+    pub free_ranges: usize,
+    /// 0 if all the free space is in one piece, approaching 1 as it gets split into lots of small pieces
+    pub fragmentation: f32,
+}
+
+/// A first-fit allocator over a free list.
+/// The free list is sorted and ranges in it never touch, since neighbors are merged when they're freed.
+#[derive(Debug)]
+pub struct NodeAlloc {
+    free: Vec<Range<usize>>,
+    /// Everything before `start` is reserved, for the root structure
+    start: usize,
+    len: usize,
+}
+
+impl NodeAlloc {
+    /// Manages nodes `start..len`, which all start out free
+    pub fn new(start: usize, len: usize) -> Self {
+        assert!(start <= len, "Reserved more nodes than there are");
+        let mut a = NodeAlloc {
+            free: Vec::new(),
+            start,
+            len,
+        };
+        a.free(start..len);
+        a
+    }
+
+    /// One past the last node that could be in use. The buffer needs to be at least this big.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == self.start
+    }
+
+    /// Finds room for `n` consecutive nodes. If nothing's big enough, it grows the end, so check `len()` afterwards.
+    pub fn alloc(&mut self, n: usize) -> Range<usize> {
+        if let Some(i) = self.free.iter().position(|r| r.len() >= n) {
+            let r = &mut self.free[i];
+            let ret = r.start..r.start + n;
+            r.start += n;
+            if r.start == r.end {
+                self.free.remove(i);
+            }
+            return ret;
+        }
+
+        // Nothing fits, so grow, reusing the free range at the end if there is one
+        let start = match self.free.last() {
+            Some(r) if r.end == self.len => self.free.pop().unwrap().start,
+            _ => self.len,
+        };
+        self.len = start + n;
+        start..self.len
+    }
+
+    pub fn alloc_node(&mut self) -> usize {
+        self.alloc(1).start
+    }
+
+    /// Gives back nodes from `alloc()`. They have to be in use: freeing twice panics.
+    pub fn free(&mut self, r: Range<usize>) {
+        if r.start >= r.end {
+            return;
+        }
+        assert!(
+            r.start >= self.start && r.end <= self.len,
+            "Freed nodes {:?} that were never allocated",
+            r
+        );
+        // The first free range that starts after this one
+        let i = self.free.partition_point(|x| x.start < r.start);
+        assert!(
+            (i == 0 || self.free[i - 1].end <= r.start)
+                && (i == self.free.len() || self.free[i].start >= r.end),
+            "Freed nodes {:?} that were already free",
+            r
+        );
+
+        let merge_prev = i > 0 && self.free[i - 1].end == r.start;
+        let merge_next = i < self.free.len() && self.free[i].start == r.end;
+        match (merge_prev, merge_next) {
+            (true, true) => {
+                self.free[i - 1].end = self.free[i].end;
+                self.free.remove(i);
+            }
+            (true, false) => self.free[i - 1].end = r.end,
+            (false, true) => self.free[i].start = r.start,
+            (false, false) => self.free.insert(i, r),
+        }
+    }
+
+    pub fn free_node(&mut self, i: usize) {
+        self.free(i..i + 1);
+    }
+
+    /// Adds room at the end, if `len` is bigger than the current length
+    pub fn grow(&mut self, len: usize) {
+        if len > self.len {
+            let old = self.len;
+            self.len = len;
+            self.free(old..len);
+        }
+    }
+
+    /// Frees everything, so the chunks can be laid out again from the start
+    pub fn clear(&mut self) {
+        self.free.clear();
+        let (start, len) = (self.start, self.len);
+        self.free(start..len);
+    }
+
+    pub fn stats(&self) -> AllocStats {
+        let free: usize = self.free.iter().map(|r| r.len()).sum();
+        let largest_free = self.free.iter().map(|r| r.len()).max().unwrap_or(0);
+        AllocStats {
+            used: self.len - self.start - free,
+            free,
+            largest_free,
+            free_ranges: self.free.len(),
+            fragmentation: if free == 0 {
+                0.0
+            } else {
+                1.0 - largest_free as f32 / free as f32
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alloc_first_fit() {
+        let mut a = NodeAlloc::new(4, 20);
+        assert_eq!(a.alloc(6), 4..10);
+        assert_eq!(a.alloc(6), 10..16);
+        assert_eq!(a.alloc_node(), 16);
+        assert_eq!(a.stats().free, 3);
+        assert_eq!(a.len(), 20);
+    }
+
+    #[test]
+    fn reuse_freed() {
+        let mut a = NodeAlloc::new(0, 16);
+        let x = a.alloc(4);
+        let y = a.alloc(4);
+        a.free(x.clone());
+        // The hole left by `x` is the first thing that fits
+        assert_eq!(a.alloc(3), 0..3);
+        assert_eq!(a.alloc_node(), 3);
+        // Now nothing before `y` is free, so this goes after it
+        assert_eq!(a.alloc(2), y.end..y.end + 2);
+    }
+
+    #[test]
+    fn free_merges() {
+        let mut a = NodeAlloc::new(0, 12);
+        let x = a.alloc(4);
+        let y = a.alloc(4);
+        let z = a.alloc(4);
+        a.free(x);
+        a.free(z);
+        assert_eq!(a.stats().free_ranges, 2);
+        // Freeing the middle joins everything into one range
+        a.free(y);
+        let s = a.stats();
+        assert_eq!(s.free_ranges, 1);
+        assert_eq!(s.largest_free, 12);
+        assert_eq!(s.used, 0);
+        assert_eq!(a.alloc(12), 0..12);
+    }
+
+    #[test]
+    fn free_nodes_one_at_a_time() {
+        let mut a = NodeAlloc::new(0, 8);
+        let nodes: Vec<_> = (0..8).map(|_| a.alloc_node()).collect();
+        assert_eq!(nodes, (0..8).collect::<Vec<_>>());
+        // Out of order, so it has to merge in both directions
+        for &i in &[5, 1, 3, 0, 7, 2, 6, 4] {
+            a.free_node(i);
+        }
+        assert_eq!(a.stats().free_ranges, 1);
+        assert_eq!(a.stats().free, 8);
+    }
+
+    #[test]
+    fn grows_when_full() {
+        let mut a = NodeAlloc::new(2, 10);
+        let x = a.alloc(6);
+        assert_eq!(x, 2..8);
+        // There are 2 free at the end, so it grows by 3 to make room for 5
+        assert_eq!(a.alloc(5), 8..13);
+        assert_eq!(a.len(), 13);
+        // With nothing free at the end it starts right at `len()`
+        a.free(x);
+        assert_eq!(a.alloc(7), 13..20);
+        assert_eq!(a.len(), 20);
+        // But the hole still gets used for things that fit
+        assert_eq!(a.alloc(6), 2..8);
+    }
+
+    #[test]
+    fn grow_adds_free_space() {
+        let mut a = NodeAlloc::new(0, 4);
+        a.alloc(2);
+        a.grow(10);
+        let s = a.stats();
+        assert_eq!(s.free, 8);
+        assert_eq!(s.free_ranges, 1);
+        // Shrinking doesn't do anything
+        a.grow(5);
+        assert_eq!(a.len(), 10);
+    }
+
+    #[test]
+    fn fragmentation() {
+        let mut a = NodeAlloc::new(0, 8);
+        assert_eq!(a.stats().fragmentation, 0.0);
+        let nodes: Vec<_> = (0..8).map(|_| a.alloc_node()).collect();
+        assert_eq!(a.stats().fragmentation, 0.0);
+        // Every other node free: four pieces of one node each
+        for i in nodes.iter().step_by(2) {
+            a.free_node(*i);
+        }
+        let s = a.stats();
+        assert_eq!(s.free_ranges, 4);
+        assert_eq!(s.fragmentation, 0.75);
+    }
+
+    #[test]
+    fn clear_frees_everything() {
+        let mut a = NodeAlloc::new(2, 10);
+        a.alloc(3);
+        let x = a.alloc(3);
+        a.free(x);
+        a.clear();
+        let s = a.stats();
+        assert_eq!(s.used, 0);
+        assert_eq!(s.free_ranges, 1);
+        // The reserved nodes stay reserved
+        assert_eq!(a.alloc(8), 2..10);
+    }
+
+    #[test]
+    #[should_panic(expected = "already free")]
+    fn double_free() {
+        let mut a = NodeAlloc::new(0, 8);
+        let x = a.alloc(4);
+        a.free(x.clone());
+        a.free(x);
+    }
+
+    #[test]
+    #[should_panic(expected = "never allocated")]
+    fn free_reserved() {
+        let mut a = NodeAlloc::new(4, 8);
+        a.free(0..2);
+    }
+}
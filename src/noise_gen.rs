@@ -0,0 +1,194 @@
+//! The noise functions the built-in terrain generator can use, picked with `noise_type` in the config.
+//! They all look like one `Noise` to `Gen`, so they can be compared, or wrapped in something that combines them.
+//! `Perlin` and `Ridged` are `noise`'s own fractals; the others are octaves of a single noise function, summed by `Fractal`.
+//! Each one gives the same value for the same seed and point every time, since chunks are generated in any order, on any thread.
+use crate::config::{GameConfig, NoiseType};
+use noise::*;
+use std::sync::Arc;
+
+/// A noise function, for 2D and 3D points. Values are about between -1 and 1.
+pub trait Noise: Send + Sync {
+    fn get2(&self, p: [f64; 2]) -> f64;
+    fn get3(&self, p: [f64; 3]) -> f64;
+}
+
+impl<T: NoiseFn<[f64; 2]> + NoiseFn<[f64; 3]> + Send + Sync> Noise for T {
+    fn get2(&self, p: [f64; 2]) -> f64 {
+        self.get(p)
+    }
+
+    fn get3(&self, p: [f64; 3]) -> f64 {
+        self.get(p)
+    }
+}
+
+/// Octaves of another noise function, each `lacunarity` times the frequency of the last one and `persistence` times as strong.
+/// Each octave has its own seed, so they don't line up with each other.
+pub struct Fractal<N> {
+    octaves: Vec<N>,
+    frequency: f64,
+    lacunarity: f64,
+    persistence: f64,
+}
+
+impl<N> Fractal<N> {
+    /// `octaves` of whatever `source` makes for each seed, starting from `seed`
+    pub fn new(
+        seed: u32,
+        octaves: usize,
+        frequency: f64,
+        lacunarity: f64,
+        persistence: f64,
+        source: impl Fn(u32) -> N,
+    ) -> Self {
+        Fractal {
+            octaves: (0..octaves.max(1) as u32)
+                .map(|i| source(seed.wrapping_add(i)))
+                .collect(),
+            frequency,
+            lacunarity,
+            persistence,
+        }
+    }
+
+    /// The weighted sum of `get` for each octave, with the frequency it's at, scaled back to the range of one octave
+    fn sum(&self, get: impl Fn(&N, f64) -> f64) -> f64 {
+        let (mut total, mut max) = (0.0, 0.0);
+        let (mut frequency, mut amplitude) = (self.frequency, 1.0);
+        for n in &self.octaves {
+            total += get(n, frequency) * amplitude;
+            max += amplitude;
+            frequency *= self.lacunarity;
+            amplitude *= self.persistence;
+        }
+        if max > 0.0 {
+            total / max
+        } else {
+            total
+        }
+    }
+}
+
+impl<N: NoiseFn<[f64; 2]>> NoiseFn<[f64; 2]> for Fractal<N> {
+    fn get(&self, p: [f64; 2]) -> f64 {
+        self.sum(|n, f| n.get([p[0] * f, p[1] * f]))
+    }
+}
+
+impl<N: NoiseFn<[f64; 3]>> NoiseFn<[f64; 3]> for Fractal<N> {
+    fn get(&self, p: [f64; 3]) -> f64 {
+        self.sum(|n, f| n.get([p[0] * f, p[1] * f, p[2] * f]))
+    }
+}
+
+/// The noise `noise_type` says, with the frequency, octaves, lacunarity and persistence from `config` where they mean something.
+/// `Worley` cells don't make sense in octaves, so it only uses the frequency.
+pub fn noise(config: &GameConfig, seed: u64) -> Arc<dyn Noise> {
+    // `noise` only takes 32-bit seeds, so the halves of the seed are mixed together
+    let seed = (seed ^ (seed >> 32)) as u32;
+    let (octaves, frequency, lacunarity, persistence) = (
+        config.noise_octaves,
+        config.noise_frequency,
+        config.noise_lacunarity,
+        config.noise_persistence,
+    );
+    match config.noise_type {
+        NoiseType::Perlin => Arc::new(
+            HybridMulti::new()
+                .set_seed(seed)
+                .set_octaves(octaves)
+                .set_frequency(frequency)
+                .set_lacunarity(lacunarity)
+                .set_persistence(persistence),
+        ),
+        NoiseType::Ridged => Arc::new(
+            RidgedMulti::new()
+                .set_seed(seed)
+                .set_octaves(octaves)
+                .set_frequency(frequency)
+                .set_lacunarity(lacunarity)
+                .set_persistence(persistence),
+        ),
+        NoiseType::Simplex => Arc::new(Fractal::new(
+            seed,
+            octaves,
+            frequency,
+            lacunarity,
+            persistence,
+            |s| OpenSimplex::new().set_seed(s),
+        )),
+        NoiseType::Value => Arc::new(Fractal::new(
+            seed,
+            octaves,
+            frequency,
+            lacunarity,
+            persistence,
+            |s| Value::new().set_seed(s),
+        )),
+        NoiseType::Worley => Arc::new(Fractal::new(
+            seed,
+            1,
+            frequency,
+            lacunarity,
+            persistence,
+            |s| Worley::new().set_seed(s).enable_range(true),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use enum_iterator::IntoEnumIterator;
+
+    fn config(noise_type: NoiseType) -> GameConfig {
+        GameConfig {
+            noise_type,
+            ..GameConfig::default()
+        }
+    }
+
+    /// Some noise at points all over, including negative ones and ones far from the origin
+    fn sample(n: &dyn Noise) -> Vec<f64> {
+        (-20..20)
+            .flat_map(|i| {
+                let x = i as f64 * 0.37 + 0.01;
+                vec![n.get2([x, x * -1.3]), n.get3([x * 0.7, 1000.5 - x, x * x])]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn deterministic() {
+        for t in NoiseType::into_enum_iter() {
+            let a = sample(&*noise(&config(t), 1234));
+            assert_eq!(a, sample(&*noise(&config(t), 1234)), "{:?}", t);
+            assert_ne!(a, sample(&*noise(&config(t), 5678)), "{:?}", t);
+            assert!(a.iter().all(|x| x.is_finite()), "{:?}", t);
+            // It actually changes from place to place
+            assert!(a.iter().any(|&x| x != a[0]), "{:?}", t);
+        }
+    }
+
+    #[test]
+    fn perlin_is_the_old_terrain() {
+        // What `Gen` used before there was a choice, so saved worlds keep generating the same way
+        let seed: u64 = 0x1234_5678_9abc_def0;
+        let old = HybridMulti::new()
+            .set_seed((seed ^ (seed >> 32)) as u32)
+            .set_octaves(8)
+            .set_persistence(0.5);
+        assert_eq!(sample(&*noise(&GameConfig::default(), seed)), sample(&old));
+    }
+
+    #[test]
+    fn fractal_octaves() {
+        // Constant noise sums to the same constant, however many octaves there are
+        let c = Fractal::new(0, 4, 1.0, 2.0, 0.5, |_| Constant::new(0.5));
+        assert!((c.get([3.0, 4.0]) - 0.5).abs() < 1e-12);
+        // And one octave at frequency 1 is just the source
+        let one = Fractal::new(7, 1, 1.0, 2.0, 0.5, |s| Perlin::new().set_seed(s));
+        let p = [0.3, 1.7, -2.2];
+        assert_eq!(one.get(p), Perlin::new().set_seed(7).get(p));
+    }
+}
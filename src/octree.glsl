@@ -1,4 +1,3 @@
-//#define STACKLESS
 #define START_OUTSIDE
 
 #define PRE_ITER 2
@@ -9,6 +8,38 @@ uint u_idx(vec3 idx) {
         | uint(idx.y > 0.0) << 1
         | uint(idx.z > 0.0);
 }
+// How leaf nodes are laid out, which has to match `pack_leaf()` in `common.rs`:
+// the material is in bits 1-15, and if `AO_BAKED` is set, two bits of AO per face start at `AO_SHIFT`.
+// The three bits from `SHADOW_SHIFT` are the baked shadows for `shadow_mode` 1.
+#define MAT_SHIFT 1u
+#define MAT_MASK 0x7FFFu
+#define AO_SHIFT 16u
+#define AO_BAKED (1u << 28)
+#define SHADOW_SHIFT 29u
+
+uint leaf_mat(uint node) {
+    return (node >> MAT_SHIFT) & MAT_MASK;
+}
+
+// For soft shadows: when `penumbra_k` is positive, `trace` lowers `penumbra` toward 0 as the ray passes close to things.
+// Empty nodes are bigger the farther they are from anything solid, so their size stands in for the distance to the nearest occluder.
+float penumbra_k = 0.0;
+float penumbra = 1.0;
+
+// For camera rays: when `falloff_start` is positive, a ray that has gone farther than that only gets part of the steps it started with,
+// shrinking to `falloff_min` of them at `falloff_end`. Distant rays mostly end up in fog anyway, so they give up sooner instead of
+// wandering through the tree, and whatever they would have hit is drawn as sky.
+float falloff_start = 0.0;
+float falloff_end = 0.0;
+float falloff_min = 1.0;
+
+// For camera rays: when `lod_tan` is positive, `trace` doesn't go into nodes smaller than `lod_tan` times how far away they are,
+// and draws them as one voxel of whatever's first inside them, like `TAN_W` does for beams. The adaptive LOD bias makes it bigger.
+float lod_tan = 0.0;
+// Which group of nodes the one `trace` last stopped at for `lod_tan` points to, which is where its LOD color is, or 0 if it didn't.
+// The first group is the root's, which nothing points to, so 0 is never a real one.
+uint lod_block = 0u;
+
 bvec3 b_idx(vec3 idx) {
     return greaterThan(idx, vec3(0));
 }
@@ -16,8 +47,13 @@ vec3 v_idx(bvec3 b) {
   return vec3(b) * 2.0 - 1.0;
 }
 
-#ifndef STACKLESS
-const int MAX_LEVELS = 8;
+// Which way `trace` goes back up the tree when it leaves a node: when this is false it pops the parent off a stack,
+// and when it's true it starts again from the root and goes down to where it was. They visit the same leaves either way,
+// which `raymarch_stackless()` in `octree.rs` is tested for.
+bool stackless = false;
+
+// Enough for a root of 2^16 voxels, which is `MAX_ROOT_SIZE` in `config.rs`
+const int MAX_LEVELS = 16;
 
 struct ST {
     uint parent_pointer;
@@ -32,15 +68,19 @@ void stack_reset() { stack_ptr = 0; }
 void stack_push(in ST s) { stack[stack_ptr++] = s; }
 ST stack_pop() { return stack[--stack_ptr]; }
 bool stack_empty() { return stack_ptr == 0; }
-#else
-struct ST {
-    uint parent_pointer;
-    vec3 pos;
-    bvec3 idx;
-    float size;
-    float h;
-};
-#endif
+
+// A leaf inside the non-leaf `node`, whose children start at `ptr`, for drawing the whole node as one voxel.
+// It takes the first nonempty child on each level, so it's stable from frame to frame.
+uint lod_leaf(uint ptr, uint node) {
+    for (int j = 0; j < MAX_LEVELS && (node & 1u) > 0; j++) {
+        ptr += node >> 1;
+        uint next = 0u;
+        for (uint k = 0u; k < 8u && next == 0u; k++)
+            next = tree[ptr + k];
+        node = next;
+    }
+    return node;
+}
 
 // `rdi` is 1/rd, assumed to have been precomputed
 vec2 isect(in vec3 ro, in vec3 rdi, in vec3 pos, in float size, out vec3 tmid, out vec3 tmax) {
@@ -78,14 +118,14 @@ uint get_voxel(in vec3 target) {
     return 0u;
 }
 
+// `raymarch()` in `octree.rs` does the same thing on the CPU, without the extras, so tests can check it. Keep them in step.
 #ifdef TAN_W
 uint trace(in vec3 ro, in vec3 rd, in float tan_w, out vec2 t, out int i, out vec3 pos) {
 #else
 uint trace(in vec3 ro, in vec3 rd, out vec2 t, inout int i, out vec3 pos) {
 #endif
-    #ifndef STACKLESS
     stack_reset();
-    #endif
+    lod_block = 0u;
 
     vec3 tstep = sign(rd);
     vec3 rdi = 1.0 / rd; // Inverse for isect
@@ -139,10 +179,17 @@ uint trace(in vec3 ro, in vec3 rd, out vec2 t, inout int i, out vec3 pos) {
     #endif
 
     bool c = true;
+    int budget = i;
 
     for (; i > 0; i--) {
         t = isect(ro, rdi, pos, size, tmid, tmax);
 
+        if (falloff_start > 0.0 && t.x > falloff_start) {
+            float f = clamp((t.x - falloff_start) / max(falloff_end - falloff_start, 1.0), 0.0, 1.0);
+            if (float(budget - i) > float(budget) * mix(1.0, falloff_min, f))
+                return 0;
+        }
+
         uidx = u_idx(idx);
 
         uint node = tree[parent_pointer + uidx];
@@ -150,14 +197,12 @@ uint trace(in vec3 ro, in vec3 rd, out vec2 t, inout int i, out vec3 pos) {
         #ifdef TAN_W
         if (size * 0.5 > abs(t.x) * tan_w && (node & 1u) > 0) {
         #else
-        if ((node & 1u) > 0) { // Non-leaf
+        if ((node & 1u) > 0 && (lod_tan <= 0.0 || size * 0.5 > t.x * lod_tan)) { // Non-leaf, and big enough to go into
         #endif
             if (c) {
               //-- PUSH --//
-              #ifndef STACKLESS
-              if (t.y < h)
+              if (!stackless && t.y < h)
                   stack_push(ST(parent_pointer, pos, b_idx(idx), size, h));
-              #endif
               h = t.y;
               parent_pointer += node >> 1;
               size *= 0.5;
@@ -171,8 +216,17 @@ uint trace(in vec3 ro, in vec3 rd, out vec2 t, inout int i, out vec3 pos) {
               pos += 0.5 * size * idx;
               continue;
             }
-        } else if (node != 0) // Nonempty, but either leaf, or TAN_W and it's small enough
-            return node >> 1;
+        } else if (node != 0) { // Nonempty, but either leaf, or small enough to stop at with TAN_W or `lod_tan`
+            #ifndef TAN_W
+            if ((node & 1u) > 0) {
+                lod_block = (parent_pointer + (node >> 1)) >> 3;
+                return lod_leaf(parent_pointer, node);
+            }
+            #endif
+            return node;
+        }
+        else if (penumbra_k > 0.0 && t.x > 0.0)
+            penumbra = min(penumbra, penumbra_k * size * 0.5 / t.x);
 
         //-- ADVANCE --//
 
@@ -183,47 +237,47 @@ uint trace(in vec3 ro, in vec3 rd, out vec2 t, inout int i, out vec3 pos) {
 
         if (old == idx) { // We're at the last child
             //-- POP --//
-            #ifdef STACKLESS
-
-            vec3 target = pos;
-            size = root_size;
-            pos = root_pos;
+            if (stackless) {
+                vec3 target = pos;
+                size = root_size;
+                pos = root_pos;
 
-            t = isect(ro, rdi, pos, size, tmid, tmax);
-            if (t.y <= h)
-                return 0;
-
-            parent_pointer = 0;
-            float nh = t.y;
-            for (int j = 0; j < 100; j++) { // J is there just in case
-                size *= 0.5;
-                idx = sign(target - pos);
-                if (any(equal(idx, vec3(0.0))))
-                  break;
-                pos += idx * size * 0.5;
                 t = isect(ro, rdi, pos, size, tmid, tmax);
-
-                // We have more nodes to traverse within this one
-                if (t.y > h) {
-                    uidx = u_idx(idx);
-                    node = tree[parent_pointer + uidx];
-                    parent_pointer += node >> 1;
-                    nh = t.y;
-                } else break;
+                if (t.y <= h)
+                    return 0;
+
+                parent_pointer = 0;
+                float nh = t.y;
+                for (int j = 0; j < 100; j++) { // J is there just in case
+                    // Going back down is steps too, or a ray could take far more than `max_ray_steps` of them
+                    if (--i <= 0)
+                        return 0;
+                    size *= 0.5;
+                    idx = sign(target - pos);
+                    if (any(equal(idx, vec3(0.0))))
+                      break;
+                    pos += idx * size * 0.5;
+                    t = isect(ro, rdi, pos, size, tmid, tmax);
+
+                    // We have more nodes to traverse within this one
+                    if (t.y > h) {
+                        uidx = u_idx(idx);
+                        node = tree[parent_pointer + uidx];
+                        parent_pointer += node >> 1;
+                        nh = t.y;
+                    } else break;
+                }
+                h = nh;
+            } else {
+                if (stack_empty()) return 0;
+
+                ST s = stack_pop();
+                h = s.h;
+                idx = v_idx(s.idx);
+                parent_pointer = s.parent_pointer;
+                pos = s.pos;
+                size = s.size;
             }
-            h = nh;
-
-            #else
-            if (stack_empty()) return 0;
-
-            ST s = stack_pop();
-            h = s.h;
-            idx = v_idx(s.idx);
-            parent_pointer = s.parent_pointer;
-            pos = s.pos;
-            size = s.size;
-
-            #endif
 
             c = false;
             continue;
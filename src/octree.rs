@@ -1,4 +1,5 @@
 use crate::common::*;
+use crate::config::LodColor;
 use num_traits::FromPrimitive;
 
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
@@ -22,9 +23,67 @@ impl DerefMut for Chunk {
 pub struct RayCast {
     pub t: [f32; 2],
     pub mat: Material,
-    /// The center of the block we hit, relative to the chunk center.
+    /// The center of the block we hit, relative to the chunk center, or in the same space as the ray for `raymarch()`.
     /// Note that `ro+rd*t` is the hit position.
     pub pos: Vector3<f32>,
+    /// How many steps it took to get there, counted the way the shader counts `max_ray_steps`
+    pub steps: usize,
+}
+
+/// Gets the material out of a leaf node. See `pack_leaf()` in `common.rs` for how leaves are laid out.
+pub fn leaf_material(node: u32) -> Material {
+    Material::from_u32(unpack_leaf(node).mat).unwrap_or(Material::Wrong)
+}
+
+/// One of the six faces of a voxel, in the same order as `neighbors()`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Face {
+    NegX,
+    PosX,
+    NegY,
+    PosY,
+    NegZ,
+    PosZ,
+}
+
+impl Face {
+    pub fn all() -> [Face; 6] {
+        [
+            Face::NegX,
+            Face::PosX,
+            Face::NegY,
+            Face::PosY,
+            Face::NegZ,
+            Face::PosZ,
+        ]
+    }
+
+    /// The axis this face is perpendicular to, and which side of the voxel it's on
+    pub fn axis(self) -> (usize, f32) {
+        match self {
+            Face::NegX => (0, -1.0),
+            Face::PosX => (0, 1.0),
+            Face::NegY => (1, -1.0),
+            Face::PosY => (1, 1.0),
+            Face::NegZ => (2, -1.0),
+            Face::PosZ => (2, 1.0),
+        }
+    }
+
+    pub fn normal(self) -> Vector3<f32> {
+        let (axis, side) = self.axis();
+        let mut n = Vector3::zeros();
+        n[axis] = side;
+        n
+    }
+}
+
+/// One step down the octree: the child we went into, and where it is
+struct Step {
+    parent: usize,
+    idx: Vector3<f32>,
+    pos: Vector3<f32>,
+    size: f32,
 }
 
 /// Returns (t, tmid, tmax)
@@ -46,117 +105,216 @@ pub fn isect(
     ([tmin.max(), tmax.min()], tmid, tmax)
 }
 
-impl Chunk {
-    /// Casts a ray from a position relative to the chunk center
-    #[allow(clippy::float_cmp)]
-    pub fn raycast(&self, ro: Vector3<f32>, rd: Vector3<f32>, max_iters: usize) -> Option<RayCast> {
-        struct ST {
-            parent: usize,
-            pos: Vector3<f32>,
-            idx: Vector3<f32>,
-            size: f32,
-            h: f32,
-        }
+/// Walks a ray through an octree the same way `trace()` in `octree.glsl` does, so tests can check traversal without a GPU.
+/// `node(i)` is the `i`th node of the tree, relative to the root, which is `root_size` wide and centered on `root_pos`;
+/// `ro` is in the same space, and so is the `pos` of what it hits. Nodes past the end of the tree should come back empty.
+///
+/// Both of them follow the same algorithm, and have to stay in step:
+/// - Intersect the root's box, and start in the child the ray enters first, skipping children entirely behind `ro`.
+/// - Each step looks at one child. A non-leaf is entered: push where we are (unless the child ends where its parent does,
+///   since then there's nothing to come back to), and go to the first of its children the ray enters.
+///   Non-empty leaves are hits, and empty ones are skipped.
+/// - Skipping moves to the next child along every axis whose face the ray leaves through first.
+///   If that's out of the parent, pop back to it and skip it too; with an empty stack, the ray left the root.
+/// - It gives up after `max_steps` steps, like the shader does after `max_ray_steps`, and counts that as a miss.
+///
+/// This is the shader without its extras: no `TAN_W` level of detail or `falloff_start`.
+/// `raymarch_stackless()` is its stackless mode.
+pub fn raymarch(
+    node: impl Fn(usize) -> u32,
+    root_pos: Vector3<f32>,
+    root_size: f32,
+    ro: Vector3<f32>,
+    rd: Vector3<f32>,
+    max_steps: usize,
+) -> Option<RayCast> {
+    march(node, root_pos, root_size, ro, rd, max_steps, false)
+}
 
-        let mut stack = Vec::new();
+/// `raymarch()`, but popping back up the tree like the shader does when `stackless` is set:
+/// starting again from the root and going down to the node we'd have popped to.
+/// Going down counts towards `max_steps`, so it takes more steps than `raymarch()`, but it should hit the same voxels.
+pub fn raymarch_stackless(
+    node: impl Fn(usize) -> u32,
+    root_pos: Vector3<f32>,
+    root_size: f32,
+    ro: Vector3<f32>,
+    rd: Vector3<f32>,
+    max_steps: usize,
+) -> Option<RayCast> {
+    march(node, root_pos, root_size, ro, rd, max_steps, true)
+}
 
-        let tstep = rd.map(f32::signum);
-        let rdi = rd.map(|x| 1.0 / x); // Inverse for isect
+#[allow(clippy::float_cmp)]
+fn march(
+    tree: impl Fn(usize) -> u32,
+    root_pos: Vector3<f32>,
+    root_size: f32,
+    ro: Vector3<f32>,
+    rd: Vector3<f32>,
+    max_steps: usize,
+    stackless: bool,
+) -> Option<RayCast> {
+    struct ST {
+        parent: usize,
+        pos: Vector3<f32>,
+        idx: Vector3<f32>,
+        size: f32,
+        h: f32,
+    }
 
-        let mut pos = Vector3::zeros();
+    let mut stack = Vec::new();
 
-        let (t, tmid, tmax) = isect(ro, rdi, pos, CHUNK_SIZE);
-        if t[0] > t[1] || t[1] <= 0.0 {
-            return None;
-        }
-        let mut h = t[1];
-
-        // Which axes we're skipping the first voxel on (hitting it from the side)
-        let q = tmid.map(|x| x <= t[0]);
-        let idx = q.zip_map(&tstep, |b, x| if b { x } else { -x });
-        // tmax of the resulting voxel
-        let tq = q.zip_zip_map(&tmid, &tmax, |b, x, y| if b { y } else { x });
-        // Don't worry about voxels behind `ro`
-        let mut idx = tq.zip_map(&idx, |x, y| if x >= 0.0 { y } else { -y });
-
-        let mut size = CHUNK_SIZE * 0.5;
-        pos += 0.5 * size * idx;
-        let mut parent = 0;
+    let tstep = rd.map(f32::signum);
+    let rdi = rd.map(|x| 1.0 / x); // Inverse for isect
 
-        let mut c = true;
+    let mut pos = root_pos;
 
-        for _ in 0..max_iters {
-            let (t, tmid, tmax) = isect(ro, rdi, pos, size);
+    let (t, tmid, tmax) = isect(ro, rdi, pos, root_size);
+    if t[0] > t[1] || t[1] <= 0.0 {
+        return None;
+    }
+    let mut h = t[1];
 
-            let uidx = pos_to_idx(idx);
+    // Which axes we're skipping the first voxel on (hitting it from the side)
+    let q = tmid.map(|x| x <= t[0]);
+    let idx = q.zip_map(&tstep, |b, x| if b { x } else { -x });
+    // tmax of the resulting voxel
+    let tq = q.zip_zip_map(&tmid, &tmax, |b, x, y| if b { y } else { x });
+    // Don't worry about voxels behind `ro`
+    let mut idx = tq.zip_map(&idx, |x, y| if x >= 0.0 { y } else { -y });
 
-            let node = self[parent + uidx];
+    let mut size = root_size * 0.5;
+    pos += 0.5 * size * idx;
+    let mut parent = 0;
 
-            if (node & 1) > 0 {
-                // Non-leaf
-                if c {
-                    //-- PUSH --//
-                    if t[1] < h {
-                        stack.push(ST {
-                            parent,
-                            pos,
-                            idx,
-                            size,
-                            h,
-                        });
-                    }
-                    h = t[1];
-                    parent += (node >> 1) as usize;
-                    size *= 0.5;
-                    // Which axes we're skipping the first voxel on (hitting it from the side)
-                    let q = tmid.map(|x| x <= t[0]);
-                    idx = q.zip_map(&tstep, |b, x| if b { x } else { -x });
-                    // tmax of the resulting voxel
-                    let tq = q.zip_zip_map(&tmid, &tmax, |b, x, y| if b { y } else { x });
-                    // Don't worry about voxels behind `ro`
-                    idx = tq.zip_map(&idx, |x, y| if x >= 0.0 { y } else { -y });
-                    pos += 0.5 * size * idx;
-                    continue;
+    let mut c = true;
+
+    let mut steps = 0;
+    while steps < max_steps {
+        steps += 1;
+        let (t, tmid, tmax) = isect(ro, rdi, pos, size);
+
+        let uidx = pos_to_idx(idx);
+
+        let node = tree(parent + uidx);
+
+        if (node & 1) > 0 {
+            // Non-leaf
+            if c {
+                //-- PUSH --//
+                if !stackless && t[1] < h {
+                    stack.push(ST {
+                        parent,
+                        pos,
+                        idx,
+                        size,
+                        h,
+                    });
                 }
-            } else if node != 0 {
-                // Nonempty, but leaf
-                return Some(RayCast {
-                    mat: Material::from_u32(node >> 1).unwrap_or(Material::Wrong),
-                    t,
-                    pos,
-                });
+                h = t[1];
+                parent += (node >> 1) as usize;
+                size *= 0.5;
+                // Which axes we're skipping the first voxel on (hitting it from the side)
+                let q = tmid.map(|x| x <= t[0]);
+                idx = q.zip_map(&tstep, |b, x| if b { x } else { -x });
+                // tmax of the resulting voxel
+                let tq = q.zip_zip_map(&tmid, &tmax, |b, x, y| if b { y } else { x });
+                // Don't worry about voxels behind `ro`
+                idx = tq.zip_map(&idx, |x, y| if x >= 0.0 { y } else { -y });
+                pos += 0.5 * size * idx;
+                continue;
             }
+        } else if node != 0 {
+            // Nonempty, but leaf
+            return Some(RayCast {
+                mat: leaf_material(node),
+                t,
+                pos,
+                steps,
+            });
+        }
+
+        //-- ADVANCE --//
 
-            //-- ADVANCE --//
+        // Advance for every direction where we're hitting the side
+        let old = idx;
+        idx = idx.zip_zip_map(&tstep, &tmax, |a, b, s| if s == t[1] { b } else { a });
+        pos += tstep.zip_zip_map(&old, &idx, |x, a, b| if a != b { x * size } else { 0.0 });
+
+        if old == idx {
+            // We're at the last child
+            //-- POP --//
+            if stackless {
+                let target = pos;
+                size = root_size;
+                pos = root_pos;
+
+                let (t, _, _) = isect(ro, rdi, pos, size);
+                if t[1] <= h {
+                    return None;
+                }
 
-            // Advance for every direction where we're hitting the side
-            let old = idx;
-            idx = idx.zip_zip_map(&tstep, &tmax, |a, b, s| if s == t[1] { b } else { a });
-            pos += tstep.zip_zip_map(&old, &idx, |x, a, b| if a != b { x * size } else { 0.0 });
+                parent = 0;
+                let mut nh = t[1];
+                for _ in 0..100 {
+                    steps += 1;
+                    if steps >= max_steps {
+                        warn!("Ran out of steps in raymarch()!");
+                        return None;
+                    }
+                    size *= 0.5;
+                    idx = (target - pos).map(f32::signum);
+                    if idx.iter().any(|&x| x == 0.0) {
+                        break;
+                    }
+                    pos += idx * size * 0.5;
+                    let (t, _, _) = isect(ro, rdi, pos, size);
 
-            if old == idx {
-                // We're at the last child
-                //-- POP --//
+                    // We have more nodes to traverse within this one
+                    if t[1] > h {
+                        parent += (tree(parent + pos_to_idx(idx)) >> 1) as usize;
+                        nh = t[1];
+                    } else {
+                        break;
+                    }
+                }
+                h = nh;
+            } else {
                 let st = stack.pop()?;
                 h = st.h;
                 idx = st.idx;
                 parent = st.parent;
                 pos = st.pos;
                 size = st.size;
-
-                c = false;
-            } else {
-                c = true;
             }
+
+            c = false;
+        } else {
+            c = true;
         }
+    }
 
-        println!("WARNING: ran out of iterations in Chunk::raycast()!");
-        None
+    warn!("Ran out of steps in raymarch()!");
+    None
+}
+
+impl Chunk {
+    /// Casts a ray from a position relative to the chunk center, with `raymarch()`
+    pub fn raycast(&self, ro: Vector3<f32>, rd: Vector3<f32>, max_iters: usize) -> Option<RayCast> {
+        raymarch(
+            |i| self.get(i).copied().unwrap_or(0),
+            Vector3::zeros(),
+            chunk_size(),
+            ro,
+            rd,
+            max_iters,
+        )
     }
 
     /// Get the material at a location relative to the chunk center
     pub fn block(&self, target: Vector3<f32>) -> Material {
-        let mut size = CHUNK_SIZE;
+        let mut size = chunk_size();
         let mut pos = Vector3::zeros();
         let mut parent = 0;
 
@@ -172,17 +330,164 @@ impl Chunk {
             if node & 1 > 0 {
                 parent += (node >> 1) as usize;
             } else {
-                break Material::from_u32(node >> 1).unwrap();
+                break leaf_material(node);
+            }
+        }
+    }
+
+    /// Returns every node we pass through on the way to the leaf containing `target`, relative to the chunk center
+    fn path(&self, target: Vector3<f32>) -> Vec<Step> {
+        let mut size = chunk_size();
+        let mut pos = Vector3::zeros();
+        let mut parent = 0;
+        let mut path = Vec::new();
+
+        loop {
+            size *= 0.5;
+            let idx = (target - pos).map(f32::signum);
+            pos += idx * size * 0.5;
+            path.push(Step {
+                parent,
+                idx,
+                pos,
+                size,
+            });
+
+            let node = self[parent + pos_to_idx(idx)];
+            if node & 1 > 0 {
+                parent += (node >> 1) as usize;
+            } else {
+                break path;
+            }
+        }
+    }
+
+    /// Descends from the child of `parent` at `idx` to a leaf, always picking children on the `side` of `axis` and otherwise heading towards `target`.
+    /// Returns the leaf's (center, size, material).
+    #[allow(clippy::too_many_arguments)]
+    fn descend_face(
+        &self,
+        mut parent: usize,
+        mut idx: Vector3<f32>,
+        mut pos: Vector3<f32>,
+        mut size: f32,
+        target: Vector3<f32>,
+        axis: usize,
+        side: f32,
+    ) -> (Vector3<f32>, f32, Material) {
+        loop {
+            let node = self[parent + pos_to_idx(idx)];
+            if node & 1 == 0 {
+                break (pos, size, leaf_material(node));
+            }
+            parent += (node >> 1) as usize;
+            size *= 0.5;
+            idx = (target - pos).map(f32::signum);
+            idx[axis] = side;
+            pos += idx * size * 0.5;
+        }
+    }
+
+    /// Finds the leaf on the other side of `face` of the leaf containing `target`, relative to the chunk center.
+    /// Returns (center, size, material), or None if the neighbor is in another chunk.
+    /// If the neighbor is bigger than our leaf, it returns the whole neighbor; if it's smaller, the one touching `target`.
+    pub fn neighbor(
+        &self,
+        target: Vector3<f32>,
+        face: Face,
+    ) -> Option<(Vector3<f32>, f32, Material)> {
+        let (axis, side) = face.axis();
+        let path = self.path(target);
+
+        // Go up until we find an ancestor where we can step across `face` without leaving its parent
+        let step = path.iter().rev().find(|s| s.idx[axis] != side)?;
+
+        let mut idx = step.idx;
+        idx[axis] = side;
+        let mut pos = step.pos;
+        pos[axis] += side * step.size;
+
+        // Then go back down the mirrored path, which always stays against the face we crossed
+        Some(self.descend_face(step.parent, idx, pos, step.size, target, axis, -side))
+    }
+
+    /// Finds the leaf on the `side` of `axis` of this chunk that touches `target` when projected onto that face.
+    /// This is the other half of `neighbor()` for when the neighbor is in another chunk.
+    pub fn face_leaf(
+        &self,
+        target: Vector3<f32>,
+        axis: usize,
+        side: f32,
+    ) -> (Vector3<f32>, f32, Material) {
+        let size = chunk_size() * 0.5;
+        let mut idx = target.map(f32::signum);
+        idx[axis] = side;
+        let pos = idx * size * 0.5;
+        self.descend_face(0, idx, pos, size, target, axis, side)
+    }
+
+    /// Stores baked AO (two bits per face) in the nonempty leaf containing `target`, relative to the chunk center.
+    /// Returns the index of the node it changed, if there was a leaf there.
+    pub fn set_ao(&mut self, target: Vector3<f32>, ao: u32) -> Option<usize> {
+        self.set_leaf(target, |leaf| Leaf {
+            ao: Some(ao),
+            ..leaf
+        })
+    }
+
+    /// Stores baked shadows (a bit per axis) in the nonempty leaf containing `target`, like `set_ao()`
+    pub fn set_shadow(&mut self, target: Vector3<f32>, shadow: u32) -> Option<usize> {
+        self.set_leaf(target, |leaf| Leaf { shadow, ..leaf })
+    }
+
+    /// Changes the fields of the nonempty leaf containing `target` with `f`, and returns its index
+    fn set_leaf(&mut self, target: Vector3<f32>, f: impl FnOnce(Leaf) -> Leaf) -> Option<usize> {
+        let ptr = self.leaf_index(target)?;
+        self[ptr] = pack_leaf(f(unpack_leaf(self[ptr])));
+        Some(ptr)
+    }
+
+    /// The index of the nonempty leaf containing `target`, relative to the chunk center
+    pub fn leaf_index(&self, target: Vector3<f32>) -> Option<usize> {
+        let mut size = chunk_size();
+        let mut pos = Vector3::zeros();
+        let mut parent = 0;
+
+        loop {
+            size *= 0.5;
+            let idx = (target - pos).map(f32::signum);
+            pos += idx * size * 0.5;
+
+            let ptr = parent + pos_to_idx(idx);
+            let node = self[ptr];
+
+            if node & 1 > 0 {
+                parent += (node >> 1) as usize;
+            } else if node != 0 {
+                return Some(ptr);
+            } else {
+                return None;
             }
         }
     }
 
     /// Set the material at a location relative to the chunk center
-    /// Setting a block to air does work, but uses more space
-    pub fn set_block(&mut self, target: Vector3<f32>, level: u32, new: Material) {
-        let mut size = CHUNK_SIZE;
+    /// Setting a block to air leaves any node that's all air afterwards as an empty leaf, so rays cross it in one step
+    /// instead of going into it; the nodes that were under it stay where they were, with nothing pointing to them.
+    /// Returns the ranges of nodes that changed, including any added at the end, which are all that need uploading again.
+    pub fn set_block(
+        &mut self,
+        target: Vector3<f32>,
+        level: u32,
+        new: Material,
+    ) -> Vec<std::ops::Range<usize>> {
+        let mut size = chunk_size();
         let mut pos = Vector3::zeros();
         let mut parent = 0;
+        let old_len = self.len();
+        let mut changed = Vec::new();
+        // The node we went into at each level, and the group of eight it's in
+        let mut path = Vec::new();
 
         // Find the spot to put this block, creating a new subtree if necessary
         for i in 0..level {
@@ -195,28 +500,158 @@ impl Chunk {
 
             if i == level - 1 {
                 // Actually put the new material there
-                self[ptr] = (new as u32) << 1;
+                self[ptr] = pack_leaf(Leaf::new(new));
+                changed.push(ptr..ptr + 1);
                 break;
             }
 
             let node = self[ptr];
+            path.push((parent, ptr));
 
             // We have more nodes to traverse within this one
             if node & 1 > 0 {
                 parent += (node >> 1) as usize;
+            } else if node == 0 && new == Material::Air {
+                // It's already empty
+                return changed;
             } else {
                 // Create a new node
                 self[ptr] = ((self.len() - parent) as u32) << 1 | 1;
+                changed.push(ptr..ptr + 1);
                 parent = self.len();
+                // The baked AO and shadows were for the whole leaf, so the smaller ones need baking again
+                let node = pack_leaf(Leaf {
+                    ao: None,
+                    shadow: 0,
+                    ..unpack_leaf(node)
+                });
                 self.extend((0..8).map(|_| node));
             }
         }
+        if new == Material::Air {
+            // Going back up, each group of eight that's all empty now makes the node pointing to it an empty leaf
+            while let Some((up, ptr)) = path.pop() {
+                if self[parent..parent + 8].iter().any(|&x| x != 0) {
+                    break;
+                }
+                self[ptr] = 0;
+                changed.push(ptr..ptr + 1);
+                parent = up;
+            }
+        }
+        if self.len() > old_len {
+            changed.push(old_len..self.len());
+        }
+        changed
+    }
+
+    /// Calls `f(center, size, material)` for every nonempty leaf that overlaps the box from `min` to `max`.
+    /// Both are relative to the chunk center, and we only descend into nodes that intersect the box.
+    pub fn visit_box<F: FnMut(Vector3<f32>, f32, Material)>(
+        &self,
+        min: Vector3<f32>,
+        max: Vector3<f32>,
+        f: &mut F,
+    ) {
+        self.visit_node(0, Vector3::zeros(), chunk_size(), min, max, f);
+    }
+
+    fn visit_node<F: FnMut(Vector3<f32>, f32, Material)>(
+        &self,
+        parent: usize,
+        pos: Vector3<f32>,
+        size: f32,
+        min: Vector3<f32>,
+        max: Vector3<f32>,
+        f: &mut F,
+    ) {
+        let size = size * 0.5; // Child size
+        for uidx in 0..8 {
+            let pos = pos + idx_to_pos(uidx) * size * 0.5;
+            let overlaps =
+                (0..3).all(|i| pos[i] - size * 0.5 < max[i] && pos[i] + size * 0.5 > min[i]);
+            if !overlaps {
+                continue;
+            }
+
+            let node = self[parent + uidx];
+            if node & 1 > 0 {
+                self.visit_node(parent + (node >> 1) as usize, pos, size, min, max, f);
+            } else if node != 0 {
+                f(pos, size, leaf_material(node));
+            }
+        }
     }
 
     pub fn empty() -> Self {
         Chunk(vec![0; 8])
     }
 
+    /// Renumbers the materials in every leaf, from a save's numbering to the current one - see `Material::remap()`
+    pub fn remap_materials(&mut self, map: &[Material]) {
+        for node in self.iter_mut().filter(|x| **x & 1 == 0) {
+            let mut leaf = unpack_leaf(*node);
+            let new = map
+                .get(leaf.mat as usize)
+                .copied()
+                .unwrap_or(Material::Wrong);
+            leaf.mat = new as u32;
+            *node = pack_leaf(leaf);
+        }
+    }
+
+    /// Builds a chunk from the material of each voxel, where `voxel` gets coordinates from 0 to `chunk_size()` on each axis.
+    /// Any node whose children are all the same material becomes a leaf. It goes down to `chunk_depth()`.
+    pub fn from_voxels(voxel: impl Fn(Vector3<usize>) -> Material) -> Self {
+        Chunk::from_voxels_to_depth(chunk_depth(), voxel)
+    }
+
+    /// Like `from_voxels()`, but only `depth` levels deep. If that's not deep enough for single voxels,
+    /// each of the smallest leaves is whichever material most of the voxels in it are.
+    pub fn from_voxels_to_depth(depth: u32, voxel: impl Fn(Vector3<usize>) -> Material) -> Self {
+        let size = chunk_size() as usize;
+        let leaf = size >> depth.max(1).min(size.trailing_zeros());
+        let mut tree = vec![0; 8];
+        Chunk::build_voxels(&mut tree, 0, Vector3::zeros(), size, leaf, &voxel);
+        Chunk(tree)
+    }
+
+    /// Fills in the eight slots at `parent` for the node with minimum corner `min`, with leaves `leaf` wide at the bottom
+    fn build_voxels(
+        tree: &mut Vec<u32>,
+        parent: usize,
+        min: Vector3<usize>,
+        size: usize,
+        leaf: usize,
+        voxel: &impl Fn(Vector3<usize>) -> Material,
+    ) {
+        let half = size / 2;
+        for j in 0..8 {
+            let min = min + idx_to_pos(j).map(|x| if x > 0.0 { half } else { 0 });
+            tree[parent + j] = if half == leaf {
+                let mat = if leaf == 1 {
+                    voxel(min)
+                } else {
+                    let cell = (0..leaf * leaf * leaf)
+                        .map(|i| min + Vector3::new(i % leaf, i / leaf % leaf, i / leaf / leaf));
+                    majority(cell.map(voxel), 0)
+                };
+                pack_leaf(Leaf::new(mat))
+            } else {
+                let ptr = tree.len();
+                tree.extend_from_slice(&[0; 8]);
+                Chunk::build_voxels(tree, ptr, min, half, leaf, voxel);
+                let first = tree[ptr];
+                if tree.len() == ptr + 8 && tree[ptr..].iter().all(|&x| x & 1 == 0 && x == first) {
+                    tree.truncate(ptr);
+                    first
+                } else {
+                    (((ptr - parent) as u32) << 1) | 1
+                }
+            };
+        }
+    }
+
     pub fn from_dist(mut dist: impl FnMut(Vector3<f32>) -> (f32, Material)) -> Self {
         struct ST {
             parent: usize,
@@ -225,7 +660,7 @@ impl Chunk {
             scale: i32,
         }
 
-        let levels = CHUNK_SIZE.log2() as i32 - 1;
+        let levels = chunk_depth() as i32 - 1;
         let mut stack: Vec<ST> = vec![];
         let d_corner = 0.75_f32.sqrt();
 
@@ -233,7 +668,7 @@ impl Chunk {
         for i in 0.. {
             let (pos, root, idx, parent, scale) = if i == 0 {
                 (
-                    Vector3::repeat(CHUNK_SIZE * 0.5),
+                    Vector3::repeat(chunk_size() * 0.5),
                     true,
                     Vector3::zeros(),
                     0,
@@ -246,8 +681,10 @@ impl Chunk {
                 break;
             };
 
+            let ptr = tree.len();
+            let pushed = stack.len();
             let mut v = vec![0; 8];
-            let size = 2.0_f32.powf(-scale as f32) * CHUNK_SIZE * 0.5; // Next level's size
+            let size = 2.0_f32.powf(-scale as f32) * chunk_size() * 0.5; // Next level's size
             for j in 0..8 {
                 let jdx = idx_to_pos(j);
                 let np = pos + jdx * size * 0.5;
@@ -257,17 +694,17 @@ impl Chunk {
                     if d > size * d_corner {
                         v[j] = 0;
                     } else {
-                        v[j] = (mat as u32) << 1;
+                        v[j] = pack_leaf(Leaf::new(mat));
                     }
                 } else if d > size * d_corner {
                     //v.leaf[j] = true;
                     v[j] = 0;
                 } else if d < -size * d_corner {
                     //v.leaf[j] = true;
-                    v[j] = (mat as u32) << 1;
+                    v[j] = pack_leaf(Leaf::new(mat));
                 } else {
                     stack.push(ST {
-                        parent: i * 8,
+                        parent: ptr,
                         idx: jdx,
                         pos: np,
                         scale: scale + 1,
@@ -275,8 +712,12 @@ impl Chunk {
                 }
             }
             if !root {
+                // Nothing in it turned out to be near the surface, so it stays an empty leaf and rays skip it in one step
+                if stack.len() == pushed && v.iter().all(|&x| x == 0) {
+                    continue;
+                }
                 let uidx = pos_to_idx(idx);
-                tree[parent + uidx] = (((i * 8 - parent) as u32) << 1) | 1;
+                tree[parent + uidx] = (((ptr - parent) as u32) << 1) | 1;
             }
             tree.append(&mut v);
         }
@@ -284,6 +725,155 @@ impl Chunk {
     }
 }
 
+/// The material there's the most of in `mats`, counting `air` more voxels of air that aren't in it.
+/// Ties go to whichever came first, with the extra air last.
+pub fn majority(mats: impl IntoIterator<Item = Material>, air: usize) -> Material {
+    let mut counts: Vec<(Material, usize)> = Vec::new();
+    for mat in mats
+        .into_iter()
+        .chain(std::iter::repeat(Material::Air).take(air))
+    {
+        match counts.iter_mut().find(|(m, _)| *m == mat) {
+            Some((_, n)) => *n += 1,
+            None => counts.push((mat, 1)),
+        }
+    }
+    let mut best = (Material::Air, 0);
+    for &(mat, n) in &counts {
+        if n > best.1 {
+            best = (mat, n);
+        }
+    }
+    best.0
+}
+
+/// Packs a color from 0 to 1 on each channel for the LOD buffer. Each channel is stored as its square root,
+/// so dark colors keep more of their precision, and the top byte is 255, since 0 means there's no color.
+pub fn pack_lod_color(rgb: [f32; 3]) -> u32 {
+    let byte = |x: f32| (x.max(0.0).min(1.0).sqrt() * 255.0).round() as u32;
+    byte(rgb[0]) | byte(rgb[1]) << 8 | byte(rgb[2]) << 16 | 255 << 24
+}
+
+/// The color of each non-leaf node in `tree` for when the LOD draws it as one voxel, picked the way `mode` says.
+/// There's one for each group of 8 nodes in the tree, and it's the color of the node that points to that group,
+/// so the first one is for the whole chunk. Groups nothing points to, like slack, are 0, and so is everything for `LodColor::First`,
+/// which the shader does without them. `colors` has the color and opacity of each material, by its number.
+/// Any edit can change the color of every node above it, so these are made again for the whole chunk after one.
+pub fn lod_colors(tree: &[u32], mode: LodColor, colors: &[[f32; 4]]) -> Vec<u32> {
+    let mut out = vec![0; tree.len() / 8];
+    if mode != LodColor::First && tree.len() >= 8 {
+        lod_sum(tree, 0, 1.0, mode, colors, &mut out);
+    }
+    out
+}
+
+/// Everything under a node, for `lod_colors()`
+struct LodSum {
+    /// The colors of what's in it, each weighted by its volume, and for `LodColor::AlphaWeighted` its opacity too
+    color: [f64; 3],
+    /// What all the weights add up to
+    weight: f64,
+    /// How much of the node each material takes up, by its number
+    volumes: Vec<f64>,
+}
+
+/// Adds up the group of nodes starting at `block`, which all together take up `volume` of the chunk,
+/// and writes the color of the node that points to it, and every one under it, to `out`
+fn lod_sum(
+    tree: &[u32],
+    block: usize,
+    volume: f64,
+    mode: LodColor,
+    colors: &[[f32; 4]],
+    out: &mut [u32],
+) -> LodSum {
+    let mut sum = LodSum {
+        color: [0.0; 3],
+        weight: 0.0,
+        volumes: vec![0.0; colors.len()],
+    };
+    let volume = volume / 8.0;
+    for &node in &tree[block..block + 8] {
+        if node & 1 > 0 {
+            let child = lod_sum(
+                tree,
+                block + (node >> 1) as usize,
+                volume,
+                mode,
+                colors,
+                out,
+            );
+            for i in 0..3 {
+                sum.color[i] += child.color[i];
+            }
+            sum.weight += child.weight;
+            for (a, b) in sum.volumes.iter_mut().zip(child.volumes) {
+                *a += b;
+            }
+            continue;
+        }
+        let mat = unpack_leaf(node).mat as usize;
+        let c = match colors.get(mat) {
+            Some(c) if mat != Material::Air as usize => c,
+            _ => continue,
+        };
+        let weight = match mode {
+            LodColor::AlphaWeighted => volume * c[3] as f64,
+            _ => volume,
+        };
+        for i in 0..3 {
+            sum.color[i] += c[i] as f64 * weight;
+        }
+        sum.weight += weight;
+        sum.volumes[mat] += volume;
+    }
+
+    let color = match mode {
+        // Ties go to the lower material number
+        LodColor::Dominant => sum
+            .volumes
+            .iter()
+            .enumerate()
+            .filter(|&(_, &v)| v > 0.0)
+            .fold(None, |best: Option<(usize, f64)>, (i, &v)| match best {
+                Some((_, b)) if b >= v => best,
+                _ => Some((i, v)),
+            })
+            .map(|(i, _)| [colors[i][0], colors[i][1], colors[i][2]]),
+        _ if sum.weight > 0.0 => Some(sum.color.map(|x| (x / sum.weight) as f32)),
+        _ => None,
+    };
+    out[block / 8] = color.map_or(0, pack_lod_color);
+    sum
+}
+
+/// Downsamples voxels in world space, like ones being imported, to cells `leaf` voxels wide lined up with the chunks,
+/// so they fit in octrees that only go down to `leaf_size()`. Each cell is whichever material most of it is,
+/// counting the voxels that aren't in `voxels` as air, and cells that are mostly air are left out.
+/// Returns the center of each cell and its material, sorted by position.
+pub fn downsample_voxels(
+    voxels: impl IntoIterator<Item = (Vector3<f32>, Material)>,
+    leaf: f32,
+) -> Vec<(Vector3<f32>, Material)> {
+    let mut cells: HashMap<[i32; 3], Vec<Material>> = HashMap::new();
+    for (pos, mat) in voxels {
+        let cell = pos.map(|x| (x / leaf).floor() as i32);
+        cells.entry(cell.into()).or_default().push(mat);
+    }
+    let volume = (leaf * leaf * leaf) as usize;
+    let mut out: Vec<_> = cells
+        .into_iter()
+        .filter_map(|(cell, mats)| {
+            let air = volume.saturating_sub(mats.len());
+            let mat = majority(mats, air);
+            let center = Vector3::from(cell).map(|x| (x as f32 + 0.5) * leaf);
+            Some((center, mat)).filter(|_| mat != Material::Air)
+        })
+        .collect();
+    out.sort_by(|(a, _), (b, _)| (a.x, a.y, a.z).partial_cmp(&(b.x, b.y, b.z)).unwrap());
+    out
+}
+
 /// Converts between a 3D vector representing the child slot, and the actual index into the `pointer` array
 pub fn pos_to_idx<T: na::Scalar + Zero + PartialOrd>(idx: Vector3<T>) -> usize {
     // Once again, this function closely mirrors the GLSL one for testing
@@ -316,3 +906,379 @@ pub fn neighbors(idx: Vector3<i32>) -> Vec<Vector3<i32>> {
     .map(|x| idx + x)
     .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaf(mat: Material) -> u32 {
+        pack_leaf(Leaf::new(mat))
+    }
+
+    /// A root 4 wide at the origin, with stone filling its +x -y -z child,
+    /// and grass in the +x +y +z corner of its -x -y -z child, so right in the middle of the root
+    fn tiny_tree() -> Vec<u32> {
+        let mut tree = vec![0; 16];
+        tree[pos_to_idx(Vector3::new(1.0, -1.0, -1.0))] = leaf(Material::Stone);
+        tree[pos_to_idx(Vector3::new(-1.0, -1.0, -1.0))] = (8 << 1) | 1;
+        tree[8 + pos_to_idx(Vector3::new(1.0, 1.0, 1.0))] = leaf(Material::Grass);
+        tree
+    }
+
+    fn march(ro: Vector3<f32>, rd: Vector3<f32>, max_steps: usize) -> Option<RayCast> {
+        let tree = tiny_tree();
+        raymarch(
+            |i| tree.get(i).copied().unwrap_or(0),
+            Vector3::zeros(),
+            4.0,
+            ro,
+            rd.normalize(),
+            max_steps,
+        )
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-4
+    }
+
+    #[test]
+    fn axis_aligned_rays() {
+        // Along the bottom, through the air in the -x child and into the stone
+        let hit = march(Vector3::new(-5.0, -1.5, -1.7), Vector3::x(), 64).unwrap();
+        assert_eq!(hit.mat, Material::Stone);
+        assert_eq!(hit.pos, Vector3::new(1.0, -1.0, -1.0));
+        assert!(close(hit.t[0], 5.0), "{:?}", hit);
+
+        // Coming the other way, it hits the other side of the stone
+        let hit = march(Vector3::new(5.0, -0.3, -0.4), -Vector3::x(), 64).unwrap();
+        assert_eq!(hit.mat, Material::Stone);
+        assert!(close(hit.t[0], 3.0), "{:?}", hit);
+
+        // Up into the grass, inside the child that has children
+        let hit = march(Vector3::new(-0.4, -5.0, -0.6), Vector3::y(), 64).unwrap();
+        assert_eq!(hit.mat, Material::Grass);
+        assert_eq!(hit.pos, Vector3::repeat(-0.5));
+        assert!(close(hit.t[0], 4.0), "{:?}", hit);
+
+        // Through nothing but air, and away from the tree entirely
+        assert!(march(Vector3::new(1.5, 1.5, -5.0), Vector3::z(), 64).is_none());
+        assert!(march(Vector3::new(-5.0, -1.5, -1.7), -Vector3::x(), 64).is_none());
+    }
+
+    #[test]
+    fn diagonal_rays() {
+        let rd = Vector3::repeat(1.0).normalize();
+        // In through the bottom corner, past the empty nodes next to the grass
+        let ro = Vector3::new(-3.0, -3.1, -2.9);
+        let hit = march(ro, rd, 64).unwrap();
+        assert_eq!(hit.mat, Material::Grass);
+        assert_eq!(hit.pos, Vector3::repeat(-0.5));
+        // It went in through the grass's -y face
+        assert!(close((ro + rd * hit.t[0]).y, -1.0), "{:?}", hit);
+
+        // From the top corner, past the stone without touching it
+        let ro = Vector3::new(3.0, 2.9, 3.2);
+        let hit = march(ro, -rd, 64).unwrap();
+        assert_eq!(hit.mat, Material::Grass);
+        assert!(close((ro - rd * hit.t[0]).z, 0.0), "{:?}", hit);
+    }
+
+    #[test]
+    fn step_limit() {
+        let (ro, rd) = (Vector3::new(-3.0, -3.1, -2.9), Vector3::repeat(1.0));
+        let hit = march(ro, rd, 64).unwrap();
+        // It takes the same steps every time, and one fewer isn't enough
+        assert_eq!(march(ro, rd, hit.steps).unwrap().steps, hit.steps);
+        assert!(march(ro, rd, hit.steps - 1).is_none());
+        assert!(hit.steps > 2);
+    }
+
+    #[test]
+    fn limited_depth() {
+        // A detailed model, like an imported .vox: a solid floor four voxels thick, with thin posts sticking up
+        let model = crate::vox::VoxModel {
+            size: [16, 16, 16],
+            voxels: (0..16 * 16 * 16)
+                .map(|i| [i % 16, i / 16 % 16, i / 256])
+                .filter(|p| p[1] < 4 || (p[0] % 4 == 0 && p[2] % 2 == 0))
+                .map(|p| ([p[0] as u8, p[1] as u8, p[2] as u8], 1))
+                .collect(),
+        };
+        let solid: std::collections::HashSet<[usize; 3]> = model
+            .voxels
+            .iter()
+            .map(|(p, _)| [p[0] as usize, p[1] as usize, p[2] as usize])
+            .collect();
+        let voxel = |p: Vector3<usize>| {
+            if solid.contains(&[p.x, p.y, p.z]) {
+                Material::Stone
+            } else {
+                Material::Air
+            }
+        };
+        let full = Chunk::from_voxels_to_depth(MAX_OCTREE_DEPTH, voxel);
+        let coarse = Chunk::from_voxels_to_depth(2, voxel);
+        assert!(
+            coarse.len() * 10 < full.len(),
+            "{} {}",
+            coarse.len(),
+            full.len()
+        );
+        // The posts are mostly air at four voxels wide, so they're gone, but the floor's still there
+        let mut leaves = 0;
+        coarse.visit_box(
+            Vector3::repeat(-8.0),
+            Vector3::repeat(8.0),
+            &mut |pos, size, mat| {
+                assert!(size >= 4.0);
+                assert_eq!(mat, Material::Stone);
+                assert!(pos.y < -4.0, "{:?}", pos);
+                leaves += 1;
+            },
+        );
+        assert!(leaves > 0);
+        assert_eq!(full.block(Vector3::new(-7.5, 0.5, -7.5)), Material::Stone);
+        assert_eq!(coarse.block(Vector3::new(-7.5, 0.5, -7.5)), Material::Air);
+    }
+
+    /// Whether any group of eight nodes that something points to is all empty
+    fn has_empty_groups(tree: &[u32], group: usize) -> bool {
+        tree[group..group + 8].iter().all(|&x| x == 0)
+            || tree[group..group + 8]
+                .iter()
+                .any(|&x| x & 1 > 0 && has_empty_groups(tree, group + (x >> 1) as usize))
+    }
+
+    #[test]
+    fn skips_empty_nodes() {
+        let size = chunk_size();
+        let fresh = Chunk::from_voxels(|p| {
+            if p.y == 0 {
+                Material::Stone
+            } else {
+                Material::Air
+            }
+        });
+        // A block up in the +x +y +z octant, which is then dug out again
+        let mut chunk = fresh.clone();
+        let block = Vector3::new(0.5, size * 0.5 - 1.5, 0.5);
+        chunk.set_block(block, chunk_depth(), Material::Stone);
+        assert_ne!(chunk[pos_to_idx(Vector3::repeat(1.0))] & 1, 0);
+        chunk.set_block(block, chunk_depth(), Material::Air);
+        // So the octant's all air again, and it's an empty leaf like it was before
+        assert_eq!(chunk[pos_to_idx(Vector3::repeat(1.0))], 0);
+        assert!(!has_empty_groups(&chunk, 0));
+        // Digging out air doesn't do anything
+        assert!(chunk
+            .set_block(block + Vector3::y(), chunk_depth(), Material::Air)
+            .is_empty());
+
+        // Rays down through where it was hit the floor in as many steps as in a chunk that never had it
+        for &(ro, rd) in &[
+            (
+                Vector3::new(1.0, size * 0.5 - 0.5, 1.0),
+                Vector3::new(0.1, -1.0, 0.2),
+            ),
+            (
+                Vector3::new(size * 0.4, size * 0.4, 0.3),
+                Vector3::new(-0.5, -1.0, 0.05),
+            ),
+        ] {
+            let rd = rd.normalize();
+            let a = chunk.raycast(ro, rd, 256).unwrap();
+            let b = fresh.raycast(ro, rd, 256).unwrap();
+            assert_eq!((a.mat, a.pos, a.steps), (b.mat, b.pos, b.steps));
+            assert_eq!(a.mat, Material::Stone);
+        }
+
+        // Chunks made from distances don't have any either
+        let plane = Chunk::from_dist(|p| (p.y - size * 0.3, Material::Dirt));
+        assert!(!has_empty_groups(&plane, 0));
+    }
+
+    #[test]
+    fn stackless_hits_the_same() {
+        let size = chunk_size();
+        // Rolling hills, with a few floating blocks to go past and under
+        let chunk = Chunk::from_voxels(|p| {
+            let (x, y, z) = (p.x as f32, p.y as f32, p.z as f32);
+            if y < 4.0 + 3.0 * (x * 0.4).sin() * (z * 0.3).cos() {
+                Material::Dirt
+            } else if p.y == 12 && (p.x + p.z) % 5 == 0 {
+                Material::Stone
+            } else {
+                Material::Air
+            }
+        });
+        let (mut hits, mut longer) = (0, 0);
+        // The ray where going back down took the most extra steps, and how many it took with a stack
+        let mut longest = None;
+        let mut most = 0;
+        for i in 0..400 {
+            // Directions spread around the sphere, from inside the chunk and outside it
+            let (a, b) = (i as f32 * 2.4, (i as f32 / 400.0) * 2.0 - 1.0);
+            let rd = Vector3::new(
+                a.cos() * (1.0 - b * b).sqrt(),
+                b,
+                a.sin() * (1.0 - b * b).sqrt(),
+            );
+            let ro = if i % 2 == 0 {
+                -rd * size
+            } else {
+                Vector3::new(1.3, size * 0.3, -2.7)
+            };
+            let node = |i| chunk.get(i).copied().unwrap_or(0);
+            let stack = raymarch(node, Vector3::zeros(), size, ro, rd, 1024);
+            let stackless = raymarch_stackless(node, Vector3::zeros(), size, ro, rd, 1024);
+            match (stack, stackless) {
+                (Some(a), Some(b)) => {
+                    assert_eq!(
+                        (a.mat, a.pos, a.t),
+                        (b.mat, b.pos, b.t),
+                        "{:?} {:?}",
+                        ro,
+                        rd
+                    );
+                    assert!(b.steps >= a.steps);
+                    hits += 1;
+                    if b.steps > a.steps {
+                        longer += 1;
+                    }
+                    if b.steps - a.steps > most {
+                        most = b.steps - a.steps;
+                        longest = Some((ro, rd, a.steps));
+                    }
+                }
+                (None, None) => (),
+                (a, b) => panic!("{:?} {:?}: {:?} vs {:?}", ro, rd, a, b),
+            }
+        }
+        // Enough of them hit something, and went back up the tree on the way
+        assert!(hits > 100, "{}", hits);
+        assert!(longer > 50, "{}", longer);
+
+        // Going back down is charged to the budget, so a stackless ray runs out first
+        let (ro, rd, steps) = longest.unwrap();
+        let node = |i| chunk.get(i).copied().unwrap_or(0);
+        assert!(raymarch(node, Vector3::zeros(), size, ro, rd, steps).is_some());
+        assert!(raymarch_stackless(node, Vector3::zeros(), size, ro, rd, steps).is_none());
+    }
+
+    #[test]
+    fn splitting_drops_baked_bits() {
+        let mut chunk = Chunk::from_voxels(|_| Material::Stone);
+        let (near, far) = (Vector3::repeat(-4.0), Vector3::repeat(4.0));
+        for &p in &[near, far] {
+            chunk.set_ao(p, 0b01_10_11);
+            chunk.set_shadow(p, 0b101);
+        }
+        chunk.set_block(Vector3::repeat(-0.5), chunk_depth(), Material::Dirt);
+        let leaf = |p| unpack_leaf(chunk[chunk.leaf_index(p).unwrap()]);
+        // The rest of the split leaf is still stone, but not baked
+        assert_eq!(leaf(Vector3::repeat(-7.5)), Leaf::new(Material::Stone));
+        // The other side wasn't touched
+        assert_eq!(leaf(far).ao, Some(0b01_10_11));
+        assert_eq!(leaf(far).shadow, 0b101);
+    }
+
+    #[test]
+    fn downsamples_voxels() {
+        assert_eq!(
+            majority(vec![Material::Dirt, Material::Stone, Material::Stone], 1),
+            Material::Stone
+        );
+        // Ties go to the first one, and the extra air comes last
+        assert_eq!(
+            majority(vec![Material::Dirt, Material::Stone], 0),
+            Material::Dirt
+        );
+        assert_eq!(
+            majority(vec![Material::Dirt, Material::Dirt], 2),
+            Material::Dirt
+        );
+        assert_eq!(
+            majority(vec![Material::Dirt, Material::Stone], 2),
+            Material::Air
+        );
+        assert_eq!(majority(vec![Material::Dirt], 3), Material::Air);
+        assert_eq!(majority(vec![], 0), Material::Air);
+
+        // A cube that fills a cell, with a stray voxel in the next one
+        let mut voxels: Vec<_> = (0..8)
+            .map(|i| (idx_to_pos(i).map(|x| x.max(0.0) + 0.5), Material::Sand))
+            .collect();
+        voxels.push((Vector3::new(-0.5, 0.5, 0.5), Material::Stone));
+        assert_eq!(
+            downsample_voxels(voxels, 2.0),
+            vec![(Vector3::repeat(1.0), Material::Sand)]
+        );
+    }
+
+    #[test]
+    fn lod_colors_follow_edits() {
+        // Red opaque stone, and blue half-transparent water
+        let mut colors = vec![[0.0; 4]; 5];
+        colors[Material::Stone as usize] = [1.0, 0.0, 0.0, 1.0];
+        colors[Material::Grass as usize] = [0.0, 1.0, 0.0, 1.0];
+        colors[Material::Water as usize] = [0.0, 0.0, 1.0, 0.5];
+        let unpack = |c: u32| {
+            assert_eq!(c >> 24, 255);
+            let x = |i: u32| ((c >> (i * 8)) & 255) as f32 / 255.0;
+            Vector3::new(x(0), x(1), x(2)).map(|x| x * x)
+        };
+        let near = |c: u32, rgb: [f32; 3]| (unpack(c) - Vector3::from(rgb)).norm() < 0.02;
+
+        // The bottom half is stone for three quarters of the way along x, and water the rest, so 3/8 stone and 1/8 water
+        let size = chunk_size() as usize;
+        let mut chunk = Chunk::from_voxels(|p| match (p.y < size / 2, p.x < size * 3 / 4) {
+            (false, _) => Material::Air,
+            (true, true) => Material::Stone,
+            (true, false) => Material::Water,
+        });
+        assert!(lod_colors(&chunk, LodColor::First, &colors)
+            .iter()
+            .all(|&c| c == 0));
+        assert_eq!(
+            lod_colors(&chunk, LodColor::Mean, &colors).len(),
+            chunk.len() / 8
+        );
+        assert!(near(
+            lod_colors(&chunk, LodColor::Mean, &colors)[0],
+            [0.75, 0.0, 0.25]
+        ));
+        // Water's half as opaque, so it counts half as much
+        assert!(near(
+            lod_colors(&chunk, LodColor::AlphaWeighted, &colors)[0],
+            [6.0 / 7.0, 0.0, 1.0 / 7.0]
+        ));
+        assert!(near(
+            lod_colors(&chunk, LodColor::Dominant, &colors)[0],
+            [1.0, 0.0, 0.0]
+        ));
+
+        // The -x -y -z octant was all stone, and now it's water, so they're even; ties go to stone
+        chunk.set_block(Vector3::repeat(-1.0), 1, Material::Water);
+        assert!(near(
+            lod_colors(&chunk, LodColor::Mean, &colors)[0],
+            [0.5, 0.0, 0.5]
+        ));
+        assert!(near(
+            lod_colors(&chunk, LodColor::Dominant, &colors)[0],
+            [1.0, 0.0, 0.0]
+        ));
+
+        // One voxel of grass up in the air, deep in new nodes, reaches all the way up
+        let level = chunk_size().log2() as u32;
+        let before = lod_colors(&chunk, LodColor::Mean, &colors);
+        chunk.set_block(
+            Vector3::new(0.5, size as f32 * 0.5 - 0.5, 0.5),
+            level,
+            Material::Grass,
+        );
+        let after = lod_colors(&chunk, LodColor::Mean, &colors);
+        assert!(after.len() > before.len());
+        assert!(unpack(after[0]).y > 0.0 && unpack(before[0]).y == 0.0);
+        // The new nodes right around it are just grass
+        assert!(near(*after.last().unwrap(), [0.0, 1.0, 0.0]));
+    }
+}
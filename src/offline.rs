@@ -0,0 +1,129 @@
+//! What the client does while the connection to the server is down. The player keeps playing on the chunks they have,
+//! and their edits wait in an `EditQueue` until we're connected again and the server has sent each chunk back,
+//! when they're put back into it and sent on. Meanwhile `Backoff` says when to try connecting again,
+//! waiting longer after each try that fails, so a server that's down isn't flooded.
+use crate::common::*;
+use std::time::{Duration, Instant};
+
+/// How long to wait before the first try at connecting again. It doubles after each one that fails.
+pub const FIRST_RETRY: Duration = Duration::from_secs(1);
+/// The longest to wait between tries
+pub const MAX_RETRY: Duration = Duration::from_secs(30);
+
+/// Edits made while we were offline, in the order they were made
+#[derive(Default)]
+pub struct EditQueue {
+    edits: Vec<(Vector3<f32>, Material)>,
+    /// How many didn't fit since `take_dropped()` was last called
+    dropped: usize,
+}
+
+impl EditQueue {
+    /// Adds an edit, replacing any earlier one of the same voxel, unless there are already `limit` others.
+    /// Returns whether it was kept.
+    pub fn push(&mut self, pos: Vector3<f32>, mat: Material, limit: usize) -> bool {
+        let voxel = pos.map(|x| x.floor());
+        self.edits.retain(|(p, _)| p.map(|x| x.floor()) != voxel);
+        if self.edits.len() >= limit {
+            self.dropped += 1;
+            return false;
+        }
+        self.edits.push((pos, mat));
+        true
+    }
+
+    /// Takes out the edits in chunk `chunk`, in order
+    pub fn take_in(&mut self, chunk: Vector3<i32>) -> Vec<(Vector3<f32>, Material)> {
+        let (taken, kept) = self
+            .edits
+            .drain(..)
+            .partition(|&(p, _)| world_to_chunk(p) == chunk);
+        self.edits = kept;
+        taken
+    }
+
+    /// How many edits didn't fit since the last time this was called
+    pub fn take_dropped(&mut self) -> usize {
+        std::mem::take(&mut self.dropped)
+    }
+
+    pub fn len(&self) -> usize {
+        self.edits.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.edits.is_empty()
+    }
+}
+
+/// When to try connecting again
+pub struct Backoff {
+    delay: Duration,
+    next: Instant,
+}
+
+impl Backoff {
+    /// We just lost the connection at `now`
+    pub fn new(now: Instant) -> Self {
+        Backoff {
+            delay: FIRST_RETRY,
+            next: now + FIRST_RETRY,
+        }
+    }
+
+    /// Whether it's time for the next try
+    pub fn ready(&self, now: Instant) -> bool {
+        now >= self.next
+    }
+
+    /// A try that ended at `now` didn't work, so wait twice as long for the next one, up to `MAX_RETRY`
+    pub fn failed(&mut self, now: Instant) {
+        self.delay = (self.delay * 2).min(MAX_RETRY);
+        self.next = now + self.delay;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queues_edits_by_chunk() {
+        let mut q = EditQueue::default();
+        let a = Vector3::new(0.5, 0.5, 0.5);
+        let far = Vector3::new(0.5, 0.5, chunk_size() * 3.0 + 0.5);
+        assert!(q.push(a, Material::Stone, 2));
+        assert!(q.push(far, Material::Dirt, 2));
+        // The same voxel again replaces the first one, so it still fits
+        assert!(q.push(a + Vector3::repeat(0.2), Material::Air, 2));
+        assert!(!q.push(Vector3::new(1.5, 0.5, 0.5), Material::Sand, 2));
+        assert_eq!(q.take_dropped(), 1);
+        assert_eq!(q.take_dropped(), 0);
+
+        assert_eq!(
+            q.take_in(world_to_chunk(a)),
+            vec![(a + Vector3::repeat(0.2), Material::Air)]
+        );
+        assert_eq!(q.len(), 1);
+        assert!(q.take_in(world_to_chunk(a)).is_empty());
+        assert_eq!(q.take_in(world_to_chunk(far)), vec![(far, Material::Dirt)]);
+        assert!(q.is_empty());
+    }
+
+    #[test]
+    fn backs_off() {
+        let start = Instant::now();
+        let mut b = Backoff::new(start);
+        assert!(!b.ready(start));
+        assert!(b.ready(start + FIRST_RETRY));
+        let mut now = start + FIRST_RETRY;
+        for _ in 0..10 {
+            b.failed(now);
+            now += MAX_RETRY;
+        }
+        // It stops growing at the most
+        b.failed(now);
+        assert!(!b.ready(now + MAX_RETRY - Duration::from_millis(1)));
+        assert!(b.ready(now + MAX_RETRY));
+    }
+}
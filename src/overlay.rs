@@ -0,0 +1,353 @@
+//! The debug overlay, like F3 in other games: a few lines of text in the top-left corner of the window,
+//! with where the camera is, how much of the world is loaded and how fast it's all going. The `overlay` key toggles it.
+//! It's drawn over the finished frame, so bloom, depth of field and the brightness slider don't change it.
+//! The text only changes every `UPDATE_INTERVAL`, which is easier to read than numbers changing every frame,
+//! and it's only then that we ask `ClientWorld` for its stats and ping the server.
+use crate::client_world::WorldStats;
+use crate::common::*;
+use crate::config::DebugMode;
+use crate::font;
+use crate::markers::font_desc;
+use crate::shaders::{self, OverlayConstants, OverlayVertex, ShaderError, TextFragment};
+
+use std::sync::Arc;
+use vulkano::buffer::{BufferAccess, CpuBufferPool};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, DynamicState};
+use vulkano::descriptor::descriptor_set::DescriptorSet;
+use vulkano::device::{Device, Queue};
+use vulkano::framebuffer::{RenderPassAbstract, Subpass};
+use vulkano::pipeline::{GraphicsPipeline, GraphicsPipelineAbstract};
+use vulkano::sync::GpuFuture;
+
+/// How often the text changes, in seconds
+pub const UPDATE_INTERVAL: f64 = 0.25;
+/// How many pixels across each texel of the font is, at a scale factor of 1
+pub const OVERLAY_SCALE: f32 = 2.0;
+const OVERLAY_COLOR: [f32; 3] = [1.0, 1.0, 1.0];
+/// How far the text is from the corner of the window, in texels of the font
+const MARGIN: f32 = 2.0;
+/// How far apart lines are, in texels of the font, so there's a gap between their outlines
+const LINE_HEIGHT: u32 = font::CELL_SIZE[1] + 1;
+
+/// What the overlay shows that the client knows itself
+#[derive(Clone, Debug, PartialEq)]
+pub struct OverlayInfo {
+    /// The vertical field of view, in radians
+    pub fov: f32,
+    pub pos: Vector3<f64>,
+    /// The average since the text last changed
+    pub fps: f64,
+    pub render_scale: f32,
+    /// `None` if `adaptive_lod` is off
+    pub lod_bias: Option<f32>,
+    pub debug_mode: DebugMode,
+}
+
+/// The text the overlay shows, a line at a time. `stats` is the last answer from `ClientWorld`, if there's been one.
+pub fn overlay_lines(info: &OverlayInfo, stats: Option<&WorldStats>) -> Vec<String> {
+    let p = info.pos;
+    let chunk = world_to_chunk(p.map(|x| x as f32));
+    let mut lines = vec![
+        if info.fps > 0.0 {
+            format!("{:.1} fps ({:.2} ms)", info.fps, 1000.0 / info.fps)
+        } else {
+            "- fps".to_string()
+        },
+        format!("xyz {:.2} {:.2} {:.2}", p.x, p.y, p.z),
+        format!("chunk {} {} {}", chunk.x, chunk.y, chunk.z),
+        format!("fov {:.0}", info.fov.to_degrees()),
+        match info.lod_bias {
+            Some(bias) => format!(
+                "render scale {:.2}, lod bias {:.2}",
+                info.render_scale, bias
+            ),
+            None => format!("render scale {:.2}", info.render_scale),
+        },
+        format!("debug view {:?}", info.debug_mode),
+    ];
+    match stats {
+        Some(s) => {
+            lines.push(format!("{} chunks, {} nodes", s.chunks, s.nodes));
+            lines.push(match s.ping_ms {
+                Some(ms) => format!("ping {:.0} ms", ms),
+                None => "ping -".to_string(),
+            });
+        }
+        None => lines.push("waiting for the world...".to_string()),
+    }
+    lines
+}
+
+/// Whether the overlay is showing, and what it says
+#[derive(Default)]
+pub struct Overlay {
+    pub visible: bool,
+    lines: Vec<String>,
+    stats: Option<WorldStats>,
+    /// When the text last changed, in seconds, and the frames and time since then, for the average FPS
+    updated: Option<f64>,
+    frames: usize,
+    frame_time: f64,
+}
+
+impl Overlay {
+    pub fn toggle(&mut self) {
+        self.visible = !self.visible;
+        // Start again, so the first text doesn't average in frames from when it was hidden
+        self.updated = None;
+        self.frames = 0;
+        self.frame_time = 0.0;
+    }
+
+    /// `ClientWorld` answered `Event::QueryStats`
+    pub fn set_stats(&mut self, stats: WorldStats) {
+        self.stats = Some(stats);
+    }
+
+    /// Counts a frame that took `delta` seconds, at `time`. If it's been `UPDATE_INTERVAL` since the text last changed,
+    /// it's made again from `info`, which gets the average FPS since then, and this returns true,
+    /// which is when to send `Event::QueryStats` for next time. Nothing happens while it's hidden.
+    pub fn frame(&mut self, time: f64, delta: f64, info: impl FnOnce(f64) -> OverlayInfo) -> bool {
+        if !self.visible {
+            return false;
+        }
+        self.frames += 1;
+        self.frame_time += delta;
+        if self.updated.map_or(false, |t| time - t < UPDATE_INTERVAL) {
+            return false;
+        }
+        let fps = if self.frame_time > 0.0 {
+            self.frames as f64 / self.frame_time
+        } else {
+            0.0
+        };
+        self.lines = overlay_lines(&info(fps), self.stats.as_ref());
+        self.updated = Some(time);
+        self.frames = 0;
+        self.frame_time = 0.0;
+        true
+    }
+
+    /// What to draw, which is nothing while it's hidden
+    pub fn lines(&self) -> &[String] {
+        if self.visible {
+            &self.lines
+        } else {
+            &[]
+        }
+    }
+}
+
+/// A corner of a glyph, as the GPU sees it
+#[derive(Default, Debug, Clone, Copy)]
+struct OverlayVert {
+    /// In pixels from the top-left of the window
+    position: [f32; 2],
+    /// Where the corner is in the font atlas, in texels
+    uv: [f32; 2],
+    color: [f32; 3],
+}
+vulkano::impl_vertex!(OverlayVert, position, uv, color);
+
+/// How many pixels across each texel of the font is on a monitor with `scale_factor`, see `Window::scale_factor()`.
+/// It's a whole number, so the texels stay sharp.
+pub fn overlay_scale(scale_factor: f32) -> f32 {
+    (OVERLAY_SCALE * scale_factor).round().max(1.0)
+}
+
+/// Two triangles for each glyph, with the first line in the top-left corner and the rest under it, with texels `scale` pixels across
+fn overlay_vertices(lines: &[String], scale: f32) -> Vec<OverlayVert> {
+    let [w, h] = font::CELL_SIZE;
+    let mut verts = Vec::new();
+    for (i, line) in lines.iter().enumerate() {
+        // `layout()` centers lines, and has y going up from the bottom of the glyphs
+        let left = MARGIN + font::width(line) as f32 * 0.5;
+        let bottom = MARGIN + ((i as u32 + 1) * LINE_HEIGHT) as f32;
+        for ([x, y], [u, v]) in font::layout(line) {
+            let corner = |cx: u32, cy: u32| OverlayVert {
+                position: [
+                    (left + x + (cx * w) as f32) * scale,
+                    (bottom - y - (cy * h) as f32) * scale,
+                ],
+                uv: [(u + cx * w) as f32, (v + (1 - cy) * h) as f32],
+                color: OVERLAY_COLOR,
+            };
+            verts.extend_from_slice(&[
+                corner(0, 0),
+                corner(1, 0),
+                corner(1, 1),
+                corner(0, 0),
+                corner(1, 1),
+                corner(0, 1),
+            ]);
+        }
+    }
+    verts
+}
+
+/// The pipeline for drawing the overlay
+pub struct OverlayPass {
+    pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    /// The font atlas
+    desc: Arc<dyn DescriptorSet + Send + Sync>,
+    pool: CpuBufferPool<OverlayVert>,
+}
+
+impl OverlayPass {
+    /// `rpass` should be the window's, which `Post::draw()` leaves open.
+    /// The future is for uploading the font, which has to be done before drawing.
+    pub fn new(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        rpass: Arc<dyn RenderPassAbstract + Send + Sync>,
+    ) -> Result<(Self, impl GpuFuture + Send + Sync), ShaderError> {
+        let vs = shaders::load("overlay.vert", || OverlayVertex::load(device.clone()))?;
+        let fs = shaders::load("text.frag", || TextFragment::load(device.clone()))?;
+        let pipeline = Arc::new(
+            GraphicsPipeline::start()
+                .vertex_input_single_buffer::<OverlayVert>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(fs.main_entry_point(), ())
+                .render_pass(Subpass::from(rpass, 0).unwrap())
+                .build(device.clone())
+                .unwrap(),
+        ) as Arc<dyn GraphicsPipelineAbstract + Send + Sync>;
+        let (desc, future) = font_desc(device.clone(), queue, &pipeline);
+        Ok((
+            OverlayPass {
+                pipeline,
+                desc,
+                pool: CpuBufferPool::vertex_buffer(device),
+            },
+            future,
+        ))
+    }
+
+    /// Records drawing `lines` over a window `size` pixels big, into its render pass, which should still be open.
+    /// `scale` is how many pixels each texel of the font is, from `overlay_scale()`.
+    pub fn draw(
+        &self,
+        builder: AutoCommandBufferBuilder,
+        lines: &[String],
+        state: &DynamicState,
+        size: [f32; 2],
+        scale: f32,
+    ) -> AutoCommandBufferBuilder {
+        if lines.is_empty() {
+            return builder;
+        }
+        let buf = Arc::new(self.pool.chunk(overlay_vertices(lines, scale)).unwrap())
+            as Arc<dyn BufferAccess + Send + Sync>;
+        builder
+            .draw(
+                self.pipeline.clone(),
+                state,
+                vec![buf],
+                self.desc.clone(),
+                OverlayConstants { size },
+            )
+            .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info() -> OverlayInfo {
+        OverlayInfo {
+            fov: std::f32::consts::FRAC_PI_2,
+            pos: Vector3::new(40.5, 70.25, -3.0),
+            fps: 50.0,
+            render_scale: 1.0,
+            lod_bias: None,
+            debug_mode: DebugMode::Off,
+        }
+    }
+
+    #[test]
+    fn lines() {
+        let stats = WorldStats {
+            chunks: 12,
+            nodes: 3456,
+            ping_ms: Some(23.4),
+        };
+        let lines = overlay_lines(&info(), Some(&stats));
+        assert_eq!(lines[0], "50.0 fps (20.00 ms)");
+        assert!(lines.contains(&"xyz 40.50 70.25 -3.00".to_string()));
+        assert!(lines.contains(&"fov 90".to_string()));
+        assert!(lines.contains(&"12 chunks, 3456 nodes".to_string()));
+        assert!(lines.contains(&"ping 23 ms".to_string()));
+        // Chunks go down from 0 on the negative side too
+        let chunk = world_to_chunk(Vector3::new(40.5, 70.25, -3.0));
+        assert!(lines.contains(&format!("chunk {} {} {}", chunk.x, chunk.y, chunk.z)));
+        assert!(chunk.z < 0);
+    }
+
+    #[test]
+    fn updates_a_few_times_a_second() {
+        let mut o = Overlay::default();
+        // Hidden, it doesn't do anything
+        assert!(!o.frame(0.0, 0.01, |_| info()));
+        assert!(o.lines().is_empty());
+
+        o.toggle();
+        assert!(o.frame(0.0, 0.01, |_| info()));
+        let mut updates = 0;
+        let mut fps = 0.0;
+        // A second at 128 FPS, which adds up without rounding
+        let dt = 1.0 / 128.0;
+        for i in 1..=128 {
+            if o.frame(i as f64 * dt, dt, |f| {
+                fps = f;
+                info()
+            }) {
+                updates += 1;
+            }
+        }
+        assert_eq!(updates, (1.0 / UPDATE_INTERVAL) as usize);
+        assert!((fps - 128.0).abs() < 1e-6, "{}", fps);
+        assert!(!o.lines().is_empty());
+
+        o.toggle();
+        assert!(o.lines().is_empty());
+    }
+
+    #[test]
+    fn vertices_start_in_the_corner() {
+        let lines = vec!["ab".to_string(), "c d".to_string()];
+        let verts = overlay_vertices(&lines, OVERLAY_SCALE);
+        // Four glyphs, without the space
+        assert_eq!(verts.len(), 4 * 6);
+        let left = verts
+            .iter()
+            .map(|v| v.position[0])
+            .fold(f32::INFINITY, f32::min);
+        let top = verts
+            .iter()
+            .map(|v| v.position[1])
+            .fold(f32::INFINITY, f32::min);
+        assert_eq!(left, (MARGIN - font::PADDING as f32) * OVERLAY_SCALE);
+        assert_eq!(
+            top,
+            (MARGIN + LINE_HEIGHT as f32 - font::CELL_SIZE[1] as f32 + font::PADDING as f32)
+                * OVERLAY_SCALE
+        );
+        // The second line is under the first
+        assert!(verts[12..]
+            .iter()
+            .all(|v| v.position[1] > verts[0].position[1]));
+
+        // On a HiDPI monitor it's all twice as big
+        let big = overlay_vertices(&lines, overlay_scale(2.0));
+        assert!(big
+            .iter()
+            .zip(&verts)
+            .all(|(a, b)| a.position == [b.position[0] * 2.0, b.position[1] * 2.0]));
+        // And in between, it's whole pixels
+        assert_eq!(overlay_scale(1.25), 3.0);
+        assert_eq!(overlay_scale(0.1), 1.0);
+    }
+}
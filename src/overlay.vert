@@ -0,0 +1,26 @@
+#version 450
+
+// Text from `overlay.rs`, drawn flat over the window, in pixels from its top-left corner.
+// It goes with `text.frag`, like labels in the world do.
+
+layout(push_constant) uniform PushConstants {
+  // The window's size, in pixels
+  vec2 size;
+};
+
+layout(location = 0) in vec2 position;
+// Where it is in the atlas, in texels
+layout(location = 1) in vec2 uv;
+layout(location = 2) in vec3 color;
+
+layout(location = 0) out vec3 frag_color_in;
+layout(location = 1) out vec3 from_camera;
+layout(location = 2) out vec2 frag_uv;
+
+void main() {
+  gl_Position = vec4(position / size * 2.0 - 1.0, 0.0, 1.0);
+  frag_color_in = color;
+  // There's no camera, and nothing after this reads the alpha
+  from_camera = vec3(0.0);
+  frag_uv = uv;
+}
@@ -0,0 +1,136 @@
+//! Hooks for extending the client without forking it, like HUDs or tools.
+//! Register plugins with `Client::add_plugin()` or by passing them to `run_client_loop()`.
+use crate::common::*;
+use crate::console::Console;
+use crate::event::Event;
+use crate::markers::Markers;
+use crate::shaders::MatData;
+use crate::window::Window;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::Arc;
+use vulkano::buffer::{DeviceLocalBuffer, ImmutableBuffer};
+use vulkano::command_buffer::AutoCommandBufferBuilder;
+
+/// The GPU buffers the world is drawn from, for plugins that run their own shaders over it, from `Plugin::on_compute()`.
+/// They're replaced with bigger ones when the world outgrows them, so get them again each frame instead of keeping them.
+/// `main.frag` binds them as `tree`, `materials` and `lod_colors`, and `octree.glsl` is the reference for walking `tree`.
+#[derive(Clone)]
+pub struct WorldBuffers {
+    /// The octree, as `u32` nodes in groups of eight, one for each child of a node. Child `i` of a group is
+    /// on the + side along x if bit 2 of `i` is set, y for bit 1 and z for bit 0, like `idx_to_pos()` in `octree.rs`.
+    /// The group at 0 is the root's children. A node with bit 0 set has children, in the group `node >> 1` nodes
+    /// after the start of the group it's in; otherwise it's a leaf, packed like the comment above `pack_leaf()` in `common.rs` says.
+    pub tree: Arc<DeviceLocalBuffer<[u32]>>,
+    /// One color for each group of eight nodes in `tree`, at the group's start divided by 8, for drawing the node that points to it as one voxel.
+    /// They're packed by `pack_lod_color()` in `octree.rs`, or 0 for nodes that don't have one.
+    pub lod_colors: Arc<DeviceLocalBuffer<[u32]>>,
+    /// What each material looks like, as `MatData` in `shade.glsl`, indexed by the material number in a leaf
+    pub materials: Arc<ImmutableBuffer<[MatData]>>,
+    /// The center of the root, relative to the render origin like the positions in the push constants, and how wide it is
+    pub origin: Vector3<f32>,
+    pub root_size: f32,
+}
+
+/// Every hook does nothing by default, so plugins only implement the ones they need.
+/// If a hook panics, the plugin is logged and removed, and the client keeps going.
+/// If that happens in `on_compute()` or `on_render()`, the frame is recorded again without it, so the other plugins' ones can be called twice in a frame.
+pub trait Plugin: Send {
+    /// Used to say which plugin failed
+    fn name(&self) -> &str;
+
+    /// Called once when the plugin is added, to add any slash commands it has with `Console::register()`.
+    /// Commands can't reach the plugin itself, so ones that need its state should share it with an `Arc<Mutex<_>>`.
+    fn register_commands(&mut self, _console: &mut Console) {}
+
+    /// Called for every event the client sees, just before the client handles it
+    fn on_event(&mut self, _event: &Event) {}
+
+    /// Called once per frame with the time since the last frame, in seconds
+    fn on_update(&mut self, _dt: f64) {}
+
+    /// Called every frame just before the world is drawn, to add lines and triangles to draw over it this frame.
+    /// They're hidden behind voxels in front of them, and cleared after the frame.
+    fn on_markers(&mut self, _markers: &mut Markers) {}
+
+    /// Called every frame before the world is drawn, outside of any render pass, to record compute dispatches over `buffers`
+    /// or anything else. It goes in the same command buffer as the frame, so vulkano puts in the barriers between this and
+    /// drawing, and it doesn't race with uploads or the last frame. Writing to `tree` shows up in the frame, but `ClientWorld`
+    /// doesn't know about it, so anything there gets overwritten when that chunk is uploaded again.
+    fn on_compute(
+        &mut self,
+        builder: AutoCommandBufferBuilder,
+        _buffers: &WorldBuffers,
+    ) -> AutoCommandBufferBuilder {
+        builder
+    }
+
+    /// Called at the end of each frame, while the render pass for the window is still open,
+    /// so anything drawn here goes on top. Pipelines should use `Subpass::from(window.rpass.clone(), 0)`.
+    fn on_render(
+        &mut self,
+        builder: AutoCommandBufferBuilder,
+        _window: &Window,
+    ) -> AutoCommandBufferBuilder {
+        builder
+    }
+}
+
+/// Calls `f` on each plugin, removing any that panic
+pub fn call_plugins(plugins: &mut Vec<Box<dyn Plugin>>, mut f: impl FnMut(&mut dyn Plugin)) {
+    let mut i = 0;
+    while i < plugins.len() {
+        // We throw out the plugin if it panics, so we don't care what state it's left in
+        if catch_unwind(AssertUnwindSafe(|| f(&mut *plugins[i]))).is_ok() {
+            i += 1;
+        } else {
+            error!(
+                "Plugin {} panicked, so it's been disabled",
+                plugins[i].name()
+            );
+            plugins.remove(i);
+        }
+    }
+}
+
+/// Calls `on_render` on each plugin. If one panics, it's removed and the command buffer is lost, so this returns `None`.
+pub fn render_plugins(
+    plugins: &mut Vec<Box<dyn Plugin>>,
+    builder: AutoCommandBufferBuilder,
+    window: &Window,
+) -> Option<AutoCommandBufferBuilder> {
+    record_plugins(plugins, builder, |p, b| p.on_render(b, window))
+}
+
+/// Calls `on_compute` on each plugin, like `render_plugins()`
+pub fn compute_plugins(
+    plugins: &mut Vec<Box<dyn Plugin>>,
+    builder: AutoCommandBufferBuilder,
+    buffers: &WorldBuffers,
+) -> Option<AutoCommandBufferBuilder> {
+    record_plugins(plugins, builder, |p, b| p.on_compute(b, buffers))
+}
+
+/// Passes the command buffer through `f` for each plugin, losing it and removing the plugin if one panics
+fn record_plugins(
+    plugins: &mut Vec<Box<dyn Plugin>>,
+    mut builder: AutoCommandBufferBuilder,
+    f: impl Fn(&mut dyn Plugin, AutoCommandBufferBuilder) -> AutoCommandBufferBuilder,
+) -> Option<AutoCommandBufferBuilder> {
+    let mut i = 0;
+    while i < plugins.len() {
+        let plugin = &mut plugins[i];
+        match catch_unwind(AssertUnwindSafe(|| f(&mut **plugin, builder))) {
+            Ok(b) => builder = b,
+            Err(_) => {
+                error!(
+                    "Plugin {} panicked, so it's been disabled",
+                    plugins[i].name()
+                );
+                plugins.remove(i);
+                return None;
+            }
+        }
+        i += 1;
+    }
+    Some(builder)
+}
@@ -0,0 +1,174 @@
+//! Writes PNGs, for screenshots. The pixels are stored without compressing them, which makes big files,
+//! but it means this is all we need instead of an image library, and any image viewer or editor can read them.
+use std::io::{self, Write};
+use std::path::Path;
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+/// The most a stored deflate block can hold
+const MAX_BLOCK: usize = 0xffff;
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut table = [0u32; 256];
+    for (i, x) in table.iter_mut().enumerate() {
+        let mut c = i as u32;
+        for _ in 0..8 {
+            c = if c & 1 != 0 {
+                0xedb8_8320 ^ (c >> 1)
+            } else {
+                c >> 1
+            };
+        }
+        *x = c;
+    }
+    !bytes.iter().fold(!0, |c, &b| {
+        table[((c ^ b as u32) & 0xff) as usize] ^ (c >> 8)
+    })
+}
+
+fn adler32(bytes: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    // Small enough pieces that the sums can't overflow before we take them mod 65521
+    for piece in bytes.chunks(5552) {
+        for &x in piece {
+            a += x as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+/// Adds a chunk with its length and checksum
+fn chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// A PNG of an image `width` by `height`, from four bytes per pixel, RGBA in sRGB, in rows from the top.
+/// It has to be at least one pixel in each direction.
+pub fn encode(width: u32, height: u32, rgba: &[u8]) -> Vec<u8> {
+    assert!(width > 0 && height > 0, "PNGs can't be empty");
+    assert_eq!(rgba.len(), width as usize * height as usize * 4);
+    let mut out = SIGNATURE.to_vec();
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    // Eight bits per channel, RGBA, and then the only compression, filtering and interlacing methods there are
+    header.extend_from_slice(&[8, 6, 0, 0, 0]);
+    chunk(&mut out, b"IHDR", &header);
+
+    // Every row starts with the filter it uses, and we don't use one
+    let row = width as usize * 4;
+    let mut raw = Vec::with_capacity((row + 1) * height as usize);
+    for line in rgba.chunks(row) {
+        raw.push(0);
+        raw.extend_from_slice(line);
+    }
+
+    // A zlib stream of stored deflate blocks
+    let blocks = raw.chunks(MAX_BLOCK).count();
+    let mut data = Vec::with_capacity(raw.len() + blocks * 5 + 6);
+    data.extend_from_slice(&[0x78, 0x01]);
+    for (i, block) in raw.chunks(MAX_BLOCK).enumerate() {
+        // The first bit says whether it's the last block, and the next two that it's stored
+        data.push((i == blocks - 1) as u8);
+        let len = block.len() as u16;
+        data.extend_from_slice(&len.to_le_bytes());
+        data.extend_from_slice(&(!len).to_le_bytes());
+        data.extend_from_slice(block);
+    }
+    data.extend_from_slice(&adler32(&raw).to_be_bytes());
+    chunk(&mut out, b"IDAT", &data);
+
+    chunk(&mut out, b"IEND", &[]);
+    out
+}
+
+/// Writes `encode()` to `path`, through a temporary file like thumbnails, so it's never half written
+pub fn save(path: &Path, width: u32, height: u32, rgba: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("png.tmp");
+    std::fs::File::create(&tmp)?.write_all(&encode(width, height, rgba))?;
+    std::fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksums() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b"IEND"), 0xae42_6082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+        assert_eq!(adler32(&[]), 1);
+        assert_eq!(adler32(&vec![255; 100_000]), 0x149a_302c);
+    }
+
+    /// The chunks in a PNG, checking their checksums
+    fn chunks(png: &[u8]) -> Vec<([u8; 4], Vec<u8>)> {
+        assert_eq!(png[..8], SIGNATURE);
+        let mut i = 8;
+        let mut chunks = Vec::new();
+        while i < png.len() {
+            let len = u32::from_be_bytes([png[i], png[i + 1], png[i + 2], png[i + 3]]) as usize;
+            let body = &png[i + 4..i + 8 + len];
+            let crc = &png[i + 8 + len..i + 12 + len];
+            assert_eq!(crc, crc32(body).to_be_bytes());
+            let mut kind = [0; 4];
+            kind.copy_from_slice(&body[..4]);
+            chunks.push((kind, body[4..].to_vec()));
+            i += 12 + len;
+        }
+        chunks
+    }
+
+    /// Undoes the stored blocks `encode()` writes
+    fn inflate_stored(zlib: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut i = 2;
+        loop {
+            let last = zlib[i] & 1 != 0;
+            assert_eq!(zlib[i] >> 1, 0, "not a stored block");
+            let len = u16::from_le_bytes([zlib[i + 1], zlib[i + 2]]);
+            let nlen = u16::from_le_bytes([zlib[i + 3], zlib[i + 4]]);
+            assert_eq!(len, !nlen);
+            out.extend_from_slice(&zlib[i + 5..i + 5 + len as usize]);
+            i += 5 + len as usize;
+            if last {
+                break;
+            }
+        }
+        assert_eq!(zlib[i..], adler32(&out).to_be_bytes());
+        out
+    }
+
+    #[test]
+    fn encodes() {
+        // Big enough to need a few blocks
+        let (width, height) = (300, 100);
+        let rgba: Vec<u8> = (0..width * height * 4).map(|i| (i * 7) as u8).collect();
+        let png = encode(width, height, &rgba);
+        let parsed = chunks(&png);
+        let kinds: Vec<_> = parsed.iter().map(|(k, _)| k).collect();
+        assert_eq!(kinds, [b"IHDR", b"IDAT", b"IEND"]);
+        assert_eq!(parsed[0].1[..8], [0, 0, 1, 44, 0, 0, 0, 100]);
+
+        let raw = inflate_stored(&parsed[1].1);
+        let row = width as usize * 4;
+        assert_eq!(raw.len(), (row + 1) * height as usize);
+        for (y, line) in raw.chunks(row + 1).enumerate() {
+            assert_eq!(line[0], 0);
+            assert_eq!(line[1..], rgba[y * row..(y + 1) * row]);
+        }
+
+        // A single pixel fits in one block
+        let png = encode(1, 1, &[1, 2, 3, 4]);
+        assert_eq!(inflate_stored(&chunks(&png)[1].1), [0, 1, 2, 3, 4]);
+    }
+}
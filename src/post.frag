@@ -0,0 +1,155 @@
+#version 450
+
+layout(location=0) in vec2 frag_coord_ndc;
+layout(location=0) out vec4 frag_color;
+
+layout(push_constant) uniform PushConstants {
+  vec3 camera_pos;
+  // How much of the bloom to add back in, or zero if it's disabled
+  float bloom_intensity;
+  vec3 camera_dir;
+  float fov;
+  vec3 camera_up;
+  // Width / height
+  float aspect;
+  // The camera last frame, for motion blur
+  vec3 prev_pos;
+  // How far along the motion to blur, where 1 is the whole distance moved in a frame
+  float motion_strength;
+  vec3 prev_dir;
+  // Zero if motion blur is off, or the camera isn't moving
+  int motion_samples;
+  vec3 prev_up;
+  // If this is 0, leave the clear color wherever there's sky
+  uint draw_sky;
+  // With supersampling, the HDR image is this many times bigger than the output on each axis,
+  // and each pixel averages the block of texels it covers
+  int downsample;
+  // The distance to keep sharp, in voxels, for depth of field
+  float focus_distance;
+  // The blur radius for something infinitely far behind the focus, as a fraction of the screen height, or zero if depth of field is off
+  float dof_aperture;
+  // The brightness slider: the linear color is raised to 1 / this, so above 1 lifts the shadows more than the highlights.
+  // It's applied to linear color so the sRGB target still does the encoding, instead of correcting twice.
+  float brightness;
+  // `Tonemap` in config.rs: 0 for none, 1 for Reinhard, 2 for ACES
+  uint tonemap;
+};
+
+layout(set=0, binding=0) uniform sampler2D hdr_image;
+// Each bloom level is half the size of the last one
+layout(set=0, binding=1) uniform sampler2D bloom0;
+layout(set=0, binding=2) uniform sampler2D bloom1;
+layout(set=0, binding=3) uniform sampler2D bloom2;
+
+#define MAX_MOTION_SAMPLES 32
+// What main.frag puts in the alpha channel for the sky
+#define SKY_DEPTH 60000.0
+#define DOF_SAMPLES 24
+// The biggest depth of field blur radius, as a fraction of the screen height
+#define MAX_DOF_RADIUS 0.05
+#define GOLDEN_ANGLE 2.39996
+
+// The world-space direction through a point on the screen, the same way main.frag does it
+vec3 ray_dir(vec2 ndc, vec3 dir, vec3 up) {
+  vec2 uv = ndc;
+  uv.x *= aspect;
+  uv *= -1;
+  vec3 right = normalize(cross(up, dir));
+  float film_width = tan(fov*0.5);
+  return normalize(dir + film_width * (up * uv.y + right * uv.x));
+}
+
+// Where a world-space point was on the screen last frame, in uv coordinates
+vec2 reproject(vec3 pos) {
+  vec3 right = normalize(cross(prev_up, prev_dir));
+  vec3 d = pos - prev_pos;
+  float film_width = tan(fov*0.5);
+  vec2 uv = vec2(dot(d, right), dot(d, prev_up)) / (max(dot(d, prev_dir), 0.001) * film_width);
+  uv *= -1;
+  uv.x /= aspect;
+  return uv * 0.5 + 0.5;
+}
+
+// The average of the `downsample` by `downsample` texels around `uv`, which are each sampled at their center
+vec3 resolve(vec2 uv) {
+  vec2 texel = 1.0 / vec2(textureSize(hdr_image, 0));
+  vec3 col = vec3(0.0);
+  for (int y = 0; y < downsample; y++)
+    for (int x = 0; x < downsample; x++)
+      col += texture(hdr_image, uv + (vec2(x, y) + 0.5 - 0.5 * float(downsample)) * texel).rgb;
+  return col / float(downsample * downsample);
+}
+
+// How big the blur is for something `depth` away, as a fraction of the screen height
+float circle_of_confusion(float depth) {
+  return min(dof_aperture * abs(depth - focus_distance) / max(depth, 0.001), MAX_DOF_RADIUS);
+}
+
+// Averages a disk around `uv` as big as its circle of confusion.
+// Each sample only counts if its own blur reaches back to `uv`, so sharp things in focus don't smear over what's behind them.
+vec3 depth_of_field(vec2 uv, vec3 center, float depth) {
+  float radius = circle_of_confusion(depth);
+  // Less than half a texel wouldn't show
+  if (radius * float(textureSize(hdr_image, 0).y) < 0.5)
+    return center;
+  vec3 col = center;
+  float total = 1.0;
+  for (int i = 0; i < DOF_SAMPLES; i++) {
+    // A spiral that covers the disk evenly
+    float r = sqrt((float(i) + 0.5) / float(DOF_SAMPLES)) * radius;
+    float a = float(i) * GOLDEN_ANGLE;
+    vec2 offset = vec2(cos(a) / aspect, sin(a)) * r;
+    vec4 s = texture(hdr_image, uv + offset);
+    float w = clamp((circle_of_confusion(s.a) - r) / (0.25 * radius + 0.0001) + 1.0, 0.0, 1.0);
+    col += s.rgb * w;
+    total += w;
+  }
+  return col / total;
+}
+
+// Brings HDR color into 0 to 1 for the screen, the way `tonemap` says
+vec3 tonemap_color(vec3 col) {
+  col = max(col, vec3(0.0));
+  if (tonemap == 1u)
+    return col / (1.0 + col);
+  if (tonemap == 2u)
+    // Krzysztof Narkowicz's fit of the ACES curve
+    return clamp(col * (2.51 * col + 0.03) / (col * (2.43 * col + 0.59) + 0.14), 0.0, 1.0);
+  return col;
+}
+
+void main() {
+  vec2 uv = frag_coord_ndc * 0.5 + 0.5;
+  vec4 hdr = texture(hdr_image, uv);
+  vec3 col = downsample > 1 ? resolve(uv) : hdr.rgb;
+  if (draw_sky == 0u && hdr.a >= SKY_DEPTH)
+    discard;
+
+  if (dof_aperture > 0.0)
+    col = depth_of_field(uv, col, hdr.a);
+
+  if (motion_samples > 1 && hdr.a > 0.0) {
+    vec3 pos = camera_pos + ray_dir(frag_coord_ndc, camera_dir, camera_up) * hdr.a;
+    vec2 vel = (uv - reproject(pos)) * motion_strength;
+    int n = min(motion_samples, MAX_MOTION_SAMPLES);
+    col = vec3(0.0);
+    for (int i = 0; i < n; i++) {
+      col += texture(hdr_image, uv + vel * (float(i) / float(n - 1) - 0.5)).rgb;
+    }
+    col /= float(n);
+  }
+
+  // The bloom images aren't written to when it's off, so they could have anything in them
+  if (bloom_intensity > 0.0) {
+    vec3 bloom = texture(bloom0, uv).rgb + texture(bloom1, uv).rgb + texture(bloom2, uv).rgb;
+    col += bloom * bloom_intensity / 3.0;
+  }
+
+  col = tonemap_color(col);
+
+  if (brightness != 1.0)
+    col = pow(max(col, vec3(0.0)), vec3(1.0 / brightness));
+
+  frag_color = vec4(col, 1.0);
+}
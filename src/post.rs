@@ -0,0 +1,934 @@
+//! The offscreen HDR target the main shader renders into, and the passes that turn it into the final image
+use crate::common::RenderOrigin;
+use crate::config::ClientConfig;
+use crate::shaders::{
+    self, AccumConstants, BloomConstants, PostConstants, PushConstants, ShaderError,
+};
+use crate::window::{color_rpass, Window};
+
+use std::sync::Arc;
+use vulkano::command_buffer::{AutoCommandBufferBuilder, DynamicState};
+use vulkano::descriptor::descriptor_set::{DescriptorSet, PersistentDescriptorSet};
+use vulkano::descriptor::PipelineLayoutAbstract;
+use vulkano::device::Device;
+use vulkano::format::{D32Sfloat, R16G16B16A16Sfloat, R8G8B8A8Srgb};
+use vulkano::framebuffer::{Framebuffer, FramebufferAbstract, RenderPassAbstract, Subpass};
+use vulkano::image::{AttachmentImage, Dimensions, ImageUsage, StorageImage};
+use vulkano::pipeline::{
+    vertex::BufferlessDefinition, vertex::BufferlessVertices, viewport::Viewport, GraphicsPipeline,
+};
+use vulkano::sampler::{Filter, MipmapMode, Sampler, SamplerAddressMode};
+
+/// How many times to halve the image for bloom. `post.frag` has a binding for each level.
+const BLOOM_LEVELS: usize = 3;
+/// If the camera moved less than this between frames, on every axis of its position and direction, we skip motion blur
+const STILL_EPSILON: f32 = 0.0001;
+
+/// Moves the depth of field focus from `focus` toward `target`, both distances in voxels, after `dt` seconds,
+/// so it gets a `1 - e^(-dt / time)` share of the way there. With no focus yet, or a time of 0, it jumps straight there.
+pub fn ease_focus(focus: Option<f32>, target: f32, dt: f32, time: f32) -> f32 {
+    match focus {
+        Some(focus) if time > 0.0 => focus + (target - focus) * (1.0 - (-dt / time).exp()),
+        _ => target,
+    }
+}
+
+/// Where the camera was last frame, for reprojection
+#[derive(Clone, Copy)]
+struct CameraState {
+    /// Relative to `origin`
+    pos: [f32; 3],
+    origin: RenderOrigin,
+    dir: [f32; 3],
+    up: [f32; 3],
+}
+
+impl CameraState {
+    fn new(pc: &PushConstants, origin: RenderOrigin) -> Self {
+        CameraState {
+            pos: pc.camera_pos,
+            origin,
+            dir: pc.camera_dir,
+            up: pc.camera_up,
+        }
+    }
+
+    /// Moves `pos` to be relative to `origin`, which it is the rest of the time
+    fn rebase(self, origin: RenderOrigin) -> Self {
+        CameraState {
+            pos: origin.rebase(self.origin, self.pos.into()).into(),
+            origin,
+            ..self
+        }
+    }
+
+    fn still(&self, other: &CameraState) -> bool {
+        let close =
+            |a: [f32; 3], b: [f32; 3]| a.iter().zip(&b).all(|(a, b)| (a - b).abs() < STILL_EPSILON);
+        close(self.pos, other.pos) && close(self.dir, other.dir) && close(self.up, other.up)
+    }
+}
+
+/// The `i`th number of the Halton sequence in `base`, from 0 to 1, which spreads out evenly however many of them there are
+fn halton(mut i: u32, base: u32) -> f32 {
+    let mut x = 0.0;
+    let mut f = 1.0;
+    while i > 0 {
+        f /= base as f32;
+        x += f * (i % base) as f32;
+        i /= base;
+    }
+    x
+}
+
+/// How far off the pixel centers the `n`th frame of `still_accumulation` is drawn, in pixels, starting at 1
+fn jitter(n: u32) -> [f32; 2] {
+    [halton(n, 2) - 0.5, halton(n, 3) - 0.5]
+}
+
+/// What to do with a frame for `still_accumulation`
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum AccumStep {
+    /// The camera moved recently, so it's drawn like normal
+    Off,
+    /// The frame is drawn `jitter` pixels off and added to the average, which it's `weight` of
+    Add { jitter: [f32; 2], weight: f32 },
+    /// The average has all the frames it's getting, so that's what's shown
+    Hold,
+}
+
+/// Keeps track of how long the camera's been still, and how many frames are averaged so far
+#[derive(Default)]
+struct Accumulation {
+    still: u32,
+    samples: u32,
+    /// Which of the two images in `AccumTargets` the average is in
+    current: usize,
+}
+
+impl Accumulation {
+    fn restart(&mut self) {
+        self.still = 0;
+        self.samples = 0;
+    }
+
+    /// Goes on to the next frame, where the camera is `still` if it's where it was last frame
+    fn step(&mut self, still: bool, config: &ClientConfig) -> AccumStep {
+        if !still || !config.still_accumulation {
+            self.restart();
+            return AccumStep::Off;
+        }
+        self.still = self.still.saturating_add(1);
+        if self.still <= config.still_frames {
+            AccumStep::Off
+        } else if self.samples >= config.still_samples {
+            AccumStep::Hold
+        } else {
+            self.samples += 1;
+            AccumStep::Add {
+                jitter: jitter(self.samples),
+                weight: 1.0 / self.samples as f32,
+            }
+        }
+    }
+}
+
+type RenderPass = Arc<dyn RenderPassAbstract + Send + Sync>;
+type Fb = Arc<dyn FramebufferAbstract + Send + Sync>;
+type Desc = Arc<dyn DescriptorSet + Send + Sync>;
+pub type HdrImage = Arc<AttachmentImage<R16G16B16A16Sfloat>>;
+/// An image `draw_to_image()` can draw the final frame to
+pub type RenderTarget = Arc<StorageImage<R8G8B8A8Srgb>>;
+type BufferlessPipeline = GraphicsPipeline<
+    BufferlessDefinition,
+    Box<dyn PipelineLayoutAbstract + Send + Sync>,
+    RenderPass,
+>;
+
+/// The main shader's render pass: the HDR image, and a depth buffer so markers drawn after it are hidden behind voxels
+fn scene_rpass(device: Arc<Device>) -> RenderPass {
+    Arc::new(
+        vulkano::single_pass_renderpass! {
+            device,
+            attachments: {
+                color: {
+                    load: DontCare,
+                    store: Store,
+                    format: vulkano::format::Format::R16G16B16A16Sfloat,
+                    samples: 1,
+                },
+                depth: {
+                    load: DontCare,
+                    store: DontCare,
+                    format: vulkano::format::Format::D32Sfloat,
+                    samples: 1,
+                }
+            },
+            pass: {
+                color: [color],
+                depth_stencil: {depth}
+            }
+        }
+        .unwrap(),
+    )
+}
+
+/// A render pass with one HDR color attachment, which is what bloom draws to
+fn bloom_rpass(device: Arc<Device>) -> RenderPass {
+    Arc::new(
+        vulkano::single_pass_renderpass! {
+            device,
+            attachments: {
+                color: {
+                    load: DontCare,
+                    store: Store,
+                    format: vulkano::format::Format::R16G16B16A16Sfloat,
+                    samples: 1,
+                }
+            },
+            pass: {
+                color: [color],
+                depth_stencil: {}
+            }
+        }
+        .unwrap(),
+    )
+}
+
+fn hdr_image(device: Arc<Device>, size: [u32; 2]) -> HdrImage {
+    AttachmentImage::with_usage(
+        device,
+        size,
+        R16G16B16A16Sfloat,
+        ImageUsage {
+            sampled: true,
+            color_attachment: true,
+            // So the client can read frames back, for thumbnails
+            transfer_source: true,
+            ..ImageUsage::none()
+        },
+    )
+    .unwrap()
+}
+
+/// Makes a `RenderTarget`, which can also be copied from to read the frame back
+pub fn render_target(window: &Window, size: [u32; 2]) -> RenderTarget {
+    StorageImage::with_usage(
+        window.device(),
+        Dimensions::Dim2d {
+            width: size[0],
+            height: size[1],
+        },
+        R8G8B8A8Srgb,
+        ImageUsage {
+            color_attachment: true,
+            transfer_source: true,
+            ..ImageUsage::none()
+        },
+        Some(window.queue.family()),
+    )
+    .unwrap()
+}
+
+/// For drawing to all of an image of `size`
+fn viewport(size: [u32; 2]) -> DynamicState {
+    DynamicState {
+        viewports: Some(vec![Viewport {
+            origin: [0.0, 0.0],
+            dimensions: [size[0] as f32, size[1] as f32],
+            depth_range: 0.0..1.0,
+        }]),
+        ..Default::default()
+    }
+}
+
+fn sampled_desc(
+    pipeline: &Arc<BufferlessPipeline>,
+    image: &HdrImage,
+    sampler: &Arc<Sampler>,
+) -> Desc {
+    Arc::new(
+        PersistentDescriptorSet::start(pipeline.layout().descriptor_set_layout(0).unwrap().clone())
+            .add_sampled_image(image.clone(), sampler.clone())
+            .unwrap()
+            .build()
+            .unwrap(),
+    )
+}
+
+/// One bloom level: the result, and a second image at the same size for the horizontal blur
+struct BloomLevel {
+    size: [u32; 2],
+    state: DynamicState,
+    image: HdrImage,
+    framebuffer: Fb,
+    tmp_framebuffer: Fb,
+    /// Samples the previous level (or the HDR image), for downsampling
+    down_desc: Desc,
+    /// Samples `image`, for the horizontal blur
+    h_desc: Desc,
+    /// Samples the temporary image, for the vertical blur
+    v_desc: Desc,
+}
+
+/// The images `still_accumulation` averages frames into. Each frame reads the average from one and writes the new one to the other.
+/// They're only made the first time they're needed, since they're as big as the HDR image.
+struct AccumTargets {
+    framebuffers: [Fb; 2],
+    /// Samples the HDR image and the other image, for drawing to each one
+    accum_descs: [Desc; 2],
+    /// Like `post_desc` and the first bloom level's `down_desc` in `Targets`, but reading each image instead of the HDR image
+    post_descs: [Desc; 2],
+    down_descs: [Desc; 2],
+}
+
+impl AccumTargets {
+    fn new(post: &Post, device: Arc<Device>, targets: &Targets) -> Self {
+        let images = [
+            hdr_image(device.clone(), targets.size),
+            hdr_image(device, targets.size),
+        ];
+        let framebuffer = |image: &HdrImage| {
+            Arc::new(
+                Framebuffer::start(post.bloom_rpass.clone())
+                    .add(image.clone())
+                    .unwrap()
+                    .build()
+                    .unwrap(),
+            ) as Fb
+        };
+        let accum_desc = |other: &HdrImage| {
+            Arc::new(
+                PersistentDescriptorSet::start(
+                    post.accum_pipeline
+                        .layout()
+                        .descriptor_set_layout(0)
+                        .unwrap()
+                        .clone(),
+                )
+                .add_sampled_image(targets.hdr.clone(), post.sampler.clone())
+                .unwrap()
+                .add_sampled_image(other.clone(), post.sampler.clone())
+                .unwrap()
+                .build()
+                .unwrap(),
+            ) as Desc
+        };
+        AccumTargets {
+            framebuffers: [framebuffer(&images[0]), framebuffer(&images[1])],
+            accum_descs: [accum_desc(&images[1]), accum_desc(&images[0])],
+            post_descs: [
+                post_desc(post, &images[0], &targets.levels),
+                post_desc(post, &images[1], &targets.levels),
+            ],
+            down_descs: [
+                sampled_desc(&post.bloom_pipeline, &images[0], &post.sampler),
+                sampled_desc(&post.bloom_pipeline, &images[1], &post.sampler),
+            ],
+        }
+    }
+}
+
+// Each image added to a descriptor set changes the builder's type, so `post_desc()` can't loop over the levels,
+// and this stops it building if there stop being three of them
+const _: [(); 3] = [(); BLOOM_LEVELS];
+
+/// The composite's descriptor set, for drawing `image` with the bloom from `levels`
+fn post_desc(post: &Post, image: &HdrImage, levels: &[BloomLevel]) -> Desc {
+    Arc::new(
+        PersistentDescriptorSet::start(
+            post.post_pipeline
+                .layout()
+                .descriptor_set_layout(0)
+                .unwrap()
+                .clone(),
+        )
+        .add_sampled_image(image.clone(), post.sampler.clone())
+        .unwrap()
+        .add_sampled_image(levels[0].image.clone(), post.sampler.clone())
+        .unwrap()
+        .add_sampled_image(levels[1].image.clone(), post.sampler.clone())
+        .unwrap()
+        .add_sampled_image(levels[2].image.clone(), post.sampler.clone())
+        .unwrap()
+        .build()
+        .unwrap(),
+    )
+}
+
+/// Everything that depends on the size of what we're drawing.
+/// `Post` has one for the window, and anything drawing somewhere else makes its own with `Post::new_targets()`.
+pub struct Targets {
+    size: [u32; 2],
+    hdr: HdrImage,
+    hdr_framebuffer: Fb,
+    /// The viewport for drawing to `hdr`, which is smaller than the window if `render_scale` is below 1
+    hdr_state: DynamicState,
+    levels: Vec<BloomLevel>,
+    post_desc: Desc,
+    accum: Option<AccumTargets>,
+}
+
+impl Targets {
+    fn new(post: &Post, device: Arc<Device>, size: [u32; 2]) -> Self {
+        let hdr = hdr_image(device.clone(), size);
+        // Nothing reads it after the scene pass, so it doesn't need to be stored
+        let depth = AttachmentImage::transient(device.clone(), size, D32Sfloat).unwrap();
+        let hdr_framebuffer = Arc::new(
+            Framebuffer::start(post.scene_rpass.clone())
+                .add(hdr.clone())
+                .unwrap()
+                .add(depth)
+                .unwrap()
+                .build()
+                .unwrap(),
+        ) as Fb;
+
+        let mut levels: Vec<BloomLevel> = Vec::new();
+        for i in 0..BLOOM_LEVELS {
+            let size = [(size[0] >> (i + 1)).max(1), (size[1] >> (i + 1)).max(1)];
+            let image = hdr_image(device.clone(), size);
+            let tmp = hdr_image(device.clone(), size);
+            let framebuffer = Arc::new(
+                Framebuffer::start(post.bloom_rpass.clone())
+                    .add(image.clone())
+                    .unwrap()
+                    .build()
+                    .unwrap(),
+            ) as Fb;
+            let tmp_framebuffer = Arc::new(
+                Framebuffer::start(post.bloom_rpass.clone())
+                    .add(tmp.clone())
+                    .unwrap()
+                    .build()
+                    .unwrap(),
+            ) as Fb;
+            let prev = levels.last().map_or(&hdr, |l| &l.image);
+            let down_desc = sampled_desc(&post.bloom_pipeline, prev, &post.sampler);
+            levels.push(BloomLevel {
+                size,
+                state: viewport(size),
+                framebuffer,
+                tmp_framebuffer,
+                down_desc,
+                h_desc: sampled_desc(&post.bloom_pipeline, &image, &post.sampler),
+                v_desc: sampled_desc(&post.bloom_pipeline, &tmp, &post.sampler),
+                image,
+            });
+        }
+
+        let post_desc = post_desc(post, &hdr, &levels);
+
+        Targets {
+            size,
+            hdr,
+            hdr_framebuffer,
+            hdr_state: viewport(size),
+            levels,
+            post_desc,
+            accum: None,
+        }
+    }
+
+    /// The size of the HDR image
+    pub fn size(&self) -> [u32; 2] {
+        self.size
+    }
+
+    pub fn hdr_framebuffer(&self) -> Fb {
+        self.hdr_framebuffer.clone()
+    }
+
+    pub fn hdr_state(&self) -> &DynamicState {
+        &self.hdr_state
+    }
+}
+
+/// Post-processing: motion blur and bloom, and averaging frames while the camera is still.
+/// The main shader draws to `hdr_framebuffer()`, and `draw()` composites that onto the swapchain image.
+pub struct Post {
+    scene_rpass: RenderPass,
+    bloom_rpass: RenderPass,
+    bloom_pipeline: Arc<BufferlessPipeline>,
+    /// Adds a frame into the average for `still_accumulation`, which is drawn with the bloom render pass
+    accum_pipeline: Arc<BufferlessPipeline>,
+    post_pipeline: Arc<BufferlessPipeline>,
+    /// The same as `post_pipeline`, but for drawing to a `RenderTarget` instead of the window
+    image_rpass: RenderPass,
+    image_pipeline: Arc<BufferlessPipeline>,
+    sampler: Arc<Sampler>,
+    targets: Option<Targets>,
+    prev_cam: Option<CameraState>,
+    accumulation: Accumulation,
+    /// What `begin_frame()` said to do with this frame
+    step: AccumStep,
+}
+
+impl Post {
+    /// `render_scale` is how big the HDR image is compared to the window, like in `resize()`
+    pub fn new(window: &Window, render_scale: f32) -> Result<Self, ShaderError> {
+        let device = window.device();
+        let scene_rpass = scene_rpass(device.clone());
+        let bloom_rpass = bloom_rpass(device.clone());
+
+        let vs = shaders::load("blank.vert", || shaders::Vertex::load(device.clone()))?;
+        let fs_bloom = shaders::load("bloom.frag", || shaders::Bloom::load(device.clone()))?;
+        let fs_post = shaders::load("post.frag", || shaders::Post::load(device.clone()))?;
+        let fs_accum = shaders::load("accum.frag", || shaders::Accum::load(device.clone()))?;
+
+        let bloom_pipeline = Arc::new(
+            GraphicsPipeline::start()
+                .vertex_shader(vs.main_entry_point(), ())
+                .fragment_shader(fs_bloom.main_entry_point(), ())
+                .triangle_strip()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .render_pass(Subpass::from(bloom_rpass.clone(), 0).unwrap())
+                .build(device.clone())
+                .unwrap(),
+        );
+        let accum_pipeline = Arc::new(
+            GraphicsPipeline::start()
+                .vertex_shader(vs.main_entry_point(), ())
+                .fragment_shader(fs_accum.main_entry_point(), ())
+                .triangle_strip()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .render_pass(Subpass::from(bloom_rpass.clone(), 0).unwrap())
+                .build(device.clone())
+                .unwrap(),
+        );
+        let composite_pipeline = |rpass: &RenderPass| {
+            Arc::new(
+                GraphicsPipeline::start()
+                    .vertex_shader(vs.main_entry_point(), ())
+                    .fragment_shader(fs_post.main_entry_point(), ())
+                    .triangle_strip()
+                    .viewports_dynamic_scissors_irrelevant(1)
+                    .render_pass(Subpass::from(rpass.clone(), 0).unwrap())
+                    .build(device.clone())
+                    .unwrap(),
+            )
+        };
+        let post_pipeline = composite_pipeline(&window.rpass);
+        // These are only drawn now and then, so it isn't worth being careful about clearing them
+        let image_rpass = color_rpass(device.clone(), vulkano::format::Format::R8G8B8A8Srgb, true);
+        let image_pipeline = composite_pipeline(&image_rpass);
+
+        // Linear filtering does half the work of downsampling and blurring for us
+        let sampler = Sampler::new(
+            device.clone(),
+            Filter::Linear,
+            Filter::Linear,
+            MipmapMode::Nearest,
+            SamplerAddressMode::ClampToEdge,
+            SamplerAddressMode::ClampToEdge,
+            SamplerAddressMode::ClampToEdge,
+            0.0,
+            1.0,
+            0.0,
+            0.0,
+        )
+        .unwrap();
+
+        let mut post = Post {
+            scene_rpass,
+            bloom_rpass,
+            bloom_pipeline,
+            accum_pipeline,
+            post_pipeline,
+            image_rpass,
+            image_pipeline,
+            sampler,
+            targets: None,
+            prev_cam: None,
+            accumulation: Accumulation::default(),
+            step: AccumStep::Off,
+        };
+        post.resize(window, render_scale);
+        Ok(post)
+    }
+
+    /// The render pass for anything that draws to `hdr_framebuffer()`.
+    /// It has a depth buffer, which the main shader fills in, so pipelines drawing after it can test against the world.
+    pub fn hdr_rpass(&self) -> RenderPass {
+        self.scene_rpass.clone()
+    }
+
+    /// The images for drawing to the window
+    pub fn targets(&self) -> &Targets {
+        self.targets.as_ref().unwrap()
+    }
+
+    pub fn hdr_framebuffer(&self) -> Fb {
+        self.targets().hdr_framebuffer()
+    }
+
+    /// What to draw to `hdr_framebuffer()` with, since it isn't always the size of the window
+    pub fn hdr_state(&self) -> &DynamicState {
+        self.targets().hdr_state()
+    }
+
+    /// The image behind `hdr_framebuffer()`, before bloom and tone mapping
+    pub fn hdr_image(&self) -> HdrImage {
+        self.targets.as_ref().unwrap().hdr.clone()
+    }
+
+    /// Recreates the offscreen images to match the swapchain, at `render_scale` times its size.
+    /// With supersampling, that should already be multiplied by the factor.
+    /// Call this whenever the swapchain is recreated or the render scale changes.
+    /// The composite stretches the HDR image over the whole window, so below 1 everything is drawn at a lower resolution.
+    pub fn resize(&mut self, window: &Window, render_scale: f32) {
+        let size = window.dimensions();
+        let size = [
+            ((size[0] as f32 * render_scale) as u32).max(1),
+            ((size[1] as f32 * render_scale) as u32).max(1),
+        ];
+        self.targets = Some(Targets::new(self, window.device(), size));
+        self.restart_accumulation();
+    }
+
+    /// Throws out the average `still_accumulation` has so far, for when the world changes under it.
+    /// It starts again after the camera's been still for another `still_frames`.
+    pub fn restart_accumulation(&mut self) {
+        self.accumulation.restart();
+    }
+
+    /// Call this before drawing the scene each frame, with the same `pc` and `origin` as `draw()`.
+    /// Returns how far off the pixel centers to draw it, in pixels, which is only ever nonzero with `still_accumulation`.
+    pub fn begin_frame(
+        &mut self,
+        pc: &PushConstants,
+        origin: RenderOrigin,
+        config: &ClientConfig,
+    ) -> [f32; 2] {
+        let cam = CameraState::new(pc, origin);
+        let still = self
+            .prev_cam
+            .map_or(true, |prev| cam.still(&prev.rebase(origin)));
+        self.step = self.accumulation.step(still, config);
+        match self.step {
+            AccumStep::Add { jitter, .. } => jitter,
+            _ => [0.0; 2],
+        }
+    }
+
+    /// Records adding the HDR image to the average, with `weight`
+    fn accumulate(
+        &mut self,
+        builder: AutoCommandBufferBuilder,
+        window: &Window,
+        weight: f32,
+    ) -> AutoCommandBufferBuilder {
+        if self.targets().accum.is_none() {
+            let accum = AccumTargets::new(self, window.device(), self.targets());
+            self.targets.as_mut().unwrap().accum = Some(accum);
+        }
+        let next = 1 - self.accumulation.current;
+        self.accumulation.current = next;
+        let targets = self.targets();
+        let accum = targets.accum.as_ref().unwrap();
+        builder
+            .begin_render_pass(
+                accum.framebuffers[next].clone(),
+                false,
+                vec![vulkano::format::ClearValue::None],
+            )
+            .unwrap()
+            .draw(
+                self.accum_pipeline.clone(),
+                &targets.hdr_state,
+                BufferlessVertices {
+                    vertices: 4,
+                    instances: 1,
+                },
+                accum.accum_descs[next].clone(),
+                AccumConstants { weight },
+            )
+            .unwrap()
+            .end_render_pass()
+            .unwrap()
+    }
+
+    /// Images for drawing a frame of `size` somewhere other than the window, with `draw_to_image()`
+    pub fn new_targets(&self, window: &Window, size: [u32; 2]) -> Targets {
+        Targets::new(self, window.device(), size)
+    }
+
+    /// Records bloom, if it's enabled, and then the composite onto `framebuffer`, with motion blur if that's enabled.
+    /// With `still_accumulation`, it's the average of the last few frames instead, if `begin_frame()` said to.
+    /// `pc` is what the main shader was drawn with this frame, with positions relative to `origin`.
+    /// With a `focus` distance, things nearer and farther than that are blurred by `dof_aperture`.
+    /// `brightness` is the slider, like `brightness` in the config.
+    /// The render pass is left open so more can be drawn on top, so call `end_render_pass()` after.
+    #[allow(clippy::too_many_arguments)]
+    pub fn draw(
+        &mut self,
+        builder: AutoCommandBufferBuilder,
+        framebuffer: Fb,
+        window: &Window,
+        config: &ClientConfig,
+        pc: &PushConstants,
+        origin: RenderOrigin,
+        focus: Option<f32>,
+        brightness: f32,
+    ) -> AutoCommandBufferBuilder {
+        let cam = CameraState::new(pc, origin);
+        let prev = self.prev_cam.replace(cam).unwrap_or(cam).rebase(origin);
+        let builder = match self.step {
+            AccumStep::Add { weight, .. } => self.accumulate(builder, window, weight),
+            _ => builder,
+        };
+        let targets = self.targets();
+        let (post_desc, down_desc) = match (self.step, &targets.accum) {
+            (AccumStep::Add { .. }, Some(accum)) | (AccumStep::Hold, Some(accum)) => {
+                let i = self.accumulation.current;
+                (&accum.post_descs[i], &accum.down_descs[i])
+            }
+            _ => (&targets.post_desc, &targets.levels[0].down_desc),
+        };
+        self.composite(
+            builder,
+            targets,
+            post_desc,
+            down_desc,
+            &self.post_pipeline,
+            framebuffer,
+            window.clears(),
+            &window.dynamic_state,
+            config,
+            pc,
+            cam,
+            prev,
+            focus,
+            brightness,
+        )
+    }
+
+    /// Like `draw()`, but draws what's in `targets` onto `target`, and ends the render pass.
+    /// `targets` should be the size of `target`, times the supersampling factor if `aa_mode` is `Ssaa`.
+    /// There's no motion blur, since there's no last frame to blur from, and it doesn't change what the next `draw()` blurs from.
+    #[allow(clippy::too_many_arguments)]
+    pub fn draw_to_image(
+        &self,
+        builder: AutoCommandBufferBuilder,
+        targets: &Targets,
+        target: RenderTarget,
+        config: &ClientConfig,
+        pc: &PushConstants,
+        origin: RenderOrigin,
+        focus: Option<f32>,
+        brightness: f32,
+    ) -> AutoCommandBufferBuilder {
+        let target_size = vulkano::image::ImageAccess::dimensions(&*target).width_height();
+        let framebuffer = Arc::new(
+            Framebuffer::start(self.image_rpass.clone())
+                .add(target)
+                .unwrap()
+                .build()
+                .unwrap(),
+        ) as Fb;
+        let cam = CameraState::new(pc, origin);
+        self.composite(
+            builder,
+            targets,
+            &targets.post_desc,
+            &targets.levels[0].down_desc,
+            &self.image_pipeline,
+            framebuffer,
+            true,
+            &viewport(target_size),
+            config,
+            pc,
+            cam,
+            cam,
+            focus,
+            brightness,
+        )
+        .end_render_pass()
+        .unwrap()
+    }
+
+    /// `post_desc` and `down_desc` are what to read the frame from, for the composite and the first bloom level
+    #[allow(clippy::too_many_arguments)]
+    fn composite(
+        &self,
+        mut builder: AutoCommandBufferBuilder,
+        targets: &Targets,
+        post_desc: &Desc,
+        down_desc: &Desc,
+        pipeline: &Arc<BufferlessPipeline>,
+        framebuffer: Fb,
+        clear: bool,
+        state: &DynamicState,
+        config: &ClientConfig,
+        pc: &PushConstants,
+        cam: CameraState,
+        prev: CameraState,
+        focus: Option<f32>,
+        brightness: f32,
+    ) -> AutoCommandBufferBuilder {
+        let motion_samples = if config.motion_blur && !cam.still(&prev) {
+            config.motion_blur_samples as i32
+        } else {
+            0
+        };
+
+        let verts = || BufferlessVertices {
+            vertices: 4,
+            instances: 1,
+        };
+
+        if config.bloom {
+            for (i, level) in targets.levels.iter().enumerate() {
+                let texel = [1.0 / level.size[0] as f32, 1.0 / level.size[1] as f32];
+                // Only the first level is thresholded, the rest are downsampled as-is
+                let threshold = if i == 0 { config.bloom_threshold } else { 0.0 };
+                let down = if i == 0 { down_desc } else { &level.down_desc };
+                let passes = [
+                    (&level.framebuffer, down, [0.0, 0.0]),
+                    (&level.tmp_framebuffer, &level.h_desc, [1.0, 0.0]),
+                    (&level.framebuffer, &level.v_desc, [0.0, 1.0]),
+                ];
+                for (fb, desc, dir) in passes.iter() {
+                    builder = builder
+                        .begin_render_pass(
+                            (*fb).clone(),
+                            false,
+                            vec![vulkano::format::ClearValue::None],
+                        )
+                        .unwrap()
+                        .draw(
+                            self.bloom_pipeline.clone(),
+                            &level.state,
+                            verts(),
+                            (*desc).clone(),
+                            BloomConstants {
+                                texel,
+                                dir: *dir,
+                                threshold,
+                            },
+                        )
+                        .unwrap()
+                        .end_render_pass()
+                        .unwrap();
+                }
+            }
+        }
+
+        builder
+            .begin_render_pass(
+                framebuffer,
+                false,
+                // Without clearing, the composite has to cover every pixel, which it does unless it leaves the sky out
+                vec![if clear {
+                    config.clear_color.into()
+                } else {
+                    vulkano::format::ClearValue::None
+                }],
+            )
+            .unwrap()
+            .draw(
+                pipeline.clone(),
+                state,
+                verts(),
+                post_desc.clone(),
+                PostConstants {
+                    camera_pos: cam.pos,
+                    bloom_intensity: if config.bloom {
+                        config.bloom_intensity
+                    } else {
+                        0.0
+                    },
+                    camera_dir: cam.dir,
+                    fov: pc.fov,
+                    camera_up: cam.up,
+                    aspect: pc.resolution[0] / pc.resolution[1],
+                    prev_pos: prev.pos,
+                    motion_strength: config.motion_blur_strength,
+                    prev_dir: prev.dir,
+                    motion_samples,
+                    prev_up: prev.up,
+                    draw_sky: config.sky as u32,
+                    downsample: config.aa_mode.factor() as i32,
+                    focus_distance: focus.unwrap_or(0.0),
+                    dof_aperture: if focus.is_some() {
+                        config.dof_aperture
+                    } else {
+                        0.0
+                    },
+                    brightness,
+                    tonemap: config.tonemap as u32,
+                },
+            )
+            .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn focus_eases() {
+        // Nothing to ease from, so it snaps
+        assert_eq!(ease_focus(None, 10.0, 0.016, 0.2), 10.0);
+        assert_eq!(ease_focus(Some(2.0), 10.0, 0.016, 0.0), 10.0);
+        // Partway after one frame, and nearly there after a second
+        let f = ease_focus(Some(2.0), 10.0, 0.016, 0.2);
+        assert!(f > 2.0 && f < 3.0, "{}", f);
+        let mut f = Some(2.0);
+        for _ in 0..60 {
+            f = Some(ease_focus(f, 10.0, 1.0 / 60.0, 0.2));
+        }
+        assert!((f.unwrap() - 10.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn accumulates_when_still() {
+        let config = ClientConfig {
+            still_accumulation: true,
+            still_frames: 2,
+            still_samples: 3,
+            ..ClientConfig::default()
+        };
+        let mut a = Accumulation::default();
+        assert_eq!(a.step(true, &config), AccumStep::Off);
+        assert_eq!(a.step(true, &config), AccumStep::Off);
+        // The first frame replaces whatever was there, and each one after counts for less
+        let weights: Vec<f32> = (0..3)
+            .map(|_| match a.step(true, &config) {
+                AccumStep::Add { jitter, weight } => {
+                    assert!(jitter.iter().all(|x| x.abs() <= 0.5));
+                    weight
+                }
+                x => panic!("{:?}", x),
+            })
+            .collect();
+        assert_eq!(weights, [1.0, 0.5, 1.0 / 3.0]);
+        assert_eq!(a.step(true, &config), AccumStep::Hold);
+        assert_eq!(a.step(true, &config), AccumStep::Hold);
+
+        // Moving throws it out straight away, and it waits for the camera to be still again
+        assert_eq!(a.step(false, &config), AccumStep::Off);
+        assert_eq!(a.step(true, &config), AccumStep::Off);
+        assert_eq!(a.step(true, &config), AccumStep::Off);
+        assert!(matches!(
+            a.step(true, &config),
+            AccumStep::Add { weight, .. } if weight == 1.0
+        ));
+
+        let off = ClientConfig::default();
+        assert_eq!(a.step(true, &off), AccumStep::Off);
+    }
+
+    #[test]
+    fn jitter_spreads_out() {
+        assert_eq!(halton(1, 2), 0.5);
+        assert_eq!(halton(3, 2), 0.75);
+        assert!((halton(2, 3) - 2.0 / 3.0).abs() < 1e-6);
+        // No two of the first few are the same
+        let j: Vec<[f32; 2]> = (1..=16).map(jitter).collect();
+        for (i, a) in j.iter().enumerate() {
+            assert!(j[i + 1..].iter().all(|b| a != b));
+        }
+    }
+}
@@ -0,0 +1,73 @@
+//! Lightweight named timing spans for the main loop.
+//! Everything here compiles to nothing unless the `profiling` feature is enabled, so release builds pay nothing.
+#[cfg(feature = "profiling")]
+use std::collections::HashMap;
+#[cfg(feature = "profiling")]
+use std::time::{Duration, Instant};
+
+/// How many frames to average over before printing a report
+#[cfg(feature = "profiling")]
+const REPORT_FRAMES: usize = 120;
+
+/// The start of a span, returned by `mark()`
+#[cfg(feature = "profiling")]
+pub type Mark = Instant;
+#[cfg(not(feature = "profiling"))]
+#[derive(Clone, Copy)]
+pub struct Mark;
+
+#[cfg(feature = "profiling")]
+pub fn mark() -> Mark {
+    Instant::now()
+}
+#[cfg(not(feature = "profiling"))]
+#[inline(always)]
+pub fn mark() -> Mark {
+    Mark
+}
+
+/// Accumulates span durations and periodically logs the per-frame average of each.
+/// Lives in the specs `World` so every system can record spans into it.
+#[derive(Default)]
+pub struct Profiler {
+    #[cfg(feature = "profiling")]
+    spans: HashMap<&'static str, Duration>,
+    #[cfg(feature = "profiling")]
+    frames: usize,
+}
+
+impl Profiler {
+    /// Records the time since `start` under `name`
+    #[cfg(feature = "profiling")]
+    pub fn record(&mut self, name: &'static str, start: Mark) {
+        *self.spans.entry(name).or_insert_with(Duration::default) += start.elapsed();
+    }
+    #[cfg(not(feature = "profiling"))]
+    #[inline(always)]
+    pub fn record(&mut self, _name: &'static str, _start: Mark) {}
+
+    /// Marks the end of a frame, and reports the averages every `REPORT_FRAMES` frames
+    #[cfg(feature = "profiling")]
+    pub fn frame(&mut self) {
+        self.frames += 1;
+        if self.frames >= REPORT_FRAMES {
+            let mut spans: Vec<_> = self.spans.drain().collect();
+            spans.sort_by_key(|(name, _)| *name);
+            let report: Vec<_> = spans
+                .into_iter()
+                .map(|(name, d)| {
+                    format!(
+                        "{} {:.3}ms",
+                        name,
+                        d.as_secs_f64() * 1000.0 / self.frames as f64
+                    )
+                })
+                .collect();
+            info!("Frame spans: {}", report.join(", "));
+            self.frames = 0;
+        }
+    }
+    #[cfg(not(feature = "profiling"))]
+    #[inline(always)]
+    pub fn frame(&mut self) {}
+}
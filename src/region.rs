@@ -0,0 +1,271 @@
+//! The on-disk format for saved chunks. The world is split into regions of `REGION_SIZE`³ chunks, each in its own file:
+//!
+//! - The magic bytes `QREG` and a `u32` format version
+//! - Since version 2, the material names from `Material::names()`: a `u32` count, then each as a `u32` length and UTF-8.
+//!   Chunks store materials as numbers, so this is what they meant when they were saved.
+//! - An index with an `(offset, length)` pair of `u32`s for every chunk in the region, in `in_region()` order.
+//!   A length of 0 means that chunk isn't saved.
+//! - The chunks, each serialized with bincode and compressed with zstd, at those offsets from the start of the file
+//!
+//! All numbers are little-endian. Version 1 files don't have material names, and are assumed to use the current ones.
+//! If the names don't match the current materials, the chunks are renumbered when the region is loaded. Regions are written to a temporary file which is then renamed over the old one,
+//! so a crash while saving leaves either the old version of the region or the new one, never half of each.
+use crate::common::*;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8; 4] = b"QREG";
+const VERSION: u32 = 2;
+/// The number of chunks in a region
+const REGION_CHUNKS: usize = (REGION_SIZE * REGION_SIZE * REGION_SIZE) as usize;
+const INDEX_LEN: usize = REGION_CHUNKS * 8;
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+/// The chunks in one region. They're kept compressed until they're needed, so a region in memory is about as big as its file.
+pub struct Region {
+    chunks: Vec<Option<Vec<u8>>>,
+    /// Whether it's changed since it was loaded or saved
+    pub dirty: bool,
+}
+
+impl Region {
+    pub fn empty() -> Self {
+        Region {
+            chunks: vec![None; REGION_CHUNKS],
+            dirty: false,
+        }
+    }
+
+    /// Where the region at `region` (from `chunk_to_region()`) is stored in `dir`
+    pub fn path(dir: &Path, region: Vector3<i32>) -> PathBuf {
+        dir.join(format!("{},{},{}.region", region.x, region.y, region.z))
+    }
+
+    /// Reads a region file, or returns an empty region if the file doesn't exist
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let mut buf = Vec::new();
+        match std::fs::File::open(path) {
+            Ok(mut f) => f.read_to_end(&mut buf)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Region::empty()),
+            Err(e) => return Err(e),
+        };
+
+        if buf.len() < 8 || &buf[0..4] != MAGIC {
+            return Err(invalid("not a region file"));
+        }
+        let u32_at = |i: usize| {
+            if i + 4 > buf.len() {
+                return Err(invalid("the header is cut off"));
+            }
+            let mut b = [0; 4];
+            b.copy_from_slice(&buf[i..i + 4]);
+            Ok(u32::from_le_bytes(b) as usize)
+        };
+        let version = u32_at(4)?;
+        let mut pos = 8;
+        let names = match version {
+            1 => None,
+            2 => {
+                let count = u32_at(pos)?;
+                pos += 4;
+                let mut names = Vec::new();
+                for _ in 0..count {
+                    let len = u32_at(pos)?;
+                    pos += 4;
+                    let name = buf
+                        .get(pos..pos + len)
+                        .ok_or_else(|| invalid("the header is cut off"))?;
+                    names.push(
+                        String::from_utf8(name.to_vec())
+                            .map_err(|_| invalid("a material name isn't UTF-8"))?,
+                    );
+                    pos += len;
+                }
+                Some(names)
+            }
+            _ => {
+                return Err(invalid(format!(
+                    "unknown region format version {}",
+                    version
+                )))
+            }
+        };
+        let header_len = pos + INDEX_LEN;
+
+        let chunks = (0..REGION_CHUNKS)
+            .map(|i| {
+                let offset = u32_at(pos + i * 8)?;
+                let len = u32_at(pos + 4 + i * 8)?;
+                if len == 0 {
+                    Ok(None)
+                } else if offset < header_len || offset + len > buf.len() {
+                    Err(invalid(format!("chunk {} is outside the file", i)))
+                } else {
+                    Ok(Some(buf[offset..offset + len].to_vec()))
+                }
+            })
+            .collect::<io::Result<_>>()?;
+        let mut region = Region {
+            chunks,
+            dirty: false,
+        };
+
+        if let Some(map) = names.as_deref().and_then(Material::remap) {
+            for i in 0..REGION_CHUNKS {
+                if let Some(mut chunk) = region.get(i)? {
+                    chunk.remap_materials(&map);
+                    region.set(i, &chunk);
+                }
+            }
+            // Save it with the new numbering, so this only happens once
+            region.dirty = true;
+        }
+        Ok(region)
+    }
+
+    /// Writes the region to `path`, safely - see the module docs
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        self.save_with(path, &Material::names())
+    }
+
+    /// Saves as if `names` were the materials, which is only different from `save()` for testing loading old saves
+    fn save_with(&self, path: &Path, names: &[String]) -> io::Result<()> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(MAGIC);
+        buf.extend_from_slice(&VERSION.to_le_bytes());
+        buf.extend_from_slice(&(names.len() as u32).to_le_bytes());
+        for name in names {
+            buf.extend_from_slice(&(name.len() as u32).to_le_bytes());
+            buf.extend_from_slice(name.as_bytes());
+        }
+        let mut offset = buf.len() + INDEX_LEN;
+        for c in &self.chunks {
+            let len = c.as_ref().map_or(0, Vec::len);
+            buf.extend_from_slice(&(if len == 0 { 0 } else { offset as u32 }).to_le_bytes());
+            buf.extend_from_slice(&(len as u32).to_le_bytes());
+            offset += len;
+        }
+        for c in self.chunks.iter().flatten() {
+            buf.extend_from_slice(c);
+        }
+
+        let tmp = path.with_extension("region.tmp");
+        {
+            let mut f = std::fs::File::create(&tmp)?;
+            f.write_all(&buf)?;
+            // Make sure it's actually on disk before it replaces the old file
+            f.sync_all()?;
+        }
+        std::fs::rename(&tmp, path)
+    }
+
+    /// The chunk at `idx` (from `in_region()`), if it's saved
+    pub fn get(&self, idx: usize) -> io::Result<Option<Chunk>> {
+        match &self.chunks[idx] {
+            Some(data) => {
+                let data = zstd::stream::decode_all(&data[..])?;
+                bincode::deserialize(&data)
+                    .map(Some)
+                    .map_err(|e| invalid(e.to_string()))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn set(&mut self, idx: usize, chunk: &Chunk) {
+        let data = bincode::serialize(chunk).unwrap();
+        self.chunks[idx] = Some(zstd::stream::encode_all(&data[..], 3).unwrap());
+        self.dirty = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_chunk() -> Chunk {
+        Chunk::from_voxels(|p| {
+            if p.y < 5 {
+                Material::Stone
+            } else {
+                Material::Air
+            }
+        })
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = test_dir("region-round-trip");
+        let path = Region::path(&dir, Vector3::new(-1, 0, 2));
+        let mut region = Region::empty();
+        region.set(3, &test_chunk());
+        region.set(REGION_CHUNKS - 1, &Chunk::empty());
+        region.save(&path).unwrap();
+
+        let loaded = Region::load(&path).unwrap();
+        assert!(!loaded.dirty);
+        assert_eq!(loaded.get(3).unwrap().unwrap().0, test_chunk().0);
+        assert_eq!(
+            loaded.get(REGION_CHUNKS - 1).unwrap().unwrap().0,
+            Chunk::empty().0
+        );
+        assert!(loaded.get(0).unwrap().is_none());
+        // The temporary file was renamed away
+        assert!(!path.with_extension("region.tmp").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn missing_is_empty() {
+        let dir = test_dir("region-missing");
+        let region = Region::load(&Region::path(&dir, Vector3::zeros())).unwrap();
+        assert!((0..REGION_CHUNKS).all(|i| region.get(i).unwrap().is_none()));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_truncated() {
+        let dir = test_dir("region-truncated");
+        let path = Region::path(&dir, Vector3::zeros());
+        let mut region = Region::empty();
+        region.set(0, &test_chunk());
+        region.save(&path).unwrap();
+
+        let data = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &data[..data.len() - 1]).unwrap();
+        assert!(Region::load(&path).is_err());
+        std::fs::write(&path, &data[..10]).unwrap();
+        assert!(Region::load(&path).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn renumbers_old_materials() {
+        let dir = test_dir("region-materials");
+        let path = Region::path(&dir, Vector3::zeros());
+
+        // A save from a version where stone and dirt were the other way around, and there was no sand
+        let mut names = Material::names();
+        names.swap(Material::Stone as usize, Material::Dirt as usize);
+        names.truncate(Material::Sand as usize);
+        let mut old = test_chunk();
+        old.remap_materials(&Material::remap(&names).unwrap());
+        let mut region = Region::empty();
+        region.set(0, &old);
+        region.save_with(&path, &names).unwrap();
+
+        let loaded = Region::load(&path).unwrap();
+        assert!(loaded.dirty);
+        let chunk = loaded.get(0).unwrap().unwrap();
+        assert_eq!(chunk.0, test_chunk().0);
+        assert_eq!(chunk.block(Vector3::repeat(-7.5)), Material::Stone);
+
+        // Once it's saved again with the current materials, it loads as-is
+        loaded.save(&path).unwrap();
+        assert!(!Region::load(&path).unwrap().dirty);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,198 @@
+//! Other players, as the server tells us about them, and where to draw them each frame.
+//! Their positions only come a few times a second, so drawing them right where the last one said would be jerky.
+//! Instead we draw them `remote_player_delay` seconds in the past, between two positions we already have.
+//! If the next one is late, we keep going the way they were going for up to `remote_player_extrapolation` seconds,
+//! and then wait where that left them.
+use crate::common::*;
+use crate::config::ClientConfig;
+use std::collections::VecDeque;
+
+/// If a player moves farther than this between two updates, in voxels, they teleported,
+/// so they jump straight there instead of sliding through whatever's in the way
+pub const SNAP_DISTANCE: f32 = 16.0;
+/// The most positions we keep for each player, in case updates come much faster than the delay
+const MAX_SAMPLES: usize = 64;
+
+/// The positions we've heard for one player, oldest first, with the time each one arrived in seconds
+#[derive(Default)]
+struct Samples(VecDeque<(f64, Vector3<f32>)>);
+
+impl Samples {
+    /// Where the player is at `time`, extrapolating at most `max_ahead` seconds past the last position
+    fn at(&self, time: f64, max_ahead: f64) -> Option<Vector3<f32>> {
+        let s = &self.0;
+        let &(first_t, first) = s.front()?;
+        if time <= first_t {
+            return Some(first);
+        }
+        // The pair of positions `time` is between, or the last two if it's past them
+        let i = s
+            .iter()
+            .rposition(|&(t, _)| t <= time)
+            .unwrap()
+            .min(s.len().saturating_sub(2));
+        let (t0, p0) = s[i];
+        let (t1, p1) = match s.get(i + 1) {
+            Some(&x) => x,
+            None => return Some(p0),
+        };
+        if (p1 - p0).norm() > SNAP_DISTANCE || t1 <= t0 {
+            // No sliding between a teleport's two ends, or going on in the direction it went
+            return Some(if time < t1 { p0 } else { p1 });
+        }
+        let time = time.min(t1 + max_ahead);
+        let f = ((time - t0) / (t1 - t0)) as f32;
+        Some(p0 + (p1 - p0) * f)
+    }
+}
+
+pub struct RemotePlayers {
+    players: HashMap<u32, Samples>,
+    /// The names players have told the server, which can come before or after their first position
+    names: HashMap<u32, String>,
+    delay: f64,
+    max_extrapolation: f64,
+}
+
+impl RemotePlayers {
+    pub fn new(config: &ClientConfig) -> Self {
+        let mut r = RemotePlayers {
+            players: HashMap::new(),
+            names: HashMap::new(),
+            delay: 0.0,
+            max_extrapolation: 0.0,
+        };
+        r.configure(config);
+        r
+    }
+
+    /// Picks up `remote_player_delay` and `remote_player_extrapolation`. Call this again when the config changes.
+    pub fn configure(&mut self, config: &ClientConfig) {
+        self.delay = config.remote_player_delay;
+        self.max_extrapolation = config.remote_player_extrapolation;
+    }
+
+    /// The server says player `id` is at `pos`, and it got here at `time`, in seconds
+    pub fn update(&mut self, id: u32, time: f64, pos: Vector3<f32>) {
+        let s = &mut self.players.entry(id).or_default().0;
+        s.push_back((time, pos));
+        // We only ever draw them `delay` behind the newest position, so we just need one from before that
+        let oldest = time - self.delay;
+        while s.len() > MAX_SAMPLES || (s.len() > 2 && s[1].0 <= oldest) {
+            s.pop_front();
+        }
+    }
+
+    /// Player `id` left, so stop drawing them
+    pub fn remove(&mut self, id: u32) {
+        self.players.remove(&id);
+        self.names.remove(&id);
+    }
+
+    /// The server says player `id` is called `name`
+    pub fn set_name(&mut self, id: u32, name: String) {
+        self.names.insert(id, name);
+    }
+
+    /// What player `id` is called, if they've said
+    pub fn name(&self, id: u32) -> Option<&str> {
+        self.names.get(&id).map(|s| s.as_str())
+    }
+
+    /// Where to draw each player at `time`, in seconds, on the same clock as `update()`
+    pub fn positions(&self, time: f64) -> Vec<(u32, Vector3<f32>)> {
+        let time = time - self.delay;
+        let mut v: Vec<_> = self
+            .players
+            .iter()
+            .filter_map(|(&id, s)| Some((id, s.at(time, self.max_extrapolation)?)))
+            .collect();
+        v.sort_by_key(|&(id, _)| id);
+        v
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn players(delay: f64, extrapolation: f64) -> RemotePlayers {
+        RemotePlayers::new(&ClientConfig {
+            remote_player_delay: delay,
+            remote_player_extrapolation: extrapolation,
+            ..ClientConfig::default()
+        })
+    }
+
+    fn at(r: &RemotePlayers, time: f64) -> Vector3<f32> {
+        r.positions(time)[0].1
+    }
+
+    fn close(a: Vector3<f32>, b: Vector3<f32>) -> bool {
+        (a - b).norm() < 1e-4
+    }
+
+    #[test]
+    fn interpolates_in_the_past() {
+        let mut r = players(0.1, 0.0);
+        r.update(1, 0.0, Vector3::new(0.0, 0.0, 0.0));
+        r.update(1, 0.1, Vector3::new(1.0, 0.0, 0.0));
+        // Before the first one, it waits there
+        assert!(close(at(&r, 0.05), Vector3::zeros()));
+        r.update(1, 0.2, Vector3::new(2.0, 2.0, 0.0));
+        // A tenth of a second behind, so it's between the last two
+        assert!(close(at(&r, 0.25), Vector3::new(1.5, 1.0, 0.0)));
+        assert!(close(at(&r, 0.2), Vector3::new(1.0, 0.0, 0.0)));
+        // Without extrapolation, it stops at the last one
+        assert!(close(at(&r, 1.0), Vector3::new(2.0, 2.0, 0.0)));
+        assert!(r.players[&1].0.len() <= 3);
+
+        r.set_name(1, "Steve".to_string());
+        assert_eq!(r.name(1), Some("Steve"));
+        r.remove(1);
+        assert!(r.positions(1.0).is_empty());
+        assert_eq!(r.name(1), None);
+    }
+
+    #[test]
+    fn extrapolates_late_updates() {
+        let mut r = players(0.0, 0.2);
+        r.update(7, 0.0, Vector3::new(0.0, 0.0, 0.0));
+        r.update(7, 0.1, Vector3::new(1.0, 0.0, 0.0));
+        // The next one is late, so keep going the same way
+        assert!(close(at(&r, 0.15), Vector3::new(1.5, 0.0, 0.0)));
+        // But not forever
+        assert!(close(at(&r, 0.3), Vector3::new(3.0, 0.0, 0.0)));
+        assert!(close(at(&r, 10.0), Vector3::new(3.0, 0.0, 0.0)));
+        // When it does come, we go back to interpolating
+        r.update(7, 0.4, Vector3::new(1.0, 0.0, 0.0));
+        assert!(close(at(&r, 0.4), Vector3::new(1.0, 0.0, 0.0)));
+    }
+
+    #[test]
+    fn snaps_on_teleports() {
+        let mut r = players(0.1, 0.5);
+        let far = Vector3::new(1000.0, 50.0, 0.0);
+        r.update(2, 0.0, Vector3::zeros());
+        r.update(2, 0.1, far);
+        // Halfway between in time, but it doesn't go through the middle
+        assert!(close(at(&r, 0.15), Vector3::zeros()));
+        assert!(close(at(&r, 0.2), far));
+        // And it doesn't fly off in that direction after
+        assert!(close(at(&r, 0.5), far));
+    }
+
+    #[test]
+    fn keeps_players_apart() {
+        let mut r = players(0.0, 0.0);
+        r.update(3, 0.0, Vector3::new(3.0, 0.0, 0.0));
+        r.update(1, 0.0, Vector3::new(1.0, 0.0, 0.0));
+        for i in 0..1000 {
+            r.update(1, i as f64 * 0.001, Vector3::new(1.0, 0.0, 0.0));
+        }
+        let ids: Vec<u32> = r.positions(1.0).iter().map(|x| x.0).collect();
+        assert_eq!(ids, [1, 3]);
+        assert!(r.players[&1].0.len() <= MAX_SAMPLES);
+        assert!(close(at(&r, 1.0), Vector3::new(1.0, 0.0, 0.0)));
+    }
+}
@@ -0,0 +1,145 @@
+//! Dynamic resolution: lowers `render_scale` when frames take too long, and raises it again when there's time to spare.
+//! Changes are small, and only happen after a run of slow or fast frames,
+//! so one hitch doesn't change anything and the resolution doesn't bounce back and forth between two sizes.
+use crate::config::ClientConfig;
+
+/// How many frames in a row have to be over budget before we lower the resolution
+const SLOW_FRAMES: usize = 30;
+/// How many frames in a row have to be comfortably under budget before we raise it.
+/// This is longer than `SLOW_FRAMES`, since raising it too soon would just make it slow again.
+const FAST_FRAMES: usize = 120;
+/// Frames have to take less than this much of the budget to count as fast,
+/// so there's still room left once the resolution goes up
+const FAST_MARGIN: f64 = 0.8;
+/// How much `render_scale` changes at a time
+const STEP: f32 = 0.05;
+
+pub struct DynamicScale {
+    scale: f32,
+    min: f32,
+    max: f32,
+    target_ms: f64,
+    /// How many frames in a row were over budget, or under it with room to spare
+    slow: usize,
+    fast: usize,
+}
+
+impl DynamicScale {
+    /// Starts at `render_scale`, and stays between `min_render_scale` and `max_render_scale`
+    pub fn new(config: &ClientConfig) -> Self {
+        DynamicScale {
+            scale: config
+                .render_scale
+                .max(config.min_render_scale)
+                .min(config.max_render_scale),
+            min: config.min_render_scale,
+            max: config.max_render_scale,
+            target_ms: config.target_frame_ms as f64,
+            slow: 0,
+            fast: 0,
+        }
+    }
+
+    pub fn scale(&self) -> f32 {
+        self.scale
+    }
+
+    /// Whether it's at `max_render_scale`, with nothing more to get back
+    pub fn at_max(&self) -> bool {
+        self.scale >= self.max
+    }
+
+    /// Records how long a frame took, and returns the new scale if it changed
+    pub fn frame(&mut self, ms: f64) -> Option<f32> {
+        if ms > self.target_ms {
+            self.slow += 1;
+            self.fast = 0;
+        } else if ms < self.target_ms * FAST_MARGIN {
+            self.fast += 1;
+            self.slow = 0;
+        } else {
+            // Close enough, so this is where we want to be
+            self.slow = 0;
+            self.fast = 0;
+        }
+
+        let new = if self.slow >= SLOW_FRAMES {
+            (self.scale - STEP).max(self.min)
+        } else if self.fast >= FAST_FRAMES {
+            (self.scale + STEP).min(self.max)
+        } else {
+            return None;
+        };
+        // Start counting again, with frames at the new resolution
+        self.slow = 0;
+        self.fast = 0;
+        if (new - self.scale).abs() < f32::EPSILON {
+            return None;
+        }
+        self.scale = new;
+        Some(new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scaler() -> DynamicScale {
+        DynamicScale::new(&ClientConfig {
+            render_scale: 1.0,
+            min_render_scale: 0.5,
+            max_render_scale: 1.0,
+            target_frame_ms: 10.0,
+            ..ClientConfig::default()
+        })
+    }
+
+    #[test]
+    fn lowers_after_sustained_slow_frames() {
+        let mut s = scaler();
+        for _ in 0..SLOW_FRAMES - 1 {
+            assert_eq!(s.frame(15.0), None);
+        }
+        assert_eq!(s.frame(15.0), Some(0.95));
+        // And not again right away
+        assert_eq!(s.frame(15.0), None);
+
+        // It never goes below the minimum
+        for _ in 0..SLOW_FRAMES * 100 {
+            s.frame(100.0);
+        }
+        assert_eq!(s.scale(), 0.5);
+    }
+
+    #[test]
+    fn ignores_spikes() {
+        let mut s = scaler();
+        for i in 0..SLOW_FRAMES * 10 {
+            let ms = if i % 10 == 0 { 50.0 } else { 9.0 };
+            assert_eq!(s.frame(ms), None);
+        }
+    }
+
+    #[test]
+    fn raises_only_with_room_to_spare() {
+        let mut s = scaler();
+        for _ in 0..SLOW_FRAMES {
+            s.frame(15.0);
+        }
+        assert_eq!(s.scale(), 0.95);
+
+        // Just under budget isn't enough, since it'd probably be over at the higher resolution
+        for _ in 0..FAST_FRAMES * 2 {
+            assert_eq!(s.frame(9.0), None);
+        }
+        for _ in 0..FAST_FRAMES - 1 {
+            assert_eq!(s.frame(5.0), None);
+        }
+        assert_eq!(s.frame(5.0), Some(1.0));
+        // Already at the maximum
+        for _ in 0..FAST_FRAMES * 2 {
+            assert_eq!(s.frame(5.0), None);
+        }
+    }
+}
@@ -0,0 +1,187 @@
+//! World generation from a rhai script, for custom terrain without recompiling.
+//!
+//! The script defines `fn generate(cx, cy, cz)`, which gets chunk coordinates and returns an array of
+//! `chunk_size()`³ material ids, indexed by `(x * chunk_size() + y) * chunk_size() + z` where each coordinate starts at 0
+//! on the chunk's minimum corner. It can return an empty array for an empty chunk.
+//! `noise2(x, y)`, `noise3(x, y, z)` and `chunk_size()` are available to it.
+use crate::common::*;
+use crate::noise_gen::Noise;
+use num_traits::FromPrimitive;
+use rhai::{Array, Engine, RegisterFn, Scope, AST};
+use std::path::Path;
+use std::sync::Arc;
+
+pub struct ScriptGen {
+    engine: Engine,
+    /// `None` if the script didn't compile, in which case every chunk is empty
+    ast: Option<AST>,
+}
+
+impl ScriptGen {
+    /// Compiles the script once, so we don't have to for every chunk
+    pub fn new(path: &Path, noise: Arc<dyn Noise>) -> Self {
+        let mut engine = Engine::new();
+        // rhai's own limits are much lower in debug builds, where even `hills.rhai` is too deeply nested,
+        // so use its release ones everywhere to have scripts work the same in both
+        engine.set_max_expr_depths(128, 32);
+        let n = Arc::clone(&noise);
+        engine.register_fn("noise2", move |x: f64, y: f64| n.get2([x, y]));
+        let n = noise;
+        engine.register_fn("noise3", move |x: f64, y: f64, z: f64| n.get3([x, y, z]));
+        engine.register_fn("chunk_size", || chunk_size() as i64);
+
+        let ast = match engine.compile_file(path.to_path_buf()) {
+            Ok(ast) => {
+                info!("Generating terrain with {}", path.display());
+                Some(ast)
+            }
+            Err(e) => {
+                error!(
+                    "World generation script {} failed to compile, so chunks will be empty: {}",
+                    path.display(),
+                    e
+                );
+                None
+            }
+        };
+        ScriptGen { engine, ast }
+    }
+
+    /// Runs the script for one chunk. If anything goes wrong, we log it and return an empty chunk.
+    pub fn gen(&self, pos: Vector3<i32>) -> Chunk {
+        let ast = match &self.ast {
+            Some(ast) => ast,
+            None => return Chunk::empty(),
+        };
+        match self.try_gen(ast, pos) {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                warn!("World generation script failed on chunk {:?}: {}", pos, e);
+                Chunk::empty()
+            }
+        }
+    }
+
+    fn try_gen(&self, ast: &AST, pos: Vector3<i32>) -> Result<Chunk, String> {
+        let voxels: Array = self
+            .engine
+            .call_fn(
+                &mut Scope::new(),
+                ast,
+                "generate",
+                (pos.x as i64, pos.y as i64, pos.z as i64),
+            )
+            .map_err(|e| e.to_string())?;
+        if voxels.is_empty() {
+            return Ok(Chunk::empty());
+        }
+
+        let size = chunk_size() as usize;
+        if voxels.len() != size * size * size {
+            return Err(format!(
+                "it returned {} voxels instead of {}",
+                voxels.len(),
+                size * size * size
+            ));
+        }
+        let mats = voxels
+            .into_iter()
+            .map(|v| {
+                let id = v
+                    .as_int()
+                    .map_err(|t| format!("got a {} instead of a material id", t))?;
+                Material::from_i64(id).ok_or_else(|| format!("{} isn't a material", id))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Chunk::from_voxels(|p| {
+            mats[(p.x * size + p.y) * size + p.z]
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Noise that's 0 everywhere, so `hills.rhai` makes flat ground with its top at y = 16
+    fn flat() -> Arc<dyn Noise> {
+        Arc::new(noise::Constant::new(0.0))
+    }
+
+    fn empty(chunk: Chunk) -> bool {
+        chunk.iter().all(|&x| x == 0)
+    }
+
+    /// A `ScriptGen` running `source`, which is written to a file first since that's what it loads
+    fn script(name: &str, source: &str) -> ScriptGen {
+        let path = test_dir(&format!("script-{}", name)).join("gen.rhai");
+        std::fs::write(&path, source).unwrap();
+        ScriptGen::new(&path, flat())
+    }
+
+    #[test]
+    fn runs_hills() {
+        let gen = ScriptGen::new(Path::new("scripts/hills.rhai"), flat());
+        let half = chunk_size() * 0.5;
+        let at = |chunk: &Chunk, y: f32| chunk.block(Vector3::new(0.5, y + 0.5 - half, 0.5));
+
+        // Stone down to three below the top, then dirt
+        let ground = gen.gen(Vector3::new(0, 0, 0));
+        assert_eq!(at(&ground, 0.0), Material::Stone);
+        assert_eq!(at(&ground, 13.0), Material::Stone);
+        assert_eq!(at(&ground, 14.0), Material::Dirt);
+        assert_eq!(at(&ground, 15.0), Material::Dirt);
+        // Grass on top, with air over it
+        let above = gen.gen(Vector3::new(0, 1, 0));
+        assert_eq!(at(&above, 0.0), Material::Grass);
+        assert_eq!(at(&above, 1.0), Material::Air);
+        // Up high it returns an empty array
+        assert!(empty(gen.gen(Vector3::new(0, 3, 0))));
+    }
+
+    #[test]
+    fn bad_scripts_make_empty_chunks() {
+        let pos = Vector3::zeros();
+        let n = chunk_size() as usize;
+        let n = n * n * n;
+        for (name, source, why) in &[
+            (
+                "short",
+                "fn generate(cx, cy, cz) { [1, 2, 3] }".to_string(),
+                format!("it returned 3 voxels instead of {}", n),
+            ),
+            (
+                "floats",
+                format!(
+                    "fn generate(cx, cy, cz) {{ let v = []; for i in range(0, {}) {{ v.push(1.5); }} v }}",
+                    n
+                ),
+                "instead of a material id".to_string(),
+            ),
+            (
+                "unknown",
+                format!(
+                    "fn generate(cx, cy, cz) {{ let v = []; for i in range(0, {}) {{ v.push(9999); }} v }}",
+                    n
+                ),
+                "9999 isn't a material".to_string(),
+            ),
+            (
+                "errors",
+                "fn generate(cx, cy, cz) { nope(cx) }".to_string(),
+                "nope".to_string(),
+            ),
+        ] {
+            let gen = script(name, source);
+            let e = gen.try_gen(gen.ast.as_ref().unwrap(), pos).unwrap_err();
+            assert!(e.contains(why.as_str()), "{}: {}", name, e);
+            assert!(empty(gen.gen(pos)), "{}", name);
+        }
+
+        // One that doesn't compile doesn't make anything either
+        let gen = script("broken", "fn generate(cx, cy, cz) {");
+        assert!(gen.ast.is_none());
+        assert!(empty(gen.gen(pos)));
+    }
+}
@@ -3,15 +3,144 @@ use crate::common::*;
 use crate::config::*;
 use crate::world::*;
 use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::{TcpListener, ToSocketAddrs};
 use std::rc::Rc;
 use std::sync::mpsc::*;
 use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long to sleep between ticks, so the server doesn't spin a whole core waiting for messages
+const TICK_SLEEP: Duration = Duration::from_millis(1);
+/// The most often players hear where the others are. Clients move them smoothly in between, see `remote.rs`.
+const PLAYER_UPDATE_INTERVAL: Duration = Duration::from_millis(50);
+/// How often network players hear the time of day. Clients keep counting in between, see `day_clock.rs`.
+const TIME_UPDATE_INTERVAL: Duration = Duration::from_secs(5);
+/// The most often we log, and tell a player with `warn_rejected_edits`, that their edits were dropped,
+/// so a client spamming them doesn't get the server to spam back
+const REJECTION_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Why an edit from a network player was dropped
+#[derive(Debug, PartialEq)]
+enum EditError {
+    /// This chunk is farther away than they can see
+    OutOfRange(Vector3<i32>),
+    /// This chunk isn't loaded, like one they just moved away from
+    NotLoaded(Vector3<i32>),
+    /// They're over `max_edits_per_second`
+    TooFast,
+    /// It's this many blocks, which is more than `max_fill_volume`
+    TooBig(usize),
+}
+
+impl fmt::Display for EditError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EditError::OutOfRange(c) => {
+                write!(f, "chunk {:?} is farther away than your view distance", c)
+            }
+            EditError::NotLoaded(c) => write!(f, "chunk {:?} isn't loaded", c),
+            EditError::TooFast => write!(f, "too many edits at once"),
+            EditError::TooBig(n) => write!(f, "{} blocks is too many to fill at once", n),
+        }
+    }
+}
+
+/// How many edits a player can make right now, for `max_edits_per_second`.
+/// It refills at that rate, up to a second's worth, so short bursts like a big brush are fine.
+struct EditBudget {
+    edits: f64,
+    last: Instant,
+}
+
+impl EditBudget {
+    fn new(now: Instant, per_second: u32) -> Self {
+        EditBudget {
+            edits: per_second as f64,
+            last: now,
+        }
+    }
+
+    /// Takes one edit at `now`, if there's one left
+    fn take(&mut self, now: Instant, per_second: u32) -> Result<(), EditError> {
+        if per_second == 0 {
+            return Ok(());
+        }
+        let dt = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = self.last.max(now);
+        self.edits = (self.edits + dt * per_second as f64).min(per_second as f64);
+        if self.edits >= 1.0 {
+            self.edits -= 1.0;
+            Ok(())
+        } else {
+            Err(EditError::TooFast)
+        }
+    }
+}
+
+/// The chunks the box of blocks from `min` to `max`, inclusive, touches
+fn chunks_in(min: Vector3<i32>, max: Vector3<i32>) -> impl Iterator<Item = Vector3<i32>> {
+    let to_chunk = |v: Vector3<i32>| world_to_chunk(v.map(|x| x as f32 + 0.5));
+    let (a, b) = (to_chunk(min), to_chunk(max));
+    (a.x..=b.x).flat_map(move |x| {
+        (a.y..=b.y).flat_map(move |y| (a.z..=b.z).map(move |z| Vector3::new(x, y, z)))
+    })
+}
+
+/// Checks that a player at `pos`, who can see `view_distance` chunks, can edit the box of blocks from `min` to `max`, inclusive:
+/// that it's no bigger than `max_volume`, and every chunk it touches is loaded and close enough for them to see.
+fn check_edit(
+    world: &crate::world::World,
+    pos: Vector3<f32>,
+    view_distance: usize,
+    min: Vector3<i32>,
+    max: Vector3<i32>,
+    max_volume: usize,
+) -> Result<(), EditError> {
+    let volume = (max - min)
+        .iter()
+        .map(|&x| x as usize + 1)
+        .fold(1usize, |acc, x| acc.saturating_mul(x));
+    if volume > max_volume {
+        return Err(EditError::TooBig(volume));
+    }
+    for chunk in chunks_in(min, max) {
+        if !in_range(pos, view_distance, chunk) {
+            return Err(EditError::OutOfRange(chunk));
+        }
+        if !world.contains_chunk(chunk) {
+            return Err(EditError::NotLoaded(chunk));
+        }
+    }
+    Ok(())
+}
 
 struct Player {
     pos: Vector3<f32>,
+    /// How many chunks this player can see in every direction
+    view_distance: usize,
     conn: Rc<Connection>,
     id: usize,
+    /// Where the other players last heard this one was, or `None` if they haven't yet
+    sent_pos: Option<Vector3<f32>>,
+    /// What they're called, once they've said
+    name: Option<String>,
+    edits: EditBudget,
+    /// When we last told them, or logged, that an edit was dropped
+    last_rejection: Option<Instant>,
+}
+
+impl Player {
+    /// The local player is whoever's hosting, so their edits aren't limited
+    fn trusted(&self) -> bool {
+        matches!(*self.conn, Connection::Local(_, _))
+    }
+}
+
+/// Whether `chunk` is within `view_distance` chunks of `pos`
+fn in_range(pos: Vector3<f32>, view_distance: usize, chunk: Vector3<i32>) -> bool {
+    (world_to_chunk(pos) - chunk).map(|x| x as f32).norm() <= view_distance as f32
 }
 
 pub struct Server {
@@ -21,11 +150,29 @@ pub struct Server {
     orders: HashMap<Vector3<i32>, Vec<(usize, Rc<Connection>)>>,
     ch: (Sender<ChunkMessage>, Receiver<ChunkMessage>),
     config: Arc<GameConfig>,
+    /// New connections from the listener thread, if we're listening, and from `connector()`
+    incoming: (Sender<Connection>, Receiver<Connection>),
+    next_id: usize,
+    /// Players that asked for a save, who are waiting to hear that it's done
+    saving: Vec<Rc<Connection>>,
+    /// The world seed, once the chunk thread has told us
+    seed: Option<u64>,
+    /// Players that asked for the seed before we knew it
+    seed_waiting: Vec<Rc<Connection>>,
+    /// When we last told players where the others are
+    last_player_update: Instant,
+    /// The start of the time of day clock, which everyone's sky follows
+    started: Instant,
+    /// When we last told network players the time of day
+    last_time_update: Instant,
 }
 
 impl Server {
-    /// Creates and starts a chunk thread, and creates a Server
+    /// Creates and starts a chunk thread, and creates a Server.
+    /// This sets the chunk size for the process, so a local client needs to be using the same config.
     pub fn new(config: Arc<GameConfig>) -> Self {
+        set_chunk_size(config.chunk_size);
+        set_max_depth(config.max_depth);
         let (to, from_them) = channel();
         let (to_them, from) = channel();
         let c = Arc::clone(&config);
@@ -41,17 +188,70 @@ impl Server {
             orders: HashMap::new(),
             ch: (to, from),
             config,
+            incoming: channel(),
+            next_id: 0,
+            saving: Vec::new(),
+            seed: None,
+            seed_waiting: Vec::new(),
+            last_player_update: Instant::now(),
+            started: Instant::now(),
+            last_time_update: Instant::now(),
         }
     }
 
-    /// Add a player to the game
-    pub fn join(&mut self, conn: Connection, pos: Vector3<f32>) {
+    /// Starts accepting players over TCP on `address`, in addition to any local ones
+    pub fn listen(&mut self, address: impl ToSocketAddrs) -> std::io::Result<()> {
+        let listener = TcpListener::bind(address)?;
+        info!("Listening on {}", listener.local_addr()?);
+        let to = self.connector();
+        let config = Arc::clone(&self.config);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream.and_then(|s| Connection::tcp(s, &config)) {
+                    Ok(conn) => {
+                        if to.send(conn).is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => warn!("Failed to accept a connection: {}", e),
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Where to send connections for players that join like network ones do, hearing the chunk size first and starting out seeing nothing.
+    /// `listen()` sends the TCP ones here, and tests can send one end of `Connection::memory()`.
+    pub fn connector(&self) -> Sender<Connection> {
+        self.incoming.0.clone()
+    }
+
+    /// Add a player to the game, who wants to see `view_distance` chunks in every direction
+    pub fn join(&mut self, conn: Connection, pos: Vector3<f32>, view_distance: usize) {
+        let view_distance = view_distance.min(self.config.draw_chunks);
         let new_player = Player {
             pos,
+            view_distance,
             conn: Rc::new(conn),
-            id: self.players.len(),
+            id: self.next_id,
+            sent_pos: None,
+            name: None,
+            edits: EditBudget::new(Instant::now(), self.config.max_edits_per_second),
+            last_rejection: None,
         };
-        let (wait, load) = self.load_chunks_around(pos);
+        self.next_id += 1;
+        // Everyone else hears about them at the next update, but they need to know about everyone already here
+        for p in &self.players {
+            if let Some(pos) = p.sent_pos {
+                new_player.conn.send(Message::OtherPlayer(p.id as u32, pos));
+            }
+            if let Some(name) = &p.name {
+                new_player
+                    .conn
+                    .send(Message::PlayerName(p.id as u32, name.clone()));
+            }
+        }
+        let (wait, load) = self.load_chunks_around(pos, view_distance);
 
         for i in wait {
             self.orders
@@ -60,7 +260,7 @@ impl Server {
                 .push((new_player.id, Rc::clone(&new_player.conn)));
         }
         if !load.is_empty() {
-            new_player.conn.send(Message::Chunks(load)).unwrap();
+            new_player.conn.send(Message::Chunks(load));
         }
         self.players.push(new_player);
     }
@@ -69,6 +269,19 @@ impl Server {
     pub fn run(mut self) {
         let mut running = true;
         while running {
+            // Network players start out seeing nothing, and send their view distance right after connecting
+            let new: Vec<_> = self.incoming.1.try_iter().collect();
+            for conn in new {
+                info!("A player connected");
+                conn.send(Message::ChunkSize(chunk_size() as u32));
+                conn.send(Message::TimeOfDay(self.time_of_day()));
+                self.join(conn, Vector3::zeros(), 0);
+            }
+
+            let now = Instant::now();
+            let mut edited = Vec::new();
+            let mut left = Vec::new();
+            let mut named = Vec::new();
             let mut p = Vec::new();
             std::mem::swap(&mut p, &mut self.players);
             let mut change = false;
@@ -76,28 +289,84 @@ impl Server {
                 .into_iter()
                 .filter_map(|mut p| {
                     let mut np = p.pos;
+                    let mut nr = p.view_distance;
                     while let Some(m) = p.conn.recv() {
                         match m {
                             Message::PlayerMove(n_pos) => {
                                 np = n_pos;
                             }
+                            Message::ViewDistance(r) => {
+                                nr = r.min(self.config.draw_chunks);
+                            }
+                            Message::SetName(name) => {
+                                let name: String = name.chars().take(MAX_NAME_LEN).collect();
+                                info!("Player {} is called {}", p.id, name);
+                                named.push((p.id, name.clone()));
+                                p.name = Some(name);
+                            }
                             Message::Leave => match *p.conn {
                                 Connection::Local(_, _) => {
                                     running = false;
                                     break;
                                 }
-                                _ => return None,
+                                _ => {
+                                    info!("A player disconnected");
+                                    // Let go of the chunks they were using
+                                    self.load_chunk_diff(p.pos, p.pos, p.view_distance, 0);
+                                    left.push(p.id);
+                                    return None;
+                                }
+                            },
+                            Message::Save if !p.trusted() && !self.config.remote_save => {
+                                debug!("Player {} asked to save, but remote_save is off", p.id);
+                                p.conn.send(Message::SaveRefused);
+                            }
+                            Message::Save => {
+                                // The chunk thread does the saving, so the server keeps running while it writes
+                                if self.saving.is_empty() {
+                                    self.ch.0.send(ChunkMessage::Save).unwrap();
+                                }
+                                self.saving.push(Rc::clone(&p.conn));
+                            }
+                            Message::Ping(n) => {
+                                p.conn.send(Message::Pong(n));
+                            }
+                            Message::GetSeed => match self.seed {
+                                Some(seed) => {
+                                    p.conn.send(Message::Seed(seed));
+                                }
+                                None => self.seed_waiting.push(Rc::clone(&p.conn)),
                             },
-                            // Message::SetBlock(p, b) => {
-                            //     self.world
-                            //         .write()
-                            //         .unwrap()
-                            //         .set_block(p.map(|x| x as f32), b);
-                            // }
-                            _ => panic!("Hey, a client sent a message {:?}", m),
+                            Message::SetBlock(pos, mat) => {
+                                let v = pos.map(|x| x.floor() as i32);
+                                if self.allow_edit(&mut p, now, np, nr, v, v) {
+                                    self.world.write().unwrap().set_block(pos, mat);
+                                    edited.push(world_to_chunk(pos));
+                                }
+                            }
+                            Message::FillRegion(a, b, mat) => {
+                                let (min, max) = (a.zip_map(&b, i32::min), a.zip_map(&b, i32::max));
+                                if self.allow_edit(&mut p, now, np, nr, min, max) {
+                                    let mut world = self.world.write().unwrap();
+                                    for x in min.x..=max.x {
+                                        for y in min.y..=max.y {
+                                            for z in min.z..=max.z {
+                                                // The center of the voxel, so it can't round into the next one
+                                                let pos =
+                                                    Vector3::new(x, y, z).map(|x| x as f32 + 0.5);
+                                                world.set_block(pos, mat);
+                                            }
+                                        }
+                                    }
+                                    edited.extend(chunks_in(min, max));
+                                }
+                            }
+                            _ => {
+                                warn!("Hey, a client sent a message {:?}", m);
+                            }
                         }
                     }
-                    let (wait, load) = self.load_chunk_diff(p.pos, np);
+                    let (wait, load) = self.load_chunk_diff(p.pos, np, p.view_distance, nr);
                     //p.to_send.append(&mut wait);
                     if !change && (!wait.is_empty() || !load.is_empty()) {
                         change = true;
@@ -109,22 +378,46 @@ impl Server {
                             .push((p.id, Rc::clone(&p.conn)));
                     }
                     if !load.is_empty() {
-                        p.conn.send(Message::Chunks(load)).unwrap();
+                        p.conn.send(Message::Chunks(load));
                     }
                     p.pos = np;
+                    p.view_distance = nr;
                     Some(p)
                 })
                 .collect();
 
+            edited.sort_by_key(|x| (x.x, x.y, x.z));
+            edited.dedup();
+            self.send_updates(edited);
+
+            for id in left {
+                for p in &self.players {
+                    p.conn.send(Message::PlayerLeft(id as u32));
+                }
+            }
+            for (id, name) in named {
+                for p in self.players.iter().filter(|p| p.id != id) {
+                    p.conn.send(Message::PlayerName(id as u32, name.clone()));
+                }
+            }
+            if self.last_player_update.elapsed() >= PLAYER_UPDATE_INTERVAL {
+                self.last_player_update = Instant::now();
+                self.send_player_positions();
+            }
+            if self.last_time_update.elapsed() >= TIME_UPDATE_INTERVAL {
+                self.last_time_update = Instant::now();
+                self.send_time_of_day();
+            }
+
             if change {
-                let p: Vec<Vector3<f32>> = self.players.iter().map(|x| x.pos).collect();
-                let p2: Vec<_> = p.iter().map(|x| world_to_chunk(*x)).collect();
+                let p: Vec<_> = self
+                    .players
+                    .iter()
+                    .map(|x| (x.pos, x.view_distance))
+                    .collect();
                 let keys: Vec<_> = self.orders.keys().cloned().collect();
                 for k in keys {
-                    if !p2
-                        .iter()
-                        .any(|y| (y - k).map(|x| x as f32).norm() <= self.config.draw_chunks as f32)
-                    {
+                    if !p.iter().any(|&(pos, r)| in_range(pos, r, k)) {
                         self.orders.remove(&k);
                     }
                 }
@@ -148,7 +441,7 @@ impl Server {
                                                 .push((*i, c.clone()));
                                         }
                                     } else {
-                                        println!("WARNING: chunk thread told us it's loaded, but it isn't!");
+                                        warn!("Chunk thread told us it's loaded, but it isn't!");
                                     }
                                 }
                             }
@@ -158,34 +451,23 @@ impl Server {
                             conn.send(Message::Chunks(v));
                         }
                     }
-                    ChunkMessage::UpdateChunks(v) => {
-                        let mut batches = HashMap::new();
-                        for i in v {
-                            for p in &self.players {
-                                if (world_to_chunk(p.pos) - i).map(|x| x as f32).norm()
-                                    <= self.config.draw_chunks as f32
-                                {
-                                    batches
-                                        .entry(p.id)
-                                        .or_insert((p.conn.clone(), Vec::new()))
-                                        .1
-                                        .push(i);
-                                }
-                            }
+                    ChunkMessage::UpdateChunks(v) => self.send_updates(v),
+                    ChunkMessage::Saved(n) => {
+                        info!("Saved {} chunks", n);
+                        for conn in self.saving.drain(..) {
+                            conn.send(Message::Saved(n));
                         }
-                        let world = self.world.read().unwrap();
-                        for (_, (conn, v)) in batches {
-                            conn.send(Message::Chunks(
-                                v.into_iter()
-                                    .filter_map(|x| world.chunks.get(&x).cloned().map(|y| (x, y)))
-                                    .collect(),
-                            ))
-                            .unwrap();
+                    }
+                    ChunkMessage::Seed(seed) => {
+                        self.seed = Some(seed);
+                        for conn in self.seed_waiting.drain(..) {
+                            conn.send(Message::Seed(seed));
                         }
                     }
                     _ => panic!("Chunk thread sent {:?}", m),
                 }
             }
+            thread::sleep(TICK_SLEEP);
         }
         self.unload_all();
         for p in self.players {
@@ -193,6 +475,111 @@ impl Server {
         }
     }
 
+    /// Whether player `p` can edit the box from `min` to `max`, inclusive, at `now`, where `pos` and `view_distance`
+    /// are theirs as of this message. If they can't, it's logged and they're told, at most once every `REJECTION_INTERVAL`.
+    /// Anything not loaded is dropped even for the local player, since there's nothing there to edit.
+    fn allow_edit(
+        &self,
+        p: &mut Player,
+        now: Instant,
+        pos: Vector3<f32>,
+        view_distance: usize,
+        min: Vector3<i32>,
+        max: Vector3<i32>,
+    ) -> bool {
+        let world = self.world.read().unwrap();
+        let result = if p.trusted() {
+            match chunks_in(min, max).find(|&c| !world.contains_chunk(c)) {
+                Some(c) => Err(EditError::NotLoaded(c)),
+                None => Ok(()),
+            }
+        } else {
+            check_edit(
+                &world,
+                pos,
+                view_distance,
+                min,
+                max,
+                self.config.max_fill_volume,
+            )
+            .and_then(|()| p.edits.take(now, self.config.max_edits_per_second))
+        };
+        let e = match result {
+            Ok(()) => return true,
+            Err(e) => e,
+        };
+        if p.last_rejection
+            .map_or(true, |t| now - t >= REJECTION_INTERVAL)
+        {
+            p.last_rejection = Some(now);
+            warn!("Dropped an edit from player {}: {}", p.id, e);
+            if self.config.warn_rejected_edits && !p.trusted() {
+                p.conn.send(Message::EditRejected(e.to_string()));
+            }
+        } else {
+            debug!("Dropped an edit from player {}: {}", p.id, e);
+        }
+        false
+    }
+
+    /// Seconds since the server started, which is what the sun follows
+    fn time_of_day(&self) -> f64 {
+        self.started.elapsed().as_secs_f64()
+    }
+
+    /// Tells network players the time of day. A local player started with the server, so its clock already matches.
+    fn send_time_of_day(&self) {
+        let time = self.time_of_day();
+        for p in &self.players {
+            if let Connection::Local(_, _) = *p.conn {
+                continue;
+            }
+            p.conn.send(Message::TimeOfDay(time));
+        }
+    }
+
+    /// Tells everyone where each player that moved since the last time is
+    fn send_player_positions(&mut self) {
+        let moved: Vec<_> = self
+            .players
+            .iter_mut()
+            .filter(|p| p.sent_pos != Some(p.pos))
+            .map(|p| {
+                p.sent_pos = Some(p.pos);
+                (p.id, p.pos)
+            })
+            .collect();
+        for (id, pos) in moved {
+            for p in self.players.iter().filter(|p| p.id != id) {
+                p.conn.send(Message::OtherPlayer(id as u32, pos));
+            }
+        }
+    }
+
+    /// Sends the current version of each of these chunks to every player that can see it
+    fn send_updates(&self, chunks: Vec<Vector3<i32>>) {
+        let mut batches = HashMap::new();
+        for i in chunks {
+            for p in &self.players {
+                if in_range(p.pos, p.view_distance, i) {
+                    batches
+                        .entry(p.id)
+                        .or_insert((p.conn.clone(), Vec::new()))
+                        .1
+                        .push(i);
+                }
+            }
+        }
+        let world = self.world.read().unwrap();
+        for (_, (conn, v)) in batches {
+            conn.send(Message::Chunks(
+                v.into_iter()
+                    .filter_map(|x| world.chunks.get(&x).cloned().map(|y| (x, y)))
+                    .collect(),
+            ));
+        }
+    }
+
     fn unload_all(&mut self) {
         let mut m = HashMap::new();
         std::mem::swap(&mut self.world.write().unwrap().chunks, &mut m);
@@ -210,24 +597,26 @@ impl Server {
         }
     }
 
-    /// Loads initial chunks around a player
+    /// Loads initial chunks within `view_distance` of a player
     /// Returns `(chunks_to_wait_for, chunks_already_loaded)`
     /// Doesn't update `orders`
     fn load_chunks_around(
         &mut self,
         pos: Vector3<f32>,
+        view_distance: usize,
     ) -> (Vec<Vector3<i32>>, Vec<(Vector3<i32>, Chunk)>) {
         let chunk_pos = world_to_chunk(pos);
 
         let mut to_load = Vec::new();
 
-        let draw_chunks = self.config.draw_chunks as i32;
+        let draw_chunks = view_distance as i32;
 
-        for x in -draw_chunks..draw_chunks {
-            for y in -draw_chunks..draw_chunks {
-                for z in -draw_chunks..draw_chunks {
+        for x in -draw_chunks..=draw_chunks {
+            for y in -draw_chunks..=draw_chunks {
+                for z in -draw_chunks..=draw_chunks {
                     let p = Vector3::new(x, y, z);
-                    if p.map(|x| x as f32).norm() <= self.config.draw_chunks as f32 {
+                    // A view distance of zero means no chunks at all, not just the one we're in
+                    if view_distance > 0 && p.map(|x| x as f32).norm() <= view_distance as f32 {
                         to_load.push(p);
                     }
                 }
@@ -265,30 +654,37 @@ impl Server {
     }
 
     /// Figures out what chunks need to be loaded, and either returns them or sends them to the chunk thread
+    /// This handles both moving and changing view distance, from `old_r` to `new_r`
     /// Returns `(chunks_to_wait_for, chunks_already_loaded)`
     /// Doesn't update `orders`
     fn load_chunk_diff(
         &mut self,
         old: Vector3<f32>,
         new: Vector3<f32>,
+        old_r: usize,
+        new_r: usize,
     ) -> (Vec<Vector3<i32>>, Vec<(Vector3<i32>, Chunk)>) {
         let chunk_old = world_to_chunk(old);
         let chunk_new = world_to_chunk(new);
 
-        if chunk_old == chunk_new {
+        if chunk_old == chunk_new && old_r == new_r {
             return (Vec::new(), Vec::new());
         }
 
         let mut around_old = HashSet::new();
         let mut around_new = HashSet::new();
-        let draw_chunks = self.config.draw_chunks as i32;
+        let draw_chunks = old_r.max(new_r) as i32;
 
-        for x in -draw_chunks..draw_chunks {
-            for y in -draw_chunks..draw_chunks {
-                for z in -draw_chunks..draw_chunks {
+        for x in -draw_chunks..=draw_chunks {
+            for y in -draw_chunks..=draw_chunks {
+                for z in -draw_chunks..=draw_chunks {
                     let p = Vector3::new(x, y, z);
-                    if p.map(|x| x as f32).norm() <= self.config.draw_chunks as f32 {
+                    let d = p.map(|x| x as f32).norm();
+                    // A view distance of zero means no chunks at all, like in `load_chunks_around()`
+                    if old_r > 0 && d <= old_r as f32 {
                         around_old.insert(chunk_old + p);
+                    }
+                    if new_r > 0 && d <= new_r as f32 {
                         around_new.insert(chunk_new + p);
                     }
                 }
@@ -350,3 +746,79 @@ impl Server {
         (to_send, to_pass)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::octree::Chunk;
+
+    /// A world with every chunk within a couple of chunks of the origin loaded
+    fn world() -> crate::world::World {
+        let mut world = crate::world::World::new();
+        for x in -2..=2 {
+            for y in -2..=2 {
+                for z in -2..=2 {
+                    world.add_chunk(Vector3::new(x, y, z), Chunk::empty());
+                }
+            }
+        }
+        world
+    }
+
+    #[test]
+    fn checks_edits() {
+        let world = world();
+        let here = Vector3::zeros();
+        let v = Vector3::new(1, 2, 3);
+        assert_eq!(check_edit(&world, here, 2, v, v, 1), Ok(()));
+        // A fill across a chunk border touches both
+        let size = chunk_size() as i32;
+        let (a, b) = (Vector3::new(-1, 0, 0), Vector3::new(1, 0, 0));
+        assert_eq!(chunks_in(a, b).count(), 2);
+        assert_eq!(check_edit(&world, here, 2, a, b, 3), Ok(()));
+
+        // Too big
+        assert_eq!(
+            check_edit(&world, here, 2, a, b, 2),
+            Err(EditError::TooBig(3))
+        );
+        // Loaded, but farther than they can see
+        let far = Vector3::new(2 * size, 0, 0);
+        assert_eq!(
+            check_edit(&world, here, 1, far, far, 1),
+            Err(EditError::OutOfRange(Vector3::new(2, 0, 0)))
+        );
+        // Close enough, but not loaded
+        let mut world = world;
+        world.remove_chunk(Vector3::new(0, 1, 0));
+        let above = Vector3::new(0, size, 0);
+        assert_eq!(
+            check_edit(&world, here, 2, above, above, 1),
+            Err(EditError::NotLoaded(Vector3::new(0, 1, 0)))
+        );
+    }
+
+    #[test]
+    fn limits_edit_rate() {
+        let start = Instant::now();
+        let mut budget = EditBudget::new(start, 10);
+        // A second's worth at once is fine
+        for _ in 0..10 {
+            assert_eq!(budget.take(start, 10), Ok(()));
+        }
+        assert_eq!(budget.take(start, 10), Err(EditError::TooFast));
+        // And it fills back up at the rate
+        let later = start + Duration::from_millis(250);
+        assert_eq!(budget.take(later, 10), Ok(()));
+        assert_eq!(budget.take(later, 10), Ok(()));
+        assert_eq!(budget.take(later, 10), Err(EditError::TooFast));
+        // But it doesn't save up more than a second's worth
+        let much_later = start + Duration::from_secs(60);
+        for _ in 0..10 {
+            assert_eq!(budget.take(much_later, 10), Ok(()));
+        }
+        assert_eq!(budget.take(much_later, 10), Err(EditError::TooFast));
+        // 0 is no limit
+        assert_eq!(budget.take(much_later, 0), Ok(()));
+    }
+}
@@ -71,12 +71,38 @@ float ao(in vec3 vos, in vec3 nor, in vec3 pos) {
     return occ;
 }
 
+// The AO baked into `node` for the face with normal `n`, or -1 if it wasn't baked
+float baked_ao(in uint node, in vec3 n) {
+    if ((node & AO_BAKED) == 0u) return -1.0;
+    uint face = n.x != 0.0 ? (n.x < 0.0 ? 0u : 1u)
+              : n.y != 0.0 ? (n.y < 0.0 ? 2u : 3u)
+              : (n.z < 0.0 ? 4u : 5u);
+    float level = float((node >> (AO_SHIFT + 2u * face)) & 3u);
+    // Same falloff as `ao()`, with the level standing in for the average occlusion over the face
+    float occ = 1.0 - level/8.0;
+    occ = occ*occ;
+    occ = occ*occ;
+    return occ;
+}
+
 #if SHADOWS
 float shadow(in vec3 p, in vec3 sun_dir, in vec3 normal) {
 	vec2 t;
 	int i = SHADOW_ITERS;
 	vec3 pos;
-	return float(trace(p+normal*0.01, sun_dir, t, i, pos) == 0);
+	// The penumbra is as wide as the sun looks from the occluder, so this is 1 / tan(angular radius)
+	penumbra_k = sun_angular_size > 0.0 ? 1.0 / tan(sun_angular_size * 0.5) : 0.0;
+	penumbra = 1.0;
+	bool hit = trace(p+normal*0.01, sun_dir, t, i, pos) != 0;
+	penumbra_k = 0.0;
+	return hit ? 0.0 : smoothstep(0.0, 1.0, penumbra);
+}
+
+// For `shadow_mode` 1: the shadow baked into `node` for the face with normal `n`, instead of tracing a ray.
+// There's only a bit for the face on the sun's side of each axis, but the others face away from the sun and aren't lit anyway.
+float baked_shadow(in uint node, in vec3 n) {
+	uint axis = n.x != 0.0 ? 0u : n.y != 0.0 ? 1u : 2u;
+	return ((node >> (SHADOW_SHIFT + axis)) & 1u) != 0u ? 0.0 : 1.0;
 }
 #endif
 
@@ -92,13 +118,24 @@ vec3 applyFog( in vec3 rgb, // original color of the pixel
 
     float fogAmount = c * exp(-rayOri.y*b) * (1.0-exp( -dist*rayDir.y*b ))/rayDir.y;
     float sunAmount = max( dot( rayDir, sunDir ), 0.0 );
-    vec3 fogColor = mix( vec3(0.5,0.6,0.7), // bluish
+    vec3 fogColor = mix( draw_sky != 0u ? horizon_color() : background, // the sky at the horizon, so distant terrain fades into it
         vec3(1.0,0.9,0.7), // yellowish
         pow(sunAmount,8.0) );
     return mix( rgb, fogColor, fogAmount );
 }
 
-vec3 shade(in vec3 ro, in vec3 rd, in vec2 t, in vec3 pos, in MatData mat) {
+// How quickly things fade out when the camera is inside a transparent material like water
+#define UNDERWATER_DENSITY 0.15
+
+// Tints and washes out a pixel `dist` away, when the camera is inside a transparent material with color `tint`
+vec3 underwater(in vec3 col, in float dist, in vec3 tint) {
+    vec3 murk = tint * 0.5 * day_light();
+    // Lose some contrast even right up close
+    col = mix(col, murk, 0.2) * tint * 1.5;
+    return mix(murk, col, exp(-dist * UNDERWATER_DENSITY));
+}
+
+vec3 shade(in vec3 ro, in vec3 rd, in vec2 t, in vec3 pos, in MatData mat, in uint node) {
     vec3 p = ro+rd*t.x;
     vec3 n = p-pos;
 
@@ -111,12 +148,14 @@ vec3 shade(in vec3 ro, in vec3 rd, in vec2 t, in vec3 pos, in MatData mat) {
 #endif
 
 #if SHADOWS
-		float sha = shadow(p, sun_dir, n);
+		float sha = shadow_mode == 1u ? baked_shadow(node, n) : shadow(p, sun_dir, n);
 #else
 	  float sha = 1.0;
 #endif
 
-    float occ = ao(floor(p-0.1*n), n, p);
+    float occ = baked_ao(node, n);
+    if (occ < 0.0)
+        occ = ao(floor(p-0.1*n), n, p);
 
 		vec3 sun_color = pow(vec3(0.7031,0.4687,0.1055), vec3(1.0 / 4.2));
 		vec3 sky_color = pow(vec3(0.3984,0.5117,0.7305), vec3(1.0 / 4.2));
@@ -1,3 +1,13 @@
+use std::ffi::CStr;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+use vulkano::descriptor::descriptor::ShaderStages;
+use vulkano::descriptor::pipeline_layout::PipelineLayoutDesc;
+use vulkano::device::Device;
+use vulkano::pipeline::shader::{GraphicsEntryPoint, GraphicsShaderType, ShaderModule};
+use vulkano::OomError;
+
 mod vs {
     vulkano_shaders::shader! {
         ty: "vertex",
@@ -19,9 +29,237 @@ mod beam {
     }
 }
 
+mod accum {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        path: "src/accum.frag"
+    }
+}
+
+mod bloom {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        path: "src/bloom.frag"
+    }
+}
+
+mod post {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        path: "src/post.frag"
+    }
+}
+
+mod marker_vs {
+    vulkano_shaders::shader! {
+        ty: "vertex",
+        path: "src/marker.vert"
+    }
+}
+
+mod marker_fs {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        path: "src/marker.frag"
+    }
+}
+
+mod ghost_fs {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        path: "src/ghost.frag"
+    }
+}
+
+mod text_vs {
+    vulkano_shaders::shader! {
+        ty: "vertex",
+        path: "src/text.vert"
+    }
+}
+
+mod overlay_vs {
+    vulkano_shaders::shader! {
+        ty: "vertex",
+        path: "src/overlay.vert"
+    }
+}
+
+mod text_fs {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        path: "src/text.frag"
+    }
+}
+
+pub use accum::ty::PushConstants as AccumConstants;
+pub use accum::Shader as Accum;
 pub use beam::ty::PushConstants as BeamConstants;
 pub use beam::Shader as Beam;
+pub use bloom::ty::PushConstants as BloomConstants;
+pub use bloom::Shader as Bloom;
+pub use fs::ty::FrameData;
 pub use fs::ty::MatData;
 pub use fs::ty::PushConstants;
+pub use fs::ty::Settings;
 pub use fs::Shader as Fragment;
+pub use ghost_fs::Shader as GhostFragment;
+pub use marker_fs::Shader as MarkerFragment;
+pub use marker_vs::ty::PushConstants as MarkerConstants;
+pub use marker_vs::Shader as MarkerVertex;
+pub use overlay_vs::ty::PushConstants as OverlayConstants;
+pub use overlay_vs::Shader as OverlayVertex;
+pub use post::ty::PushConstants as PostConstants;
+pub use post::Shader as Post;
+pub use text_fs::Shader as TextFragment;
+pub use text_vs::ty::PushConstants as TextConstants;
+pub use text_vs::Shader as TextVertex;
 pub use vs::Shader as Vertex;
+
+/// A shader that couldn't be loaded, and why
+#[derive(Debug)]
+pub struct ShaderError {
+    /// The file it's compiled from, like `main.frag`
+    pub shader: &'static str,
+    pub reason: String,
+}
+
+impl fmt::Display for ShaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "couldn't load the {} shader: {}",
+            self.shader, self.reason
+        )
+    }
+}
+
+impl std::error::Error for ShaderError {}
+
+/// Loads a built-in shader, like `load("post.frag", || Post::load(device.clone()))`, saying which one it was if it fails
+pub fn load<S>(
+    shader: &'static str,
+    load: impl FnOnce() -> Result<S, OomError>,
+) -> Result<S, ShaderError> {
+    load().map_err(|e| ShaderError {
+        shader,
+        reason: e.to_string(),
+    })
+}
+
+/// Checks that `spirv` can go where a built-in fragment shader with this `layout` goes: it needs a `main` for fragments,
+/// it can only use descriptors the built-in one has, and it can't read more push constants than the built-in one gets.
+/// The pipeline is made with the built-in layout either way, so that's all that's bound and pushed.
+fn check_layout(spirv: &[u8], layout: &impl PipelineLayoutDesc) -> Result<(), String> {
+    let r = crate::spirv::reflect(spirv)?;
+    if !r
+        .entry_points
+        .iter()
+        .any(|(model, name)| *model == crate::spirv::FRAGMENT && name == "main")
+    {
+        return Err("it doesn't have a fragment shader called main".to_string());
+    }
+    for &(set, binding) in &r.bindings {
+        if layout.descriptor(set as usize, binding as usize).is_none() {
+            return Err(format!(
+                "it uses binding {} in set {}, which the built-in one doesn't have",
+                binding, set
+            ));
+        }
+    }
+    let pushed = (0..layout.num_push_constants_ranges())
+        .filter_map(|i| layout.push_constants_range(i))
+        .map(|r| r.offset + r.size)
+        .max()
+        .unwrap_or(0);
+    match r.push_constants {
+        Some(size) if size as usize > pushed => Err(format!(
+            "it reads {} bytes of push constants, but the built-in one only gets {}",
+            size, pushed
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Reads a compiled shader from `path` and makes a module of it, if `check_layout()` says it fits
+fn load_override(
+    device: &Arc<Device>,
+    path: &Path,
+    layout: &impl PipelineLayoutDesc,
+) -> Result<Arc<ShaderModule>, String> {
+    let spirv = std::fs::read(path).map_err(|e| e.to_string())?;
+    check_layout(&spirv, layout)?;
+    // Safe as long as it's valid SPIR-V, which is as far as we can check without a validator
+    unsafe { ShaderModule::new(device.clone(), &spirv) }.map_err(|e| e.to_string())
+}
+
+fn fragment_stages() -> ShaderStages {
+    ShaderStages {
+        fragment: true,
+        ..ShaderStages::none()
+    }
+}
+
+/// Fragment shaders that can be replaced with a compiled one from `shader_dir` in the config, for modding how the world's drawn.
+/// The replacement is `<file>.spv` in that folder. It has to have the same inputs and outputs as the built-in one,
+/// and fit its layout, see `check_layout()`; if it doesn't, or it can't be read, the built-in one is used with a warning.
+macro_rules! overridable {
+    ($name:ident, $module:ident, $file:expr, $doc:expr) => {
+        #[doc = $doc]
+        pub struct $name(Arc<ShaderModule>);
+
+        impl $name {
+            pub fn load(device: &Arc<Device>, dir: Option<&Path>) -> Result<Self, ShaderError> {
+                if let Some(dir) = dir {
+                    let path = dir.join(concat!($file, ".spv"));
+                    if path.exists() {
+                        let layout = $module::Layout(fragment_stages());
+                        match load_override(device, &path, &layout) {
+                            Ok(module) => {
+                                info!("Using {} instead of the built-in {}", path.display(), $file);
+                                return Ok($name(module));
+                            }
+                            Err(e) => warn!(
+                                "Couldn't use {}, using the built-in {} instead: {}",
+                                path.display(),
+                                $file,
+                                e
+                            ),
+                        }
+                    }
+                }
+                let shader = load($file, || $module::Shader::load(device.clone()))?;
+                Ok($name(shader.module().clone()))
+            }
+
+            pub fn main_entry_point(
+                &self,
+            ) -> GraphicsEntryPoint<(), $module::MainInput, $module::MainOutput, $module::Layout>
+            {
+                // The same as the built-in one's, which the replacement was checked against
+                unsafe {
+                    self.0.graphics_entry_point(
+                        CStr::from_bytes_with_nul_unchecked(b"main\0"),
+                        $module::MainInput,
+                        $module::MainOutput,
+                        $module::Layout(fragment_stages()),
+                        GraphicsShaderType::Fragment,
+                    )
+                }
+            }
+        }
+    };
+}
+
+overridable!(
+    RaymarchShader,
+    fs,
+    "main.frag",
+    "The main shader, which raymarches the octree, or its replacement from `shader_dir`"
+);
+overridable!(
+    BeamShader,
+    beam,
+    "beam.frag",
+    "The beam pass, which raymarches at a lower resolution so the main shader can start farther along, or its replacement from `shader_dir`"
+);
@@ -1,22 +1,26 @@
-float isect(vec3 ro, vec3 rd, vec3 p, float size) {
-    vec3 mn = p - size;
-    vec3 mx = p + size;
-    vec3 t1 = (mn-ro) / rd;
-    vec3 t2 = (mx-ro) / rd;
-    vec3 tmin = min(t1, t2);
-    vec3 tmax = max(t1, t2);
-	  vec2 t = vec2(max(tmin.x, max(tmin.y, tmin.z)), min(tmax.x, min(tmax.y, tmax.z)));
-    return (t.y > t.x) && (t.y > 0.0) ? clamp((t.y-t.x) * 0.05, 0.0, 1.0) : 0.0;
+// How bright the sky is, from night to full day
+float day_light() {
+    return mix(0.05, 1.0, smoothstep(-0.2, 0.2, sun_dir.y));
+}
+
+// The sky color right at the horizon, which fog should blend towards
+vec3 horizon_color() {
+    return sky_horizon * day_light();
 }
 
 vec3 sky(vec3 ro, vec3 rd)
 {
-    float sun = isect(ro, rd, ro + sun_dir * 1000.0, 40.0);
-    sun = sun * length(sun) * 10.0;
-    sun += 0.5 * isect(ro, rd, ro + sun_dir * 1000.0, 80.0);
+    // Vertical gradient from the horizon to the zenith. Below the horizon is just horizon color.
+    vec3 col = mix(horizon_color(), sky_zenith * day_light(), pow(saturate(rd.y), 0.5));
+
+    // The sun gets redder closer to the horizon
+    vec3 sun_color = pow(vec3(0.7031,0.4687,0.1055), vec3(1.2) * (2.0 - sun_dir.y));
 
-    vec3 col = vec3(sun) * pow(vec3(0.7031,0.4687,0.1055), vec3(1.2) * (2.0 - sun_dir.y))
-		+ 0.8 * vec3(0.3984,0.5117,0.7305) * ((0.5 + 1.0 * pow(sun_dir.y,0.4)) * (1.2-0.7*dot(vec3(0,1,0), rd)));
+    // Sun disk, with a soft glow around it. `sun_size` is the angular radius.
+    float d = dot(rd, sun_dir);
+    float disk = smoothstep(cos(sun_size), cos(sun_size * 0.8), d);
+    float glow = 0.5 * pow(saturate(d), 64.0);
+    col += (disk * sun_intensity + glow) * sun_color;
 
     return col;
 }
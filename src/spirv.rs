@@ -0,0 +1,273 @@
+//! Just enough of reading SPIR-V to check that a shader from `shader_dir` fits where the built-in one goes:
+//! its entry points, which descriptors it uses, and how many bytes of push constants it reads.
+//! See the SPIR-V spec for the numbers; only the instructions and decorations that matter for that are looked at.
+use std::collections::HashMap;
+
+const MAGIC: u32 = 0x0723_0203;
+/// The execution model of fragment shaders in `OpEntryPoint`
+pub const FRAGMENT: u32 = 4;
+
+const OP_ENTRY_POINT: u32 = 15;
+const OP_TYPE_INT: u32 = 21;
+const OP_TYPE_FLOAT: u32 = 22;
+const OP_TYPE_VECTOR: u32 = 23;
+const OP_TYPE_MATRIX: u32 = 24;
+const OP_TYPE_ARRAY: u32 = 28;
+const OP_TYPE_STRUCT: u32 = 30;
+const OP_TYPE_POINTER: u32 = 32;
+const OP_CONSTANT: u32 = 43;
+const OP_VARIABLE: u32 = 59;
+const OP_DECORATE: u32 = 71;
+const OP_MEMBER_DECORATE: u32 = 72;
+
+const ARRAY_STRIDE: u32 = 6;
+const BINDING: u32 = 33;
+const DESCRIPTOR_SET: u32 = 34;
+const OFFSET: u32 = 35;
+
+const PUSH_CONSTANT: u32 = 9;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Reflection {
+    /// The execution model of each entry point, like `FRAGMENT`, and its name
+    pub entry_points: Vec<(u32, String)>,
+    /// The `(set, binding)` of each descriptor it declares, sorted
+    pub bindings: Vec<(u32, u32)>,
+    /// How far into the push constants it reads, in bytes, which is the end of the last member of the block.
+    /// `None` if it doesn't have any.
+    pub push_constants: Option<u32>,
+}
+
+enum Type {
+    /// Ints and floats, with their size in bytes
+    Scalar(u32),
+    /// The component type and how many there are, which is also how matrices are made of columns
+    Vector(u32, u32),
+    /// The element type and the id of the constant that's its length
+    Array(u32, u32),
+    /// The type of each member
+    Struct(Vec<u32>),
+}
+
+/// The types declared in a module, and what's needed to work out how big they are
+#[derive(Default)]
+struct Types {
+    types: HashMap<u32, Type>,
+    constants: HashMap<u32, u32>,
+    /// Each decoration with a value, by the id it's on and which one it is
+    decorations: HashMap<(u32, u32), u32>,
+    /// Where each member of a struct starts, by the struct's id and the member's index
+    offsets: HashMap<(u32, u32), u32>,
+}
+
+impl Types {
+    /// How many bytes of a block type `id` takes, going by the offsets and strides it's decorated with
+    fn size(&self, id: u32) -> Result<u32, String> {
+        let unknown = || {
+            format!(
+                "it has push constants of a type we can't work out the size of (%{})",
+                id
+            )
+        };
+        Ok(match self.types.get(&id).ok_or_else(unknown)? {
+            Type::Scalar(size) => *size,
+            Type::Vector(component, n) => self.size(*component)? * n,
+            Type::Array(element, length) => {
+                let length = *self.constants.get(length).ok_or_else(unknown)?;
+                let stride = match self.decorations.get(&(id, ARRAY_STRIDE)) {
+                    Some(&stride) => stride,
+                    None => self.size(*element)?,
+                };
+                stride * length
+            }
+            Type::Struct(members) => {
+                let mut end = 0;
+                for (i, &member) in members.iter().enumerate() {
+                    let offset = *self.offsets.get(&(id, i as u32)).ok_or_else(unknown)?;
+                    end = end.max(offset + self.size(member)?);
+                }
+                end
+            }
+        })
+    }
+}
+
+/// The words of a SPIR-V module, in either byte order
+fn words(bytes: &[u8]) -> Result<Vec<u32>, String> {
+    if bytes.len() % 4 != 0 || bytes.len() < 20 {
+        return Err("it isn't SPIR-V, it's not a whole header of 4-byte words".to_string());
+    }
+    let mut words: Vec<u32> = bytes
+        .chunks(4)
+        .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+        .collect();
+    if words[0] == MAGIC.swap_bytes() {
+        for w in &mut words {
+            *w = w.swap_bytes();
+        }
+    } else if words[0] != MAGIC {
+        return Err("it isn't SPIR-V, it doesn't start with the magic number".to_string());
+    }
+    Ok(words)
+}
+
+/// A null-terminated string packed into words, like entry point names
+fn string(words: &[u32]) -> String {
+    let bytes: Vec<u8> = words
+        .iter()
+        .flat_map(|w| w.to_le_bytes().to_vec())
+        .take_while(|&b| b != 0)
+        .collect();
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Reads the entry points, descriptors and push constants out of a SPIR-V module
+pub fn reflect(bytes: &[u8]) -> Result<Reflection, String> {
+    let words = words(bytes)?;
+    let mut r = Reflection::default();
+    let mut t = Types::default();
+    let mut pointers = HashMap::new();
+    let mut variables = Vec::new();
+    // After the header
+    let mut i = 5;
+    while i < words.len() {
+        let count = (words[i] >> 16) as usize;
+        let op = words[i] & 0xffff;
+        if count == 0 || i + count > words.len() {
+            return Err(format!("the instruction at word {} runs off the end", i));
+        }
+        let a = &words[i + 1..i + count];
+        match op {
+            OP_ENTRY_POINT if a.len() >= 3 => r.entry_points.push((a[0], string(&a[2..]))),
+            OP_TYPE_INT | OP_TYPE_FLOAT if a.len() >= 2 => {
+                t.types.insert(a[0], Type::Scalar(a[1] / 8));
+            }
+            OP_TYPE_VECTOR | OP_TYPE_MATRIX if a.len() >= 3 => {
+                t.types.insert(a[0], Type::Vector(a[1], a[2]));
+            }
+            OP_TYPE_ARRAY if a.len() >= 3 => {
+                t.types.insert(a[0], Type::Array(a[1], a[2]));
+            }
+            OP_TYPE_STRUCT if !a.is_empty() => {
+                t.types.insert(a[0], Type::Struct(a[1..].to_vec()));
+            }
+            OP_TYPE_POINTER if a.len() >= 3 => {
+                pointers.insert(a[0], a[2]);
+            }
+            OP_CONSTANT if a.len() >= 3 => {
+                t.constants.insert(a[1], a[2]);
+            }
+            OP_VARIABLE if a.len() >= 3 => variables.push((a[0], a[1], a[2])),
+            OP_DECORATE if a.len() >= 3 => {
+                t.decorations.insert((a[0], a[1]), a[2]);
+            }
+            OP_MEMBER_DECORATE if a.len() >= 4 && a[2] == OFFSET => {
+                t.offsets.insert((a[0], a[1]), a[3]);
+            }
+            _ => (),
+        }
+        i += count;
+    }
+
+    for (ty, id, storage) in variables {
+        let set = t.decorations.get(&(id, DESCRIPTOR_SET));
+        let binding = t.decorations.get(&(id, BINDING));
+        if let (Some(&set), Some(&binding)) = (set, binding) {
+            r.bindings.push((set, binding));
+        }
+        if storage == PUSH_CONSTANT {
+            let block = *pointers
+                .get(&ty)
+                .ok_or("its push constants aren't declared through a pointer")?;
+            let size = t.size(block)?;
+            r.push_constants = Some(r.push_constants.unwrap_or(0).max(size));
+        }
+    }
+    r.bindings.sort_unstable();
+    r.bindings.dedup();
+    Ok(r)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inst(op: u32, operands: &[u32]) -> Vec<u32> {
+        let mut v = vec![((operands.len() as u32 + 1) << 16) | op];
+        v.extend_from_slice(operands);
+        v
+    }
+
+    /// A fragment shader with push constants `{ mat4; vec4; vec4[3] }` and a uniform buffer at set 0, binding 3
+    fn module() -> Vec<u32> {
+        let main = u32::from_le_bytes(*b"main");
+        let mut w = vec![MAGIC, 0x0001_0000, 0, 20, 0];
+        w.extend(inst(OP_ENTRY_POINT, &[FRAGMENT, 1, main, 0]));
+        w.extend(inst(OP_DECORATE, &[13, ARRAY_STRIDE, 16]));
+        w.extend(inst(OP_MEMBER_DECORATE, &[5, 0, OFFSET, 0]));
+        w.extend(inst(OP_MEMBER_DECORATE, &[5, 1, OFFSET, 64]));
+        w.extend(inst(OP_MEMBER_DECORATE, &[5, 2, OFFSET, 80]));
+        // A decoration without a value, like `Block`
+        w.extend(inst(OP_DECORATE, &[5, 2]));
+        w.extend(inst(OP_DECORATE, &[10, DESCRIPTOR_SET, 0]));
+        w.extend(inst(OP_DECORATE, &[10, BINDING, 3]));
+        w.extend(inst(OP_TYPE_FLOAT, &[2, 32]));
+        w.extend(inst(OP_TYPE_VECTOR, &[3, 2, 4]));
+        w.extend(inst(OP_TYPE_MATRIX, &[4, 3, 4]));
+        w.extend(inst(OP_TYPE_INT, &[11, 32, 0]));
+        w.extend(inst(OP_CONSTANT, &[11, 12, 3]));
+        w.extend(inst(OP_TYPE_ARRAY, &[13, 3, 12]));
+        w.extend(inst(OP_TYPE_STRUCT, &[5, 4, 3, 13]));
+        w.extend(inst(OP_TYPE_POINTER, &[6, PUSH_CONSTANT, 5]));
+        w.extend(inst(OP_VARIABLE, &[6, 7, PUSH_CONSTANT]));
+        w.extend(inst(OP_TYPE_STRUCT, &[8, 3]));
+        w.extend(inst(OP_TYPE_POINTER, &[9, 2, 8]));
+        w.extend(inst(OP_VARIABLE, &[9, 10, 2]));
+        w
+    }
+
+    fn bytes(words: &[u32], swap: bool) -> Vec<u8> {
+        words
+            .iter()
+            .flat_map(|&w| {
+                let b = if swap {
+                    w.to_be_bytes()
+                } else {
+                    w.to_le_bytes()
+                };
+                b.to_vec()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reflects() {
+        let expected = Reflection {
+            entry_points: vec![(FRAGMENT, "main".to_string())],
+            bindings: vec![(0, 3)],
+            push_constants: Some(128),
+        };
+        assert_eq!(reflect(&bytes(&module(), false)), Ok(expected.clone()));
+        // Either byte order is allowed
+        assert_eq!(reflect(&bytes(&module(), true)), Ok(expected));
+
+        let header = vec![MAGIC, 0x0001_0000, 0, 1, 0];
+        assert_eq!(reflect(&bytes(&header, false)), Ok(Reflection::default()));
+    }
+
+    #[test]
+    fn rejects_bad_modules() {
+        assert!(reflect(b"#version 450\nvoid main() {}\n").is_err());
+        assert!(reflect(&bytes(&module(), false)[..21]).is_err());
+        // The last instruction says it's longer than what's left
+        let mut m = module();
+        let last = m.len() - 4;
+        m[last] += 1 << 16;
+        assert!(reflect(&bytes(&m, false)).is_err());
+        // A push constant member without an offset
+        let mut m = module();
+        let i = m.iter().position(|&w| w == 80).unwrap();
+        m[i - 1] = 0;
+        assert!(reflect(&bytes(&m, false)).is_err());
+    }
+}
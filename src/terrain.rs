@@ -1,19 +1,38 @@
 use crate::common::*;
+use crate::config::GameConfig;
+use crate::noise_gen::{noise, Noise};
 use crate::world::World;
-use noise::*;
+use std::sync::Arc;
 // use rayon::prelude::*;
 
 pub struct Gen {
-    noise: HybridMulti,
+    /// Whichever `noise_type` the config picks
+    noise: Arc<dyn Noise>,
+    /// Replaces the built-in terrain if `gen_script` is set
+    #[cfg(feature = "scripting")]
+    script: Option<crate::script::ScriptGen>,
 }
 
 impl Gen {
-    pub fn new() -> Self {
+    /// The same seed always generates the same terrain
+    pub fn new(config: &GameConfig, seed: u64) -> Self {
+        #[cfg(not(feature = "scripting"))]
+        {
+            if let Some(path) = &config.gen_script {
+                warn!(
+                    "Not using world generation script {}, since scripting isn't enabled in this build",
+                    path.display()
+                );
+            }
+        }
+        let noise = noise(config, seed);
         Gen {
-            noise: HybridMulti::new()
-                .set_seed(1)
-                .set_octaves(8)
-                .set_persistence(0.5),
+            #[cfg(feature = "scripting")]
+            script: config
+                .gen_script
+                .as_ref()
+                .map(|path| crate::script::ScriptGen::new(path, Arc::clone(&noise))),
+            noise,
         }
     }
 
@@ -21,14 +40,22 @@ impl Gen {
     pub fn decorate(&self, world: &mut World, chunk: Vector3<i32>) -> Vec<Vector3<i32>> {
         let mut modified = Vec::new();
 
-        let start = chunk.map(|x| x * CHUNK_SIZE as i32);
+        // Scripts do all their own generation
+        #[cfg(feature = "scripting")]
+        {
+            if self.script.is_some() {
+                return modified;
+            }
+        }
+
+        let start = chunk.map(|x| x * chunk_size() as i32);
 
-        let chunk_heightmap = (0..CHUNK_SIZE as usize)
+        let chunk_heightmap = (0..chunk_size() as usize)
             .map(move |x| {
-                (0..CHUNK_SIZE as usize)
+                (0..chunk_size() as usize)
                     .map(move |z| {
                         3.0 + 48.0
-                            * self.noise.get([
+                            * self.noise.get2([
                                 (start.x as f64 + x as f64) * 0.0004,
                                 (start.z as f64 + z as f64) * 0.0004,
                             ]) as f32
@@ -37,7 +64,7 @@ impl Gen {
             })
             .collect::<Vec<_>>();
 
-        let ntrees = (self.noise.get([
+        let ntrees = (self.noise.get3([
             chunk.x as f64 * 0.04,
             chunk.y as f64 * 0.04,
             chunk.z as f64 * 0.04,
@@ -52,35 +79,35 @@ impl Gen {
 
             let fx = self
                 .noise
-                .get([
+                .get3([
                     chunk.x as f64 + f * 2.3,
                     chunk.y as f64 - f,
                     chunk.z as f64 + f,
                 ])
                 .abs()
                 / 1.5;
-            let x = (fx * CHUNK_SIZE as f64).min(CHUNK_SIZE as f64 - 1.0) as usize;
+            let x = (fx * chunk_size() as f64).min(chunk_size() as f64 - 1.0) as usize;
             let fz = self
                 .noise
-                .get([
+                .get3([
                     chunk.x as f64 + f,
                     chunk.y as f64 + f * 3.9,
                     chunk.z as f64 - f * 0.91,
                 ])
                 .abs()
                 / 1.5;
-            let z = (fz * CHUNK_SIZE as f64).min(CHUNK_SIZE as f64 - 1.0) as usize;
+            let z = (fz * chunk_size() as f64).min(chunk_size() as f64 - 1.0) as usize;
 
             let y = chunk_heightmap[x][z].ceil() as i32 + 3;
             // Is it in this chunk? (instead of above or below)
-            if (y - start.y) > 0 && (y - start.y) < CHUNK_SIZE as i32 {
+            if (y - start.y) > 0 && (y - start.y) < chunk_size() as i32 {
                 let x = start.x + x as i32;
                 let z = start.z + z as i32;
 
                 if world.block(Vector3::new(x as f32, (y - 1) as f32, z as f32))
                     == Some(Material::Grass)
                 {
-                    let tree_height = (self.noise.get([
+                    let tree_height = (self.noise.get3([
                         chunk.x as f64 + fz,
                         chunk.y as f64 - fx,
                         chunk.z as f64 + y as f64,
@@ -96,7 +123,7 @@ impl Gen {
                         world.set_block(pos, Material::Wood);
                     }
 
-                    let canopy_width = (self.noise.get([
+                    let canopy_width = (self.noise.get3([
                         chunk.x as f64 - fz * 2.3,
                         chunk.y as f64 + fx * 3.0,
                         chunk.z as f64 - y as f64,
@@ -131,14 +158,21 @@ impl Gen {
     }
 
     pub fn gen(&self, pos: Vector3<i32>) -> Chunk {
-        let start = chunk_to_world(pos).map(|x| (x - 0.5 * CHUNK_SIZE) as i32);
+        #[cfg(feature = "scripting")]
+        {
+            if let Some(script) = &self.script {
+                return script.gen(pos);
+            }
+        }
+
+        let start = chunk_to_world(pos).map(|x| (x - 0.5 * chunk_size()) as i32);
 
-        let chunk_heightmap = (0..CHUNK_SIZE as usize)
+        let chunk_heightmap = (0..chunk_size() as usize)
             .map(move |x| {
-                (0..CHUNK_SIZE as usize)
+                (0..chunk_size() as usize)
                     .map(move |z| {
                         3.0 + 48.0
-                            * self.noise.get([
+                            * self.noise.get2([
                                 (start.x as f64 + x as f64) * 0.0004,
                                 (start.z as f64 + z as f64) * 0.0004,
                             ]) as f32
@@ -169,7 +203,7 @@ impl Gen {
             let d = (y - height.ceil() as i32) as f32;
 
             if d < 3.0 && d > 1.0 {
-                let m = if y < 3 + self.noise.get([
+                let m = if y < 3 + self.noise.get2([
                     (start.x as f64 + p.x as f64) * 0.04,
                     (start.z as f64 + p.z as f64) * 0.04,
                 ]) as i32
@@ -191,3 +225,21 @@ impl Gen {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gen_bytes(seed: u64) -> Vec<Vec<u8>> {
+        let gen = Gen::new(&GameConfig::default(), seed);
+        (-1..2)
+            .map(|y| bincode::serialize(&gen.gen(Vector3::new(3, y, -2))).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn same_seed_same_terrain() {
+        assert_eq!(gen_bytes(1234), gen_bytes(1234));
+        assert_ne!(gen_bytes(1234), gen_bytes(5678));
+    }
+}
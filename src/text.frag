@@ -0,0 +1,38 @@
+#version 450
+
+// The glyphs are drawn in the label's color with a black outline, so they can be read against anything
+
+layout(set = 0, binding = 0) uniform sampler2D atlas;
+
+layout(location = 0) in vec3 frag_color_in;
+layout(location = 1) in vec3 from_camera;
+layout(location = 2) in vec2 frag_uv;
+
+// Like `main.frag`, the alpha channel has the distance from the camera, for motion blur
+layout(location = 0) out vec4 frag_color;
+
+bool inside(ivec2 texel) {
+  // Glyphs have empty padding around them, so clamping at the edges of the atlas doesn't find anything
+  texel = clamp(texel, ivec2(0), textureSize(atlas, 0) - 1);
+  return texelFetch(atlas, texel, 0).r > 0.5;
+}
+
+void main() {
+  ivec2 texel = ivec2(floor(frag_uv));
+  vec3 color;
+  if (inside(texel)) {
+    color = frag_color_in;
+  } else {
+    bool edge = false;
+    for (int x = -1; x <= 1; x++) {
+      for (int y = -1; y <= 1; y++) {
+        edge = edge || inside(texel + ivec2(x, y));
+      }
+    }
+    if (!edge) {
+      discard;
+    }
+    color = vec3(0.0);
+  }
+  frag_color = vec4(color, length(from_camera));
+}
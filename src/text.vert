@@ -0,0 +1,36 @@
+#version 450
+
+// Labels from `markers.rs`, like the names over other players.
+// Each glyph is a quad that faces the camera, centered on its label's position.
+
+layout(push_constant) uniform PushConstants {
+  // Takes positions relative to the render origin to clip space
+  mat4 view_proj;
+  // w is how big a texel of the font is up close, in voxels
+  vec4 camera_pos;
+  // The camera's right and up directions, which labels are drawn along.
+  // `right.w` is the smallest a texel gets as a fraction of its distance from the camera, so far away labels stay readable.
+  vec4 right;
+  vec4 up;
+};
+
+// The label's position, which every glyph in it shares
+layout(location = 0) in vec3 position;
+// Where this corner is from `position`, in texels of the font
+layout(location = 1) in vec2 offset;
+// Where it is in the atlas, in texels
+layout(location = 2) in vec2 uv;
+layout(location = 3) in vec3 color;
+
+layout(location = 0) out vec3 frag_color_in;
+layout(location = 1) out vec3 from_camera;
+layout(location = 2) out vec2 frag_uv;
+
+void main() {
+  float texel = max(camera_pos.w, distance(position, camera_pos.xyz) * right.w);
+  vec3 pos = position + (right.xyz * offset.x + up.xyz * offset.y) * texel;
+  gl_Position = view_proj * vec4(pos, 1.0);
+  frag_color_in = color;
+  from_camera = pos - camera_pos.xyz;
+  frag_uv = uv;
+}
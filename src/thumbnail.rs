@@ -0,0 +1,159 @@
+//! Small pictures of saved worlds, so a save picker can show what each one looks like.
+//! The client takes one from the last frame it drew when it quits, and it's stored next to the save as a binary PPM,
+//! which is simple enough that we don't need an image library to read or write it.
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// How big thumbnails are, in pixels. Frames with a different aspect ratio get stretched.
+pub const THUMBNAIL_SIZE: [u32; 2] = [256, 144];
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Linear to sRGB, as a byte
+fn srgb(x: f32) -> u8 {
+    let x = x.clamp(0.0, 1.0);
+    let x = if x <= 0.003_130_8 {
+        x * 12.92
+    } else {
+        1.055 * x.powf(1.0 / 2.4) - 0.055
+    };
+    (x * 255.0).round() as u8
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Thumbnail {
+    pub width: u32,
+    pub height: u32,
+    /// Three bytes per pixel, in sRGB, starting at the top left
+    pub rgb: Vec<u8>,
+}
+
+impl Thumbnail {
+    /// Where the thumbnail for the save in `dir` goes
+    pub fn path(dir: &Path) -> PathBuf {
+        dir.join("thumbnail.ppm")
+    }
+
+    /// Shrinks a frame to `size`, averaging the pixels that go into each one.
+    /// `rgba` has four linear floats per pixel, starting at the top left; alpha is ignored.
+    pub fn from_frame(width: u32, height: u32, rgba: &[f32], size: [u32; 2]) -> Self {
+        assert_eq!(rgba.len(), (width * height * 4) as usize);
+        let mut rgb = Vec::with_capacity((size[0] * size[1] * 3) as usize);
+        for y in 0..size[1] {
+            // The frame pixels this row covers, at least one even if the frame is smaller than the thumbnail
+            let y0 = y * height / size[1];
+            let y1 = ((y + 1) * height / size[1]).max(y0 + 1).min(height);
+            for x in 0..size[0] {
+                let x0 = x * width / size[0];
+                let x1 = ((x + 1) * width / size[0]).max(x0 + 1).min(width);
+                let mut sum = [0.0; 3];
+                for fy in y0..y1 {
+                    for fx in x0..x1 {
+                        let i = ((fy * width + fx) * 4) as usize;
+                        for c in 0..3 {
+                            sum[c] += rgba[i + c];
+                        }
+                    }
+                }
+                let n = ((y1 - y0) * (x1 - x0)) as f32;
+                rgb.extend(sum.iter().map(|s| srgb(s / n)));
+            }
+        }
+        Thumbnail {
+            width: size[0],
+            height: size[1],
+            rgb,
+        }
+    }
+
+    /// Writes to a temporary file and renames it over the old one, like regions, so it's never half written
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let tmp = path.with_extension("ppm.tmp");
+        {
+            let mut f = std::fs::File::create(&tmp)?;
+            write!(f, "P6\n{} {}\n255\n", self.width, self.height)?;
+            f.write_all(&self.rgb)?;
+        }
+        std::fs::rename(&tmp, path)
+    }
+
+    /// Reads a thumbnail written by `save()`
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let mut buf = Vec::new();
+        std::fs::File::open(path)?.read_to_end(&mut buf)?;
+
+        // The header is four fields separated by whitespace, then one whitespace byte before the pixels
+        let mut fields = Vec::new();
+        let mut i = 0;
+        while fields.len() < 4 {
+            while buf.get(i).is_some_and(u8::is_ascii_whitespace) {
+                i += 1;
+            }
+            let start = i;
+            while buf.get(i).is_some_and(|x| !x.is_ascii_whitespace()) {
+                i += 1;
+            }
+            if start == i {
+                return Err(invalid("the header is cut off"));
+            }
+            fields.push(String::from_utf8_lossy(&buf[start..i]).into_owned());
+        }
+        let number = |s: &str| s.parse::<u32>().map_err(|_| invalid("bad header"));
+        if fields[0] != "P6" || number(&fields[3])? != 255 {
+            return Err(invalid("not a thumbnail"));
+        }
+        let (width, height) = (number(&fields[1])?, number(&fields[2])?);
+
+        let rgb = buf
+            .get(i + 1..)
+            .filter(|x| x.len() == (width * height * 3) as usize)
+            .ok_or_else(|| invalid("wrong number of pixels"))?
+            .to_vec();
+        Ok(Thumbnail { width, height, rgb })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn averages_pixels() {
+        // Black on the left half and white on the right, shrunk to two pixels
+        let frame: Vec<f32> = (0..4 * 2)
+            .flat_map(|i| {
+                let v = if i % 4 < 2 { 0.0 } else { 1.0 };
+                vec![v, v, v, 1.0]
+            })
+            .collect();
+        let t = Thumbnail::from_frame(4, 2, &frame, [2, 1]);
+        assert_eq!(t.rgb, vec![0, 0, 0, 255, 255, 255]);
+
+        // Mid grey in linear is brighter in sRGB, and too-bright HDR values are clamped
+        let t = Thumbnail::from_frame(1, 1, &[0.5, 2.0, -1.0, 1.0], [1, 1]);
+        assert_eq!(t.rgb, vec![188, 255, 0]);
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = crate::common::test_dir("thumbnail");
+        let path = Thumbnail::path(&dir);
+
+        let t = Thumbnail {
+            width: 3,
+            height: 2,
+            // Starts with a byte that's whitespace in ASCII, which shouldn't be mistaken for part of the header
+            rgb: vec![
+                b'\n', 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17,
+            ],
+        };
+        t.save(&path).unwrap();
+        assert_eq!(Thumbnail::load(&path).unwrap(), t);
+
+        std::fs::write(&path, b"P6\n3 2\n255\n\x01\x02").unwrap();
+        assert!(Thumbnail::load(&path).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
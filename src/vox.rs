@@ -0,0 +1,286 @@
+//! Reads MagicaVoxel `.vox` files, for importing models into the world.
+//! These come from wherever the player got them, so anything malformed is an error rather than a panic,
+//! and sizes are checked against the length of the file before we allocate anything.
+//!
+//! A file is `VOX `, a version number, and then a `MAIN` chunk whose children are the models and palette.
+//! Each chunk is a four-letter id, the length of its content, the length of its children, and then those.
+//! We only need `SIZE`, `XYZI` and `RGBA`; everything else (scene graph, materials, layers) is skipped.
+use std::fmt;
+use std::path::Path;
+
+/// MagicaVoxel doesn't make models bigger than this on any axis
+pub const MAX_VOX_SIZE: u32 = 256;
+
+#[derive(Debug)]
+pub enum VoxError {
+    Io(std::io::Error),
+    /// It doesn't start with `VOX `, so it's probably not a .vox file at all
+    BadMagic,
+    /// The file ended partway through this chunk
+    Truncated(String),
+    /// A chunk that doesn't make sense, and why
+    BadChunk(String, String),
+    /// A model is bigger than `MAX_VOX_SIZE` on some axis, or empty
+    BadSize([u32; 3]),
+    /// There's no `MAIN` chunk, or it doesn't have any models in it
+    NoModels,
+}
+
+impl fmt::Display for VoxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VoxError::Io(e) => write!(f, "{}", e),
+            VoxError::BadMagic => write!(f, "not a .vox file"),
+            VoxError::Truncated(id) => write!(f, "the file ends in the middle of a {} chunk", id),
+            VoxError::BadChunk(id, why) => write!(f, "bad {} chunk: {}", id, why),
+            VoxError::BadSize(size) => write!(
+                f,
+                "a model is {}x{}x{}, but models have to be between 1 and {} on each side",
+                size[0], size[1], size[2], MAX_VOX_SIZE
+            ),
+            VoxError::NoModels => write!(f, "there aren't any models in it"),
+        }
+    }
+}
+
+impl std::error::Error for VoxError {}
+
+impl From<std::io::Error> for VoxError {
+    fn from(e: std::io::Error) -> Self {
+        VoxError::Io(e)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct VoxModel {
+    /// In MagicaVoxel's axes, where z is up
+    pub size: [u32; 3],
+    /// The position and palette index of each voxel. Index 0 is never used, so `palette[i - 1]` is its color.
+    pub voxels: Vec<([u8; 3], u8)>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Vox {
+    pub models: Vec<VoxModel>,
+    /// RGBA colors, or `None` if the file uses MagicaVoxel's default palette
+    pub palette: Option<Vec<[u8; 4]>>,
+}
+
+/// Reads little-endian numbers and byte ranges, failing if there aren't enough bytes left
+struct Reader<'a> {
+    buf: &'a [u8],
+    /// What we're reading, for errors
+    what: String,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], VoxError> {
+        if n > self.buf.len() {
+            return Err(VoxError::Truncated(self.what.clone()));
+        }
+        let (x, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Ok(x)
+    }
+
+    fn u32(&mut self) -> Result<u32, VoxError> {
+        let b = self.take(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    /// The id, content and children of the next chunk
+    fn chunk(&mut self) -> Result<(String, &'a [u8], &'a [u8]), VoxError> {
+        let id = String::from_utf8_lossy(self.take(4)?).into_owned();
+        let mut r = Reader {
+            buf: self.buf,
+            what: id.clone(),
+        };
+        let content_len = r.u32()? as usize;
+        let children_len = r.u32()? as usize;
+        let content = r.take(content_len)?;
+        let children = r.take(children_len)?;
+        self.buf = r.buf;
+        Ok((id, content, children))
+    }
+}
+
+/// Parses the contents of a .vox file
+pub fn parse_vox(buf: &[u8]) -> Result<Vox, VoxError> {
+    if buf.len() < 8 || &buf[0..4] != b"VOX " {
+        return Err(VoxError::BadMagic);
+    }
+    // We don't check the version; the parts we read haven't changed between them
+    let mut r = Reader {
+        buf: &buf[8..],
+        what: "MAIN".to_string(),
+    };
+    let (id, _, children) = r.chunk()?;
+    if id != "MAIN" {
+        return Err(VoxError::NoModels);
+    }
+
+    let mut models = Vec::new();
+    let mut palette = None;
+    let mut size = None;
+    let mut r = Reader {
+        buf: children,
+        what: "MAIN".to_string(),
+    };
+    while !r.buf.is_empty() {
+        let (id, content, _) = r.chunk()?;
+        let mut c = Reader {
+            buf: content,
+            what: id.clone(),
+        };
+        match id.as_str() {
+            "SIZE" => {
+                let s = [c.u32()?, c.u32()?, c.u32()?];
+                if s.iter().any(|&x| x == 0 || x > MAX_VOX_SIZE) {
+                    return Err(VoxError::BadSize(s));
+                }
+                size = Some(s);
+            }
+            "XYZI" => {
+                // Each model is a SIZE followed by an XYZI
+                let s = size.take().ok_or_else(|| {
+                    VoxError::BadChunk(id.clone(), "it doesn't come after a SIZE".to_string())
+                })?;
+                let n = c.u32()? as usize;
+                // Checking there's room for them first means a bad count can't make us allocate too much
+                if n > c.buf.len() / 4 {
+                    return Err(VoxError::Truncated(id));
+                }
+                let mut voxels = Vec::with_capacity(n);
+                for _ in 0..n {
+                    let v = c.take(4)?;
+                    if (0..3).any(|i| v[i] as u32 >= s[i]) {
+                        return Err(VoxError::BadChunk(
+                            id,
+                            format!("voxel {:?} is outside the {:?} model", &v[0..3], s),
+                        ));
+                    }
+                    if v[3] != 0 {
+                        voxels.push(([v[0], v[1], v[2]], v[3]));
+                    }
+                }
+                models.push(VoxModel { size: s, voxels });
+            }
+            "RGBA" => {
+                let colors = c.take(256 * 4)?;
+                palette = Some(colors.chunks(4).map(|x| [x[0], x[1], x[2], x[3]]).collect());
+            }
+            _ => debug!("Skipping {} chunk in .vox file", id),
+        }
+    }
+
+    if models.is_empty() {
+        return Err(VoxError::NoModels);
+    }
+    Ok(Vox { models, palette })
+}
+
+/// Reads and parses a .vox file
+pub fn load_vox(path: &Path) -> Result<Vox, VoxError> {
+    parse_vox(&std::fs::read(path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(id: &[u8; 4], content: &[u8], children: &[u8]) -> Vec<u8> {
+        let mut v = id.to_vec();
+        v.extend_from_slice(&(content.len() as u32).to_le_bytes());
+        v.extend_from_slice(&(children.len() as u32).to_le_bytes());
+        v.extend_from_slice(content);
+        v.extend_from_slice(children);
+        v
+    }
+
+    fn words(x: &[u32]) -> Vec<u8> {
+        x.iter().flat_map(|x| x.to_le_bytes().to_vec()).collect()
+    }
+
+    fn file(children: &[u8]) -> Vec<u8> {
+        let mut v = b"VOX ".to_vec();
+        v.extend_from_slice(&150u32.to_le_bytes());
+        v.extend(chunk(b"MAIN", &[], children));
+        v
+    }
+
+    /// A 2x3x4 model with two voxels, and some chunks we don't use
+    fn test_file() -> Vec<u8> {
+        let mut children = chunk(b"SIZE", &words(&[2, 3, 4]), &[]);
+        children.extend(chunk(b"nTRN", &[1, 2, 3, 4, 5, 6], &[]));
+        let mut xyzi = words(&[2]);
+        xyzi.extend_from_slice(&[0, 0, 0, 1, 1, 2, 3, 200]);
+        children.extend(chunk(b"XYZI", &xyzi, &[]));
+        let palette: Vec<u8> = (0..256 * 4).map(|i| i as u8).collect();
+        children.extend(chunk(b"RGBA", &palette, &[]));
+        file(&children)
+    }
+
+    #[test]
+    fn parses_models() {
+        let vox = parse_vox(&test_file()).unwrap();
+        assert_eq!(
+            vox.models,
+            vec![VoxModel {
+                size: [2, 3, 4],
+                voxels: vec![([0, 0, 0], 1), ([1, 2, 3], 200)],
+            }]
+        );
+        assert_eq!(vox.palette.unwrap()[1], [4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        assert!(matches!(parse_vox(b""), Err(VoxError::BadMagic)));
+        assert!(matches!(
+            parse_vox(b"PK\x03\x04 not a vox file"),
+            Err(VoxError::BadMagic)
+        ));
+        assert!(matches!(parse_vox(&file(&[])), Err(VoxError::NoModels)));
+        // Random bytes after a valid header
+        let mut x = b"VOX \x96\x00\x00\x00".to_vec();
+        x.extend((0..200u32).map(|i| (i * 7919 % 251) as u8));
+        assert!(parse_vox(&x).is_err());
+    }
+
+    #[test]
+    fn rejects_truncated() {
+        let f = test_file();
+        // Every possible place to cut it off
+        for len in 0..f.len() {
+            assert!(parse_vox(&f[..len]).is_err(), "Cut off at {} was fine", len);
+        }
+        // A count that claims more voxels than there are
+        let mut xyzi = words(&[1_000_000_000]);
+        xyzi.extend_from_slice(&[0, 0, 0, 1]);
+        let mut children = chunk(b"SIZE", &words(&[2, 2, 2]), &[]);
+        children.extend(chunk(b"XYZI", &xyzi, &[]));
+        assert!(matches!(
+            parse_vox(&file(&children)),
+            Err(VoxError::Truncated(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_bad_sizes() {
+        let children = chunk(b"SIZE", &words(&[2, 100_000, 2]), &[]);
+        assert!(matches!(
+            parse_vox(&file(&children)),
+            Err(VoxError::BadSize([2, 100_000, 2]))
+        ));
+
+        // A voxel outside the model
+        let mut children = chunk(b"SIZE", &words(&[2, 2, 2]), &[]);
+        let mut xyzi = words(&[1]);
+        xyzi.extend_from_slice(&[0, 5, 0, 1]);
+        children.extend(chunk(b"XYZI", &xyzi, &[]));
+        assert!(matches!(
+            parse_vox(&file(&children)),
+            Err(VoxError::BadChunk(_, _))
+        ));
+    }
+}
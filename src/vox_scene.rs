@@ -0,0 +1,291 @@
+//! Scenes put together from several .vox models, each placed and turned, described by a RON manifest like
+//! ```ron
+//! VoxScene(models: [
+//!     (file: "ground.vox"),
+//!     (file: "house.vox", position: (10, 1, 4), rotation: 90),
+//!     (file: "props.vox", model: 2, position: (12, 1, 6), up_axis: Some(Y)),
+//! ])
+//! ```
+//! Files are relative to the manifest. Where models overlap, the later one in the list wins,
+//! so put the ground first and the details on top of it.
+//! Every model's palette is merged into one for the scene, so placing it only needs one color for each index.
+use crate::common::UpAxis;
+use crate::vox::{load_vox, Vox, VoxError};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct SceneModel {
+    /// The .vox file, relative to the manifest
+    pub file: PathBuf,
+    /// Which model in the file, for files with more than one
+    #[serde(default)]
+    pub model: usize,
+    /// Where the model's -x -y -z corner goes, in voxels with y up. Only where the models are compared to each other matters.
+    #[serde(default)]
+    pub position: [i32; 3],
+    /// Degrees around up, by the right-hand rule, so 90 turns +x to -z. It has to be a multiple of 90.
+    #[serde(default)]
+    pub rotation: i32,
+    /// Which axis is up in the file, if it isn't `vox_up_axis` from the config
+    #[serde(default)]
+    pub up_axis: Option<UpAxis>,
+}
+
+/// A scene manifest; see the module docs
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct VoxScene {
+    /// In overwrite order: each one replaces the voxels of the ones before it where they overlap
+    pub models: Vec<SceneModel>,
+}
+
+#[derive(Debug)]
+pub enum SceneError {
+    Io(std::io::Error),
+    /// The manifest isn't RON that looks like a `VoxScene`
+    Parse(String),
+    /// One of the .vox files couldn't be read
+    Vox(PathBuf, VoxError),
+    /// The file doesn't have a model with this index
+    NoModel(PathBuf, usize),
+    /// The rotation isn't a multiple of 90 degrees
+    BadRotation(PathBuf, i32),
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SceneError::Io(e) => write!(f, "{}", e),
+            SceneError::Parse(e) => write!(f, "bad scene manifest: {}", e),
+            SceneError::Vox(file, e) => write!(f, "couldn't read {}: {}", file.display(), e),
+            SceneError::NoModel(file, i) => write!(
+                f,
+                "{} doesn't have a model {}; they're numbered from 0",
+                file.display(),
+                i
+            ),
+            SceneError::BadRotation(file, r) => write!(
+                f,
+                "{} is turned {} degrees, but it has to be a multiple of 90",
+                file.display(),
+                r
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SceneError {}
+
+impl From<std::io::Error> for SceneError {
+    fn from(e: std::io::Error) -> Self {
+        SceneError::Io(e)
+    }
+}
+
+/// A scene with every model placed, ready to put in the world
+#[derive(Clone, Debug, PartialEq)]
+pub struct AssembledScene {
+    /// The size of the box around all of it, with y up
+    pub size: [u32; 3],
+    /// Every color used in any of the models, once each.
+    /// `None` is for models from files without a palette, which use MagicaVoxel's default one.
+    pub palette: Vec<Option<[u8; 4]>>,
+    /// The position of each voxel from the -x -y -z corner of `size`, and its index in `palette`, sorted by position
+    pub voxels: Vec<([u32; 3], usize)>,
+}
+
+/// Where voxel `p` of something `size` big goes after `turns` quarter turns around y, and its new size
+fn turn(p: [u32; 3], size: [u32; 3], turns: i32) -> ([u32; 3], [u32; 3]) {
+    let (mut p, mut size) = (p, size);
+    for _ in 0..turns.rem_euclid(4) {
+        // +x goes to -z, and +z goes to +x
+        p = [p[2], p[1], size[0] - 1 - p[0]];
+        size = [size[2], size[1], size[0]];
+    }
+    (p, size)
+}
+
+impl VoxScene {
+    pub fn parse(s: &str) -> Result<Self, SceneError> {
+        ron::de::from_str(s).map_err(|e| SceneError::Parse(e.to_string()))
+    }
+
+    /// Loads each model with `load`, which gets the `file` from the manifest, and places it.
+    /// Files used more than once are only loaded once. Models are turned so `up_axis` is up unless they say otherwise.
+    pub fn assemble(
+        &self,
+        up_axis: UpAxis,
+        mut load: impl FnMut(&Path) -> Result<Vox, VoxError>,
+    ) -> Result<AssembledScene, SceneError> {
+        let mut files: HashMap<&Path, Vox> = HashMap::new();
+        let mut palette = Vec::new();
+        let mut colors = HashMap::new();
+        let mut grid: HashMap<[i32; 3], usize> = HashMap::new();
+        for m in &self.models {
+            if m.rotation % 90 != 0 {
+                return Err(SceneError::BadRotation(m.file.clone(), m.rotation));
+            }
+            if !files.contains_key(m.file.as_path()) {
+                let vox = load(&m.file).map_err(|e| SceneError::Vox(m.file.clone(), e))?;
+                files.insert(&m.file, vox);
+            }
+            let vox = &files[m.file.as_path()];
+            let model = vox
+                .models
+                .get(m.model)
+                .ok_or_else(|| SceneError::NoModel(m.file.clone(), m.model))?;
+
+            // Where each of this file's palette indices went in the scene's palette
+            let mut remap = [None; 256];
+            let up = m.up_axis.unwrap_or(up_axis);
+            let size = up.grid_size(model.size);
+            for &(p, i) in &model.voxels {
+                let index = *remap[i as usize].get_or_insert_with(|| {
+                    let color = vox.palette.as_ref().map(|p| p[i as usize - 1]);
+                    *colors.entry(color).or_insert_with(|| {
+                        palette.push(color);
+                        palette.len() - 1
+                    })
+                });
+                let p = up.grid_pos([p[0] as u32, p[1] as u32, p[2] as u32], model.size);
+                let (p, _) = turn(p, size, m.rotation / 90);
+                let pos = [
+                    m.position[0] + p[0] as i32,
+                    m.position[1] + p[1] as i32,
+                    m.position[2] + p[2] as i32,
+                ];
+                grid.insert(pos, index);
+            }
+        }
+
+        let min: Vec<i32> = (0..3)
+            .map(|i| grid.keys().map(|p| p[i]).min().unwrap_or(0))
+            .collect();
+        let max: Vec<i32> = (0..3)
+            .map(|i| grid.keys().map(|p| p[i]).max().unwrap_or(-1))
+            .collect();
+        let mut voxels: Vec<_> = grid
+            .into_iter()
+            .map(|(p, i)| {
+                (
+                    [
+                        (p[0] - min[0]) as u32,
+                        (p[1] - min[1]) as u32,
+                        (p[2] - min[2]) as u32,
+                    ],
+                    i,
+                )
+            })
+            .collect();
+        voxels.sort_unstable();
+        // An empty scene's max is one less than its min, so it's 0 on each side
+        let size = [
+            (max[0] - min[0] + 1) as u32,
+            (max[1] - min[1] + 1) as u32,
+            (max[2] - min[2] + 1) as u32,
+        ];
+        Ok(AssembledScene {
+            size,
+            palette,
+            voxels,
+        })
+    }
+}
+
+/// Reads the manifest at `path` and puts together the scene it describes, like `VoxScene::assemble()`
+pub fn load_scene(path: &Path, up_axis: UpAxis) -> Result<AssembledScene, SceneError> {
+    let scene = VoxScene::parse(&std::fs::read_to_string(path)?)?;
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    scene.assemble(up_axis, |file| load_vox(&dir.join(file)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vox::VoxModel;
+
+    /// A model with a voxel at each of `voxels`, each with palette index 1, in a palette where that's `color`
+    fn vox(size: [u32; 3], voxels: &[[u8; 3]], color: [u8; 4]) -> Vox {
+        let mut palette = vec![[0; 4]; 256];
+        palette[0] = color;
+        Vox {
+            models: vec![VoxModel {
+                size,
+                voxels: voxels.iter().map(|&p| (p, 1)).collect(),
+            }],
+            palette: Some(palette),
+        }
+    }
+
+    fn files(path: &Path) -> Result<Vox, VoxError> {
+        match path.to_str().unwrap() {
+            "red.vox" => Ok(vox([2, 1, 1], &[[0, 0, 0], [1, 0, 0]], [255, 0, 0, 255])),
+            "blue.vox" => Ok(vox([1, 1, 1], &[[0, 0, 0]], [0, 0, 255, 255])),
+            _ => Err(VoxError::NoModels),
+        }
+    }
+
+    #[test]
+    fn places_models_in_order() {
+        let scene = VoxScene::parse(
+            r#"VoxScene(models: [
+                (file: "red.vox", position: (0, 0, 0), up_axis: Some(Y)),
+                (file: "blue.vox", position: (1, 0, 0), up_axis: Some(Y)),
+                (file: "red.vox", position: (-1, 0, 0), rotation: 90, up_axis: Some(Y)),
+            ])"#,
+        )
+        .unwrap();
+        let a = scene.assemble(UpAxis::Z, files).unwrap();
+        // Red and blue, once each however many times they're used
+        assert_eq!(
+            a.palette,
+            vec![Some([255, 0, 0, 255]), Some([0, 0, 255, 255])]
+        );
+        // The turned red one goes along z at x = -1, and blue replaced red at x = 1
+        assert_eq!(a.size, [3, 1, 2]);
+        assert_eq!(
+            a.voxels,
+            vec![
+                ([0, 0, 0], 0),
+                ([0, 0, 1], 0),
+                ([1, 0, 0], 0),
+                ([2, 0, 0], 1)
+            ]
+        );
+
+        // With blue first, red wins there instead
+        let mut reversed = scene.clone();
+        reversed.models.swap(0, 1);
+        let a = reversed.assemble(UpAxis::Z, files).unwrap();
+        let red = a.palette.iter().position(|&c| c == Some([255, 0, 0, 255]));
+        assert!(a.voxels.contains(&([2, 0, 0], red.unwrap())));
+    }
+
+    #[test]
+    fn rejects_bad_models() {
+        let scene = |s: &str| {
+            VoxScene::parse(&format!("VoxScene(models: [{}])", s))
+                .unwrap()
+                .assemble(UpAxis::Z, files)
+        };
+        assert!(matches!(
+            scene(r#"(file: "red.vox", rotation: 45)"#),
+            Err(SceneError::BadRotation(_, 45))
+        ));
+        assert!(matches!(
+            scene(r#"(file: "red.vox", model: 1)"#),
+            Err(SceneError::NoModel(_, 1))
+        ));
+        assert!(matches!(
+            scene(r#"(file: "missing.vox")"#),
+            Err(SceneError::Vox(_, VoxError::NoModels))
+        ));
+        assert!(matches!(
+            VoxScene::parse("VoxScene(modles: [])"),
+            Err(SceneError::Parse(_))
+        ));
+        assert_eq!(scene("").unwrap().size, [0; 3]);
+    }
+}
@@ -1,172 +1,362 @@
+use crate::config::ClientConfig;
+use crate::window_geometry::{Rect, WindowGeometry};
 use std::sync::Arc;
+use vulkano::instance::PhysicalDevice;
 use vulkano_win::VkSurfaceBuild;
 use winit::window::Window as RawWindow;
 
+/// Where we draw to: either a real window with a swapchain, or, with `Window::headless()`, an offscreen image
 pub struct Window {
-    pub swapchain: Arc<vulkano::swapchain::Swapchain<RawWindow>>,
-    images: Vec<Arc<vulkano::image::SwapchainImage<RawWindow>>>,
-    surface: Arc<vulkano::swapchain::Surface<RawWindow>>,
+    /// Kept so we can make a new device if the old one is lost
+    instance: Arc<vulkano::instance::Instance>,
+    /// `None` if we're headless
+    pub swapchain: Option<Arc<vulkano::swapchain::Swapchain<RawWindow>>>,
+    surface: Option<Arc<vulkano::swapchain::Surface<RawWindow>>>,
     // TODO remove dynamic viewport (https://computergraphics.stackexchange.com/questions/5742/vulkan-best-way-of-updating-pipeline-viewport)
     pub dynamic_state: vulkano::command_buffer::DynamicState,
     pub rpass: Arc<dyn vulkano::framebuffer::RenderPassAbstract + Send + Sync>,
     framebuffers: Vec<Arc<dyn vulkano::framebuffer::FramebufferAbstract + Send + Sync>>,
+    /// The swapchain's images, so the framebuffers can be made again when `rpass` changes. Empty when headless.
+    images: Vec<Arc<vulkano::image::SwapchainImage<RawWindow>>>,
+    /// Whether `rpass` clears the window, or leaves it for the post pass to draw over every pixel
+    clear: bool,
     size: winit::dpi::PhysicalSize<u32>,
     device: Arc<vulkano::device::Device>,
     pub queue: Arc<vulkano::device::Queue>,
+    /// For uploading chunks, on a family that only does transfers if there is one, so uploads don't wait behind drawing.
+    /// Otherwise it's `queue` again. Buffers on a device with two families are shared between them,
+    /// so they can be used on either queue without transferring ownership.
+    pub transfer_queue: Arc<vulkano::device::Queue>,
+    /// What the window was opened with, which `set_status()` adds to
+    title: String,
+    /// Where the window was before it went fullscreen, to put it back there after.
+    /// `None` if it's never been anywhere else, or we're headless.
+    windowed: Option<Rect>,
 }
 
 pub struct Frame {
     pub image_num: usize,
-    pub acquire: vulkano::swapchain::SwapchainAcquireFuture<winit::window::Window>,
+    /// `None` if we're headless, since there's nothing to wait for
+    pub acquire: Option<vulkano::swapchain::SwapchainAcquireFuture<winit::window::Window>>,
     pub framebuffer: Arc<dyn vulkano::framebuffer::FramebufferAbstract + Send + Sync>,
 }
 
-impl Window {
-    pub fn device(&self) -> Arc<vulkano::device::Device> {
-        Arc::clone(&self.device)
+/// The format of the offscreen image when we're headless
+const HEADLESS_FORMAT: vulkano::format::Format = vulkano::format::Format::B8G8R8A8Srgb;
+
+fn create_instance(
+    extensions: &vulkano::instance::InstanceExtensions,
+) -> Arc<vulkano::instance::Instance> {
+    // We can set this to None for release builds
+    let layers = vec!["VK_LAYER_KHRONOS_validation"];
+    vulkano::instance::Instance::new(None, extensions, layers).unwrap_or_else(|x| {
+        panic!(
+            "Error creating instance: {}",
+            match x {
+                vulkano::instance::InstanceCreationError::LayerNotPresent =>
+                    "The Khronos validation layer is not present on your system".to_string(),
+                x => format!("{:?}", x),
+            }
+        )
+    })
+}
+
+/// The features we can't run without
+fn required_features() -> vulkano::device::Features {
+    vulkano::device::Features {
+        fragment_stores_and_atomics: true,
+        ..vulkano::device::Features::none()
     }
+}
 
-    pub fn frame(&self) -> Result<Frame, vulkano::swapchain::AcquireError> {
-        // TODO do something with suboptimal
-        let (image_num, suboptimal, acquire) =
-            vulkano::swapchain::acquire_next_image(Arc::clone(&self.swapchain), None)?;
-        let framebuffer = Arc::clone(&self.framebuffers[image_num]);
-        Ok(Frame {
-            image_num,
-            acquire,
-            framebuffer,
-        })
+/// The extensions we can't run without
+fn required_extensions(presenting: bool) -> vulkano::device::DeviceExtensions {
+    vulkano::device::DeviceExtensions {
+        khr_swapchain: presenting,
+        khr_storage_buffer_storage_class: true,
+        ..vulkano::device::DeviceExtensions::none()
     }
+}
 
-    pub fn new(title: &str) -> (Self, winit::event_loop::EventLoop<()>) {
-        // We can set this to None for release builds
-        let layers = vec!["VK_LAYER_KHRONOS_validation"];
-        let instance =
-            vulkano::instance::Instance::new(None, &vulkano_win::required_extensions(), layers)
-                .unwrap_or_else(|x| {
-                    panic!(
-                        "Error creating instance: {}",
-                        match x {
-                            vulkano::instance::InstanceCreationError::LayerNotPresent =>
-                                "The Khronos validation layer is not present on your system"
-                                    .to_string(),
-                            x => format!("{:?}", x),
-                        }
-                    )
-                });
+/// The queue family we'd use on `device`, or everything it's missing that we need, one per line.
+/// The queue has to be able to present to `surface`, if there is one.
+fn check_device<'a>(
+    device: PhysicalDevice<'a>,
+    surface: Option<&Arc<vulkano::swapchain::Surface<RawWindow>>>,
+    config: &ClientConfig,
+) -> Result<vulkano::instance::QueueFamily<'a>, String> {
+    let mut missing = Vec::new();
 
-        let evloop = winit::event_loop::EventLoop::new();
-        let surface = winit::window::WindowBuilder::new()
-            .with_fullscreen(Some(winit::window::Fullscreen::Borderless(
-                evloop.primary_monitor(),
-            )))
-            .with_title(title)
-            .build_vk_surface(&evloop, Arc::clone(&instance))
-            .unwrap();
-        let window = surface.window();
-        if window.set_cursor_grab(true).is_err() {
-            println!("Failed to grab cursor. If you're on wayland, try setting the environment variable WINIT_UNIX_BACKEND=x11.\nLaunching without grabbed cursor...");
+    if !device.supported_features().fragment_stores_and_atomics {
+        missing.push(
+            "the fragmentStoresAndAtomics feature, for storage buffers in fragment shaders"
+                .to_string(),
+        );
+    }
+    let extensions = vulkano::device::DeviceExtensions::supported_by_device(device);
+    if !extensions.khr_storage_buffer_storage_class {
+        missing.push("the VK_KHR_storage_buffer_storage_class extension".to_string());
+    }
+    if surface.is_some() && !extensions.khr_swapchain {
+        missing.push("the VK_KHR_swapchain extension, for drawing to a window".to_string());
+    }
+
+    // The whole tree buffer is bound at once, so it has to fit in one storage buffer
+    let max = device.limits().max_storage_buffer_range() as usize;
+    let needed = crate::client_world::initial_tree_len(config) * 4;
+    if max < needed {
+        missing.push(format!(
+            "storage buffers of {} MB for the octree, but it only supports {} MB; try a smaller view_distance or max_root_size",
+            needed / (1024 * 1024),
+            max / (1024 * 1024)
+        ));
+    }
+
+    // TODO if no families support compute, pick a graphics one and disable graphics options that require compute shaders
+    // TODO separate compute queues
+    let queue_family = device.queue_families().find(|&q| {
+        q.supports_graphics()
+            && q.supports_compute()
+            && surface.map_or(true, |s| s.is_supported(q).unwrap_or(false))
+    });
+    if queue_family.is_none() {
+        missing.push(
+            "a queue family that supports graphics, compute, and drawing to the window".to_string(),
+        );
+    }
+
+    match queue_family {
+        Some(q) if missing.is_empty() => Ok(q),
+        _ => Err(missing.iter().map(|x| format!("\n  - {}", x)).collect()),
+    }
+}
+
+/// The device `config` asks for, if it's there and we can use it
+fn configured_device<'a>(
+    devices: &[PhysicalDevice<'a>],
+    surface: Option<&Arc<vulkano::swapchain::Surface<RawWindow>>>,
+    config: &ClientConfig,
+) -> Option<PhysicalDevice<'a>> {
+    let device = if let Some(i) = config.device_index {
+        let d = devices.iter().find(|d| d.index() == i);
+        if d.is_none() {
+            warn!("There's no device {}, picking one instead", i);
         }
-        window.set_cursor_visible(false);
+        d
+    } else if let Some(name) = &config.device_name {
+        let lower = name.to_lowercase();
+        let d = devices
+            .iter()
+            .find(|d| d.name().to_lowercase().contains(&lower));
+        if d.is_none() {
+            warn!(
+                "No device has \"{}\" in its name, picking one instead",
+                name
+            );
+        }
+        d
+    } else {
+        None
+    }?;
+
+    match check_device(*device, surface, config) {
+        Ok(_) => Some(*device),
+        Err(e) => {
+            warn!(
+                "Picking another device instead of {}, since it doesn't have everything we need:{}",
+                device.name(),
+                e
+            );
+            None
+        }
+    }
+}
+
+/// Picks a device, from the config or by asking the user if there's more than one, and creates it.
+/// If it doesn't support everything we need, the error says what's missing.
+fn create_device(
+    instance: &Arc<vulkano::instance::Instance>,
+    surface: Option<&Arc<vulkano::swapchain::Surface<RawWindow>>>,
+    config: &ClientConfig,
+) -> Result<
+    (
+        Arc<vulkano::device::Device>,
+        Arc<vulkano::device::Queue>,
+        Arc<vulkano::device::Queue>,
+    ),
+    String,
+> {
+    let devices: Vec<_> = PhysicalDevice::enumerate(instance).collect();
+    if devices.is_empty() {
+        return Err("No hardware on your system supports Vulkan!".to_string());
+    }
+    info!("Available devices (set device_name or device_index in the config to pick one):");
+    for device in &devices {
+        info!(
+            "\t{}. {} ({:?})",
+            device.index(),
+            device.name(),
+            device.ty()
+        );
+    }
 
-        // window.set_fullscreen(Some(window.get_current_monitor()));
+    let device = match configured_device(&devices, surface, config) {
+        Some(device) => device,
+        None if devices.len() == 1 => devices[0],
+        None => {
+            use std::io::Write;
 
-        let (device, queue, caps) = {
-            let mut devices = vulkano::instance::PhysicalDevice::enumerate(&instance);
-            let device = if devices.len() == 0 {
-                panic!("No hardware on your system supports Vulkan!")
-            } else if devices.len() == 1 {
-                devices.next().unwrap()
-            } else {
-                use std::io::Write;
+            println!("Available devices: \n");
+            for device in &devices {
+                println!("\t{}. {}\n", device.index(), device.name());
+            }
+            print!("Please select a device by index: ");
+            std::io::stdout().flush().unwrap();
 
-                println!("Available devices: \n");
-                for (i, device) in devices.enumerate() {
-                    println!("\t{}. {}\n", i, device.name());
-                }
-                print!("Please select a device by index: ");
-                std::io::stdout().flush().unwrap();
-
-                let mut s = String::new();
-                std::io::stdin().read_line(&mut s).unwrap();
-                let i: usize = s.trim().parse().expect("That's not a valid number");
-                vulkano::instance::PhysicalDevice::from_index(&instance, i)
-                    .expect("No device with that index")
-            };
-
-            println!("Selected device: {}", device.name());
-
-            // TODO if no families support compute, pick a graphics one and disable graphics options that require compute shaders
-            // TODO separate graphics, transfer, and maybe compute queues
-            let queue_family = device
-                .queue_families()
-                .find(|&q| {
-                    q.supports_graphics()
-                        && q.supports_compute()
-                        && surface.is_supported(q).unwrap_or(false)
-                })
-                .expect(
-                    "No queue families that support graphics, compute, and drawing to the window",
-                );
+            let mut s = String::new();
+            std::io::stdin().read_line(&mut s).unwrap();
+            let i: usize = s.trim().parse().expect("That's not a valid number");
+            PhysicalDevice::from_index(instance, i).expect("No device with that index")
+        }
+    };
 
-            let caps = surface.capabilities(device).unwrap();
+    info!("Selected device: {}", device.name());
 
-            let (device, mut queues) = vulkano::device::Device::new(
-                device,
-                &vulkano::device::Features {
-                    fragment_stores_and_atomics: true,
-                    ..vulkano::device::Features::none()
-                },
-                &vulkano::device::DeviceExtensions {
-                    khr_swapchain: true,
-                    khr_storage_buffer_storage_class: true,
-                    ..vulkano::device::DeviceExtensions::none()
-                },
-                [(queue_family, 0.5)].iter().cloned(),
-            )
-            .expect("Failed to create device");
-            (device, queues.next().unwrap(), caps)
-        };
+    let queue_family = check_device(device, surface, config).map_err(|e| {
+        format!(
+            "{} doesn't support everything the game needs. It's missing:{}",
+            device.name(),
+            e
+        )
+    })?;
+
+    let transfer = transfer_family(device).filter(|_| config.transfer_queue);
+    match transfer {
+        Some(q) => info!("Uploading chunks on transfer queue family {}", q.id()),
+        None => info!("Uploading chunks on the graphics queue"),
+    }
+    let families: Vec<_> = std::iter::once((queue_family, 0.5))
+        .chain(transfer.map(|q| (q, 0.5)))
+        .collect();
 
-        let (swapchain, images) = {
-            let mut usage = caps.supported_usage_flags;
-            // Validation layers are complaining
-            usage.storage = false;
-            let alpha = caps.supported_composite_alpha.iter().next().unwrap();
-            let format = caps.supported_formats[0].0;
-
-            let size: (u32, u32) = window.inner_size().into();
-            let size = [size.0, size.1];
-            let size = caps.current_extent.unwrap_or(size);
-            vulkano::swapchain::Swapchain::new(
-                Arc::clone(&device),
-                Arc::clone(&surface),
-                caps.min_image_count,
-                format,
-                size,
-                1,
-                usage,
-                &queue,
-                vulkano::swapchain::SurfaceTransform::Identity,
-                alpha,
-                vulkano::swapchain::PresentMode::Fifo,
-                vulkano::swapchain::FullscreenExclusive::Allowed,
-                true,
-                vulkano::swapchain::ColorSpace::SrgbNonLinear,
-            )
+    let (device, mut queues) = vulkano::device::Device::new(
+        device,
+        &required_features(),
+        &required_extensions(surface.is_some()),
+        families,
+    )
+    .map_err(|e| format!("Failed to create device: {}", e))?;
+    let queue = queues.next().unwrap();
+    let transfer_queue = queues.next().unwrap_or_else(|| queue.clone());
+    Ok((device, queue, transfer_queue))
+}
+
+/// A queue family on `device` for nothing but transfers, which is usually the DMA engine, if there is one
+fn transfer_family(device: PhysicalDevice) -> Option<vulkano::instance::QueueFamily> {
+    device.queue_families().find(|&q| {
+        q.explicitly_supports_transfers()
+            && !q.supports_graphics()
+            && !q.supports_compute()
+            && q.queues_count() > 0
+    })
+}
+
+/// A swapchain for `surface` the size of the window, and its images
+fn create_swapchain(
+    device: &Arc<vulkano::device::Device>,
+    surface: &Arc<vulkano::swapchain::Surface<RawWindow>>,
+    queue: &Arc<vulkano::device::Queue>,
+) -> Result<
+    (
+        Arc<vulkano::swapchain::Swapchain<RawWindow>>,
+        Vec<Arc<vulkano::image::SwapchainImage<RawWindow>>>,
+    ),
+    String,
+> {
+    let caps = surface
+        .capabilities(device.physical_device())
+        .map_err(|e| format!("Couldn't get the window's capabilities: {}", e))?;
+    let mut usage = caps.supported_usage_flags;
+    // Validation layers are complaining
+    usage.storage = false;
+    let alpha = caps.supported_composite_alpha.iter().next().unwrap();
+    let format = caps.supported_formats[0].0;
+
+    let size: (u32, u32) = surface.window().inner_size().into();
+    let size = [size.0, size.1];
+    let size = caps.current_extent.unwrap_or(size);
+    vulkano::swapchain::Swapchain::new(
+        Arc::clone(device),
+        Arc::clone(surface),
+        caps.min_image_count,
+        format,
+        size,
+        1,
+        usage,
+        queue,
+        vulkano::swapchain::SurfaceTransform::Identity,
+        alpha,
+        vulkano::swapchain::PresentMode::Fifo,
+        vulkano::swapchain::FullscreenExclusive::Allowed,
+        true,
+        vulkano::swapchain::ColorSpace::SrgbNonLinear,
+    )
+    .map_err(|e| format!("Failed to create swapchain: {}", e))
+}
+
+/// The offscreen image we draw to when we're headless, as a framebuffer for `rpass`
+fn headless_framebuffer(
+    device: &Arc<vulkano::device::Device>,
+    rpass: &Arc<dyn vulkano::framebuffer::RenderPassAbstract + Send + Sync>,
+    size: [u32; 2],
+) -> Arc<dyn vulkano::framebuffer::FramebufferAbstract + Send + Sync> {
+    let image = vulkano::image::AttachmentImage::with_usage(
+        device.clone(),
+        size,
+        HEADLESS_FORMAT,
+        vulkano::image::ImageUsage {
+            color_attachment: true,
+            transfer_source: true,
+            ..vulkano::image::ImageUsage::none()
+        },
+    )
+    .unwrap();
+    Arc::new(
+        vulkano::framebuffer::Framebuffer::start(Arc::clone(rpass))
+            .add(image)
             .unwrap()
-        };
+            .build()
+            .unwrap(),
+    )
+}
 
-        let mut dynamic_state = vulkano::command_buffer::DynamicState::default();
+/// Where `window` is now, which should only be asked while it isn't fullscreen
+fn windowed_rect(window: &RawWindow) -> Rect {
+    // Some platforms can't say where windows are, so those go in the corner next time
+    let pos = window
+        .outer_position()
+        .unwrap_or_else(|_| winit::dpi::PhysicalPosition::new(0, 0));
+    let size = window.inner_size();
+    ([pos.x, pos.y], [size.width, size.height])
+}
 
-        let rpass = Arc::new(
+/// A render pass with one color attachment in `format`, which is cleared first if `clear` is set.
+/// If it isn't, what's there to start with is undefined, so whatever's drawn first has to cover every pixel,
+/// and `begin_render_pass()` takes `ClearValue::None` instead of a color.
+pub fn color_rpass(
+    device: Arc<vulkano::device::Device>,
+    format: vulkano::format::Format,
+    clear: bool,
+) -> Arc<dyn vulkano::framebuffer::RenderPassAbstract + Send + Sync> {
+    if clear {
+        Arc::new(
             vulkano::single_pass_renderpass! {
-                device.clone(),
+                device,
                 attachments: {
                     color: {
                         load: Clear,
                         store: Store,
-                        format: swapchain.format(),
+                        format: format,
                         samples: 1,
                     }
                 },
@@ -176,7 +366,112 @@ impl Window {
                 }
             }
             .unwrap(),
-        ) as Arc<dyn vulkano::framebuffer::RenderPassAbstract + Send + Sync>;
+        )
+    } else {
+        Arc::new(
+            vulkano::single_pass_renderpass! {
+                device,
+                attachments: {
+                    color: {
+                        load: DontCare,
+                        store: Store,
+                        format: format,
+                        samples: 1,
+                    }
+                },
+                pass: {
+                    color: [color],
+                    depth_stencil: {}
+                }
+            }
+            .unwrap(),
+        )
+    }
+}
+
+impl Window {
+    pub fn device(&self) -> Arc<vulkano::device::Device> {
+        Arc::clone(&self.device)
+    }
+
+    pub fn frame(&self) -> Result<Frame, vulkano::swapchain::AcquireError> {
+        let swapchain = match &self.swapchain {
+            Some(swapchain) => swapchain,
+            None => {
+                return Ok(Frame {
+                    image_num: 0,
+                    acquire: None,
+                    framebuffer: Arc::clone(&self.framebuffers[0]),
+                })
+            }
+        };
+        // TODO do something with suboptimal
+        let (image_num, suboptimal, acquire) =
+            vulkano::swapchain::acquire_next_image(Arc::clone(swapchain), None)?;
+        let framebuffer = Arc::clone(&self.framebuffers[image_num]);
+        Ok(Frame {
+            image_num,
+            acquire: Some(acquire),
+            framebuffer,
+        })
+    }
+
+    /// Opens a window, on the device `config` asks for if there is one.
+    /// It goes where `geometry` says, moved onto a monitor if it isn't on one, or fullscreen on the primary monitor if there's no `geometry`.
+    /// Fails if there isn't a device that can run the game, with a message saying why.
+    pub fn new(
+        title: &str,
+        config: &ClientConfig,
+        mut geometry: Option<WindowGeometry>,
+    ) -> Result<(Self, winit::event_loop::EventLoop<()>), String> {
+        let instance = create_instance(&vulkano_win::required_extensions());
+
+        let evloop = winit::event_loop::EventLoop::new();
+        let monitors: Vec<_> = std::iter::once(evloop.primary_monitor())
+            .chain(evloop.available_monitors())
+            .collect();
+        let rects: Vec<Rect> = monitors
+            .iter()
+            .map(|m| {
+                let (pos, size) = (m.position(), m.size());
+                ([pos.x, pos.y], [size.width, size.height])
+            })
+            .collect();
+        let mut builder = winit::window::WindowBuilder::new().with_title(title);
+        let mut monitor = evloop.primary_monitor();
+        if let Some(g) = &mut geometry {
+            if let Some(i) = g.fit(&rects) {
+                monitor = monitors[i].clone();
+            }
+            builder = builder.with_inner_size(winit::dpi::PhysicalSize::new(g.size[0], g.size[1]));
+        }
+        if geometry.map_or(true, |g| g.fullscreen) {
+            builder = builder.with_fullscreen(Some(winit::window::Fullscreen::Borderless(monitor)));
+        }
+        let surface = builder
+            .build_vk_surface(&evloop, Arc::clone(&instance))
+            .unwrap();
+        let window = surface.window();
+        if window.set_cursor_grab(true).is_err() {
+            warn!("Failed to grab cursor. If you're on wayland, try setting the environment variable WINIT_UNIX_BACKEND=x11.\nLaunching without grabbed cursor...");
+        }
+        window.set_cursor_visible(false);
+        if let Some(g) = geometry {
+            // Even if it's fullscreen, so it's in the right place when it leaves
+            window.set_outer_position(winit::dpi::PhysicalPosition::new(
+                g.position[0],
+                g.position[1],
+            ));
+        }
+
+        let (device, queue, transfer_queue) = create_device(&instance, Some(&surface), config)?;
+
+        let (swapchain, images) = create_swapchain(&device, &surface, &queue)?;
+
+        let mut dynamic_state = vulkano::command_buffer::DynamicState::default();
+
+        let clear = config.clear_window();
+        let rpass = color_rpass(device.clone(), swapchain.format(), clear);
 
         let framebuffers = Window::resize(
             Arc::clone(&device),
@@ -185,48 +480,242 @@ impl Window {
             &mut dynamic_state,
         );
 
-        (
+        Ok((
             Window {
-                swapchain,
-                images,
-                surface: Arc::clone(&surface),
+                instance,
+                swapchain: Some(swapchain),
+                surface: Some(Arc::clone(&surface)),
                 dynamic_state,
                 rpass,
                 framebuffers,
+                images,
+                clear,
                 size: window.inner_size(),
                 device,
                 queue,
+                transfer_queue,
+                title: title.to_string(),
+                windowed: geometry.map(|g| (g.position, g.size)),
             },
             evloop,
-        )
+        ))
+    }
+
+    /// Renders to an offscreen image of this size instead of a window, so it works without a display.
+    /// Fails like `new()` does.
+    pub fn headless(size: [u32; 2], config: &ClientConfig) -> Result<Self, String> {
+        let instance = create_instance(&vulkano::instance::InstanceExtensions::none());
+        let (device, queue, transfer_queue) = create_device(&instance, None, config)?;
+
+        let mut dynamic_state = vulkano::command_buffer::DynamicState::default();
+        dynamic_state.viewports = Some(vec![vulkano::pipeline::viewport::Viewport {
+            origin: [0.0, 0.0],
+            dimensions: [size[0] as f32, size[1] as f32],
+            depth_range: 0.0..1.0,
+        }]);
+
+        let clear = config.clear_window();
+        let rpass = color_rpass(device.clone(), HEADLESS_FORMAT, clear);
+        let framebuffer = headless_framebuffer(&device, &rpass, size);
+
+        Ok(Window {
+            instance,
+            swapchain: None,
+            surface: None,
+            dynamic_state,
+            rpass,
+            framebuffers: vec![framebuffer],
+            images: Vec::new(),
+            clear,
+            size: winit::dpi::PhysicalSize::new(size[0], size[1]),
+            device,
+            queue,
+            transfer_queue,
+            title: String::new(),
+            windowed: None,
+        })
+    }
+
+    /// Shows what the game is busy with in the title bar, after the title, or just the title if `status` is `None`.
+    /// Does nothing when headless.
+    pub fn set_status(&self, status: Option<&str>) {
+        if let Some(surface) = &self.surface {
+            match status {
+                Some(status) => surface
+                    .window()
+                    .set_title(&format!("{} - {}", self.title, status)),
+                None => surface.window().set_title(&self.title),
+            }
+        }
+    }
+
+    /// Goes fullscreen on the monitor the window's on, or back to where it was before that. Does nothing when headless.
+    pub fn toggle_fullscreen(&mut self) {
+        let window = match &self.surface {
+            Some(surface) => surface.window(),
+            None => return,
+        };
+        if window.fullscreen().is_some() {
+            window.set_fullscreen(None);
+            if let Some((pos, size)) = self.windowed {
+                window.set_inner_size(winit::dpi::PhysicalSize::new(size[0], size[1]));
+                window.set_outer_position(winit::dpi::PhysicalPosition::new(pos[0], pos[1]));
+            }
+        } else {
+            self.windowed = Some(windowed_rect(window));
+            window.set_fullscreen(Some(winit::window::Fullscreen::Borderless(
+                window.current_monitor(),
+            )));
+        }
+    }
+
+    /// Where the window is, to open it the same way next time. If it's fullscreen, the position and size are from before that.
+    /// `None` when headless, or if it's been fullscreen the whole time, since then there's nothing to remember.
+    pub fn geometry(&self) -> Option<WindowGeometry> {
+        let window = self.surface.as_ref()?.window();
+        let fullscreen = window.fullscreen().is_some();
+        let (position, size) = if fullscreen {
+            self.windowed?
+        } else {
+            windowed_rect(window)
+        };
+        Some(WindowGeometry {
+            position,
+            size,
+            fullscreen,
+        })
+    }
+
+    pub fn is_headless(&self) -> bool {
+        self.swapchain.is_none()
+    }
+
+    /// The size of the images we're drawing to, which can be different from `size()` while resizing
+    pub fn dimensions(&self) -> [u32; 2] {
+        match &self.swapchain {
+            Some(swapchain) => swapchain.dimensions(),
+            None => [self.size.width, self.size.height],
+        }
     }
 
     pub fn size(&self) -> (f64, f64) {
         self.size.into()
     }
 
+    /// How many pixels each logical pixel is on the monitor the window's on, like 2 on most HiDPI screens.
+    /// It's 1 when headless.
+    pub fn scale_factor(&self) -> f32 {
+        self.surface
+            .as_ref()
+            .map_or(1.0, |s| s.window().scale_factor() as f32)
+    }
+
+    /// Makes a new device after the old one was lost, like when the driver resets or the GPU hangs,
+    /// along with the swapchain or offscreen image. It's the same physical device, so nobody's asked to pick one again.
+    /// Everything made with the old device is useless now, so the caller has to make it all again with `device()`.
+    pub fn recreate_device(&mut self, config: &ClientConfig) -> Result<(), String> {
+        let size = self.dimensions();
+        let config = ClientConfig {
+            device_index: Some(self.device.physical_device().index()),
+            ..config.clone()
+        };
+        // The surface can only have one swapchain at a time
+        self.framebuffers.clear();
+        self.swapchain = None;
+
+        let (device, queue, transfer_queue) =
+            create_device(&self.instance, self.surface.as_ref(), &config)?;
+        self.device = device;
+        self.queue = queue;
+        self.transfer_queue = transfer_queue;
+        match &self.surface {
+            Some(surface) => {
+                self.size = surface.window().inner_size();
+                let (swapchain, images) = create_swapchain(&self.device, surface, &self.queue)?;
+                self.rpass = color_rpass(self.device(), swapchain.format(), self.clear);
+                self.framebuffers = Window::resize(
+                    self.device(),
+                    &images,
+                    Arc::clone(&self.rpass),
+                    &mut self.dynamic_state,
+                );
+                self.images = images;
+                self.swapchain = Some(swapchain);
+            }
+            None => {
+                self.rpass = color_rpass(self.device(), HEADLESS_FORMAT, self.clear);
+                self.framebuffers = vec![headless_framebuffer(&self.device, &self.rpass, size)];
+            }
+        }
+        Ok(())
+    }
+
     /// Returns whether to render this frame. `continue` if it returns false
     pub fn recreate(&mut self) -> bool {
-        self.size = self.surface.window().inner_size();
+        let (swapchain, surface) = match (&self.swapchain, &self.surface) {
+            (Some(swapchain), Some(surface)) => (swapchain, surface),
+            // The offscreen image never changes size
+            _ => return true,
+        };
+        self.size = surface.window().inner_size();
         let size = self.size();
         let size = [size.0 as u32, size.1 as u32];
-        let (new_swapchain, new_images) = match self.swapchain.recreate_with_dimensions(size) {
+        // There's nothing to draw to while it's minimized, or in the middle of being dragged down to nothing
+        if size[0] == 0 || size[1] == 0 {
+            return false;
+        }
+        let (new_swapchain, new_images) = match swapchain.recreate_with_dimensions(size) {
             Ok(r) => r,
             // Apparently this error sometimes happens when the window is being resized, just try again
             Err(vulkano::swapchain::SwapchainCreationError::UnsupportedDimensions) => return false,
             Err(err) => panic!("Swapchain recreation error: {:?}", err),
         };
 
-        self.swapchain = new_swapchain;
+        self.swapchain = Some(new_swapchain);
         self.framebuffers = Window::resize(
             self.device(),
             &new_images,
             Arc::clone(&self.rpass),
             &mut self.dynamic_state,
         );
+        self.images = new_images;
         true
     }
 
+    /// Whether `rpass` clears the window before each frame
+    pub fn clears(&self) -> bool {
+        self.clear
+    }
+
+    /// Switches between clearing the window before each frame and not, with a new `rpass` and framebuffers.
+    /// Pipelines made for the old `rpass` still work with the new one, since render passes that only differ in
+    /// what they do first with each attachment are compatible.
+    pub fn set_clear(&mut self, clear: bool) {
+        if clear == self.clear {
+            return;
+        }
+        self.clear = clear;
+        match &self.swapchain {
+            Some(swapchain) => {
+                self.rpass = color_rpass(self.device(), swapchain.format(), clear);
+                self.framebuffers = Window::resize(
+                    self.device(),
+                    &self.images,
+                    Arc::clone(&self.rpass),
+                    &mut self.dynamic_state,
+                );
+            }
+            None => {
+                self.rpass = color_rpass(self.device(), HEADLESS_FORMAT, clear);
+                self.framebuffers = vec![headless_framebuffer(
+                    &self.device,
+                    &self.rpass,
+                    self.dimensions(),
+                )];
+            }
+        }
+    }
+
     fn resize(
         _device: Arc<vulkano::device::Device>,
         images: &[Arc<vulkano::image::SwapchainImage<RawWindow>>],
@@ -0,0 +1,122 @@
+//! Where the window was and how big it was, saved in `window.ron` next to the config when the game closes,
+//! so it opens the same way next time.
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// A rectangle on the desktop, like a monitor: its top-left corner and its size, in physical pixels
+pub type Rect = ([i32; 2], [u32; 2]);
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct WindowGeometry {
+    /// Where the window goes when it isn't fullscreen. If it's fullscreen, this is where it was before that.
+    pub position: [i32; 2],
+    pub size: [u32; 2],
+    pub fullscreen: bool,
+}
+
+/// How much of `a` and `b` overlap, in pixels
+fn overlap(a: Rect, b: Rect) -> u64 {
+    let axis = |i: usize| {
+        let lo = a.0[i].max(b.0[i]) as i64;
+        let hi = (a.0[i] as i64 + a.1[i] as i64).min(b.0[i] as i64 + b.1[i] as i64);
+        (hi - lo).max(0) as u64
+    };
+    axis(0) * axis(1)
+}
+
+impl WindowGeometry {
+    /// Where it's saved, next to the config file at `config_path`
+    pub fn path(config_path: &Path) -> PathBuf {
+        config_path.with_file_name("window.ron")
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let f = std::fs::File::open(path).map_err(|e| e.to_string())?;
+        ron::de::from_reader(f).map_err(|e| e.to_string())
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let s = ron::ser::to_string_pretty(self, Default::default()).map_err(|e| e.to_string())?;
+        std::fs::write(path, s).map_err(|e| e.to_string())
+    }
+
+    /// Moves and shrinks the window to fit on whichever of `monitors` it's mostly on.
+    /// If it isn't on any of them, like when the monitor it was on is unplugged, it goes in the middle of the first one,
+    /// which should be the primary monitor. Returns the monitor it ended up on, if there are any.
+    pub fn fit(&mut self, monitors: &[Rect]) -> Option<usize> {
+        let rect = (self.position, self.size);
+        let best = (0..monitors.len()).max_by_key(|&i| overlap(rect, monitors[i]))?;
+        let (best, centered) = if overlap(rect, monitors[best]) > 0 {
+            (best, false)
+        } else {
+            (0, true)
+        };
+        let (pos, size) = monitors[best];
+        for i in 0..2 {
+            self.size[i] = self.size[i].min(size[i]).max(1);
+            let max = pos[i] + (size[i] - self.size[i]) as i32;
+            self.position[i] = if centered {
+                pos[i] + (size[i] - self.size[i]) as i32 / 2
+            } else {
+                self.position[i].max(pos[i]).min(max)
+            };
+        }
+        Some(best)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn geom(position: [i32; 2], size: [u32; 2]) -> WindowGeometry {
+        WindowGeometry {
+            position,
+            size,
+            fullscreen: false,
+        }
+    }
+
+    const MONITORS: [Rect; 2] = [([0, 0], [1920, 1080]), ([1920, -200], [1280, 1024])];
+
+    #[test]
+    fn fits_on_monitors() {
+        // Already fits, so it stays put
+        let mut g = geom([100, 50], [800, 600]);
+        assert_eq!(g.fit(&MONITORS), Some(0));
+        assert_eq!(g, geom([100, 50], [800, 600]));
+
+        // Mostly on the second one, hanging off the top
+        let mut g = geom([2000, -300], [800, 600]);
+        assert_eq!(g.fit(&MONITORS), Some(1));
+        assert_eq!(g, geom([2000, -200], [800, 600]));
+
+        // Too big for it
+        let mut g = geom([1900, 0], [4000, 3000]);
+        assert_eq!(g.fit(&MONITORS), Some(1));
+        assert_eq!(g, geom([1920, -200], [1280, 1024]));
+
+        // Its monitor's gone, so it goes in the middle of the primary one
+        let mut g = geom([-3000, 200], [1000, 500]);
+        assert_eq!(g.fit(&MONITORS), Some(0));
+        assert_eq!(g, geom([460, 290], [1000, 500]));
+
+        let mut g = geom([-3000, 200], [1000, 500]);
+        assert_eq!(g.fit(&[]), None);
+        assert_eq!(g.position, [-3000, 200]);
+    }
+
+    #[test]
+    fn save_and_load() {
+        let dir = crate::common::test_dir("window");
+        let file = WindowGeometry::path(&dir.join("config.ron"));
+        assert_eq!(file, dir.join("window.ron"));
+        let g = WindowGeometry {
+            fullscreen: true,
+            ..geom([10, -20], [640, 480])
+        };
+        g.save(&file).unwrap();
+        assert_eq!(WindowGeometry::load(&file).unwrap(), g);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,9 +1,70 @@
 use crate::common::*;
 use std::collections::HashMap;
+use std::ops::Range;
 use std::sync::{Arc, RwLock};
 
+/// Edited ranges of a chunk this close together, in nodes, are uploaded in one copy, since each copy costs something too
+const EDIT_MERGE_GAP: usize = 32;
+/// How far `bake_shadows()` looks for something between a voxel and the sun, in voxels. Anything farther doesn't shadow it.
+pub const SHADOW_REACH: f32 = 64.0;
+
+/// The box that changing voxels from `min` to `max` can change the baked shadows in, with the sun in direction `sun`:
+/// everything that has them between it and the sun, within `SHADOW_REACH`, and the voxels themselves
+pub fn shadow_area(
+    min: Vector3<f32>,
+    max: Vector3<f32>,
+    sun: Vector3<f32>,
+) -> (Vector3<f32>, Vector3<f32>) {
+    let back = -sun.normalize() * SHADOW_REACH;
+    let r = Vector3::repeat(1.0);
+    (
+        min.zip_map(&(min + back), f32::min) - r,
+        max.zip_map(&(max + back), f32::max) + r,
+    )
+}
+
 pub struct World {
     pub chunks: HashMap<Vector3<i32>, Chunk>,
+    /// The ranges of nodes in each chunk that changed since `take_edits()`, if we're keeping track.
+    /// The client does, so it only has to upload those; the server doesn't need to.
+    edits: Option<HashMap<Vector3<i32>, Vec<Range<usize>>>>,
+}
+
+/// The result of a sphere cast
+#[derive(Clone, Debug)]
+pub struct Hit {
+    /// How far the sphere's center travelled along the ray before making contact
+    pub t: f32,
+    /// The center of the sphere at the point of contact
+    pub pos: Vector3<f32>,
+    /// The contact normal, pointing away from the geometry we hit
+    pub normal: Vector3<f32>,
+}
+
+/// The top of each column of voxels in a chunk, for drawing maps without the whole octree
+#[derive(Clone, Debug, PartialEq)]
+pub struct Heightmap {
+    /// How many columns it has on each side, which is `chunk_size()`
+    pub size: usize,
+    /// Indexed by `x + z * size`, from the chunk's -x -z corner.
+    /// Each is the height of the top of the highest nonempty voxel in world space, and its material,
+    /// or `None` if the column is all air.
+    pub columns: Vec<Option<(f32, Material)>>,
+}
+
+impl Heightmap {
+    /// The column `x` voxels along x and `z` along z from the chunk's -x -z corner
+    pub fn get(&self, x: usize, z: usize) -> Option<(f32, Material)> {
+        self.columns[x + z * self.size]
+    }
+
+    /// The highest point in the chunk, or `None` if it's all air
+    pub fn top(&self) -> Option<f32> {
+        self.columns
+            .iter()
+            .filter_map(|c| c.map(|(h, _)| h))
+            .fold(None, |a, h| Some(a.map_or(h, |a: f32| a.max(h))))
+    }
 }
 
 pub type ArcWorld = Arc<RwLock<World>>;
@@ -11,13 +72,76 @@ pub fn arcworld() -> ArcWorld {
     Arc::new(RwLock::new(World::new()))
 }
 
+/// Sorts `ranges`, and merges any that overlap or have less than `gap` between them
+pub fn coalesce(mut ranges: Vec<Range<usize>>, gap: usize) -> Vec<Range<usize>> {
+    ranges.sort_by_key(|r| r.start);
+    let mut merged: Vec<Range<usize>> = Vec::new();
+    for r in ranges {
+        match merged.last_mut() {
+            Some(last) if r.start <= last.end + gap => last.end = last.end.max(r.end),
+            _ => merged.push(r),
+        }
+    }
+    merged
+}
+
+/// Builds the chunks `voxels` are in, with everything else in them air.
+/// Positions are voxel centers in world space, like `World::set_block()` takes.
+/// Returns only the chunks with something in them, sorted by position.
+pub fn chunks_from_voxels(
+    voxels: impl IntoIterator<Item = (Vector3<f32>, Material)>,
+) -> Vec<(Vector3<i32>, Chunk)> {
+    let mut grids: HashMap<Vector3<i32>, HashMap<Vector3<usize>, Material>> = HashMap::new();
+    for (pos, mat) in voxels {
+        let chunk = world_to_chunk(pos);
+        let local = (pos - chunk.map(|x| x as f32 * chunk_size())).map(|x| x.floor() as usize);
+        grids.entry(chunk).or_default().insert(local, mat);
+    }
+    let mut chunks: Vec<_> = grids
+        .into_iter()
+        .map(|(i, grid)| {
+            let chunk = Chunk::from_voxels(|p| grid.get(&p).copied().unwrap_or(Material::Air));
+            (i, chunk)
+        })
+        .collect();
+    chunks.sort_by_key(|(i, _)| (i.x, i.y, i.z));
+    chunks
+}
+
+/// A nonempty leaf node found by `World::visit_box()`.
+/// Leaves can be bigger than one voxel if the octree merged them.
+#[derive(Clone, Debug)]
+pub struct VoxelRef {
+    /// The center of the leaf in world space
+    pub pos: Vector3<f32>,
+    pub size: f32,
+    pub mat: Material,
+}
+
 impl World {
     pub fn new() -> Self {
         World {
             chunks: HashMap::new(),
+            edits: None,
         }
     }
 
+    /// A world that keeps track of which nodes of each chunk are edited, for `take_edits()`
+    pub fn with_edit_tracking() -> Self {
+        World {
+            edits: Some(HashMap::new()),
+            ..World::new()
+        }
+    }
+
+    /// The ranges of nodes in the chunk at `chunk` that changed since the last time this was called for it, merged into as few as makes sense.
+    /// `None` if it hasn't been edited, or we aren't keeping track.
+    /// Chunks that are added or removed start over, since the whole chunk is new.
+    pub fn take_edits(&mut self, chunk: Vector3<i32>) -> Option<Vec<Range<usize>>> {
+        let ranges = self.edits.as_mut()?.remove(&chunk)?;
+        Some(coalesce(ranges, EDIT_MERGE_GAP))
+    }
+
     pub fn contains_chunk(&self, chunk: Vector3<i32>) -> bool {
         self.chunks.contains_key(&chunk)
     }
@@ -30,9 +154,15 @@ impl World {
         self.chunks.get(&k)
     }
     pub fn add_chunk(&mut self, k: Vector3<i32>, v: Chunk) {
+        if let Some(edits) = &mut self.edits {
+            edits.remove(&k);
+        }
         self.chunks.insert(k, v);
     }
     pub fn remove_chunk(&mut self, k: Vector3<i32>) -> Option<Chunk> {
+        if let Some(edits) = &mut self.edits {
+            edits.remove(&k);
+        }
         self.chunks.remove(&k)
     }
 
@@ -45,45 +175,384 @@ impl World {
     pub fn set_block(&mut self, k: Vector3<f32>, v: Material) {
         let chunk = world_to_chunk(k);
         let in_chunk = k - chunk_to_world(chunk);
-        let chunk = self.chunks.get_mut(&chunk).unwrap();
-        chunk.set_block(in_chunk, CHUNK_SIZE.log2().ceil() as u32, v);
+        let loc = chunk;
+        let chunk = self.chunks.get_mut(&loc).unwrap();
+        let changed = chunk.set_block(in_chunk, chunk_depth(), v);
+        if let Some(edits) = &mut self.edits {
+            edits.entry(loc).or_default().extend(changed);
+        }
+    }
+
+    /// Calls `f` for every nonempty leaf in a loaded chunk that overlaps the box from `min` to `max`.
+    /// Doesn't allocate, so prefer this over `voxels_in_box()` for big boxes.
+    pub fn visit_box(&self, min: Vector3<f32>, max: Vector3<f32>, mut f: impl FnMut(VoxelRef)) {
+        let lo = world_to_chunk(min);
+        let hi = world_to_chunk(max);
+        for x in lo.x..=hi.x {
+            for y in lo.y..=hi.y {
+                for z in lo.z..=hi.z {
+                    let loc = Vector3::new(x, y, z);
+                    if let Some(chunk) = self.chunk(loc) {
+                        let center = chunk_to_world(loc);
+                        chunk.visit_box(min - center, max - center, &mut |pos, size, mat| {
+                            f(VoxelRef {
+                                pos: pos + center,
+                                size,
+                                mat,
+                            })
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    /// Collects every nonempty leaf that overlaps the box from `min` to `max`
+    pub fn voxels_in_box(&self, min: Vector3<f32>, max: Vector3<f32>) -> Vec<VoxelRef> {
+        let mut ret = Vec::new();
+        self.visit_box(min, max, |v| ret.push(v));
+        ret
+    }
+
+    /// The top of each column in the chunk at `chunk`, or `None` if it isn't loaded.
+    /// Merged leaves count for every column they cover.
+    pub fn heightmap(&self, chunk: Vector3<i32>) -> Option<Heightmap> {
+        let c = self.chunk(chunk)?;
+        let size = chunk_size() as usize;
+        let center = chunk_to_world(chunk);
+        let half = Vector3::repeat(chunk_size() * 0.5);
+        let mut columns = vec![None; size * size];
+        c.visit_box(-half, half, &mut |pos, leaf_size, mat| {
+            let top = center.y + pos.y + leaf_size * 0.5;
+            let corner = pos - Vector3::repeat(leaf_size * 0.5) + half;
+            let (x0, z0, n) = (corner.x as usize, corner.z as usize, leaf_size as usize);
+            for z in z0..z0 + n {
+                for x in x0..x0 + n {
+                    let column = &mut columns[x + z * size];
+                    if column.map_or(true, |(h, _)| top > h) {
+                        *column = Some((top, mat));
+                    }
+                }
+            }
+        });
+        Some(Heightmap { size, columns })
+    }
+
+    /// Finds the voxel on the other side of `face` of the one containing `pos`, which can be in a neighboring chunk.
+    /// Empty voxels are returned too, so this only returns None if the neighboring chunk isn't loaded.
+    pub fn neighbor(&self, pos: Vector3<f32>, face: Face) -> Option<VoxelRef> {
+        self.neighbor_in(pos, face, |_| true)
+    }
+
+    /// Like `neighbor()`, but chunks where `loaded` is false count as not loaded
+    pub fn neighbor_in(
+        &self,
+        pos: Vector3<f32>,
+        face: Face,
+        loaded: impl Fn(Vector3<i32>) -> bool,
+    ) -> Option<VoxelRef> {
+        let chunk = |loc| if loaded(loc) { self.chunk(loc) } else { None };
+        let loc = world_to_chunk(pos);
+        let center = chunk_to_world(loc);
+        let chunk_here = chunk(loc)?;
+
+        let (pos, size, mat) = match chunk_here.neighbor(pos - center, face) {
+            Some((p, size, mat)) => (p + center, size, mat),
+            None => {
+                // We're on the edge of the chunk, so look at the facing side of the next one over
+                let loc = loc + face.normal().map(|x| x as i32);
+                let next_center = chunk_to_world(loc);
+                let (axis, side) = face.axis();
+                let (p, size, mat) = chunk(loc)?.face_leaf(pos - next_center, axis, -side);
+                (p + next_center, size, mat)
+            }
+        };
+        Some(VoxelRef { pos, size, mat })
+    }
+
+    /// Bakes ambient occlusion into every voxel that overlaps the box from `min` to `max`.
+    /// Only unit-size leaves are baked, bigger ones are left for the shader to handle.
+    /// Returns the chunks that were modified, which need to be uploaded again.
+    pub fn bake_ao(&mut self, min: Vector3<f32>, max: Vector3<f32>) -> Vec<Vector3<i32>> {
+        let mut baked = Vec::new();
+        self.visit_box(min, max, |v| {
+            if v.size == 1.0 && v.mat != Material::Air {
+                baked.push((v.pos, self.face_ao(v.pos)));
+            }
+        });
+
+        let mut modified = Vec::new();
+        for (pos, ao) in baked {
+            let loc = world_to_chunk(pos);
+            let center = chunk_to_world(loc);
+            if let Some(chunk) = self.chunks.get_mut(&loc) {
+                let changed = chunk.set_ao(pos - center, ao);
+                if let (Some(edits), Some(i)) = (&mut self.edits, changed) {
+                    edits.entry(loc).or_default().push(i..i + 1);
+                }
+                if !modified.contains(&loc) {
+                    modified.push(loc);
+                }
+            }
+        }
+        modified
+    }
+
+    /// Bakes which faces of every voxel that overlaps the box from `min` to `max` are in shadow from a sun in direction `sun`,
+    /// for `ShadowMode::Baked`. Each nonempty leaf gets a bit for the face on the sun's side of each axis, set if a ray from the
+    /// middle of that face toward the sun hits something within `SHADOW_REACH`. Leaves bigger than one voxel only look from the middle,
+    /// so each face is all in shadow or not at all. Returns the chunks that were modified, which need to be uploaded again.
+    pub fn bake_shadows(
+        &mut self,
+        min: Vector3<f32>,
+        max: Vector3<f32>,
+        sun: Vector3<f32>,
+    ) -> Vec<Vector3<i32>> {
+        // `raycast()` doesn't like directions with a component of exactly 0
+        let sun = sun
+            .normalize()
+            .map(|x| if x.abs() < 0.0001 { 0.0001 } else { x });
+        let mut baked = Vec::new();
+        self.visit_box(min, max, |v| {
+            if v.mat != Material::Air {
+                baked.push((v.pos, self.face_shadows(v.pos, v.size, sun)));
+            }
+        });
+
+        let mut modified = Vec::new();
+        for (pos, shadow) in baked {
+            let loc = world_to_chunk(pos);
+            let center = chunk_to_world(loc);
+            if let Some(chunk) = self.chunks.get_mut(&loc) {
+                let changed = chunk.set_shadow(pos - center, shadow);
+                if let (Some(edits), Some(i)) = (&mut self.edits, changed) {
+                    edits.entry(loc).or_default().push(i..i + 1);
+                }
+                if !modified.contains(&loc) {
+                    modified.push(loc);
+                }
+            }
+        }
+        modified
+    }
+
+    /// The shadow bits of the leaf at `pos` that's `size` wide, for `bake_shadows()`
+    fn face_shadows(&self, pos: Vector3<f32>, size: f32, sun: Vector3<f32>) -> u32 {
+        let mut shadow = 0;
+        for axis in 0..3 {
+            let mut n = Vector3::zeros();
+            n[axis] = sun[axis].signum();
+            // Just off the face, like the shader's shadow rays
+            let ro = pos + n * (size * 0.5 + 0.01);
+            if self.raycast(ro, sun, SHADOW_REACH).is_some() {
+                shadow |= 1 << axis;
+            }
+        }
+        shadow
+    }
+
+    /// Computes the occlusion of each face of the voxel at `pos`, from the voxels around the one in front of that face.
+    /// Each face gets two bits, in `Face::all()` order.
+    fn face_ao(&self, pos: Vector3<f32>) -> u32 {
+        let cell = pos.map(|x| x.floor() as i32);
+        let mut ao = 0;
+        for (i, face) in Face::all().iter().enumerate() {
+            let (axis, _) = face.axis();
+            let front = cell + face.normal().map(|x| x as i32);
+
+            // Edges count for twice as much as corners, which is about what the shader's AO does
+            let mut occ = 0;
+            for a in -1..=1 {
+                for b in -1..=1 {
+                    if a == 0 && b == 0 {
+                        continue;
+                    }
+                    let mut d = Vector3::zeros();
+                    d[(axis + 1) % 3] = a;
+                    d[(axis + 2) % 3] = b;
+                    if self.solid(front + d) {
+                        occ += if a == 0 || b == 0 { 2 } else { 1 };
+                    }
+                }
+            }
+            let level = ((occ + 2) / 4).min(3);
+            ao |= level << (2 * i);
+        }
+        ao
+    }
+
+    /// Whether the voxel with its minimum corner at `cell` is nonempty. Unloaded chunks count as empty.
+    fn solid(&self, cell: Vector3<i32>) -> bool {
+        match self.block(cell.map(|x| x as f32 + 0.5)) {
+            None | Some(Material::Air) => false,
+            Some(_) => true,
+        }
+    }
+
+    /// If a sphere at `center` overlaps any voxels in chunks where `loaded` is true, returns the direction to push it out in
+    fn sphere_overlap(
+        &self,
+        center: Vector3<f32>,
+        radius: f32,
+        loaded: &impl Fn(Vector3<i32>) -> bool,
+    ) -> Option<Vector3<f32>> {
+        let lo = center.map(|x| (x - radius).floor() as i32);
+        let hi = center.map(|x| (x + radius).floor() as i32);
+
+        let mut push = Vector3::zeros();
+        let mut hit = false;
+        for x in lo.x..=hi.x {
+            for y in lo.y..=hi.y {
+                for z in lo.z..=hi.z {
+                    let cell = Vector3::new(x, y, z);
+                    let mn = cell.map(|x| x as f32);
+                    if !loaded(world_to_chunk(mn + Vector3::repeat(0.5))) || !self.solid(cell) {
+                        continue;
+                    }
+                    let closest = center.zip_map(&mn, |c, m| na::clamp(c, m, m + 1.0));
+                    let d = center - closest;
+                    let dist = d.norm();
+                    if dist < radius {
+                        hit = true;
+                        // If the center is inside the voxel, push away from the voxel's center instead
+                        let n = if dist > 0.0001 {
+                            d / dist
+                        } else {
+                            center - mn - Vector3::repeat(0.5)
+                        };
+                        push += n * (radius - dist);
+                    }
+                }
+            }
+        }
+
+        if !hit {
+            None
+        } else if push.norm() > 0.0001 {
+            Some(push.normalize())
+        } else {
+            // We're wedged symmetrically between voxels, so any direction is as good as another
+            Some(up())
+        }
+    }
+
+    /// Sweeps a sphere of `radius` from `ro` along `rd`, and returns the first contact within `max_t`.
+    /// If the sphere starts out overlapping something, returns a hit at `t = 0` with a normal that pushes it out.
+    pub fn sphere_cast(
+        &self,
+        ro: Vector3<f32>,
+        rd: Vector3<f32>,
+        radius: f32,
+        max_t: f32,
+    ) -> Option<Hit> {
+        self.sphere_cast_in(ro, rd, radius, max_t, |_| true)
+    }
+
+    /// Like `sphere_cast()`, but only voxels in chunks where `loaded` is true count, and the rest are empty
+    pub fn sphere_cast_in(
+        &self,
+        ro: Vector3<f32>,
+        rd: Vector3<f32>,
+        radius: f32,
+        max_t: f32,
+        loaded: impl Fn(Vector3<i32>) -> bool,
+    ) -> Option<Hit> {
+        let rd = rd.normalize();
+        if let Some(normal) = self.sphere_overlap(ro, radius, &loaded) {
+            return Some(Hit {
+                t: 0.0,
+                pos: ro,
+                normal,
+            });
+        }
+
+        // Step by less than the radius and less than a voxel, so we can't tunnel through anything
+        let step = (radius * 0.5).min(0.5).max(0.01);
+        let mut t = 0.0;
+        while t < max_t {
+            let next = (t + step).min(max_t);
+            if self
+                .sphere_overlap(ro + rd * next, radius, &loaded)
+                .is_some()
+            {
+                // Bisect between the last free position and this one to find the contact
+                let (mut free, mut blocked) = (t, next);
+                for _ in 0..8 {
+                    let mid = (free + blocked) * 0.5;
+                    if self
+                        .sphere_overlap(ro + rd * mid, radius, &loaded)
+                        .is_some()
+                    {
+                        blocked = mid;
+                    } else {
+                        free = mid;
+                    }
+                }
+                let normal = self.sphere_overlap(ro + rd * blocked, radius, &loaded)?;
+                return Some(Hit {
+                    t: free,
+                    pos: ro + rd * free,
+                    normal,
+                });
+            }
+            t = next;
+        }
+        None
     }
 
     pub fn raycast(&self, ro: Vector3<f32>, rd: Vector3<f32>, max_t: f32) -> Option<RayCast> {
+        self.cast(ro, rd, max_t).ok().flatten()
+    }
+
+    /// The chunk that isn't loaded yet where a ray stops, if it gets to one within `max_t` before hitting anything,
+    /// so what it would hit isn't known yet
+    pub fn unloaded_on_ray(
+        &self,
+        ro: Vector3<f32>,
+        rd: Vector3<f32>,
+        max_t: f32,
+    ) -> Option<Vector3<i32>> {
+        self.cast(ro, rd, max_t).err()
+    }
+
+    /// Like `raycast()`, but says which chunk it stopped at when that isn't loaded
+    fn cast(
+        &self,
+        ro: Vector3<f32>,
+        rd: Vector3<f32>,
+        max_t: f32,
+    ) -> Result<Option<RayCast>, Vector3<i32>> {
         // Adapted from _A Fast Voxel Traversal Algorithm for Ray Tracing_ by Amanatides and Woo
         // Basically DDA
         let mut pos = world_to_chunk(ro);
         // rd is m/t
-        // CHUNK_SIZE is m/chunk
+        // chunk_size() is m/chunk
         // t/chunk
-        let tdelta = rd.map(|x| CHUNK_SIZE / x).abs();
+        let tdelta = rd.map(|x| chunk_size() / x).abs();
         let tstep = rd.map(|x| x.signum() as i32);
+        // How many nodes a ray can visit in one chunk grows with its width
+        let chunk_iters = 4 * chunk_size() as usize;
         // t
-        let mut tmax = (chunk_to_world(pos) + rd.map(f32::signum) * CHUNK_SIZE * 0.5
-            - ro
-            - Vector3::repeat(CHUNK_SIZE * 0.5))
-        .zip_map(&rd, |p, r| p / r);
+        let mut tmax = (chunk_to_world(pos) + rd.map(f32::signum) * chunk_size() * 0.5 - ro)
+            .zip_map(&rd, |p, r| p / r);
 
         loop {
-            let chunk = self.chunk(pos)?;
+            let chunk = self.chunk(pos).ok_or(pos)?;
             if chunk[0..8] != [0; 8] {
-                if let Some(x) = chunk.raycast(
-                    ro - chunk_to_world(pos) + Vector3::repeat(CHUNK_SIZE * 0.5),
-                    rd,
-                    64,
-                ) {
+                // `chunk_to_world()` is the center of the chunk, which is what `Chunk::raycast()` wants
+                if let Some(x) = chunk.raycast(ro - chunk_to_world(pos), rd, chunk_iters) {
                     if x.t[0] > max_t {
-                        return None;
+                        return Ok(None);
                     }
-                    return Some(RayCast {
+                    return Ok(Some(RayCast {
                         pos: chunk_to_world(pos) + x.pos,
                         ..x
-                    });
+                    }));
                 }
             }
 
             if tmax.min() > max_t {
-                return None;
+                return Ok(None);
             }
 
             if tmax.x < tmax.y {
@@ -110,3 +579,408 @@ impl Extend<(Vector3<i32>, Chunk)> for World {
         self.chunks.extend(it);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_world() -> World {
+        let mut world = World::new();
+        world.add_chunk(Vector3::new(0, 0, 0), Chunk::empty());
+        world.add_chunk(Vector3::new(1, 0, 0), Chunk::empty());
+        world.set_block(Vector3::new(7.5, 0.5, 0.5), Material::Stone);
+        world.set_block(Vector3::new(15.5, 0.5, 0.5), Material::Stone);
+        world.set_block(Vector3::new(16.5, 0.5, 0.5), Material::Dirt);
+        world
+    }
+
+    #[test]
+    fn bakes_ao() {
+        let mut world = World::new();
+        world.add_chunk(Vector3::new(0, 0, 0), Chunk::empty());
+        // A floor voxel with one more diagonally above it on the +x side, touching its top and +x faces along an edge
+        let (floor, wall) = (Vector3::new(4.5, 4.5, 4.5), Vector3::new(5.5, 5.5, 4.5));
+        let lone = Vector3::new(12.5, 4.5, 4.5);
+        for &p in &[floor, wall, lone] {
+            world.set_block(p, Material::Stone);
+        }
+        let leaf = |world: &World, p: Vector3<f32>| {
+            let chunk = world.chunk(world_to_chunk(p)).unwrap();
+            chunk[chunk
+                .leaf_index(p - chunk_to_world(world_to_chunk(p)))
+                .unwrap()]
+        };
+        assert_eq!(leaf(&world, floor) & AO_BAKED, 0);
+
+        let modified = world.bake_ao(floor - Vector3::repeat(1.5), floor + Vector3::repeat(1.5));
+        assert_eq!(modified, vec![Vector3::new(0, 0, 0)]);
+        // Two bits per face in `Face::all()` order, and one edge is level 1
+        let face =
+            |f: Face, level: u32| level << (2 * Face::all().iter().position(|&x| x == f).unwrap());
+        assert_eq!(
+            unpack_leaf(leaf(&world, floor)).ao,
+            Some(face(Face::PosX, 1) | face(Face::PosY, 1))
+        );
+        assert_eq!(
+            unpack_leaf(leaf(&world, wall)).ao,
+            Some(face(Face::NegX, 1) | face(Face::NegY, 1))
+        );
+        assert_ne!(leaf(&world, floor) & AO_BAKED, 0);
+        assert_eq!(unpack_leaf(leaf(&world, floor)).mat, Material::Stone as u32);
+        // Outside the box isn't baked
+        assert_eq!(leaf(&world, lone) & AO_BAKED, 0);
+
+        // With nothing around it, it's still baked, just without any occlusion
+        world.bake_ao(lone - Vector3::repeat(1.5), lone + Vector3::repeat(1.5));
+        assert_eq!(unpack_leaf(leaf(&world, lone)).ao, Some(0));
+    }
+
+    #[test]
+    fn bakes_shadows() {
+        let mut world = test_world();
+        let shadow = |world: &World, p: Vector3<f32>| {
+            let chunk = world.chunk(world_to_chunk(p)).unwrap();
+            let i = chunk.leaf_index(p - chunk_to_world(world_to_chunk(p)));
+            unpack_leaf(chunk[i.unwrap()]).shadow
+        };
+        // A roof over the stone at 7.5, with the sun nearly straight up
+        let sun = Vector3::new(0.1, 1.0, 0.0);
+        let roof = Vector3::new(7.5, 4.5, 0.5);
+        world.set_block(roof, Material::Dirt);
+        let (min, max) = shadow_area(
+            roof - Vector3::repeat(0.5),
+            roof + Vector3::repeat(0.5),
+            sun,
+        );
+        // It reaches down below the roof, against the sun
+        assert!(min.y < -50.0 && min.x < 1.0 && max.y > 5.0);
+
+        let modified = world.bake_shadows(min, max, sun);
+        assert_eq!(modified, vec![Vector3::new(0, 0, 0)]);
+        // The stone's top is under the roof, but the sides facing x and z aren't
+        assert_eq!(shadow(&world, Vector3::new(7.5, 0.5, 0.5)), 0b010);
+        // And the roof's in the open
+        assert_eq!(shadow(&world, roof), 0);
+
+        // Taking the roof away lets the sun back in
+        world.set_block(roof, Material::Air);
+        world.bake_shadows(min, max, sun);
+        assert_eq!(shadow(&world, Vector3::new(7.5, 0.5, 0.5)), 0);
+    }
+
+    #[test]
+    fn sphere_cast() {
+        let mut world = test_world();
+        // Starting just over the stone at 7.5 pushes straight up
+        let ro = Vector3::new(7.5, 1.2, 0.5);
+        let hit = world.sphere_cast(ro, Vector3::x(), 0.5, 10.0).unwrap();
+        assert_eq!(hit.t, 0.0);
+        assert_eq!(hit.pos, ro);
+        assert!((hit.normal - up()).norm() < 1e-4, "{:?}", hit);
+
+        // Across the border into the next chunk, touching the side of a block there
+        world.set_block(Vector3::new(20.5, 2.5, 0.5), Material::Stone);
+        let ro = Vector3::new(12.0, 2.5, 0.5);
+        let hit = world.sphere_cast(ro, Vector3::x(), 0.4, 10.0).unwrap();
+        assert!((hit.t - 7.6).abs() < 0.01, "{:?}", hit);
+        assert!((hit.normal + Vector3::x()).norm() < 1e-4, "{:?}", hit);
+        assert!(world.sphere_cast(ro, Vector3::x(), 0.4, 7.0).is_none());
+
+        // Without the next chunk it goes straight through, but the first one still counts
+        let first = |c: Vector3<i32>| c == Vector3::zeros();
+        assert!(world
+            .sphere_cast_in(ro, Vector3::x(), 0.4, 10.0, first)
+            .is_none());
+        let ro = Vector3::new(7.5, 1.2, 0.5);
+        assert_eq!(
+            world
+                .sphere_cast_in(ro, Vector3::x(), 0.5, 10.0, first)
+                .map(|hit| hit.t),
+            Some(0.0)
+        );
+    }
+
+    #[test]
+    fn voxels_in_box_across_chunks() {
+        let world = test_world();
+        let found = |min: Vector3<f32>, max: Vector3<f32>| {
+            let v: Vec<_> = world
+                .voxels_in_box(min, max)
+                .into_iter()
+                .map(|v| (v.pos, v.size, v.mat))
+                .collect();
+            v
+        };
+        // The stone and dirt on either side of the border, once each
+        assert_eq!(
+            found(Vector3::new(12.0, 0.2, 0.2), Vector3::new(20.0, 0.8, 0.8)),
+            vec![
+                (Vector3::new(15.5, 0.5, 0.5), 1.0, Material::Stone),
+                (Vector3::new(16.5, 0.5, 0.5), 1.0, Material::Dirt),
+            ]
+        );
+        // Ending right on the border doesn't reach into the next chunk
+        assert_eq!(
+            found(Vector3::new(12.0, 0.2, 0.2), Vector3::new(16.0, 0.8, 0.8)),
+            vec![(Vector3::new(15.5, 0.5, 0.5), 1.0, Material::Stone)]
+        );
+        // And starting on it doesn't reach back
+        assert_eq!(
+            found(Vector3::new(16.0, 0.2, 0.2), Vector3::new(20.0, 0.8, 0.8)),
+            vec![(Vector3::new(16.5, 0.5, 0.5), 1.0, Material::Dirt)]
+        );
+    }
+
+    #[test]
+    fn raycast_hits_the_right_voxel() {
+        // One block near a corner of a chunk that isn't at the origin, with every chunk around it loaded.
+        // Being half a chunk off would miss it, or find it in the wrong place.
+        let mut world = World::new();
+        for x in -1..3 {
+            for y in -2..2 {
+                for z in -3..1 {
+                    world.add_chunk(Vector3::new(x, y, z), Chunk::empty());
+                }
+            }
+        }
+        let block = Vector3::new(20.5, 3.5, -5.5);
+        world.set_block(block, Material::Stone);
+        assert_eq!(world_to_chunk(block), Vector3::new(1, 0, -1));
+
+        let e = Vector3::repeat(0.0001);
+        for &(ro, rd, t) in &[
+            // Along each axis from the chunk past the next one, and back the other way
+            (Vector3::new(20.5, 3.5, -40.0), Vector3::z(), 34.0),
+            (Vector3::new(20.5, 3.5, 10.0), -Vector3::z(), 15.0),
+            (Vector3::new(-10.0, 3.5, -5.5), Vector3::x(), 30.0),
+            (Vector3::new(20.5, -12.0, -5.5), Vector3::y(), 15.0),
+            // Diagonally, into the corner between its -x, -y and -z faces
+            (block - Vector3::repeat(20.5), Vector3::repeat(1.0), 20.0),
+        ] {
+            let hit = world.raycast(ro, rd + e, 100.0).unwrap();
+            assert_eq!(hit.pos, block, "{:?} {:?}", ro, rd);
+            assert_eq!(hit.mat, Material::Stone);
+            assert!((hit.t[0] - t).abs() < 0.01, "{:?} {:?}", ro, hit);
+        }
+        // Next to it is still air, on both sides of every chunk border it crossed
+        let ro = Vector3::new(21.5, 3.5, -40.0);
+        assert!(world.raycast(ro, Vector3::z() + e, 100.0).is_none());
+    }
+
+    #[test]
+    fn stops_at_unloaded_chunks() {
+        let world = test_world();
+        let ro = Vector3::new(1.5, 0.5, 0.5);
+        let rd = Vector3::new(-1.0, 0.0001, 0.0001);
+        assert!(world.raycast(ro, rd, 12.0).is_none());
+        assert_eq!(
+            world.unloaded_on_ray(ro, rd, 12.0),
+            Some(Vector3::new(-1, 0, 0))
+        );
+        // Not if it's out of reach
+        assert_eq!(world.unloaded_on_ray(ro, rd, 1.0), None);
+        // Or if there's something in the way
+        let rd = Vector3::new(1.0, 0.0001, 0.0001);
+        assert_eq!(world.raycast(ro, rd, 12.0).unwrap().mat, Material::Stone);
+        assert_eq!(world.unloaded_on_ray(ro, rd, 12.0), None);
+    }
+
+    #[test]
+    fn neighbor_sibling() {
+        let world = test_world();
+        let n = world
+            .neighbor(Vector3::new(15.5, 0.5, 0.5), Face::NegX)
+            .unwrap();
+        assert_eq!(n.pos, Vector3::new(14.5, 0.5, 0.5));
+        assert_eq!(n.size, 1.0);
+        assert_eq!(n.mat, Material::Air);
+    }
+
+    #[test]
+    fn neighbor_across_subtrees() {
+        // The voxel at x=7.5 is at the edge of the chunk's -x half, and its neighbor is an unsplit node in the +x half
+        let world = test_world();
+        let n = world
+            .neighbor(Vector3::new(7.5, 0.5, 0.5), Face::PosX)
+            .unwrap();
+        assert_eq!(n.pos, Vector3::new(10.0, 2.0, 2.0));
+        assert_eq!(n.size, 4.0);
+        assert_eq!(n.mat, Material::Air);
+
+        // Going back finds the small voxel touching our position
+        let n = world
+            .neighbor(Vector3::new(8.5, 0.5, 0.5), Face::NegX)
+            .unwrap();
+        assert_eq!(n.pos, Vector3::new(7.5, 0.5, 0.5));
+        assert_eq!(n.size, 1.0);
+        assert_eq!(n.mat, Material::Stone);
+    }
+
+    #[test]
+    fn neighbor_in_loaded_chunks() {
+        let world = test_world();
+        let (stone, dirt) = (Vector3::new(15.5, 0.5, 0.5), Vector3::new(16.5, 0.5, 0.5));
+        let both = |_| true;
+        let first = |c: Vector3<i32>| c == Vector3::zeros();
+        let second = |c: Vector3<i32>| c == Vector3::x();
+
+        // Over the border between them, both have to be loaded
+        let n = world.neighbor_in(stone, Face::PosX, both).unwrap();
+        assert_eq!((n.pos, n.size, n.mat), (dirt, 1.0, Material::Dirt));
+        assert!(world.neighbor_in(stone, Face::PosX, first).is_none());
+        assert!(world.neighbor_in(stone, Face::PosX, second).is_none());
+        assert!(world.neighbor_in(dirt, Face::NegX, second).is_none());
+
+        // Inside one, the other doesn't matter
+        let n = world.neighbor_in(stone, Face::NegX, first).unwrap();
+        assert_eq!(n.pos, Vector3::new(14.5, 0.5, 0.5));
+    }
+
+    #[test]
+    fn builds_chunks_from_voxels() {
+        let voxels = vec![
+            (Vector3::new(0.5, 0.5, 0.5), Material::Stone),
+            (Vector3::new(-0.5, 3.5, 0.5), Material::Dirt),
+            (Vector3::new(20.5, 0.5, 0.5), Material::Stone),
+        ];
+        let chunks = chunks_from_voxels(voxels.clone());
+        let locs: Vec<_> = chunks.iter().map(|(i, _)| *i).collect();
+        assert_eq!(
+            locs,
+            [
+                Vector3::new(-1, 0, 0),
+                Vector3::new(0, 0, 0),
+                Vector3::new(1, 0, 0)
+            ]
+        );
+        let mut world = World::new();
+        world.extend(chunks);
+        for (pos, mat) in voxels {
+            assert_eq!(world.block(pos), Some(mat));
+        }
+        assert_eq!(
+            world.block(Vector3::new(1.5, 0.5, 0.5)),
+            Some(Material::Air)
+        );
+        assert!(chunks_from_voxels(Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn coalesces_ranges() {
+        assert_eq!(
+            coalesce(vec![40..41, 0..8, 5..10, 12..13, 100..108], 4),
+            vec![0..13, 40..41, 100..108]
+        );
+        assert_eq!(coalesce(Vec::new(), 4), Vec::<Range<usize>>::new());
+    }
+
+    #[test]
+    fn tracks_edits() {
+        let loc = Vector3::zeros();
+        let ground = |p: Vector3<usize>| {
+            if p.y < 4 + (p.x * 3 + p.z * 5) % 7 {
+                Material::Stone
+            } else {
+                Material::Air
+            }
+        };
+        let mut world = World::with_edit_tracking();
+        world.add_chunk(loc, Chunk::from_voxels(ground));
+        assert!(world.take_edits(loc).is_none());
+
+        // Scattered single voxel edits, each uploaded on its own
+        let (mut copied, mut whole) = (0, 0);
+        let mut seed = 7u32;
+        for _ in 0..200 {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            let p = Vector3::new(seed >> 8, seed >> 12, seed >> 16).map(|x| (x % 16) as f32 + 0.5);
+            let before = world.chunk(loc).unwrap().clone();
+            world.set_block(p, Material::Dirt);
+            let baked = world.bake_ao(p - Vector3::repeat(1.5), p + Vector3::repeat(1.5));
+            assert_eq!(baked, vec![loc]);
+            let edits = world.take_edits(loc).unwrap();
+            let after = world.chunk(loc).unwrap();
+            // Everything that changed is in there
+            for i in 0..after.len() {
+                if before.get(i) != Some(&after[i]) {
+                    assert!(
+                        edits.iter().any(|r| r.contains(&i)),
+                        "{} isn't in {:?}",
+                        i,
+                        edits
+                    );
+                }
+            }
+            copied += edits.iter().map(|r| r.len()).sum::<usize>();
+            whole += after.len();
+        }
+        assert!(
+            copied * 10 < whole,
+            "{} nodes copied, out of {} in the whole chunks",
+            copied,
+            whole
+        );
+        assert!(world.take_edits(loc).is_none());
+
+        // Swapping the whole chunk out means there's no point uploading just the edits
+        world.set_block(Vector3::repeat(0.5), Material::Air);
+        world.add_chunk(loc, Chunk::from_voxels(ground));
+        assert!(world.take_edits(loc).is_none());
+        // And the server doesn't keep track at all
+        let mut world = World::new();
+        world.add_chunk(loc, Chunk::from_voxels(ground));
+        world.set_block(Vector3::repeat(0.5), Material::Air);
+        assert!(world.take_edits(loc).is_none());
+    }
+
+    #[test]
+    fn heightmap() {
+        let mut world = test_world();
+        let size = chunk_size();
+        world.add_chunk(
+            Vector3::new(0, -1, 0),
+            Chunk::from_voxels(|p| {
+                if p.y < 2 {
+                    Material::Stone
+                } else {
+                    Material::Air
+                }
+            }),
+        );
+        let map = world.heightmap(Vector3::new(0, 0, 0)).unwrap();
+        assert_eq!(map.size, size as usize);
+        assert_eq!(map.get(7, 0), Some((1.0, Material::Stone)));
+        assert_eq!(map.get(6, 0), None);
+        assert_eq!(map.top(), Some(1.0));
+
+        // The ground is in merged leaves, which still count for every column
+        let map = world.heightmap(Vector3::new(0, -1, 0)).unwrap();
+        assert!(map
+            .columns
+            .iter()
+            .all(|&c| c == Some((2.0 - size, Material::Stone))));
+        assert!(world.heightmap(Vector3::new(5, 0, 0)).is_none());
+    }
+
+    #[test]
+    fn neighbor_across_chunks() {
+        let world = test_world();
+        let n = world
+            .neighbor(Vector3::new(15.5, 0.5, 0.5), Face::PosX)
+            .unwrap();
+        assert_eq!(n.pos, Vector3::new(16.5, 0.5, 0.5));
+        assert_eq!(n.size, 1.0);
+        assert_eq!(n.mat, Material::Dirt);
+
+        let n = world
+            .neighbor(Vector3::new(16.5, 0.5, 0.5), Face::NegX)
+            .unwrap();
+        assert_eq!(n.pos, Vector3::new(15.5, 0.5, 0.5));
+        assert_eq!(n.mat, Material::Stone);
+
+        // There's no chunk loaded at -x
+        assert!(world
+            .neighbor(Vector3::new(0.5, 0.5, 0.5), Face::NegX)
+            .is_none());
+    }
+}
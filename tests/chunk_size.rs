@@ -0,0 +1,153 @@
+//! Everything at a chunk size other than the default, to catch code that still assumes 16.
+//! The chunk size is global, so this is its own test binary, and every test here uses the same size.
+use quanta::common::*;
+use quanta::config::GameConfig;
+use quanta::region::Region;
+use quanta::terrain::Gen;
+use quanta::world::World;
+
+const SIZE: u32 = 32;
+
+fn setup() {
+    assert_ne!(SIZE, DEFAULT_CHUNK_SIZE);
+    set_chunk_size(SIZE);
+}
+
+/// A chunk with stone below `height` voxels from its bottom
+fn layer(height: usize) -> Chunk {
+    Chunk::from_voxels(|p| {
+        if p.y < height {
+            Material::Stone
+        } else {
+            Material::Air
+        }
+    })
+}
+
+#[test]
+fn coordinates() {
+    setup();
+    assert_eq!(chunk_size(), SIZE as f32);
+    assert_eq!(
+        world_to_chunk(Vector3::new(31.5, 0.5, -0.5)),
+        Vector3::new(0, 0, -1)
+    );
+    assert_eq!(
+        world_to_chunk(Vector3::new(32.5, 63.5, -32.5)),
+        Vector3::new(1, 1, -2)
+    );
+    assert_eq!(
+        chunk_to_world(Vector3::new(1, 0, -1)),
+        Vector3::new(48.0, 16.0, -16.0)
+    );
+
+    let origin = RenderOrigin::new(Vector3::new(40.0, -1.0, 70.0));
+    assert_eq!(origin.0, Vector3::new(1, -1, 2));
+    assert_eq!(origin.world(), Vector3::new(32.0, -32.0, 64.0));
+    let from = RenderOrigin(Vector3::zeros());
+    assert_eq!(
+        origin.rebase(from, Vector3::new(40.0, 0.0, 0.0)),
+        Vector3::new(8.0, 32.0, -64.0)
+    );
+}
+
+#[test]
+fn blocks_across_chunks() {
+    setup();
+    let mut world = World::new();
+    world.add_chunk(Vector3::new(0, 0, 0), Chunk::empty());
+    world.add_chunk(Vector3::new(1, 0, 0), Chunk::empty());
+    world.set_block(Vector3::new(20.5, 0.5, 0.5), Material::Stone);
+    world.set_block(Vector3::new(32.5, 0.5, 0.5), Material::Dirt);
+
+    assert_eq!(
+        world.block(Vector3::new(20.5, 0.5, 0.5)),
+        Some(Material::Stone)
+    );
+    assert_eq!(
+        world.block(Vector3::new(32.5, 0.5, 0.5)),
+        Some(Material::Dirt)
+    );
+    // Where these would be with 16-voxel chunks
+    assert_eq!(
+        world.block(Vector3::new(4.5, 0.5, 0.5)),
+        Some(Material::Air)
+    );
+    assert_eq!(
+        world.block(Vector3::new(16.5, 0.5, 0.5)),
+        Some(Material::Air)
+    );
+
+    // Only one voxel was set in each chunk
+    assert_eq!(
+        world
+            .voxels_in_box(Vector3::zeros(), Vector3::new(63.0, 31.0, 31.0))
+            .len(),
+        2
+    );
+
+    let hit = world
+        .raycast(Vector3::new(24.5, 0.5, 0.5), Vector3::x(), 100.0)
+        .unwrap();
+    assert_eq!(hit.mat, Material::Dirt);
+    assert_eq!(hit.pos, Vector3::new(32.5, 0.5, 0.5));
+}
+
+#[test]
+fn chunks_from_voxels() {
+    setup();
+    // Higher than a 16-voxel chunk goes
+    let chunk = layer(20);
+    assert_eq!(
+        chunk.block(Vector3::new(0.5, 19.5 - 16.0, 0.5)),
+        Material::Stone
+    );
+    assert_eq!(
+        chunk.block(Vector3::new(0.5, 20.5 - 16.0, 0.5)),
+        Material::Air
+    );
+    assert_eq!(
+        chunk.block(Vector3::new(15.5, -15.5, 15.5)),
+        Material::Stone
+    );
+}
+
+#[test]
+fn region_round_trip() {
+    setup();
+    let dir = test_dir("chunk-size");
+    let path = Region::path(&dir, Vector3::zeros());
+
+    let mut region = Region::empty();
+    region.set(5, &layer(20));
+    region.save(&path).unwrap();
+    let loaded = Region::load(&path).unwrap();
+    assert_eq!(loaded.get(5).unwrap().unwrap().0, layer(20).0);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn terrain_lines_up() {
+    setup();
+    // The ground is always between -45 and 51, so this column covers it
+    let gen = Gen::new(&GameConfig::default(), 1234);
+    let mut world = World::new();
+    for x in 0..2 {
+        for y in -2..2 {
+            let p = Vector3::new(x, y, 0);
+            world.add_chunk(p, gen.gen(p));
+        }
+    }
+
+    let top = |x: f32, z: f32| {
+        (-64i32..64)
+            .rev()
+            .find(|&y| world.block(Vector3::new(x, y as f32 + 0.5, z)) != Some(Material::Air))
+            .expect("There's no ground")
+    };
+    // The ground is smooth, so it shouldn't jump at the edges of chunks
+    for &z in &[0.5, 15.5, 16.5, 31.5] {
+        let (a, b) = (top(31.5, z), top(32.5, z));
+        assert!((a - b).abs() <= 2, "The ground goes from {} to {}", a, b);
+    }
+}
@@ -0,0 +1,143 @@
+//! Runs a real server in this process, with clients talking to it over `Connection::memory()`,
+//! so the protocol gets tested end to end without any sockets.
+use quanta::common::*;
+use quanta::config::GameConfig;
+use quanta::server::Server;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Generating the first chunks can take a while in a debug build
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Starts a server on its own thread, with a local player so we can stop it.
+/// Returns the host's connection, where to send new players, and the thread.
+fn start() -> (Connection, Sender<Connection>, thread::JoinHandle<()>) {
+    let config = Arc::new(GameConfig {
+        save_chunks: false,
+        seed: Some(1),
+        draw_chunks: 2,
+        ..GameConfig::default()
+    });
+    let (host, host_server) = Connection::local();
+    let (to, from) = channel();
+    // The server isn't `Send`, so it's made on its thread
+    let thread = thread::spawn(move || {
+        let mut server = Server::new(config);
+        to.send(server.connector()).unwrap();
+        server.join(host_server, Vector3::zeros(), 0);
+        server.run();
+    });
+    (host, from.recv().unwrap(), thread)
+}
+
+/// Skips messages until one `f` likes, and returns it
+fn wait_for(conn: &Connection, what: &str, mut f: impl FnMut(&Message) -> bool) -> Message {
+    let start = Instant::now();
+    loop {
+        match conn.recv() {
+            Some(m) if f(&m) => return m,
+            Some(_) => (),
+            None if start.elapsed() > TIMEOUT => panic!("didn't get {} in time", what),
+            None => thread::sleep(Duration::from_millis(5)),
+        }
+    }
+}
+
+/// Connects a new player, who hears the chunk size before anything else, like over TCP
+fn connect(connector: &Sender<Connection>) -> Connection {
+    let (conn, server_end) = Connection::memory();
+    connector.send(server_end).unwrap();
+    let first = wait_for(&conn, "anything", |_| true);
+    assert!(
+        matches!(first, Message::ChunkSize(size) if size == DEFAULT_CHUNK_SIZE),
+        "{:?}",
+        first
+    );
+    conn
+}
+
+/// Says who the player on `conn` is and sends them to `pos`, seeing one chunk around them,
+/// and waits until they have the chunk they're in
+fn enter(conn: &Connection, name: &str, pos: Vector3<f32>) -> Chunk {
+    conn.send(Message::ViewDistance(1));
+    conn.send(Message::SetName(name.to_string()));
+    conn.send(Message::PlayerMove(pos));
+    let loc = world_to_chunk(pos);
+    match wait_for(
+        conn,
+        "the chunk we're in",
+        |m| matches!(m, Message::Chunks(c) if c.iter().any(|(l, _)| *l == loc)),
+    ) {
+        Message::Chunks(c) => c.into_iter().find(|(l, _)| *l == loc).unwrap().1,
+        _ => unreachable!(),
+    }
+}
+
+#[test]
+fn players_edit_and_leave() {
+    let (host, connector, thread) = start();
+    let pos = Vector3::new(8.0, 8.0, 8.0);
+    let loc = world_to_chunk(pos);
+    let edit = Vector3::new(8.5, 8.5, 8.5);
+
+    let a = connect(&connector);
+    let chunk = enter(&a, "Alex", pos);
+    let center = chunk_to_world(loc);
+    assert_ne!(chunk.block(edit - center), Material::Ice);
+    a.send(Message::Ping(7));
+    wait_for(&a, "a pong", |m| matches!(m, Message::Pong(7)));
+
+    // The host is player 0, so Alex is 1, and the next one hears their name when they join
+    let b = connect(&connector);
+    wait_for(
+        &b,
+        "Alex's name",
+        |m| matches!(m, Message::PlayerName(1, name) if name == "Alex"),
+    );
+    enter(&b, "Blake", pos);
+    wait_for(
+        &a,
+        "where Blake is",
+        |m| matches!(m, Message::OtherPlayer(2, p) if *p == pos),
+    );
+
+    // An edit goes to everyone who can see it
+    a.send(Message::SetBlock(edit, Material::Ice));
+    wait_for(&b, "Alex's edit", |m| match m {
+        Message::Chunks(c) => c
+            .iter()
+            .any(|(l, c)| *l == loc && c.block(edit - center) == Material::Ice),
+        _ => false,
+    });
+
+    // Only the host can save, unless `remote_save` is on
+    a.send(Message::Save);
+    wait_for(&a, "the save being refused", |m| {
+        matches!(m, Message::SaveRefused)
+    });
+    host.send(Message::Save);
+    wait_for(&host, "the save", |m| matches!(m, Message::Saved(_)));
+
+    a.send(Message::Leave);
+    wait_for(&b, "Alex leaving", |m| matches!(m, Message::PlayerLeft(1)));
+
+    // Hanging up without saying so counts as leaving too
+    let c = connect(&connector);
+    enter(&c, "Casey", pos);
+    wait_for(
+        &b,
+        "where Casey is",
+        |m| matches!(m, Message::OtherPlayer(3, p) if *p == pos),
+    );
+    drop(c);
+    wait_for(&b, "Casey hanging up", |m| {
+        matches!(m, Message::PlayerLeft(3))
+    });
+
+    // The host leaving stops the server, which tells everyone else
+    host.send(Message::Leave);
+    thread.join().unwrap();
+    wait_for(&b, "the server stopping", |m| matches!(m, Message::Leave));
+}